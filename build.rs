@@ -0,0 +1,98 @@
+//! Packs the initial ramdisk (initrd) that is bundled into the kernel. Everything under `initrd/` goes into a
+//! USTAR archive in `OUT_DIR`, which the kernel includes and unpacks into its root ramfs at boot. To ship a
+//! ready made archive instead (a tar, or a newc cpio like Linux uses), point `DBOS_INITRD` at it.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The directory that is packed, relative to the crate
+const INITRD_DIRECTORY: &str = "initrd";
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", INITRD_DIRECTORY);
+    println!("cargo:rerun-if-env-changed=DBOS_INITRD");
+    let output = PathBuf::from(env::var("OUT_DIR").unwrap()).join("initrd.img");
+
+    let archive = match env::var("DBOS_INITRD") {
+        Ok(path) => {
+            println!("cargo:rerun-if-changed={}", path);
+            fs::read(&path).unwrap_or_else(|error| panic!("Couldn't read the initrd {}: {}", path, error))
+        }
+        Err(_) => {
+            let mut archive = Vec::new();
+            let directory = Path::new(INITRD_DIRECTORY);
+            if directory.is_dir() {
+                pack(directory, "", &mut archive).expect("Couldn't pack the initrd");
+            }
+            // The end of the archive - two empty blocks
+            archive.resize(archive.len() + 1024, 0);
+            archive
+        }
+    };
+    fs::write(&output, archive).expect("Couldn't write the initrd");
+}
+
+/// Add everything in `directory` to `archive`, named from `prefix`, in name order so builds are the same
+fn pack(directory: &Path, prefix: &str, archive: &mut Vec<u8>) -> io::Result<()> {
+    let mut entries = fs::read_dir(directory)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let kind = entry.file_type()?;
+        if kind.is_symlink() {
+            let target = fs::read_link(entry.path())?;
+            add(archive, &name, b'2', &target.to_string_lossy(), &[]);
+        } else if kind.is_dir() {
+            add(archive, &format!("{}/", name), b'5', "", &[]);
+            pack(&entry.path(), &format!("{}/", name), archive)?;
+        } else {
+            add(archive, &name, b'0', "", &fs::read(entry.path())?);
+        }
+    }
+    Ok(())
+}
+
+/// Write `value` into `field` as NUL terminated octal
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+}
+
+/// Add one USTAR entry - a header block, then the data padded to a whole block
+fn add(archive: &mut Vec<u8>, name: &str, kind: u8, link: &str, data: &[u8]) {
+    let mut header = [0u8; 512];
+    // Names over 100 bytes are split into a prefix (the directories) and the rest
+    let (prefix, name) = match name.len() {
+        0..=100 => ("", name),
+        _ => {
+            let split = name[..name.len() - 1].rfind('/').filter(|&split| split <= 155 && name.len() - split - 1 <= 100);
+            let split = split.unwrap_or_else(|| panic!("{} is too long for the initrd", name));
+            (&name[..split], &name[split + 1..])
+        }
+    };
+    assert!(link.len() <= 100, "The symlink {} is too long for the initrd", name);
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header[100..108], if kind == b'5' { 0o755 } else { 0o644 });
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], data.len() as u64);
+    octal(&mut header[136..148], 0);
+    header[156] = kind;
+    header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    // The checksum is worked out with its own field as spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    octal(&mut header[148..155], checksum as u64);
+    header[154] = 0;
+    header[155] = b' ';
+
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(data);
+    let padding = (512 - data.len() % 512) % 512;
+    archive.resize(archive.len() + padding, 0);
+}
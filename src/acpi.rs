@@ -0,0 +1,333 @@
+//! # ACPI
+//!
+//! ACPI (Advanced Configuration and Power Interface) is how the firmware tells us about the machine -
+//! what CPUs it has, how interrupts are wired, where PCI config space lives, how to power off and more.
+//! It does this through a tree of tables in memory, rooted at the RSDP.
+//!
+//! [init](fn.init.html) finds the RSDP, walks the RSDT/XSDT, checks every table's checksum and parses the ones
+//! we care about:
+//!
+//! * MADT (`APIC`) - processors, I/O APICs and interrupt source overrides
+//! * FADT (`FACP`) - power management registers, the reset register and the address of the DSDT
+//! * HPET (`HPET`) - the high precision event timer
+//! * MCFG (`MCFG`) - PCI Express memory mapped (ECAM) config space regions
+//! * DSDT/SSDT - the AML bytecode describing the rest of the machine. We only check the headers here
+//!
+//! Everything is parsed once and kept for the life of the kernel, so the query functions (like
+//! [processors](fn.processors.html)) are cheap and can be called from anywhere.
+
+pub mod madt; // Multiple APIC Description Table - CPUs and interrupt routing
+pub mod fadt; // Fixed ACPI Description Table - power management
+pub mod hpet; // High Precision Event Timer description
+pub mod mcfg; // PCI Express memory mapped configuration space
+pub mod aml; // The AML interpreter, for the DSDT and SSDTs
+
+pub use madt::{Madt, Processor, IoApic, InterruptOverride, Polarity, TriggerMode};
+pub use fadt::{Fadt, GenericAddress};
+pub use hpet::Hpet;
+pub use mcfg::EcamRegion;
+
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::{mem, ptr, slice};
+use x86_64::PhysAddr;
+use crate::{memory, serial_println};
+
+/// Signature the RSDP starts with. It is always on a 16 byte boundary
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+/// The parsed tables, set by `init`
+static TABLES: OnceCell<AcpiTables> = OnceCell::uninit();
+
+/// # Rsdp
+///
+/// The Root System Description Pointer. Points to the RSDT (ACPI 1.0), and the XSDT (ACPI 2.0+)
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // The fields below only exist if `revision >= 2`
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+/// # SdtHeader
+///
+/// The header every ACPI table (System Description Table) starts with
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// # AmlTable
+///
+/// A table made of AML bytecode - the DSDT, or one of the SSDTs. The header has been checked, the
+/// bytecode is left for an AML interpreter
+#[derive(Debug, Clone, Copy)]
+pub struct AmlTable {
+    pub header: SdtHeader,
+    /// Physical address of the table (the header, not the bytecode)
+    pub address: PhysAddr,
+}
+
+impl AmlTable {
+    /// The AML bytecode that follows the header
+    pub fn aml(&self) -> &'static [u8] {
+        let header_size = mem::size_of::<SdtHeader>();
+        let start = memory::phys_to_virt(self.address + header_size);
+        let length = (self.header.length as usize).saturating_sub(header_size);
+        unsafe { slice::from_raw_parts(start.as_ptr::<u8>(), length) }
+    }
+}
+
+/// # AcpiTables
+///
+/// Everything we found. Tables the firmware didn't provide (or that failed their checksum) are `None`
+#[derive(Debug)]
+pub struct AcpiTables {
+    /// The RSDP revision - 0 for ACPI 1.0, 2 or more for ACPI 2.0+
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    /// PCI Express config space regions from the MCFG. Empty on machines without PCI Express
+    pub ecam_regions: Vec<EcamRegion>,
+    pub dsdt: Option<AmlTable>,
+    pub ssdts: Vec<AmlTable>,
+}
+
+/// Read a `T` from physical memory, through the bootloader's physical memory mapping
+fn read_phys<T: Copy>(addr: PhysAddr) -> T {
+    unsafe { ptr::read_unaligned(memory::phys_to_virt(addr).as_ptr::<T>()) }
+}
+
+/// Read a `T` at `offset` into the table at `table`, if it fits within the table's `length`.
+/// Older firmware has shorter versions of some tables, so later fields may simply not be there
+fn read_field<T: Copy>(table: PhysAddr, length: u32, offset: usize) -> Option<T> {
+    if offset + mem::size_of::<T>() > length as usize {
+        return None;
+    }
+    Some(read_phys(table + offset))
+}
+
+/// Add up `length` bytes starting at `addr`. Every ACPI structure is valid only if this is 0
+fn checksum(addr: PhysAddr, length: usize) -> u8 {
+    (0..length as u64).fold(0u8, |sum, i| sum.wrapping_add(read_phys::<u8>(addr + i)))
+}
+
+/// Check whether there is a valid RSDP at `addr`
+fn rsdp_at(addr: PhysAddr) -> Option<Rsdp> {
+    let rsdp: Rsdp = read_phys(addr);
+    if &rsdp.signature != RSDP_SIGNATURE || checksum(addr, 20) != 0 {
+        return None; // the first 20 bytes are the ACPI 1.0 structure
+    }
+    if rsdp.revision >= 2 && checksum(addr, rsdp.length as usize) != 0 {
+        return None;
+    }
+    Some(rsdp)
+}
+
+/// Scan `start..end` on 16 byte boundaries for the RSDP
+fn scan_for_rsdp(start: u64, end: u64) -> Option<Rsdp> {
+    (start..end).step_by(16).find_map(|addr| rsdp_at(PhysAddr::new(addr)))
+}
+
+/// Find the RSDP. The spec says it's either in the first KiB of the EBDA (Extended BIOS Data Area),
+/// or in the BIOS area between 0xE0000 and 0xFFFFF
+fn find_rsdp() -> Option<Rsdp> {
+    // The real mode segment of the EBDA is stored at 0x40E
+    let ebda = (read_phys::<u16>(PhysAddr::new(0x40E)) as u64) << 4;
+    if ebda != 0 {
+        if let Some(rsdp) = scan_for_rsdp(ebda, ebda + 1024) {
+            return Some(rsdp);
+        }
+    }
+    scan_for_rsdp(0xE0000, 0x100000)
+}
+
+/// Read the header of the table at `addr`, if its checksum is good
+fn valid_header(addr: PhysAddr) -> Option<SdtHeader> {
+    let header: SdtHeader = read_phys(addr);
+    if (header.length as usize) < mem::size_of::<SdtHeader>() || checksum(addr, header.length as usize) != 0 {
+        return None;
+    }
+    Some(header)
+}
+
+/// Get the physical addresses of every table listed in the XSDT (or RSDT on ACPI 1.0 machines)
+fn table_addresses(rsdp: &Rsdp) -> Vec<PhysAddr> {
+    // The XSDT has 64 bit pointers, the RSDT 32 bit ones
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (PhysAddr::new(rsdp.xsdt_address), 8)
+    } else {
+        (PhysAddr::new(rsdp.rsdt_address as u64), 4)
+    };
+
+    let header = match valid_header(root) {
+        Some(header) => header,
+        None => {
+            serial_println!("[LOG] ACPI root table at {:?} has a bad checksum", root);
+            return Vec::new();
+        }
+    };
+
+    let entries = (header.length as usize - mem::size_of::<SdtHeader>()) / entry_size;
+    let first_entry = root + mem::size_of::<SdtHeader>();
+    (0..entries)
+        .map(|i| {
+            let entry = first_entry + (i * entry_size) as u64;
+            if entry_size == 8 {
+                PhysAddr::new(read_phys::<u64>(entry))
+            } else {
+                PhysAddr::new(read_phys::<u32>(entry) as u64)
+            }
+        })
+        .collect()
+}
+
+/// Find and parse every table we understand
+fn parse_tables(rsdp: &Rsdp) -> AcpiTables {
+    let mut tables = AcpiTables {
+        revision: rsdp.revision,
+        oem_id: rsdp.oem_id,
+        madt: None,
+        fadt: None,
+        hpet: None,
+        ecam_regions: Vec::new(),
+        dsdt: None,
+        ssdts: Vec::new(),
+    };
+
+    for address in table_addresses(rsdp) {
+        let header = match valid_header(address) {
+            Some(header) => header,
+            None => {
+                serial_println!("[LOG] Skipping ACPI table at {:?} - bad checksum", address);
+                continue;
+            }
+        };
+
+        match &header.signature {
+            b"APIC" => tables.madt = Some(Madt::parse(address, &header)),
+            b"FACP" => tables.fadt = Some(Fadt::parse(address, &header)),
+            b"HPET" => tables.hpet = Hpet::parse(address, &header),
+            b"MCFG" => tables.ecam_regions = mcfg::parse(address, &header),
+            b"SSDT" => tables.ssdts.push(AmlTable { header, address }),
+            _ => {}
+        }
+    }
+
+    // The DSDT isn't in the root table, the FADT points to it
+    if let Some(dsdt) = tables.fadt.as_ref().and_then(|fadt| fadt.dsdt_address) {
+        match valid_header(dsdt) {
+            Some(header) if &header.signature == b"DSDT" => tables.dsdt = Some(AmlTable { header, address: dsdt }),
+            _ => serial_println!("[LOG] The DSDT at {:?} is invalid", dsdt),
+        }
+    }
+
+    tables
+}
+
+/// # init
+///
+/// Find and parse the ACPI tables. Call this once, after `memory::init` (we read the tables through the
+/// physical memory mapping), and before anything queries them - `smp::init` needs the processor list.
+pub fn init() {
+    let rsdp = match find_rsdp() {
+        Some(rsdp) => rsdp,
+        None => {
+            serial_println!("[LOG] No ACPI RSDP found");
+            return;
+        }
+    };
+
+    let tables = parse_tables(&rsdp);
+    serial_println!(
+        "[LOG] ACPI {}: {} processors, {} interrupt overrides, FADT: {}, HPET: {}, {} ECAM regions, DSDT: {}, {} SSDTs",
+        if tables.revision >= 2 { "2.0+" } else { "1.0" },
+        tables.madt.as_ref().map_or(0, |madt| madt.processors.len()),
+        tables.madt.as_ref().map_or(0, |madt| madt.interrupt_overrides.len()),
+        tables.fadt.is_some(),
+        tables.hpet.is_some(),
+        tables.ecam_regions.len(),
+        tables.dsdt.is_some(),
+        tables.ssdts.len()
+    );
+
+    let _ = TABLES.try_init_once(|| tables);
+    aml::init();
+}
+
+/// All the parsed tables. `None` if there is no ACPI (or `init` hasn't run yet)
+pub fn tables() -> Option<&'static AcpiTables> {
+    TABLES.try_get().ok()
+}
+
+/// Every processor in the MADT (Multiple APIC Description Table). Empty if there is no ACPI
+pub fn processors() -> &'static [Processor] {
+    match tables().and_then(|tables| tables.madt.as_ref()) {
+        Some(madt) => &madt.processors,
+        None => &[],
+    }
+}
+
+/// Every interrupt source override in the MADT. These say which ISA IRQs aren't wired to the global
+/// system interrupt with the same number (the PIT's IRQ 0 is usually on GSI 2, for example)
+pub fn interrupt_overrides() -> &'static [InterruptOverride] {
+    match tables().and_then(|tables| tables.madt.as_ref()) {
+        Some(madt) => &madt.interrupt_overrides,
+        None => &[],
+    }
+}
+
+/// The override for ISA IRQ `irq`, if there is one
+pub fn isa_irq_override(irq: u8) -> Option<&'static InterruptOverride> {
+    interrupt_overrides().iter().find(|o| o.bus == 0 && o.source_irq == irq)
+}
+
+/// The PCI Express config space regions from the MCFG. Empty if there isn't one (like on QEMU's `pc` machine)
+pub fn ecam_regions() -> &'static [EcamRegion] {
+    match tables() {
+        Some(tables) => &tables.ecam_regions,
+        None => &[],
+    }
+}
+
+/// The FADT, if there is one
+pub fn fadt() -> Option<&'static Fadt> {
+    tables().and_then(|tables| tables.fadt.as_ref())
+}
+
+/// The HPET description, if there is one
+pub fn hpet() -> Option<&'static Hpet> {
+    tables().and_then(|tables| tables.hpet.as_ref())
+}
+
+/// The DSDT, if there is one
+pub fn dsdt() -> Option<&'static AmlTable> {
+    tables().and_then(|tables| tables.dsdt.as_ref())
+}
+
+/// Every SSDT (extra AML tables, often one per CPU or device)
+pub fn ssdts() -> &'static [AmlTable] {
+    match tables() {
+        Some(tables) => &tables.ssdts,
+        None => &[],
+    }
+}
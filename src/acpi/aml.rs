@@ -0,0 +1,238 @@
+//! # AML
+//!
+//! The DSDT and SSDTs don't hold plain data like the other ACPI tables - they hold AML (ACPI Machine
+//! Language), bytecode that declares the machine's devices and the methods used to drive them. Things like
+//! the `\_S5` sleep values, PCI interrupt routing (`_PRT`), battery and thermal info all have to be
+//! evaluated rather than just read.
+//!
+//! This is a small AML interpreter. It loads the tables into a namespace (a tree of named objects, like
+//! `\_SB_.PCI0._PRT`) and can evaluate methods and read the data objects in it. Operation regions (the way
+//! AML touches hardware) go through a [Handler](trait.Handler.html), so the interpreter itself can be tested
+//! without real hardware.
+//!
+//! It covers what QEMU's `pc` (i440fx) and `q35` tables use. Things real firmware rarely needs (`Load`,
+//! `BankField`, `Match`...) return [AmlError::Unsupported](enum.AmlError.html).
+
+pub mod name; // Names and paths in the namespace
+pub mod value; // The values and objects AML works with
+mod opcode; // AML opcode numbers
+mod interpreter; // Parsing and running AML
+
+pub use interpreter::Interpreter;
+pub use value::{AmlValue, RegionSpace};
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+use crate::driver::pci::config::{self, PciAddress};
+use crate::{memory, serial_println, smp};
+
+/// The kernel's interpreter, with the DSDT and SSDTs loaded. Set by `init`
+static INTERPRETER: OnceCell<Mutex<Interpreter>> = OnceCell::uninit();
+
+/// # AmlError
+///
+/// Everything that can go wrong loading or running AML
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AmlError {
+    /// The bytecode ended in the middle of something
+    UnexpectedEnd,
+    /// An opcode we don't know
+    UnknownOpcode(u16),
+    /// Something valid that this interpreter doesn't do
+    Unsupported(&'static str),
+    InvalidName,
+    NameNotFound(alloc::string::String),
+    /// A value was the wrong type for what it was used for
+    TypeMismatch,
+    /// Something that can't be stored to was used as a target
+    InvalidTarget,
+    IndexOutOfBounds,
+    DivideByZero,
+    /// A method was called with the wrong number of arguments
+    WrongArgumentCount,
+    /// Methods called each other too deeply
+    RecursionLimit,
+    /// A `While` loop ran for too long - probably waiting for hardware that isn't going to answer
+    LoopLimit,
+    /// The AML executed `Fatal`
+    Fatal { fatal_type: u8, code: u32, argument: u64 },
+    /// `init` hasn't loaded the tables (or there's no DSDT)
+    NoNamespace,
+}
+
+/// # Handler
+///
+/// How AML reaches the hardware. `width` is the access size in bits (8, 16, 32 or 64)
+pub trait Handler {
+    fn read_memory(&mut self, address: u64, width: u8) -> u64;
+    fn write_memory(&mut self, address: u64, width: u8, value: u64);
+    fn read_io(&mut self, port: u16, width: u8) -> u64;
+    fn write_io(&mut self, port: u16, width: u8, value: u64);
+    fn read_pci(&mut self, segment: u16, bus: u8, device: u8, function: u8, offset: u16, width: u8) -> u64;
+    #[allow(clippy::too_many_arguments)]
+    fn write_pci(&mut self, segment: u16, bus: u8, device: u8, function: u8, offset: u16, width: u8, value: u64);
+
+    /// `Stall` - busy wait for a few microseconds
+    fn stall(&mut self, _micros: u64) {}
+    /// `Sleep` - wait for some milliseconds
+    fn sleep(&mut self, _millis: u64) {}
+}
+
+/// # KernelHandler
+///
+/// The real hardware. Memory goes through the physical memory mapping, I/O through ports, and PCI config
+/// space through [pci::config](../../driver/pci/config/index.html) (ECAM, or the legacy I/O ports)
+pub struct KernelHandler;
+
+impl Handler for KernelHandler {
+    fn read_memory(&mut self, address: u64, width: u8) -> u64 {
+        let pointer = memory::phys_to_virt(PhysAddr::new(address)).as_ptr::<u8>();
+        unsafe {
+            match width {
+                8 => core::ptr::read_volatile(pointer) as u64,
+                16 => core::ptr::read_volatile(pointer as *const u16) as u64,
+                32 => core::ptr::read_volatile(pointer as *const u32) as u64,
+                _ => core::ptr::read_volatile(pointer as *const u64),
+            }
+        }
+    }
+
+    fn write_memory(&mut self, address: u64, width: u8, value: u64) {
+        let pointer = memory::phys_to_virt(PhysAddr::new(address)).as_mut_ptr::<u8>();
+        unsafe {
+            match width {
+                8 => core::ptr::write_volatile(pointer, value as u8),
+                16 => core::ptr::write_volatile(pointer as *mut u16, value as u16),
+                32 => core::ptr::write_volatile(pointer as *mut u32, value as u32),
+                _ => core::ptr::write_volatile(pointer as *mut u64, value),
+            }
+        }
+    }
+
+    fn read_io(&mut self, port: u16, width: u8) -> u64 {
+        unsafe {
+            match width {
+                8 => Port::<u8>::new(port).read() as u64,
+                16 => Port::<u16>::new(port).read() as u64,
+                _ => Port::<u32>::new(port).read() as u64,
+            }
+        }
+    }
+
+    fn write_io(&mut self, port: u16, width: u8, value: u64) {
+        unsafe {
+            match width {
+                8 => Port::<u8>::new(port).write(value as u8),
+                16 => Port::<u16>::new(port).write(value as u16),
+                _ => Port::<u32>::new(port).write(value as u32),
+            }
+        }
+    }
+
+    fn read_pci(&mut self, segment: u16, bus: u8, device: u8, function: u8, offset: u16, width: u8) -> u64 {
+        let address = PciAddress::new(segment, bus, device, function);
+        match width {
+            8 => config::read_u8(address, offset) as u64,
+            16 => config::read_u16(address, offset) as u64,
+            _ => config::read_u32(address, offset) as u64,
+        }
+    }
+
+    fn write_pci(&mut self, segment: u16, bus: u8, device: u8, function: u8, offset: u16, width: u8, value: u64) {
+        let address = PciAddress::new(segment, bus, device, function);
+        match width {
+            8 => config::write_u8(address, offset, value as u8),
+            16 => config::write_u16(address, offset, value as u16),
+            _ => config::write_u32(address, offset, value as u32),
+        }
+    }
+
+    fn stall(&mut self, micros: u64) {
+        smp::delay_us(micros.max(1));
+    }
+
+    fn sleep(&mut self, millis: u64) {
+        for _ in 0..millis {
+            smp::delay_us(1000);
+        }
+    }
+}
+
+/// # init
+///
+/// Load the DSDT and every SSDT into the kernel's namespace. Called by `acpi::init`
+pub fn init() {
+    let dsdt = match super::dsdt() {
+        Some(dsdt) => dsdt,
+        None => return,
+    };
+
+    let mut interpreter = Interpreter::new(Box::new(KernelHandler));
+    if let Err(err) = interpreter.load(dsdt.aml(), dsdt.header.revision) {
+        serial_println!("[LOG] Error loading the DSDT: {:?}", err);
+    }
+    for ssdt in super::ssdts() {
+        if let Err(err) = interpreter.load(ssdt.aml(), ssdt.header.revision) {
+            serial_println!("[LOG] Error loading an SSDT: {:?}", err);
+        }
+    }
+    serial_println!("[LOG] AML namespace loaded, {} objects", interpreter.object_count());
+
+    let _ = INTERPRETER.try_init_once(|| Mutex::new(interpreter));
+}
+
+/// Evaluate the object at `path` (like `\_SB.PCI0._PRT`) in the kernel's namespace. Methods are called
+/// with `args`, fields are read, and anything else is returned as it is
+pub fn evaluate(path: &str, args: Vec<AmlValue>) -> Result<AmlValue, AmlError> {
+    let interpreter = INTERPRETER.try_get().map_err(|_| AmlError::NoNamespace)?;
+    interpreter.lock().evaluate(path, args)
+}
+
+/// Run `f` with the kernel's interpreter, for anything more involved than `evaluate` (like walking the
+/// namespace). `None` if nothing is loaded
+pub fn with_interpreter<R>(f: impl FnOnce(&mut Interpreter) -> R) -> Option<R> {
+    let interpreter = INTERPRETER.try_get().ok()?;
+    let mut interpreter = interpreter.lock();
+    Some(f(&mut interpreter))
+}
+
+/// # hardware_id
+///
+/// The hardware ID (`_HID`) of the device at `path`, like `PNP0A03`. Compressed EISA IDs (integers) are
+/// decoded to the same string form. `None` if the device doesn't have one
+pub fn hardware_id(path: &str) -> Option<String> {
+    match evaluate(&format!("{}._HID", path), Vec::new()).ok()? {
+        AmlValue::Integer(id) => Some(decode_eisa_id(id as u32)),
+        AmlValue::String(id) => Some(id),
+        _ => None,
+    }
+}
+
+/// Whether the device at `path` is present, according to bit 0 of its `_STA`. No `_STA` means it is
+pub fn is_present(path: &str) -> bool {
+    match evaluate(&format!("{}._STA", path), Vec::new()) {
+        Ok(status) => status.as_integer().map_or(true, |status| status & 1 != 0),
+        Err(_) => true,
+    }
+}
+
+/// Decode a compressed EISA ID. It is stored big endian: three 5 bit letters (`A` is 1), then a 16 bit
+/// product number, shown as hex
+pub fn decode_eisa_id(id: u32) -> String {
+    let id = id.swap_bytes();
+    let letter = |shift: u32| (((id >> shift) & 0x1F) as u8 + b'@') as char;
+    format!("{}{}{}{:04X}", letter(26), letter(21), letter(16), id & 0xFFFF)
+}
+
+/* Testing */
+
+#[test_case]
+fn eisa_ids() {
+    assert_eq!(decode_eisa_id(0x030A_D041), "PNP0A03"); // PCI host bridge
+    assert_eq!(decode_eisa_id(0x0303_D041), "PNP0303"); // PS/2 keyboard
+}
@@ -0,0 +1,1670 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use alloc::{format, vec};
+use core::cmp::Ordering;
+use crate::serial_println;
+use super::name::{self, AmlName, ROOT};
+use super::opcode::*;
+use super::value::{AccessType, AmlValue, BufferSource, FieldKind, FieldUnit, RegionSpace, UpdateRule};
+use super::{AmlError, Handler};
+
+/// How deeply methods can call each other
+const MAX_CALL_DEPTH: usize = 64;
+/// How many times a `While` loop can go round before we give up on it
+const MAX_LOOP_ITERATIONS: u64 = 1_000_000;
+/// The biggest `Buffer` we make - its size comes from the table, and the heap is small
+const MAX_BUFFER_SIZE: usize = 64 * 1024;
+
+/// `_OSI` strings we answer yes to. Firmware tends to only turn features on for the newest Windows it knows
+const SUPPORTED_INTERFACES: &[&str] = &[
+    "Windows 2000",
+    "Windows 2001",
+    "Windows 2001 SP1",
+    "Windows 2001 SP2",
+    "Windows 2006",
+    "Windows 2009",
+    "Windows 2012",
+    "Windows 2015",
+    "Module Device",
+    "Processor Device",
+    "3.0 Thermal Model",
+    "Processor Aggregator Device",
+];
+
+/// What running a term did to the flow of control
+enum Flow {
+    Next,
+    Return(AmlValue),
+    Break,
+    Continue,
+}
+
+/// Somewhere a result can be stored
+#[derive(Debug, Clone)]
+enum Target {
+    None,
+    Debug,
+    Local(usize),
+    Arg(usize),
+    Name(String),
+    /// An element of a package or buffer
+    Index(Box<Target>, usize),
+}
+
+/// The arguments and locals of the running method, and the objects it has created (deleted when it returns)
+struct Frame {
+    args: Vec<AmlValue>,
+    locals: [AmlValue; 8],
+    created: Vec<String>,
+}
+
+impl Frame {
+    fn new(args: Vec<AmlValue>) -> Self {
+        const UNINITIALIZED: AmlValue = AmlValue::Uninitialized;
+        Frame { args, locals: [UNINITIALIZED; 8], created: Vec::new() }
+    }
+}
+
+/// A position in some AML bytecode
+struct Cursor {
+    code: &'static [u8],
+    pos: usize,
+}
+
+impl Cursor {
+    fn new(code: &'static [u8]) -> Self {
+        Cursor { code, pos: 0 }
+    }
+
+    fn peek(&self) -> Result<u8, AmlError> {
+        self.code.get(self.pos).copied().ok_or(AmlError::UnexpectedEnd)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<u8> {
+        self.code.get(self.pos + offset).copied()
+    }
+
+    fn byte(&mut self) -> Result<u8, AmlError> {
+        let byte = self.peek()?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'static [u8], AmlError> {
+        let bytes = self.code.get(self.pos..self.pos + count).ok_or(AmlError::UnexpectedEnd)?;
+        self.pos += count;
+        Ok(bytes)
+    }
+
+    /// The rest of a package ending at `end`. Fails if a malformed name or size already took us past it
+    fn up_to(&self, end: usize) -> Result<&'static [u8], AmlError> {
+        self.code.get(self.pos..end).ok_or(AmlError::UnexpectedEnd)
+    }
+
+    /// A little endian integer of `size` bytes
+    fn integer(&mut self, size: usize) -> Result<u64, AmlError> {
+        let bytes = self.bytes(size)?;
+        Ok(bytes.iter().rev().fold(0, |value, &byte| value << 8 | byte as u64))
+    }
+
+    /// A PkgLength. The top two bits of the first byte say how many more bytes follow
+    fn pkg_length(&mut self) -> Result<usize, AmlError> {
+        let lead = self.byte()?;
+        let extra = (lead >> 6) as usize;
+        if extra == 0 {
+            return Ok((lead & 0x3F) as usize);
+        }
+        let mut length = (lead & 0x0F) as usize;
+        for i in 0..extra {
+            length |= (self.byte()? as usize) << (4 + 8 * i);
+        }
+        Ok(length)
+    }
+
+    /// Read a PkgLength and return where the package ends. The length counts from the start of the PkgLength
+    fn pkg_end(&mut self) -> Result<usize, AmlError> {
+        let start = self.pos;
+        let end = start + self.pkg_length()?;
+        if end > self.code.len() {
+            return Err(AmlError::UnexpectedEnd);
+        }
+        Ok(end)
+    }
+
+    fn name_seg(&mut self) -> Result<String, AmlError> {
+        let bytes = self.bytes(4)?;
+        if !name::is_lead_name_char(bytes[0]) || !bytes[1..].iter().all(|&b| name::is_name_char(b)) {
+            return Err(AmlError::InvalidName);
+        }
+        Ok(bytes.iter().map(|&b| b as char).collect())
+    }
+
+    fn name_string(&mut self) -> Result<AmlName, AmlError> {
+        let mut name = AmlName { root: false, parents: 0, segments: Vec::new() };
+        if self.peek()? == b'\\' {
+            name.root = true;
+            self.pos += 1;
+        } else {
+            while self.peek()? == b'^' {
+                name.parents += 1;
+                self.pos += 1;
+            }
+        }
+
+        let count = match self.peek()? {
+            name::DUAL_NAME_PREFIX => {
+                self.pos += 1;
+                2
+            }
+            name::MULTI_NAME_PREFIX => {
+                self.pos += 1;
+                self.byte()? as usize
+            }
+            ZERO_OP => {
+                self.pos += 1; // NullName
+                0
+            }
+            _ => 1,
+        };
+        for _ in 0..count {
+            name.segments.push(self.name_seg()?);
+        }
+        Ok(name)
+    }
+}
+
+/// `\_OSI` - does the OS support an interface? We pretend to be a recent Windows, like everyone else
+fn osi(args: &[AmlValue]) -> Result<AmlValue, AmlError> {
+    let interface = args.first().ok_or(AmlError::WrongArgumentCount)?.as_string()?;
+    let supported = SUPPORTED_INTERFACES.contains(&interface.as_str());
+    Ok(AmlValue::Integer(if supported { !0 } else { 0 }))
+}
+
+/// # Interpreter
+///
+/// An ACPI namespace, and everything needed to run the AML in it.
+///
+/// ```
+/// let mut interpreter = Interpreter::new(Box::new(KernelHandler));
+/// interpreter.load(dsdt.aml(), dsdt.header.revision)?;
+/// let hid = interpreter.evaluate("\\_SB.PCI0._HID", Vec::new())?;
+/// ```
+pub struct Interpreter {
+    /// Every named object, by absolute path
+    namespace: BTreeMap<String, AmlValue>,
+    handler: Box<dyn Handler + Send>,
+    /// Integers are 64 bits wide, unless the DSDT is revision 1
+    integer_width_64: bool,
+    /// How many tables have been loaded
+    tables: usize,
+    depth: usize,
+}
+
+impl Interpreter {
+    /// Create an empty namespace, holding only the predefined scopes and objects
+    pub fn new(handler: Box<dyn Handler + Send>) -> Self {
+        let mut namespace = BTreeMap::new();
+        namespace.insert(ROOT.to_string(), AmlValue::Scope);
+        for scope in &["\\_GPE", "\\_PR_", "\\_SB_", "\\_SI_", "\\_TZ_"] {
+            namespace.insert(scope.to_string(), AmlValue::Scope);
+        }
+        namespace.insert("\\_OSI".to_string(), AmlValue::NativeMethod { arg_count: 1, function: osi });
+        namespace.insert("\\_OS_".to_string(), AmlValue::String("Microsoft Windows NT".to_string()));
+        namespace.insert("\\_REV".to_string(), AmlValue::Integer(2));
+
+        Interpreter { namespace, handler, integer_width_64: true, tables: 0, depth: 0 }
+    }
+
+    /// Load a table's AML (the bytes after the header) into the namespace. Load the DSDT first - its
+    /// `revision` decides whether integers are 32 or 64 bits. On error, whatever loaded before it stays loaded
+    pub fn load(&mut self, aml: &'static [u8], revision: u8) -> Result<(), AmlError> {
+        if self.tables == 0 {
+            self.integer_width_64 = revision >= 2;
+        }
+        self.tables += 1;
+
+        let mut cursor = Cursor::new(aml);
+        let mut frame = Frame::new(Vec::new());
+        let result = self.run(&mut cursor, aml.len(), ROOT, &mut frame);
+        if let Err(err) = &result {
+            serial_println!("[LOG] AML error at offset {:#x}: {:?}", cursor.pos, err);
+        }
+        self.resolve_forward_references();
+        result.map(|_| ())
+    }
+
+    /// Evaluate the object at `path`. Methods are called with `args`, fields are read, references are
+    /// followed, and anything else is returned as it is
+    pub fn evaluate(&mut self, path: &str, args: Vec<AmlValue>) -> Result<AmlValue, AmlError> {
+        let path = name::normalize(path)?;
+        let mut frame = Frame::new(Vec::new());
+        self.value_of(&path, args, &mut frame)
+    }
+
+    /// The object at `path`, without evaluating it
+    pub fn get(&self, path: &str) -> Option<&AmlValue> {
+        self.namespace.get(&name::normalize(path).ok()?)
+    }
+
+    /// The paths of the objects directly inside `path`
+    pub fn children(&self, path: &str) -> Vec<String> {
+        let path = match name::normalize(path) {
+            Ok(path) => path,
+            Err(_) => return Vec::new(),
+        };
+        self.namespace
+            .keys()
+            .filter(|child| name::parent(child).as_deref() == Some(path.as_str()))
+            .cloned()
+            .collect()
+    }
+
+    /// The paths of every device in the namespace
+    pub fn devices(&self) -> Vec<String> {
+        self.namespace
+            .iter()
+            .filter(|(_, value)| matches!(value, AmlValue::Device))
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    /// How many objects are in the namespace
+    pub fn object_count(&self) -> usize {
+        self.namespace.len()
+    }
+}
+
+/* Names and objects */
+
+impl Interpreter {
+    /// Find the object `name` refers to from `scope`, following the search rules
+    fn resolve(&self, name: &AmlName, scope: &str) -> Option<String> {
+        if !name.uses_search_rules() {
+            let path = name.absolute(scope);
+            return if self.namespace.contains_key(&path) { Some(path) } else { None };
+        }
+
+        let mut scope = scope.to_string();
+        loop {
+            let candidate = name::join(&scope, &name.segments[0]);
+            if self.namespace.contains_key(&candidate) {
+                return Some(candidate);
+            }
+            scope = name::parent(&scope)?;
+        }
+    }
+
+    /// Packages can name objects declared further on in the table (q35's `_PRT` names link devices declared
+    /// after it), so those names couldn't be searched for while loading. Search for them again now. A
+    /// single segment name that wasn't found was made absolute in the scope it was used in, so that is
+    /// where the search starts
+    fn resolve_forward_references(&mut self) {
+        fn fix(namespace: &BTreeMap<String, AmlValue>, value: &mut AmlValue) {
+            match value {
+                AmlValue::Package(elements) => elements.iter_mut().for_each(|element| fix(namespace, element)),
+                AmlValue::Reference(path) if !namespace.contains_key(path.as_str()) => {
+                    let segment = match name::split(path).last() {
+                        Some(segment) => segment.to_string(),
+                        None => return,
+                    };
+                    let mut scope = name::parent(path);
+                    while let Some(current) = scope {
+                        let candidate = name::join(&current, &segment);
+                        if namespace.contains_key(&candidate) {
+                            *path = candidate;
+                            return;
+                        }
+                        scope = name::parent(&current);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let packages: Vec<String> = self
+            .namespace
+            .iter()
+            .filter(|(_, value)| matches!(value, AmlValue::Package(_)))
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in packages {
+            if let Some(mut package) = self.namespace.remove(&path) {
+                fix(&self.namespace, &mut package);
+                self.namespace.insert(path, package);
+            }
+        }
+    }
+
+    /// Like `resolve`, but an error if nothing is found
+    fn resolve_existing(&self, name: &AmlName, scope: &str) -> Result<String, AmlError> {
+        self.resolve(name, scope).ok_or_else(|| AmlError::NameNotFound(name.to_string()))
+    }
+
+    /// Add an object to the namespace. Objects created by a method are deleted when it returns
+    fn create(&mut self, path: String, value: AmlValue, frame: &mut Frame) {
+        if self.depth > 0 {
+            frame.created.push(path.clone());
+        }
+        self.namespace.insert(path, value);
+    }
+
+    /// The value of the object at `path` - calling it if it's a method, reading it if it's a field
+    fn value_of(&mut self, path: &str, args: Vec<AmlValue>, frame: &mut Frame) -> Result<AmlValue, AmlError> {
+        let object = self.namespace.get(path).cloned().ok_or_else(|| AmlError::NameNotFound(path.to_string()))?;
+        match object {
+            AmlValue::Method { .. } | AmlValue::NativeMethod { .. } => self.invoke(path, args),
+            AmlValue::Field(field) => self.read_field(&field),
+            AmlValue::BufferField { source, bit_offset, bit_length } => {
+                let buffer = self.buffer_of(&source, frame)?;
+                Ok(extract_bits(&buffer, bit_offset, bit_length))
+            }
+            AmlValue::Reference(target) => self.value_of(&target, args, frame),
+            other => Ok(other),
+        }
+    }
+
+    /// Call the method at `path`
+    fn invoke(&mut self, path: &str, args: Vec<AmlValue>) -> Result<AmlValue, AmlError> {
+        match self.namespace.get(path).cloned() {
+            Some(AmlValue::NativeMethod { arg_count, function }) => {
+                if args.len() != arg_count as usize {
+                    return Err(AmlError::WrongArgumentCount);
+                }
+                function(&args)
+            }
+            Some(AmlValue::Method { code, arg_count, .. }) => {
+                if args.len() != arg_count as usize {
+                    return Err(AmlError::WrongArgumentCount);
+                }
+                if self.depth >= MAX_CALL_DEPTH {
+                    return Err(AmlError::RecursionLimit);
+                }
+
+                let mut frame = Frame::new(args);
+                let mut cursor = Cursor::new(code);
+                self.depth += 1;
+                let result = self.run(&mut cursor, code.len(), path, &mut frame);
+                self.depth -= 1;
+
+                for created in frame.created.drain(..) {
+                    self.namespace.remove(&created);
+                }
+                match result? {
+                    Flow::Return(value) => Ok(value),
+                    _ => Ok(AmlValue::Integer(0)),
+                }
+            }
+            _ => Err(AmlError::TypeMismatch),
+        }
+    }
+
+    fn mask(&self, value: u64) -> u64 {
+        if self.integer_width_64 { value } else { value & 0xFFFF_FFFF }
+    }
+
+    fn ones(&self) -> u64 {
+        self.mask(!0)
+    }
+
+    fn boolean(&self, value: bool) -> AmlValue {
+        AmlValue::Integer(if value { self.ones() } else { 0 })
+    }
+}
+
+/* Running terms */
+
+impl Interpreter {
+    /// Run terms until `end`, or until something changes the flow of control
+    fn run(&mut self, cursor: &mut Cursor, end: usize, scope: &str, frame: &mut Frame) -> Result<Flow, AmlError> {
+        while cursor.pos < end {
+            match self.term(cursor, scope, frame)? {
+                Flow::Next => {}
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    /// Run one term - a statement, a named object declaration, or an expression (whose result is thrown away)
+    fn term(&mut self, cursor: &mut Cursor, scope: &str, frame: &mut Frame) -> Result<Flow, AmlError> {
+        match cursor.peek()? {
+            NAME_OP => {
+                cursor.byte()?;
+                let path = cursor.name_string()?.absolute(scope);
+                let value = self.term_arg(cursor, scope, frame)?;
+                self.create(path, value, frame);
+            }
+            SCOPE_OP => {
+                cursor.byte()?;
+                let end = cursor.pkg_end()?;
+                let name = cursor.name_string()?;
+                // Scopes usually refer to existing objects, but are allowed to create one
+                let path = self.resolve(&name, scope).unwrap_or_else(|| name.absolute(scope));
+                if !self.namespace.contains_key(&path) {
+                    self.create(path.clone(), AmlValue::Scope, frame);
+                }
+                self.scoped_block(cursor, end, &path, frame)?;
+            }
+            METHOD_OP => {
+                cursor.byte()?;
+                let end = cursor.pkg_end()?;
+                let path = cursor.name_string()?.absolute(scope);
+                let flags = cursor.byte()?;
+                let code = cursor.up_to(end)?;
+                self.create(path, AmlValue::Method { code, arg_count: flags & 0x7, serialized: flags & 0x8 != 0 }, frame);
+                cursor.pos = end;
+            }
+            ALIAS_OP => {
+                cursor.byte()?;
+                let source = cursor.name_string()?;
+                let source = self.resolve(&source, scope).unwrap_or_else(|| source.absolute(scope));
+                let alias = cursor.name_string()?.absolute(scope);
+                self.create(alias, AmlValue::Reference(source), frame);
+            }
+            EXTERNAL_OP => {
+                // Declares something defined in another table - nothing to do
+                cursor.byte()?;
+                cursor.name_string()?;
+                cursor.bytes(2)?; // object type, argument count
+            }
+            IF_OP => return self.if_else(cursor, scope, frame),
+            ELSE_OP => {
+                // An Else whose If was taken - `if_else` normally skips these
+                cursor.byte()?;
+                cursor.pos = cursor.pkg_end()?;
+            }
+            WHILE_OP => return self.while_loop(cursor, scope, frame),
+            RETURN_OP => {
+                cursor.byte()?;
+                let value = self.term_arg(cursor, scope, frame)?;
+                return Ok(Flow::Return(value));
+            }
+            BREAK_OP => {
+                cursor.byte()?;
+                return Ok(Flow::Break);
+            }
+            CONTINUE_OP => {
+                cursor.byte()?;
+                return Ok(Flow::Continue);
+            }
+            NOOP_OP | BREAKPOINT_OP => {
+                cursor.byte()?;
+            }
+            NOTIFY_OP => {
+                cursor.byte()?;
+                let object = self.target(cursor, scope, frame)?;
+                let value = self.integer_arg(cursor, scope, frame)?;
+                serial_println!("[LOG] AML Notify({:?}, {:#x})", object, value);
+            }
+            CREATE_BIT_FIELD_OP | CREATE_BYTE_FIELD_OP | CREATE_WORD_FIELD_OP | CREATE_DWORD_FIELD_OP
+            | CREATE_QWORD_FIELD_OP => {
+                let op = cursor.byte()?;
+                let source = self.buffer_source(cursor, scope)?;
+                let index = self.integer_arg(cursor, scope, frame)?;
+                let path = cursor.name_string()?.absolute(scope);
+                let (bit_offset, bit_length) = match op {
+                    CREATE_BIT_FIELD_OP => (index, 1),
+                    CREATE_BYTE_FIELD_OP => (index * 8, 8),
+                    CREATE_WORD_FIELD_OP => (index * 8, 16),
+                    CREATE_DWORD_FIELD_OP => (index * 8, 32),
+                    _ => (index * 8, 64),
+                };
+                self.create(path, AmlValue::BufferField { source, bit_offset, bit_length }, frame);
+            }
+            EXT_OP_PREFIX => return self.ext_term(cursor, scope, frame),
+            _ => {
+                self.term_arg(cursor, scope, frame)?;
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    /// Terms starting with the extended opcode prefix
+    fn ext_term(&mut self, cursor: &mut Cursor, scope: &str, frame: &mut Frame) -> Result<Flow, AmlError> {
+        let op = cursor.peek_at(1).ok_or(AmlError::UnexpectedEnd)?;
+        match op {
+            EXT_MUTEX_OP => {
+                cursor.bytes(2)?;
+                let path = cursor.name_string()?.absolute(scope);
+                let sync_level = cursor.byte()? & 0x0F;
+                self.create(path, AmlValue::Mutex { sync_level }, frame);
+            }
+            EXT_EVENT_OP => {
+                cursor.bytes(2)?;
+                let path = cursor.name_string()?.absolute(scope);
+                self.create(path, AmlValue::Event, frame);
+            }
+            EXT_OP_REGION_OP => {
+                cursor.bytes(2)?;
+                let path = cursor.name_string()?.absolute(scope);
+                let space = RegionSpace::from(cursor.byte()?);
+                let offset = self.integer_arg(cursor, scope, frame)?;
+                let length = self.integer_arg(cursor, scope, frame)?;
+                self.create(path, AmlValue::OpRegion { space, offset, length }, frame);
+            }
+            EXT_FIELD_OP => {
+                cursor.bytes(2)?;
+                let end = cursor.pkg_end()?;
+                let region = cursor.name_string()?;
+                let region = self.resolve(&region, scope).unwrap_or_else(|| region.absolute(scope));
+                let flags = cursor.byte()?;
+                self.field_list(cursor, end, scope, flags, FieldKind::Region(region), frame)?;
+            }
+            EXT_INDEX_FIELD_OP => {
+                cursor.bytes(2)?;
+                let end = cursor.pkg_end()?;
+                let index = cursor.name_string()?;
+                let index = self.resolve(&index, scope).unwrap_or_else(|| index.absolute(scope));
+                let data = cursor.name_string()?;
+                let data = self.resolve(&data, scope).unwrap_or_else(|| data.absolute(scope));
+                let flags = cursor.byte()?;
+                self.field_list(cursor, end, scope, flags, FieldKind::Index { index, data }, frame)?;
+            }
+            EXT_BANK_FIELD_OP => {
+                cursor.bytes(2)?;
+                serial_println!("[LOG] AML BankField isn't supported, skipping it");
+                cursor.pos = cursor.pkg_end()?;
+            }
+            EXT_DEVICE_OP | EXT_THERMAL_ZONE_OP => {
+                cursor.bytes(2)?;
+                let end = cursor.pkg_end()?;
+                let path = cursor.name_string()?.absolute(scope);
+                let value = if op == EXT_DEVICE_OP { AmlValue::Device } else { AmlValue::ThermalZone };
+                self.create(path.clone(), value, frame);
+                self.scoped_block(cursor, end, &path, frame)?;
+            }
+            EXT_PROCESSOR_OP => {
+                cursor.bytes(2)?;
+                let end = cursor.pkg_end()?;
+                let path = cursor.name_string()?.absolute(scope);
+                let id = cursor.byte()?;
+                let block_address = cursor.integer(4)? as u32;
+                let block_length = cursor.byte()?;
+                self.create(path.clone(), AmlValue::Processor { id, block_address, block_length }, frame);
+                self.scoped_block(cursor, end, &path, frame)?;
+            }
+            EXT_POWER_RES_OP => {
+                cursor.bytes(2)?;
+                let end = cursor.pkg_end()?;
+                let path = cursor.name_string()?.absolute(scope);
+                let system_level = cursor.byte()?;
+                let resource_order = cursor.integer(2)? as u16;
+                self.create(path.clone(), AmlValue::PowerResource { system_level, resource_order }, frame);
+                self.scoped_block(cursor, end, &path, frame)?;
+            }
+            EXT_CREATE_FIELD_OP => {
+                cursor.bytes(2)?;
+                let source = self.buffer_source(cursor, scope)?;
+                let bit_offset = self.integer_arg(cursor, scope, frame)?;
+                let bit_length = self.integer_arg(cursor, scope, frame)?;
+                let path = cursor.name_string()?.absolute(scope);
+                self.create(path, AmlValue::BufferField { source, bit_offset, bit_length }, frame);
+            }
+            EXT_STALL_OP | EXT_SLEEP_OP => {
+                cursor.bytes(2)?;
+                let time = self.integer_arg(cursor, scope, frame)?;
+                if op == EXT_STALL_OP {
+                    self.handler.stall(time);
+                } else {
+                    self.handler.sleep(time);
+                }
+            }
+            EXT_RELEASE_OP | EXT_RESET_OP | EXT_SIGNAL_OP => {
+                // We run one method at a time, with the interpreter locked, so mutexes and events have nothing to do
+                cursor.bytes(2)?;
+                self.target(cursor, scope, frame)?;
+            }
+            EXT_FATAL_OP => {
+                cursor.bytes(2)?;
+                let fatal_type = cursor.byte()?;
+                let code = cursor.integer(4)? as u32;
+                let argument = self.integer_arg(cursor, scope, frame)?;
+                return Err(AmlError::Fatal { fatal_type, code, argument });
+            }
+            _ => {
+                self.term_arg(cursor, scope, frame)?;
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    /// The terms of a scope, device, processor... run with that object as the scope. Method frames carry
+    /// on through these, so objects declared inside are still cleaned up
+    fn scoped_block(&mut self, cursor: &mut Cursor, end: usize, path: &str, frame: &mut Frame) -> Result<(), AmlError> {
+        match self.run(cursor, end, path, frame)? {
+            Flow::Next => {
+                cursor.pos = end;
+                Ok(())
+            }
+            _ => Err(AmlError::Unsupported("control flow out of a scope")),
+        }
+    }
+
+    fn if_else(&mut self, cursor: &mut Cursor, scope: &str, frame: &mut Frame) -> Result<Flow, AmlError> {
+        cursor.byte()?;
+        let end = cursor.pkg_end()?;
+        let predicate = self.integer_arg(cursor, scope, frame)? != 0;
+
+        let flow = if predicate { self.run(cursor, end, scope, frame)? } else { Flow::Next };
+        cursor.pos = end;
+
+        if cursor.peek_at(0) == Some(ELSE_OP) {
+            cursor.byte()?;
+            let else_end = cursor.pkg_end()?;
+            if predicate {
+                cursor.pos = else_end;
+            } else {
+                let flow = self.run(cursor, else_end, scope, frame)?;
+                cursor.pos = else_end;
+                return Ok(flow);
+            }
+        }
+        Ok(flow)
+    }
+
+    fn while_loop(&mut self, cursor: &mut Cursor, scope: &str, frame: &mut Frame) -> Result<Flow, AmlError> {
+        cursor.byte()?;
+        let end = cursor.pkg_end()?;
+        let predicate_start = cursor.pos;
+
+        for _ in 0..MAX_LOOP_ITERATIONS {
+            cursor.pos = predicate_start;
+            if self.integer_arg(cursor, scope, frame)? == 0 {
+                cursor.pos = end;
+                return Ok(Flow::Next);
+            }
+            match self.run(cursor, end, scope, frame)? {
+                Flow::Break => {
+                    cursor.pos = end;
+                    return Ok(Flow::Next);
+                }
+                Flow::Return(value) => return Ok(Flow::Return(value)),
+                Flow::Next | Flow::Continue => {}
+            }
+        }
+        Err(AmlError::LoopLimit)
+    }
+
+    /// Parse a field list, adding a field for each named entry
+    fn field_list(
+        &mut self,
+        cursor: &mut Cursor,
+        end: usize,
+        scope: &str,
+        flags: u8,
+        kind: FieldKind,
+        frame: &mut Frame,
+    ) -> Result<(), AmlError> {
+        let mut access = AccessType::from_flags(flags);
+        let update = UpdateRule::from_flags(flags);
+        let mut bit_offset = 0;
+
+        while cursor.pos < end {
+            match cursor.peek()? {
+                FIELD_RESERVED => {
+                    cursor.byte()?;
+                    bit_offset += cursor.pkg_length()? as u64;
+                }
+                FIELD_ACCESS => {
+                    cursor.byte()?;
+                    access = AccessType::from_flags(cursor.byte()?);
+                    cursor.byte()?; // access attributes, only used for SMBus and friends
+                }
+                FIELD_EXTENDED_ACCESS => {
+                    cursor.byte()?;
+                    access = AccessType::from_flags(cursor.byte()?);
+                    cursor.bytes(2)?;
+                }
+                FIELD_CONNECT => return Err(AmlError::Unsupported("ConnectField")),
+                _ => {
+                    let path = name::join(scope, &cursor.name_seg()?);
+                    let bit_length = cursor.pkg_length()? as u64;
+                    let field = FieldUnit { kind: kind.clone(), bit_offset, bit_length, access, update };
+                    self.create(path, AmlValue::Field(field), frame);
+                    bit_offset += bit_length;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The buffer operand of `CreateDWordField` and friends - it has to be somewhere we can write back to
+    fn buffer_source(&mut self, cursor: &mut Cursor, scope: &str) -> Result<BufferSource, AmlError> {
+        match cursor.peek()? {
+            op @ LOCAL0_OP..=LOCAL7_OP => {
+                cursor.byte()?;
+                Ok(BufferSource::Local((op - LOCAL0_OP) as usize))
+            }
+            op @ ARG0_OP..=ARG6_OP => {
+                cursor.byte()?;
+                Ok(BufferSource::Arg((op - ARG0_OP) as usize))
+            }
+            byte if name::is_name_start(byte) => {
+                let name = cursor.name_string()?;
+                Ok(BufferSource::Name(self.resolve_existing(&name, scope)?))
+            }
+            _ => Err(AmlError::Unsupported("buffer field of a temporary buffer")),
+        }
+    }
+}
+
+/* Expressions */
+
+impl Interpreter {
+    fn integer_arg(&mut self, cursor: &mut Cursor, scope: &str, frame: &mut Frame) -> Result<u64, AmlError> {
+        self.term_arg(cursor, scope, frame)?.as_integer()
+    }
+
+    /// Evaluate an expression
+    fn term_arg(&mut self, cursor: &mut Cursor, scope: &str, frame: &mut Frame) -> Result<AmlValue, AmlError> {
+        let op = cursor.peek()?;
+        if name::is_name_start(op) {
+            return self.name_term(cursor, scope, frame);
+        }
+
+        cursor.byte()?;
+        match op {
+            ZERO_OP => Ok(AmlValue::Integer(0)),
+            ONE_OP => Ok(AmlValue::Integer(1)),
+            ONES_OP => Ok(AmlValue::Integer(self.ones())),
+            BYTE_PREFIX => Ok(AmlValue::Integer(cursor.integer(1)?)),
+            WORD_PREFIX => Ok(AmlValue::Integer(cursor.integer(2)?)),
+            DWORD_PREFIX => Ok(AmlValue::Integer(cursor.integer(4)?)),
+            QWORD_PREFIX => Ok(AmlValue::Integer(cursor.integer(8)?)),
+            STRING_PREFIX => {
+                let start = cursor.pos;
+                while cursor.byte()? != 0 {}
+                let bytes = &cursor.code[start..cursor.pos - 1];
+                Ok(AmlValue::String(bytes.iter().map(|&b| b as char).collect()))
+            }
+            BUFFER_OP => {
+                let end = cursor.pkg_end()?;
+                let size = self.integer_arg(cursor, scope, frame)? as usize;
+                let initializer = cursor.up_to(end)?;
+                if size > MAX_BUFFER_SIZE || initializer.len() > MAX_BUFFER_SIZE {
+                    return Err(AmlError::Unsupported("buffer too big"));
+                }
+                let mut buffer = vec![0; size.max(initializer.len())];
+                buffer[..initializer.len()].copy_from_slice(initializer);
+                cursor.pos = end;
+                Ok(AmlValue::Buffer(buffer))
+            }
+            PACKAGE_OP | VAR_PACKAGE_OP => {
+                let end = cursor.pkg_end()?;
+                let count = if op == PACKAGE_OP {
+                    cursor.byte()? as usize
+                } else {
+                    self.integer_arg(cursor, scope, frame)? as usize
+                };
+                self.package_elements(cursor, end, count, scope, frame)
+            }
+            op @ LOCAL0_OP..=LOCAL7_OP => Ok(frame.locals[(op - LOCAL0_OP) as usize].clone()),
+            op @ ARG0_OP..=ARG6_OP => {
+                Ok(frame.args.get((op - ARG0_OP) as usize).cloned().unwrap_or(AmlValue::Uninitialized))
+            }
+            STORE_OP => {
+                let value = self.term_arg(cursor, scope, frame)?;
+                let target = self.target(cursor, scope, frame)?;
+                self.store(&target, value.clone(), frame)?;
+                Ok(value)
+            }
+            COPY_OBJECT_OP => {
+                let value = self.term_arg(cursor, scope, frame)?;
+                match self.target(cursor, scope, frame)? {
+                    Target::Name(path) => {
+                        self.namespace.insert(path, value.clone());
+                    }
+                    target => self.store(&target, value.clone(), frame)?,
+                }
+                Ok(value)
+            }
+            ADD_OP | SUBTRACT_OP | MULTIPLY_OP | SHIFT_LEFT_OP | SHIFT_RIGHT_OP | AND_OP | NAND_OP | OR_OP
+            | NOR_OP | XOR_OP | MOD_OP => {
+                let a = self.integer_arg(cursor, scope, frame)?;
+                let b = self.integer_arg(cursor, scope, frame)?;
+                let result = match op {
+                    ADD_OP => a.wrapping_add(b),
+                    SUBTRACT_OP => a.wrapping_sub(b),
+                    MULTIPLY_OP => a.wrapping_mul(b),
+                    SHIFT_LEFT_OP => if b < 64 { a << b } else { 0 },
+                    SHIFT_RIGHT_OP => if b < 64 { a >> b } else { 0 },
+                    AND_OP => a & b,
+                    NAND_OP => !(a & b),
+                    OR_OP => a | b,
+                    NOR_OP => !(a | b),
+                    XOR_OP => a ^ b,
+                    _ => a.checked_rem(b).ok_or(AmlError::DivideByZero)?,
+                };
+                self.store_result(cursor, scope, frame, AmlValue::Integer(self.mask(result)))
+            }
+            DIVIDE_OP => {
+                let dividend = self.integer_arg(cursor, scope, frame)?;
+                let divisor = self.integer_arg(cursor, scope, frame)?;
+                if divisor == 0 {
+                    return Err(AmlError::DivideByZero);
+                }
+                let remainder = self.target(cursor, scope, frame)?;
+                self.store(&remainder, AmlValue::Integer(dividend % divisor), frame)?;
+                self.store_result(cursor, scope, frame, AmlValue::Integer(dividend / divisor))
+            }
+            NOT_OP => {
+                let value = !self.integer_arg(cursor, scope, frame)?;
+                self.store_result(cursor, scope, frame, AmlValue::Integer(self.mask(value)))
+            }
+            FIND_SET_LEFT_BIT_OP | FIND_SET_RIGHT_BIT_OP => {
+                let value = self.integer_arg(cursor, scope, frame)?;
+                let bit = match (value, op) {
+                    (0, _) => 0,
+                    (_, FIND_SET_LEFT_BIT_OP) => 64 - value.leading_zeros() as u64,
+                    _ => value.trailing_zeros() as u64 + 1,
+                };
+                self.store_result(cursor, scope, frame, AmlValue::Integer(bit))
+            }
+            INCREMENT_OP | DECREMENT_OP => {
+                let target = self.target(cursor, scope, frame)?;
+                let value = self.read_target(&target, frame)?.as_integer()?;
+                let value = if op == INCREMENT_OP { value.wrapping_add(1) } else { value.wrapping_sub(1) };
+                let value = AmlValue::Integer(self.mask(value));
+                self.store(&target, value.clone(), frame)?;
+                Ok(value)
+            }
+            LAND_OP | LOR_OP => {
+                let a = self.integer_arg(cursor, scope, frame)? != 0;
+                let b = self.integer_arg(cursor, scope, frame)? != 0;
+                Ok(self.boolean(if op == LAND_OP { a && b } else { a || b }))
+            }
+            LNOT_OP => {
+                let value = self.integer_arg(cursor, scope, frame)?;
+                Ok(self.boolean(value == 0))
+            }
+            LEQUAL_OP | LGREATER_OP | LLESS_OP => {
+                let a = self.term_arg(cursor, scope, frame)?;
+                let b = self.term_arg(cursor, scope, frame)?;
+                let ordering = compare(&a, &b)?;
+                Ok(self.boolean(match op {
+                    LEQUAL_OP => ordering == Ordering::Equal,
+                    LGREATER_OP => ordering == Ordering::Greater,
+                    _ => ordering == Ordering::Less,
+                }))
+            }
+            CONCAT_OP => {
+                let a = self.term_arg(cursor, scope, frame)?;
+                let b = self.term_arg(cursor, scope, frame)?;
+                let result = match &a {
+                    AmlValue::String(string) => AmlValue::String(format!("{}{}", string, b.as_string()?)),
+                    AmlValue::Integer(value) => {
+                        let mut bytes = self.integer_bytes(*value);
+                        bytes.extend(self.integer_bytes(b.as_integer()?));
+                        AmlValue::Buffer(bytes)
+                    }
+                    _ => {
+                        let mut bytes = a.as_buffer()?;
+                        bytes.extend(b.as_buffer()?);
+                        AmlValue::Buffer(bytes)
+                    }
+                };
+                self.store_result(cursor, scope, frame, result)
+            }
+            CONCAT_RES_OP => {
+                // Two resource templates - drop the first one's end tag (2 bytes), the second one keeps its own
+                let mut a = self.term_arg(cursor, scope, frame)?.as_buffer()?;
+                let b = self.term_arg(cursor, scope, frame)?.as_buffer()?;
+                a.truncate(a.len().saturating_sub(2));
+                a.extend(b);
+                self.store_result(cursor, scope, frame, AmlValue::Buffer(a))
+            }
+            SIZE_OF_OP => {
+                let object = self.object_of(cursor, scope, frame)?;
+                let size = match object {
+                    AmlValue::String(string) => string.len(),
+                    AmlValue::Buffer(bytes) => bytes.len(),
+                    AmlValue::Package(elements) => elements.len(),
+                    _ => return Err(AmlError::TypeMismatch),
+                };
+                Ok(AmlValue::Integer(size as u64))
+            }
+            OBJECT_TYPE_OP => {
+                let object = self.object_of(cursor, scope, frame)?;
+                Ok(AmlValue::Integer(object.object_type()))
+            }
+            INDEX_OP => {
+                let source = self.term_arg(cursor, scope, frame)?;
+                let index = self.integer_arg(cursor, scope, frame)? as usize;
+                let element = match source {
+                    AmlValue::Package(elements) => elements.get(index).cloned(),
+                    AmlValue::Buffer(bytes) => bytes.get(index).map(|&byte| AmlValue::Integer(byte as u64)),
+                    AmlValue::String(string) => string.as_bytes().get(index).map(|&byte| AmlValue::Integer(byte as u64)),
+                    _ => return Err(AmlError::TypeMismatch),
+                };
+                let element = element.ok_or(AmlError::IndexOutOfBounds)?;
+                self.store_result(cursor, scope, frame, element)
+            }
+            DEREF_OF_OP => match self.term_arg(cursor, scope, frame)? {
+                AmlValue::Reference(path) => self.value_of(&path, Vec::new(), frame),
+                value => Ok(value),
+            },
+            REF_OF_OP => match self.target(cursor, scope, frame)? {
+                Target::Name(path) => Ok(AmlValue::Reference(path)),
+                target => self.read_target(&target, frame),
+            },
+            TO_INTEGER_OP => {
+                let value = match self.term_arg(cursor, scope, frame)? {
+                    AmlValue::String(string) => parse_integer(&string)?,
+                    value => value.as_integer()?,
+                };
+                self.store_result(cursor, scope, frame, AmlValue::Integer(value))
+            }
+            TO_BUFFER_OP => {
+                let value = self.term_arg(cursor, scope, frame)?;
+                let bytes = match value {
+                    AmlValue::Integer(value) => self.integer_bytes(value),
+                    value => value.as_buffer()?,
+                };
+                self.store_result(cursor, scope, frame, AmlValue::Buffer(bytes))
+            }
+            TO_HEX_STRING_OP | TO_DECIMAL_STRING_OP => {
+                let value = self.term_arg(cursor, scope, frame)?;
+                let string = match (value, op) {
+                    (AmlValue::Integer(value), TO_HEX_STRING_OP) => format!("0x{:X}", value),
+                    (AmlValue::Integer(value), _) => format!("{}", value),
+                    (AmlValue::Buffer(bytes), TO_HEX_STRING_OP) => AmlValue::Buffer(bytes).as_string()?,
+                    (AmlValue::Buffer(bytes), _) => {
+                        let decimal: Vec<String> = bytes.iter().map(|byte| format!("{}", byte)).collect();
+                        decimal.join(",")
+                    }
+                    (value, _) => value.as_string()?,
+                };
+                self.store_result(cursor, scope, frame, AmlValue::String(string))
+            }
+            TO_STRING_OP => {
+                let bytes = self.term_arg(cursor, scope, frame)?.as_buffer()?;
+                let length = self.integer_arg(cursor, scope, frame)? as usize;
+                let string = bytes.iter().take(length).take_while(|&&byte| byte != 0).map(|&b| b as char).collect();
+                self.store_result(cursor, scope, frame, AmlValue::String(string))
+            }
+            MID_OP => {
+                let source = self.term_arg(cursor, scope, frame)?;
+                let index = self.integer_arg(cursor, scope, frame)? as usize;
+                let length = self.integer_arg(cursor, scope, frame)? as usize;
+                let result = match source {
+                    AmlValue::String(string) => {
+                        AmlValue::String(string.chars().skip(index).take(length).collect())
+                    }
+                    value => AmlValue::Buffer(value.as_buffer()?.into_iter().skip(index).take(length).collect()),
+                };
+                self.store_result(cursor, scope, frame, result)
+            }
+            EXT_OP_PREFIX => self.ext_term_arg(cursor, scope, frame),
+            op => Err(AmlError::UnknownOpcode(op as u16)),
+        }
+    }
+
+    /// Expressions starting with the extended opcode prefix (which has already been read)
+    fn ext_term_arg(&mut self, cursor: &mut Cursor, scope: &str, frame: &mut Frame) -> Result<AmlValue, AmlError> {
+        let op = cursor.byte()?;
+        match op {
+            EXT_REVISION_OP => Ok(AmlValue::Integer(1)),
+            EXT_DEBUG_OP => Ok(AmlValue::Uninitialized),
+            EXT_COND_REF_OF_OP => {
+                let exists = match cursor.peek()? {
+                    byte if name::is_name_start(byte) => {
+                        let name = cursor.name_string()?;
+                        self.resolve(&name, scope).map(AmlValue::Reference)
+                    }
+                    _ => {
+                        let target = self.target(cursor, scope, frame)?;
+                        Some(self.read_target(&target, frame)?)
+                    }
+                };
+                let target = self.target(cursor, scope, frame)?;
+                match exists {
+                    Some(reference) => {
+                        self.store(&target, reference, frame)?;
+                        Ok(self.boolean(true))
+                    }
+                    None => Ok(self.boolean(false)),
+                }
+            }
+            EXT_ACQUIRE_OP => {
+                self.target(cursor, scope, frame)?;
+                cursor.integer(2)?; // timeout
+                Ok(AmlValue::Integer(0)) // 0 means we got it
+            }
+            EXT_WAIT_OP => {
+                self.target(cursor, scope, frame)?;
+                self.integer_arg(cursor, scope, frame)?;
+                Ok(AmlValue::Integer(0))
+            }
+            op => Err(AmlError::UnknownOpcode(0x5B00 | op as u16)),
+        }
+    }
+
+    /// A name in an expression - a method call (with its arguments following), or the value of an object
+    fn name_term(&mut self, cursor: &mut Cursor, scope: &str, frame: &mut Frame) -> Result<AmlValue, AmlError> {
+        let name = cursor.name_string()?;
+        let path = self.resolve_existing(&name, scope)?;
+
+        let arg_count = match self.namespace.get(&path) {
+            Some(AmlValue::Method { arg_count, .. }) | Some(AmlValue::NativeMethod { arg_count, .. }) => *arg_count,
+            _ => 0,
+        };
+        let mut args = Vec::with_capacity(arg_count as usize);
+        for _ in 0..arg_count {
+            args.push(self.term_arg(cursor, scope, frame)?);
+        }
+        self.value_of(&path, args, frame)
+    }
+
+    /// The operand of `SizeOf`/`ObjectType` - like an expression, but names aren't called or read
+    fn object_of(&mut self, cursor: &mut Cursor, scope: &str, frame: &mut Frame) -> Result<AmlValue, AmlError> {
+        if name::is_name_start(cursor.peek()?) {
+            let name = cursor.name_string()?;
+            let path = self.resolve_existing(&name, scope)?;
+            return match self.namespace.get(&path).cloned() {
+                Some(AmlValue::Field(field)) => self.read_field(&field),
+                Some(AmlValue::Reference(target)) => Ok(self.namespace.get(&target).cloned().unwrap_or(AmlValue::Uninitialized)),
+                Some(object) => Ok(object),
+                None => Err(AmlError::NameNotFound(path)),
+            };
+        }
+        self.term_arg(cursor, scope, frame)
+    }
+
+    fn package_elements(
+        &mut self,
+        cursor: &mut Cursor,
+        end: usize,
+        count: usize,
+        scope: &str,
+        frame: &mut Frame,
+    ) -> Result<AmlValue, AmlError> {
+        let mut elements = Vec::with_capacity(count);
+        while cursor.pos < end {
+            if name::is_name_start(cursor.peek()?) {
+                // Names in packages are references to objects, which may not even exist yet
+                let name = cursor.name_string()?;
+                let path = self.resolve(&name, scope).unwrap_or_else(|| name.absolute(scope));
+                elements.push(AmlValue::Reference(path));
+            } else {
+                elements.push(self.term_arg(cursor, scope, frame)?);
+            }
+        }
+        cursor.pos = end;
+        while elements.len() < count {
+            elements.push(AmlValue::Uninitialized);
+        }
+        Ok(AmlValue::Package(elements))
+    }
+
+    /// An integer as a buffer - 8 bytes, or 4 if integers are 32 bits
+    fn integer_bytes(&self, value: u64) -> Vec<u8> {
+        let size = if self.integer_width_64 { 8 } else { 4 };
+        value.to_le_bytes()[..size].to_vec()
+    }
+}
+
+/* Targets */
+
+impl Interpreter {
+    /// Parse a target (SuperName or NullName)
+    fn target(&mut self, cursor: &mut Cursor, scope: &str, frame: &mut Frame) -> Result<Target, AmlError> {
+        match cursor.peek()? {
+            ZERO_OP => {
+                cursor.byte()?;
+                Ok(Target::None)
+            }
+            op @ LOCAL0_OP..=LOCAL7_OP => {
+                cursor.byte()?;
+                Ok(Target::Local((op - LOCAL0_OP) as usize))
+            }
+            op @ ARG0_OP..=ARG6_OP => {
+                cursor.byte()?;
+                Ok(Target::Arg((op - ARG0_OP) as usize))
+            }
+            EXT_OP_PREFIX if cursor.peek_at(1) == Some(EXT_DEBUG_OP) => {
+                cursor.bytes(2)?;
+                Ok(Target::Debug)
+            }
+            INDEX_OP => {
+                cursor.byte()?;
+                let source = self.target(cursor, scope, frame)?;
+                let index = self.integer_arg(cursor, scope, frame)? as usize;
+                let _ = self.target(cursor, scope, frame)?;
+                Ok(Target::Index(Box::new(source), index))
+            }
+            DEREF_OF_OP => {
+                cursor.byte()?;
+                match self.term_arg(cursor, scope, frame)? {
+                    AmlValue::Reference(path) => Ok(Target::Name(path)),
+                    _ => Err(AmlError::InvalidTarget),
+                }
+            }
+            byte if name::is_name_start(byte) => {
+                let name = cursor.name_string()?;
+                Ok(Target::Name(self.resolve_existing(&name, scope)?))
+            }
+            _ => Err(AmlError::InvalidTarget),
+        }
+    }
+
+    /// Parse a target and store `result` in it, then hand `result` back (expressions return what they store)
+    fn store_result(
+        &mut self,
+        cursor: &mut Cursor,
+        scope: &str,
+        frame: &mut Frame,
+        result: AmlValue,
+    ) -> Result<AmlValue, AmlError> {
+        let target = self.target(cursor, scope, frame)?;
+        self.store(&target, result.clone(), frame)?;
+        Ok(result)
+    }
+
+    fn read_target(&mut self, target: &Target, frame: &mut Frame) -> Result<AmlValue, AmlError> {
+        match target {
+            Target::None | Target::Debug => Ok(AmlValue::Uninitialized),
+            Target::Local(i) => Ok(frame.locals[*i].clone()),
+            Target::Arg(i) => frame.args.get(*i).cloned().ok_or(AmlError::InvalidTarget),
+            Target::Name(path) => self.value_of(path, Vec::new(), frame),
+            Target::Index(source, index) => match self.read_target(source, frame)? {
+                AmlValue::Package(elements) => elements.get(*index).cloned().ok_or(AmlError::IndexOutOfBounds),
+                AmlValue::Buffer(bytes) => {
+                    bytes.get(*index).map(|&byte| AmlValue::Integer(byte as u64)).ok_or(AmlError::IndexOutOfBounds)
+                }
+                _ => Err(AmlError::TypeMismatch),
+            },
+        }
+    }
+
+    fn store(&mut self, target: &Target, value: AmlValue, frame: &mut Frame) -> Result<(), AmlError> {
+        match target {
+            Target::None => Ok(()),
+            Target::Debug => {
+                serial_println!("[AML] {:?}", value);
+                Ok(())
+            }
+            Target::Local(i) => {
+                frame.locals[*i] = value;
+                Ok(())
+            }
+            Target::Arg(i) => {
+                while frame.args.len() <= *i {
+                    frame.args.push(AmlValue::Uninitialized);
+                }
+                frame.args[*i] = value;
+                Ok(())
+            }
+            Target::Name(path) => self.store_name(path, value, frame),
+            Target::Index(source, index) => {
+                let mut container = self.read_target(source, frame)?;
+                match &mut container {
+                    AmlValue::Package(elements) => {
+                        *elements.get_mut(*index).ok_or(AmlError::IndexOutOfBounds)? = value;
+                    }
+                    AmlValue::Buffer(bytes) => {
+                        *bytes.get_mut(*index).ok_or(AmlError::IndexOutOfBounds)? = value.as_integer()? as u8;
+                    }
+                    _ => return Err(AmlError::TypeMismatch),
+                }
+                self.store(source, container, frame)
+            }
+        }
+    }
+
+    /// Store to a named object - writing to hardware if it's a field
+    fn store_name(&mut self, path: &str, value: AmlValue, frame: &mut Frame) -> Result<(), AmlError> {
+        match self.namespace.get(path).cloned() {
+            Some(AmlValue::Field(field)) => self.write_field(&field, &value),
+            Some(AmlValue::BufferField { source, bit_offset, bit_length }) => {
+                let mut buffer = self.buffer_of(&source, frame)?;
+                insert_bits(&mut buffer, bit_offset, bit_length, &value.as_buffer()?);
+                self.set_buffer(&source, buffer, frame)
+            }
+            Some(AmlValue::Reference(target)) => self.store_name(&target, value, frame),
+            Some(AmlValue::Method { .. }) | Some(AmlValue::NativeMethod { .. }) => Err(AmlError::InvalidTarget),
+            _ => {
+                self.namespace.insert(path.to_string(), value);
+                Ok(())
+            }
+        }
+    }
+}
+
+/* Buffer fields and operation region fields */
+
+impl Interpreter {
+    fn buffer_of(&mut self, source: &BufferSource, frame: &mut Frame) -> Result<Vec<u8>, AmlError> {
+        let value = match source {
+            BufferSource::Name(path) => self.namespace.get(path).cloned().ok_or_else(|| AmlError::NameNotFound(path.clone()))?,
+            BufferSource::Local(i) => frame.locals[*i].clone(),
+            BufferSource::Arg(i) => frame.args.get(*i).cloned().ok_or(AmlError::InvalidTarget)?,
+        };
+        match value {
+            AmlValue::Buffer(bytes) => Ok(bytes),
+            _ => Err(AmlError::TypeMismatch),
+        }
+    }
+
+    fn set_buffer(&mut self, source: &BufferSource, buffer: Vec<u8>, frame: &mut Frame) -> Result<(), AmlError> {
+        match source {
+            BufferSource::Name(path) => {
+                self.namespace.insert(path.clone(), AmlValue::Buffer(buffer));
+            }
+            BufferSource::Local(i) => frame.locals[*i] = AmlValue::Buffer(buffer),
+            BufferSource::Arg(i) => *frame.args.get_mut(*i).ok_or(AmlError::InvalidTarget)? = AmlValue::Buffer(buffer),
+        }
+        Ok(())
+    }
+
+    /// Read a field, an access unit at a time. Up to 64 bits come back as an integer, anything bigger as a buffer
+    fn read_field(&mut self, field: &FieldUnit) -> Result<AmlValue, AmlError> {
+        let mut bytes = vec![0u8; ((field.bit_length + 7) / 8) as usize];
+        let width = field.access.bits();
+        let end = field.bit_offset + field.bit_length;
+
+        if field.bit_length > 0 {
+            for unit in field.bit_offset / width..=(end - 1) / width {
+                let value = self.read_unit(field, unit * width / 8, width)?;
+                for bit in 0..width {
+                    let position = unit * width + bit;
+                    if position >= field.bit_offset && position < end && (value >> bit) & 1 != 0 {
+                        let relative = position - field.bit_offset;
+                        bytes[(relative / 8) as usize] |= 1u8 << (relative % 8);
+                    }
+                }
+            }
+        }
+
+        if field.bit_length <= 64 {
+            Ok(AmlValue::Integer(AmlValue::Buffer(bytes).as_integer()?))
+        } else {
+            Ok(AmlValue::Buffer(bytes))
+        }
+    }
+
+    /// Write a field, an access unit at a time. Bits of a unit outside the field follow the update rule
+    fn write_field(&mut self, field: &FieldUnit, value: &AmlValue) -> Result<(), AmlError> {
+        let bytes = value.as_buffer()?;
+        let width = field.access.bits();
+        let end = field.bit_offset + field.bit_length;
+        if field.bit_length == 0 {
+            return Ok(());
+        }
+
+        for unit in field.bit_offset / width..=(end - 1) / width {
+            let unit_start = unit * width;
+            let whole_unit = field.bit_offset <= unit_start && unit_start + width <= end;
+            let mut current = if whole_unit {
+                0
+            } else {
+                match field.update {
+                    UpdateRule::Preserve => self.read_unit(field, unit_start / 8, width)?,
+                    UpdateRule::WriteAsOnes => !0,
+                    UpdateRule::WriteAsZeros => 0,
+                }
+            };
+
+            for bit in 0..width {
+                let position = unit_start + bit;
+                if position < field.bit_offset || position >= end {
+                    continue;
+                }
+                let relative = position - field.bit_offset;
+                let set = bytes.get((relative / 8) as usize).map_or(false, |byte| (byte >> (relative % 8)) & 1 != 0);
+                if set {
+                    current |= 1u64 << bit;
+                } else {
+                    current &= !(1u64 << bit);
+                }
+            }
+            self.write_unit(field, unit_start / 8, width, current)?;
+        }
+        Ok(())
+    }
+
+    /// Read one access unit, `byte_offset` bytes into the field's region
+    fn read_unit(&mut self, field: &FieldUnit, byte_offset: u64, width: u64) -> Result<u64, AmlError> {
+        match &field.kind {
+            FieldKind::Region(region) => self.region_access(region, byte_offset, width, None),
+            FieldKind::Index { index, data } => {
+                let (index, data) = (self.field_at(index)?, self.field_at(data)?);
+                self.write_field(&index, &AmlValue::Integer(byte_offset))?;
+                self.read_field(&data)?.as_integer()
+            }
+        }
+    }
+
+    fn write_unit(&mut self, field: &FieldUnit, byte_offset: u64, width: u64, value: u64) -> Result<(), AmlError> {
+        match &field.kind {
+            FieldKind::Region(region) => self.region_access(region, byte_offset, width, Some(value)).map(|_| ()),
+            FieldKind::Index { index, data } => {
+                let (index, data) = (self.field_at(index)?, self.field_at(data)?);
+                self.write_field(&index, &AmlValue::Integer(byte_offset))?;
+                self.write_field(&data, &AmlValue::Integer(value))
+            }
+        }
+    }
+
+    fn field_at(&self, path: &str) -> Result<FieldUnit, AmlError> {
+        match self.namespace.get(path) {
+            Some(AmlValue::Field(field)) => Ok(field.clone()),
+            Some(_) => Err(AmlError::TypeMismatch),
+            None => Err(AmlError::NameNotFound(path.to_string())),
+        }
+    }
+
+    /// Read (or write, if `write` is `Some`) `width` bits at `byte_offset` into an operation region
+    fn region_access(&mut self, region: &str, byte_offset: u64, width: u64, write: Option<u64>) -> Result<u64, AmlError> {
+        let (space, base) = match self.namespace.get(region) {
+            Some(AmlValue::OpRegion { space, offset, .. }) => (*space, *offset),
+            Some(_) => return Err(AmlError::TypeMismatch),
+            None => return Err(AmlError::NameNotFound(region.to_string())),
+        };
+        let address = base + byte_offset;
+        let width = width as u8;
+
+        match space {
+            RegionSpace::SystemMemory => Ok(match write {
+                Some(value) => {
+                    self.handler.write_memory(address, width, value);
+                    0
+                }
+                None => self.handler.read_memory(address, width),
+            }),
+            RegionSpace::SystemIo => Ok(match write {
+                Some(value) => {
+                    self.handler.write_io(address as u16, width, value);
+                    0
+                }
+                None => self.handler.read_io(address as u16, width),
+            }),
+            RegionSpace::PciConfig => {
+                let (segment, bus, device, function) = self.pci_address(region)?;
+                Ok(match write {
+                    Some(value) => {
+                        self.handler.write_pci(segment, bus, device, function, address as u16, width, value);
+                        0
+                    }
+                    None => self.handler.read_pci(segment, bus, device, function, address as u16, width),
+                })
+            }
+            _ => Err(AmlError::Unsupported("operation region address space")),
+        }
+    }
+
+    /// The PCI device a PCI config space region belongs to - from the `_ADR` of the device it is declared in,
+    /// and the `_BBN` (bus) and `_SEG` (segment) of the host bridge above that
+    fn pci_address(&mut self, region: &str) -> Result<(u16, u8, u8, u8), AmlError> {
+        let device = name::parent(region).ok_or(AmlError::InvalidName)?;
+        let address = self.optional_integer(&name::join(&device, "_ADR"))?.unwrap_or(0);
+
+        let mut bus = 0;
+        let mut segment = 0;
+        let mut scope = Some(device);
+        while let Some(current) = scope {
+            if let Some(value) = self.optional_integer(&name::join(&current, "_BBN"))? {
+                bus = value;
+                segment = self.optional_integer(&name::join(&current, "_SEG"))?.unwrap_or(0);
+                break;
+            }
+            scope = name::parent(&current);
+        }
+
+        Ok((segment as u16, bus as u8, (address >> 16) as u8, address as u8))
+    }
+
+    /// Evaluate `path` as an integer, if it exists
+    fn optional_integer(&mut self, path: &str) -> Result<Option<u64>, AmlError> {
+        if !self.namespace.contains_key(path) {
+            return Ok(None);
+        }
+        let mut frame = Frame::new(Vec::new());
+        Ok(Some(self.value_of(path, Vec::new(), &mut frame)?.as_integer()?))
+    }
+}
+
+/// Compare two values the way `LEqual`, `LGreater` and `LLess` do - as the type of the first one
+fn compare(a: &AmlValue, b: &AmlValue) -> Result<Ordering, AmlError> {
+    match a {
+        AmlValue::String(string) => Ok(string.as_str().cmp(b.as_string()?.as_str())),
+        AmlValue::Buffer(bytes) => Ok(bytes.as_slice().cmp(b.as_buffer()?.as_slice())),
+        _ => Ok(a.as_integer()?.cmp(&b.as_integer()?)),
+    }
+}
+
+/// `ToInteger` on a string - hex with a `0x` prefix, decimal without
+fn parse_integer(string: &str) -> Result<u64, AmlError> {
+    let string = string.trim();
+    let (digits, radix) = if string.starts_with("0x") || string.starts_with("0X") {
+        (&string[2..], 16)
+    } else {
+        (string, 10)
+    };
+    u64::from_str_radix(digits, radix).map_err(|_| AmlError::TypeMismatch)
+}
+
+/// Read `bit_length` bits from `buffer`, as an integer (up to 64 bits) or a buffer
+fn extract_bits(buffer: &[u8], bit_offset: u64, bit_length: u64) -> AmlValue {
+    let mut bytes = vec![0u8; ((bit_length + 7) / 8) as usize];
+    for bit in 0..bit_length {
+        let position = bit_offset + bit;
+        let set = buffer.get((position / 8) as usize).map_or(false, |byte| (byte >> (position % 8)) & 1 != 0);
+        if set {
+            bytes[(bit / 8) as usize] |= 1u8 << (bit % 8);
+        }
+    }
+    if bit_length <= 64 {
+        AmlValue::Integer(bytes.iter().rev().fold(0, |value, &byte| value << 8 | byte as u64))
+    } else {
+        AmlValue::Buffer(bytes)
+    }
+}
+
+/// Write the low `bit_length` bits of `value` into `buffer`
+fn insert_bits(buffer: &mut [u8], bit_offset: u64, bit_length: u64, value: &[u8]) {
+    for bit in 0..bit_length {
+        let position = bit_offset + bit;
+        let set = value.get((bit / 8) as usize).map_or(false, |byte| (byte >> (bit % 8)) & 1 != 0);
+        if let Some(byte) = buffer.get_mut((position / 8) as usize) {
+            if set {
+                *byte |= 1u8 << (position % 8);
+            } else {
+                *byte &= !(1u8 << (position % 8));
+            }
+        }
+    }
+}
+
+/* Testing */
+
+/// Backs I/O regions with a few bytes of fake ports. Memory and PCI config space read as all ones
+struct MockHandler {
+    ports: BTreeMap<u16, u8>,
+}
+
+impl Handler for MockHandler {
+    fn read_memory(&mut self, _address: u64, _width: u8) -> u64 {
+        !0
+    }
+
+    fn write_memory(&mut self, _address: u64, _width: u8, _value: u64) {}
+
+    fn read_io(&mut self, port: u16, width: u8) -> u64 {
+        (0..width as u16 / 8).fold(0, |value, i| value | (*self.ports.get(&(port + i)).unwrap_or(&0) as u64) << (i * 8))
+    }
+
+    fn write_io(&mut self, port: u16, width: u8, value: u64) {
+        for i in 0..width as u16 / 8 {
+            self.ports.insert(port + i, (value >> (i * 8)) as u8);
+        }
+    }
+
+    fn read_pci(&mut self, _segment: u16, _bus: u8, _device: u8, _function: u8, _offset: u16, _width: u8) -> u64 {
+        !0
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write_pci(&mut self, _segment: u16, _bus: u8, _device: u8, _function: u8, _offset: u16, _width: u8, _value: u64) {}
+}
+
+/// Bits of the i440fx (`pc`) machine's DSDT, and some methods to exercise the interpreter
+///
+/// ```text
+/// Name (_S5, Package () { 5, 5, 0, 0 })
+/// Scope (\_SB) {
+///     Device (LNKD) { Name (_UID, 4) }
+///     Device (PCI0) {
+///         Name (_HID, EisaId ("PNP0A03"))
+///         Name (_PRT, Package () { Package () { 0x0001FFFF, 0, LNKD, 0 } })
+///         Method (_STA) { Local0 = 0xF0; Return (Local0 >> 4) }
+///     }
+/// }
+/// Method (SUMN, 1) {
+///     Local0 = 0; Local1 = 1
+///     While (Local1 < Arg0 + 1) { Local0 += Local1; Local1++ }
+///     If (Local0 == 0) { Return ("none") } Else { Return (Local0) }
+/// }
+/// OperationRegion (DBG0, SystemIO, 0x400, 4)
+/// Field (DBG0, ByteAcc) { DBGB, 8, , 8, DBGW, 16 }
+/// Method (WDBG, 1) { DBGW = Arg0; Return (DBGB) }
+/// Method (CRS0) { Local0 = Buffer (8) {}; CreateDWordField (Local0, 4, ADDR); ADDR = 0xFED00000; Return (Local0) }
+/// ```
+static TEST_AML: [u8; 218] = [
+    0x08, 0x5F, 0x53, 0x35, 0x5F, 0x12, 0x08, 0x04, 0x0A, 0x05, 0x0A, 0x05, 0x00, 0x00, 0x10, 0x4D,
+    0x04, 0x5C, 0x5F, 0x53, 0x42, 0x5F, 0x5B, 0x82, 0x0C, 0x4C, 0x4E, 0x4B, 0x44, 0x08, 0x5F, 0x55,
+    0x49, 0x44, 0x0A, 0x04, 0x5B, 0x82, 0x36, 0x50, 0x43, 0x49, 0x30, 0x08, 0x5F, 0x48, 0x49, 0x44,
+    0x0C, 0x41, 0xD0, 0x0A, 0x03, 0x08, 0x5F, 0x50, 0x52, 0x54, 0x12, 0x10, 0x01, 0x12, 0x0D, 0x04,
+    0x0C, 0xFF, 0xFF, 0x01, 0x00, 0x00, 0x4C, 0x4E, 0x4B, 0x44, 0x00, 0x14, 0x10, 0x5F, 0x53, 0x54,
+    0x41, 0x00, 0x70, 0x0A, 0xF0, 0x60, 0xA4, 0x7A, 0x60, 0x0A, 0x04, 0x00, 0x14, 0x2A, 0x53, 0x55,
+    0x4D, 0x4E, 0x01, 0x70, 0x00, 0x60, 0x70, 0x01, 0x61, 0xA2, 0x0D, 0x95, 0x61, 0x72, 0x68, 0x01,
+    0x00, 0x72, 0x60, 0x61, 0x60, 0x75, 0x61, 0xA0, 0x0B, 0x93, 0x60, 0x00, 0xA4, 0x0D, 0x6E, 0x6F,
+    0x6E, 0x65, 0x00, 0xA1, 0x03, 0xA4, 0x60, 0x5B, 0x80, 0x44, 0x42, 0x47, 0x30, 0x01, 0x0B, 0x00,
+    0x04, 0x0A, 0x04, 0x5B, 0x81, 0x12, 0x44, 0x42, 0x47, 0x30, 0x01, 0x44, 0x42, 0x47, 0x42, 0x08,
+    0x00, 0x08, 0x44, 0x42, 0x47, 0x57, 0x10, 0x14, 0x11, 0x57, 0x44, 0x42, 0x47, 0x01, 0x70, 0x68,
+    0x44, 0x42, 0x47, 0x57, 0xA4, 0x44, 0x42, 0x47, 0x42, 0x14, 0x20, 0x43, 0x52, 0x53, 0x30, 0x00,
+    0x70, 0x11, 0x03, 0x0A, 0x08, 0x60, 0x8A, 0x60, 0x0A, 0x04, 0x41, 0x44, 0x44, 0x52, 0x70, 0x0C,
+    0x00, 0x00, 0xD0, 0xFE, 0x41, 0x44, 0x44, 0x52, 0xA4, 0x60,
+];
+
+/// The q35 machine's PCI interrupt routing - which table `_PRT` returns depends on the mode `_PIC` was told
+/// we use. The link devices are declared after the packages naming them
+///
+/// ```text
+/// Name (PICF, 0)
+/// Method (_PIC, 1) { PICF = Arg0 }
+/// Scope (\_SB) {
+///     Device (PCI0) {
+///         Name (_HID, EisaId ("PNP0A08"))
+///         Name (_CID, EisaId ("PNP0A03"))
+///         Name (PRTP, Package () { Package () { 0xFFFF, 0, LNKE, 0 } })
+///         Name (PRTA, Package () { Package () { 0xFFFF, 0, GSIE, 0 } })
+///         Method (_PRT) { If (PICF == 0) { Return (PRTP) } Else { Return (PRTA) } }
+///     }
+///     Device (LNKE) { Name (_UID, 4) }
+///     Device (GSIE) { Name (_UID, 0x10) }
+/// }
+/// ```
+static Q35_AML: [u8; 150] = [
+    0x08, 0x50, 0x49, 0x43, 0x46, 0x00, 0x14, 0x0C, 0x5F, 0x50, 0x49, 0x43, 0x01, 0x70, 0x68, 0x50,
+    0x49, 0x43, 0x46, 0x10, 0x42, 0x08, 0x5C, 0x5F, 0x53, 0x42, 0x5F, 0x5B, 0x82, 0x4D, 0x05, 0x50,
+    0x43, 0x49, 0x30, 0x08, 0x5F, 0x48, 0x49, 0x44, 0x0C, 0x41, 0xD0, 0x0A, 0x08, 0x08, 0x5F, 0x43,
+    0x49, 0x44, 0x0C, 0x41, 0xD0, 0x0A, 0x03, 0x08, 0x50, 0x52, 0x54, 0x50, 0x12, 0x0E, 0x01, 0x12,
+    0x0B, 0x04, 0x0B, 0xFF, 0xFF, 0x00, 0x4C, 0x4E, 0x4B, 0x45, 0x00, 0x08, 0x50, 0x52, 0x54, 0x41,
+    0x12, 0x0E, 0x01, 0x12, 0x0B, 0x04, 0x0B, 0xFF, 0xFF, 0x00, 0x47, 0x53, 0x49, 0x45, 0x00, 0x14,
+    0x1A, 0x5F, 0x50, 0x52, 0x54, 0x00, 0xA0, 0x0C, 0x93, 0x50, 0x49, 0x43, 0x46, 0x00, 0xA4, 0x50,
+    0x52, 0x54, 0x50, 0xA1, 0x06, 0xA4, 0x50, 0x52, 0x54, 0x41, 0x5B, 0x82, 0x0C, 0x4C, 0x4E, 0x4B,
+    0x45, 0x08, 0x5F, 0x55, 0x49, 0x44, 0x0A, 0x04, 0x5B, 0x82, 0x0C, 0x47, 0x53, 0x49, 0x45, 0x08,
+    0x5F, 0x55, 0x49, 0x44, 0x0A, 0x10,
+];
+
+fn test_interpreter() -> Interpreter {
+    let mut ports = BTreeMap::new();
+    ports.insert(0x400, 0xAB);
+    let mut interpreter = Interpreter::new(Box::new(MockHandler { ports }));
+    interpreter.load(&TEST_AML, 2).expect("Test AML failed to load");
+    interpreter
+}
+
+#[test_case]
+fn aml_loads_packages() {
+    let mut interpreter = test_interpreter();
+    let s5: Vec<AmlValue> = [5, 5, 0, 0].iter().map(|&value| AmlValue::Integer(value)).collect();
+    assert_eq!(interpreter.evaluate("\\_S5", Vec::new()), Ok(AmlValue::Package(s5)));
+}
+
+#[test_case]
+fn aml_evaluates_devices() {
+    let mut interpreter = test_interpreter();
+    assert_eq!(interpreter.evaluate("\\_SB.PCI0._HID", Vec::new()), Ok(AmlValue::Integer(0x030AD041)));
+    assert_eq!(interpreter.evaluate("\\_SB.PCI0._STA", Vec::new()), Ok(AmlValue::Integer(0x0F)));
+
+    let prt = interpreter.evaluate("\\_SB.PCI0._PRT", Vec::new()).unwrap();
+    let route = prt.as_package().unwrap()[0].as_package().unwrap().to_vec();
+    assert_eq!(route[0], AmlValue::Integer(0x0001FFFF));
+    assert_eq!(route[2], AmlValue::Reference("\\_SB_.LNKD".to_string()));
+
+    let devices = interpreter.devices();
+    assert!(devices.contains(&"\\_SB_.PCI0".to_string()));
+    assert!(interpreter.children("\\_SB").contains(&"\\_SB_.LNKD".to_string()));
+}
+
+#[test_case]
+fn aml_runs_loops_and_conditionals() {
+    let mut interpreter = test_interpreter();
+    assert_eq!(interpreter.evaluate("\\SUMN", vec![AmlValue::Integer(4)]), Ok(AmlValue::Integer(10)));
+    assert_eq!(interpreter.evaluate("\\SUMN", vec![AmlValue::Integer(0)]), Ok(AmlValue::from("none")));
+    assert_eq!(interpreter.evaluate("\\SUMN", Vec::new()), Err(AmlError::WrongArgumentCount));
+}
+
+#[test_case]
+fn aml_fields_use_the_handler() {
+    let mut interpreter = test_interpreter();
+    // The write to DBGW mustn't touch DBGB
+    assert_eq!(interpreter.evaluate("\\WDBG", vec![AmlValue::Integer(0x1234)]), Ok(AmlValue::Integer(0xAB)));
+    assert_eq!(interpreter.evaluate("\\DBGW", Vec::new()), Ok(AmlValue::Integer(0x1234)));
+}
+
+#[test_case]
+fn aml_buffer_fields_are_method_local() {
+    let mut interpreter = test_interpreter();
+    let buffer = interpreter.evaluate("\\CRS0", Vec::new()).unwrap();
+    assert_eq!(buffer, AmlValue::Buffer(vec![0, 0, 0, 0, 0x00, 0x00, 0xD0, 0xFE]));
+    assert!(interpreter.get("\\ADDR").is_none());
+}
+
+#[test_case]
+fn aml_answers_osi() {
+    let mut interpreter = test_interpreter();
+    assert_eq!(interpreter.evaluate("\\_OSI", vec![AmlValue::from("Windows 2009")]), Ok(AmlValue::Integer(!0)));
+    assert_eq!(interpreter.evaluate("\\_OSI", vec![AmlValue::from("Linux")]), Ok(AmlValue::Integer(0)));
+}
+
+#[test_case]
+fn aml_q35_routing_follows_pic_mode() {
+    let mut interpreter = Interpreter::new(Box::new(MockHandler { ports: BTreeMap::new() }));
+    interpreter.load(&Q35_AML, 2).expect("Test AML failed to load");
+    assert_eq!(interpreter.evaluate("\\_SB.PCI0._HID", Vec::new()), Ok(AmlValue::Integer(0x080AD041)));
+
+    let link = |interpreter: &mut Interpreter| {
+        let prt = interpreter.evaluate("\\_SB.PCI0._PRT", Vec::new()).unwrap();
+        prt.as_package().unwrap()[0].as_package().unwrap()[2].clone()
+    };
+    assert_eq!(link(&mut interpreter), AmlValue::Reference("\\_SB_.LNKE".to_string()));
+    interpreter.evaluate("\\_PIC", vec![AmlValue::Integer(1)]).unwrap(); // APIC mode
+    assert_eq!(link(&mut interpreter), AmlValue::Reference("\\_SB_.GSIE".to_string()));
+}
+
+#[test_case]
+fn aml_rejects_bad_packages() {
+    // A method whose package ends inside its name
+    static SHORT_METHOD: [u8; 9] = [0x14, 0x02, 0x41, 0x42, 0x43, 0x44, 0x00, 0x00, 0x00];
+    let mut interpreter = Interpreter::new(Box::new(MockHandler { ports: BTreeMap::new() }));
+    assert_eq!(interpreter.load(&SHORT_METHOD, 2), Err(AmlError::UnexpectedEnd));
+
+    // Name (BUFF, Buffer (0xFFFFFFFF) {})
+    static HUGE_BUFFER: [u8; 12] = [0x08, 0x42, 0x55, 0x46, 0x46, 0x11, 0x06, 0x0C, 0xFF, 0xFF, 0xFF, 0xFF];
+    let mut interpreter = Interpreter::new(Box::new(MockHandler { ports: BTreeMap::new() }));
+    assert_eq!(interpreter.load(&HUGE_BUFFER, 2), Err(AmlError::Unsupported("buffer too big")));
+}
@@ -0,0 +1,120 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use super::AmlError;
+
+/// The path of the root scope. Every other path is `\` followed by dot separated, 4 character name segments,
+/// like `\_SB_.PCI0._PRT`
+pub const ROOT: &str = "\\";
+
+/// # AmlName
+///
+/// A name as it appears in AML - maybe absolute (`\_SB_.PCI0`), maybe relative to the current scope,
+/// maybe going up a few scopes first (`^^FOO_`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AmlName {
+    /// Starts at the root (`\`)
+    pub root: bool,
+    /// How many `^` (parent scope) prefixes there were
+    pub parents: usize,
+    pub segments: Vec<String>,
+}
+
+impl AmlName {
+    /// A plain single segment name with no prefix is looked up using the search rules - in the current
+    /// scope, then each parent scope in turn
+    pub fn uses_search_rules(&self) -> bool {
+        !self.root && self.parents == 0 && self.segments.len() == 1
+    }
+
+    /// The absolute path this name means, relative to `scope`. No searching
+    pub fn absolute(&self, scope: &str) -> String {
+        let mut segments: Vec<&str> = if self.root { Vec::new() } else { split(scope) };
+        for _ in 0..self.parents {
+            segments.pop();
+        }
+        segments.extend(self.segments.iter().map(|segment| segment.as_str()));
+        join_all(&segments)
+    }
+}
+
+impl fmt::Display for AmlName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.root {
+            write!(f, "\\")?;
+        }
+        for _ in 0..self.parents {
+            write!(f, "^")?;
+        }
+        write!(f, "{}", self.segments.join("."))
+    }
+}
+
+/// Whether `byte` can start a name string
+pub fn is_name_start(byte: u8) -> bool {
+    is_lead_name_char(byte) || byte == b'\\' || byte == b'^' || byte == DUAL_NAME_PREFIX || byte == MULTI_NAME_PREFIX
+}
+
+/// The first character of a name segment - `A`-`Z` or `_`
+pub fn is_lead_name_char(byte: u8) -> bool {
+    byte.is_ascii_uppercase() || byte == b'_'
+}
+
+/// Any other character of a name segment - `A`-`Z`, `0`-`9` or `_`
+pub fn is_name_char(byte: u8) -> bool {
+    is_lead_name_char(byte) || byte.is_ascii_digit()
+}
+
+pub const DUAL_NAME_PREFIX: u8 = 0x2E;
+pub const MULTI_NAME_PREFIX: u8 = 0x2F;
+
+/// The segments of an absolute path (none for the root)
+pub fn split(path: &str) -> Vec<&str> {
+    path.trim_start_matches('\\').split('.').filter(|segment| !segment.is_empty()).collect()
+}
+
+/// Build an absolute path from its segments
+pub fn join_all(segments: &[&str]) -> String {
+    let mut path = String::from(ROOT);
+    path.push_str(&segments.join("."));
+    path
+}
+
+/// The path of `segment` inside `scope`
+pub fn join(scope: &str, segment: &str) -> String {
+    let mut path = String::from(scope);
+    if path != ROOT {
+        path.push('.');
+    }
+    path.push_str(segment);
+    path
+}
+
+/// The scope containing `path`, or `None` for the root
+pub fn parent(path: &str) -> Option<String> {
+    if path == ROOT {
+        return None;
+    }
+    let mut segments = split(path);
+    segments.pop();
+    Some(join_all(&segments))
+}
+
+/// Turn a path written by a person (`\_SB.PCI0._HID`) into the form the namespace uses - absolute, and
+/// with every segment padded to 4 characters with `_` (`\_SB_.PCI0._HID`)
+pub fn normalize(path: &str) -> Result<String, AmlError> {
+    let mut segments = Vec::new();
+    for segment in split(path) {
+        let bytes = segment.as_bytes();
+        if bytes.is_empty() || bytes.len() > 4 || !is_lead_name_char(bytes[0]) || !bytes.iter().all(|&b| is_name_char(b)) {
+            return Err(AmlError::InvalidName);
+        }
+        let mut padded = String::from(segment);
+        while padded.len() < 4 {
+            padded.push('_');
+        }
+        segments.push(padded);
+    }
+    let segments: Vec<&str> = segments.iter().map(|segment| segment.as_str()).collect();
+    Ok(join_all(&segments))
+}
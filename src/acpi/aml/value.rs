@@ -0,0 +1,298 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use super::AmlError;
+
+/// # RegionSpace
+///
+/// The address space an operation region lives in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    EmbeddedControl,
+    SmBus,
+    Cmos,
+    PciBarTarget,
+    Other(u8),
+}
+
+impl From<u8> for RegionSpace {
+    fn from(space: u8) -> Self {
+        match space {
+            0 => RegionSpace::SystemMemory,
+            1 => RegionSpace::SystemIo,
+            2 => RegionSpace::PciConfig,
+            3 => RegionSpace::EmbeddedControl,
+            4 => RegionSpace::SmBus,
+            5 => RegionSpace::Cmos,
+            6 => RegionSpace::PciBarTarget,
+            other => RegionSpace::Other(other),
+        }
+    }
+}
+
+/// How a field is read and written - in units of this size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessType {
+    Any,
+    Byte,
+    Word,
+    DWord,
+    QWord,
+    Buffer,
+}
+
+impl AccessType {
+    pub(super) fn from_flags(flags: u8) -> Self {
+        match flags & 0x0F {
+            1 => AccessType::Byte,
+            2 => AccessType::Word,
+            3 => AccessType::DWord,
+            4 => AccessType::QWord,
+            5 => AccessType::Buffer,
+            _ => AccessType::Any,
+        }
+    }
+
+    /// The width of one access, in bits. We do `Any` and `Buffer` accesses a byte at a time
+    pub fn bits(self) -> u64 {
+        match self {
+            AccessType::Word => 16,
+            AccessType::DWord => 32,
+            AccessType::QWord => 64,
+            _ => 8,
+        }
+    }
+}
+
+/// What to do with the bits of an access unit that aren't part of the field being written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateRule {
+    Preserve,
+    WriteAsOnes,
+    WriteAsZeros,
+}
+
+impl UpdateRule {
+    pub(super) fn from_flags(flags: u8) -> Self {
+        match (flags >> 5) & 0b11 {
+            1 => UpdateRule::WriteAsOnes,
+            2 => UpdateRule::WriteAsZeros,
+            _ => UpdateRule::Preserve,
+        }
+    }
+}
+
+/// Where a field's bits come from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldKind {
+    /// Part of an operation region (the absolute path of the region)
+    Region(String),
+    /// Reached by writing the offset to the `index` field, then accessing the `data` field
+    Index { index: String, data: String },
+}
+
+/// # FieldUnit
+///
+/// A named field - some bits of an operation region (or of an index/data register pair)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldUnit {
+    pub kind: FieldKind,
+    pub bit_offset: u64,
+    pub bit_length: u64,
+    pub access: AccessType,
+    pub update: UpdateRule,
+}
+
+/// The buffer a buffer field (`CreateDWordField` and friends) points into
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BufferSource {
+    /// A named buffer object
+    Name(String),
+    /// A buffer in a local variable or argument of the running method. Only valid while that method runs -
+    /// which is fine, as the buffer field is created (and deleted) by the same method
+    Local(usize),
+    Arg(usize),
+}
+
+/// A method implemented in Rust rather than AML, like `\_OSI`
+pub type NativeMethod = fn(&[AmlValue]) -> Result<AmlValue, AmlError>;
+
+/// # AmlValue
+///
+/// Everything that can be stored in the ACPI namespace, or passed around while running AML - plain data
+/// (integers, strings, buffers, packages) as well as devices, methods, operation regions and fields.
+#[derive(Clone)]
+pub enum AmlValue {
+    Uninitialized,
+    Integer(u64),
+    String(String),
+    Buffer(Vec<u8>),
+    Package(Vec<AmlValue>),
+    /// A reference to a named object (absolute path). Package elements that name an object (like the
+    /// link devices in a `_PRT`) are references, as are aliases and the results of `RefOf`
+    Reference(String),
+    Method { code: &'static [u8], arg_count: u8, serialized: bool },
+    NativeMethod { arg_count: u8, function: NativeMethod },
+    OpRegion { space: RegionSpace, offset: u64, length: u64 },
+    Field(FieldUnit),
+    BufferField { source: BufferSource, bit_offset: u64, bit_length: u64 },
+    /// A plain namespace scope, like `\_SB`
+    Scope,
+    Device,
+    Processor { id: u8, block_address: u32, block_length: u8 },
+    PowerResource { system_level: u8, resource_order: u16 },
+    ThermalZone,
+    Mutex { sync_level: u8 },
+    Event,
+}
+
+impl AmlValue {
+    /// Convert to an integer - buffers are read as little endian, strings as hex (the AML implicit conversion rules)
+    pub fn as_integer(&self) -> Result<u64, AmlError> {
+        match self {
+            AmlValue::Integer(value) => Ok(*value),
+            AmlValue::Buffer(bytes) => {
+                Ok(bytes.iter().take(8).enumerate().fold(0, |value, (i, &byte)| value | (byte as u64) << (i * 8)))
+            }
+            AmlValue::String(string) => {
+                let digits = string.trim_start_matches("0x").trim_start_matches("0X");
+                let digits: String = digits.chars().take_while(|c| c.is_ascii_hexdigit()).collect();
+                Ok(if digits.is_empty() { 0 } else { u64::from_str_radix(&digits, 16).map_err(|_| AmlError::TypeMismatch)? })
+            }
+            _ => Err(AmlError::TypeMismatch),
+        }
+    }
+
+    /// Convert to a buffer. Integers become 8 little endian bytes
+    pub fn as_buffer(&self) -> Result<Vec<u8>, AmlError> {
+        match self {
+            AmlValue::Integer(value) => Ok(value.to_le_bytes().to_vec()),
+            AmlValue::Buffer(bytes) => Ok(bytes.clone()),
+            AmlValue::String(string) => {
+                let mut bytes = string.as_bytes().to_vec();
+                bytes.push(0);
+                Ok(bytes)
+            }
+            _ => Err(AmlError::TypeMismatch),
+        }
+    }
+
+    /// Convert to a string. Integers and buffers are written out in hex
+    pub fn as_string(&self) -> Result<String, AmlError> {
+        match self {
+            AmlValue::String(string) => Ok(string.clone()),
+            AmlValue::Integer(value) => Ok(format!("{:X}", value)),
+            AmlValue::Buffer(bytes) => {
+                let hex: Vec<String> = bytes.iter().map(|byte| format!("0x{:02X}", byte)).collect();
+                Ok(hex.join(","))
+            }
+            _ => Err(AmlError::TypeMismatch),
+        }
+    }
+
+    /// The elements, if this is a package
+    pub fn as_package(&self) -> Result<&[AmlValue], AmlError> {
+        match self {
+            AmlValue::Package(elements) => Ok(elements),
+            _ => Err(AmlError::TypeMismatch),
+        }
+    }
+
+    /// The type number `ObjectType` returns
+    pub fn object_type(&self) -> u64 {
+        match self {
+            AmlValue::Uninitialized => 0,
+            AmlValue::Integer(_) => 1,
+            AmlValue::String(_) => 2,
+            AmlValue::Buffer(_) => 3,
+            AmlValue::Package(_) => 4,
+            AmlValue::Field(_) => 5,
+            AmlValue::Device => 6,
+            AmlValue::Event => 7,
+            AmlValue::Method { .. } | AmlValue::NativeMethod { .. } => 8,
+            AmlValue::Mutex { .. } => 9,
+            AmlValue::OpRegion { .. } => 10,
+            AmlValue::PowerResource { .. } => 11,
+            AmlValue::Processor { .. } => 12,
+            AmlValue::ThermalZone => 13,
+            AmlValue::BufferField { .. } => 14,
+            AmlValue::Reference(_) | AmlValue::Scope => 0,
+        }
+    }
+
+    /// Whether this is a scope-like object other objects can be declared inside
+    pub fn is_scope(&self) -> bool {
+        matches!(
+            self,
+            AmlValue::Scope
+                | AmlValue::Device
+                | AmlValue::Processor { .. }
+                | AmlValue::PowerResource { .. }
+                | AmlValue::ThermalZone
+                | AmlValue::Method { .. }
+        )
+    }
+}
+
+// Written by hand, as `NativeMethod` function pointers don't implement `Debug`
+impl fmt::Debug for AmlValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AmlValue::Uninitialized => write!(f, "Uninitialized"),
+            AmlValue::Integer(value) => write!(f, "Integer({:#x})", value),
+            AmlValue::String(string) => write!(f, "String({:?})", string),
+            AmlValue::Buffer(bytes) => write!(f, "Buffer({:x?})", bytes),
+            AmlValue::Package(elements) => f.debug_list().entries(elements.iter()).finish(),
+            AmlValue::Reference(path) => write!(f, "Reference({})", path),
+            AmlValue::Method { arg_count, serialized, .. } => {
+                write!(f, "Method(args: {}, serialized: {})", arg_count, serialized)
+            }
+            AmlValue::NativeMethod { arg_count, .. } => write!(f, "NativeMethod(args: {})", arg_count),
+            AmlValue::OpRegion { space, offset, length } => {
+                write!(f, "OpRegion({:?}, {:#x}, {:#x})", space, offset, length)
+            }
+            AmlValue::Field(field) => write!(f, "{:?}", field),
+            AmlValue::BufferField { source, bit_offset, bit_length } => {
+                write!(f, "BufferField({:?}, bits {}..{})", source, bit_offset, bit_offset + bit_length)
+            }
+            AmlValue::Scope => write!(f, "Scope"),
+            AmlValue::Device => write!(f, "Device"),
+            AmlValue::Processor { id, .. } => write!(f, "Processor({})", id),
+            AmlValue::PowerResource { .. } => write!(f, "PowerResource"),
+            AmlValue::ThermalZone => write!(f, "ThermalZone"),
+            AmlValue::Mutex { .. } => write!(f, "Mutex"),
+            AmlValue::Event => write!(f, "Event"),
+        }
+    }
+}
+
+// Data is compared by value. Namespace objects (devices, methods...) are never equal
+impl PartialEq for AmlValue {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (AmlValue::Uninitialized, AmlValue::Uninitialized) => true,
+            (AmlValue::Integer(a), AmlValue::Integer(b)) => a == b,
+            (AmlValue::String(a), AmlValue::String(b)) => a == b,
+            (AmlValue::Buffer(a), AmlValue::Buffer(b)) => a == b,
+            (AmlValue::Package(a), AmlValue::Package(b)) => a == b,
+            (AmlValue::Reference(a), AmlValue::Reference(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl From<u64> for AmlValue {
+    fn from(value: u64) -> Self {
+        AmlValue::Integer(value)
+    }
+}
+
+impl From<&str> for AmlValue {
+    fn from(string: &str) -> Self {
+        AmlValue::String(string.to_string())
+    }
+}
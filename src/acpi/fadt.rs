@@ -0,0 +1,111 @@
+use x86_64::PhysAddr;
+use super::{read_field, SdtHeader};
+
+/// Set in `Fadt::flags` if the reset register is supported
+const RESET_REG_SUPPORTED: u32 = 1 << 10;
+/// Set in `Fadt::iapc_boot_arch` if there is an 8042 keyboard controller
+const BOOT_ARCH_8042: u16 = 1 << 1;
+
+/// The address spaces a [GenericAddress](struct.GenericAddress.html) can point into
+pub const ADDRESS_SPACE_MEMORY: u8 = 0;
+pub const ADDRESS_SPACE_IO: u8 = 1;
+pub const ADDRESS_SPACE_PCI_CONFIG: u8 = 2;
+
+/// # GenericAddress
+///
+/// ACPI's way of pointing at a register - which address space it's in (memory, I/O ports, PCI config),
+/// where, and how wide it is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+/// # Fadt
+///
+/// The Fixed ACPI Description Table - where the power management registers are, the SCI interrupt,
+/// the reset register, and the address of the DSDT. We only keep the fields we use.
+///
+/// The FADT has grown with every ACPI version, so fields from newer versions are `None` on older firmware
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    pub revision: u8,
+    /// Physical address of the DSDT (the 64 bit `X_DSDT` if there is one)
+    pub dsdt_address: Option<PhysAddr>,
+    /// The ISA IRQ the SCI (System Control Interrupt) arrives on. Usually 9
+    pub sci_interrupt: u16,
+    /// I/O port to write `acpi_enable`/`acpi_disable` to, to move between legacy and ACPI mode. 0 if the
+    /// machine is always in ACPI mode
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    /// I/O ports of the power management 1 event (status/enable) and control blocks. `b` is 0 if unused
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    pub pm1_event_length: u8,
+    pub pm1_control_length: u8,
+    /// IA-PC boot architecture flags - legacy devices, 8042, VGA and so on (ACPI 2.0+)
+    pub iapc_boot_arch: u16,
+    pub flags: u32,
+    /// The reset register and the value to write to it, if the firmware supports it (ACPI 2.0+)
+    pub reset_register: Option<(GenericAddress, u8)>,
+}
+
+impl Fadt {
+    /// Parse the FADT at `address`. The checksum has already been checked
+    pub(super) fn parse(address: PhysAddr, header: &SdtHeader) -> Self {
+        let length = header.length;
+        let u8_at = |offset| read_field::<u8>(address, length, offset).unwrap_or(0);
+        let u16_at = |offset| read_field::<u16>(address, length, offset).unwrap_or(0);
+        let u32_at = |offset| read_field::<u32>(address, length, offset).unwrap_or(0);
+
+        let flags = u32_at(112);
+
+        // Prefer the 64 bit X_DSDT, the 32 bit one is for ACPI 1.0
+        let dsdt = match read_field::<u64>(address, length, 140) {
+            Some(x_dsdt) if x_dsdt != 0 => x_dsdt,
+            _ => u32_at(40) as u64,
+        };
+
+        let reset_register = if flags & RESET_REG_SUPPORTED != 0 {
+            match (read_field::<GenericAddress>(address, length, 116), read_field::<u8>(address, length, 128)) {
+                (Some(register), Some(value)) => Some((register, value)),
+                _ => None,
+            }
+        } else {
+            None
+        };
+
+        Fadt {
+            revision: header.revision,
+            dsdt_address: if dsdt != 0 { Some(PhysAddr::new(dsdt)) } else { None },
+            sci_interrupt: u16_at(46),
+            smi_command_port: u32_at(48),
+            acpi_enable: u8_at(52),
+            acpi_disable: u8_at(53),
+            pm1a_event_block: u32_at(56),
+            pm1b_event_block: u32_at(60),
+            pm1a_control_block: u32_at(64),
+            pm1b_control_block: u32_at(68),
+            pm_timer_block: u32_at(76),
+            pm1_event_length: u8_at(88),
+            pm1_control_length: u8_at(89),
+            // ACPI 1.0 doesn't have this field - it assumed a PC with all the legacy hardware
+            iapc_boot_arch: if header.revision >= 2 { u16_at(109) } else { BOOT_ARCH_8042 },
+            flags,
+            reset_register,
+        }
+    }
+
+    /// Whether the machine has an 8042 (PS/2) keyboard controller we can use to reset
+    pub fn has_8042(&self) -> bool {
+        self.iapc_boot_arch & BOOT_ARCH_8042 != 0
+    }
+}
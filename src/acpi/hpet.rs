@@ -0,0 +1,48 @@
+use x86_64::PhysAddr;
+use super::{read_field, GenericAddress, SdtHeader};
+
+/// # Hpet
+///
+/// The High Precision Event Timer, as described by the `HPET` table. A much nicer timer than the PIT -
+/// a 10+ MHz main counter and a few comparators, all memory mapped at `base_address`
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub hardware_revision: u8,
+    /// How many comparators (timers) it has
+    pub comparator_count: u8,
+    /// Whether the main counter is 64 bits wide (otherwise 32)
+    pub counter_64bit: bool,
+    /// Whether it can take over the PIT's IRQ 0 and the RTC's IRQ 8
+    pub legacy_replacement: bool,
+    pub pci_vendor_id: u16,
+    /// Physical address of the registers
+    pub base_address: PhysAddr,
+    /// Which HPET this is, if there are several
+    pub number: u8,
+    /// The smallest tick (in main counter clocks) that won't lose interrupts in periodic mode
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    /// Parse the HPET table at `address`. The checksum has already been checked. `None` if the table is
+    /// too short, or the registers aren't in memory space
+    pub(super) fn parse(address: PhysAddr, header: &SdtHeader) -> Option<Self> {
+        let length = header.length;
+        let block_id: u32 = read_field(address, length, 36)?;
+        let base: GenericAddress = read_field(address, length, 40)?;
+        if base.address_space != super::fadt::ADDRESS_SPACE_MEMORY {
+            return None;
+        }
+
+        Some(Hpet {
+            hardware_revision: block_id as u8,
+            comparator_count: ((block_id >> 8) & 0x1F) as u8 + 1,
+            counter_64bit: block_id & (1 << 13) != 0,
+            legacy_replacement: block_id & (1 << 15) != 0,
+            pci_vendor_id: (block_id >> 16) as u16,
+            base_address: PhysAddr::new(base.address),
+            number: read_field(address, length, 52)?,
+            minimum_tick: read_field(address, length, 53)?,
+        })
+    }
+}
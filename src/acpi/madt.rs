@@ -0,0 +1,184 @@
+use alloc::vec::Vec;
+use core::mem;
+use x86_64::PhysAddr;
+use super::{read_field, read_phys, SdtHeader};
+
+/// # Processor
+///
+/// A CPU core, as listed in the MADT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    /// The id ACPI uses for this processor (in the DSDT, for example)
+    pub acpi_id: u32,
+    /// The local APIC id - this is what we use to send it IPIs
+    pub apic_id: u32,
+    /// Whether the firmware says this processor can be used
+    pub enabled: bool,
+}
+
+/// # IoApic
+///
+/// An I/O APIC - routes device interrupts to local APICs. Each one handles a range of global system
+/// interrupts (GSIs), starting at `gsi_base`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    pub gsi_base: u32,
+}
+
+/// The polarity of an interrupt line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    /// Whatever the bus normally uses (active high for ISA)
+    BusDefault,
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Whether an interrupt line is edge or level triggered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    /// Whatever the bus normally uses (edge for ISA)
+    BusDefault,
+    Edge,
+    Level,
+}
+
+/// # InterruptOverride
+///
+/// Says that an ISA IRQ isn't connected to the GSI with the same number, or doesn't use the ISA polarity
+/// and trigger mode. On most machines the PIT (IRQ 0) is on GSI 2, and the SCI (IRQ 9) is level triggered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    /// Always 0 (ISA)
+    pub bus: u8,
+    pub source_irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// # LocalApicNmi
+///
+/// Which LINT pin of a processor's local APIC is wired to NMI. `acpi_id` 0xFF means every processor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicNmi {
+    pub acpi_id: u8,
+    pub lint: u8,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// # Madt
+///
+/// The Multiple APIC Description Table - lists the processors and interrupt controllers
+#[derive(Debug, Clone)]
+pub struct Madt {
+    /// Physical address of the local APIC registers (the same for every core)
+    pub local_apic_address: PhysAddr,
+    /// Set if the machine also has 8259 PICs, which must be masked if we switch to the APICs
+    pub has_8259_pics: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub interrupt_overrides: Vec<InterruptOverride>,
+    pub local_apic_nmis: Vec<LocalApicNmi>,
+}
+
+/// Split the MPS INTI flags (used by overrides and NMI entries) into polarity and trigger mode
+fn inti_flags(flags: u16) -> (Polarity, TriggerMode) {
+    let polarity = match flags & 0b11 {
+        0b01 => Polarity::ActiveHigh,
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::BusDefault,
+    };
+    let trigger = match (flags >> 2) & 0b11 {
+        0b01 => TriggerMode::Edge,
+        0b11 => TriggerMode::Level,
+        _ => TriggerMode::BusDefault,
+    };
+    (polarity, trigger)
+}
+
+impl Madt {
+    /// Parse the MADT at `address`. The checksum has already been checked
+    pub(super) fn parse(address: PhysAddr, header: &SdtHeader) -> Self {
+        let length = header.length;
+        let header_size = mem::size_of::<SdtHeader>();
+        let mut madt = Madt {
+            local_apic_address: PhysAddr::new(read_field::<u32>(address, length, header_size).unwrap_or(0) as u64),
+            has_8259_pics: read_field::<u32>(address, length, header_size + 4).unwrap_or(0) & 1 != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            interrupt_overrides: Vec::new(),
+            local_apic_nmis: Vec::new(),
+        };
+
+        let end = address + length as u64;
+        // The entries start after the header, the local APIC address (u32) and flags (u32)
+        let mut entry = address + header_size + 8u64;
+
+        while entry + 2u64 <= end {
+            let entry_type: u8 = read_phys(entry);
+            let entry_length: u8 = read_phys(entry + 1u64);
+            if entry_length < 2 || entry + entry_length as u64 > end {
+                break; // broken table, don't loop forever (or read past the end)
+            }
+
+            match entry_type {
+                // Processor local APIC: acpi id (u8), apic id (u8), flags (u32, bit 0 = enabled)
+                0 => {
+                    let flags: u32 = read_phys(entry + 4u64);
+                    madt.processors.push(Processor {
+                        acpi_id: read_phys::<u8>(entry + 2u64) as u32,
+                        apic_id: read_phys::<u8>(entry + 3u64) as u32,
+                        enabled: flags & 1 != 0,
+                    });
+                }
+                // I/O APIC: id (u8), reserved (u8), address (u32), GSI base (u32)
+                1 => madt.io_apics.push(IoApic {
+                    id: read_phys(entry + 2u64),
+                    address: PhysAddr::new(read_phys::<u32>(entry + 4u64) as u64),
+                    gsi_base: read_phys(entry + 8u64),
+                }),
+                // Interrupt source override: bus (u8), source IRQ (u8), GSI (u32), flags (u16)
+                2 => {
+                    let (polarity, trigger) = inti_flags(read_phys(entry + 8u64));
+                    madt.interrupt_overrides.push(InterruptOverride {
+                        bus: read_phys(entry + 2u64),
+                        source_irq: read_phys(entry + 3u64),
+                        gsi: read_phys(entry + 4u64),
+                        polarity,
+                        trigger,
+                    });
+                }
+                // Local APIC NMI: acpi id (u8), flags (u16), LINT pin (u8)
+                4 => {
+                    let (polarity, trigger) = inti_flags(read_phys(entry + 3u64));
+                    madt.local_apic_nmis.push(LocalApicNmi {
+                        acpi_id: read_phys(entry + 2u64),
+                        lint: read_phys(entry + 5u64),
+                        polarity,
+                        trigger,
+                    });
+                }
+                // Local APIC address override: reserved (u16), 64 bit address
+                5 => madt.local_apic_address = PhysAddr::new(read_phys(entry + 4u64)),
+                // Processor local x2APIC (for APIC ids over 254): reserved (u16), x2APIC id (u32), flags (u32), acpi uid (u32)
+                9 => {
+                    let flags: u32 = read_phys(entry + 8u64);
+                    madt.processors.push(Processor {
+                        acpi_id: read_phys(entry + 12u64),
+                        apic_id: read_phys(entry + 4u64),
+                        enabled: flags & 1 != 0,
+                    });
+                }
+                _ => {}
+            }
+
+            entry += entry_length as u64;
+        }
+
+        madt
+    }
+}
@@ -0,0 +1,61 @@
+use alloc::vec::Vec;
+use core::mem;
+use x86_64::PhysAddr;
+use super::{read_phys, SdtHeader};
+
+/// Size of one MCFG entry: base (u64), segment (u16), start bus (u8), end bus (u8), reserved (u32)
+const ENTRY_SIZE: usize = 16;
+
+/// # EcamRegion
+///
+/// A block of PCI Express memory mapped config space (ECAM) from the MCFG. Every function of every device
+/// on buses `start_bus..=end_bus` gets 4KiB of config space, at
+/// `base_address + (bus << 20 | device << 15 | function << 12)`. The base address is where bus 0 would be,
+/// even if the region starts at a later bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EcamRegion {
+    pub base_address: PhysAddr,
+    /// The PCI segment group. Always 0 on PCs with a single host bridge
+    pub segment_group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl EcamRegion {
+    /// Whether this region covers `bus` in `segment_group`
+    pub fn contains(&self, segment_group: u16, bus: u8) -> bool {
+        self.segment_group == segment_group && (self.start_bus..=self.end_bus).contains(&bus)
+    }
+
+    /// Physical address of the config space of `bus:device.function`. The caller makes sure this region
+    /// contains `bus`
+    pub fn config_address(&self, bus: u8, device: u8, function: u8) -> PhysAddr {
+        let offset = (bus as u64) << 20 | (device as u64) << 15 | (function as u64) << 12;
+        self.base_address + offset
+    }
+
+    /// Size of the region in bytes - 1MiB per bus
+    pub fn size(&self) -> u64 {
+        (self.end_bus as u64 - self.start_bus as u64 + 1) << 20
+    }
+}
+
+/// Parse the MCFG at `address`. The checksum has already been checked
+pub(super) fn parse(address: PhysAddr, header: &SdtHeader) -> Vec<EcamRegion> {
+    // The entries start after the header and 8 reserved bytes
+    let first_entry = mem::size_of::<SdtHeader>() + 8;
+    let entries = (header.length as usize).saturating_sub(first_entry) / ENTRY_SIZE;
+
+    (0..entries)
+        .map(|i| {
+            let entry = address + first_entry + i * ENTRY_SIZE;
+            EcamRegion {
+                base_address: PhysAddr::new(read_phys(entry)),
+                segment_group: read_phys(entry + 8u64),
+                start_bus: read_phys(entry + 10u64),
+                end_bus: read_phys(entry + 11u64),
+            }
+        })
+        .filter(|region| region.start_bus <= region.end_bus)
+        .collect()
+}
@@ -15,12 +15,13 @@ use x86_64::{
     },
     VirtAddr,
 }; // Used for memory allocation
+use crate::memory;
 
 
 /// Define the memory location where the heap starts
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-/// Define the heap size (100 KiB). We can increase this as we'd like
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+/// Define the heap size (1 MiB). We can increase this as we'd like - the ACPI namespace needs a fair bit of it
+pub const HEAP_SIZE: usize = 1024 * 1024; // 1 MiB
 
 
 /// We define our allocator here, which needs to inherit GlobalAlloc type.
@@ -44,8 +45,20 @@ unsafe impl GlobalAlloc for Dummy {
     }
 }
 
+/// Map the heap into pages with the kernel page table (see `memory::with_mapper`), then set up the allocator
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+    memory::with_mapper(|mapper, frame_allocator| map_heap(mapper, frame_allocator))?;
+
+    // Initalize our allocator
+    unsafe {
+        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+    }
+
+    Ok(())
+}
+
 /// This function takes a frame allocator and mapper, then maps the heap into pages
-pub fn init_heap(
+fn map_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
@@ -79,11 +92,6 @@ pub fn init_heap(
         };
     }
 
-    // Initalize our allocator
-    unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
-    }
-
     Ok(())
 }
 
@@ -0,0 +1,132 @@
+//! # Local APIC
+//!
+//! Every CPU core has its own local APIC (Advanced Programmable Interrupt Controller). We still take
+//! device interrupts through the legacy PIC, but the local APIC is how cores talk to each other - by
+//! sending IPIs (inter-processor interrupts). We use them to wake up halted cores and to start up
+//! application processors.
+//!
+//! The registers are memory mapped. Each core sees its *own* APIC at the same physical address, so
+//! we only need to map them (uncached, like any other device registers) and store the address once.
+
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::registers::model_specific::Msr;
+use conquer_once::spin::OnceCell;
+use core::ptr;
+use crate::{memory, serial_println};
+use crate::interrupts::InterruptIndex;
+
+/// The IA32_APIC_BASE MSR, which stores the physical base address of the local APIC and its enable bit
+const IA32_APIC_BASE_MSR: u32 = 0x1B;
+/// Bit 11 of IA32_APIC_BASE - globally enables the local APIC
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/* Register offsets (from the base address) */
+const REG_ID: usize = 0x20;
+const REG_EOI: usize = 0xB0;
+const REG_SPURIOUS: usize = 0xF0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+
+/// Bit 8 of the spurious interrupt vector register - software enables the APIC
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+/// ICR bit 12 - set while the previous IPI is still being delivered
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+/// ICR bit 14 - level assert (must be set for everything but INIT de-assert)
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+/// ICR delivery mode 101 - INIT, which resets the target core into its wait-for-SIPI state
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+/// ICR delivery mode 110 - start up (SIPI). The vector is the page number the core starts executing at
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+
+/// Virtual address of the local APIC registers. Set by [init](fn.init.html)
+static APIC_BASE: OnceCell<VirtAddr> = OnceCell::uninit();
+
+/// # init
+///
+/// Enable the local APIC of the calling CPU. Must be called once per core, after `memory::init` (the first
+/// call maps the registers, uncached, with `memory::map_device`).
+pub fn init() {
+    let base = unsafe {
+        let mut msr = Msr::new(IA32_APIC_BASE_MSR);
+        let value = msr.read() | APIC_BASE_ENABLE;
+        msr.write(value); // make sure the APIC is globally enabled
+        PhysAddr::new(value & 0x000F_FFFF_FFFF_F000) // bits 12..52 are the base address
+    };
+
+    if !is_initialized() {
+        // Every core sees its own APIC at the same address, so the BSP maps it once for all of them
+        let virt = memory::map_device(base, 4096).expect("failed to map the local APIC");
+        let _ = APIC_BASE.try_init_once(|| virt);
+    }
+
+    // Software enable the APIC, and set which vector spurious interrupts come in on
+    write(REG_SPURIOUS, SPURIOUS_APIC_ENABLE | InterruptIndex::ApicSpurious as u32);
+    serial_println!("[LOG] Local APIC {} enabled", id());
+}
+
+/// Check whether [init](fn.init.html) has run - before then, we can't send IPIs
+pub fn is_initialized() -> bool {
+    APIC_BASE.try_get().is_ok()
+}
+
+/// The local APIC id of the calling CPU
+pub fn id() -> u32 {
+    read(REG_ID) >> 24
+}
+
+/// Signal end of interrupt to the local APIC. Every interrupt delivered by the APIC (like IPIs) must call this
+/// before returning, or we won't get any more of them.
+pub fn end_of_interrupt() {
+    write(REG_EOI, 0);
+}
+
+/// Send a fixed interrupt with `vector` to the CPU whose local APIC id is `apic_id`.
+///
+/// Doesn't block or allocate, so it is safe to call from an interrupt handler.
+pub fn send_ipi(apic_id: u32, vector: u8) {
+    send_raw_ipi(apic_id, ICR_LEVEL_ASSERT | vector as u32);
+}
+
+/// Send an INIT IPI, the first step of starting an application processor
+pub fn send_init(apic_id: u32) {
+    send_raw_ipi(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Send a start up IPI (SIPI). The core starts running in real mode at physical address `page << 12`,
+/// so the code has to live in the first MiB.
+pub fn send_startup(apic_id: u32, page: u8) {
+    send_raw_ipi(apic_id, ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | page as u32);
+}
+
+/// Write `command` to the interrupt command register, targeting `apic_id`. The ICR low dword
+/// contains the vector, delivery mode and flags.
+pub(crate) fn send_raw_ipi(apic_id: u32, command: u32) {
+    use x86_64::instructions::interrupts;
+
+    // An interrupt handler sending its own IPI between our two writes would change the destination under us
+    interrupts::without_interrupts(|| {
+        wait_for_delivery(); // wait for the previous IPI to go through
+        write(REG_ICR_HIGH, apic_id << 24);
+        write(REG_ICR_LOW, command); // writing the low dword sends the IPI
+        wait_for_delivery();
+    });
+}
+
+/// Spin until the APIC has delivered the last IPI
+fn wait_for_delivery() {
+    while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Read a 32 bit APIC register
+fn read(register: usize) -> u32 {
+    let base = APIC_BASE.try_get().expect("local APIC not initialized");
+    unsafe { ptr::read_volatile((base.as_u64() as usize + register) as *const u32) }
+}
+
+/// Write a 32 bit APIC register
+fn write(register: usize, value: u32) {
+    let base = APIC_BASE.try_get().expect("local APIC not initialized");
+    unsafe { ptr::write_volatile((base.as_u64() as usize + register) as *mut u32, value) }
+}
@@ -0,0 +1,159 @@
+//! # Block devices
+//!
+//! Disks, and anything else that stores data in fixed size sectors. Storage drivers implement
+//! [BlockDevice](trait.BlockDevice.html) for each disk they find and [register](fn.register.html) it, so
+//! filesystems can use any disk without knowing what is behind it.
+//!
+//! Reads and writes are async - the driver starts the transfer, and the task sleeps until the disk's interrupt
+//! says it is done.
+//!
+//! Registering a disk reads its [partition table](partition/index.html), and registers each partition as a
+//! block device too. Block devices stack: a [RequestQueue](queue/struct.RequestQueue.html) merges requests
+//! for neighbouring sectors, and a [BufferCache](cache/struct.BufferCache.html) keeps recently used sectors in
+//! memory - both are block devices themselves, wrapping another.
+
+pub mod queue; // Merging requests for neighbouring sectors
+pub mod cache; // Write-back LRU cache of sectors
+pub mod partition; // MBR and GPT partition tables, and partitions as block devices
+pub mod ramdisk; // Block devices in memory
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use spin::Mutex;
+use crate::serial_println;
+
+/// # BlockError
+///
+/// Why a block request failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request goes past the end of the device
+    OutOfRange,
+    /// The buffer isn't a whole number of sectors
+    UnalignedBuffer,
+    /// Writing to a read only device
+    ReadOnly,
+    /// The device reported an error, or stopped responding
+    DeviceError(&'static str),
+}
+
+/// What `BlockDevice` methods return - a boxed future, so the trait can be used as `dyn BlockDevice`
+pub type BlockFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, BlockError>> + Send + 'a>>;
+
+/// # BlockDevice
+///
+/// A device made of `sector_count` sectors of `sector_size` bytes. Buffers must be a whole number of sectors
+pub trait BlockDevice: Send + Sync {
+    /// The name it is registered under, like `ata0`
+    fn name(&self) -> &str;
+
+    fn sector_size(&self) -> usize;
+
+    fn sector_count(&self) -> u64;
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// The name of the device this one is part of - a partition's disk
+    fn parent(&self) -> Option<&str> {
+        None
+    }
+
+    /// Read `buffer.len() / sector_size()` sectors, starting at `sector`
+    fn read<'a>(&'a self, sector: u64, buffer: &'a mut [u8]) -> BlockFuture<'a, ()>;
+
+    /// Write `buffer.len() / sector_size()` sectors, starting at `sector`
+    fn write<'a>(&'a self, sector: u64, buffer: &'a [u8]) -> BlockFuture<'a, ()>;
+
+    /// Make sure everything written so far is on the disk, not just in its cache
+    fn flush(&self) -> BlockFuture<'_, ()>;
+}
+
+/// # check_request
+///
+/// Check that a transfer of `length` bytes at `sector` fits `device`, returning how many sectors it is.
+/// Drivers call this before touching the hardware
+pub fn check_request(device: &dyn BlockDevice, sector: u64, length: usize) -> Result<u64, BlockError> {
+    let sector_size = device.sector_size();
+    if length % sector_size != 0 {
+        return Err(BlockError::UnalignedBuffer);
+    }
+    let count = (length / sector_size) as u64;
+    match sector.checked_add(count) {
+        Some(end) if end <= device.sector_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// Every registered block device
+static DEVICES: Mutex<Vec<Arc<dyn BlockDevice>>> = Mutex::new(Vec::new());
+
+/// # register
+///
+/// Make a block device available. Its name must be unique. A disk's partitions are registered after it, so
+/// this reads from the disk - call it where waiting is fine, like a driver's `probe`
+pub fn register(device: Arc<dyn BlockDevice>) {
+    serial_println!(
+        "[LOG] Block device {}: {} sectors of {} bytes ({} MiB)",
+        device.name(),
+        device.sector_count(),
+        device.sector_size(),
+        device.sector_count() * device.sector_size() as u64 >> 20
+    );
+    DEVICES.lock().push(device.clone());
+    if device.parent().is_none() {
+        partition::scan(&device);
+    }
+}
+
+/// Remove the block device called `name`, and its partitions - its driver is going away
+pub fn unregister(name: &str) {
+    DEVICES.lock().retain(|device| device.name() != name && device.parent() != Some(name));
+}
+
+/// The block device called `name`
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.lock().iter().find(|device| device.name() == name).cloned()
+}
+
+/// Every registered block device
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.lock().clone()
+}
+
+/* Testing */
+
+#[test_case]
+fn block_request_checks() {
+    struct Disk;
+    impl BlockDevice for Disk {
+        fn name(&self) -> &str {
+            "test"
+        }
+        fn sector_size(&self) -> usize {
+            512
+        }
+        fn sector_count(&self) -> u64 {
+            100
+        }
+        fn read<'a>(&'a self, _sector: u64, _buffer: &'a mut [u8]) -> BlockFuture<'a, ()> {
+            Box::pin(async { Ok(()) })
+        }
+        fn write<'a>(&'a self, _sector: u64, _buffer: &'a [u8]) -> BlockFuture<'a, ()> {
+            Box::pin(async { Ok(()) })
+        }
+        fn flush(&self) -> BlockFuture<'_, ()> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    assert_eq!(check_request(&Disk, 0, 1024), Ok(2));
+    assert_eq!(check_request(&Disk, 98, 1024), Ok(2));
+    assert_eq!(check_request(&Disk, 99, 1024), Err(BlockError::OutOfRange));
+    assert_eq!(check_request(&Disk, u64::MAX, 512), Err(BlockError::OutOfRange));
+    assert_eq!(check_request(&Disk, 0, 100), Err(BlockError::UnalignedBuffer));
+}
@@ -0,0 +1,325 @@
+//! # Buffer cache
+//!
+//! A [BufferCache](struct.BufferCache.html) keeps recently used sectors of a block device in memory, so
+//! reading them again doesn't go to the disk. It is write-back: writes only change the cached copy and mark it
+//! dirty, and dirty sectors go to the disk when they are evicted or the cache is flushed. When the cache is
+//! full the least recently used sector is evicted.
+//!
+//! Like the request queue it is a block device itself, so a filesystem can sit on a cache, on a request
+//! queue, on a disk. Nothing is on the disk for sure until `flush` - filesystems flush when they sync.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use super::{check_request, BlockDevice, BlockError, BlockFuture};
+
+/// The most `flush` writes in one go, when dirty sectors are next to each other
+const MAX_WRITEBACK_BYTES: usize = 64 * 1024;
+
+/// A cached sector. `version` changes every time it is written, so a write back can tell whether the sector
+/// was written again while it was on its way to the disk
+struct Entry {
+    data: Box<[u8]>,
+    dirty: bool,
+    last_used: u64,
+    version: u64,
+}
+
+struct Sectors {
+    entries: BTreeMap<u64, Entry>,
+    /// Counts every use, to order them
+    clock: u64,
+}
+
+impl Sectors {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+/// # CacheStats
+///
+/// How the cache has done so far, in sectors
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Dirty sectors written to the disk
+    pub written_back: u64,
+}
+
+/// # BufferCache
+///
+/// A write-back LRU cache of a block device's sectors
+pub struct BufferCache {
+    device: Arc<dyn BlockDevice>,
+    /// How many sectors it keeps, at most (it can go over while evicted sectors are written back)
+    capacity: usize,
+    sectors: Mutex<Sectors>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    written_back: AtomicU64,
+}
+
+impl BufferCache {
+    /// A cache of up to `capacity` sectors of `device`
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> Self {
+        BufferCache {
+            device,
+            capacity: capacity.max(1),
+            sectors: Mutex::new(Sectors { entries: BTreeMap::new(), clock: 0 }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            written_back: AtomicU64::new(0),
+        }
+    }
+
+    /// The device behind the cache
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            written_back: self.written_back.load(Ordering::Relaxed),
+        }
+    }
+
+    /// How many sectors are cached
+    pub fn len(&self) -> usize {
+        self.sectors.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How many cached sectors haven't been written to the disk
+    pub fn dirty_count(&self) -> usize {
+        self.sectors.lock().entries.values().filter(|entry| entry.dirty).count()
+    }
+
+    /// Whether `sector` is cached and dirty
+    pub fn is_dirty(&self, sector: u64) -> bool {
+        self.sectors.lock().entries.get(&sector).map_or(false, |entry| entry.dirty)
+    }
+
+    /// Forget every clean sector - for when the disk may have changed under us
+    pub fn invalidate_clean(&self) {
+        self.sectors.lock().entries.retain(|_, entry| entry.dirty);
+    }
+
+    /// Mark the sectors written back at `versions` clean, unless they were written again in the meantime
+    fn mark_clean(&self, versions: &[(u64, u64)]) {
+        let mut sectors = self.sectors.lock();
+        for (sector, version) in versions {
+            if let Some(entry) = sectors.entries.get_mut(sector) {
+                if entry.version == *version {
+                    entry.dirty = false;
+                }
+            }
+        }
+    }
+
+    /// Evict least recently used sectors until there are at most `capacity`, writing back dirty ones
+    async fn make_room(&self) -> Result<(), BlockError> {
+        loop {
+            let (sector, data, version) = {
+                let mut sectors = self.sectors.lock();
+                if sectors.entries.len() <= self.capacity {
+                    return Ok(());
+                }
+                let (&sector, entry) = sectors.entries.iter().min_by_key(|(_, entry)| entry.last_used).unwrap();
+                if !entry.dirty {
+                    sectors.entries.remove(&sector);
+                    continue;
+                }
+                (sector, entry.data.clone(), entry.version)
+            };
+            self.device.write(sector, &data).await?;
+            self.written_back.fetch_add(1, Ordering::Relaxed);
+            // Clean now, so the next time round evicts it - unless it was used again meanwhile
+            self.mark_clean(&[(sector, version)]);
+        }
+    }
+
+    /// Write every dirty sector to the disk, neighbours together
+    async fn write_back(&self) -> Result<(), BlockError> {
+        let sector_size = self.device.sector_size();
+        let max_run = (MAX_WRITEBACK_BYTES / sector_size).max(1);
+        // Copy them out first - the lock can't be held while we wait for the disk
+        let dirty: Vec<(u64, Box<[u8]>, u64)> = self
+            .sectors
+            .lock()
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(&sector, entry)| (sector, entry.data.clone(), entry.version))
+            .collect();
+
+        let mut index = 0;
+        while index < dirty.len() {
+            let first = dirty[index].0;
+            let mut end = index + 1;
+            while end < dirty.len() && end - index < max_run && dirty[end].0 == first + (end - index) as u64 {
+                end += 1;
+            }
+            let run = &dirty[index..end];
+            let mut buffer = Vec::with_capacity(run.len() * sector_size);
+            for (_, data, _) in run {
+                buffer.extend_from_slice(data);
+            }
+            self.device.write(first, &buffer).await?;
+            self.written_back.fetch_add(run.len() as u64, Ordering::Relaxed);
+            let versions: Vec<(u64, u64)> = run.iter().map(|(sector, _, version)| (*sector, *version)).collect();
+            self.mark_clean(&versions);
+            index = end;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for BufferCache {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn sector_size(&self) -> usize {
+        self.device.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.device.sector_count()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+
+    fn read<'a>(&'a self, sector: u64, buffer: &'a mut [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            let count = check_request(self, sector, buffer.len())?;
+            let sector_size = self.sector_size();
+
+            // Copy out what is cached, and collect runs of sectors that aren't
+            let mut missing: Vec<(u64, u64)> = Vec::new();
+            {
+                let mut sectors = self.sectors.lock();
+                let now = sectors.tick();
+                for index in 0..count {
+                    let offset = index as usize * sector_size;
+                    match sectors.entries.get_mut(&(sector + index)) {
+                        Some(entry) => {
+                            buffer[offset..offset + sector_size].copy_from_slice(&entry.data);
+                            entry.last_used = now;
+                        }
+                        None => match missing.last_mut() {
+                            Some((first, length)) if *first + *length == sector + index => *length += 1,
+                            _ => missing.push((sector + index, 1)),
+                        },
+                    }
+                }
+            }
+            let missed: u64 = missing.iter().map(|(_, length)| length).sum();
+            self.hits.fetch_add(count - missed, Ordering::Relaxed);
+            self.misses.fetch_add(missed, Ordering::Relaxed);
+
+            for (first, length) in missing {
+                let mut data = vec![0; length as usize * sector_size];
+                self.device.read(first, &mut data).await?;
+                let mut sectors = self.sectors.lock();
+                let now = sectors.tick();
+                for (index, fresh) in data.chunks(sector_size).enumerate() {
+                    let offset = (first - sector) as usize * sector_size + index * sector_size;
+                    // If it was written while we were reading, the cached copy is newer than the disk's
+                    let entry = sectors.entries.entry(first + index as u64).or_insert_with(|| Entry {
+                        data: fresh.into(),
+                        dirty: false,
+                        last_used: now,
+                        version: now,
+                    });
+                    buffer[offset..offset + sector_size].copy_from_slice(&entry.data);
+                }
+            }
+            self.make_room().await
+        })
+    }
+
+    fn write<'a>(&'a self, sector: u64, buffer: &'a [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            check_request(self, sector, buffer.len())?;
+            if self.is_read_only() {
+                return Err(BlockError::ReadOnly);
+            }
+            let sector_size = self.sector_size();
+            {
+                let mut sectors = self.sectors.lock();
+                let now = sectors.tick();
+                for (index, data) in buffer.chunks(sector_size).enumerate() {
+                    let entry = sectors.entries.entry(sector + index as u64).or_insert_with(|| Entry {
+                        data: vec![0; sector_size].into_boxed_slice(),
+                        dirty: false,
+                        last_used: now,
+                        version: now,
+                    });
+                    entry.data.copy_from_slice(data);
+                    entry.dirty = true;
+                    entry.last_used = now;
+                    entry.version = now;
+                }
+            }
+            self.make_room().await
+        })
+    }
+
+    fn flush(&self) -> BlockFuture<'_, ()> {
+        Box::pin(async move {
+            self.write_back().await?;
+            self.device.flush().await
+        })
+    }
+}
+
+/* Testing */
+
+#[test_case]
+fn buffer_cache_write_back() {
+    use alloc::string::String;
+    use crate::task::block_on;
+    use super::ramdisk::RamDisk;
+
+    let disk = Arc::new(RamDisk::new(String::from("cache-test"), 512, 64));
+    let cache = BufferCache::new(disk.clone(), 4);
+    let ones = vec![1u8; 1024];
+    block_on(cache.write(0, &ones)).unwrap();
+    assert_eq!(cache.dirty_count(), 2);
+    // Not on the disk yet
+    let mut buffer = vec![0xFFu8; 1024];
+    block_on(disk.read(0, &mut buffer)).unwrap();
+    assert!(buffer.iter().all(|&byte| byte == 0));
+    // But read back from the cache
+    block_on(cache.read(0, &mut buffer)).unwrap();
+    assert_eq!(buffer, ones);
+    assert_eq!(cache.stats().hits, 2);
+
+    // Filling the cache evicts the least recently used sectors - the dirty ones, which are written back
+    let mut other = vec![0u8; 2048];
+    block_on(cache.read(8, &mut other)).unwrap();
+    block_on(cache.read(12, &mut other[..512])).unwrap();
+    assert_eq!(cache.len(), 4);
+    assert_eq!(cache.dirty_count(), 0);
+    block_on(disk.read(0, &mut buffer)).unwrap();
+    assert_eq!(buffer, ones);
+
+    block_on(cache.write(20, &ones[..512])).unwrap();
+    block_on(cache.flush()).unwrap();
+    assert_eq!(cache.dirty_count(), 0);
+    assert_eq!(cache.stats().written_back, 3);
+}
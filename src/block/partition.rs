@@ -0,0 +1,382 @@
+//! # Partitions
+//!
+//! Disks are usually split into partitions, listed in a table at the start of the disk. Two kinds are read:
+//!
+//! * MBR - four entries at the end of sector 0 (which ends `55 AA`). One of them can be an extended partition,
+//!   holding a chain of boot records, each with one more (logical) partition - numbered from 5.
+//! * GPT - a header in sector 1 pointing at an array of entries, both checked with CRC32. The disk's MBR then
+//!   has a single protective entry (type `EE`) covering it.
+//!
+//! [scan](fn.scan.html) registers each partition as a block device of its own - a window onto its parent
+//! disk - named after the disk: `ata0p1`, `nvme0n1p2`...
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::task::block_on;
+use super::{check_request, BlockDevice, BlockError, BlockFuture};
+
+const MBR_ENTRIES: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_SIGNATURE: usize = 510;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+/// CHS, LBA and Linux extended partitions
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+/// How many logical partitions we follow an extended partition's chain for
+const MAX_LOGICAL_PARTITIONS: u32 = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_LBA: u64 = 1;
+/// The header size in revision 1.0 - it can be bigger, but not smaller
+const GPT_MIN_HEADER_SIZE: usize = 92;
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+/// How many entries we read, at most. Tables are usually 128 long
+const GPT_MAX_ENTRIES: usize = 256;
+
+/// # crc32
+///
+/// The CRC32 (IEEE 802.3, as used by GPT and zip) of `data`
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (!(crc & 1)).wrapping_add(1));
+        }
+    }
+    !crc
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u32_at(data, offset) as u64 | (u32_at(data, offset + 4) as u64) << 32
+}
+
+/// # PartitionKind
+///
+/// What the table says a partition is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionKind {
+    /// The MBR type byte (`0x0C` for FAT32, `0x83` for Linux...)
+    Mbr(u8),
+    /// The type GUID, as stored on disk (mixed endian), and the partition's name
+    Gpt { type_guid: [u8; 16], name: String },
+}
+
+/// # PartitionInfo
+///
+/// A partition table entry - where the partition is on its disk, in sectors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionInfo {
+    /// 1 to 4 for MBR primary partitions, 5 on for logical ones, and the entry number (from 1) for GPT
+    pub number: u32,
+    pub start: u64,
+    pub count: u64,
+    pub kind: PartitionKind,
+}
+
+/// An MBR entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct MbrEntry {
+    kind: u8,
+    start: u64,
+    count: u64,
+}
+
+/// The four entries of the MBR (or extended boot record) in `sector`. `None` if it doesn't end with the
+/// signature, or an entry's status isn't 0 or 0x80 - then it is boot code, not a partition table
+fn mbr_entries(sector: &[u8]) -> Option<[MbrEntry; 4]> {
+    if sector.len() < 512 || sector[MBR_SIGNATURE..MBR_SIGNATURE + 2] != [0x55, 0xAA] {
+        return None;
+    }
+    let mut entries = [MbrEntry { kind: 0, start: 0, count: 0 }; 4];
+    for (index, entry) in entries.iter_mut().enumerate() {
+        let raw = &sector[MBR_ENTRIES + index * MBR_ENTRY_SIZE..MBR_ENTRIES + (index + 1) * MBR_ENTRY_SIZE];
+        if raw[0] & 0x7F != 0 {
+            return None;
+        }
+        *entry = MbrEntry { kind: raw[4], start: u32_at(raw, 8) as u64, count: u32_at(raw, 12) as u64 };
+    }
+    Some(entries)
+}
+
+/// Where a GPT's entries are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct GptHeader {
+    entries_lba: u64,
+    entry_count: usize,
+    entry_size: usize,
+    entries_crc: u32,
+}
+
+/// The GPT header in `sector`, if it is one and its CRC is right
+fn gpt_header(sector: &[u8]) -> Option<GptHeader> {
+    if sector.len() < GPT_MIN_HEADER_SIZE || &sector[..8] != GPT_SIGNATURE {
+        return None;
+    }
+    let header_size = u32_at(sector, 12) as usize;
+    if header_size < GPT_MIN_HEADER_SIZE || header_size > sector.len() {
+        return None;
+    }
+    // The CRC is of the header with the CRC field zeroed
+    let mut header = sector[..header_size].to_vec();
+    header[16..20].copy_from_slice(&[0; 4]);
+    if crc32(&header) != u32_at(sector, 16) {
+        return None;
+    }
+    let entry_size = u32_at(sector, 84) as usize;
+    if entry_size < GPT_MIN_ENTRY_SIZE || entry_size % 8 != 0 {
+        return None;
+    }
+    Some(GptHeader {
+        entries_lba: u64_at(sector, 72),
+        entry_count: (u32_at(sector, 80) as usize).min(GPT_MAX_ENTRIES),
+        entry_size,
+        entries_crc: u32_at(sector, 88),
+    })
+}
+
+/// The partitions in a GPT's entry array. Unused entries have a zero type GUID
+fn gpt_partitions(entries: &[u8], header: &GptHeader) -> Vec<PartitionInfo> {
+    let mut partitions = Vec::new();
+    for (index, entry) in entries.chunks_exact(header.entry_size).take(header.entry_count).enumerate() {
+        let mut type_guid = [0; 16];
+        type_guid.copy_from_slice(&entry[..16]);
+        let (first, last) = (u64_at(entry, 32), u64_at(entry, 40));
+        if type_guid == [0; 16] || last < first {
+            continue;
+        }
+        let name = core::char::decode_utf16(entry[56..128].chunks_exact(2).map(|unit| u16::from_le_bytes([unit[0], unit[1]])))
+            .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+            .take_while(|&c| c != '\0')
+            .collect();
+        partitions.push(PartitionInfo {
+            number: index as u32 + 1,
+            start: first,
+            count: last - first + 1,
+            kind: PartitionKind::Gpt { type_guid, name },
+        });
+    }
+    partitions
+}
+
+/// Read the GPT of `device`. `None` if there isn't a valid one
+async fn read_gpt(device: &dyn BlockDevice) -> Result<Option<Vec<PartitionInfo>>, BlockError> {
+    let sector_size = device.sector_size();
+    let mut sector = vec![0; sector_size];
+    device.read(GPT_HEADER_LBA, &mut sector).await?;
+    let header = match gpt_header(&sector) {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let length = header.entry_count * header.entry_size;
+    let sectors = ((length + sector_size - 1) / sector_size) as u64;
+    if header.entries_lba.checked_add(sectors).map_or(true, |end| end > device.sector_count()) {
+        return Ok(None);
+    }
+    let mut entries = vec![0; sectors as usize * sector_size];
+    device.read(header.entries_lba, &mut entries).await?;
+    if crc32(&entries[..length]) != header.entries_crc {
+        return Ok(None);
+    }
+    Ok(Some(gpt_partitions(&entries[..length], &header)))
+}
+
+/// Follow the chain of extended boot records in the extended partition at `extended`
+async fn read_logical(device: &dyn BlockDevice, extended: u64) -> Result<Vec<PartitionInfo>, BlockError> {
+    let mut partitions = Vec::new();
+    let mut sector = vec![0; device.sector_size()];
+    let mut next = 0;
+    for number in 5..5 + MAX_LOGICAL_PARTITIONS {
+        let record = extended + next;
+        if record >= device.sector_count() {
+            break;
+        }
+        device.read(record, &mut sector).await?;
+        let entries = match mbr_entries(&sector) {
+            Some(entries) => entries,
+            None => break,
+        };
+        // The first entry is the logical partition (relative to this record), the second the next record
+        // (relative to the extended partition)
+        if entries[0].kind != 0 && entries[0].count != 0 {
+            let kind = PartitionKind::Mbr(entries[0].kind);
+            partitions.push(PartitionInfo { number, start: record + entries[0].start, count: entries[0].count, kind });
+        }
+        if !MBR_TYPES_EXTENDED.contains(&entries[1].kind) || entries[1].start == 0 {
+            break;
+        }
+        next = entries[1].start;
+    }
+    Ok(partitions)
+}
+
+/// # read_table
+///
+/// Read the partition table of `device` - GPT if it has one, otherwise MBR. Empty if it has neither, and
+/// partitions that don't fit on the device are left out
+pub async fn read_table(device: &dyn BlockDevice) -> Result<Vec<PartitionInfo>, BlockError> {
+    let sector_size = device.sector_size();
+    if sector_size < 512 || device.sector_count() < 2 {
+        return Ok(Vec::new());
+    }
+    let mut sector = vec![0; sector_size];
+    device.read(0, &mut sector).await?;
+    let entries = match mbr_entries(&sector) {
+        Some(entries) => entries,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut partitions = Vec::new();
+    if entries.iter().any(|entry| entry.kind == MBR_TYPE_GPT_PROTECTIVE) {
+        if let Some(gpt) = read_gpt(device).await? {
+            partitions = gpt;
+        }
+    } else {
+        for (index, entry) in entries.iter().enumerate() {
+            if entry.kind == 0 || entry.count == 0 {
+                continue;
+            }
+            match MBR_TYPES_EXTENDED.contains(&entry.kind) {
+                true => partitions.extend(read_logical(device, entry.start).await?),
+                false => partitions.push(PartitionInfo {
+                    number: index as u32 + 1,
+                    start: entry.start,
+                    count: entry.count,
+                    kind: PartitionKind::Mbr(entry.kind),
+                }),
+            }
+        }
+    }
+    let sector_count = device.sector_count();
+    partitions.retain(|partition| partition.start > 0 && partition.start.checked_add(partition.count).map_or(false, |end| end <= sector_count));
+    Ok(partitions)
+}
+
+/// The name of partition `number` of `disk` - a `p` goes between them if the disk's name ends with a digit
+pub fn partition_name(disk: &str, number: u32) -> String {
+    match disk.chars().last().map_or(false, |c| c.is_ascii_digit()) {
+        true => format!("{}p{}", disk, number),
+        false => format!("{}{}", disk, number),
+    }
+}
+
+/// # Partition
+///
+/// A partition, as a block device - sector 0 is its first sector on the disk
+pub struct Partition {
+    name: String,
+    disk: Arc<dyn BlockDevice>,
+    info: PartitionInfo,
+}
+
+impl Partition {
+    pub fn new(disk: Arc<dyn BlockDevice>, info: PartitionInfo) -> Self {
+        Partition { name: partition_name(disk.name(), info.number), disk, info }
+    }
+
+    pub fn info(&self) -> &PartitionInfo {
+        &self.info
+    }
+
+    /// The disk it is part of
+    pub fn disk(&self) -> &Arc<dyn BlockDevice> {
+        &self.disk
+    }
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        self.disk.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.info.count
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.disk.is_read_only()
+    }
+
+    fn parent(&self) -> Option<&str> {
+        Some(self.disk.name())
+    }
+
+    fn read<'a>(&'a self, sector: u64, buffer: &'a mut [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            check_request(self, sector, buffer.len())?;
+            self.disk.read(self.info.start + sector, buffer).await
+        })
+    }
+
+    fn write<'a>(&'a self, sector: u64, buffer: &'a [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            check_request(self, sector, buffer.len())?;
+            self.disk.write(self.info.start + sector, buffer).await
+        })
+    }
+
+    fn flush(&self) -> BlockFuture<'_, ()> {
+        self.disk.flush()
+    }
+}
+
+/// # scan
+///
+/// Read the partition table of `disk`, and register a block device for each partition. Returns their names.
+/// [block::register](../fn.register.html) does this for every disk, so drivers don't need to
+pub fn scan(disk: &Arc<dyn BlockDevice>) -> Vec<String> {
+    let partitions = match block_on(read_table(&**disk)) {
+        Ok(partitions) => partitions,
+        Err(_) => return Vec::new(),
+    };
+    partitions
+        .into_iter()
+        .map(|info| {
+            let partition = Arc::new(Partition::new(disk.clone(), info));
+            let name = String::from(partition.name());
+            super::register(partition);
+            name
+        })
+        .collect()
+}
+
+/* Testing */
+
+#[test_case]
+fn partition_crc32() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+}
+
+#[test_case]
+fn partition_mbr_entries() {
+    let mut sector = vec![0u8; 512];
+    assert_eq!(mbr_entries(&sector), None);
+    sector[510] = 0x55;
+    sector[511] = 0xAA;
+    let entry = MBR_ENTRIES + MBR_ENTRY_SIZE;
+    sector[entry] = 0x80;
+    sector[entry + 4] = 0x0C;
+    sector[entry + 8..entry + 12].copy_from_slice(&2048u32.to_le_bytes());
+    sector[entry + 12..entry + 16].copy_from_slice(&4096u32.to_le_bytes());
+    let entries = mbr_entries(&sector).unwrap();
+    assert_eq!(entries[1], MbrEntry { kind: 0x0C, start: 2048, count: 4096 });
+    assert_eq!(entries[0].kind, 0);
+    // Boot code where the table should be
+    sector[MBR_ENTRIES] = 0xEB;
+    assert_eq!(mbr_entries(&sector), None);
+
+    assert_eq!(partition_name("ata0", 1), "ata0p1");
+    assert_eq!(partition_name("sda", 2), "sda2");
+}
@@ -0,0 +1,332 @@
+//! # Request queue
+//!
+//! A [RequestQueue](struct.RequestQueue.html) sits in front of a block device and merges requests: reads (or
+//! writes) of neighbouring sectors that are waiting at the same time go to the device as one bigger transfer,
+//! which is much cheaper than many small ones. It is a block device itself, so anything that takes a block
+//! device can use it.
+//!
+//! There is no thread behind the queue. The first request to arrive while nothing is being sent becomes the
+//! dispatcher: it lets the other tasks that are ready run (so their requests can join it), then sends
+//! everything waiting, in batches, until the queue is empty. Requests are sent in the order they arrived -
+//! one only joins the batch before it, so a read never passes a write to the same sector. Flushes are never
+//! merged with reads or writes, so everything before a flush is on the disk by the time it finishes.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use spin::Mutex;
+use crate::task::channel::oneshot;
+use super::{check_request, BlockDevice, BlockError, BlockFuture};
+
+/// The most a merged transfer moves. A request bigger than this still goes as it is
+const MAX_MERGE_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Read,
+    Write,
+    Flush,
+}
+
+/// A request waiting to be sent. Reads bring a buffer to fill, writes their data. The result (and a read's
+/// buffer) goes back through `done`
+struct Request {
+    kind: Kind,
+    sector: u64,
+    count: u64,
+    data: Vec<u8>,
+    done: oneshot::Sender<Result<Vec<u8>, BlockError>>,
+}
+
+/// Requests for neighbouring sectors that go to the device as one, in sector order
+struct Batch {
+    kind: Kind,
+    sector: u64,
+    count: u64,
+    requests: VecDeque<Request>,
+}
+
+impl Batch {
+    /// Add `request` to the batch, if it is the same kind and next to it, and the batch stays small enough.
+    /// Gives it back if not
+    fn merge(&mut self, request: Request, sector_size: usize) -> Result<(), Request> {
+        if request.kind != self.kind {
+            return Err(request);
+        }
+        if self.kind == Kind::Flush {
+            self.requests.push_back(request);
+            return Ok(());
+        }
+        if (self.count + request.count) as usize * sector_size > MAX_MERGE_BYTES {
+            return Err(request);
+        }
+        if request.sector == self.sector + self.count {
+            self.count += request.count;
+            self.requests.push_back(request);
+            Ok(())
+        } else if request.sector + request.count == self.sector {
+            self.sector = request.sector;
+            self.count += request.count;
+            self.requests.push_front(request);
+            Ok(())
+        } else {
+            Err(request)
+        }
+    }
+}
+
+/// Group `requests` into batches, keeping their order - each can only join the batch before it
+fn merge(requests: Vec<Request>, sector_size: usize) -> Vec<Batch> {
+    let mut batches: Vec<Batch> = Vec::new();
+    for request in requests {
+        let request = match batches.last_mut() {
+            Some(batch) => match batch.merge(request, sector_size) {
+                Ok(()) => continue,
+                Err(request) => request,
+            },
+            None => request,
+        };
+        let mut requests = VecDeque::new();
+        let (kind, sector, count) = (request.kind, request.sector, request.count);
+        requests.push_back(request);
+        batches.push(Batch { kind, sector, count, requests });
+    }
+    batches
+}
+
+/// Returns pending once, so the tasks that are ready run before the dispatcher takes the queue
+#[derive(Default)]
+struct Plug {
+    yielded: bool,
+}
+
+impl Future for Plug {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// # QueueStats
+///
+/// How many requests a queue was given, and how many transfers it sent the device for them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    pub submitted: u64,
+    pub dispatched: u64,
+}
+
+/// # RequestQueue
+///
+/// Merges requests for a block device
+pub struct RequestQueue {
+    device: Arc<dyn BlockDevice>,
+    pending: Mutex<VecDeque<Request>>,
+    /// Whether a request is dispatching. Only changed with `pending` locked
+    dispatching: AtomicBool,
+    submitted: AtomicU64,
+    dispatched: AtomicU64,
+}
+
+/// Clears `dispatching` when the dispatcher stops. If it stopped early (its future was dropped), the requests
+/// still waiting fail rather than wait for a dispatcher that isn't coming
+struct DispatchGuard<'a> {
+    queue: &'a RequestQueue,
+    finished: bool,
+}
+
+impl<'a> Drop for DispatchGuard<'a> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let mut pending = self.queue.pending.lock();
+            pending.clear(); // dropping the senders fails the requests
+            self.queue.dispatching.store(false, Ordering::Release);
+        }
+    }
+}
+
+impl RequestQueue {
+    pub fn new(device: Arc<dyn BlockDevice>) -> Self {
+        RequestQueue {
+            device,
+            pending: Mutex::new(VecDeque::new()),
+            dispatching: AtomicBool::new(false),
+            submitted: AtomicU64::new(0),
+            dispatched: AtomicU64::new(0),
+        }
+    }
+
+    /// The device behind the queue
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        QueueStats { submitted: self.submitted.load(Ordering::Relaxed), dispatched: self.dispatched.load(Ordering::Relaxed) }
+    }
+
+    /// Queue a request, dispatching if nobody else is, and wait for it
+    async fn submit(&self, kind: Kind, sector: u64, count: u64, data: Vec<u8>) -> Result<Vec<u8>, BlockError> {
+        let (done, result) = oneshot::channel();
+        let dispatch = {
+            let mut pending = self.pending.lock();
+            pending.push_back(Request { kind, sector, count, data, done });
+            self.submitted.fetch_add(1, Ordering::Relaxed);
+            !self.dispatching.swap(true, Ordering::AcqRel)
+        };
+        if dispatch {
+            self.dispatch().await;
+        }
+        result.await.unwrap_or(Err(BlockError::DeviceError("request dropped by the queue")))
+    }
+
+    /// Send everything waiting, until the queue is empty
+    async fn dispatch(&self) {
+        let mut guard = DispatchGuard { queue: self, finished: false };
+        // Let requests made alongside this one arrive, so they can join it
+        Plug::default().await;
+        loop {
+            let requests: Vec<Request> = {
+                let mut pending = self.pending.lock();
+                if pending.is_empty() {
+                    self.dispatching.store(false, Ordering::Release);
+                    guard.finished = true;
+                    return;
+                }
+                pending.drain(..).collect()
+            };
+            for batch in merge(requests, self.device.sector_size()) {
+                self.run(batch).await;
+            }
+        }
+    }
+
+    /// Send `batch` to the device as one transfer, and hand each request its part of the result
+    async fn run(&self, batch: Batch) {
+        self.dispatched.fetch_add(1, Ordering::Relaxed);
+        let sector_size = self.device.sector_size();
+        match batch.kind {
+            Kind::Read => {
+                let mut buffer = vec![0; batch.count as usize * sector_size];
+                let result = self.device.read(batch.sector, &mut buffer).await;
+                for Request { sector, mut data, done, .. } in batch.requests {
+                    let start = (sector - batch.sector) as usize * sector_size;
+                    let result = result.map(|_| {
+                        data.copy_from_slice(&buffer[start..start + data.len()]);
+                        data
+                    });
+                    let _ = done.send(result);
+                }
+            }
+            Kind::Write => {
+                let mut buffer = Vec::with_capacity(batch.count as usize * sector_size);
+                for request in batch.requests.iter() {
+                    buffer.extend_from_slice(&request.data);
+                }
+                let result = self.device.write(batch.sector, &buffer).await;
+                for request in batch.requests {
+                    let _ = request.done.send(result.map(|_| Vec::new()));
+                }
+            }
+            Kind::Flush => {
+                let result = self.device.flush().await;
+                for request in batch.requests {
+                    let _ = request.done.send(result.map(|_| Vec::new()));
+                }
+            }
+        }
+    }
+}
+
+impl BlockDevice for RequestQueue {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn sector_size(&self) -> usize {
+        self.device.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.device.sector_count()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+
+    fn read<'a>(&'a self, sector: u64, buffer: &'a mut [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            let count = check_request(self, sector, buffer.len())?;
+            if count == 0 {
+                return Ok(());
+            }
+            let data = self.submit(Kind::Read, sector, count, vec![0; buffer.len()]).await?;
+            buffer.copy_from_slice(&data);
+            Ok(())
+        })
+    }
+
+    fn write<'a>(&'a self, sector: u64, buffer: &'a [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            let count = check_request(self, sector, buffer.len())?;
+            if self.is_read_only() {
+                return Err(BlockError::ReadOnly);
+            }
+            if count == 0 {
+                return Ok(());
+            }
+            self.submit(Kind::Write, sector, count, buffer.to_vec()).await.map(|_| ())
+        })
+    }
+
+    fn flush(&self) -> BlockFuture<'_, ()> {
+        Box::pin(async move { self.submit(Kind::Flush, 0, 0, Vec::new()).await.map(|_| ()) })
+    }
+}
+
+/* Testing */
+
+#[test_case]
+fn request_merging() {
+    let request = |kind: Kind, sector: u64, count: u64| Request { kind, sector, count, data: Vec::new(), done: oneshot::channel().0 };
+    let requests = vec![
+        request(Kind::Read, 10, 2),
+        request(Kind::Read, 12, 1), // after the first
+        request(Kind::Read, 8, 2),  // before it
+        request(Kind::Write, 13, 1),
+        request(Kind::Read, 13, 1), // next to the reads, but after a write - so it can't join them
+        request(Kind::Flush, 0, 0),
+        request(Kind::Flush, 0, 0),
+        request(Kind::Write, 0, 128),
+        request(Kind::Write, 128, 1), // too big to merge with 512 byte sectors
+    ];
+    let batches = merge(requests, 512);
+    let shape: Vec<(Kind, u64, u64, usize)> =
+        batches.iter().map(|batch| (batch.kind, batch.sector, batch.count, batch.requests.len())).collect();
+    assert_eq!(
+        shape,
+        [
+            (Kind::Read, 8, 5, 3),
+            (Kind::Write, 13, 1, 1),
+            (Kind::Read, 13, 1, 1),
+            (Kind::Flush, 0, 0, 2),
+            (Kind::Write, 0, 128, 1),
+            (Kind::Write, 128, 1, 1),
+        ]
+    );
+    let sectors: Vec<u64> = batches[0].requests.iter().map(|request| request.sector).collect();
+    assert_eq!(sectors, [8, 10, 12]);
+}
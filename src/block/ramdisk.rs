@@ -0,0 +1,178 @@
+//! # RAM disks
+//!
+//! Block devices kept in memory - for tests, and for filesystem images loaded with the kernel. They are
+//! sparse: memory is only taken for chunks that have been written, and everything else reads as zeros, so a
+//! big disk that is mostly empty costs little.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use super::{check_request, BlockDevice, BlockError, BlockFuture};
+
+/// How much memory is taken at a time, at least (and a whole number of sectors)
+const CHUNK_SIZE: usize = 4096;
+
+static NEXT_NAME: AtomicUsize = AtomicUsize::new(0);
+
+/// A name for a new RAM disk - `ram0`, `ram1`...
+pub fn next_name() -> String {
+    format!("ram{}", NEXT_NAME.fetch_add(1, Ordering::Relaxed))
+}
+
+/// # RamDisk
+///
+/// A block device in memory
+pub struct RamDisk {
+    name: String,
+    sector_size: usize,
+    sector_count: u64,
+    chunk_size: usize,
+    /// The chunks that have been written, by index
+    chunks: Mutex<BTreeMap<u64, Box<[u8]>>>,
+    read_only: AtomicBool,
+}
+
+impl RamDisk {
+    /// An empty (all zero) disk of `sector_count` sectors of `sector_size` bytes
+    pub fn new(name: String, sector_size: usize, sector_count: u64) -> Self {
+        // Round the chunk up to whole sectors, for sectors that don't divide it
+        let chunk_size = (CHUNK_SIZE + sector_size - 1) / sector_size * sector_size;
+        RamDisk {
+            name,
+            sector_size,
+            sector_count,
+            chunk_size,
+            chunks: Mutex::new(BTreeMap::new()),
+            read_only: AtomicBool::new(false),
+        }
+    }
+
+    /// A disk holding `image`, padded with zeros to a whole number of sectors
+    pub fn from_image(name: String, sector_size: usize, image: &[u8]) -> Self {
+        let sector_count = ((image.len() + sector_size - 1) / sector_size) as u64;
+        let disk = RamDisk::new(name, sector_size, sector_count);
+        disk.copy_in(0, image);
+        disk
+    }
+
+    /// Stop (or allow) writes
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::Release);
+    }
+
+    /// How many bytes of memory the disk is using
+    pub fn memory_used(&self) -> usize {
+        self.chunks.lock().len() * self.chunk_size
+    }
+
+    /// Copy `data` into the disk at byte `offset`. Chunks of zeros that aren't there yet are left out
+    fn copy_in(&self, offset: u64, data: &[u8]) {
+        let mut chunks = self.chunks.lock();
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done as u64;
+            let index = position / self.chunk_size as u64;
+            let within = (position % self.chunk_size as u64) as usize;
+            let length = (self.chunk_size - within).min(data.len() - done);
+            let part = &data[done..done + length];
+            let chunk_size = self.chunk_size;
+            match chunks.get_mut(&index) {
+                Some(chunk) => chunk[within..within + length].copy_from_slice(part),
+                None if part.iter().all(|&byte| byte == 0) => {}
+                None => {
+                    let mut chunk = vec![0; chunk_size].into_boxed_slice();
+                    chunk[within..within + length].copy_from_slice(part);
+                    chunks.insert(index, chunk);
+                }
+            }
+            done += length;
+        }
+    }
+
+    /// Copy from the disk at byte `offset` into `buffer`
+    fn copy_out(&self, offset: u64, buffer: &mut [u8]) {
+        let chunks = self.chunks.lock();
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let index = position / self.chunk_size as u64;
+            let within = (position % self.chunk_size as u64) as usize;
+            let length = (self.chunk_size - within).min(buffer.len() - done);
+            let part = &mut buffer[done..done + length];
+            match chunks.get(&index) {
+                Some(chunk) => part.copy_from_slice(&chunk[within..within + length]),
+                None => part.iter_mut().for_each(|byte| *byte = 0),
+            }
+            done += length;
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Acquire)
+    }
+
+    fn read<'a>(&'a self, sector: u64, buffer: &'a mut [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            check_request(self, sector, buffer.len())?;
+            self.copy_out(sector * self.sector_size as u64, buffer);
+            Ok(())
+        })
+    }
+
+    fn write<'a>(&'a self, sector: u64, buffer: &'a [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            check_request(self, sector, buffer.len())?;
+            if self.is_read_only() {
+                return Err(BlockError::ReadOnly);
+            }
+            self.copy_in(sector * self.sector_size as u64, buffer);
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> BlockFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/* Testing */
+
+#[test_case]
+fn ramdisk_sparse() {
+    use crate::task::block_on;
+
+    let disk = RamDisk::new(String::from("test"), 512, 1 << 20); // 512 MiB, nominally
+    assert_eq!(disk.memory_used(), 0);
+    let mut buffer = vec![0xFFu8; 1024];
+    block_on(disk.read(1000, &mut buffer)).unwrap();
+    assert!(buffer.iter().all(|&byte| byte == 0));
+
+    // Across a chunk boundary
+    let data: alloc::vec::Vec<u8> = (0..1024).map(|index| index as u8).collect();
+    block_on(disk.write(7, &data)).unwrap();
+    assert_eq!(disk.memory_used(), 2 * CHUNK_SIZE);
+    block_on(disk.read(7, &mut buffer)).unwrap();
+    assert_eq!(buffer, data);
+
+    disk.set_read_only(true);
+    assert_eq!(block_on(disk.write(0, &data)), Err(BlockError::ReadOnly));
+    assert_eq!(block_on(disk.read(1 << 20, &mut buffer)), Err(BlockError::OutOfRange));
+}
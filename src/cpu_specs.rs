@@ -1,6 +1,7 @@
 extern crate raw_cpuid;
 
 use crate::{println};
+use crate::acpi;
 
 use alloc::fmt::format;
 use alloc::string::{String, ToString};
@@ -352,4 +353,26 @@ pub fn print_cpu_info() {
     });
 
     println!("CPU supported Features: {}\n", features.join(" "));
-}
\ No newline at end of file
+
+    print_processors();
+}
+
+/// Print the processors the ACPI MADT lists. Needs `acpi::init` to have run
+pub fn print_processors() {
+    let processors = acpi::processors();
+    if processors.is_empty() {
+        println!("Processors: n/a (no ACPI MADT)");
+        return;
+    }
+
+    let enabled = processors.iter().filter(|p| p.enabled).count();
+    println!("Processors: {} ({} enabled)", processors.len(), enabled);
+    for processor in processors {
+        println!(
+            "  ACPI id {} -> APIC id {}{}",
+            processor.acpi_id,
+            processor.apic_id,
+            if processor.enabled { "" } else { " (disabled)" }
+        );
+    }
+}
@@ -1,41 +1,115 @@
-pub mod keyboard;
-pub mod pci;
-
-use keyboard::KeyboardDriver;
-use pci::PciScanner;
-use lazy_static::lazy_static;
-use spin::Mutex;
-use crate::serial_println;
-
-lazy_static! {
-    /// Static global reference to our DriverHandler
-    pub static ref DRIVER_HANDLER: Mutex<DriverHandler> = {
-        let mut driver_handler = DriverHandler::new();
-        let mut driver_handler = Mutex::new(driver_handler);
-        driver_handler
-    };
+//! # Drivers
+//!
+//! Devices are found on a bus (PCI, ISA, ACPI or virtio) and added to the [registry](registry/index.html),
+//! which binds each one to the first registered [Driver](trait.Driver.html) whose match table fits it and
+//! whose `probe` accepts it.
+//!
+//! [init](fn.init.html) registers the built in drivers, then adds the devices on every bus.
+
+pub mod keyboard; // PS/2 keyboard
+pub mod pci; // PCI config space, BARs, capabilities and interrupts
+pub mod device; // Devices, the buses they're on, and matching them to drivers
+pub mod registry; // Which devices there are, and which driver each is bound to
+pub mod ata; // ATA (IDE) disks, using PIO
+pub mod ahci; // SATA disks behind an AHCI controller, using DMA
+pub mod nvme; // NVM Express SSDs
+pub mod e1000; // Intel gigabit network cards (8254x and 82574)
+pub mod virtio; // Paravirtual devices (virtio over PCI)
+
+pub use device::{Bus, Device, DeviceId, DeviceKind, DeviceMatch};
+
+use alloc::string::ToString;
+use crate::{acpi, serial_println};
+
+/// # ProbeError
+///
+/// Why a driver didn't bind to a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeError {
+    /// The device matched, but it's a variant this driver can't drive. The next matching driver gets a go
+    Unsupported,
+    /// The device didn't respond, or not the way it should have
+    DeviceError(&'static str),
+    /// We ran out of something - memory, interrupt vectors...
+    OutOfResources,
 }
 
-/// # DriverHandler
-/// 
-/// Driver handler controls all kernel level drivers, such as keyboard, usb,
-/// networking and more.
-/// 
-/// It is a global handler that is wrapped within a mutex, so call `lock` to get 
-/// access to the `DRIVER_HANDLER` static.
-/// 
-pub struct DriverHandler{
-    pub keyboard_driver: KeyboardDriver,
-    pub pci_scanner: PciScanner,
+/// # Driver
+///
+/// A driver for one kind of device. Drivers are statics - they keep the state of the devices they're bound
+/// to themselves, usually keyed by [DeviceId](struct.DeviceId.html). Register them with
+/// [registry::register_driver](registry/fn.register_driver.html).
+pub trait Driver: Send + Sync {
+    /// A short name, for logging (like `ata` or `virtio-blk`)
+    fn name(&self) -> &'static str;
+
+    /// The devices this driver might handle
+    fn match_table(&self) -> &'static [DeviceMatch];
+
+    /// Set up `device`. Called at most once per device until `remove`
+    fn probe(&self, device: &Device) -> Result<(), ProbeError>;
+
+    /// Stop using `device` - it is going away, or another driver is taking over
+    fn remove(&self, _device: &Device) {}
+
+    /// Quiesce `device` before the machine sleeps or powers off
+    fn suspend(&self, _device: &Device) {}
+
+    /// Bring `device` back after `suspend`
+    fn resume(&self, _device: &Device) {}
 }
 
-impl DriverHandler{
-    /// Initialize all our drivers
-    pub fn new() -> Self{
-        serial_println!("Initialized driver handler");
-        Self{
-            keyboard_driver: KeyboardDriver::new(),
-            pci_scanner: PciScanner::new(),
+/// Legacy devices at fixed ports. The ACPI namespace has them too, but a machine without ACPI still has these
+const ISA_DEVICES: &[(&str, u16, u16, Option<u8>)] = &[
+    ("i8042", 0x60, 5, Some(1)), // PS/2 controller - data at 0x60, status and command at 0x64
+];
+
+/// # init
+///
+/// Register the built in drivers, then add every device on the PCI, ISA and ACPI buses, binding drivers as
+/// we go. Call after `acpi::init` and `pci::ecam::init`, with the local APIC up (for MSI)
+pub fn init() {
+    registry::register_driver(&keyboard::KEYBOARD_DRIVER);
+    registry::register_driver(&ata::ATA_DRIVER);
+    registry::register_driver(&ahci::AHCI_DRIVER);
+    registry::register_driver(&nvme::NVME_DRIVER);
+    registry::register_driver(&e1000::E1000_DRIVER);
+    registry::register_driver(&virtio::blk::VIRTIO_BLK_DRIVER);
+    registry::register_driver(&virtio::net::VIRTIO_NET_DRIVER);
+    registry::register_driver(&virtio::console::VIRTIO_CONSOLE_DRIVER);
+    registry::register_driver(&virtio::rng::VIRTIO_RNG_DRIVER);
+    registry::register_driver(&virtio::VIRTIO_PCI_DRIVER);
+
+    pci::add_devices();
+    add_isa_devices();
+    add_acpi_devices();
+
+    serial_println!(
+        "[LOG] {} devices, {} bound to drivers",
+        registry::devices().len(),
+        registry::bound_devices().len()
+    );
+}
+
+fn add_isa_devices() {
+    // Without a FADT, assume there's an 8042 - every PC had one
+    let has_8042 = acpi::fadt().map_or(true, |fadt| fadt.has_8042());
+    for &(name, io_base, io_size, irq) in ISA_DEVICES {
+        if name == "i8042" && !has_8042 {
+            continue;
         }
+        registry::add_device(name.to_string(), DeviceKind::Isa { io_base, io_size, irq });
     }
-}
\ No newline at end of file
+}
+
+/// Every present device in the ACPI namespace with a hardware ID
+fn add_acpi_devices() {
+    let paths = acpi::aml::with_interpreter(|interpreter| interpreter.devices()).unwrap_or_default();
+    for path in paths {
+        if let Some(hid) = acpi::aml::hardware_id(&path) {
+            if acpi::aml::is_present(&path) {
+                registry::add_device(path, DeviceKind::Acpi { hid });
+            }
+        }
+    }
+}
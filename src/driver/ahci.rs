@@ -0,0 +1,791 @@
+//! # AHCI
+//!
+//! Driver for SATA disks behind an AHCI controller (like QEMU's `ich9-ahci`). Unlike IDE, the controller
+//! (the HBA) does the transfers itself by DMA: we fill in a command table in memory - the ATA command, as a
+//! register FIS, and where the data goes - then set the slot's bit in the port's command issue register, and
+//! the HBA interrupts when it is done.
+//!
+//! Each port has up to 32 command slots, so many commands can be in flight. If the drive supports NCQ we
+//! queue them with READ/WRITE FPDMA QUEUED, and the drive can finish them in whatever order suits it.
+//! Otherwise the HBA runs them one after the other.
+//!
+//! The data goes through a DMA bounce buffer, as the caller's buffer is only contiguous virtually. Each disk
+//! found is registered as a [block device](../../block/index.html) called `sata0`, `sata1`...
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use tinypci::PciFullClass;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::block::{self, BlockDevice, BlockError, BlockFuture};
+use crate::memory::{self, dma::DmaBuffer};
+use crate::sync::IrqSpinlock;
+use crate::{serial_println, smp};
+use super::ata::IdentifyData;
+use super::pci::{Bar, Msi};
+use super::{Device, DeviceId, DeviceMatch, Driver, ProbeError};
+
+/* Generic host control registers (offsets from ABAR) */
+const HBA_CAP: u64 = 0x00;
+const HBA_GHC: u64 = 0x04;
+/// Bit `n` is set while port `n` has an interrupt pending
+const HBA_IS: u64 = 0x08;
+/// Bit `n` is set if port `n` is implemented
+const HBA_PI: u64 = 0x0C;
+const HBA_VS: u64 = 0x10;
+
+/* Host capabilities */
+const CAP_S64A: u32 = 1 << 31;
+const CAP_SNCQ: u32 = 1 << 30;
+/// Staggered spin up - ports have to be spun up by hand
+const CAP_SSS: u32 = 1 << 27;
+/// Bits 8-12 - how many command slots each port has, minus one
+const CAP_NCS_SHIFT: u32 = 8;
+
+/* Global host control */
+const GHC_HR: u32 = 1 << 0;
+const GHC_IE: u32 = 1 << 1;
+/// AHCI enable - without it the HBA pretends to be an IDE controller
+const GHC_AE: u32 = 1 << 31;
+
+/* Port registers (offsets from the port's registers) */
+const PORTS_OFFSET: u64 = 0x100;
+const PORT_SIZE: u64 = 0x80;
+const PX_CLB: u64 = 0x00;
+const PX_CLBU: u64 = 0x04;
+const PX_FB: u64 = 0x08;
+const PX_FBU: u64 = 0x0C;
+const PX_IS: u64 = 0x10;
+const PX_IE: u64 = 0x14;
+const PX_CMD: u64 = 0x18;
+const PX_TFD: u64 = 0x20;
+const PX_SIG: u64 = 0x24;
+const PX_SSTS: u64 = 0x28;
+const PX_SCTL: u64 = 0x2C;
+const PX_SERR: u64 = 0x30;
+/// Bit `n` is set while NCQ command `n` is outstanding
+const PX_SACT: u64 = 0x34;
+/// Bit `n` is set while command slot `n` is issued
+const PX_CI: u64 = 0x38;
+
+/* Port command and status */
+const CMD_ST: u32 = 1 << 0;
+const CMD_SUD: u32 = 1 << 1;
+const CMD_POD: u32 = 1 << 2;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+/* Port interrupt status and enable */
+/// A D2H register FIS arrived - a non-queued command finished
+const IS_DHRS: u32 = 1 << 0;
+const IS_PSS: u32 = 1 << 1;
+/// A set device bits FIS arrived - NCQ commands finished
+const IS_SDBS: u32 = 1 << 3;
+/// Task file, host bus fatal, host bus data and interface fatal errors. The port stops after any of them
+const IS_ERRORS: u32 = (1 << 30) | (1 << 29) | (1 << 28) | (1 << 27);
+
+/* Task file data - the ATA status register, in the low byte */
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+
+/// SStatus bits 0-3 - nothing is plugged in (or the PHY hasn't noticed it yet)
+const SSTS_DET_NONE: u32 = 0;
+/// SStatus bits 0-3 - a device is present and talking to us
+const SSTS_DET_ESTABLISHED: u32 = 3;
+/// SControl bits 0-3 - 1 sends a COMRESET
+const SCTL_DET_COMRESET: u32 = 1;
+/// The signature of an ATA disk (ATAPI devices are `0xEB14_0101`)
+const SIGNATURE_ATA: u32 = 0x0000_0101;
+
+/* ATA commands */
+const COMMAND_IDENTIFY: u8 = 0xEC;
+const COMMAND_READ_DMA_EXT: u8 = 0x25;
+const COMMAND_WRITE_DMA_EXT: u8 = 0x35;
+const COMMAND_READ_FPDMA_QUEUED: u8 = 0x60;
+const COMMAND_WRITE_FPDMA_QUEUED: u8 = 0x61;
+const COMMAND_FLUSH_CACHE_EXT: u8 = 0xEA;
+
+/// A host to device register FIS - how commands are sent
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+/// The length of one, in dwords
+const FIS_REG_H2D_DWORDS: u32 = 5;
+/// Device register bit 6 - the LBA is an LBA (not a cylinder, head and sector)
+const DEVICE_LBA: u8 = 1 << 6;
+
+/* The memory each port needs, all in one DMA buffer */
+/// 32 command headers of 32 bytes. Must be 1 KiB aligned
+const COMMAND_LIST: usize = 0;
+const COMMAND_HEADER_SIZE: usize = 32;
+/// Where the HBA copies FISes the drive sends us. 256 bytes, 256 byte aligned
+const RECEIVED_FIS: usize = 0x400;
+/// A command table per slot. 128 byte aligned
+const COMMAND_TABLES: usize = 0x1000;
+const COMMAND_TABLE_SIZE: usize = 0x100;
+/// The physical region descriptor table (scatter/gather list) in each command table
+const COMMAND_TABLE_PRDT: usize = 0x80;
+const PORT_MEMORY_SIZE: usize = COMMAND_TABLES + 32 * COMMAND_TABLE_SIZE;
+
+pub const SECTOR_SIZE: usize = 512;
+/// The most sectors one command moves (the size of its bounce buffer, 128 KiB)
+const MAX_SECTORS_PER_COMMAND: usize = 256;
+/// How many times to poll a register before giving up on the HBA, with `POLL_DELAY_US` between polls (1s)
+const POLL_LIMIT: usize = 100_000;
+const POLL_DELAY_US: u64 = 10;
+/// How many polls (10ms) a port gets to detect a device at all, before we decide it is empty. Once one is
+/// detected, the link gets the full `POLL_LIMIT` to come up
+const NO_DEVICE_POLL_LIMIT: usize = 1000;
+
+/// Read a 32 bit register at `base + offset`
+fn read_register(base: VirtAddr, offset: u64) -> u32 {
+    unsafe { ptr::read_volatile((base + offset).as_ptr::<u32>()) }
+}
+
+fn write_register(base: VirtAddr, offset: u64, value: u32) {
+    unsafe { ptr::write_volatile((base + offset).as_mut_ptr::<u32>(), value) }
+}
+
+/// Poll until `done` says yes. Returns whether it did before we gave up
+fn wait_for(mut done: impl FnMut() -> bool) -> bool {
+    for _ in 0..POLL_LIMIT {
+        if done() {
+            return true;
+        }
+        smp::delay_us(POLL_DELAY_US);
+    }
+    false
+}
+
+/// Wait for the link of the port at `registers` to come up. Returns whether it did - an empty port gives up
+/// after `NO_DEVICE_POLL_LIMIT` polls, rather than holding up the probe for a second
+fn wait_for_link(registers: VirtAddr) -> bool {
+    for poll in 0..POLL_LIMIT {
+        match read_register(registers, PX_SSTS) & 0xF {
+            SSTS_DET_ESTABLISHED => return true,
+            SSTS_DET_NONE if poll >= NO_DEVICE_POLL_LIMIT => return false,
+            _ => {}
+        }
+        smp::delay_us(POLL_DELAY_US);
+    }
+    false
+}
+
+/// # register_fis
+///
+/// A host to device register FIS (20 bytes), which sends `command` to the drive. For NCQ commands the sector
+/// count goes in `features`, and `count` holds the tag
+pub fn register_fis(command: u8, lba: u64, count: u16, features: u16) -> [u8; 20] {
+    let mut fis = [0u8; 20];
+    fis[0] = FIS_TYPE_REG_H2D;
+    fis[1] = 1 << 7; // this is a command, not a device control update
+    fis[2] = command;
+    fis[3] = features as u8;
+    fis[4] = lba as u8;
+    fis[5] = (lba >> 8) as u8;
+    fis[6] = (lba >> 16) as u8;
+    fis[7] = DEVICE_LBA;
+    fis[8] = (lba >> 24) as u8;
+    fis[9] = (lba >> 32) as u8;
+    fis[10] = (lba >> 40) as u8;
+    fis[11] = (features >> 8) as u8;
+    fis[12] = count as u8;
+    fis[13] = (count >> 8) as u8;
+    fis
+}
+
+/// Who owns the port's command slots, and what has happened to the commands in them. Shared with the
+/// interrupt handler
+struct SlotState {
+    /// Slots a task has claimed
+    claimed: u32,
+    /// Slots issued to the HBA, that haven't finished
+    active: u32,
+    /// Slots whose command finished, waiting for their task to notice
+    done: u32,
+    /// The subset of `done` that failed
+    failed: u32,
+    /// An error stopped the port. The next command restarts it first
+    restart: bool,
+}
+
+/// # AhciPort
+///
+/// A port with a SATA disk on it, as a block device
+pub struct AhciPort {
+    name: String,
+    /// Which of the HBA's ports this is
+    number: u32,
+    registers: VirtAddr,
+    /// Command list, received FIS area and command tables
+    memory: DmaBuffer,
+    /// How many slots we use - the HBA's, or fewer if the drive's NCQ queue is shorter
+    slots: usize,
+    ncq: bool,
+    info: IdentifyData,
+    state: IrqSpinlock<SlotState>,
+    /// The task waiting for each slot's command
+    wakers: Vec<AtomicWaker>,
+    /// Tasks waiting for a slot to be free
+    slot_waiters: Mutex<Vec<Waker>>,
+    /// Whether the HBA's interrupts reach us. Until they do (or if they never will) commands are polled
+    interrupts: AtomicBool,
+}
+
+impl AhciPort {
+    fn read(&self, register: u64) -> u32 {
+        read_register(self.registers, register)
+    }
+
+    fn write(&self, register: u64, value: u32) {
+        write_register(self.registers, register, value)
+    }
+
+    pub fn info(&self) -> &IdentifyData {
+        &self.info
+    }
+
+    /// Whether commands are queued with NCQ
+    pub fn uses_ncq(&self) -> bool {
+        self.ncq
+    }
+
+    /// Stop the command engine and FIS receive. They must be stopped to change the port's memory
+    fn stop(&self) -> bool {
+        self.write(PX_CMD, self.read(PX_CMD) & !CMD_ST);
+        if !wait_for(|| self.read(PX_CMD) & CMD_CR == 0) {
+            return false;
+        }
+        self.write(PX_CMD, self.read(PX_CMD) & !CMD_FRE);
+        wait_for(|| self.read(PX_CMD) & CMD_FR == 0)
+    }
+
+    /// Start FIS receive and, once the drive is ready, the command engine
+    fn start(&self) -> bool {
+        self.write(PX_CMD, self.read(PX_CMD) | CMD_FRE);
+        self.write(PX_SERR, !0); // write one to clear
+        self.write(PX_IS, !0);
+        if !wait_for(|| self.read(PX_TFD) & (TFD_BSY | TFD_DRQ) == 0) {
+            return false;
+        }
+        self.write(PX_CMD, self.read(PX_CMD) | CMD_ST);
+        true
+    }
+
+    /// Recover from an error - stopping the port clears it and throws away every outstanding command. If the
+    /// drive is still stuck, reset the link too
+    fn restart(&self) {
+        self.stop();
+        if self.read(PX_TFD) & (TFD_BSY | TFD_DRQ) != 0 {
+            let control = self.read(PX_SCTL) & !0xF;
+            self.write(PX_SCTL, control | SCTL_DET_COMRESET);
+            smp::delay_us(1000);
+            self.write(PX_SCTL, control);
+            wait_for_link(self.registers);
+        }
+        if !self.start() {
+            serial_println!("[LOG] {}: port didn't come back after an error", self.name);
+        }
+    }
+
+    /// Look at what the HBA finished, and wake the tasks waiting for it. Called from the interrupt handler, and
+    /// by commands being polled
+    fn reap(&self) {
+        let completed = {
+            let mut state = self.state.lock();
+            let status = self.read(PX_IS);
+            self.write(PX_IS, status);
+            let completed = if status & IS_ERRORS != 0 {
+                // The port has stopped, taking every outstanding command with it
+                state.failed |= state.active;
+                state.restart = true;
+                state.active
+            } else {
+                state.active & !(self.read(PX_CI) | self.read(PX_SACT))
+            };
+            state.active &= !completed;
+            state.done |= completed;
+            completed
+        };
+        for slot in 0..self.slots {
+            if completed & (1 << slot) != 0 {
+                self.wakers[slot].wake();
+            }
+        }
+    }
+
+    /// Wait for a free command slot. An `exclusive` claim waits for every slot - non-queued commands can't
+    /// run alongside NCQ ones
+    fn claim(&self, exclusive: bool) -> SlotClaim {
+        SlotClaim { port: self, exclusive }
+    }
+
+    fn release(&self, slot: usize, exclusive: bool) {
+        {
+            let mut state = self.state.lock();
+            state.claimed &= if exclusive { 0 } else { !(1 << slot) };
+        }
+        let waiters = core::mem::take(&mut *self.slot_waiters.lock());
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+
+    /// Fill in `slot`'s command header and table. `data` is where the transfer goes, if it has one
+    fn build(&self, slot: usize, fis: &[u8; 20], data: Option<(&DmaBuffer, usize)>, write: bool) {
+        let table = COMMAND_TABLES + slot * COMMAND_TABLE_SIZE;
+        let table_phys = self.memory.phys_addr() + table as u64;
+        unsafe {
+            ptr::write_bytes(self.memory.ptr::<u8>(table), 0, COMMAND_TABLE_PRDT);
+            ptr::copy_nonoverlapping(fis.as_ptr(), self.memory.ptr::<u8>(table), fis.len());
+        }
+
+        let regions = match data {
+            Some((buffer, length)) => {
+                let entry = table + COMMAND_TABLE_PRDT;
+                let address = buffer.phys_addr().as_u64();
+                unsafe {
+                    ptr::write_volatile(self.memory.ptr::<u32>(entry), address as u32);
+                    ptr::write_volatile(self.memory.ptr::<u32>(entry + 4), (address >> 32) as u32);
+                    ptr::write_volatile(self.memory.ptr::<u32>(entry + 8), 0);
+                    ptr::write_volatile(self.memory.ptr::<u32>(entry + 12), length as u32 - 1); // byte count, minus one
+                }
+                1
+            }
+            None => 0,
+        };
+
+        let header = COMMAND_LIST + slot * COMMAND_HEADER_SIZE;
+        let flags = FIS_REG_H2D_DWORDS | (write as u32) << 6 | regions << 16;
+        unsafe {
+            ptr::write_volatile(self.memory.ptr::<u32>(header), flags);
+            ptr::write_volatile(self.memory.ptr::<u32>(header + 4), 0); // bytes transferred, set by the HBA
+            ptr::write_volatile(self.memory.ptr::<u32>(header + 8), table_phys.as_u64() as u32);
+            ptr::write_volatile(self.memory.ptr::<u32>(header + 12), (table_phys.as_u64() >> 32) as u32);
+        }
+    }
+
+    /// Hand `slot` to the HBA, and wait for it to finish
+    fn issue(&self, slot: usize, queued: bool) -> Completion {
+        {
+            let mut state = self.state.lock();
+            // Errors are rare, so we don't mind restarting the port with interrupts off
+            if state.restart {
+                state.restart = false;
+                self.restart();
+            }
+            if queued {
+                self.write(PX_SACT, 1 << slot);
+            }
+            self.write(PX_CI, 1 << slot);
+            state.active |= 1 << slot;
+        }
+        Completion { port: self, slot }
+    }
+
+    /// Run a command in a slot of its own - `fis` from `make_fis(slot)`, as NCQ commands are tagged with their
+    /// slot
+    async fn command(
+        &self,
+        make_fis: impl FnOnce(usize) -> [u8; 20],
+        data: Option<(&DmaBuffer, usize)>,
+        write: bool,
+        queued: bool,
+    ) -> Result<(), BlockError> {
+        let exclusive = self.ncq && !queued;
+        let slot = self.claim(exclusive).await;
+        self.build(slot, &make_fis(slot), data, write);
+        let result = self.issue(slot, queued).await;
+        self.release(slot, exclusive);
+        result
+    }
+
+    /// Ask the drive who it is
+    async fn identify(&self) -> Result<IdentifyData, BlockError> {
+        let buffer = DmaBuffer::new(SECTOR_SIZE).ok_or(BlockError::DeviceError("out of DMA memory"))?;
+        self.command(|_| register_fis(COMMAND_IDENTIFY, 0, 0, 0), Some((&buffer, SECTOR_SIZE)), false, false).await?;
+        let mut words = [0u16; 256];
+        for (word, bytes) in words.iter_mut().zip(buffer.as_slice().chunks_exact(2)) {
+            *word = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        IdentifyData::parse(&words).ok_or(BlockError::DeviceError("not an ATA disk"))
+    }
+
+    /// Read or write `count` sectors at `sector`, through `buffer`
+    async fn transfer(&self, sector: u64, count: usize, buffer: &DmaBuffer, write: bool) -> Result<(), BlockError> {
+        let length = count * SECTOR_SIZE;
+        if self.ncq {
+            let command = if write { COMMAND_WRITE_FPDMA_QUEUED } else { COMMAND_READ_FPDMA_QUEUED };
+            let fis = |slot: usize| register_fis(command, sector, (slot as u16) << 3, count as u16);
+            self.command(fis, Some((buffer, length)), write, true).await
+        } else {
+            let command = if write { COMMAND_WRITE_DMA_EXT } else { COMMAND_READ_DMA_EXT };
+            let fis = |_| register_fis(command, sector, count as u16, 0);
+            self.command(fis, Some((buffer, length)), write, false).await
+        }
+    }
+}
+
+/// The future returned by `AhciPort::claim`. Gives the slot
+struct SlotClaim<'a> {
+    port: &'a AhciPort,
+    exclusive: bool,
+}
+
+impl<'a> SlotClaim<'a> {
+    fn try_claim(&self) -> Option<usize> {
+        let mut state = self.port.state.lock();
+        let all = ((1u64 << self.port.slots) - 1) as u32;
+        if self.exclusive {
+            if state.claimed != 0 {
+                return None;
+            }
+            state.claimed = all;
+            return Some(0);
+        }
+        let slot = (!state.claimed & all).trailing_zeros() as usize;
+        if slot < self.port.slots {
+            state.claimed |= 1 << slot;
+            Some(slot)
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a> Future for SlotClaim<'a> {
+    type Output = usize;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<usize> {
+        if let Some(slot) = self.try_claim() {
+            return Poll::Ready(slot);
+        }
+        self.port.slot_waiters.lock().push(cx.waker().clone());
+        // A slot may have been released between our try and registering
+        match self.try_claim() {
+            Some(slot) => Poll::Ready(slot),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// The future returned by `AhciPort::issue`
+struct Completion<'a> {
+    port: &'a AhciPort,
+    slot: usize,
+}
+
+impl<'a> Completion<'a> {
+    fn check(&self) -> Option<Result<(), BlockError>> {
+        let mut state = self.port.state.lock();
+        let bit = 1 << self.slot;
+        if state.done & bit == 0 {
+            return None;
+        }
+        let failed = state.failed & bit != 0;
+        state.done &= !bit;
+        state.failed &= !bit;
+        Some(if failed { Err(BlockError::DeviceError("AHCI command failed")) } else { Ok(()) })
+    }
+}
+
+impl<'a> Future for Completion<'a> {
+    type Output = Result<(), BlockError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), BlockError>> {
+        self.port.wakers[self.slot].register(cx.waker());
+        // The command may have finished before the interrupt was enabled, or before we registered
+        self.port.reap();
+        match self.check() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                if !self.port.interrupts.load(Ordering::Acquire) {
+                    cx.waker().wake_by_ref(); // nothing will wake us, so ask to be polled again
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl BlockDevice for AhciPort {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.info.sectors
+    }
+
+    fn read<'a>(&'a self, sector: u64, buffer: &'a mut [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            block::check_request(self, sector, buffer.len())?;
+            let bounce = DmaBuffer::new(buffer.len().min(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE))
+                .ok_or(BlockError::DeviceError("out of DMA memory"))?;
+            for (index, chunk) in buffer.chunks_mut(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+                let lba = sector + (index * MAX_SECTORS_PER_COMMAND) as u64;
+                self.transfer(lba, chunk.len() / SECTOR_SIZE, &bounce, false).await?;
+                chunk.copy_from_slice(&bounce.as_slice()[..chunk.len()]);
+            }
+            Ok(())
+        })
+    }
+
+    fn write<'a>(&'a self, sector: u64, buffer: &'a [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            block::check_request(self, sector, buffer.len())?;
+            let mut bounce = DmaBuffer::new(buffer.len().min(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE))
+                .ok_or(BlockError::DeviceError("out of DMA memory"))?;
+            for (index, chunk) in buffer.chunks(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+                let lba = sector + (index * MAX_SECTORS_PER_COMMAND) as u64;
+                bounce.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+                self.transfer(lba, chunk.len() / SECTOR_SIZE, &bounce, true).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> BlockFuture<'_, ()> {
+        Box::pin(async move {
+            self.command(|_| register_fis(COMMAND_FLUSH_CACHE_EXT, 0, 0, 0), None, false, false).await
+        })
+    }
+}
+
+/// # AhciController
+///
+/// An HBA, and the disks on its ports
+struct AhciController {
+    abar: VirtAddr,
+    ports: Vec<Arc<AhciPort>>,
+    msi: Mutex<Option<Msi>>,
+}
+
+impl AhciController {
+    fn read(&self, register: u64) -> u32 {
+        read_register(self.abar, register)
+    }
+
+    fn write(&self, register: u64, value: u32) {
+        write_register(self.abar, register, value)
+    }
+}
+
+/// The MSI handler. `context` points to the `AhciController`
+fn handle_interrupt(context: usize) {
+    let controller = unsafe { &*(context as *const AhciController) };
+    let pending = controller.read(HBA_IS);
+    for port in controller.ports.iter() {
+        if pending & (1 << port.number) != 0 {
+            port.reap();
+        }
+    }
+    // Clear the HBA's status after the ports', or the interrupt comes straight back
+    controller.write(HBA_IS, pending);
+}
+
+/// # AhciDriver
+///
+/// Binds to AHCI controllers, and registers a block device for each disk on them
+pub struct AhciDriver {
+    controllers: Mutex<Vec<(DeviceId, Arc<AhciController>)>>,
+    next_drive: AtomicUsize,
+}
+
+/// The AHCI driver. [driver::init](../fn.init.html) registers it
+pub static AHCI_DRIVER: AhciDriver = AhciDriver { controllers: Mutex::new(Vec::new()), next_drive: AtomicUsize::new(0) };
+
+impl AhciDriver {
+    /// Reset the HBA, and put it in AHCI mode
+    fn reset(abar: VirtAddr) -> Result<(), ProbeError> {
+        write_register(abar, HBA_GHC, read_register(abar, HBA_GHC) | GHC_AE);
+        write_register(abar, HBA_GHC, read_register(abar, HBA_GHC) | GHC_HR);
+        if !wait_for(|| read_register(abar, HBA_GHC) & GHC_HR == 0) {
+            return Err(ProbeError::DeviceError("HBA reset timed out"));
+        }
+        write_register(abar, HBA_GHC, read_register(abar, HBA_GHC) | GHC_AE);
+        Ok(())
+    }
+
+    /// Set up port `number`, and find out what is on it. `None` if it isn't an ATA disk, or doesn't work
+    fn port(&self, abar: VirtAddr, number: u32, capabilities: u32) -> Option<AhciPort> {
+        let registers = abar + PORTS_OFFSET + number as u64 * PORT_SIZE;
+        if capabilities & CAP_SSS != 0 {
+            let command = read_register(registers, PX_CMD);
+            write_register(registers, PX_CMD, command | CMD_SUD | CMD_POD);
+        }
+        // Give the link a moment to come up after the reset
+        if !wait_for_link(registers) || read_register(registers, PX_SIG) != SIGNATURE_ATA {
+            return None;
+        }
+
+        let memory = DmaBuffer::new(PORT_MEMORY_SIZE)?;
+        if memory.phys_addr().as_u64() >> 32 != 0 && capabilities & CAP_S64A == 0 {
+            serial_println!("[LOG] ahci: port {} - the DMA pool is above 4 GiB, and the HBA is 32 bit", number);
+            return None;
+        }
+        let slots = ((capabilities >> CAP_NCS_SHIFT) & 0x1F) as usize + 1;
+        let mut port = AhciPort {
+            name: String::new(),
+            number,
+            registers,
+            memory,
+            slots,
+            ncq: false,
+            info: IdentifyData { model: String::new(), serial: String::new(), sectors: 0, lba48: false, queue_depth: 0 },
+            state: IrqSpinlock::new(SlotState { claimed: 0, active: 0, done: 0, failed: 0, restart: false }),
+            wakers: (0..slots).map(|_| AtomicWaker::new()).collect(),
+            slot_waiters: Mutex::new(Vec::new()),
+            interrupts: AtomicBool::new(false),
+        };
+
+        if !port.stop() {
+            serial_println!("[LOG] ahci: port {} won't stop", number);
+            return None;
+        }
+        let base = port.memory.phys_addr();
+        port.write(PX_CLB, (base + COMMAND_LIST as u64).as_u64() as u32);
+        port.write(PX_CLBU, ((base + COMMAND_LIST as u64).as_u64() >> 32) as u32);
+        port.write(PX_FB, (base + RECEIVED_FIS as u64).as_u64() as u32);
+        port.write(PX_FBU, ((base + RECEIVED_FIS as u64).as_u64() >> 32) as u32);
+        if !port.start() {
+            serial_println!("[LOG] ahci: port {} - drive not ready", number);
+            return None;
+        }
+
+        // Interrupts aren't set up yet, so this is polled
+        port.info = match crate::task::block_on(port.identify()) {
+            Ok(info) => info,
+            Err(err) => {
+                serial_println!("[LOG] ahci: port {} - IDENTIFY failed: {:?}", number, err);
+                return None;
+            }
+        };
+        if capabilities & CAP_SNCQ != 0 && port.info.queue_depth > 0 {
+            port.ncq = true;
+            port.slots = port.slots.min(port.info.queue_depth as usize);
+        }
+        port.name = format!("sata{}", self.next_drive.fetch_add(1, Ordering::Relaxed));
+        Some(port)
+    }
+}
+
+impl Driver for AhciDriver {
+    fn name(&self) -> &'static str {
+        "ahci"
+    }
+
+    fn match_table(&self) -> &'static [DeviceMatch] {
+        // Programming interface 1 is AHCI. The class code entry catches controllers `brute_force_scan` missed
+        &[DeviceMatch::PciClass(PciFullClass::MassStorage_SATA), DeviceMatch::PciClassCode { class: 0x01, subclass: 0x06 }]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let function = device.pci_function().ok_or(ProbeError::Unsupported)?;
+        if function.prog_if != 0x01 {
+            return Err(ProbeError::Unsupported); // a vendor specific interface
+        }
+        // Registers, so mapped uncached rather than reached through the physical memory mapping
+        let abar = match function.bar(5) {
+            Some(Bar::Memory { address, size, .. }) => {
+                memory::map_device(PhysAddr::new(address), size).map_err(|_| ProbeError::OutOfResources)?
+            }
+            _ => return Err(ProbeError::DeviceError("no ABAR")),
+        };
+        function.enable_memory_space();
+        function.enable_bus_mastering();
+
+        Self::reset(abar)?;
+        let capabilities = read_register(abar, HBA_CAP);
+        let implemented = read_register(abar, HBA_PI);
+        let version = read_register(abar, HBA_VS);
+        serial_println!(
+            "[LOG] ahci: version {}.{}, {} ports, {} slots{}",
+            version >> 16,
+            version & 0xFFFF,
+            implemented.count_ones(),
+            ((capabilities >> CAP_NCS_SHIFT) & 0x1F) + 1,
+            if capabilities & CAP_SNCQ != 0 { ", NCQ" } else { "" }
+        );
+
+        let ports: Vec<Arc<AhciPort>> = (0..32)
+            .filter(|number| implemented & (1 << number) != 0)
+            .filter_map(|number| self.port(abar, number, capabilities))
+            .map(Arc::new)
+            .collect();
+        let controller = Arc::new(AhciController { abar, ports, msi: Mutex::new(None) });
+
+        // The controller stays alive in `controllers` until `remove` has disabled the interrupt
+        match function.msi() {
+            Ok(mut msi) => match msi.enable(handle_interrupt, Arc::as_ptr(&controller) as usize) {
+                Ok(_) => {
+                    *controller.msi.lock() = Some(msi);
+                    for port in controller.ports.iter() {
+                        port.write(PX_IE, IS_DHRS | IS_PSS | IS_SDBS | IS_ERRORS);
+                        port.interrupts.store(true, Ordering::Release);
+                    }
+                    controller.write(HBA_GHC, controller.read(HBA_GHC) | GHC_IE);
+                }
+                Err(err) => serial_println!("[LOG] ahci: couldn't enable MSI ({:?}), polling instead", err),
+            },
+            Err(err) => serial_println!("[LOG] ahci: no MSI ({:?}), polling instead", err),
+        }
+
+        for port in controller.ports.iter() {
+            serial_println!(
+                "[LOG] {}: \"{}\"{}",
+                port.name,
+                port.info.model,
+                if port.ncq { format!(", NCQ ({} deep)", port.slots) } else { String::new() }
+            );
+            block::register(port.clone());
+        }
+        self.controllers.lock().push((device.id, controller));
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        let mut controllers = self.controllers.lock();
+        if let Some(index) = controllers.iter().position(|(id, _)| *id == device.id) {
+            let (_, controller) = controllers.remove(index);
+            controller.write(HBA_GHC, controller.read(HBA_GHC) & !GHC_IE);
+            if let Some(mut msi) = controller.msi.lock().take() {
+                msi.disable();
+            }
+            for port in controller.ports.iter() {
+                block::unregister(&port.name);
+                port.write(PX_IE, 0);
+                port.stop();
+            }
+        }
+    }
+}
+
+/* Testing */
+
+#[test_case]
+fn ahci_register_fis() {
+    let fis = register_fis(COMMAND_READ_DMA_EXT, 0x0123_4567_89AB, 8, 0);
+    assert_eq!(fis[0], FIS_TYPE_REG_H2D);
+    assert_eq!(fis[1], 0x80);
+    assert_eq!(fis[2], COMMAND_READ_DMA_EXT);
+    assert_eq!(&fis[4..11], &[0xAB, 0x89, 0x67, DEVICE_LBA, 0x45, 0x23, 0x01]);
+    assert_eq!(&fis[12..14], &[8, 0]);
+
+    // NCQ - the count moves to the features, and the tag (slot 3) goes in bits 3-7 of the count
+    let fis = register_fis(COMMAND_WRITE_FPDMA_QUEUED, 0, 3 << 3, 256);
+    assert_eq!((fis[3], fis[11]), (0, 1));
+    assert_eq!(fis[12], 3 << 3);
+}
@@ -0,0 +1,605 @@
+//! # ATA (IDE)
+//!
+//! Driver for parallel ATA disks behind an IDE controller, using PIO - the CPU moves every word of data
+//! through the data port itself. Slow, but every PC (and QEMU's `pc` machine, which boots from an IDE disk)
+//! has one.
+//!
+//! An IDE controller has two channels, each with up to two drives (master and slave) that share its
+//! registers - so a channel runs one command at a time. In compatibility mode the channels are at the
+//! legacy ports and interrupt on ISA IRQs 14 and 15. In native mode the ports come from the BARs, and we
+//! poll instead.
+//!
+//! Each drive found is registered as a [block device](../../block/index.html) called `ata0`, `ata1`...
+//! Drives are addressed with 28 bit LBAs, or 48 bit LBAs for requests past 128 GiB (if the drive has them).
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use tinypci::PciFullClass;
+use x86_64::instructions::port::Port;
+use crate::block::{self, BlockDevice, BlockError, BlockFuture};
+use crate::sync::AsyncMutex;
+use crate::{interrupts, serial_println};
+use super::pci::{Bar, PciDevice};
+use super::{Device, DeviceId, DeviceMatch, Driver, ProbeError};
+
+/* Command block registers (offsets from the I/O base) */
+const REG_DATA: u16 = 0;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16 = 3;
+const REG_LBA_MID: u16 = 4;
+const REG_LBA_HIGH: u16 = 5;
+const REG_DRIVE: u16 = 6;
+/// Reading the status register acknowledges the drive's interrupt
+const REG_STATUS: u16 = 7;
+const REG_COMMAND: u16 = 7;
+
+/* Status register bits */
+const STATUS_ERR: u8 = 1 << 0;
+/// The drive wants to transfer a sector of data
+const STATUS_DRQ: u8 = 1 << 3;
+/// Drive fault
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+/* Commands */
+const COMMAND_READ_SECTORS: u8 = 0x20;
+const COMMAND_READ_SECTORS_EXT: u8 = 0x24;
+const COMMAND_WRITE_SECTORS: u8 = 0x30;
+const COMMAND_WRITE_SECTORS_EXT: u8 = 0x34;
+const COMMAND_FLUSH_CACHE: u8 = 0xE7;
+const COMMAND_FLUSH_CACHE_EXT: u8 = 0xEA;
+const COMMAND_IDENTIFY: u8 = 0xEC;
+
+pub const SECTOR_SIZE: usize = 512;
+/// 28 bit LBAs reach the first 128 GiB
+const LBA28_LIMIT: u64 = 1 << 28;
+/// The most sectors one command can move. The count register holds 0 for the maximum
+const MAX_SECTORS_LBA28: u64 = 256;
+const MAX_SECTORS_LBA48: u64 = 65536;
+/// How many times to read the status register before deciding the drive isn't going to answer
+const POLL_LIMIT: usize = 1_000_000;
+/// How long to wait for a command's interrupt (in timer ticks, about 5 seconds) before looking at the status
+/// ourselves
+const COMPLETION_TIMEOUT_TICKS: u64 = 5 * interrupts::TIMER_HZ;
+
+/// The compatibility mode channels - command block, control block, and ISA IRQ
+const LEGACY_CHANNELS: [(u16, u16, u8); 2] = [(0x1F0, 0x3F6, 14), (0x170, 0x376, 15)];
+
+/// Set by the interrupt handler of a legacy channel, and cleared before each command
+struct IrqState {
+    fired: AtomicBool,
+    waker: AtomicWaker,
+}
+
+lazy_static! {
+    static ref IRQ_STATE: [IrqState; 2] = [
+        IrqState { fired: AtomicBool::new(false), waker: AtomicWaker::new() },
+        IrqState { fired: AtomicBool::new(false), waker: AtomicWaker::new() },
+    ];
+}
+
+/// Called by the IRQ 14 (`channel` 0) and 15 (`channel` 1) interrupt handlers
+///
+/// Must not block or allocate.
+pub(crate) fn handle_interrupt(channel: usize) {
+    let (io_base, _, _) = LEGACY_CHANNELS[channel];
+    let _status: u8 = unsafe { Port::new(io_base + REG_STATUS).read() }; // acknowledge it
+    let state = &IRQ_STATE[channel];
+    state.fired.store(true, Ordering::Release);
+    state.waker.wake();
+}
+
+/// Called by the timer interrupt handler, so a command waiting for an interrupt that never comes gets polled
+/// again and can time out. Only wakes a channel with a command in flight
+///
+/// Must not block or allocate.
+pub(crate) fn handle_timer() {
+    for state in IRQ_STATE.iter() {
+        state.waker.wake();
+    }
+}
+
+/// # IdentifyData
+///
+/// What a drive says about itself in answer to IDENTIFY DEVICE. SATA drives answer the same way, so the AHCI
+/// driver uses this too
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdentifyData {
+    pub model: String,
+    pub serial: String,
+    /// How many sectors can be addressed
+    pub sectors: u64,
+    /// Whether the drive takes 48 bit LBAs
+    pub lba48: bool,
+    /// How many commands the drive can queue with NCQ (SATA only). 0 if it can't
+    pub queue_depth: u8,
+}
+
+impl IdentifyData {
+    /// Parse the 256 words of IDENTIFY data. `None` for ATAPI devices, and drives that can only do CHS
+    pub fn parse(words: &[u16; 256]) -> Option<Self> {
+        if words[0] & (1 << 15) != 0 || words[49] & (1 << 9) == 0 {
+            return None;
+        }
+        let lba48 = words[83] & (1 << 10) != 0;
+        let lba28_sectors = words[60] as u64 | (words[61] as u64) << 16;
+        let lba48_sectors = words[100..104].iter().rev().fold(0, |sectors, &word| sectors << 16 | word as u64);
+        let ncq = words[76] & (1 << 8) != 0;
+        Some(IdentifyData {
+            model: ata_string(&words[27..47]),
+            serial: ata_string(&words[10..20]),
+            sectors: if lba48 && lba48_sectors != 0 { lba48_sectors } else { lba28_sectors },
+            lba48,
+            queue_depth: if ncq { (words[75] & 0x1F) as u8 + 1 } else { 0 },
+        })
+    }
+}
+
+/// IDENTIFY strings have two characters per word, the first in the high byte, padded with spaces
+fn ata_string(words: &[u16]) -> String {
+    let mut string = String::new();
+    for &word in words {
+        string.push((word >> 8) as u8 as char);
+        string.push(word as u8 as char);
+    }
+    String::from(string.trim())
+}
+
+/// The drive register for an LBA28 command - LBA mode, which drive, and the top 4 bits of the LBA
+fn lba28_drive_select(slave: bool, lba: u64) -> u8 {
+    0xE0 | (slave as u8) << 4 | ((lba >> 24) & 0xF) as u8
+}
+
+/// Whether a transfer needs a 48 bit command - it ends past LBA28's reach, or is too big for one LBA28 command
+fn needs_lba48(sector: u64, count: u64) -> bool {
+    sector + count > LBA28_LIMIT || count > MAX_SECTORS_LBA28
+}
+
+/// # Channel
+///
+/// One IDE channel. The lock is held for the whole of a command, as both drives share the registers
+struct Channel {
+    io_base: u16,
+    /// The alternate status (read) and device control (write) register
+    control: u16,
+    /// Which `IRQ_STATE` the channel's interrupts set, if it has a legacy IRQ. Without one we poll
+    irq: Option<usize>,
+    lock: AsyncMutex<()>,
+}
+
+impl Channel {
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.io_base + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.io_base + register).write(value) }
+    }
+
+    /// The status, without acknowledging an interrupt
+    fn alternate_status(&self) -> u8 {
+        unsafe { Port::new(self.control).read() }
+    }
+
+    /// Drives take 400ns to put their status up after a command or drive select. Each read takes about 100ns
+    fn delay_400ns(&self) {
+        for _ in 0..4 {
+            self.alternate_status();
+        }
+    }
+
+    /// Spin until the channel isn't busy. `None` if it never stops being busy, or if nothing is there
+    /// (a floating bus reads all ones)
+    fn wait_not_busy(&self) -> Option<u8> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.alternate_status();
+            if status == 0xFF {
+                return None;
+            }
+            if status & STATUS_BSY == 0 {
+                return Some(status);
+            }
+            core::hint::spin_loop();
+        }
+        None
+    }
+
+    /// Spin until the drive wants data, or reports an error
+    fn wait_drq(&self) -> Result<(), BlockError> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.alternate_status();
+            if status & STATUS_BSY == 0 {
+                if status & (STATUS_ERR | STATUS_DF) != 0 {
+                    return Err(BlockError::DeviceError("ATA command failed"));
+                }
+                if status & STATUS_DRQ != 0 {
+                    return Ok(());
+                }
+            }
+            core::hint::spin_loop();
+        }
+        Err(BlockError::DeviceError("ATA drive timed out"))
+    }
+
+    fn clear_interrupt(&self) {
+        if let Some(irq) = self.irq {
+            IRQ_STATE[irq].fired.store(false, Ordering::Release);
+        }
+    }
+
+    /// Ask drive `slave` who it is. Polled, so it works before interrupts are set up
+    fn identify(&self, slave: bool) -> Option<IdentifyData> {
+        self.wait_not_busy()?;
+        self.write(REG_DRIVE, 0xA0 | (slave as u8) << 4);
+        self.delay_400ns();
+        for register in REG_SECTOR_COUNT..=REG_LBA_HIGH {
+            self.write(register, 0);
+        }
+        self.clear_interrupt();
+        self.write(REG_COMMAND, COMMAND_IDENTIFY);
+        self.delay_400ns();
+        if self.alternate_status() == 0 {
+            return None; // no drive
+        }
+        self.wait_not_busy()?;
+        // ATAPI and SATA devices put their signature here and abort the command
+        if self.read(REG_LBA_MID) != 0 || self.read(REG_LBA_HIGH) != 0 {
+            return None;
+        }
+        self.wait_drq().ok()?;
+
+        let mut words = [0u16; 256];
+        let mut data = Port::<u16>::new(self.io_base + REG_DATA);
+        for word in words.iter_mut() {
+            *word = unsafe { data.read() };
+        }
+        self.read(REG_STATUS); // acknowledge the interrupt, in case it isn't masked
+        IdentifyData::parse(&words)
+    }
+
+    /// Load the task file and send `command`
+    fn issue(&self, slave: bool, command: u8, lba: u64, count: u64, lba48: bool) {
+        self.clear_interrupt();
+        if lba48 {
+            self.write(REG_DRIVE, 0x40 | (slave as u8) << 4);
+            // Each register is a two deep FIFO - the high bytes go in first
+            self.write(REG_SECTOR_COUNT, (count >> 8) as u8);
+            self.write(REG_LBA_LOW, (lba >> 24) as u8);
+            self.write(REG_LBA_MID, (lba >> 32) as u8);
+            self.write(REG_LBA_HIGH, (lba >> 40) as u8);
+        } else {
+            self.write(REG_DRIVE, lba28_drive_select(slave, lba));
+        }
+        self.write(REG_SECTOR_COUNT, count as u8);
+        self.write(REG_LBA_LOW, lba as u8);
+        self.write(REG_LBA_MID, (lba >> 8) as u8);
+        self.write(REG_LBA_HIGH, (lba >> 16) as u8);
+        self.write(REG_COMMAND, command);
+        self.delay_400ns();
+    }
+
+    /// Wait for the drive to interrupt (or, without an IRQ, to stop being busy), then check the status
+    fn completion(&self) -> Completion {
+        Completion { channel: self, started: interrupts::ticks() }
+    }
+
+    fn read_sector(&self, buffer: &mut [u8]) {
+        let mut data = Port::<u16>::new(self.io_base + REG_DATA);
+        for bytes in buffer.chunks_exact_mut(2) {
+            let word: u16 = unsafe { data.read() };
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+    }
+
+    fn write_sector(&self, buffer: &[u8]) {
+        let mut data = Port::<u16>::new(self.io_base + REG_DATA);
+        for bytes in buffer.chunks_exact(2) {
+            unsafe { data.write(u16::from_le_bytes([bytes[0], bytes[1]])) };
+        }
+    }
+}
+
+/// The future returned by `Channel::completion`. Gives the status, or an error if the drive reported one.
+/// If the interrupt doesn't come within `COMPLETION_TIMEOUT_TICKS`, the status decides - a drive that isn't
+/// busy any more finished (and the interrupt got lost), one that still is has timed out
+struct Completion<'a> {
+    channel: &'a Channel,
+    /// `interrupts::ticks()` when the command was issued
+    started: u64,
+}
+
+impl<'a> Completion<'a> {
+    fn finish(&self) -> Result<u8, BlockError> {
+        let status = self.channel.read(REG_STATUS);
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            Err(BlockError::DeviceError("ATA command failed"))
+        } else {
+            Ok(status)
+        }
+    }
+}
+
+impl<'a> Future for Completion<'a> {
+    type Output = Result<u8, BlockError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<u8, BlockError>> {
+        match self.channel.irq {
+            Some(irq) => {
+                let state = &IRQ_STATE[irq];
+                if state.fired.swap(false, Ordering::AcqRel) {
+                    return Poll::Ready(self.finish());
+                }
+                state.waker.register(cx.waker());
+                // The interrupt may have come in before we registered
+                if state.fired.swap(false, Ordering::AcqRel) {
+                    Poll::Ready(self.finish())
+                } else if interrupts::ticks().wrapping_sub(self.started) < COMPLETION_TIMEOUT_TICKS {
+                    Poll::Pending // the timer interrupt wakes us to check again
+                } else if self.channel.alternate_status() & STATUS_BSY == 0 {
+                    serial_println!("[LOG] ATA interrupt {} lost, the drive finished without it", irq);
+                    Poll::Ready(self.finish())
+                } else {
+                    Poll::Ready(Err(BlockError::DeviceError("ATA drive timed out")))
+                }
+            }
+            None => {
+                if self.channel.alternate_status() & STATUS_BSY == 0 {
+                    Poll::Ready(self.finish())
+                } else {
+                    cx.waker().wake_by_ref(); // nothing will wake us, so ask to be polled again
+                    Poll::Pending
+                }
+            }
+        }
+    }
+}
+
+/// # AtaDrive
+///
+/// A drive on an IDE channel, as a block device
+pub struct AtaDrive {
+    name: String,
+    channel: Arc<Channel>,
+    slave: bool,
+    info: IdentifyData,
+}
+
+impl AtaDrive {
+    pub fn info(&self) -> &IdentifyData {
+        &self.info
+    }
+
+    /// Read or write `buffer`, starting at `sector`, splitting it into as few commands as we can
+    async fn transfer(&self, sector: u64, buffer: Transfer<'_>) -> Result<(), BlockError> {
+        let max = if self.info.lba48 { MAX_SECTORS_LBA48 } else { MAX_SECTORS_LBA28 };
+        let _channel = self.channel.lock.lock().await;
+        self.channel.wait_not_busy().ok_or(BlockError::DeviceError("ATA drive not responding"))?;
+
+        match buffer {
+            Transfer::Read(buffer) => {
+                for (index, chunk) in buffer.chunks_mut(max as usize * SECTOR_SIZE).enumerate() {
+                    let lba = sector + index as u64 * max;
+                    let count = (chunk.len() / SECTOR_SIZE) as u64;
+                    let lba48 = self.use_lba48(lba, count)?;
+                    let command = if lba48 { COMMAND_READ_SECTORS_EXT } else { COMMAND_READ_SECTORS };
+                    self.channel.issue(self.slave, command, lba, count, lba48);
+                    // The drive interrupts once each sector is ready to be read
+                    for data in chunk.chunks_exact_mut(SECTOR_SIZE) {
+                        self.channel.completion().await?;
+                        self.channel.read_sector(data);
+                    }
+                }
+            }
+            Transfer::Write(buffer) => {
+                for (index, chunk) in buffer.chunks(max as usize * SECTOR_SIZE).enumerate() {
+                    let lba = sector + index as u64 * max;
+                    let count = (chunk.len() / SECTOR_SIZE) as u64;
+                    let lba48 = self.use_lba48(lba, count)?;
+                    let command = if lba48 { COMMAND_WRITE_SECTORS_EXT } else { COMMAND_WRITE_SECTORS };
+                    self.channel.issue(self.slave, command, lba, count, lba48);
+                    // The first sector goes straight away. After that, the drive interrupts when it has taken
+                    // each one, and once more when the command is done
+                    self.channel.wait_drq()?;
+                    for data in chunk.chunks_exact(SECTOR_SIZE) {
+                        self.channel.write_sector(data);
+                        self.channel.completion().await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn use_lba48(&self, sector: u64, count: u64) -> Result<bool, BlockError> {
+        match needs_lba48(sector, count) {
+            true if !self.info.lba48 => Err(BlockError::OutOfRange),
+            needed => Ok(needed),
+        }
+    }
+}
+
+/// Which way a transfer goes, and the buffer
+enum Transfer<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+impl BlockDevice for AtaDrive {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.info.sectors
+    }
+
+    fn read<'a>(&'a self, sector: u64, buffer: &'a mut [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            block::check_request(self, sector, buffer.len())?;
+            self.transfer(sector, Transfer::Read(buffer)).await
+        })
+    }
+
+    fn write<'a>(&'a self, sector: u64, buffer: &'a [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            block::check_request(self, sector, buffer.len())?;
+            self.transfer(sector, Transfer::Write(buffer)).await
+        })
+    }
+
+    fn flush(&self) -> BlockFuture<'_, ()> {
+        Box::pin(async move {
+            let _channel = self.channel.lock.lock().await;
+            self.channel.wait_not_busy().ok_or(BlockError::DeviceError("ATA drive not responding"))?;
+            let command = if self.info.lba48 { COMMAND_FLUSH_CACHE_EXT } else { COMMAND_FLUSH_CACHE };
+            self.channel.issue(self.slave, command, 0, 0, false);
+            self.channel.completion().await.map(|_| ())
+        })
+    }
+}
+
+/// # AtaDriver
+///
+/// Binds to IDE controllers, and registers a block device for each drive on them
+pub struct AtaDriver {
+    /// The drives found on each controller, so `remove` can unregister them
+    drives: Mutex<Vec<(DeviceId, Arc<AtaDrive>)>>,
+    next_drive: AtomicUsize,
+}
+
+/// The ATA driver. [driver::init](../fn.init.html) registers it
+pub static ATA_DRIVER: AtaDriver = AtaDriver { drives: Mutex::new(Vec::new()), next_drive: AtomicUsize::new(0) };
+
+impl AtaDriver {
+    /// Channel `index` (0 for primary, 1 for secondary) of an IDE controller. Its programming interface says
+    /// whether the channel is in native mode. `None` if a native mode channel's BARs aren't set up
+    fn channel(function: &PciDevice, index: usize) -> Option<Channel> {
+        let native = function.prog_if & (1 << (index * 2)) != 0;
+        if native {
+            // The control block BAR is 4 ports, with the alternate status register at +2
+            match (function.bar(index * 2), function.bar(index * 2 + 1)) {
+                (Some(Bar::Io { port: io_base, .. }), Some(Bar::Io { port: control, .. })) => Some(Channel {
+                    io_base: io_base as u16,
+                    control: control as u16 + 2,
+                    irq: None,
+                    lock: AsyncMutex::new(()),
+                }),
+                _ => None,
+            }
+        } else {
+            let (io_base, control, irq) = LEGACY_CHANNELS[index];
+            interrupts::unmask_pic_irq(irq);
+            Some(Channel { io_base, control, irq: Some(index), lock: AsyncMutex::new(()) })
+        }
+    }
+}
+
+impl Driver for AtaDriver {
+    fn name(&self) -> &'static str {
+        "ata"
+    }
+
+    fn match_table(&self) -> &'static [DeviceMatch] {
+        // By class code too, for controllers `brute_force_scan` didn't see (behind a bridge)
+        &[DeviceMatch::PciClass(PciFullClass::MassStorage_IDE), DeviceMatch::PciClassCode { class: 0x01, subclass: 0x01 }]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let function = device.pci_function().ok_or(ProbeError::Unsupported)?;
+        function.enable_io_space();
+
+        for index in 0..2 {
+            let channel = match Self::channel(&function, index) {
+                Some(channel) => Arc::new(channel),
+                None => continue,
+            };
+            for &slave in &[false, true] {
+                let info = match channel.identify(slave) {
+                    Some(info) => info,
+                    None => continue,
+                };
+                let name = format!("ata{}", self.next_drive.fetch_add(1, Ordering::Relaxed));
+                serial_println!(
+                    "[LOG] {}: {} {} - \"{}\"{}",
+                    name,
+                    if index == 0 { "primary" } else { "secondary" },
+                    if slave { "slave" } else { "master" },
+                    info.model,
+                    if info.lba48 { ", LBA48" } else { "" }
+                );
+                let drive = Arc::new(AtaDrive { name, channel: channel.clone(), slave, info });
+                block::register(drive.clone());
+                self.drives.lock().push((device.id, drive));
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        self.drives.lock().retain(|(id, drive)| {
+            if *id == device.id {
+                block::unregister(drive.name());
+            }
+            *id != device.id
+        });
+    }
+}
+
+/* Testing */
+
+#[test_case]
+fn ata_identify_parsing() {
+    let mut words = [0u16; 256];
+    words[49] = 1 << 9; // LBA supported
+    words[60] = 0x0000;
+    words[61] = 0x0010; // 0x100000 sectors (512 MiB)
+    // "QEMU HARDDISK", two characters per word, high byte first
+    for (index, pair) in b"QEMU HARDDISK   ".chunks(2).enumerate() {
+        words[27 + index] = (pair[0] as u16) << 8 | pair[1] as u16;
+    }
+    let info = IdentifyData::parse(&words).unwrap();
+    assert_eq!(info.model, "QEMU HARDDISK");
+    assert_eq!(info.sectors, 0x10_0000);
+    assert!(!info.lba48);
+    assert_eq!(info.queue_depth, 0);
+
+    // With LBA48, the 64 bit count wins
+    words[83] = 1 << 10;
+    words[100] = 0x0000;
+    words[101] = 0x0040; // 0x400000 sectors (2 GiB)
+    let info = IdentifyData::parse(&words).unwrap();
+    assert!(info.lba48);
+    assert_eq!(info.sectors, 0x40_0000);
+
+    // NCQ, 32 deep
+    words[76] = 1 << 8;
+    words[75] = 31;
+    assert_eq!(IdentifyData::parse(&words).unwrap().queue_depth, 32);
+
+    // ATAPI devices aren't disks
+    words[0] = 1 << 15;
+    assert_eq!(IdentifyData::parse(&words), None);
+}
+
+#[test_case]
+fn ata_addressing() {
+    assert_eq!(lba28_drive_select(false, 0), 0xE0);
+    assert_eq!(lba28_drive_select(true, 0x0ABC_DEF0), 0xFA);
+    assert!(!needs_lba48(0, 256));
+    assert!(needs_lba48(0, 257));
+    assert!(needs_lba48(LBA28_LIMIT - 1, 2));
+}
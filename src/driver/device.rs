@@ -0,0 +1,176 @@
+//! # Devices
+//!
+//! A [Device](struct.Device.html) is something a driver can bind to - a PCI function, a legacy ISA device at
+//! fixed ports, a device the ACPI namespace declares, or a virtio device. Drivers say which devices they
+//! want with a table of [DeviceMatch](enum.DeviceMatch.html)es.
+
+use alloc::string::String;
+use core::fmt;
+use tinypci::PciFullClass;
+use super::pci::PciDevice;
+
+/// # DeviceId
+///
+/// The registry's handle for a device. Never reused, even after the device is removed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DeviceId(pub u32);
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "dev{}", self.0)
+    }
+}
+
+/// # Bus
+///
+/// Where a device was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Bus {
+    Pci,
+    /// Legacy devices at fixed I/O ports and IRQs (the PS/2 controller, serial ports...)
+    Isa,
+    /// Devices declared in the ACPI namespace, with a `_HID`
+    Acpi,
+    /// Paravirtual devices. The virtio core adds these when it finds their PCI functions
+    Virtio,
+}
+
+impl fmt::Display for Bus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Bus::Pci => "pci",
+            Bus::Isa => "isa",
+            Bus::Acpi => "acpi",
+            Bus::Virtio => "virtio",
+        };
+        f.write_str(name)
+    }
+}
+
+/// # DeviceKind
+///
+/// The bus specific part of a device - what a driver needs to find and talk to it
+#[derive(Debug, Clone)]
+pub enum DeviceKind {
+    /// A PCI function. `full_class` is what `brute_force_scan` reported for it, if it saw it
+    Pci { function: PciDevice, full_class: Option<PciFullClass> },
+    /// `io_size` ports from `io_base`, and the ISA IRQ it uses (if any)
+    Isa { io_base: u16, io_size: u16, irq: Option<u8> },
+    /// `hid` is the hardware ID, like `PNP0303`. EISA IDs are decoded to their string form
+    Acpi { hid: String },
+    /// A virtio device of `device_type` (1 for network, 2 for block...), and the PCI function behind it
+    Virtio { function: PciDevice, device_type: u16 },
+}
+
+/// # Device
+///
+/// A device in the [registry](../registry/index.html)
+#[derive(Debug, Clone)]
+pub struct Device {
+    pub id: DeviceId,
+    /// How the device is usually named on its bus - `0000:00:1f.2` for PCI, the namespace path for ACPI
+    pub name: String,
+    pub kind: DeviceKind,
+}
+
+impl Device {
+    pub fn bus(&self) -> Bus {
+        match self.kind {
+            DeviceKind::Pci { .. } => Bus::Pci,
+            DeviceKind::Isa { .. } => Bus::Isa,
+            DeviceKind::Acpi { .. } => Bus::Acpi,
+            DeviceKind::Virtio { .. } => Bus::Virtio,
+        }
+    }
+
+    /// The PCI function behind the device - for PCI and virtio devices
+    pub fn pci_function(&self) -> Option<PciDevice> {
+        match self.kind {
+            DeviceKind::Pci { function, .. } | DeviceKind::Virtio { function, .. } => Some(function),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}:{}", self.id, self.bus(), self.name)
+    }
+}
+
+/// # DeviceMatch
+///
+/// One entry in a driver's match table
+#[derive(Debug, Clone, Copy)]
+pub enum DeviceMatch {
+    /// A PCI function with this vendor and device ID
+    PciId { vendor: u16, device: u16 },
+    /// Any PCI function from this vendor
+    PciVendor(u16),
+    /// A PCI function of this class, as `brute_force_scan` names it
+    PciClass(PciFullClass),
+    /// A PCI function with this class and subclass code, for classes `PciFullClass` doesn't name
+    PciClassCode { class: u8, subclass: u8 },
+    /// An ISA device with this name
+    Isa(&'static str),
+    /// An ACPI device with this hardware ID
+    AcpiHid(&'static str),
+    /// A virtio device of this type
+    Virtio(u16),
+}
+
+impl DeviceMatch {
+    pub fn matches(&self, device: &Device) -> bool {
+        match (*self, &device.kind) {
+            (DeviceMatch::PciId { vendor, device: id }, DeviceKind::Pci { function, .. }) => {
+                function.vendor_id == vendor && function.device_id == id
+            }
+            (DeviceMatch::PciVendor(vendor), DeviceKind::Pci { function, .. }) => function.vendor_id == vendor,
+            (DeviceMatch::PciClass(class), DeviceKind::Pci { full_class, .. }) => *full_class == Some(class),
+            (DeviceMatch::PciClassCode { class, subclass }, DeviceKind::Pci { function, .. }) => {
+                function.class == class && function.subclass == subclass
+            }
+            (DeviceMatch::Isa(name), DeviceKind::Isa { .. }) => device.name == name,
+            (DeviceMatch::AcpiHid(id), DeviceKind::Acpi { hid }) => hid.as_str() == id,
+            (DeviceMatch::Virtio(wanted), DeviceKind::Virtio { device_type, .. }) => *device_type == wanted,
+            _ => false,
+        }
+    }
+}
+
+/// Whether any entry in `table` matches `device`
+pub fn matches_any(table: &[DeviceMatch], device: &Device) -> bool {
+    table.iter().any(|entry| entry.matches(device))
+}
+
+/* Testing */
+
+#[test_case]
+fn device_match_tables() {
+    use alloc::string::ToString;
+    use super::pci::PciAddress;
+
+    let nic = PciDevice {
+        address: PciAddress::new(0, 0, 3, 0),
+        vendor_id: 0x8086,
+        device_id: 0x100E,
+        class: 0x02,
+        subclass: 0x00,
+        prog_if: 0,
+        revision: 3,
+        header_type: 0,
+    };
+    let nic = Device { id: DeviceId(0), name: nic.address.to_string(), kind: DeviceKind::Pci { function: nic, full_class: None } };
+    assert!(DeviceMatch::PciId { vendor: 0x8086, device: 0x100E }.matches(&nic));
+    assert!(!DeviceMatch::PciId { vendor: 0x8086, device: 0x10D3 }.matches(&nic));
+    assert!(DeviceMatch::PciClassCode { class: 0x02, subclass: 0x00 }.matches(&nic));
+    assert!(!DeviceMatch::Isa("i8042").matches(&nic)); // wrong bus
+
+    let keyboard = Device {
+        id: DeviceId(1),
+        name: "i8042".to_string(),
+        kind: DeviceKind::Isa { io_base: 0x60, io_size: 5, irq: Some(1) },
+    };
+    assert!(matches_any(&[DeviceMatch::PciVendor(0x1AF4), DeviceMatch::Isa("i8042")], &keyboard));
+    assert_eq!(keyboard.bus(), Bus::Isa);
+}
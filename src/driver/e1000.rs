@@ -0,0 +1,609 @@
+//! # e1000
+//!
+//! Driver for Intel's gigabit ethernet cards - the 8254x family (`e1000`, QEMU's default NIC) and the 82574
+//! (`e1000e`). Both keep frames in rings of 16 byte descriptors in memory, one ring each way. Each descriptor
+//! points at a 2 KiB buffer; the card sets a descriptor's done bit when it has filled (or sent) it, and moves
+//! its head register along. We move the tail register to hand descriptors back.
+//!
+//! The MAC address is read from the EEPROM. The card says why it interrupted in the interrupt cause register
+//! (reading it clears it): frames arrived, frames went out, or the link changed. The 82574 can interrupt with
+//! MSI. The 8254x only has the legacy INTx line, so its rings are polled - as are the 82574's if MSI can't be
+//! set up.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::memory::{self, dma::DmaBuffer};
+use crate::net::{self, MacAddress, NetError, NetFuture, NetworkDevice, PartialChecksum, Received};
+use crate::sync::AsyncMutex;
+use crate::{serial_println, smp};
+use super::pci::{Bar, Msi, PciDevice};
+use super::{Device, DeviceId, DeviceMatch, Driver, ProbeError};
+
+/* Registers (offsets from BAR 0) */
+const REG_CTRL: u64 = 0x0000;
+const REG_STATUS: u64 = 0x0008;
+const REG_EERD: u64 = 0x0014;
+const REG_ICR: u64 = 0x00C0;
+const REG_IMS: u64 = 0x00D0;
+const REG_IMC: u64 = 0x00D8;
+const REG_RCTL: u64 = 0x0100;
+const REG_TCTL: u64 = 0x0400;
+const REG_TIPG: u64 = 0x0410;
+const REG_RDBAL: u64 = 0x2800;
+const REG_RDBAH: u64 = 0x2804;
+const REG_RDLEN: u64 = 0x2808;
+const REG_RDH: u64 = 0x2810;
+const REG_RDT: u64 = 0x2818;
+const REG_TDBAL: u64 = 0x3800;
+const REG_TDBAH: u64 = 0x3804;
+const REG_TDLEN: u64 = 0x3808;
+const REG_TDH: u64 = 0x3810;
+const REG_TDT: u64 = 0x3818;
+const REG_RXCSUM: u64 = 0x5000;
+/// The multicast table - 128 words
+const REG_MTA: u64 = 0x5200;
+const REG_RAL: u64 = 0x5400;
+const REG_RAH: u64 = 0x5404;
+
+/* Device control */
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+const CTRL_PHY_RST: u32 = 1 << 31;
+
+const STATUS_LU: u32 = 1 << 1;
+
+/* EEPROM read - the start bit, then the word address and done bit, which move between families */
+const EERD_START: u32 = 1 << 0;
+const EERD_DATA_SHIFT: u32 = 16;
+/// The MAC address is the first three words
+const EEPROM_MAC: u16 = 0;
+
+/* Interrupt causes (ICR, IMS and IMC) */
+/// A frame went out
+const ICR_TXDW: u32 = 1 << 0;
+/// The link went up or down
+const ICR_LSC: u32 = 1 << 2;
+/// The receive ring is running low
+const ICR_RXDMT0: u32 = 1 << 4;
+/// The receive ring overflowed - frames were dropped
+const ICR_RXO: u32 = 1 << 6;
+/// Frames arrived
+const ICR_RXT0: u32 = 1 << 7;
+const INTERRUPT_CAUSES: u32 = ICR_TXDW | ICR_LSC | ICR_RXDMT0 | ICR_RXO | ICR_RXT0;
+
+/* Receive control. A buffer size of zero is 2 KiB */
+const RCTL_EN: u32 = 1 << 1;
+/// Take broadcast frames
+const RCTL_BAM: u32 = 1 << 15;
+/// Strip the CRC
+const RCTL_SECRC: u32 = 1 << 26;
+
+/* Receive checksum offload - check IP, and TCP or UDP, checksums */
+const RXCSUM_IPOFLD: u32 = 1 << 8;
+const RXCSUM_TUOFLD: u32 = 1 << 9;
+
+/* Transmit control */
+const TCTL_EN: u32 = 1 << 1;
+/// Pad short frames
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x0F << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+/// The inter packet gaps for copper - IPGT, IPGR1 and IPGR2
+const TIPG_COPPER: u32 = 10 | 8 << 10 | 6 << 20;
+
+const RAH_AV: u32 = 1 << 31;
+
+/* Descriptors. Both kinds start with the buffer's address */
+const DESCRIPTOR_SIZE: usize = 16;
+const DESCRIPTOR_LENGTH: usize = 8;
+/// Receive: status and errors. Transmit: the command is at 11, status at 12
+const RX_DESCRIPTOR_STATUS: usize = 12;
+const RX_DESCRIPTOR_ERRORS: usize = 13;
+const TX_DESCRIPTOR_COMMAND: usize = 11;
+const TX_DESCRIPTOR_STATUS: usize = 12;
+
+/// Done - the card has filled (or sent) the descriptor
+const DESCRIPTOR_DD: u8 = 1 << 0;
+const RX_STATUS_EOP: u8 = 1 << 1;
+/// Ignore the checksum bits
+const RX_STATUS_IXSM: u8 = 1 << 2;
+/// The TCP or UDP checksum was checked
+const RX_STATUS_TCPCS: u8 = 1 << 5;
+/// The TCP or UDP checksum was wrong
+const RX_ERROR_TCPE: u8 = 1 << 5;
+/// CRC, symbol, sequence, carrier extension and data errors - the frame is bad
+const RX_ERROR_FRAME: u8 = 0x97;
+const TX_COMMAND_EOP: u8 = 1 << 0;
+/// Insert the CRC
+const TX_COMMAND_IFCS: u8 = 1 << 1;
+/// Report status - set DD when sent
+const TX_COMMAND_RS: u8 = 1 << 3;
+
+/// How many descriptors each ring has. The rings have to be a multiple of 128 bytes
+const RING_SIZE: usize = 32;
+const BUFFER_SIZE: usize = 2048;
+const RESET_TIMEOUT_US: u64 = 100_000;
+const EEPROM_TIMEOUT_US: u64 = 10_000;
+const POLL_DELAY_US: u64 = 10;
+
+fn read_register(base: VirtAddr, offset: u64) -> u32 {
+    unsafe { ptr::read_volatile((base + offset).as_ptr::<u32>()) }
+}
+
+fn write_register(base: VirtAddr, offset: u64, value: u32) {
+    unsafe { ptr::write_volatile((base + offset).as_mut_ptr::<u32>(), value) }
+}
+
+/// # Model
+///
+/// A card this driver knows, and where its EEPROM read register keeps the word address and done bit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Model {
+    pub device_id: u16,
+    pub name: &'static str,
+    eerd_address_shift: u32,
+    eerd_done: u32,
+}
+
+impl Model {
+    /// What to write to EERD to read EEPROM word `address`
+    fn eeprom_request(&self, address: u16) -> u32 {
+        (address as u32) << self.eerd_address_shift | EERD_START
+    }
+}
+
+const MODELS: &[Model] = &[
+    Model { device_id: 0x100E, name: "82540EM", eerd_address_shift: 8, eerd_done: 1 << 4 },
+    Model { device_id: 0x100F, name: "82545EM", eerd_address_shift: 8, eerd_done: 1 << 4 },
+    Model { device_id: 0x10D3, name: "82574L", eerd_address_shift: 2, eerd_done: 1 << 1 },
+];
+
+/// The MAC address, from the first three EEPROM words - each is two bytes, low byte first
+fn mac_from_eeprom(words: [u16; 3]) -> MacAddress {
+    let mut mac = [0; 6];
+    for (bytes, word) in mac.chunks_mut(2).zip(words.iter()) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    MacAddress(mac)
+}
+
+/// The next descriptor to look at in the receive ring
+struct Receiving {
+    next: usize,
+}
+
+/// The next descriptor to use in the transmit ring
+struct Transmitting {
+    next: usize,
+}
+
+/// # E1000
+///
+/// An e1000 card, as a network device
+pub struct E1000 {
+    name: String,
+    model: Model,
+    mac: MacAddress,
+    registers: VirtAddr,
+    rx_ring: DmaBuffer,
+    rx_buffers: DmaBuffer,
+    tx_ring: DmaBuffer,
+    tx_buffers: DmaBuffer,
+    receiving: AsyncMutex<Receiving>,
+    transmitting: AsyncMutex<Transmitting>,
+    rx_waker: AtomicWaker,
+    tx_waker: AtomicWaker,
+    /// The link state, as of the last link change
+    link: AtomicBool,
+    /// Whether MSI is on. Without it, nothing wakes the rings' futures, so they are polled
+    interrupts: AtomicBool,
+    /// Kept so `remove` can turn it off
+    msi: Mutex<Option<Msi>>,
+}
+
+impl E1000 {
+    fn read(&self, offset: u64) -> u32 {
+        read_register(self.registers, offset)
+    }
+
+    fn write(&self, offset: u64, value: u32) {
+        write_register(self.registers, offset, value)
+    }
+
+    /// Read (and so clear) the interrupt causes, and wake whoever was waiting for them. Called from the
+    /// interrupt handler, and by the rings' futures when they are polled
+    fn process(&self) {
+        let causes = self.read(REG_ICR);
+        if causes & ICR_LSC != 0 {
+            self.link.store(self.read(REG_STATUS) & STATUS_LU != 0, Ordering::Release);
+        }
+        if causes & (ICR_RXT0 | ICR_RXDMT0 | ICR_RXO) != 0 {
+            self.rx_waker.wake();
+        }
+        if causes & ICR_TXDW != 0 {
+            self.tx_waker.wake();
+        }
+    }
+
+    fn rx_status(&self, index: usize) -> u8 {
+        unsafe { ptr::read_volatile(self.rx_ring.ptr::<u8>(index * DESCRIPTOR_SIZE + RX_DESCRIPTOR_STATUS)) }
+    }
+
+    fn tx_status(&self, index: usize) -> u8 {
+        unsafe { ptr::read_volatile(self.tx_ring.ptr::<u8>(index * DESCRIPTOR_SIZE + TX_DESCRIPTOR_STATUS)) }
+    }
+
+    /// Point each descriptor of `ring` at its buffer, with `status` - done for the transmit ring, so every
+    /// descriptor starts out free
+    fn fill_ring(ring: &DmaBuffer, buffers: &DmaBuffer, status: u8) {
+        for index in 0..RING_SIZE {
+            let descriptor = index * DESCRIPTOR_SIZE;
+            let address = buffers.phys_addr().as_u64() + (index * BUFFER_SIZE) as u64;
+            unsafe {
+                ptr::write_volatile(ring.ptr::<u64>(descriptor), address);
+                ptr::write_volatile(ring.ptr::<u64>(descriptor + 8), 0);
+                ptr::write_volatile(ring.ptr::<u8>(descriptor + RX_DESCRIPTOR_STATUS), status);
+            }
+        }
+    }
+}
+
+/// Which ring a `DescriptorDone` is waiting on
+#[derive(Clone, Copy)]
+enum Ring {
+    Receive,
+    Transmit,
+}
+
+/// Waits for the card to set the done bit of descriptor `index`
+struct DescriptorDone<'a> {
+    card: &'a E1000,
+    ring: Ring,
+    index: usize,
+}
+
+impl<'a> Future for DescriptorDone<'a> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let card = self.card;
+        let (waker, status) = match self.ring {
+            Ring::Receive => (&card.rx_waker, card.rx_status(self.index)),
+            Ring::Transmit => (&card.tx_waker, card.tx_status(self.index)),
+        };
+        if status & DESCRIPTOR_DD != 0 {
+            return Poll::Ready(());
+        }
+        waker.register(cx.waker());
+        // It may have been done between our look and registering
+        card.process();
+        let status = match self.ring {
+            Ring::Receive => card.rx_status(self.index),
+            Ring::Transmit => card.tx_status(self.index),
+        };
+        if status & DESCRIPTOR_DD != 0 {
+            return Poll::Ready(());
+        }
+        if !card.interrupts.load(Ordering::Acquire) {
+            cx.waker().wake_by_ref(); // nothing will wake us, so ask to be polled again
+        }
+        Poll::Pending
+    }
+}
+
+/// The interrupt handler. `context` points to the `E1000`
+fn handle_interrupt(context: usize) {
+    let card = unsafe { &*(context as *const E1000) };
+    card.process();
+}
+
+impl NetworkDevice for E1000 {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        self.process();
+        self.link.load(Ordering::Acquire)
+    }
+
+    fn send<'a>(&'a self, frame: &'a [u8], checksum: Option<PartialChecksum>) -> NetFuture<'a, ()> {
+        Box::pin(async move {
+            net::check_frame(self, frame)?;
+            if !self.link_up() {
+                return Err(NetError::LinkDown);
+            }
+            let mut transmitting = self.transmitting.lock().await;
+            let index = transmitting.next;
+            // Wait for the card to have sent whatever was last in the descriptor
+            DescriptorDone { card: self, ring: Ring::Transmit, index }.await;
+
+            // The buffer is ours until the card is given the descriptor
+            let buffer = unsafe { core::slice::from_raw_parts_mut(self.tx_buffers.ptr::<u8>(index * BUFFER_SIZE), frame.len()) };
+            buffer.copy_from_slice(frame);
+            if let Some(checksum) = checksum {
+                net::finish_checksum(buffer, checksum);
+            }
+            let descriptor = index * DESCRIPTOR_SIZE;
+            unsafe {
+                ptr::write_volatile(self.tx_ring.ptr::<u16>(descriptor + DESCRIPTOR_LENGTH), frame.len() as u16);
+                ptr::write_volatile(self.tx_ring.ptr::<u8>(descriptor + TX_DESCRIPTOR_STATUS), 0);
+                ptr::write_volatile(
+                    self.tx_ring.ptr::<u8>(descriptor + TX_DESCRIPTOR_COMMAND),
+                    TX_COMMAND_EOP | TX_COMMAND_IFCS | TX_COMMAND_RS,
+                );
+            }
+            transmitting.next = (index + 1) % RING_SIZE;
+            self.write(REG_TDT, transmitting.next as u32);
+            Ok(())
+        })
+    }
+
+    fn receive(&self) -> NetFuture<'_, Received> {
+        Box::pin(async move {
+            let mut receiving = self.receiving.lock().await;
+            loop {
+                let index = receiving.next;
+                DescriptorDone { card: self, ring: Ring::Receive, index }.await;
+
+                let descriptor = index * DESCRIPTOR_SIZE;
+                let (length, status, errors) = unsafe {
+                    (
+                        ptr::read_volatile(self.rx_ring.ptr::<u16>(descriptor + DESCRIPTOR_LENGTH)) as usize,
+                        ptr::read_volatile(self.rx_ring.ptr::<u8>(descriptor + RX_DESCRIPTOR_STATUS)),
+                        ptr::read_volatile(self.rx_ring.ptr::<u8>(descriptor + RX_DESCRIPTOR_ERRORS)),
+                    )
+                };
+                // Long frames are off, so every frame fits in one buffer
+                let good = status & RX_STATUS_EOP != 0 && errors & RX_ERROR_FRAME == 0;
+                let received = match good && length >= net::ETHERNET_HEADER_SIZE && length <= BUFFER_SIZE {
+                    true => {
+                        let start = index * BUFFER_SIZE;
+                        let checked = status & (RX_STATUS_IXSM | RX_STATUS_TCPCS) == RX_STATUS_TCPCS;
+                        Some(Received {
+                            frame: self.rx_buffers.as_slice()[start..start + length].to_vec(),
+                            checksum_valid: checked && errors & RX_ERROR_TCPE == 0,
+                        })
+                    }
+                    false => None, // bad or cut short - skip it
+                };
+
+                // Hand the descriptor back. The tail is always one behind the descriptor we look at next
+                unsafe {
+                    ptr::write_volatile(self.rx_ring.ptr::<u8>(descriptor + RX_DESCRIPTOR_STATUS), 0);
+                    ptr::write_volatile(self.rx_ring.ptr::<u8>(descriptor + RX_DESCRIPTOR_ERRORS), 0);
+                }
+                receiving.next = (index + 1) % RING_SIZE;
+                self.write(REG_RDT, index as u32);
+                if let Some(received) = received {
+                    return Ok(received);
+                }
+            }
+        })
+    }
+}
+
+/// # E1000Driver
+///
+/// Binds to e1000 cards, and registers each as a network device
+pub struct E1000Driver {
+    /// Each card - kept here so the interrupt handler's context stays valid until `remove`
+    cards: Mutex<Vec<(DeviceId, Arc<E1000>)>>,
+}
+
+/// The e1000 driver. [driver::init](../fn.init.html) registers it
+pub static E1000_DRIVER: E1000Driver = E1000Driver { cards: Mutex::new(Vec::new()) };
+
+impl E1000Driver {
+    /// Reset the card, and wait for it to come back. Interrupts are masked afterwards
+    fn reset(registers: VirtAddr) -> Result<(), ProbeError> {
+        write_register(registers, REG_IMC, !0);
+        write_register(registers, REG_CTRL, read_register(registers, REG_CTRL) | CTRL_RST);
+        smp::delay_us(1000);
+        let mut waited = 0;
+        while read_register(registers, REG_CTRL) & CTRL_RST != 0 {
+            if waited >= RESET_TIMEOUT_US {
+                return Err(ProbeError::DeviceError("card didn't come out of reset"));
+            }
+            smp::delay_us(POLL_DELAY_US);
+            waited += POLL_DELAY_US;
+        }
+        write_register(registers, REG_IMC, !0);
+        read_register(registers, REG_ICR);
+        Ok(())
+    }
+
+    /// Read EEPROM word `address`. `None` if the card has no EEPROM, or it didn't answer
+    fn read_eeprom(registers: VirtAddr, model: &Model, address: u16) -> Option<u16> {
+        write_register(registers, REG_EERD, model.eeprom_request(address));
+        let mut waited = 0;
+        loop {
+            let value = read_register(registers, REG_EERD);
+            if value & model.eerd_done != 0 {
+                return Some((value >> EERD_DATA_SHIFT) as u16);
+            }
+            if waited >= EEPROM_TIMEOUT_US {
+                return None;
+            }
+            smp::delay_us(POLL_DELAY_US);
+            waited += POLL_DELAY_US;
+        }
+    }
+
+    /// The MAC address from the EEPROM - or, failing that, what the card loaded into its first receive
+    /// address at reset
+    fn read_mac(registers: VirtAddr, model: &Model) -> MacAddress {
+        let mut words = [0; 3];
+        for (index, word) in words.iter_mut().enumerate() {
+            match Self::read_eeprom(registers, model, EEPROM_MAC + index as u16) {
+                Some(value) => *word = value,
+                None => {
+                    let low = read_register(registers, REG_RAL);
+                    let high = read_register(registers, REG_RAH);
+                    return mac_from_eeprom([low as u16, (low >> 16) as u16, high as u16]);
+                }
+            }
+        }
+        mac_from_eeprom(words)
+    }
+
+    /// Set up filtering and both rings, and turn the receiver and transmitter on
+    fn start(card: &E1000) {
+        // Take frames for our address and broadcasts, and no multicast
+        let mac = card.mac.0;
+        card.write(REG_RAL, u32::from_le_bytes([mac[0], mac[1], mac[2], mac[3]]));
+        card.write(REG_RAH, u16::from_le_bytes([mac[4], mac[5]]) as u32 | RAH_AV);
+        for index in 0..128 {
+            card.write(REG_MTA + index * 4, 0);
+        }
+        let control = card.read(REG_CTRL) & !CTRL_PHY_RST;
+        card.write(REG_CTRL, control | CTRL_SLU | CTRL_ASDE);
+
+        let rx_ring = card.rx_ring.phys_addr().as_u64();
+        card.write(REG_RDBAL, rx_ring as u32);
+        card.write(REG_RDBAH, (rx_ring >> 32) as u32);
+        card.write(REG_RDLEN, (RING_SIZE * DESCRIPTOR_SIZE) as u32);
+        card.write(REG_RDH, 0);
+        card.write(REG_RDT, (RING_SIZE - 1) as u32);
+        card.write(REG_RXCSUM, RXCSUM_IPOFLD | RXCSUM_TUOFLD);
+        card.write(REG_RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+
+        let tx_ring = card.tx_ring.phys_addr().as_u64();
+        card.write(REG_TDBAL, tx_ring as u32);
+        card.write(REG_TDBAH, (tx_ring >> 32) as u32);
+        card.write(REG_TDLEN, (RING_SIZE * DESCRIPTOR_SIZE) as u32);
+        card.write(REG_TDH, 0);
+        card.write(REG_TDT, 0);
+        card.write(REG_TIPG, TIPG_COPPER);
+        card.write(REG_TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+    }
+
+    /// Route the card's interrupt to `handle_interrupt` with MSI, and unmask the causes we handle. Without
+    /// MSI the rings stay polled, and INTx stays off
+    fn enable_interrupts(function: PciDevice, card: &Arc<E1000>) {
+        let context = Arc::as_ptr(card) as usize;
+        let mut msi = match function.msi() {
+            Ok(msi) => msi,
+            Err(_) => {
+                function.set_interrupt_disable(true);
+                return;
+            }
+        };
+        if msi.enable(handle_interrupt, context).is_err() {
+            function.set_interrupt_disable(true);
+            return;
+        }
+        *card.msi.lock() = Some(msi);
+        card.interrupts.store(true, Ordering::Release);
+        card.write(REG_IMS, INTERRUPT_CAUSES);
+    }
+}
+
+impl Driver for E1000Driver {
+    fn name(&self) -> &'static str {
+        "e1000"
+    }
+
+    fn match_table(&self) -> &'static [DeviceMatch] {
+        &[
+            DeviceMatch::PciId { vendor: 0x8086, device: 0x100E },
+            DeviceMatch::PciId { vendor: 0x8086, device: 0x100F },
+            DeviceMatch::PciId { vendor: 0x8086, device: 0x10D3 },
+        ]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let function = device.pci_function().ok_or(ProbeError::Unsupported)?;
+        let model = *MODELS.iter().find(|model| model.device_id == function.device_id).ok_or(ProbeError::Unsupported)?;
+        let registers = match function.bar(0) {
+            Some(Bar::Memory { address, size, .. }) => {
+                memory::map_device(PhysAddr::new(address), size).map_err(|_| ProbeError::OutOfResources)?
+            }
+            _ => return Err(ProbeError::DeviceError("no register BAR")),
+        };
+        function.enable_memory_space();
+        function.enable_bus_mastering();
+        Self::reset(registers)?;
+        let mac = Self::read_mac(registers, &model);
+
+        let dma = |size: usize| DmaBuffer::new(size).ok_or(ProbeError::OutOfResources);
+        let card = Arc::new(E1000 {
+            name: net::next_name(),
+            model,
+            mac,
+            registers,
+            rx_ring: dma(RING_SIZE * DESCRIPTOR_SIZE)?,
+            rx_buffers: dma(RING_SIZE * BUFFER_SIZE)?,
+            tx_ring: dma(RING_SIZE * DESCRIPTOR_SIZE)?,
+            tx_buffers: dma(RING_SIZE * BUFFER_SIZE)?,
+            receiving: AsyncMutex::new(Receiving { next: 0 }),
+            transmitting: AsyncMutex::new(Transmitting { next: 0 }),
+            rx_waker: AtomicWaker::new(),
+            tx_waker: AtomicWaker::new(),
+            link: AtomicBool::new(read_register(registers, REG_STATUS) & STATUS_LU != 0),
+            interrupts: AtomicBool::new(false),
+            msi: Mutex::new(None),
+        });
+        E1000::fill_ring(&card.rx_ring, &card.rx_buffers, 0);
+        E1000::fill_ring(&card.tx_ring, &card.tx_buffers, DESCRIPTOR_DD);
+        Self::start(&card);
+        // The card stays alive in `cards` until `remove` has disabled the interrupt
+        Self::enable_interrupts(function, &card);
+
+        serial_println!(
+            "[LOG] {}: Intel {}{}",
+            card.name,
+            card.model.name,
+            if card.interrupts.load(Ordering::Acquire) { ", MSI" } else { ", polled" }
+        );
+        net::register(card.clone());
+        self.cards.lock().push((device.id, card));
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        let mut cards = self.cards.lock();
+        if let Some(index) = cards.iter().position(|(id, _)| *id == device.id) {
+            let (_, card) = cards.remove(index);
+            net::unregister(&card.name);
+            card.write(REG_IMC, !0);
+            if let Some(mut msi) = card.msi.lock().take() {
+                msi.disable();
+            }
+            card.write(REG_RCTL, 0);
+            card.write(REG_TCTL, 0);
+            let _ = Self::reset(card.registers);
+        }
+    }
+}
+
+/* Testing */
+
+#[test_case]
+fn e1000_mac_from_eeprom() {
+    let mac = mac_from_eeprom([0x5452, 0x1200, 0x5634]);
+    assert_eq!(mac, MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]));
+}
+
+#[test_case]
+fn e1000_eeprom_requests() {
+    let e1000 = MODELS.iter().find(|model| model.device_id == 0x100E).unwrap();
+    let e1000e = MODELS.iter().find(|model| model.device_id == 0x10D3).unwrap();
+    assert_eq!(e1000.eeprom_request(2), 0x201);
+    assert_eq!(e1000e.eeprom_request(2), 0x9);
+}
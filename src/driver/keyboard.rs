@@ -4,54 +4,30 @@
 
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1}; // Keyboard structs
 use futures_util::stream::StreamExt; // needed for the next() function when reading scancodes from the queue
-use spin::Mutex; // Protect it with a mutex
 use x86_64::instructions::port::Port;
 use crate::{print, println, del_col, serial_println};
-use lazy_static::lazy_static;
 
 /// safe one time initialization of static structs
 use conquer_once::spin::OnceCell;
-/// Atomic queue that only needs an &self ref to pop and push
-use crossbeam_queue::ArrayQueue;
-use futures_util::task::AtomicWaker;
+/// Bounded async channel - the interrupt handler sends scancodes, the `print_keypresses` task receives them
+use crate::task::channel::{mpsc, TrySendError};
+use core::sync::atomic::{AtomicBool, Ordering};
+use super::{Device, DeviceMatch, Driver, ProbeError};
 
-/// Queue of scancodes to reduce interrupt time, as we don't want to run CPU intensive tasks during interrupt time
+/// Sending half of our scancode channel, to reduce interrupt time, as we don't want to run CPU intensive tasks during interrupt time
 /// 
-/// We will do this through async
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
-
-/// Atomic waker
-/// 
-/// Like any waker, except made for atomic things, such as our SCANCODE_QUEUE's ArrayQueue
-static WAKER: AtomicWaker = AtomicWaker::new();
-
-
-/// KeyboardDriver for PS/2
-/// 
-/// This struct only contains the port. It converts scancodes into text, and outputs it
-/// to the screen. The relevant PS/2 port is `0x60`
-/// 
-/// 
-/// This struct should only be called from the DriverHandler
-
-
-// Create a static KEYBOARD we can use to convert scancodes into ascii keys
-lazy_static! {
-    static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-        Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1,
-            HandleControl::Ignore)
-        );
-}
+/// We will do this through async. The receiving half lives in the [ScancodeStream](struct.ScancodeStream.html)
+static SCANCODE_SENDER: OnceCell<mpsc::IrqSender<u8>> = OnceCell::uninit();
 
 /// Called by the keyboard interrupt handler
 ///
 /// Must not block or allocate.
 pub(crate) fn add_scancode(scancode: u8) {
-    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if let Err(_) = queue.push(scancode) {
-            println!("WARNING: scancode queue full; dropping keyboard input");
-        } else {
-            WAKER.wake(); // wake up our waker, we have new input! we MUST do this after we add the new input to make sure we don't get a race condition
+    if let Ok(sender) = SCANCODE_SENDER.try_get() {
+        match sender.try_send(scancode) { // try_send wakes up the receiving task for us
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => println!("WARNING: scancode queue full; dropping keyboard input"),
+            Err(TrySendError::Closed(_)) => println!("WARNING: scancode stream dropped; ignoring keyboard input"),
         }
     } else {
         println!("WARNING: scancode queue uninitialized");
@@ -59,40 +35,62 @@ pub(crate) fn add_scancode(scancode: u8) {
 }
 
 
+/// Called by the keyboard interrupt handler. The scancode has to be read even if nobody is listening, or the
+/// controller won't send any more
+///
+/// Must not block or allocate.
+pub(crate) fn read_scancode() {
+    let scancode: u8 = unsafe { Port::new(0x60).read() }; // The byte we read from the PS/2 data port is the scancode
+    if KEYBOARD_DRIVER.bound.load(Ordering::Relaxed) {
+        add_scancode(scancode); // Add a scancode to our scancode queue
+    }
+}
+
+/// # KeyboardDriver
+///
+/// Driver for the keyboard on the PS/2 controller (the ISA `i8042` device). The interrupt handler reads
+/// scancodes from port `0x60`, and they're only passed on while the driver is bound
 pub struct KeyboardDriver{
-    port: Port<u8>,
+    bound: AtomicBool,
 }
 
-impl KeyboardDriver{
-    pub fn new() -> Self{
-        serial_println!("Initialized the keyboard driver");
-        Self{
-            port: Port::new(0x60) // Read IO port 0x60, which is the PS/2 controller port
-        }
+/// The keyboard driver. [driver::init](../fn.init.html) registers it
+pub static KEYBOARD_DRIVER: KeyboardDriver = KeyboardDriver{ bound: AtomicBool::new(false) };
+
+impl Driver for KeyboardDriver{
+    fn name(&self) -> &'static str {
+        "ps2-keyboard"
     }
 
+    fn match_table(&self) -> &'static [DeviceMatch] {
+        &[DeviceMatch::Isa("i8042")]
+    }
 
-    pub fn read_scancode(&mut self){
-        let mut port = &mut self.port; 
-        let scancode: u8 = unsafe { port.read() }; // The byte we read from the port is the scancode
+    fn probe(&self, _device: &Device) -> Result<(), ProbeError> {
+        serial_println!("Initialized the keyboard driver");
+        self.bound.store(true, Ordering::Relaxed);
+        Ok(())
+    }
 
-        add_scancode(scancode); // Add a scancode to our scancode queue
+    fn remove(&self, _device: &Device) {
+        self.bound.store(false, Ordering::Relaxed);
     }
 }
 
 /// This struct should only be called from this module.
 /// 
-/// This struct constructs our SCANCODE_QUEUE which we use for asynchrynous scancode polling from the interrupt handler
+/// This struct constructs our scancode channel which we use for asynchrynous scancode polling from the interrupt handler
 pub struct ScancodeStream {
-    _private: (),
+    receiver: mpsc::Receiver<u8>,
 }
 
 impl ScancodeStream {
-    /// Create a new scancode stream. This function also initializes the SCANCODE_QUEUE
+    /// Create a new scancode stream. This function also initializes the SCANCODE_SENDER
     pub fn new() -> Self {
-        SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(100))
+        let (sender, receiver) = mpsc::channel(100);
+        SCANCODE_SENDER.try_init_once(|| sender.irq_sender())
             .expect("ScancodeStream::new should only be called once");
-        ScancodeStream { _private: () }
+        ScancodeStream { receiver }
     }
 }
 
@@ -104,25 +102,8 @@ impl Stream for ScancodeStream {
     type Item = u8;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
-        // Get a reference to our queue
-        let queue = SCANCODE_QUEUE
-            .try_get()
-            .expect("scancode queue not initialized");
-
-        // fast path - we optimistically check there is already a scancode in our queue
-        if let Ok(scancode) = queue.pop() {
-            return Poll::Ready(Some(scancode));
-        }
-
-        // if not :( , we register a new waker - from the context, which will continue this function when the waker awakes.
-        WAKER.register(&cx.waker());
-        match queue.pop() {
-            Ok(scancode) => {
-                WAKER.take(); // Remove the waker from the context, we no longer need it
-                Poll::Ready(Some(scancode))
-            }
-            Err(crossbeam_queue::PopError) => Poll::Pending,
-        }
+        // The channel does the fast path + waker registration for us
+        Pin::new(&mut self.get_mut().receiver).poll_next(cx)
     }
 }
 
@@ -0,0 +1,781 @@
+//! # NVMe
+//!
+//! Driver for NVM Express SSDs (like QEMU's `nvme` device). Commands go through queue pairs in memory: we
+//! write a 64 byte command to a submission queue and ring its doorbell, and the controller writes a 16 byte
+//! entry to the paired completion queue when it is done, then interrupts. An entry is new if its phase bit
+//! matches what we expect - the controller flips the bit it writes each time it wraps around the queue.
+//!
+//! Queue 0 is the admin queue, for setting the controller up (Identify, creating queues). We then create one
+//! I/O queue pair for reads, writes and flushes. Each command is tagged with the slot it was given, so
+//! completions can come back in any order.
+//!
+//! Data is described to the controller with PRPs (physical region pages): the first page, then either the
+//! second page or a list of the rest. Transfers go through a DMA bounce buffer, so every page after the first
+//! is whole. Each namespace is registered as a [block device](../../block/index.html) called `nvme0n1`...
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use tinypci::PciFullClass;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::block::{self, BlockDevice, BlockError, BlockFuture};
+use crate::memory::{self, dma::DmaBuffer};
+use crate::sync::IrqSpinlock;
+use crate::task::block_on;
+use crate::{serial_println, smp};
+use super::pci::{Bar, Msi, MsiX, PciDevice};
+use super::{Device, DeviceId, DeviceMatch, Driver, ProbeError};
+
+/* Controller registers (offsets from BAR 0) */
+const REG_CAP: u64 = 0x00;
+const REG_VS: u64 = 0x08;
+const REG_CC: u64 = 0x14;
+const REG_CSTS: u64 = 0x1C;
+const REG_AQA: u64 = 0x24;
+const REG_ASQ: u64 = 0x28;
+const REG_ACQ: u64 = 0x30;
+/// The first doorbell. They are `4 << CAP.DSTRD` bytes apart - each queue's submission tail, then its
+/// completion head
+const DOORBELLS: u64 = 0x1000;
+
+/* Capabilities */
+/// Bits 0-15 - the most entries a queue can have, minus one
+const CAP_MQES_MASK: u64 = 0xFFFF;
+/// Bits 24-31 - how long enabling or disabling can take, in 500ms units
+const CAP_TO_SHIFT: u64 = 24;
+/// Bits 32-35 - the doorbell stride
+const CAP_DSTRD_SHIFT: u64 = 32;
+/// Bits 48-51 - the smallest page size, as 4 KiB << n
+const CAP_MPSMIN_SHIFT: u64 = 48;
+
+/* Controller configuration */
+const CC_EN: u32 = 1 << 0;
+/// log2 of the submission entry size (64 bytes), in bits 16-19
+const CC_IOSQES: u32 = 6 << 16;
+/// log2 of the completion entry size (16 bytes), in bits 20-23
+const CC_IOCQES: u32 = 4 << 20;
+
+/* Controller status */
+const CSTS_RDY: u32 = 1 << 0;
+const CSTS_CFS: u32 = 1 << 1;
+
+/* Admin commands */
+const ADMIN_CREATE_IO_SQ: u8 = 0x01;
+const ADMIN_CREATE_IO_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const ADMIN_SET_FEATURES: u8 = 0x09;
+
+/* Identify CNS values - what to identify */
+const IDENTIFY_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CONTROLLER: u32 = 0x01;
+const IDENTIFY_ACTIVE_NAMESPACES: u32 = 0x02;
+
+/// Set Features feature ID for the number of I/O queues
+const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+
+/* I/O commands */
+const IO_FLUSH: u8 = 0x00;
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+const PAGE_SIZE: usize = 4096;
+const SUBMISSION_ENTRY_SIZE: usize = 64;
+const COMPLETION_ENTRY_SIZE: usize = 16;
+/// How many entries our queues have. At most 64, as slots are bits of a `u64`
+const QUEUE_DEPTH: u16 = 64;
+/// The most pages one command moves (the size of its bounce buffer, 128 KiB), unless the controller says less
+const MAX_PAGES_PER_COMMAND: usize = 32;
+/// How often to check the status register while waiting for the controller
+const POLL_DELAY_US: u64 = 1000;
+
+/// The I/O queue we create
+const IO_QUEUE: u16 = 1;
+
+fn read_register(base: VirtAddr, offset: u64) -> u32 {
+    unsafe { ptr::read_volatile((base + offset).as_ptr::<u32>()) }
+}
+
+fn write_register(base: VirtAddr, offset: u64, value: u32) {
+    unsafe { ptr::write_volatile((base + offset).as_mut_ptr::<u32>(), value) }
+}
+
+/// 64 bit registers are written a half at a time, low half first
+fn write_register_u64(base: VirtAddr, offset: u64, value: u64) {
+    write_register(base, offset, value as u32);
+    write_register(base, offset + 4, (value >> 32) as u32);
+}
+
+/// # Command
+///
+/// A submission queue entry, before it is given a command ID
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Command {
+    pub opcode: u8,
+    pub namespace: u32,
+    pub prp1: u64,
+    pub prp2: u64,
+    /// Command dwords 10 to 15 - what they mean depends on the command
+    pub cdw: [u32; 6],
+}
+
+impl Command {
+    /// The 16 dwords of the entry, tagged with `id`
+    pub fn encode(&self, id: u16) -> [u32; 16] {
+        let mut entry = [0u32; 16];
+        entry[0] = self.opcode as u32 | (id as u32) << 16;
+        entry[1] = self.namespace;
+        entry[6] = self.prp1 as u32;
+        entry[7] = (self.prp1 >> 32) as u32;
+        entry[8] = self.prp2 as u32;
+        entry[9] = (self.prp2 >> 32) as u32;
+        entry[10..16].copy_from_slice(&self.cdw);
+        entry
+    }
+
+    /// A read or write of `count` blocks at `lba`
+    fn io(opcode: u8, namespace: u32, lba: u64, count: usize) -> Self {
+        Command {
+            opcode,
+            namespace,
+            cdw: [lba as u32, (lba >> 32) as u32, count as u32 - 1, 0, 0, 0], // the count is zero based
+            ..Default::default()
+        }
+    }
+}
+
+/// # Prps
+///
+/// Where a transfer's data is, as the controller wants it. If the data covers more than two pages, `prp2`
+/// points to a list of the rest, which must hold `list`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prps {
+    pub prp1: u64,
+    pub prp2: u64,
+    pub list: Vec<u64>,
+}
+
+/// # build_prps
+///
+/// The PRPs for `length` bytes of physically contiguous memory at `address`. `list_address` is where the list
+/// will be put, if one is needed. Only the first entry can start part way through a page
+pub fn build_prps(address: u64, length: usize, list_address: u64) -> Prps {
+    let first_page = PAGE_SIZE - (address as usize % PAGE_SIZE);
+    let mut pages = Vec::new();
+    let mut next = address + first_page as u64;
+    while ((next - address) as usize) < length {
+        pages.push(next);
+        next += PAGE_SIZE as u64;
+    }
+    match pages.len() {
+        0 => Prps { prp1: address, prp2: 0, list: Vec::new() },
+        1 => Prps { prp1: address, prp2: pages[0], list: Vec::new() },
+        _ => Prps { prp1: address, prp2: list_address, list: pages },
+    }
+}
+
+/// ASCII fields in Identify data are padded with spaces
+fn identify_string(bytes: &[u8]) -> String {
+    String::from(String::from_utf8_lossy(bytes).trim())
+}
+
+/// # ControllerInfo
+///
+/// The parts of Identify Controller we use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControllerInfo {
+    pub serial: String,
+    pub model: String,
+    /// The most pages a command can move, if the controller has a limit
+    pub max_transfer_pages: Option<usize>,
+    /// How many namespace IDs there are
+    pub namespaces: u32,
+}
+
+impl ControllerInfo {
+    pub fn parse(data: &[u8]) -> Self {
+        let mdts = data[77];
+        ControllerInfo {
+            serial: identify_string(&data[4..24]),
+            model: identify_string(&data[24..64]),
+            max_transfer_pages: if mdts == 0 { None } else { Some(1 << mdts) },
+            namespaces: u32::from_le_bytes([data[516], data[517], data[518], data[519]]),
+        }
+    }
+}
+
+/// # NamespaceInfo
+///
+/// The parts of Identify Namespace we use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NamespaceInfo {
+    /// The size, in blocks
+    pub blocks: u64,
+    pub block_size: usize,
+}
+
+impl NamespaceInfo {
+    /// `None` for an inactive namespace
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let mut size = [0u8; 8];
+        size.copy_from_slice(&data[0..8]);
+        let blocks = u64::from_le_bytes(size);
+        // The formatted LBA format picks an entry from the table at 128. Bits 16-23 are log2 of the block size
+        let format = 128 + (data[26] & 0xF) as usize * 4;
+        let block_size = 1usize << data[format + 2];
+        if blocks == 0 {
+            None
+        } else {
+            Some(NamespaceInfo { blocks, block_size })
+        }
+    }
+}
+
+/// Who owns the queue's slots, where the queues are, and how commands went. Shared with the interrupt handler
+struct QueueState {
+    /// Where we write the next submission
+    tail: u16,
+    /// Where we read the next completion
+    head: u16,
+    /// The phase bit new completions have
+    phase: bool,
+    /// Slots (command IDs) a task has claimed
+    claimed: u64,
+    /// Slots whose command finished, waiting for their task to notice
+    done: u64,
+    /// Each slot's completion status (without the phase bit) and dword 0
+    status: [u16; QUEUE_DEPTH as usize],
+    result: [u32; QUEUE_DEPTH as usize],
+}
+
+/// # Queue
+///
+/// A submission and completion queue pair
+struct Queue {
+    id: u16,
+    submissions: DmaBuffer,
+    completions: DmaBuffer,
+    submission_doorbell: VirtAddr,
+    completion_doorbell: VirtAddr,
+    state: IrqSpinlock<QueueState>,
+    /// The task waiting for each slot's command
+    wakers: Vec<AtomicWaker>,
+    /// Tasks waiting for a slot to be free
+    slot_waiters: Mutex<Vec<Waker>>,
+    /// Whether the queue's interrupt reaches us. Until it does (or if it never will) commands are polled
+    interrupts: AtomicBool,
+}
+
+impl Queue {
+    fn new(id: u16, registers: VirtAddr, doorbell_stride: u64) -> Option<Self> {
+        Some(Queue {
+            id,
+            submissions: DmaBuffer::new(QUEUE_DEPTH as usize * SUBMISSION_ENTRY_SIZE)?,
+            completions: DmaBuffer::new(QUEUE_DEPTH as usize * COMPLETION_ENTRY_SIZE)?,
+            submission_doorbell: registers + DOORBELLS + (2 * id as u64) * doorbell_stride,
+            completion_doorbell: registers + DOORBELLS + (2 * id as u64 + 1) * doorbell_stride,
+            state: IrqSpinlock::new(QueueState {
+                tail: 0,
+                head: 0,
+                phase: true,
+                claimed: 0,
+                done: 0,
+                status: [0; QUEUE_DEPTH as usize],
+                result: [0; QUEUE_DEPTH as usize],
+            }),
+            wakers: (0..QUEUE_DEPTH).map(|_| AtomicWaker::new()).collect(),
+            slot_waiters: Mutex::new(Vec::new()),
+            interrupts: AtomicBool::new(false),
+        })
+    }
+
+    /// Take new entries off the completion queue, and wake the tasks waiting for them. Called from the interrupt
+    /// handler, and by commands being polled
+    fn process(&self) {
+        let mut completed = 0u64;
+        {
+            let mut state = self.state.lock();
+            loop {
+                let entry = state.head as usize * COMPLETION_ENTRY_SIZE;
+                let dword3 = unsafe { ptr::read_volatile(self.completions.ptr::<u32>(entry + 12)) };
+                if (dword3 & (1 << 16) != 0) != state.phase {
+                    break;
+                }
+                let id = (dword3 & 0xFFFF) as usize;
+                if id < QUEUE_DEPTH as usize {
+                    state.status[id] = (dword3 >> 17) as u16;
+                    state.result[id] = unsafe { ptr::read_volatile(self.completions.ptr::<u32>(entry)) };
+                    state.done |= 1 << id;
+                    completed |= 1 << id;
+                }
+                state.head += 1;
+                if state.head == QUEUE_DEPTH {
+                    state.head = 0;
+                    state.phase = !state.phase;
+                }
+            }
+            if completed != 0 {
+                unsafe { ptr::write_volatile(self.completion_doorbell.as_mut_ptr::<u32>(), state.head as u32) };
+            }
+        }
+        for slot in 0..QUEUE_DEPTH as usize {
+            if completed & (1 << slot) != 0 {
+                self.wakers[slot].wake();
+            }
+        }
+    }
+
+    /// Run `command`, returning dword 0 of its completion
+    async fn submit(&self, command: Command) -> Result<u32, BlockError> {
+        let slot = SlotClaim { queue: self }.await;
+        {
+            let mut state = self.state.lock();
+            let entry = state.tail as usize * SUBMISSION_ENTRY_SIZE;
+            for (index, dword) in command.encode(slot as u16).iter().enumerate() {
+                unsafe { ptr::write_volatile(self.submissions.ptr::<u32>(entry + index * 4), *dword) };
+            }
+            // We never have more commands out than the queue has entries, so it can't be full
+            state.tail = (state.tail + 1) % QUEUE_DEPTH;
+            unsafe { ptr::write_volatile(self.submission_doorbell.as_mut_ptr::<u32>(), state.tail as u32) };
+        }
+        let result = Completion { queue: self, slot }.await;
+
+        self.state.lock().claimed &= !(1 << slot);
+        let waiters = core::mem::take(&mut *self.slot_waiters.lock());
+        for waker in waiters {
+            waker.wake();
+        }
+        result
+    }
+}
+
+/// Waits for a free slot. There is one fewer than the queue has entries - a full queue looks empty
+struct SlotClaim<'a> {
+    queue: &'a Queue,
+}
+
+impl<'a> SlotClaim<'a> {
+    fn try_claim(&self) -> Option<usize> {
+        let mut state = self.queue.state.lock();
+        let slot = (!state.claimed).trailing_zeros() as usize;
+        if slot < QUEUE_DEPTH as usize - 1 {
+            state.claimed |= 1 << slot;
+            Some(slot)
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a> Future for SlotClaim<'a> {
+    type Output = usize;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<usize> {
+        if let Some(slot) = self.try_claim() {
+            return Poll::Ready(slot);
+        }
+        self.queue.slot_waiters.lock().push(cx.waker().clone());
+        // A slot may have been released between our try and registering
+        match self.try_claim() {
+            Some(slot) => Poll::Ready(slot),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Waits for the command in `slot` to complete
+struct Completion<'a> {
+    queue: &'a Queue,
+    slot: usize,
+}
+
+impl<'a> Future for Completion<'a> {
+    type Output = Result<u32, BlockError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<u32, BlockError>> {
+        self.queue.wakers[self.slot].register(cx.waker());
+        // The command may have completed before the interrupt was enabled, or before we registered
+        self.queue.process();
+        let mut state = self.queue.state.lock();
+        let bit = 1 << self.slot;
+        if state.done & bit == 0 {
+            drop(state);
+            if !self.queue.interrupts.load(Ordering::Acquire) {
+                cx.waker().wake_by_ref(); // nothing will wake us, so ask to be polled again
+            }
+            return Poll::Pending;
+        }
+        state.done &= !bit;
+        // Bits 0-7 are the status code, 8-10 the status code type. All zero is success
+        Poll::Ready(match state.status[self.slot] & 0x7FF {
+            0 => Ok(state.result[self.slot]),
+            _ => Err(BlockError::DeviceError("NVMe command failed")),
+        })
+    }
+}
+
+/// # NvmeController
+///
+/// A controller, with its admin and I/O queues
+struct NvmeController {
+    registers: VirtAddr,
+    admin: Queue,
+    io: Queue,
+    info: ControllerInfo,
+    /// MSI-X (or MSI) - kept so `remove` can turn it off
+    msix: Mutex<Option<MsiX>>,
+    msi: Mutex<Option<Msi>>,
+}
+
+impl NvmeController {
+    /// The most bytes one I/O command moves
+    fn max_transfer(&self) -> usize {
+        self.info.max_transfer_pages.unwrap_or(MAX_PAGES_PER_COMMAND).min(MAX_PAGES_PER_COMMAND) * PAGE_SIZE
+    }
+}
+
+/// The interrupt handler, for both queues. `context` points to the `NvmeController`
+fn handle_interrupt(context: usize) {
+    let controller = unsafe { &*(context as *const NvmeController) };
+    controller.admin.process();
+    controller.io.process();
+}
+
+/// Identify something into a fresh page, returning the page
+async fn identify(admin: &Queue, cns: u32, namespace: u32) -> Result<DmaBuffer, BlockError> {
+    let buffer = DmaBuffer::new(PAGE_SIZE).ok_or(BlockError::DeviceError("out of DMA memory"))?;
+    let command = Command {
+        opcode: ADMIN_IDENTIFY,
+        namespace,
+        prp1: buffer.phys_addr().as_u64(),
+        cdw: [cns, 0, 0, 0, 0, 0],
+        ..Default::default()
+    };
+    admin.submit(command).await?;
+    Ok(buffer)
+}
+
+/// # NvmeNamespace
+///
+/// A namespace of a controller, as a block device
+pub struct NvmeNamespace {
+    name: String,
+    controller: Arc<NvmeController>,
+    id: u32,
+    info: NamespaceInfo,
+}
+
+impl NvmeNamespace {
+    /// Read or write `length` bytes at `lba`, through `buffer`
+    async fn transfer(&self, opcode: u8, lba: u64, buffer: &DmaBuffer, length: usize) -> Result<(), BlockError> {
+        let mut command = Command::io(opcode, self.id, lba, length / self.info.block_size);
+        // The bounce buffer is page aligned, so a list is needed past two pages. It has to outlive the command
+        let list = match length > 2 * PAGE_SIZE {
+            true => Some(DmaBuffer::new(PAGE_SIZE).ok_or(BlockError::DeviceError("out of DMA memory"))?),
+            false => None,
+        };
+        let list_address = list.as_ref().map_or(0, |list| list.phys_addr().as_u64());
+        let prps = build_prps(buffer.phys_addr().as_u64(), length, list_address);
+        if let Some(list) = list.as_ref() {
+            for (index, page) in prps.list.iter().enumerate() {
+                unsafe { ptr::write_volatile(list.ptr::<u64>(index * 8), *page) };
+            }
+        }
+        command.prp1 = prps.prp1;
+        command.prp2 = prps.prp2;
+        self.controller.io.submit(command).await.map(|_| ())
+    }
+}
+
+impl BlockDevice for NvmeNamespace {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        self.info.block_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.info.blocks
+    }
+
+    fn read<'a>(&'a self, sector: u64, buffer: &'a mut [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            block::check_request(self, sector, buffer.len())?;
+            let chunk_size = self.controller.max_transfer();
+            let bounce = DmaBuffer::new(buffer.len().min(chunk_size)).ok_or(BlockError::DeviceError("out of DMA memory"))?;
+            for (index, chunk) in buffer.chunks_mut(chunk_size).enumerate() {
+                let lba = sector + (index * chunk_size / self.info.block_size) as u64;
+                self.transfer(IO_READ, lba, &bounce, chunk.len()).await?;
+                chunk.copy_from_slice(&bounce.as_slice()[..chunk.len()]);
+            }
+            Ok(())
+        })
+    }
+
+    fn write<'a>(&'a self, sector: u64, buffer: &'a [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            block::check_request(self, sector, buffer.len())?;
+            let chunk_size = self.controller.max_transfer();
+            let mut bounce =
+                DmaBuffer::new(buffer.len().min(chunk_size)).ok_or(BlockError::DeviceError("out of DMA memory"))?;
+            for (index, chunk) in buffer.chunks(chunk_size).enumerate() {
+                let lba = sector + (index * chunk_size / self.info.block_size) as u64;
+                bounce.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+                self.transfer(IO_WRITE, lba, &bounce, chunk.len()).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> BlockFuture<'_, ()> {
+        Box::pin(async move {
+            let command = Command { opcode: IO_FLUSH, namespace: self.id, ..Default::default() };
+            self.controller.io.submit(command).await.map(|_| ())
+        })
+    }
+}
+
+/// # NvmeDriver
+///
+/// Binds to NVMe controllers, and registers a block device for each of their namespaces
+pub struct NvmeDriver {
+    /// Each controller, and the names of its namespaces' block devices
+    controllers: Mutex<Vec<(DeviceId, Arc<NvmeController>, Vec<String>)>>,
+    next_controller: AtomicUsize,
+}
+
+/// The NVMe driver. [driver::init](../fn.init.html) registers it
+pub static NVME_DRIVER: NvmeDriver = NvmeDriver { controllers: Mutex::new(Vec::new()), next_controller: AtomicUsize::new(0) };
+
+impl NvmeDriver {
+    /// Wait for the ready bit to be `ready`, for up to the controller's timeout
+    fn wait_ready(registers: VirtAddr, ready: bool, timeout_ms: u64) -> Result<(), ProbeError> {
+        for _ in 0..timeout_ms.max(1) {
+            let status = read_register(registers, REG_CSTS);
+            if status & CSTS_CFS != 0 {
+                return Err(ProbeError::DeviceError("controller fatal status"));
+            }
+            if (status & CSTS_RDY != 0) == ready {
+                return Ok(());
+            }
+            smp::delay_us(POLL_DELAY_US);
+        }
+        Err(ProbeError::DeviceError("controller didn't become ready"))
+    }
+
+    /// Disable the controller, set up the admin queue, and enable it again
+    fn reset(registers: VirtAddr, admin: &Queue, capabilities: u64) -> Result<(), ProbeError> {
+        let timeout_ms = ((capabilities >> CAP_TO_SHIFT) & 0xFF) * 500;
+        write_register(registers, REG_CC, read_register(registers, REG_CC) & !CC_EN);
+        Self::wait_ready(registers, false, timeout_ms)?;
+
+        let size = (QUEUE_DEPTH - 1) as u32;
+        write_register(registers, REG_AQA, size << 16 | size);
+        write_register_u64(registers, REG_ASQ, admin.submissions.phys_addr().as_u64());
+        write_register_u64(registers, REG_ACQ, admin.completions.phys_addr().as_u64());
+        // The NVM command set and 4 KiB pages are both zero
+        write_register(registers, REG_CC, CC_IOSQES | CC_IOCQES | CC_EN);
+        Self::wait_ready(registers, true, timeout_ms)
+    }
+
+    /// Ask for an I/O queue pair, and create it. It shares interrupt vector 0 with the admin queue
+    async fn create_io_queue(admin: &Queue, io: &Queue) -> Result<(), BlockError> {
+        let size = (QUEUE_DEPTH - 1) as u32;
+        let queues = Command { opcode: ADMIN_SET_FEATURES, cdw: [FEATURE_NUMBER_OF_QUEUES, 0, 0, 0, 0, 0], ..Default::default() };
+        admin.submit(queues).await?;
+
+        // Physically contiguous (bit 0), interrupts enabled (bit 1)
+        let completion_queue = Command {
+            opcode: ADMIN_CREATE_IO_CQ,
+            prp1: io.completions.phys_addr().as_u64(),
+            cdw: [size << 16 | io.id as u32, 0b11, 0, 0, 0, 0],
+            ..Default::default()
+        };
+        admin.submit(completion_queue).await?;
+        // Physically contiguous, and completing to the queue we just made
+        let submission_queue = Command {
+            opcode: ADMIN_CREATE_IO_SQ,
+            prp1: io.submissions.phys_addr().as_u64(),
+            cdw: [size << 16 | io.id as u32, (io.id as u32) << 16 | 0b1, 0, 0, 0, 0],
+            ..Default::default()
+        };
+        admin.submit(submission_queue).await.map(|_| ())
+    }
+
+    /// Route the queues' interrupt (vector 0) to `handle_interrupt`, with MSI-X or MSI. Without either, the
+    /// queues stay polled
+    fn enable_interrupts(function: PciDevice, controller: &Arc<NvmeController>) {
+        let context = Arc::as_ptr(controller) as usize;
+        if let Ok(mut msix) = function.msix() {
+            if msix.enable_entry(0, handle_interrupt, context).is_err() {
+                msix.disable();
+                return;
+            }
+            *controller.msix.lock() = Some(msix);
+        } else if let Ok(mut msi) = function.msi() {
+            if msi.enable(handle_interrupt, context).is_err() {
+                return;
+            }
+            *controller.msi.lock() = Some(msi);
+        } else {
+            serial_println!("[LOG] nvme: no MSI-X or MSI, polling instead");
+            return;
+        }
+        controller.admin.interrupts.store(true, Ordering::Release);
+        controller.io.interrupts.store(true, Ordering::Release);
+    }
+}
+
+impl Driver for NvmeDriver {
+    fn name(&self) -> &'static str {
+        "nvme"
+    }
+
+    fn match_table(&self) -> &'static [DeviceMatch] {
+        // The class code entry catches controllers `brute_force_scan` missed
+        &[DeviceMatch::PciClass(PciFullClass::MassStorage_NVM), DeviceMatch::PciClassCode { class: 0x01, subclass: 0x08 }]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let function = device.pci_function().ok_or(ProbeError::Unsupported)?;
+        if function.prog_if != 0x02 {
+            return Err(ProbeError::Unsupported); // 0x02 is NVM Express. 0x01 is the older NVMHCI
+        }
+        let registers = match function.bar(0) {
+            Some(Bar::Memory { address, size, .. }) => {
+                memory::map_device(PhysAddr::new(address), size).map_err(|_| ProbeError::OutOfResources)?
+            }
+            _ => return Err(ProbeError::DeviceError("no register BAR")),
+        };
+        function.enable_memory_space();
+        function.enable_bus_mastering();
+
+        let capabilities = read_register(registers, REG_CAP) as u64 | (read_register(registers, REG_CAP + 4) as u64) << 32;
+        if (capabilities >> CAP_MPSMIN_SHIFT) & 0xF != 0 {
+            return Err(ProbeError::Unsupported); // 4 KiB pages are too small for it
+        }
+        if (capabilities & CAP_MQES_MASK) + 1 < QUEUE_DEPTH as u64 {
+            return Err(ProbeError::Unsupported);
+        }
+        let doorbell_stride = 4 << ((capabilities >> CAP_DSTRD_SHIFT) & 0xF);
+
+        let admin = Queue::new(0, registers, doorbell_stride).ok_or(ProbeError::OutOfResources)?;
+        let io = Queue::new(IO_QUEUE, registers, doorbell_stride).ok_or(ProbeError::OutOfResources)?;
+        Self::reset(registers, &admin, capabilities)?;
+
+        // Interrupts aren't set up yet, so these are polled
+        let info = block_on(identify(&admin, IDENTIFY_CONTROLLER, 0))
+            .map(|data| ControllerInfo::parse(data.as_slice()))
+            .map_err(|_| ProbeError::DeviceError("Identify Controller failed"))?;
+        block_on(Self::create_io_queue(&admin, &io)).map_err(|_| ProbeError::DeviceError("couldn't create I/O queue"))?;
+
+        let version = read_register(registers, REG_VS);
+        let number = self.next_controller.fetch_add(1, Ordering::Relaxed);
+        serial_println!(
+            "[LOG] nvme{}: \"{}\", NVMe {}.{}, {} namespaces",
+            number,
+            info.model,
+            version >> 16,
+            (version >> 8) & 0xFF,
+            info.namespaces
+        );
+        let controller = Arc::new(NvmeController {
+            registers,
+            admin,
+            io,
+            info,
+            msix: Mutex::new(None),
+            msi: Mutex::new(None),
+        });
+        // The controller stays alive in `controllers` until `remove` has disabled the interrupts
+        Self::enable_interrupts(function, &controller);
+
+        // The active namespace list is a page of IDs, ending at the first zero
+        let list = block_on(identify(&controller.admin, IDENTIFY_ACTIVE_NAMESPACES, 0))
+            .map_err(|_| ProbeError::DeviceError("couldn't list namespaces"))?;
+        let ids: Vec<u32> = list
+            .as_slice()
+            .chunks_exact(4)
+            .map(|id| u32::from_le_bytes([id[0], id[1], id[2], id[3]]))
+            .take_while(|&id| id != 0)
+            .collect();
+        let mut names = Vec::new();
+        for id in ids {
+            let data = match block_on(identify(&controller.admin, IDENTIFY_NAMESPACE, id)) {
+                Ok(data) => data,
+                Err(_) => continue,
+            };
+            if let Some(info) = NamespaceInfo::parse(data.as_slice()) {
+                let name = format!("nvme{}n{}", number, id);
+                names.push(name.clone());
+                block::register(Arc::new(NvmeNamespace { name, controller: controller.clone(), id, info }));
+            }
+        }
+        self.controllers.lock().push((device.id, controller, names));
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        let mut controllers = self.controllers.lock();
+        if let Some(index) = controllers.iter().position(|(id, _, _)| *id == device.id) {
+            let (_, controller, names) = controllers.remove(index);
+            for name in names {
+                block::unregister(&name);
+            }
+            if let Some(mut msix) = controller.msix.lock().take() {
+                msix.disable();
+            }
+            if let Some(mut msi) = controller.msi.lock().take() {
+                msi.disable();
+            }
+            write_register(controller.registers, REG_CC, read_register(controller.registers, REG_CC) & !CC_EN);
+        }
+    }
+}
+
+/* Testing */
+
+#[test_case]
+fn nvme_command_encoding() {
+    let command = Command::io(IO_READ, 1, 0x1_0000_0002, 8);
+    let entry = command.encode(5);
+    assert_eq!(entry[0], IO_READ as u32 | 5 << 16);
+    assert_eq!(entry[1], 1);
+    assert_eq!(&entry[10..13], &[2, 1, 7]);
+}
+
+#[test_case]
+fn nvme_prps() {
+    // One page, or less
+    assert_eq!(build_prps(0x10_0000, 4096, 0x9000), Prps { prp1: 0x10_0000, prp2: 0, list: Vec::new() });
+    // Two pages - the second goes straight in prp2
+    assert_eq!(build_prps(0x10_0000, 8192, 0x9000), Prps { prp1: 0x10_0000, prp2: 0x10_1000, list: Vec::new() });
+    // More - prp2 points to a list
+    let prps = build_prps(0x10_0000, 4 * 4096, 0x9000);
+    assert_eq!(prps.prp2, 0x9000);
+    assert_eq!(prps.list, [0x10_1000, 0x10_2000, 0x10_3000]);
+    // A start part way through a page - the first entry is shorter, the rest are whole pages
+    let prps = build_prps(0x10_0800, 4096, 0x9000);
+    assert_eq!((prps.prp1, prps.prp2), (0x10_0800, 0x10_1000));
+}
+
+#[test_case]
+fn nvme_identify_namespace() {
+    let mut data = alloc::vec![0u8; 4096];
+    data[0..8].copy_from_slice(&0x2_0000u64.to_le_bytes());
+    data[26] = 1; // LBA format 1
+    data[128 + 4 + 2] = 12; // 4 KiB blocks
+    assert_eq!(NamespaceInfo::parse(&data), Some(NamespaceInfo { blocks: 0x2_0000, block_size: 4096 }));
+    data[0..8].copy_from_slice(&[0; 8]);
+    assert_eq!(NamespaceInfo::parse(&data), None);
+}
@@ -1,19 +1,41 @@
+pub mod config; // Reading and writing config space
+pub mod bar; // Base Address Registers - where a device's registers are
+pub mod capability; // Walking the capability list
+pub mod ecam; // PCI Express memory mapped config space
+pub mod msi; // Message signalled interrupts (MSI and MSI-X)
+
+pub use config::PciAddress;
+pub use bar::Bar;
+pub use capability::{Capabilities, Capability, ExtendedCapabilities, ExtendedCapability};
+pub use msi::{Msi, MsiError, MsiX};
+
+use alloc::string::ToString;
 use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::fmt;
 use tinypci::{brute_force_scan, PciFullClass, PciDeviceInfo};
 use crate::{print, println, del_col, serial_println};
+use super::{registry, DeviceKind};
+
+/// What `add_devices` found. Set once, at boot
+static SCANNER: OnceCell<PciScanner> = OnceCell::uninit();
 
 
 /// Scans for PCI devices, and stores the vector of PCI devices
 pub struct PciScanner{
     pub devices: Vec::<PciDeviceInfo>,
+    /// Every function we found by walking the buses ourselves - these are what drivers talk to
+    pub functions: Vec::<PciDevice>,
 }
 
 impl PciScanner{
     /// Create a new PciScanner struct. Will scan upon creation
     pub fn new() -> Self{
-        serial_println!("Scanned PCI devices!");
+        let functions = enumerate();
+        serial_println!("Scanned PCI devices! {} functions", functions.len());
         Self{
-            devices: brute_force_scan()
+            devices: brute_force_scan(),
+            functions,
         }
     }
 
@@ -28,4 +50,296 @@ impl PciScanner{
 
         scanned_devices
     }
-}
\ No newline at end of file
+
+    /// The function behind a device `brute_force_scan` found, so we can talk to it
+    pub fn function_for(&self, info: &PciDeviceInfo) -> Option<&PciDevice>{
+        self.functions.iter().find(|function| {
+            function.address.bus == info.bus
+                && function.address.device == info.device
+                && function.vendor_id == info.vendor_id
+                && function.device_id == info.device_id
+        })
+    }
+
+    /// What `brute_force_scan` called a function we found, if it saw it
+    pub fn full_class_of(&self, function: &PciDevice) -> Option<PciFullClass>{
+        self.devices.iter().find(|info| {
+            info.bus == function.address.bus
+                && info.device == function.address.device
+                && info.vendor_id == function.vendor_id
+                && info.device_id == function.device_id
+        }).map(|info| info.full_class)
+    }
+
+    /// Every function with this vendor and device ID
+    pub fn find(&self, vendor_id: u16, device_id: u16) -> impl Iterator<Item = &PciDevice>{
+        self.functions.iter().filter(move |function| function.vendor_id == vendor_id && function.device_id == device_id)
+    }
+
+    /// Every function with this class and subclass
+    pub fn find_class(&self, class: u8, subclass: u8) -> impl Iterator<Item = &PciDevice>{
+        self.functions.iter().filter(move |function| function.class == class && function.subclass == subclass)
+    }
+}
+
+/// # PciDevice
+///
+/// A PCI function, and the parts of its config space header every driver needs. Everything else is
+/// read through the `read_*`/`write_*` methods.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+}
+
+impl PciDevice {
+    /// Read the function at `address`. `None` if there is nothing there
+    pub fn new(address: PciAddress) -> Option<Self> {
+        let vendor_id = config::read_u16(address, config::VENDOR_ID);
+        if vendor_id == 0xFFFF {
+            return None;
+        }
+        let class = config::read_u32(address, config::REVISION_ID);
+        Some(PciDevice {
+            address,
+            vendor_id,
+            device_id: config::read_u16(address, config::DEVICE_ID),
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type: config::read_u8(address, config::HEADER_TYPE),
+        })
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        config::read_u8(self.address, offset)
+    }
+
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        config::read_u16(self.address, offset)
+    }
+
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        config::read_u32(self.address, offset)
+    }
+
+    pub fn write_u8(&self, offset: u16, value: u8) {
+        config::write_u8(self.address, offset, value)
+    }
+
+    pub fn write_u16(&self, offset: u16, value: u16) {
+        config::write_u16(self.address, offset, value)
+    }
+
+    pub fn write_u32(&self, offset: u16, value: u32) {
+        config::write_u32(self.address, offset, value)
+    }
+
+    /// The header type, without the multifunction bit
+    pub fn header_layout(&self) -> u8 {
+        self.header_type & !config::HEADER_TYPE_MULTIFUNCTION
+    }
+
+    pub fn is_bridge(&self) -> bool {
+        self.header_layout() == config::HEADER_TYPE_PCI_BRIDGE
+    }
+
+    pub fn command(&self) -> u16 {
+        self.read_u16(config::COMMAND)
+    }
+
+    pub fn set_command(&self, command: u16) {
+        self.write_u16(config::COMMAND, command)
+    }
+
+    /// Set bits in the command register
+    fn enable(&self, bits: u16) {
+        let command = self.command();
+        if command & bits != bits {
+            self.set_command(command | bits);
+        }
+    }
+
+    /// Let the device do DMA. Needed before it can touch memory on its own (descriptor rings and so on)
+    pub fn enable_bus_mastering(&self) {
+        self.enable(config::COMMAND_BUS_MASTER);
+    }
+
+    /// Make the device respond to its memory BARs
+    pub fn enable_memory_space(&self) {
+        self.enable(config::COMMAND_MEMORY_SPACE);
+    }
+
+    /// Make the device respond to its I/O BARs
+    pub fn enable_io_space(&self) {
+        self.enable(config::COMMAND_IO_SPACE);
+    }
+
+    /// Stop (or allow) the device asserting its legacy INTx line - turn it off once MSI is set up
+    pub fn set_interrupt_disable(&self, disable: bool) {
+        let command = self.command();
+        if disable {
+            self.set_command(command | config::COMMAND_INTERRUPT_DISABLE);
+        } else {
+            self.set_command(command & !config::COMMAND_INTERRUPT_DISABLE);
+        }
+    }
+
+    /// Read and size BAR `index`. `None` if it isn't implemented (or is the upper half of a 64 bit BAR)
+    pub fn bar(&self, index: usize) -> Option<Bar> {
+        bar::read(self.address, self.header_type, index)
+    }
+
+    /// Every implemented BAR, with its index
+    pub fn bars(&self) -> Vec<(usize, Bar)> {
+        let mut bars = Vec::new();
+        let mut index = 0;
+        while index < bar::bar_count(self.header_type) {
+            match self.bar(index) {
+                Some(bar) => {
+                    bars.push((index, bar));
+                    index += bar.slots();
+                }
+                None => index += 1,
+            }
+        }
+        bars
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::new(self.address, self.header_type)
+    }
+
+    /// The first capability with this ID (see the constants in [capability](capability/index.html))
+    pub fn find_capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities().find(|capability| capability.id == id)
+    }
+
+    /// Whether this is a PCI Express function (and so has 4 KiB of config space)
+    pub fn is_pci_express(&self) -> bool {
+        self.find_capability(capability::PCI_EXPRESS).is_some()
+    }
+
+    /// The PCI Express extended capabilities. Only reachable through ECAM - empty without it
+    pub fn extended_capabilities(&self) -> ExtendedCapabilities {
+        ExtendedCapabilities::new(self.address, self.is_pci_express())
+    }
+
+    /// The first extended capability with this ID (the `EXT_` constants in [capability](capability/index.html))
+    pub fn find_extended_capability(&self, id: u16) -> Option<ExtendedCapability> {
+        self.extended_capabilities().find(|capability| capability.id == id)
+    }
+
+    /// The MSI capability, to interrupt through a single vector instead of the legacy interrupt line
+    pub fn msi(&self) -> Result<Msi, MsiError> {
+        Msi::new(*self)
+    }
+
+    /// The MSI-X capability and table, for a separate vector per queue. Prefer this over `msi` if both exist
+    pub fn msix(&self) -> Result<MsiX, MsiError> {
+        MsiX::new(*self)
+    }
+
+    /// The IRQ the firmware routed the legacy interrupt to (0xFF if none)
+    pub fn interrupt_line(&self) -> u8 {
+        self.read_u8(config::INTERRUPT_LINE)
+    }
+
+    /// Which legacy interrupt pin the device uses - 1 to 4 for INTA# to INTD#, 0 if it doesn't use one
+    pub fn interrupt_pin(&self) -> u8 {
+        self.read_u8(config::INTERRUPT_PIN)
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} [{:04x}:{:04x}] class {:02x}.{:02x}.{:02x}",
+            self.address, self.vendor_id, self.device_id, self.class, self.subclass, self.prog_if
+        )
+    }
+}
+
+/// # enumerate
+///
+/// Find every PCI function on segment 0 - starting at bus 0 and following PCI-to-PCI bridges to the buses
+/// behind them. A multifunction host bridge means there are several host controllers, one per function,
+/// each with its own bus.
+pub fn enumerate() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    let host = PciAddress::new(0, 0, 0, 0);
+    let multiple_hosts = config::read_u8(host, config::HEADER_TYPE) & config::HEADER_TYPE_MULTIFUNCTION != 0;
+    if multiple_hosts {
+        for function in 0..8 {
+            if config::read_u16(PciAddress::new(0, 0, 0, function), config::VENDOR_ID) != 0xFFFF {
+                scan_bus(function, &mut devices);
+            }
+        }
+    } else {
+        scan_bus(0, &mut devices);
+    }
+    devices
+}
+
+fn scan_bus(bus: u8, devices: &mut Vec<PciDevice>) {
+    for device in 0..32 {
+        let first = match PciDevice::new(PciAddress::new(0, bus, device, 0)) {
+            Some(first) => first,
+            None => continue,
+        };
+        let functions = if first.header_type & config::HEADER_TYPE_MULTIFUNCTION != 0 { 8 } else { 1 };
+
+        for function in 0..functions {
+            let found = if function == 0 { Some(first) } else { PciDevice::new(PciAddress::new(0, bus, device, function)) };
+            if let Some(found) = found {
+                devices.push(found);
+                if found.is_bridge() {
+                    let secondary = found.read_u8(config::SECONDARY_BUS);
+                    // An unconfigured bridge has secondary bus 0 - don't go round in circles
+                    if secondary > bus {
+                        scan_bus(secondary, devices);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// # add_devices
+///
+/// Scan the PCI buses, and add every function to the driver [registry](../registry/index.html) - binding
+/// drivers to them. Called once, by [driver::init](../fn.init.html)
+pub fn add_devices() {
+    let _ = SCANNER.try_init_once(PciScanner::new);
+    let scanner = match SCANNER.try_get() {
+        Ok(scanner) => scanner,
+        Err(_) => return,
+    };
+    for &function in scanner.functions.iter() {
+        let full_class = scanner.full_class_of(&function);
+        registry::add_device(function.address.to_string(), DeviceKind::Pci { function, full_class });
+    }
+}
+
+/// The result of the boot time scan. `None` before [add_devices](fn.add_devices.html)
+pub fn scanner() -> Option<&'static PciScanner> {
+    SCANNER.try_get().ok()
+}
+
+/// Log every PCI function, and its BARs, to serial
+pub fn print_devices(devices: &[PciDevice]) {
+    for device in devices {
+        serial_println!("[LOG] PCI {}", device);
+        for (index, bar) in device.bars() {
+            serial_println!("[LOG]     BAR{}: {:?}", index, bar);
+        }
+    }
+}
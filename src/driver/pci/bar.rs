@@ -0,0 +1,166 @@
+//! # BARs
+//!
+//! Base Address Registers say where a device's registers are - a range of I/O ports or of physical memory.
+//! The firmware assigns the addresses. The size of each range is found by writing all ones to the BAR and
+//! seeing which address bits stick.
+
+use super::config::{self, PciAddress};
+
+/// Bit 0 of a BAR - set for I/O space, clear for memory space
+const BAR_IO_SPACE: u32 = 1 << 0;
+/// Bits 1-2 of a memory BAR - how wide its address is
+const BAR_MEMORY_TYPE_MASK: u32 = 0b11 << 1;
+const BAR_MEMORY_TYPE_64: u32 = 0b10 << 1;
+/// Bit 3 of a memory BAR - reads have no side effects, so the CPU can prefetch and merge them
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+const BAR_IO_ADDRESS_MASK: u32 = !0b11;
+const BAR_MEMORY_ADDRESS_MASK: u32 = !0b1111;
+
+/// # Bar
+///
+/// A decoded Base Address Register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    /// A range of I/O ports
+    Io { port: u32, size: u32 },
+    /// A range of physical memory. 64 bit BARs take up two BAR slots
+    Memory { address: u64, size: u64, prefetchable: bool, is_64bit: bool },
+}
+
+impl Bar {
+    /// Decode a BAR from what it read (`value`, and `upper` - the next BAR, only used for 64 bit memory BARs),
+    /// and what it read after writing all ones to it (`probe` and `upper_probe`). `None` if the BAR isn't
+    /// implemented
+    pub fn decode(value: u32, upper: u32, probe: u32, upper_probe: u32) -> Option<Bar> {
+        if value & BAR_IO_SPACE != 0 {
+            let mask = probe & BAR_IO_ADDRESS_MASK;
+            if mask == 0 {
+                return None;
+            }
+            // The top 16 bits of an I/O BAR may not be implemented, and then read as 0
+            let mask = if mask & 0xFFFF_0000 == 0 { mask | 0xFFFF_0000 } else { mask };
+            return Some(Bar::Io { port: value & BAR_IO_ADDRESS_MASK, size: (!mask).wrapping_add(1) });
+        }
+
+        let prefetchable = value & BAR_PREFETCHABLE != 0;
+        if value & BAR_MEMORY_TYPE_MASK == BAR_MEMORY_TYPE_64 {
+            let address = (upper as u64) << 32 | (value & BAR_MEMORY_ADDRESS_MASK) as u64;
+            let mask = (upper_probe as u64) << 32 | (probe & BAR_MEMORY_ADDRESS_MASK) as u64;
+            if mask == 0 {
+                return None;
+            }
+            Some(Bar::Memory { address, size: (!mask).wrapping_add(1), prefetchable, is_64bit: true })
+        } else {
+            let mask = probe & BAR_MEMORY_ADDRESS_MASK;
+            if mask == 0 {
+                return None;
+            }
+            let size = (!mask).wrapping_add(1) as u64;
+            Some(Bar::Memory { address: (value & BAR_MEMORY_ADDRESS_MASK) as u64, size, prefetchable, is_64bit: false })
+        }
+    }
+
+    /// The first port or physical address
+    pub fn address(&self) -> u64 {
+        match *self {
+            Bar::Io { port, .. } => port as u64,
+            Bar::Memory { address, .. } => address,
+        }
+    }
+
+    /// How many ports or bytes
+    pub fn size(&self) -> u64 {
+        match *self {
+            Bar::Io { size, .. } => size as u64,
+            Bar::Memory { size, .. } => size,
+        }
+    }
+
+    pub fn is_io(&self) -> bool {
+        matches!(self, Bar::Io { .. })
+    }
+
+    /// How many BAR slots this takes up
+    pub fn slots(&self) -> usize {
+        match self {
+            Bar::Memory { is_64bit: true, .. } => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// How many BARs a function with this header type has
+pub fn bar_count(header_type: u8) -> usize {
+    match header_type & !config::HEADER_TYPE_MULTIFUNCTION {
+        config::HEADER_TYPE_GENERAL => 6,
+        config::HEADER_TYPE_PCI_BRIDGE => 2,
+        _ => 0,
+    }
+}
+
+/// Read and size BAR `index` of the function at `address`. Decoding is turned off while the BAR holds all
+/// ones, so the device doesn't answer at a bogus address in the meantime
+pub fn read(address: PciAddress, header_type: u8, index: usize) -> Option<Bar> {
+    if index >= bar_count(header_type) {
+        return None;
+    }
+    let offset = config::BAR0 + index as u16 * 4;
+    let value = config::read_u32(address, offset);
+    let is_64bit = value & BAR_IO_SPACE == 0 && value & BAR_MEMORY_TYPE_MASK == BAR_MEMORY_TYPE_64;
+    if is_64bit && index + 1 >= bar_count(header_type) {
+        return None; // the upper half would be past the last BAR
+    }
+
+    let command = config::read_u16(address, config::COMMAND);
+    config::write_u16(
+        address,
+        config::COMMAND,
+        command & !(config::COMMAND_IO_SPACE | config::COMMAND_MEMORY_SPACE),
+    );
+
+    config::write_u32(address, offset, !0);
+    let probe = config::read_u32(address, offset);
+    config::write_u32(address, offset, value);
+
+    let (upper, upper_probe) = if is_64bit {
+        let upper = config::read_u32(address, offset + 4);
+        config::write_u32(address, offset + 4, !0);
+        let upper_probe = config::read_u32(address, offset + 4);
+        config::write_u32(address, offset + 4, upper);
+        (upper, upper_probe)
+    } else {
+        (0, 0)
+    };
+
+    config::write_u16(address, config::COMMAND, command);
+    Bar::decode(value, upper, probe, upper_probe)
+}
+
+/* Testing */
+
+#[test_case]
+fn bar_decodes_io() {
+    // 32 ports at 0xC040. QEMU only implements the low 16 bits
+    let bar = Bar::decode(0xC041, 0, 0xFFE1, 0);
+    assert_eq!(bar, Some(Bar::Io { port: 0xC040, size: 32 }));
+}
+
+#[test_case]
+fn bar_decodes_memory() {
+    // 16 MiB prefetchable framebuffer at 0xFD000000
+    let bar = Bar::decode(0xFD00_0008, 0, 0xFF00_0008, 0).unwrap();
+    assert_eq!(bar, Bar::Memory { address: 0xFD00_0000, size: 16 << 20, prefetchable: true, is_64bit: false });
+    assert_eq!(bar.slots(), 1);
+
+    // 16 KiB 64 bit BAR above 4 GiB
+    let bar = Bar::decode(0xFE00_000C, 0x8, 0xFFFF_C00C, 0xFFFF_FFFF).unwrap();
+    assert_eq!(bar.address(), 0x8_FE00_0000);
+    assert_eq!(bar.size(), 16 << 10);
+    assert_eq!(bar.slots(), 2);
+}
+
+#[test_case]
+fn bar_unimplemented() {
+    assert_eq!(Bar::decode(0, 0, 0, 0), None);
+}
@@ -0,0 +1,142 @@
+//! # Capabilities
+//!
+//! Optional features (power management, MSI, MSI-X, PCI Express...) are described by a linked list of
+//! capability structures in config space. Each starts with its ID and the offset of the next one.
+//!
+//! PCI Express functions have a second list of extended capabilities (AER, SR-IOV...) in extended config
+//! space, starting at 0x100. It can only be read through [ECAM](../ecam/index.html).
+
+use super::config::{self, PciAddress};
+
+/* Capability IDs */
+pub const POWER_MANAGEMENT: u8 = 0x01;
+pub const MSI: u8 = 0x05;
+pub const VENDOR_SPECIFIC: u8 = 0x09;
+pub const PCI_EXPRESS: u8 = 0x10;
+pub const MSIX: u8 = 0x11;
+
+/* Extended capability IDs */
+pub const EXT_ADVANCED_ERROR_REPORTING: u16 = 0x0001;
+pub const EXT_VIRTUAL_CHANNEL: u16 = 0x0002;
+pub const EXT_DEVICE_SERIAL_NUMBER: u16 = 0x0003;
+pub const EXT_ACCESS_CONTROL_SERVICES: u16 = 0x000D;
+pub const EXT_ALTERNATIVE_ROUTING_ID: u16 = 0x000E;
+pub const EXT_SR_IOV: u16 = 0x0010;
+
+/// Each capability takes at least 4 bytes of the 192 after the header, so a longer list must be a loop
+const MAX_CAPABILITIES: usize = 48;
+/// Extended capabilities take at least 4 bytes of the 3840 above legacy config space
+const MAX_EXTENDED_CAPABILITIES: usize = 960;
+
+/// # Capability
+///
+/// A capability - its ID, and where its structure starts in config space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    pub offset: u16,
+}
+
+/// # Capabilities
+///
+/// Iterates over a function's capability list
+pub struct Capabilities {
+    address: PciAddress,
+    next: u16,
+    remaining: usize,
+}
+
+impl Capabilities {
+    /// The capability list of the function at `address`. Empty if it doesn't have one
+    pub fn new(address: PciAddress, header_type: u8) -> Self {
+        let status = config::read_u16(address, config::STATUS);
+        let next = if status == !0 || status & config::STATUS_CAPABILITIES_LIST == 0 {
+            0
+        } else if header_type & !config::HEADER_TYPE_MULTIFUNCTION == config::HEADER_TYPE_CARDBUS_BRIDGE {
+            config::read_u8(address, config::CARDBUS_CAPABILITIES_POINTER) as u16
+        } else {
+            config::read_u8(address, config::CAPABILITIES_POINTER) as u16
+        };
+        Capabilities { address, next: next & 0xFC, remaining: MAX_CAPABILITIES }
+    }
+}
+
+impl Iterator for Capabilities {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Capability> {
+        // Capabilities live after the 64 byte header
+        if self.next < 0x40 || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let offset = self.next;
+        let header = config::read_u16(self.address, offset);
+        self.next = (header >> 8) & 0xFC;
+        Some(Capability { id: header as u8, offset })
+    }
+}
+
+/// # ExtendedCapability
+///
+/// A PCI Express extended capability - its ID and version, and where its structure starts in config space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtendedCapability {
+    pub id: u16,
+    pub version: u8,
+    pub offset: u16,
+}
+
+/// Split an extended capability header into its ID, version and the offset of the next capability
+fn decode_extended_header(header: u32) -> (u16, u8, u16) {
+    (header as u16, (header >> 16) as u8 & 0xF, (header >> 20) as u16 & 0xFFC)
+}
+
+/// # ExtendedCapabilities
+///
+/// Iterates over a function's extended capability list. Empty without ECAM, or for plain PCI functions
+pub struct ExtendedCapabilities {
+    address: PciAddress,
+    next: u16,
+    remaining: usize,
+}
+
+impl ExtendedCapabilities {
+    /// The extended capability list of the function at `address`. Only PCI Express functions have one
+    pub fn new(address: PciAddress, pci_express: bool) -> Self {
+        let next = if pci_express { config::CONFIG_SPACE_SIZE } else { 0 };
+        ExtendedCapabilities { address, next, remaining: MAX_EXTENDED_CAPABILITIES }
+    }
+}
+
+impl Iterator for ExtendedCapabilities {
+    type Item = ExtendedCapability;
+
+    fn next(&mut self) -> Option<ExtendedCapability> {
+        if self.next < config::CONFIG_SPACE_SIZE || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let offset = self.next;
+        let header = config::read_u32(self.address, offset);
+        // No list reads as 0, and no config space (legacy access above 256 bytes) as all ones
+        if header == 0 || header == !0 {
+            return None;
+        }
+        let (id, version, next) = decode_extended_header(header);
+        self.next = next;
+        Some(ExtendedCapability { id, version, offset })
+    }
+}
+
+/* Testing */
+
+#[test_case]
+fn extended_capability_header() {
+    // AER, version 2, next capability at 0x148
+    assert_eq!(decode_extended_header(0x1482_0001), (EXT_ADVANCED_ERROR_REPORTING, 2, 0x148));
+    // The last capability in the list
+    assert_eq!(decode_extended_header(0x0001_0010), (EXT_SR_IOV, 1, 0));
+}
@@ -0,0 +1,215 @@
+//! # Config space
+//!
+//! Every PCI function has 256 bytes of configuration space - its IDs, class, command and status registers,
+//! BARs and capability list (PCI Express functions have 4 KiB). If the bus is covered by a mapped
+//! [ECAM](../ecam/index.html) region, config space is plain memory. Otherwise we use the legacy configuration
+//! mechanism: write the address of a dword to port `0xCF8`, then access it through `0xCFC`-`0xCFF`.
+
+use core::{fmt, ptr};
+use x86_64::instructions::port::Port;
+use crate::sync::IrqSpinlock;
+use super::ecam;
+
+/* Standard header registers (offsets) */
+pub const VENDOR_ID: u16 = 0x00;
+pub const DEVICE_ID: u16 = 0x02;
+pub const COMMAND: u16 = 0x04;
+pub const STATUS: u16 = 0x06;
+pub const REVISION_ID: u16 = 0x08;
+pub const PROG_IF: u16 = 0x09;
+pub const SUBCLASS: u16 = 0x0A;
+pub const CLASS: u16 = 0x0B;
+pub const HEADER_TYPE: u16 = 0x0E;
+pub const BAR0: u16 = 0x10;
+/// Bridges (header type 1) only - the bus on the other side of the bridge
+pub const SECONDARY_BUS: u16 = 0x19;
+pub const SUBSYSTEM_VENDOR_ID: u16 = 0x2C;
+pub const SUBSYSTEM_ID: u16 = 0x2E;
+pub const CAPABILITIES_POINTER: u16 = 0x34;
+/// CardBus bridges (header type 2) keep their capabilities pointer somewhere else
+pub const CARDBUS_CAPABILITIES_POINTER: u16 = 0x14;
+pub const INTERRUPT_LINE: u16 = 0x3C;
+pub const INTERRUPT_PIN: u16 = 0x3D;
+
+/* Command register bits */
+/// Respond to I/O space accesses (I/O BARs)
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+/// Respond to memory space accesses (memory BARs)
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+/// Allow the device to do DMA
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+/// Stop the device asserting its legacy INTx interrupt
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+/* Status register bits */
+/// There is a capability list at `CAPABILITIES_POINTER`
+pub const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+
+/// Header types (the low 7 bits of `HEADER_TYPE`)
+pub const HEADER_TYPE_GENERAL: u8 = 0x00;
+pub const HEADER_TYPE_PCI_BRIDGE: u8 = 0x01;
+pub const HEADER_TYPE_CARDBUS_BRIDGE: u8 = 0x02;
+/// Set in `HEADER_TYPE` of function 0 if the device has more functions
+pub const HEADER_TYPE_MULTIFUNCTION: u8 = 0x80;
+
+/// The size of legacy config space. PCI Express functions have 4 KiB, but only the first 256 bytes can be
+/// reached through the I/O ports
+pub const CONFIG_SPACE_SIZE: u16 = 0x100;
+/// The size of PCI Express config space. Extended capabilities start at `CONFIG_SPACE_SIZE`
+pub const EXTENDED_CONFIG_SPACE_SIZE: u16 = 0x1000;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+/// Selecting a register and then accessing it takes two port accesses, which mustn't be interleaved with
+/// another core's (or an interrupt handler's)
+static LEGACY_LOCK: IrqSpinlock<()> = IrqSpinlock::new(());
+
+/// # PciAddress
+///
+/// Where a PCI function is - segment (PCI domain), bus, device (slot) and function. Displayed the usual
+/// way, like `0000:00:1f.2`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+        PciAddress { segment, bus, device, function }
+    }
+
+    /// The value to write to `0xCF8` to select the dword at `offset`
+    fn legacy_address(&self, offset: u16) -> u32 {
+        1 << 31
+            | (self.bus as u32) << 16
+            | ((self.device & 0x1F) as u32) << 11
+            | ((self.function & 0x7) as u32) << 8
+            | (offset & 0xFC) as u32
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}:{:02x}:{:02x}.{}", self.segment, self.bus, self.device, self.function)
+    }
+}
+
+/// Whether a register can be reached through the I/O ports - only on segment 0, and in the first 256 bytes
+fn reachable(address: PciAddress, offset: u16, size: u16) -> bool {
+    address.segment == 0 && offset + size <= CONFIG_SPACE_SIZE
+}
+
+/// Read a config space dword. `offset` should be dword aligned. Reading something that doesn't exist
+/// gives all ones, like the hardware does
+pub fn read_u32(address: PciAddress, offset: u16) -> u32 {
+    if let Some(register) = ecam::config_address(address, offset) {
+        return unsafe { ptr::read_volatile(register.as_ptr::<u32>()) };
+    }
+    if !reachable(address, offset, 4) {
+        return !0;
+    }
+    let _guard = LEGACY_LOCK.lock();
+    unsafe {
+        Port::<u32>::new(CONFIG_ADDRESS).write(address.legacy_address(offset));
+        Port::<u32>::new(CONFIG_DATA).read()
+    }
+}
+
+/// Read a config space word. `offset` should be word aligned
+pub fn read_u16(address: PciAddress, offset: u16) -> u16 {
+    if let Some(register) = ecam::config_address(address, offset) {
+        return unsafe { ptr::read_volatile(register.as_ptr::<u16>()) };
+    }
+    if !reachable(address, offset, 2) {
+        return !0;
+    }
+    let _guard = LEGACY_LOCK.lock();
+    unsafe {
+        Port::<u32>::new(CONFIG_ADDRESS).write(address.legacy_address(offset));
+        Port::<u16>::new(CONFIG_DATA + (offset & 2)).read()
+    }
+}
+
+/// Read a config space byte
+pub fn read_u8(address: PciAddress, offset: u16) -> u8 {
+    if let Some(register) = ecam::config_address(address, offset) {
+        return unsafe { ptr::read_volatile(register.as_ptr::<u8>()) };
+    }
+    if !reachable(address, offset, 1) {
+        return !0;
+    }
+    let _guard = LEGACY_LOCK.lock();
+    unsafe {
+        Port::<u32>::new(CONFIG_ADDRESS).write(address.legacy_address(offset));
+        Port::<u8>::new(CONFIG_DATA + (offset & 3)).read()
+    }
+}
+
+/// Write a config space dword. `offset` should be dword aligned
+pub fn write_u32(address: PciAddress, offset: u16, value: u32) {
+    if let Some(register) = ecam::config_address(address, offset) {
+        unsafe { ptr::write_volatile(register.as_mut_ptr::<u32>(), value) };
+        return;
+    }
+    if !reachable(address, offset, 4) {
+        return;
+    }
+    let _guard = LEGACY_LOCK.lock();
+    unsafe {
+        Port::<u32>::new(CONFIG_ADDRESS).write(address.legacy_address(offset));
+        Port::<u32>::new(CONFIG_DATA).write(value);
+    }
+}
+
+/// Write a config space word. Only that word is written - not the rest of its dword, which matters for
+/// registers like the status register whose bits are cleared by writing ones to them
+pub fn write_u16(address: PciAddress, offset: u16, value: u16) {
+    if let Some(register) = ecam::config_address(address, offset) {
+        unsafe { ptr::write_volatile(register.as_mut_ptr::<u16>(), value) };
+        return;
+    }
+    if !reachable(address, offset, 2) {
+        return;
+    }
+    let _guard = LEGACY_LOCK.lock();
+    unsafe {
+        Port::<u32>::new(CONFIG_ADDRESS).write(address.legacy_address(offset));
+        Port::<u16>::new(CONFIG_DATA + (offset & 2)).write(value);
+    }
+}
+
+/// Write a config space byte
+pub fn write_u8(address: PciAddress, offset: u16, value: u8) {
+    if let Some(register) = ecam::config_address(address, offset) {
+        unsafe { ptr::write_volatile(register.as_mut_ptr::<u8>(), value) };
+        return;
+    }
+    if !reachable(address, offset, 1) {
+        return;
+    }
+    let _guard = LEGACY_LOCK.lock();
+    unsafe {
+        Port::<u32>::new(CONFIG_ADDRESS).write(address.legacy_address(offset));
+        Port::<u8>::new(CONFIG_DATA + (offset & 3)).write(value);
+    }
+}
+
+/* Testing */
+
+#[test_case]
+fn pci_address_display() {
+    use alloc::format;
+    assert_eq!(format!("{}", PciAddress::new(0, 0, 0x1F, 2)), "0000:00:1f.2");
+    assert_eq!(format!("{}", PciAddress::new(1, 0x80, 3, 0)), "0001:80:03.0");
+}
+
+#[test_case]
+fn pci_legacy_address() {
+    assert_eq!(PciAddress::new(0, 0, 0, 0).legacy_address(0), 0x8000_0000);
+    // Offsets are dword aligned - the low bits pick the byte through 0xCFC-0xCFF instead
+    assert_eq!(PciAddress::new(0, 1, 2, 3).legacy_address(0x3E), 0x8001_133C);
+}
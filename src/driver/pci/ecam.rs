@@ -0,0 +1,128 @@
+//! # ECAM
+//!
+//! PCI Express functions have 4 KiB of config space, but the legacy I/O port mechanism only reaches the
+//! first 256 bytes - the extended capabilities (AER, SR-IOV...) live above that. The Enhanced Configuration
+//! Access Mechanism maps all of it into memory, at addresses the ACPI MCFG gives us.
+//!
+//! [init](fn.init.html) maps each MCFG region (uncached). From then on [config](../config/index.html) uses
+//! ECAM for any bus it covers, and the I/O ports for everything else. QEMU's `q35` machine has an MCFG;
+//! `pc` (i440fx) doesn't, so it keeps using the I/O ports.
+
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use x86_64::structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Size4KiB};
+use x86_64::VirtAddr;
+use crate::acpi::{self, EcamRegion};
+use crate::{memory, serial_println};
+use super::config::{PciAddress, EXTENDED_CONFIG_SPACE_SIZE};
+
+/// Where the ECAM regions are mapped, one after another. A full region (256 buses) is 256 MiB
+pub const ECAM_START: u64 = 0x_6666_0000_0000;
+
+/// An MCFG region, and where we mapped it
+struct MappedRegion {
+    region: EcamRegion,
+    base: VirtAddr,
+}
+
+/// The regions `init` mapped. Unset (or empty) means there is no ECAM, and config space goes through the I/O ports
+static REGIONS: OnceCell<Vec<MappedRegion>> = OnceCell::uninit();
+
+/// # init
+///
+/// Map every ECAM region in the ACPI MCFG. Call this after `acpi::init`, and before anything touches PCI
+/// config space beyond the first 256 bytes.
+pub fn init() {
+    let mut regions = Vec::new();
+    let mut next = VirtAddr::new(ECAM_START);
+
+    for region in acpi::ecam_regions() {
+        match memory::with_mapper(|mapper, frame_allocator| map_region(region, next, mapper, frame_allocator)) {
+            Ok(()) => {
+                serial_println!(
+                    "[LOG] PCI Express ECAM: segment {}, buses {:#x}-{:#x} at {:#x}",
+                    region.segment_group,
+                    region.start_bus,
+                    region.end_bus,
+                    region.base_address.as_u64()
+                );
+                regions.push(MappedRegion { region: *region, base: next });
+                next += region.size();
+            }
+            Err(err) => {
+                serial_println!("[LOG] Failed to map the ECAM region at {:#x}: {:?}", region.base_address.as_u64(), err)
+            }
+        }
+    }
+
+    if regions.is_empty() {
+        serial_println!("[LOG] No PCI Express ECAM, using legacy config access (256 bytes per function)");
+    }
+    let _ = REGIONS.try_init_once(|| regions);
+}
+
+fn map_region(
+    region: &EcamRegion,
+    virt: VirtAddr,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    // The region for the first bus starts at the MCFG's base address, even if that bus isn't 0
+    let start = region.config_address(region.start_bus, 0, 0);
+    memory::map_mmio(start, virt, region.size(), mapper, frame_allocator)
+}
+
+/// Whether any ECAM region is mapped
+pub fn is_available() -> bool {
+    REGIONS.try_get().map_or(false, |regions| !regions.is_empty())
+}
+
+/// The ECAM region covering `bus` in `segment`, if it is mapped
+pub fn region(segment: u16, bus: u8) -> Option<&'static EcamRegion> {
+    mapped_region(segment, bus).map(|mapped| &mapped.region)
+}
+
+fn mapped_region(segment: u16, bus: u8) -> Option<&'static MappedRegion> {
+    REGIONS.try_get().ok()?.iter().find(|mapped| mapped.region.contains(segment, bus))
+}
+
+/// The virtual address of the register at `offset` in the config space of `address`. `None` if ECAM doesn't
+/// cover it
+pub(super) fn config_address(address: PciAddress, offset: u16) -> Option<VirtAddr> {
+    lookup(REGIONS.try_get().ok()?, address, offset)
+}
+
+/// Find the register at `offset` in the config space of `address`, in `regions`
+fn lookup(regions: &[MappedRegion], address: PciAddress, offset: u16) -> Option<VirtAddr> {
+    if offset >= EXTENDED_CONFIG_SPACE_SIZE {
+        return None;
+    }
+    let mapped = regions.iter().find(|mapped| mapped.region.contains(address.segment, address.bus))?;
+    let function = mapped.region.config_address(address.bus, address.device & 0x1F, address.function & 0x7);
+    let start = mapped.region.config_address(mapped.region.start_bus, 0, 0);
+    Some(mapped.base + (function.as_u64() - start.as_u64()) + offset as u64)
+}
+
+/* Testing */
+
+// Registers are found in the region covering their bus, counting from the region's first bus
+#[test_case]
+fn ecam_config_address() {
+    use alloc::vec;
+    use x86_64::PhysAddr;
+
+    let base = VirtAddr::new(ECAM_START);
+    let region = EcamRegion { base_address: PhysAddr::new(0xB000_0000), segment_group: 0, start_bus: 0x10, end_bus: 0x1F };
+    let regions = vec![MappedRegion { region, base }];
+
+    assert_eq!(lookup(&regions, PciAddress::new(0, 0x10, 0, 0), 0), Some(base));
+    let register = lookup(&regions, PciAddress::new(0, 0x11, 3, 2), 0x104);
+    assert_eq!(register, Some(base + (1u64 << 20 | 3 << 15 | 2 << 12 | 0x104)));
+    assert_eq!(lookup(&regions, PciAddress::new(0, 0x1F, 31, 7), 0xFFC), Some(base + (region.size() - 4)));
+
+    // Outside the region, or past the end of config space
+    assert_eq!(lookup(&regions, PciAddress::new(0, 0x0F, 0, 0), 0), None);
+    assert_eq!(lookup(&regions, PciAddress::new(0, 0x20, 0, 0), 0), None);
+    assert_eq!(lookup(&regions, PciAddress::new(1, 0x10, 0, 0), 0), None);
+    assert_eq!(lookup(&regions, PciAddress::new(0, 0x10, 0, 0), EXTENDED_CONFIG_SPACE_SIZE), None);
+}
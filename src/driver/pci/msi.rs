@@ -0,0 +1,249 @@
+//! # MSI and MSI-X
+//!
+//! Message Signalled Interrupts replace the legacy INTx lines. Instead of pulling a (shared) pin that goes
+//! through the PIC or I/O APIC, the device writes a message to an address in the local APIC range, and the
+//! data it writes says which vector to raise. There's nothing to share and no routing tables to read.
+//!
+//! * MSI - one capability with one message, for the whole function. (It can ask for a block of vectors, but
+//!   they have to be contiguous and aligned, so we only ever enable one)
+//! * MSI-X - a table in one of the function's memory BARs, with a message per entry. Drivers give each queue
+//!   its own entry, so queues interrupt separately, and can be sent to different cores
+//!
+//! Vectors come from [interrupts::allocate_vector](../../../interrupts/fn.allocate_vector.html), which also
+//! routes them to the driver's handler.
+
+use alloc::vec::Vec;
+use core::ptr;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::interrupts::{self, VectorHandler};
+use crate::{apic, memory};
+use super::{capability, Bar, PciDevice};
+
+/// Messages are written to `0xFEExxxxx`. Bits 12-19 pick the local APIC
+const MESSAGE_ADDRESS_BASE: u64 = 0xFEE0_0000;
+
+/* MSI capability registers (offsets from the capability) */
+const MSI_CONTROL: u16 = 0x02;
+const MSI_ADDRESS_LOW: u16 = 0x04;
+const MSI_ADDRESS_HIGH: u16 = 0x08;
+/// Where the data register is depends on whether the address is 64 bit
+const MSI_DATA_32: u16 = 0x08;
+const MSI_DATA_64: u16 = 0x0C;
+
+/* MSI message control bits */
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+/// Bits 4-6 - log2 of how many vectors are enabled. We always use 0 (one vector)
+const MSI_CONTROL_MULTIPLE_ENABLE: u16 = 0b111 << 4;
+const MSI_CONTROL_64BIT: u16 = 1 << 7;
+
+/* MSI-X capability registers (offsets from the capability) */
+const MSIX_CONTROL: u16 = 0x02;
+const MSIX_TABLE: u16 = 0x04;
+
+/* MSI-X message control bits */
+/// Bits 0-10 - the table size, minus one
+const MSIX_CONTROL_TABLE_SIZE: u16 = 0x7FF;
+/// Mask every entry at once, whatever their own mask bits say
+const MSIX_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+
+/// Each MSI-X table entry is 16 bytes - address low, address high, data and vector control
+const MSIX_ENTRY_SIZE: u64 = 16;
+/// Bit 0 of an entry's vector control - the entry is masked
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+/// # MsiError
+///
+/// Why an MSI or MSI-X interrupt couldn't be set up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiError {
+    /// The function doesn't have the capability
+    NotSupported,
+    /// The MSI-X table is in a BAR that isn't implemented, or isn't a memory BAR
+    InvalidTable,
+    /// The MSI-X table couldn't be mapped
+    UnmappedTable,
+    /// There's no such MSI-X table entry
+    InvalidEntry,
+    /// Every dynamic IDT vector is taken
+    NoFreeVectors,
+}
+
+/// The address a device writes to, to interrupt the core whose local APIC id is `apic_id`
+pub fn message_address(apic_id: u32) -> u64 {
+    MESSAGE_ADDRESS_BASE | ((apic_id & 0xFF) as u64) << 12
+}
+
+/// The data a device writes - `vector`, with fixed delivery and edge triggering (both 0)
+pub fn message_data(vector: u8) -> u32 {
+    vector as u32
+}
+
+/// # Msi
+///
+/// A function's MSI capability. Interrupts go to the core that called [enable](#method.enable)
+#[derive(Debug)]
+pub struct Msi {
+    device: PciDevice,
+    offset: u16,
+    vector: Option<u8>,
+}
+
+impl Msi {
+    /// Find the MSI capability of `device`
+    pub fn new(device: PciDevice) -> Result<Self, MsiError> {
+        let capability = device.find_capability(capability::MSI).ok_or(MsiError::NotSupported)?;
+        Ok(Msi { device, offset: capability.offset, vector: None })
+    }
+
+    /// Allocate a vector for `handler`, point the message at this core, turn off INTx and enable MSI. Returns
+    /// the vector. Messages are memory writes, so this enables bus mastering too
+    pub fn enable(&mut self, handler: VectorHandler, context: usize) -> Result<u8, MsiError> {
+        self.disable();
+        let vector = interrupts::allocate_vector(handler, context).ok_or(MsiError::NoFreeVectors)?;
+        self.vector = Some(vector);
+
+        let control = self.device.read_u16(self.offset + MSI_CONTROL);
+        let address = message_address(apic::id());
+        self.device.write_u32(self.offset + MSI_ADDRESS_LOW, address as u32);
+        if control & MSI_CONTROL_64BIT != 0 {
+            self.device.write_u32(self.offset + MSI_ADDRESS_HIGH, (address >> 32) as u32);
+            self.device.write_u16(self.offset + MSI_DATA_64, message_data(vector) as u16);
+        } else {
+            self.device.write_u16(self.offset + MSI_DATA_32, message_data(vector) as u16);
+        }
+
+        self.device.enable_bus_mastering();
+        self.device.set_interrupt_disable(true);
+        let control = control & !MSI_CONTROL_MULTIPLE_ENABLE | MSI_CONTROL_ENABLE;
+        self.device.write_u16(self.offset + MSI_CONTROL, control);
+        Ok(vector)
+    }
+
+    /// Turn MSI off, and give back the vector
+    pub fn disable(&mut self) {
+        let control = self.device.read_u16(self.offset + MSI_CONTROL);
+        self.device.write_u16(self.offset + MSI_CONTROL, control & !MSI_CONTROL_ENABLE);
+        if let Some(vector) = self.vector.take() {
+            interrupts::free_vector(vector);
+        }
+    }
+
+    /// The vector MSI is using, if it is enabled
+    pub fn vector(&self) -> Option<u8> {
+        self.vector
+    }
+}
+
+/// # MsiX
+///
+/// A function's MSI-X capability and table. Every entry starts out masked - [enable_entry](#method.enable_entry)
+/// gives one a vector and unmasks it
+#[derive(Debug)]
+pub struct MsiX {
+    device: PciDevice,
+    offset: u16,
+    table: VirtAddr,
+    vectors: Vec<Option<u8>>,
+}
+
+impl MsiX {
+    /// Find the MSI-X capability of `device`, and its table. Turns on memory space decoding, so the table can
+    /// be reached, and bus mastering, so the device can send messages
+    pub fn new(device: PciDevice) -> Result<Self, MsiError> {
+        let capability = device.find_capability(capability::MSIX).ok_or(MsiError::NotSupported)?;
+        let control = device.read_u16(capability.offset + MSIX_CONTROL);
+        let table = device.read_u32(capability.offset + MSIX_TABLE);
+
+        // The low 3 bits pick the BAR, the rest is the offset into it
+        let table_address = match device.bar((table & 0b111) as usize) {
+            Some(Bar::Memory { address, .. }) => address + (table & !0b111) as u64,
+            _ => return Err(MsiError::InvalidTable),
+        };
+        let entries = (control & MSIX_CONTROL_TABLE_SIZE) as usize + 1;
+        // The table is device registers, so it is mapped uncached
+        let table = memory::map_device(PhysAddr::new(table_address), entries as u64 * MSIX_ENTRY_SIZE)
+            .map_err(|_| MsiError::UnmappedTable)?;
+        device.enable_memory_space();
+        device.enable_bus_mastering();
+
+        let msix = MsiX { device, offset: capability.offset, table, vectors: vec![None; entries] };
+
+        // Enable MSI-X with everything masked, then unmask the function - entries are unmasked one by one
+        msix.set_control(MSIX_CONTROL_ENABLE | MSIX_CONTROL_FUNCTION_MASK, 0);
+        for entry in 0..entries {
+            msix.write_entry(entry, 3, MSIX_ENTRY_MASKED);
+        }
+        device.set_interrupt_disable(true);
+        msix.set_control(MSIX_CONTROL_ENABLE, MSIX_CONTROL_FUNCTION_MASK);
+        Ok(msix)
+    }
+
+    /// How many entries the table has (how many separate interrupts the function can send)
+    pub fn entry_count(&self) -> usize {
+        self.vectors.len()
+    }
+
+    /// Allocate a vector for `handler`, point table entry `entry` at it (on this core) and unmask it. Returns
+    /// the vector
+    pub fn enable_entry(&mut self, entry: usize, handler: VectorHandler, context: usize) -> Result<u8, MsiError> {
+        if entry >= self.entry_count() {
+            return Err(MsiError::InvalidEntry);
+        }
+        self.disable_entry(entry);
+        let vector = interrupts::allocate_vector(handler, context).ok_or(MsiError::NoFreeVectors)?;
+        self.vectors[entry] = Some(vector);
+
+        let address = message_address(apic::id());
+        self.write_entry(entry, 0, address as u32);
+        self.write_entry(entry, 1, (address >> 32) as u32);
+        self.write_entry(entry, 2, message_data(vector));
+        self.write_entry(entry, 3, 0); // unmask
+        Ok(vector)
+    }
+
+    /// Mask table entry `entry`, and give back its vector
+    pub fn disable_entry(&mut self, entry: usize) {
+        if entry >= self.entry_count() {
+            return;
+        }
+        self.write_entry(entry, 3, MSIX_ENTRY_MASKED);
+        if let Some(vector) = self.vectors[entry].take() {
+            interrupts::free_vector(vector);
+        }
+    }
+
+    /// The vector table entry `entry` is using, if it is enabled
+    pub fn vector(&self, entry: usize) -> Option<u8> {
+        self.vectors.get(entry).copied().flatten()
+    }
+
+    /// Mask every entry, turn MSI-X off and give back all the vectors
+    pub fn disable(&mut self) {
+        for entry in 0..self.entry_count() {
+            self.disable_entry(entry);
+        }
+        self.set_control(0, MSIX_CONTROL_ENABLE | MSIX_CONTROL_FUNCTION_MASK);
+    }
+
+    /// Set then clear bits in the message control register
+    fn set_control(&self, set: u16, clear: u16) {
+        let control = self.device.read_u16(self.offset + MSIX_CONTROL);
+        self.device.write_u16(self.offset + MSIX_CONTROL, (control | set) & !clear);
+    }
+
+    /// Write dword `index` (0-3) of table entry `entry`. The table must be accessed a dword at a time
+    fn write_entry(&self, entry: usize, index: u64, value: u32) {
+        let address = self.table + entry as u64 * MSIX_ENTRY_SIZE + index * 4;
+        unsafe { ptr::write_volatile(address.as_mut_ptr::<u32>(), value) };
+    }
+}
+
+/* Testing */
+
+#[test_case]
+fn msi_message() {
+    assert_eq!(message_address(0), 0xFEE0_0000);
+    assert_eq!(message_address(3), 0xFEE0_3000);
+    assert_eq!(message_data(0x41), 0x41);
+}
@@ -0,0 +1,235 @@
+//! # Registry
+//!
+//! Every device we know about, every registered driver, and which driver each device is bound to. Adding a
+//! device tries every driver whose match table fits it, in the order they registered, until one's `probe`
+//! succeeds. Registering a driver tries it on every unbound device.
+//!
+//! The lock isn't held while a driver runs, so `probe` can add devices of its own (the virtio core adds
+//! virtio devices when it probes their PCI functions). The registry isn't touched from interrupt handlers,
+//! so it is a plain `spin::Mutex`.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::serial_println;
+use super::device::{matches_any, Bus, Device, DeviceId, DeviceKind};
+use super::{Driver, ProbeError};
+
+struct Entry {
+    device: Device,
+    driver: Option<&'static dyn Driver>,
+    /// A driver is being tried on the device right now, so nobody else should bind it
+    probing: bool,
+}
+
+struct Registry {
+    /// In the order they were added (so by ID)
+    devices: Vec<Entry>,
+    drivers: Vec<&'static dyn Driver>,
+    next_id: u32,
+}
+
+impl Registry {
+    fn entry(&mut self, id: DeviceId) -> Option<&mut Entry> {
+        self.devices.iter_mut().find(|entry| entry.device.id == id)
+    }
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry { devices: Vec::new(), drivers: Vec::new(), next_id: 0 });
+
+/// # register_driver
+///
+/// Add a driver, and bind it to every unbound device it matches
+pub fn register_driver(driver: &'static dyn Driver) {
+    let unbound: Vec<DeviceId> = {
+        let mut registry = REGISTRY.lock();
+        registry.drivers.push(driver);
+        registry.devices.iter().filter(|entry| entry.driver.is_none()).map(|entry| entry.device.id).collect()
+    };
+    for id in unbound {
+        bind(id);
+    }
+}
+
+/// # add_device
+///
+/// Add a device found on a bus, and bind a driver to it if one matches
+pub fn add_device(name: String, kind: DeviceKind) -> DeviceId {
+    let id = {
+        let mut registry = REGISTRY.lock();
+        let id = DeviceId(registry.next_id);
+        registry.next_id += 1;
+        registry.devices.push(Entry { device: Device { id, name, kind }, driver: None, probing: false });
+        id
+    };
+    bind(id);
+    id
+}
+
+/// Try every matching driver on device `id` until one binds. Returns whether it is bound now
+pub fn bind(id: DeviceId) -> bool {
+    let (device, candidates) = {
+        let mut registry = REGISTRY.lock();
+        let drivers = registry.drivers.clone();
+        let entry = match registry.entry(id) {
+            Some(entry) if entry.driver.is_none() && !entry.probing => entry,
+            Some(entry) => return entry.driver.is_some(),
+            None => return false,
+        };
+        let candidates: Vec<&'static dyn Driver> =
+            drivers.into_iter().filter(|driver| matches_any(driver.match_table(), &entry.device)).collect();
+        if candidates.is_empty() {
+            return false;
+        }
+        entry.probing = true;
+        (entry.device.clone(), candidates)
+    };
+
+    let mut bound = None;
+    for driver in candidates {
+        match driver.probe(&device) {
+            Ok(()) => {
+                bound = Some(driver);
+                break;
+            }
+            Err(ProbeError::Unsupported) => {}
+            Err(err) => serial_println!("[LOG] {}: {} failed to probe: {:?}", device, driver.name(), err),
+        }
+    }
+
+    if let Some(driver) = bound {
+        serial_println!("[LOG] {}: bound to {}", device, driver.name());
+    }
+    if let Some(entry) = REGISTRY.lock().entry(id) {
+        entry.probing = false;
+        entry.driver = bound;
+    }
+    bound.is_some()
+}
+
+/// Detach device `id` from its driver (calling the driver's `remove`). The device stays in the registry
+pub fn unbind(id: DeviceId) {
+    let (device, driver) = {
+        let mut registry = REGISTRY.lock();
+        match registry.entry(id) {
+            Some(entry) if !entry.probing => match entry.driver.take() {
+                Some(driver) => (entry.device.clone(), driver),
+                None => return,
+            },
+            _ => return,
+        }
+    };
+    driver.remove(&device);
+    serial_println!("[LOG] {}: unbound from {}", device, driver.name());
+}
+
+/// Remove device `id` altogether - it has been unplugged, or the bus driver that added it is going away
+pub fn remove_device(id: DeviceId) {
+    unbind(id);
+    REGISTRY.lock().devices.retain(|entry| entry.device.id != id);
+}
+
+/// The device with this ID, if it is still registered
+pub fn device(id: DeviceId) -> Option<Device> {
+    REGISTRY.lock().entry(id).map(|entry| entry.device.clone())
+}
+
+/// Every device, bound or not
+pub fn devices() -> Vec<Device> {
+    REGISTRY.lock().devices.iter().map(|entry| entry.device.clone()).collect()
+}
+
+/// Every device on `bus`
+pub fn devices_on(bus: Bus) -> Vec<Device> {
+    REGISTRY.lock().devices.iter().filter(|entry| entry.device.bus() == bus).map(|entry| entry.device.clone()).collect()
+}
+
+/// The name of the driver bound to device `id`
+pub fn driver_of(id: DeviceId) -> Option<&'static str> {
+    REGISTRY.lock().entry(id).and_then(|entry| entry.driver).map(|driver| driver.name())
+}
+
+/// Every bound device, with the name of its driver
+pub fn bound_devices() -> Vec<(Device, &'static str)> {
+    REGISTRY
+        .lock()
+        .devices
+        .iter()
+        .filter_map(|entry| entry.driver.map(|driver| (entry.device.clone(), driver.name())))
+        .collect()
+}
+
+/// Every bound device and its driver, in the order they were bound
+fn bindings() -> Vec<(Device, &'static dyn Driver)> {
+    REGISTRY.lock().devices.iter().filter_map(|entry| entry.driver.map(|driver| (entry.device.clone(), driver))).collect()
+}
+
+/// # suspend_all
+///
+/// Suspend every bound device, last added first - so children (like virtio devices) go before the devices
+/// they sit on
+pub fn suspend_all() {
+    for (device, driver) in bindings().into_iter().rev() {
+        driver.suspend(&device);
+    }
+}
+
+/// Resume every bound device, in the opposite order to `suspend_all`
+pub fn resume_all() {
+    for (device, driver) in bindings() {
+        driver.resume(&device);
+    }
+}
+
+/// Log every device, and the driver bound to it, to serial
+pub fn print_devices() {
+    for entry in REGISTRY.lock().devices.iter() {
+        match entry.driver {
+            Some(driver) => serial_println!("[LOG] {} -> {}", entry.device, driver.name()),
+            None => serial_println!("[LOG] {} (no driver)", entry.device),
+        }
+    }
+}
+
+/* Testing */
+
+#[test_case]
+fn bind_and_remove() {
+    use alloc::string::ToString;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use super::device::DeviceMatch;
+
+    static PROBED: AtomicUsize = AtomicUsize::new(0);
+    static REMOVED: AtomicUsize = AtomicUsize::new(0);
+
+    struct TestDriver;
+    impl Driver for TestDriver {
+        fn name(&self) -> &'static str {
+            "test"
+        }
+        fn match_table(&self) -> &'static [DeviceMatch] {
+            &[DeviceMatch::Isa("test-device")]
+        }
+        fn probe(&self, _device: &Device) -> Result<(), ProbeError> {
+            PROBED.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+        fn remove(&self, _device: &Device) {
+            REMOVED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    static TEST_DRIVER: TestDriver = TestDriver;
+
+    let kind = DeviceKind::Isa { io_base: 0, io_size: 0, irq: None };
+    let other = add_device("other-device".to_string(), kind.clone());
+    register_driver(&TEST_DRIVER);
+    let id = add_device("test-device".to_string(), kind);
+    assert_eq!(PROBED.load(Ordering::Relaxed), 1);
+    assert_eq!(driver_of(id), Some("test"));
+    assert_eq!(driver_of(other), None);
+
+    remove_device(id);
+    remove_device(other);
+    assert_eq!(REMOVED.load(Ordering::Relaxed), 1);
+    assert!(device(id).is_none());
+}
@@ -0,0 +1,401 @@
+//! # Virtio
+//!
+//! Virtio devices are paravirtual - QEMU's disks, network cards, consoles and so on, made to be simple to
+//! drive rather than to look like real hardware. Over PCI they all have vendor `0x1AF4`.
+//!
+//! The [virtio-pci driver](struct.VirtioPciDriver.html) binds to those PCI functions, works out what kind of
+//! virtio device each is, and adds it to the registry on the virtio bus - so the driver for that kind (which
+//! matches [DeviceMatch::Virtio](../enum.DeviceMatch.html)) can bind to it. That driver then sets the device
+//! up through a [VirtioDevice](struct.VirtioDevice.html): reset it, agree on features, make its virtqueues,
+//! and tell it the driver is ready.
+//!
+//! Every queue of a device shares one MSI-X interrupt, which processes them all. Without MSI-X the queues are
+//! polled.
+
+pub mod transport; // Reaching the device's registers - legacy (I/O port) and modern (capabilities and MMIO)
+pub mod queue; // Split virtqueues - descriptor chains in DMA memory, and their completion
+pub mod blk; // Disks
+pub mod net; // Network cards
+pub mod console; // Consoles with several ports (log, shell and test channels)
+pub mod rng; // Random number generators
+
+pub use queue::{Buffer, Virtqueue};
+
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use crate::memory::dma::DmaBuffer;
+use crate::serial_println;
+use crate::sync::IrqSpinlock;
+use super::pci::{config, MsiX, PciDevice};
+use super::{registry, Device, DeviceId, DeviceKind, DeviceMatch, Driver, ProbeError};
+use transport::{Transport, NO_VECTOR};
+
+/// The PCI vendor ID of every virtio device
+pub const VENDOR: u16 = 0x1AF4;
+
+/* Device types */
+pub const DEVICE_NET: u16 = 1;
+pub const DEVICE_BLOCK: u16 = 2;
+pub const DEVICE_CONSOLE: u16 = 3;
+pub const DEVICE_ENTROPY: u16 = 4;
+
+/* Device status */
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_NEEDS_RESET: u8 = 64;
+pub const STATUS_FAILED: u8 = 128;
+
+/* Feature bits every device type has */
+/// The device has the modern (virtio 1.0) interface. Required with the modern transport
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+/* PCI device IDs */
+/// Transitional devices - `0x1000` up, with the device type as the subsystem ID
+const TRANSITIONAL_DEVICE_IDS: core::ops::RangeInclusive<u16> = 0x1000..=0x103F;
+/// Modern only devices - `0x1040` plus the device type
+const MODERN_DEVICE_IDS: core::ops::RangeInclusive<u16> = 0x1040..=0x107F;
+
+/// # VirtioError
+///
+/// Why a virtio device couldn't be set up, or a request failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// The registers couldn't be found, or don't make sense
+    BadTransport(&'static str),
+    /// The device didn't accept the features we picked
+    FeaturesRejected,
+    /// The queue doesn't exist, is in use, or can't be the size we asked for
+    QueueUnavailable,
+    /// A chain had more buffers than the queue has descriptors
+    ChainTooLong,
+    /// No DMA memory for the queue
+    OutOfMemory,
+    /// The device says it has failed, or needs resetting
+    DeviceFailed,
+}
+
+impl From<VirtioError> for ProbeError {
+    fn from(err: VirtioError) -> Self {
+        match err {
+            VirtioError::OutOfMemory => ProbeError::OutOfResources,
+            VirtioError::BadTransport(reason) => ProbeError::DeviceError(reason),
+            VirtioError::FeaturesRejected => ProbeError::DeviceError("virtio features rejected"),
+            VirtioError::QueueUnavailable => ProbeError::DeviceError("virtqueue unavailable"),
+            VirtioError::ChainTooLong => ProbeError::DeviceError("virtqueue chain too long"),
+            VirtioError::DeviceFailed => ProbeError::DeviceError("virtio device failed"),
+        }
+    }
+}
+
+/// The virtio device type of a PCI function, if it is a virtio device
+pub fn device_type(function: &PciDevice) -> Option<u16> {
+    if function.vendor_id != VENDOR {
+        return None;
+    }
+    if TRANSITIONAL_DEVICE_IDS.contains(&function.device_id) {
+        Some(function.read_u16(config::SUBSYSTEM_ID))
+    } else if MODERN_DEVICE_IDS.contains(&function.device_id) {
+        Some(function.device_id - 0x1040)
+    } else {
+        None
+    }
+}
+
+/// The queues the interrupt handler looks at
+struct InterruptQueues {
+    queues: IrqSpinlock<Vec<Arc<Virtqueue>>>,
+}
+
+/// The interrupt handler, for every queue of a device. `context` points to its `InterruptQueues`
+fn handle_interrupt(context: usize) {
+    let interrupt = unsafe { &*(context as *const InterruptQueues) };
+    for queue in interrupt.queues.lock().iter() {
+        queue.process();
+    }
+}
+
+/// # VirtioDevice
+///
+/// A virtio device being set up, then driven, by the driver for its type. Dropping it resets the device
+pub struct VirtioDevice {
+    function: PciDevice,
+    device_type: u16,
+    transport: Transport,
+    features: u64,
+    /// Kept alive for as long as the interrupt handler might use it
+    interrupt: Arc<InterruptQueues>,
+    msix: Option<MsiX>,
+}
+
+impl VirtioDevice {
+    /// Reset `device` (a virtio bus device), and say we've found it and have a driver for it
+    pub fn new(device: &Device) -> Result<Self, VirtioError> {
+        let (function, device_type) = match device.kind {
+            DeviceKind::Virtio { function, device_type } => (function, device_type),
+            _ => return Err(VirtioError::BadTransport("not a virtio device")),
+        };
+        let mut transport = Transport::new(&function)?;
+        transport.reset();
+        function.enable_bus_mastering();
+
+        // Enabling MSI-X moves the legacy device config, so it has to happen before anyone reads the config
+        let interrupt = Arc::new(InterruptQueues { queues: IrqSpinlock::new(Vec::new()) });
+        let msix = match function.msix() {
+            Ok(mut msix) => match msix.enable_entry(0, handle_interrupt, Arc::as_ptr(&interrupt) as usize) {
+                Ok(_) => Some(msix),
+                Err(_) => {
+                    msix.disable();
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+        match msix {
+            Some(_) => {
+                transport.set_msix(true);
+                transport.set_config_vector(NO_VECTOR);
+            }
+            None => function.set_interrupt_disable(true), // polled
+        }
+
+        transport.set_status(STATUS_ACKNOWLEDGE);
+        transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        Ok(VirtioDevice { function, device_type, transport, features: 0, interrupt, msix })
+    }
+
+    pub fn device_type(&self) -> u16 {
+        self.device_type
+    }
+
+    pub fn function(&self) -> PciDevice {
+        self.function
+    }
+
+    /// Whether the device uses the legacy transport
+    pub fn is_legacy(&self) -> bool {
+        self.transport.is_legacy()
+    }
+
+    /// Whether used chains interrupt us, rather than being polled for
+    pub fn has_interrupts(&self) -> bool {
+        self.msix.is_some()
+    }
+
+    /// The features the device offers
+    pub fn device_features(&self) -> u64 {
+        self.transport.device_features()
+    }
+
+    /// Agree on features - those in `supported` the device offers (and `FEATURE_VERSION_1`, with the modern
+    /// transport). Returns them
+    pub fn negotiate(&mut self, supported: u64) -> Result<u64, VirtioError> {
+        let offered = self.transport.device_features();
+        let mut features = offered & supported;
+        if !self.transport.is_legacy() {
+            if offered & FEATURE_VERSION_1 == 0 {
+                self.fail();
+                return Err(VirtioError::BadTransport("modern device without VERSION_1"));
+            }
+            features |= FEATURE_VERSION_1;
+        }
+        self.transport.set_driver_features(features);
+        self.features = features;
+
+        // Legacy devices don't have FEATURES_OK - the features are just what we last wrote
+        if !self.transport.is_legacy() {
+            self.transport.set_status(self.transport.status() | STATUS_FEATURES_OK);
+            if self.transport.status() & STATUS_FEATURES_OK == 0 {
+                self.fail();
+                return Err(VirtioError::FeaturesRejected);
+            }
+        }
+        Ok(features)
+    }
+
+    /// The features we agreed on
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    pub fn has_feature(&self, feature: u64) -> bool {
+        self.features & feature != 0
+    }
+
+    /// How many queues the device has
+    pub fn queue_count(&self) -> u16 {
+        self.transport.queue_count()
+    }
+
+    /// Make queue `index`, with at most `max_size` entries (legacy queues are the size the device says, and fail
+    /// if that is more than `queue::MAX_QUEUE_SIZE`). Call after `negotiate`, and before `driver_ok`
+    pub fn setup_queue(&mut self, index: u16, max_size: u16) -> Result<Arc<Virtqueue>, VirtioError> {
+        let device_max = self.transport.max_queue_size(index);
+        if device_max == 0 {
+            return Err(VirtioError::QueueUnavailable);
+        }
+        let size = match self.transport.is_legacy() {
+            true if device_max > queue::MAX_QUEUE_SIZE => return Err(VirtioError::QueueUnavailable),
+            true => device_max,
+            // Modern queues can be any size up to the maximum - we keep to powers of two
+            false => {
+                let wanted = device_max.min(max_size).min(queue::MAX_QUEUE_SIZE);
+                1u16 << (15 - wanted.leading_zeros())
+            }
+        };
+        let layout = queue::layout(size);
+        let memory = DmaBuffer::new(layout.size).ok_or(VirtioError::OutOfMemory)?;
+        let start = memory.phys_addr();
+        let vector = if self.msix.is_some() { 0 } else { NO_VECTOR };
+        let (available, used) = (start + layout.available as u64, start + layout.used as u64);
+        let notifier = self.transport.setup_queue(index, size, start, available, used, vector)?;
+        let queue = Arc::new(Virtqueue::new(index, size, memory, notifier, self.msix.is_some()));
+        self.interrupt.queues.lock().push(queue.clone());
+        Ok(queue)
+    }
+
+    /// Tell the device the driver is ready - it can start using the queues
+    pub fn driver_ok(&self) -> Result<(), VirtioError> {
+        self.transport.set_status(self.transport.status() | STATUS_DRIVER_OK);
+        match self.transport.status() & (STATUS_FAILED | STATUS_NEEDS_RESET) {
+            0 => Ok(()),
+            _ => Err(VirtioError::DeviceFailed),
+        }
+    }
+
+    /// Give up on the device
+    fn fail(&self) {
+        self.transport.set_status(self.transport.status() | STATUS_FAILED);
+    }
+
+    /* Device config. Fields are little endian (legacy ones are the guest's endianness, which is too) */
+
+    pub fn read_config_u8(&self, offset: u16) -> u8 {
+        self.transport.read_config_u8(offset)
+    }
+
+    pub fn read_config_u16(&self, offset: u16) -> u16 {
+        u16::from_le_bytes([self.read_config_u8(offset), self.read_config_u8(offset + 1)])
+    }
+
+    pub fn read_config_u32(&self, offset: u16) -> u32 {
+        let mut bytes = [0; 4];
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.read_config_u8(offset + index as u16);
+        }
+        u32::from_le_bytes(bytes)
+    }
+
+    /// 64 bit fields can change while being read, so read until two reads agree
+    pub fn read_config_u64(&self, offset: u16) -> u64 {
+        loop {
+            let value = self.read_config_u32(offset) as u64 | (self.read_config_u32(offset + 4) as u64) << 32;
+            let again = self.read_config_u32(offset) as u64 | (self.read_config_u32(offset + 4) as u64) << 32;
+            if value == again {
+                return value;
+            }
+        }
+    }
+
+    pub fn write_config_u8(&self, offset: u16, value: u8) {
+        self.transport.write_config_u8(offset, value);
+    }
+
+    pub fn write_config_u16(&self, offset: u16, value: u16) {
+        for (index, byte) in value.to_le_bytes().iter().enumerate() {
+            self.write_config_u8(offset + index as u16, *byte);
+        }
+    }
+
+    pub fn write_config_u32(&self, offset: u16, value: u32) {
+        for (index, byte) in value.to_le_bytes().iter().enumerate() {
+            self.write_config_u8(offset + index as u16, *byte);
+        }
+    }
+}
+
+impl Drop for VirtioDevice {
+    /// Reset the device, so it stops using the queues, before they (and the interrupt) go away
+    fn drop(&mut self) {
+        self.transport.reset();
+        if let Some(mut msix) = self.msix.take() {
+            msix.disable();
+        }
+        self.interrupt.queues.lock().clear();
+    }
+}
+
+/// # VirtioPciDriver
+///
+/// Binds to virtio PCI functions, and adds the virtio device behind each to the registry
+pub struct VirtioPciDriver {
+    /// Each PCI function we're bound to, and the virtio device we added for it
+    devices: Mutex<Vec<(DeviceId, DeviceId)>>,
+    next_device: AtomicUsize,
+}
+
+/// The virtio-pci driver. [driver::init](../fn.init.html) registers it
+pub static VIRTIO_PCI_DRIVER: VirtioPciDriver = VirtioPciDriver { devices: Mutex::new(Vec::new()), next_device: AtomicUsize::new(0) };
+
+impl Driver for VirtioPciDriver {
+    fn name(&self) -> &'static str {
+        "virtio-pci"
+    }
+
+    fn match_table(&self) -> &'static [DeviceMatch] {
+        &[DeviceMatch::PciVendor(VENDOR)]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let function = device.pci_function().ok_or(ProbeError::Unsupported)?;
+        // The vendor has other devices too (like the shared memory device)
+        let device_type = device_type(&function).ok_or(ProbeError::Unsupported)?;
+        let number = self.next_device.fetch_add(1, Ordering::Relaxed);
+        serial_println!("[LOG] virtio{}: type {} at {}", number, device_type, function.address);
+
+        // Adding it binds its driver - probe runs without the registry locked
+        let child = registry::add_device(format!("virtio{}", number), DeviceKind::Virtio { function, device_type });
+        self.devices.lock().push((device.id, child));
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        let child = {
+            let mut devices = self.devices.lock();
+            match devices.iter().position(|(parent, _)| *parent == device.id) {
+                Some(index) => devices.remove(index).1,
+                None => return,
+            }
+        };
+        registry::remove_device(child);
+    }
+}
+
+/* Testing */
+
+#[test_case]
+fn virtio_device_types() {
+    use super::pci::PciAddress;
+    let mut function = PciDevice {
+        address: PciAddress::new(0, 0, 0, 0),
+        vendor_id: VENDOR,
+        device_id: 0x1042,
+        class: 0x01,
+        subclass: 0x00,
+        prog_if: 0,
+        revision: 1,
+        header_type: 0,
+    };
+    assert_eq!(device_type(&function), Some(DEVICE_BLOCK));
+    function.device_id = 0x1041;
+    assert_eq!(device_type(&function), Some(DEVICE_NET));
+    function.device_id = 0x1110; // the shared memory device
+    assert_eq!(device_type(&function), None);
+    function.vendor_id = 0x8086;
+    function.device_id = 0x1042;
+    assert_eq!(device_type(&function), None);
+}
@@ -0,0 +1,298 @@
+//! # Virtio block
+//!
+//! Driver for virtio disks (QEMU's `-drive if=virtio`). A request is a three part chain: a header saying
+//! what to do and where, the data, and a status byte the device writes when it is done. Data goes through a
+//! DMA bounce buffer, with the header and status in the page before it.
+//!
+//! The device always counts in 512 byte sectors. If it has a bigger logical block size (`BLK_SIZE`), that is
+//! the sector size of the block device we register, and we convert. Devices with several queues (`MQ`) get
+//! requests spread over up to `MAX_QUEUES` of them. Disks are registered as `vblk0`, `vblk1`...
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use crate::block::{self, BlockDevice, BlockError, BlockFuture};
+use crate::memory::dma::DmaBuffer;
+use crate::serial_println;
+use super::{Buffer, VirtioDevice, Virtqueue, DEVICE_BLOCK};
+use super::super::{Device, DeviceId, DeviceMatch, Driver, ProbeError};
+
+/* Features */
+/// `size_max` is the biggest a data buffer can be
+const FEATURE_SIZE_MAX: u64 = 1 << 1;
+/// The disk is read only
+const FEATURE_RO: u64 = 1 << 5;
+/// `blk_size` is the logical block size
+const FEATURE_BLK_SIZE: u64 = 1 << 6;
+/// The device has a write cache, and takes flush requests
+const FEATURE_FLUSH: u64 = 1 << 9;
+/// `num_queues` says how many request queues there are
+const FEATURE_MQ: u64 = 1 << 12;
+
+/* Config */
+const CONFIG_CAPACITY: u16 = 0;
+const CONFIG_SIZE_MAX: u16 = 8;
+const CONFIG_BLK_SIZE: u16 = 20;
+const CONFIG_NUM_QUEUES: u16 = 34;
+
+/* Request types */
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+/* Request status */
+const STATUS_OK: u8 = 0;
+const STATUS_UNSUPPORTED: u8 = 2;
+
+/// What the device counts in
+const VIRTIO_SECTOR_SIZE: usize = 512;
+const PAGE_SIZE: usize = 4096;
+/// The header is at the start of a request's buffer, the status after it, and the data on the next page
+const HEADER_SIZE: usize = 16;
+const STATUS_OFFSET: usize = HEADER_SIZE;
+const DATA_OFFSET: usize = PAGE_SIZE;
+/// The most one request moves (the size of its bounce buffer), unless the device says less
+const MAX_TRANSFER: usize = 128 * 1024;
+/// How many request queues we use, at most
+const MAX_QUEUES: u16 = 4;
+/// How many entries we ask for in each queue. Each request takes three
+const QUEUE_SIZE: u16 = 128;
+
+/// The config we care about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DiskInfo {
+    /// In 512 byte sectors
+    capacity: u64,
+    block_size: usize,
+    max_transfer: usize,
+    read_only: bool,
+    flush: bool,
+    queues: u16,
+}
+
+impl DiskInfo {
+    /// Work out the disk's geometry and limits from its config, given the features we agreed on
+    fn new(features: u64, capacity: u64, size_max: u32, blk_size: u32, num_queues: u16) -> Self {
+        let block_size = match features & FEATURE_BLK_SIZE != 0 && blk_size as usize >= VIRTIO_SECTOR_SIZE {
+            true if (blk_size as usize).is_power_of_two() && blk_size as usize <= PAGE_SIZE => blk_size as usize,
+            _ => VIRTIO_SECTOR_SIZE,
+        };
+        let mut max_transfer = MAX_TRANSFER;
+        if features & FEATURE_SIZE_MAX != 0 && size_max != 0 {
+            max_transfer = max_transfer.min(size_max as usize);
+        }
+        DiskInfo {
+            capacity,
+            block_size,
+            // At least one block, and a whole number of them
+            max_transfer: (max_transfer / block_size).max(1) * block_size,
+            read_only: features & FEATURE_RO != 0,
+            flush: features & FEATURE_FLUSH != 0,
+            queues: if features & FEATURE_MQ != 0 { num_queues.max(1).min(MAX_QUEUES) } else { 1 },
+        }
+    }
+
+    /// How many of our blocks the disk has
+    fn blocks(&self) -> u64 {
+        self.capacity / (self.block_size / VIRTIO_SECTOR_SIZE) as u64
+    }
+}
+
+/// # VirtioBlk
+///
+/// A virtio disk, as a block device. Dropping it resets the device
+pub struct VirtioBlk {
+    name: String,
+    info: DiskInfo,
+    queues: Vec<Arc<Virtqueue>>,
+    /// Which queue the next request goes to
+    next_queue: AtomicUsize,
+    /// Kept so the device is reset when the disk goes away
+    _device: VirtioDevice,
+}
+
+impl VirtioBlk {
+    /// Send a request of `kind` at `block`, with `length` bytes of data starting at `DATA_OFFSET` in `buffer`
+    async fn request(&self, kind: u32, block: u64, buffer: &DmaBuffer, length: usize) -> Result<(), BlockError> {
+        let sector = block * (self.info.block_size / VIRTIO_SECTOR_SIZE) as u64;
+        unsafe {
+            ptr::write_volatile(buffer.ptr::<u32>(0), kind);
+            ptr::write_volatile(buffer.ptr::<u32>(4), 0);
+            ptr::write_volatile(buffer.ptr::<u64>(8), sector);
+            ptr::write_volatile(buffer.ptr::<u8>(STATUS_OFFSET), 0xFF);
+        }
+
+        let start = buffer.phys_addr();
+        let header = Buffer { address: start, length: HEADER_SIZE as u32, writable: false };
+        let status = Buffer { address: start + STATUS_OFFSET as u64, length: 1, writable: true };
+        let data = Buffer { address: start + DATA_OFFSET as u64, length: length as u32, writable: kind == REQUEST_IN };
+        let (with_data, without_data) = ([header, data, status], [header, status]);
+        let chain: &[Buffer] = if length == 0 { &without_data } else { &with_data };
+
+        let queue = &self.queues[self.next_queue.fetch_add(1, Ordering::Relaxed) % self.queues.len()];
+        queue.submit(chain).await.map_err(|_| BlockError::DeviceError("virtio request too long"))?;
+        match unsafe { ptr::read_volatile(buffer.ptr::<u8>(STATUS_OFFSET)) } {
+            STATUS_OK => Ok(()),
+            STATUS_UNSUPPORTED => Err(BlockError::DeviceError("virtio-blk request unsupported")),
+            _ => Err(BlockError::DeviceError("virtio-blk I/O error")),
+        }
+    }
+
+    /// A bounce buffer for transfers of up to `length` bytes, with room for the header and status
+    fn bounce_buffer(&self, length: usize) -> Result<DmaBuffer, BlockError> {
+        DmaBuffer::new(DATA_OFFSET + length.min(self.info.max_transfer)).ok_or(BlockError::DeviceError("out of DMA memory"))
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        self.info.block_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.info.blocks()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.info.read_only
+    }
+
+    fn read<'a>(&'a self, sector: u64, buffer: &'a mut [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            block::check_request(self, sector, buffer.len())?;
+            let chunk_size = self.info.max_transfer;
+            let bounce = self.bounce_buffer(buffer.len())?;
+            for (index, chunk) in buffer.chunks_mut(chunk_size).enumerate() {
+                let block = sector + (index * chunk_size / self.info.block_size) as u64;
+                self.request(REQUEST_IN, block, &bounce, chunk.len()).await?;
+                chunk.copy_from_slice(&bounce.as_slice()[DATA_OFFSET..DATA_OFFSET + chunk.len()]);
+            }
+            Ok(())
+        })
+    }
+
+    fn write<'a>(&'a self, sector: u64, buffer: &'a [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            if self.info.read_only {
+                return Err(BlockError::ReadOnly);
+            }
+            block::check_request(self, sector, buffer.len())?;
+            let chunk_size = self.info.max_transfer;
+            let mut bounce = self.bounce_buffer(buffer.len())?;
+            for (index, chunk) in buffer.chunks(chunk_size).enumerate() {
+                let block = sector + (index * chunk_size / self.info.block_size) as u64;
+                bounce.as_mut_slice()[DATA_OFFSET..DATA_OFFSET + chunk.len()].copy_from_slice(chunk);
+                self.request(REQUEST_OUT, block, &bounce, chunk.len()).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> BlockFuture<'_, ()> {
+        Box::pin(async move {
+            // Without FLUSH the device has no write cache - writes are done when they complete
+            if !self.info.flush {
+                return Ok(());
+            }
+            let buffer = DmaBuffer::new(HEADER_SIZE + 1).ok_or(BlockError::DeviceError("out of DMA memory"))?;
+            self.request(REQUEST_FLUSH, 0, &buffer, 0).await
+        })
+    }
+}
+
+/// # VirtioBlkDriver
+///
+/// Binds to virtio disks, and registers each as a block device
+pub struct VirtioBlkDriver {
+    /// Each disk, and its block device's name
+    disks: Mutex<Vec<(DeviceId, String)>>,
+    next_disk: AtomicUsize,
+}
+
+/// The virtio-blk driver. [driver::init](../../fn.init.html) registers it
+pub static VIRTIO_BLK_DRIVER: VirtioBlkDriver = VirtioBlkDriver { disks: Mutex::new(Vec::new()), next_disk: AtomicUsize::new(0) };
+
+impl Driver for VirtioBlkDriver {
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+
+    fn match_table(&self) -> &'static [DeviceMatch] {
+        &[DeviceMatch::Virtio(DEVICE_BLOCK)]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let mut virtio = VirtioDevice::new(device)?;
+        let features = virtio.negotiate(FEATURE_SIZE_MAX | FEATURE_RO | FEATURE_BLK_SIZE | FEATURE_FLUSH | FEATURE_MQ)?;
+        let info = DiskInfo::new(
+            features,
+            virtio.read_config_u64(CONFIG_CAPACITY),
+            virtio.read_config_u32(CONFIG_SIZE_MAX),
+            virtio.read_config_u32(CONFIG_BLK_SIZE),
+            virtio.read_config_u16(CONFIG_NUM_QUEUES),
+        );
+        let queues = (0..info.queues).map(|index| virtio.setup_queue(index, QUEUE_SIZE)).collect::<Result<Vec<_>, _>>()?;
+        virtio.driver_ok()?;
+
+        let name = format!("vblk{}", self.next_disk.fetch_add(1, Ordering::Relaxed));
+        serial_println!(
+            "[LOG] {}: {} byte blocks, {} queue(s){}{}",
+            name,
+            info.block_size,
+            queues.len(),
+            if info.read_only { ", read only" } else { "" },
+            if virtio.has_interrupts() { "" } else { ", polled" }
+        );
+        let disk = VirtioBlk { name: name.clone(), info, queues, next_queue: AtomicUsize::new(0), _device: virtio };
+        block::register(Arc::new(disk));
+        self.disks.lock().push((device.id, name));
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        let mut disks = self.disks.lock();
+        if let Some(index) = disks.iter().position(|(id, _)| *id == device.id) {
+            let (_, name) = disks.remove(index);
+            // The device is reset when the last user of the disk lets go of it
+            block::unregister(&name);
+        }
+    }
+}
+
+/* Testing */
+
+#[test_case]
+fn virtio_blk_disk_info() {
+    // No optional features - 512 byte blocks, one queue, our own transfer limit
+    let plain = DiskInfo::new(0, 2048, 4096, 4096, 8);
+    assert_eq!(plain, DiskInfo {
+        capacity: 2048,
+        block_size: 512,
+        max_transfer: MAX_TRANSFER,
+        read_only: false,
+        flush: false,
+        queues: 1,
+    });
+    assert_eq!(plain.blocks(), 2048);
+
+    // 4 KiB blocks, a 6000 byte size limit (rounded down to a block) and more queues than we use
+    let features = FEATURE_BLK_SIZE | FEATURE_SIZE_MAX | FEATURE_MQ | FEATURE_RO | FEATURE_FLUSH;
+    let info = DiskInfo::new(features, 2048, 6000, 4096, 8);
+    assert_eq!(info.block_size, 4096);
+    assert_eq!(info.blocks(), 256);
+    assert_eq!(info.max_transfer, 4096);
+    assert_eq!(info.queues, MAX_QUEUES);
+    assert!(info.read_only && info.flush);
+
+    // A block size that isn't a power of two is ignored
+    assert_eq!(DiskInfo::new(FEATURE_BLK_SIZE, 2048, 0, 1000, 0).block_size, 512);
+}
@@ -0,0 +1,559 @@
+//! # Virtio console
+//!
+//! Driver for virtio consoles (QEMU's `virtio-serial-pci`, with `virtserialport`s and `virtconsole`s on it).
+//! Each port is a byte stream to the host, with a receive and a transmit queue. With `MULTIPORT` there can be
+//! many ports, and a pair of control queues the device uses to announce them - their names, whether the host
+//! end is open - and we use to say which ones we're ready for.
+//!
+//! Ports are found by name. The kernel uses three [channels](enum.Channel.html): everything printed to serial
+//! is copied to `dbos.log`, test results go to `dbos.test`, and `dbos.shell` is for an interactive shell. A
+//! QEMU command line to see them is something like
+//!
+//! ```text
+//! -device virtio-serial-pci -chardev file,id=log,path=log.txt -device virtserialport,chardev=log,name=dbos.log
+//! ```
+//!
+//! Writes can wait for the device, or spin until it is done (for printing, which can't wait).
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use crate::memory::dma::DmaBuffer;
+use crate::sync::AsyncMutex;
+use crate::{serial, serial_println, smp};
+use super::{Buffer, VirtioDevice, Virtqueue, DEVICE_CONSOLE};
+use super::super::{Device, DeviceId, DeviceMatch, Driver, ProbeError};
+
+/* Features */
+/// There are several ports, and control queues
+const FEATURE_MULTIPORT: u64 = 1 << 1;
+
+/* Config */
+const CONFIG_MAX_PORTS: u16 = 4;
+
+/* Control events */
+const CONTROL_DEVICE_READY: u16 = 0;
+const CONTROL_DEVICE_ADD: u16 = 1;
+const CONTROL_DEVICE_REMOVE: u16 = 2;
+const CONTROL_PORT_READY: u16 = 3;
+const CONTROL_CONSOLE_PORT: u16 = 4;
+const CONTROL_PORT_OPEN: u16 = 6;
+const CONTROL_PORT_NAME: u16 = 7;
+/// A control message is a port ID, an event and a value. A name follows `PORT_NAME`
+const CONTROL_SIZE: usize = 8;
+
+/// The control queues. Port 0 has queues 0 and 1, and port `n` has `2n + 2` and `2n + 3`
+const CONTROL_RECEIVE_QUEUE: u16 = 2;
+const CONTROL_TRANSMIT_QUEUE: u16 = 3;
+
+const PAGE_SIZE: usize = 4096;
+/// How many ports we drive, at most
+const MAX_PORTS: u32 = 8;
+/// How many pages we keep in each port's receive queue, and the control receive queue
+const RECEIVE_BUFFERS: usize = 4;
+const CONTROL_BUFFERS: usize = 8;
+/// How many entries we ask for in each queue
+const QUEUE_SIZE: u16 = 32;
+/// How long the device gets to announce its ports when we probe it, and how long it has to go quiet after
+const ANNOUNCE_POLLS: usize = 100;
+const QUIET_POLLS: usize = 5;
+const POLL_DELAY_US: u64 = 1000;
+/// Printed text is sent to the log port a line at a time, or when this much builds up
+const LOG_LINE_SIZE: usize = 256;
+/// How many times a blocking write checks whether the device has taken the data, before dropping it
+const WRITE_SPIN_LIMIT: usize = 100_000;
+
+fn receive_queue(port: u32) -> u16 {
+    match port {
+        0 => 0,
+        port => 2 * port as u16 + 2,
+    }
+}
+
+/// # Channel
+///
+/// What the kernel uses console ports for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    /// A copy of everything printed to serial
+    Log,
+    /// An interactive shell
+    Shell,
+    /// Test results
+    Test,
+}
+
+impl Channel {
+    /// The name of the port for the channel
+    pub fn port_name(self) -> &'static str {
+        match self {
+            Channel::Log => "dbos.log",
+            Channel::Shell => "dbos.shell",
+            Channel::Test => "dbos.test",
+        }
+    }
+}
+
+/// # ConsoleError
+///
+/// Why a console read or write failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleError {
+    /// The device hasn't added the port (or has taken it away)
+    NotReady,
+    /// Someone else is writing, and we can't wait for them
+    Busy,
+    /// The device stopped responding, or we ran out of memory
+    DeviceError(&'static str),
+}
+
+/// The page for writes that can't wait, and the chain of the last one if the device never took it
+struct Blocking {
+    page: DmaBuffer,
+    abandoned: Option<u16>,
+}
+
+/// Pages waiting for data from the host, in the order they were posted, and data not read yet
+struct Receiving {
+    posted: VecDeque<(u16, DmaBuffer)>,
+    pending: VecDeque<u8>,
+}
+
+/// # ConsolePort
+///
+/// A port of a console - a byte stream to the host
+pub struct ConsolePort {
+    id: u32,
+    name: Mutex<Option<String>>,
+    /// Whether the device has added the port
+    ready: AtomicBool,
+    /// Whether something is connected at the host end
+    host_open: AtomicBool,
+    /// Whether the host uses it as a console (`virtconsole` rather than `virtserialport`)
+    console: AtomicBool,
+    receive_queue: Arc<Virtqueue>,
+    transmit_queue: Arc<Virtqueue>,
+    receiving: AsyncMutex<Receiving>,
+    /// For writes that can't wait
+    blocking: Mutex<Blocking>,
+}
+
+impl ConsolePort {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The name the host gave the port, if it gave it one
+    pub fn name(&self) -> Option<String> {
+        self.name.lock().clone()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    pub fn is_host_open(&self) -> bool {
+        self.host_open.load(Ordering::Acquire)
+    }
+
+    pub fn is_console(&self) -> bool {
+        self.console.load(Ordering::Acquire)
+    }
+
+    /// Give the device `page` to receive into
+    fn post(&self, receiving: &mut Receiving, page: DmaBuffer) {
+        // We never post more pages than the queue has room for
+        let head = self.receive_queue.add(&[Buffer::writable(&page, PAGE_SIZE)]).unwrap();
+        receiving.posted.push_back((head, page));
+    }
+
+    /// Send `data` to the host
+    pub async fn write(&self, data: &[u8]) -> Result<(), ConsoleError> {
+        if !self.is_ready() {
+            return Err(ConsoleError::NotReady);
+        }
+        let mut bounce = DmaBuffer::new(data.len().min(PAGE_SIZE)).ok_or(ConsoleError::DeviceError("out of DMA memory"))?;
+        for chunk in data.chunks(bounce.size()) {
+            bounce.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+            let request = [Buffer::readable(&bounce, chunk.len())];
+            self.transmit_queue.submit(&request).await.map_err(|_| ConsoleError::DeviceError("virtio console write failed"))?;
+        }
+        Ok(())
+    }
+
+    /// Send `data` to the host, spinning until the device has it. Fails with `Busy` rather than wait for
+    /// another write, and doesn't allocate, so it is safe anywhere - even in an interrupt handler. If the device
+    /// doesn't take the data within `WRITE_SPIN_LIMIT` polls, it is dropped
+    pub fn write_blocking(&self, data: &[u8]) -> Result<(), ConsoleError> {
+        if !self.is_ready() {
+            return Err(ConsoleError::NotReady);
+        }
+        let mut blocking = self.blocking.try_lock().ok_or(ConsoleError::Busy)?;
+        // The page is still the device's until it finishes with a write we gave up on
+        if let Some(head) = blocking.abandoned {
+            self.transmit_queue.try_take(head).ok_or(ConsoleError::Busy)?;
+            blocking.abandoned = None;
+        }
+        for chunk in data.chunks(PAGE_SIZE) {
+            blocking.page.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+            let request = [Buffer::readable(&blocking.page, chunk.len())];
+            let head = self.transmit_queue.add(&request).ok_or(ConsoleError::Busy)?;
+            self.transmit_queue.notify();
+            let mut spins = 0;
+            while self.transmit_queue.try_take(head).is_none() {
+                spins += 1;
+                if spins == WRITE_SPIN_LIMIT {
+                    blocking.abandoned = Some(head);
+                    return Err(ConsoleError::DeviceError("virtio console write timed out"));
+                }
+                core::hint::spin_loop();
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for data from the host, and read as much of it as fits in `buffer`. Returns how much that was
+    pub async fn read(&self, buffer: &mut [u8]) -> Result<usize, ConsoleError> {
+        let mut receiving = self.receiving.lock().await;
+        while receiving.pending.is_empty() {
+            // If we are cancelled while waiting, the page stays at the front for the next read
+            let head = receiving.posted.front().map(|(head, _)| *head).ok_or(ConsoleError::DeviceError("no receive buffers"))?;
+            let length = self.receive_queue.wait(head).await as usize;
+            let (_, page) = receiving.posted.pop_front().unwrap();
+            receiving.pending.extend(page.as_slice()[..length.min(PAGE_SIZE)].iter());
+            self.post(&mut receiving, page);
+            self.receive_queue.notify();
+        }
+        let count = buffer.len().min(receiving.pending.len());
+        for (slot, byte) in buffer.iter_mut().zip(receiving.pending.drain(..count)) {
+            *slot = byte;
+        }
+        Ok(count)
+    }
+}
+
+/// The control queues, the pages posted to the receive one, and a page for sending
+struct Control {
+    receive: Arc<Virtqueue>,
+    transmit: Arc<Virtqueue>,
+    posted: Mutex<VecDeque<(u16, DmaBuffer)>>,
+    sending: Mutex<DmaBuffer>,
+}
+
+/// # VirtioConsole
+///
+/// A virtio console and its ports. Dropping it resets the device
+pub struct VirtioConsole {
+    ports: Vec<Arc<ConsolePort>>,
+    /// Only with `MULTIPORT`
+    control: Option<Control>,
+    _device: VirtioDevice,
+}
+
+impl VirtioConsole {
+    /// Every port the device has added
+    pub fn ports(&self) -> Vec<Arc<ConsolePort>> {
+        self.process_control();
+        self.ports.iter().filter(|port| port.is_ready()).cloned().collect()
+    }
+
+    /// Send a control message, spinning until the device has it
+    fn send_control(&self, id: u32, event: u16, value: u16) {
+        let control = match &self.control {
+            Some(control) => control,
+            None => return,
+        };
+        let page = control.sending.lock();
+        unsafe {
+            ptr::write_volatile(page.ptr::<u32>(0), id);
+            ptr::write_volatile(page.ptr::<u16>(4), event);
+            ptr::write_volatile(page.ptr::<u16>(6), value);
+        }
+        if let Some(head) = control.transmit.add(&[Buffer::readable(&page, CONTROL_SIZE)]) {
+            control.transmit.notify();
+            while control.transmit.try_take(head).is_none() {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Handle the control messages the device has sent. Returns how many there were
+    fn process_control(&self) -> usize {
+        let control = match &self.control {
+            Some(control) => control,
+            None => return 0,
+        };
+        // Someone else is already at it
+        let mut posted = match control.posted.try_lock() {
+            Some(posted) => posted,
+            None => return 0,
+        };
+        let mut handled = 0;
+        while let Some(head) = posted.front().map(|(head, _)| *head) {
+            let length = match control.receive.try_take(head) {
+                Some(length) => length as usize,
+                None => break,
+            };
+            let (_, page) = posted.pop_front().unwrap();
+            self.handle_control(&page.as_slice()[..length.min(PAGE_SIZE)]);
+            handled += 1;
+            let head = control.receive.add(&[Buffer::writable(&page, PAGE_SIZE)]).unwrap();
+            posted.push_back((head, page));
+        }
+        if handled > 0 {
+            control.receive.notify();
+        }
+        handled
+    }
+
+    fn handle_control(&self, message: &[u8]) {
+        if message.len() < CONTROL_SIZE {
+            return;
+        }
+        let id = u32::from_le_bytes([message[0], message[1], message[2], message[3]]);
+        let event = u16::from_le_bytes([message[4], message[5]]);
+        let value = u16::from_le_bytes([message[6], message[7]]);
+        let port = match self.ports.get(id as usize) {
+            Some(port) => port,
+            None => {
+                // More ports than we drive - say we can't take it
+                if event == CONTROL_DEVICE_ADD {
+                    self.send_control(id, CONTROL_PORT_READY, 0);
+                }
+                return;
+            }
+        };
+        match event {
+            CONTROL_DEVICE_ADD => {
+                port.ready.store(true, Ordering::Release);
+                self.send_control(id, CONTROL_PORT_READY, 1);
+                // We don't wait for anyone to open the port - the kernel always has it open
+                self.send_control(id, CONTROL_PORT_OPEN, 1);
+            }
+            CONTROL_DEVICE_REMOVE => {
+                port.ready.store(false, Ordering::Release);
+                port.host_open.store(false, Ordering::Release);
+                *port.name.lock() = None;
+            }
+            CONTROL_CONSOLE_PORT => port.console.store(true, Ordering::Release),
+            CONTROL_PORT_OPEN => port.host_open.store(value != 0, Ordering::Release),
+            CONTROL_PORT_NAME => {
+                let name = &message[CONTROL_SIZE..];
+                let end = name.iter().position(|&byte| byte == 0).unwrap_or(name.len());
+                *port.name.lock() = Some(String::from_utf8_lossy(&name[..end]).into());
+            }
+            _ => {} // resizes, and anything newer
+        }
+    }
+}
+
+/// # VirtioConsoleDriver
+///
+/// Binds to virtio consoles, and finds their ports
+pub struct VirtioConsoleDriver {
+    consoles: Mutex<Vec<(DeviceId, Arc<VirtioConsole>)>>,
+}
+
+/// The virtio-console driver. [driver::init](../../fn.init.html) registers it
+pub static VIRTIO_CONSOLE_DRIVER: VirtioConsoleDriver = VirtioConsoleDriver { consoles: Mutex::new(Vec::new()) };
+
+/// The log port, and the line being put together for it
+static LOG: Mutex<Option<(Arc<ConsolePort>, Vec<u8>)>> = Mutex::new(None);
+
+/// Copy printed text to the log port. Given to [serial::set_mirror](../../../serial/fn.set_mirror.html)
+fn mirror_log(text: &str) {
+    // Printing from an interrupt handler while the line is being sent - leave it to serial
+    let mut log = match LOG.try_lock() {
+        Some(log) => log,
+        None => return,
+    };
+    if let Some((port, line)) = log.as_mut() {
+        for &byte in text.as_bytes() {
+            line.push(byte); // sent before it outgrows its capacity, so this never allocates
+            if byte == b'\n' || line.len() == LOG_LINE_SIZE {
+                let _ = port.write_blocking(line);
+                line.clear();
+            }
+        }
+    }
+}
+
+/// Every port, of every console
+pub fn ports() -> Vec<Arc<ConsolePort>> {
+    let consoles: Vec<Arc<VirtioConsole>> = VIRTIO_CONSOLE_DRIVER.consoles.lock().iter().map(|(_, console)| console.clone()).collect();
+    consoles.iter().flat_map(|console| console.ports()).collect()
+}
+
+/// The port called `name`
+pub fn port(name: &str) -> Option<Arc<ConsolePort>> {
+    ports().into_iter().find(|port| port.name().as_deref() == Some(name))
+}
+
+/// The port for `channel`
+pub fn channel(channel: Channel) -> Option<Arc<ConsolePort>> {
+    port(channel.port_name())
+}
+
+/// Write `text` to `channel`'s port if there is one, without waiting for anything - nothing happens if
+/// something is in the way. For reporting from places that can't wait, like the test runner
+pub fn write_channel(channel: Channel, text: &str) {
+    let consoles = match VIRTIO_CONSOLE_DRIVER.consoles.try_lock() {
+        Some(consoles) => consoles,
+        None => return,
+    };
+    let wanted = Some(channel.port_name());
+    for (_, console) in consoles.iter() {
+        for port in console.ports.iter().filter(|port| port.is_ready()) {
+            let is_wanted = match port.name.try_lock() {
+                Some(name) => name.as_deref() == wanted,
+                None => false,
+            };
+            if is_wanted {
+                let _ = port.write_blocking(text.as_bytes());
+                return;
+            }
+        }
+    }
+}
+
+impl VirtioConsoleDriver {
+    /// A port's queues, with pages posted to its receive queue
+    fn setup_port(virtio: &mut VirtioDevice, id: u32) -> Result<ConsolePort, ProbeError> {
+        let receive_queue = virtio.setup_queue(receive_queue(id), QUEUE_SIZE)?;
+        let transmit_queue = virtio.setup_queue(receive_queue(id) + 1, QUEUE_SIZE)?;
+        let port = ConsolePort {
+            id,
+            name: Mutex::new(None),
+            ready: AtomicBool::new(false),
+            host_open: AtomicBool::new(false),
+            console: AtomicBool::new(false),
+            receive_queue,
+            transmit_queue,
+            receiving: AsyncMutex::new(Receiving { posted: VecDeque::new(), pending: VecDeque::new() }),
+            blocking: Mutex::new(Blocking {
+                page: DmaBuffer::new(PAGE_SIZE).ok_or(ProbeError::OutOfResources)?,
+                abandoned: None,
+            }),
+        };
+        {
+            let mut receiving = port.receiving.try_lock().unwrap();
+            for _ in 0..RECEIVE_BUFFERS.min(port.receive_queue.size() as usize) {
+                port.post(&mut receiving, DmaBuffer::new(PAGE_SIZE).ok_or(ProbeError::OutOfResources)?);
+            }
+        }
+        Ok(port)
+    }
+}
+
+impl Driver for VirtioConsoleDriver {
+    fn name(&self) -> &'static str {
+        "virtio-console"
+    }
+
+    fn match_table(&self) -> &'static [DeviceMatch] {
+        &[DeviceMatch::Virtio(DEVICE_CONSOLE)]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let mut virtio = VirtioDevice::new(device)?;
+        let multiport = virtio.negotiate(FEATURE_MULTIPORT)? & FEATURE_MULTIPORT != 0;
+        let port_count = match multiport {
+            true => virtio.read_config_u32(CONFIG_MAX_PORTS).max(1).min(MAX_PORTS),
+            false => 1,
+        };
+
+        let mut ports = Vec::new();
+        for id in 0..port_count {
+            ports.push(Arc::new(Self::setup_port(&mut virtio, id)?));
+        }
+        let control = match multiport {
+            true => {
+                let receive = virtio.setup_queue(CONTROL_RECEIVE_QUEUE, QUEUE_SIZE)?;
+                let transmit = virtio.setup_queue(CONTROL_TRANSMIT_QUEUE, QUEUE_SIZE)?;
+                let mut posted = VecDeque::new();
+                for _ in 0..CONTROL_BUFFERS.min(receive.size() as usize) {
+                    let page = DmaBuffer::new(PAGE_SIZE).ok_or(ProbeError::OutOfResources)?;
+                    posted.push_back((receive.add(&[Buffer::writable(&page, PAGE_SIZE)]).unwrap(), page));
+                }
+                let sending = Mutex::new(DmaBuffer::new(PAGE_SIZE).ok_or(ProbeError::OutOfResources)?);
+                Some(Control { receive, transmit, posted: Mutex::new(posted), sending })
+            }
+            false => None,
+        };
+        virtio.driver_ok()?;
+
+        let console = Arc::new(VirtioConsole { ports, control, _device: virtio });
+        for port in console.ports.iter() {
+            port.receive_queue.notify();
+        }
+        match &console.control {
+            Some(control) => {
+                control.receive.notify();
+                console.send_control(0, CONTROL_DEVICE_READY, 1);
+                // The device announces its ports in reply. Give it a moment
+                let mut quiet = 0;
+                for _ in 0..ANNOUNCE_POLLS {
+                    quiet = if console.process_control() > 0 { 0 } else { quiet + 1 };
+                    if quiet >= QUIET_POLLS && console.ports.iter().any(|port| port.is_ready()) {
+                        break;
+                    }
+                    smp::delay_us(POLL_DELAY_US);
+                }
+            }
+            None => {
+                // A single port console is always there
+                console.ports[0].ready.store(true, Ordering::Release);
+                console.ports[0].host_open.store(true, Ordering::Release);
+            }
+        }
+
+        for port in console.ports() {
+            let name = port.name();
+            serial_println!(
+                "[LOG] virtio-console: port {} \"{}\"{}",
+                port.id,
+                name.as_deref().unwrap_or(""),
+                if port.is_console() { " (console)" } else { "" }
+            );
+        }
+        if let Some(log) = console.ports().into_iter().find(|port| port.name().as_deref() == Some(Channel::Log.port_name())) {
+            *LOG.lock() = Some((log, Vec::with_capacity(LOG_LINE_SIZE)));
+            serial::set_mirror(Some(mirror_log));
+        }
+        self.consoles.lock().push((device.id, console));
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        let console = {
+            let mut consoles = self.consoles.lock();
+            match consoles.iter().position(|(id, _)| *id == device.id) {
+                Some(index) => consoles.remove(index).1,
+                None => return,
+            }
+        };
+        let mut log = LOG.lock();
+        let logging_here = match log.as_ref() {
+            Some((port, _)) => console.ports.iter().any(|ours| Arc::ptr_eq(ours, port)),
+            None => false,
+        };
+        if logging_here {
+            serial::set_mirror(None);
+            *log = None;
+        }
+    }
+}
+
+/* Testing */
+
+#[test_case]
+fn virtio_console_queues() {
+    assert_eq!(receive_queue(0), 0);
+    assert_eq!(receive_queue(1), 4);
+    assert_eq!(receive_queue(2), 6);
+    assert_eq!(Channel::Test.port_name(), "dbos.test");
+}
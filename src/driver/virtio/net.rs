@@ -0,0 +1,276 @@
+//! # Virtio network
+//!
+//! Driver for virtio network cards (QEMU's `virtio-net-pci`). Queue 0 receives and queue 1 transmits. Every
+//! frame, either way, comes after a small header about checksums and segmentation offload. The header and
+//! frame are separate descriptors in one page - legacy devices need them apart.
+//!
+//! We keep the receive queue full of pages for the device to fill, and hand frames up in the order they were
+//! posted, putting each page straight back. Checksum offload (`CSUM` and `GUEST_CSUM`) is used if the device
+//! has it: we leave checksums of frames we send for the device to finish, and frames it gives us may say
+//! their checksum was checked - or need finishing, which we do before handing them up.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use crate::memory::dma::DmaBuffer;
+use crate::net::{self, MacAddress, NetError, NetFuture, NetworkDevice, PartialChecksum, Received};
+use crate::serial_println;
+use crate::sync::AsyncMutex;
+use super::{Buffer, VirtioDevice, Virtqueue, DEVICE_NET};
+use super::super::{Device, DeviceId, DeviceMatch, Driver, ProbeError};
+
+/* Features */
+/// The device finishes checksums of frames we send
+const FEATURE_CSUM: u64 = 1 << 0;
+/// We take frames with checksums to finish, or already checked
+const FEATURE_GUEST_CSUM: u64 = 1 << 1;
+/// `mtu` is in the config
+const FEATURE_MTU: u64 = 1 << 3;
+/// `mac` is in the config
+const FEATURE_MAC: u64 = 1 << 5;
+/// `status` (link up) is in the config
+const FEATURE_STATUS: u64 = 1 << 16;
+
+/* Config */
+const CONFIG_MAC: u16 = 0;
+const CONFIG_STATUS: u16 = 6;
+const CONFIG_MTU: u16 = 10;
+const STATUS_LINK_UP: u16 = 1;
+
+/* Header flags */
+/// The checksum at `csum_start + csum_offset` needs finishing
+const HEADER_NEEDS_CSUM: u8 = 1;
+/// The device checked the checksum
+const HEADER_DATA_VALID: u8 = 2;
+/* Header fields */
+const HEADER_FLAGS: usize = 0;
+const HEADER_CSUM_START: usize = 6;
+const HEADER_CSUM_OFFSET: usize = 8;
+/// The header is 10 bytes for legacy devices, and has a buffer count on the end for modern ones
+const LEGACY_HEADER_SIZE: usize = 10;
+const HEADER_SIZE: usize = 12;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+const PAGE_SIZE: usize = 4096;
+/// Where in its page a frame goes - the header is at the start
+const FRAME_OFFSET: usize = 64;
+/// How many pages we keep in the receive queue, at most. Each takes two descriptors
+const RECEIVE_BUFFERS: usize = 64;
+/// How many entries we ask for in each queue
+const QUEUE_SIZE: u16 = 256;
+
+/// Frames waiting to be received - each page's head in the receive queue, in the order they were posted
+struct Receiving {
+    posted: VecDeque<(u16, DmaBuffer)>,
+}
+
+/// # VirtioNet
+///
+/// A virtio network card. Dropping it resets the device
+pub struct VirtioNet {
+    name: String,
+    mac: MacAddress,
+    mtu: usize,
+    header_size: usize,
+    device: VirtioDevice,
+    receive_queue: Arc<Virtqueue>,
+    transmit_queue: Arc<Virtqueue>,
+    receiving: AsyncMutex<Receiving>,
+}
+
+impl VirtioNet {
+    /// The descriptors for the page `buffer` - the header, then the frame
+    fn chain(&self, buffer: &DmaBuffer, frame_length: usize, writable: bool) -> [Buffer; 2] {
+        let start = buffer.phys_addr();
+        [
+            Buffer { address: start, length: self.header_size as u32, writable },
+            Buffer { address: start + FRAME_OFFSET as u64, length: frame_length as u32, writable },
+        ]
+    }
+
+    /// Give the device `buffer` to receive into
+    fn post(&self, receiving: &mut Receiving, buffer: DmaBuffer) {
+        // We never post more pages than the queue has room for
+        let head = self.receive_queue.add(&self.chain(&buffer, PAGE_SIZE - FRAME_OFFSET, true)).unwrap();
+        receiving.posted.push_back((head, buffer));
+    }
+}
+
+impl NetworkDevice for VirtioNet {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    fn link_up(&self) -> bool {
+        // Without STATUS the link is always up
+        !self.device.has_feature(FEATURE_STATUS) || self.device.read_config_u16(CONFIG_STATUS) & STATUS_LINK_UP != 0
+    }
+
+    fn checksum_offload(&self) -> bool {
+        self.device.has_feature(FEATURE_CSUM)
+    }
+
+    fn send<'a>(&'a self, frame: &'a [u8], checksum: Option<PartialChecksum>) -> NetFuture<'a, ()> {
+        Box::pin(async move {
+            net::check_frame(self, frame)?;
+            if !self.link_up() {
+                return Err(NetError::LinkDown);
+            }
+            let mut buffer = DmaBuffer::new(PAGE_SIZE).ok_or(NetError::DeviceError("out of DMA memory"))?;
+            let page = buffer.as_mut_slice();
+            page[FRAME_OFFSET..FRAME_OFFSET + frame.len()].copy_from_slice(frame);
+            match checksum {
+                Some(checksum) if self.checksum_offload() => {
+                    page[HEADER_FLAGS] = HEADER_NEEDS_CSUM;
+                    page[HEADER_CSUM_START..HEADER_CSUM_START + 2].copy_from_slice(&(checksum.start as u16).to_le_bytes());
+                    page[HEADER_CSUM_OFFSET..HEADER_CSUM_OFFSET + 2].copy_from_slice(&(checksum.offset as u16).to_le_bytes());
+                }
+                Some(checksum) => net::finish_checksum(&mut page[FRAME_OFFSET..FRAME_OFFSET + frame.len()], checksum),
+                None => {}
+            }
+            let chain = self.chain(&buffer, frame.len(), false);
+            self.transmit_queue.submit(&chain).await.map_err(|_| NetError::DeviceError("virtio transmit failed"))?;
+            Ok(())
+        })
+    }
+
+    fn receive(&self) -> NetFuture<'_, Received> {
+        Box::pin(async move {
+            let mut receiving = self.receiving.lock().await;
+            loop {
+                // If we are cancelled while waiting, the page stays at the front for the next receive
+                let head = receiving.posted.front().map(|(head, _)| *head).ok_or(NetError::DeviceError("no receive buffers"))?;
+                let length = self.receive_queue.wait(head).await as usize;
+                let (_, buffer) = receiving.posted.pop_front().unwrap();
+
+                let page = buffer.as_slice();
+                let frame_length = length.saturating_sub(self.header_size);
+                let received = match frame_length >= net::ETHERNET_HEADER_SIZE {
+                    true => {
+                        let mut frame = page[FRAME_OFFSET..FRAME_OFFSET + frame_length].to_vec();
+                        let flags = page[HEADER_FLAGS];
+                        if flags & HEADER_NEEDS_CSUM != 0 {
+                            let field = |offset: usize| u16::from_le_bytes([page[offset], page[offset + 1]]) as usize;
+                            let checksum = PartialChecksum { start: field(HEADER_CSUM_START), offset: field(HEADER_CSUM_OFFSET) };
+                            net::finish_checksum(&mut frame, checksum);
+                        }
+                        Some(Received { frame, checksum_valid: flags & (HEADER_NEEDS_CSUM | HEADER_DATA_VALID) != 0 })
+                    }
+                    false => None, // too short to be a frame - skip it
+                };
+
+                let mut buffer = buffer;
+                buffer.as_mut_slice()[..self.header_size].iter_mut().for_each(|byte| *byte = 0);
+                self.post(&mut receiving, buffer);
+                self.receive_queue.notify();
+                if let Some(received) = received {
+                    return Ok(received);
+                }
+            }
+        })
+    }
+}
+
+/// # VirtioNetDriver
+///
+/// Binds to virtio network cards, and registers each as a network device
+pub struct VirtioNetDriver {
+    /// Each card, and its network device's name
+    cards: Mutex<Vec<(DeviceId, String)>>,
+    next_card: AtomicUsize,
+}
+
+/// The virtio-net driver. [driver::init](../../fn.init.html) registers it
+pub static VIRTIO_NET_DRIVER: VirtioNetDriver = VirtioNetDriver { cards: Mutex::new(Vec::new()), next_card: AtomicUsize::new(0) };
+
+impl Driver for VirtioNetDriver {
+    fn name(&self) -> &'static str {
+        "virtio-net"
+    }
+
+    fn match_table(&self) -> &'static [DeviceMatch] {
+        &[DeviceMatch::Virtio(DEVICE_NET)]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let mut virtio = VirtioDevice::new(device)?;
+        let features =
+            virtio.negotiate(FEATURE_CSUM | FEATURE_GUEST_CSUM | FEATURE_MTU | FEATURE_MAC | FEATURE_STATUS)?;
+        let number = self.next_card.fetch_add(1, Ordering::Relaxed);
+        let mac = match features & FEATURE_MAC != 0 {
+            true => {
+                let mut mac = [0; 6];
+                for (index, byte) in mac.iter_mut().enumerate() {
+                    *byte = virtio.read_config_u8(CONFIG_MAC + index as u16);
+                }
+                MacAddress(mac)
+            }
+            // Make one up - locally administered, so it can't clash with a real card
+            false => MacAddress([0x02, 0, 0, 0, 0, number as u8]),
+        };
+        // Frames have to fit in a page after the header
+        let largest = PAGE_SIZE - FRAME_OFFSET - net::ETHERNET_HEADER_SIZE;
+        let mtu = match features & FEATURE_MTU != 0 {
+            true => (virtio.read_config_u16(CONFIG_MTU) as usize).min(largest),
+            false => net::DEFAULT_MTU,
+        };
+        let header_size = if virtio.is_legacy() { LEGACY_HEADER_SIZE } else { HEADER_SIZE };
+
+        let receive_queue = virtio.setup_queue(RECEIVE_QUEUE, QUEUE_SIZE)?;
+        let transmit_queue = virtio.setup_queue(TRANSMIT_QUEUE, QUEUE_SIZE)?;
+        virtio.driver_ok()?;
+
+        let buffers = RECEIVE_BUFFERS.min(receive_queue.size() as usize / 2);
+        let card = VirtioNet {
+            name: net::next_name(),
+            mac,
+            mtu,
+            header_size,
+            device: virtio,
+            receive_queue,
+            transmit_queue,
+            receiving: AsyncMutex::new(Receiving { posted: VecDeque::new() }),
+        };
+        {
+            let mut receiving = card.receiving.try_lock().unwrap();
+            for _ in 0..buffers {
+                let buffer = DmaBuffer::new(PAGE_SIZE).ok_or(ProbeError::OutOfResources)?;
+                card.post(&mut receiving, buffer);
+            }
+        }
+        card.receive_queue.notify();
+
+        serial_println!(
+            "[LOG] {}: virtio-net{}{}",
+            card.name,
+            if card.checksum_offload() { ", checksum offload" } else { "" },
+            if card.device.has_interrupts() { "" } else { ", polled" }
+        );
+        let name = card.name.clone();
+        net::register(Arc::new(card));
+        self.cards.lock().push((device.id, name));
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        let mut cards = self.cards.lock();
+        if let Some(index) = cards.iter().position(|(id, _)| *id == device.id) {
+            let (_, name) = cards.remove(index);
+            // The device is reset when the last user of the card lets go of it
+            net::unregister(&name);
+        }
+    }
+}
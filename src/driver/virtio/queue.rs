@@ -0,0 +1,369 @@
+//! # Virtqueues
+//!
+//! A split virtqueue is three rings in DMA memory:
+//!
+//! * The descriptor table - each entry is a buffer (physical address, length, whether the device writes it),
+//!   chained to the next with a flag. A request is a chain: what the device reads, then what it writes
+//! * The available ring - the heads of chains we've given the device, and how many we've given in total
+//! * The used ring - the heads of chains the device has finished, with how much it wrote, and a count
+//!
+//! We add a chain, bump the available index and notify the device. When it is done, it puts the head in the
+//! used ring and interrupts us, and [process](struct.Virtqueue.html#method.process) wakes the task waiting
+//! for that head. The chain's descriptors are freed by the task, not the interrupt handler.
+
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use core::task::{Context, Poll, Waker};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::PhysAddr;
+use crate::memory::dma::DmaBuffer;
+use crate::sync::IrqSpinlock;
+use super::transport::Notifier;
+use super::VirtioError;
+
+/// The biggest queue we make. Legacy queues are the size the device says - `setup_queue` turns down any
+/// bigger than this (QEMU's are at most this)
+pub const MAX_QUEUE_SIZE: u16 = 256;
+
+const DESCRIPTOR_SIZE: usize = 16;
+/// The used ring starts on a page - the legacy transport requires it, and it costs the modern one nothing
+const USED_ALIGN: usize = 4096;
+
+/* Descriptor flags */
+const DESCRIPTOR_NEXT: u16 = 1;
+const DESCRIPTOR_WRITE: u16 = 2;
+
+/// Available ring flag - don't interrupt us when a chain is used
+const AVAILABLE_NO_INTERRUPT: u16 = 1;
+/// Used ring flag - the device doesn't need notifying
+const USED_NO_NOTIFY: u16 = 1;
+
+/// # Layout
+///
+/// Where the available and used rings are, from the start of the descriptor table, and how big the whole
+/// queue is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub available: usize,
+    pub used: usize,
+    pub size: usize,
+}
+
+/// The layout of a queue with `size` entries
+pub fn layout(size: u16) -> Layout {
+    let size = size as usize;
+    let available = size * DESCRIPTOR_SIZE;
+    // flags, index, a ring entry per descriptor, then the used event
+    let used = (available + 6 + 2 * size + USED_ALIGN - 1) / USED_ALIGN * USED_ALIGN;
+    // flags, index, an 8 byte element per descriptor, then the available event
+    Layout { available, used, size: used + 6 + 8 * size }
+}
+
+/// # Buffer
+///
+/// One part of a request - `length` bytes at `address`. `writable` buffers are for the device to fill, and
+/// must come after the ones it reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffer {
+    pub address: PhysAddr,
+    pub length: u32,
+    pub writable: bool,
+}
+
+impl Buffer {
+    /// The first `length` bytes of `buffer`, for the device to read
+    pub fn readable(buffer: &DmaBuffer, length: usize) -> Self {
+        Buffer { address: buffer.phys_addr(), length: length as u32, writable: false }
+    }
+
+    /// The first `length` bytes of `buffer`, for the device to write
+    pub fn writable(buffer: &DmaBuffer, length: usize) -> Self {
+        Buffer { address: buffer.phys_addr(), length: length as u32, writable: true }
+    }
+}
+
+/// The queue's bookkeeping. Shared with the interrupt handler
+struct QueueState {
+    /// Free descriptors. Pushed to in task context only, and never past the queue size, so it doesn't allocate
+    free: Vec<u16>,
+    /// How many chains we've made available
+    available_index: u16,
+    /// How many used entries we've looked at
+    used_index: u16,
+    /// How many bytes the device wrote, for each head it has used that its task hasn't collected
+    completed: Vec<Option<u32>>,
+}
+
+/// # Virtqueue
+///
+/// A split virtqueue. Made by [VirtioDevice::setup_queue](../struct.VirtioDevice.html#method.setup_queue)
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    memory: DmaBuffer,
+    layout: Layout,
+    notifier: Notifier,
+    state: IrqSpinlock<QueueState>,
+    /// The task waiting for each head
+    wakers: Vec<AtomicWaker>,
+    /// Tasks waiting for enough free descriptors
+    descriptor_waiters: Mutex<Vec<Waker>>,
+    /// Whether used chains interrupt us. If not, waiting tasks poll
+    interrupts: bool,
+}
+
+impl Virtqueue {
+    /// Wrap queue `index`'s memory, which is laid out as [layout](fn.layout.html) says for `size` entries, and
+    /// has been given to the device
+    pub(super) fn new(index: u16, size: u16, memory: DmaBuffer, notifier: Notifier, interrupts: bool) -> Self {
+        assert!(size <= MAX_QUEUE_SIZE, "virtqueue bigger than MAX_QUEUE_SIZE"); // `process` keeps a bit per entry
+        let queue = Virtqueue {
+            index,
+            size,
+            memory,
+            layout: layout(size),
+            notifier,
+            state: IrqSpinlock::new(QueueState {
+                free: (0..size).rev().collect(),
+                available_index: 0,
+                used_index: 0,
+                completed: alloc::vec![None; size as usize],
+            }),
+            wakers: (0..size).map(|_| AtomicWaker::new()).collect(),
+            descriptor_waiters: Mutex::new(Vec::new()),
+            interrupts,
+        };
+        if !interrupts {
+            queue.write_u16(queue.layout.available, AVAILABLE_NO_INTERRUPT);
+        }
+        queue
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    /// How many descriptors the queue has - the longest chain it takes
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// How many descriptors are free
+    pub fn free_descriptors(&self) -> usize {
+        self.state.lock().free.len()
+    }
+
+    fn read_u16(&self, offset: usize) -> u16 {
+        unsafe { ptr::read_volatile(self.memory.ptr::<u16>(offset)) }
+    }
+
+    fn write_u16(&self, offset: usize, value: u16) {
+        unsafe { ptr::write_volatile(self.memory.ptr::<u16>(offset), value) }
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile(self.memory.ptr::<u32>(offset)) }
+    }
+
+    /// Put `buffers` in a chain and make it available, without notifying the device. Returns the head, or
+    /// `None` if there aren't enough free descriptors. Doesn't allocate, so it can be used in interrupt handlers
+    pub fn add(&self, buffers: &[Buffer]) -> Option<u16> {
+        assert!(!buffers.is_empty(), "empty virtqueue chain");
+        let mut state = self.state.lock();
+        if state.free.len() < buffers.len() {
+            return None;
+        }
+        // Fill the chain in from the end, so each descriptor can point at the one after it
+        let mut next = None;
+        for buffer in buffers.iter().rev() {
+            let descriptor = state.free.pop().unwrap();
+            let entry = descriptor as usize * DESCRIPTOR_SIZE;
+            let mut flags = if buffer.writable { DESCRIPTOR_WRITE } else { 0 };
+            if next.is_some() {
+                flags |= DESCRIPTOR_NEXT;
+            }
+            unsafe {
+                ptr::write_volatile(self.memory.ptr::<u64>(entry), buffer.address.as_u64());
+                ptr::write_volatile(self.memory.ptr::<u32>(entry + 8), buffer.length);
+            }
+            self.write_u16(entry + 12, flags);
+            self.write_u16(entry + 14, next.unwrap_or(0));
+            next = Some(descriptor);
+        }
+
+        let head = next.unwrap();
+        let slot = state.available_index % self.size;
+        self.write_u16(self.layout.available + 4 + 2 * slot as usize, head);
+        // The device must see the chain and ring entry before the new index
+        fence(Ordering::Release);
+        state.available_index = state.available_index.wrapping_add(1);
+        self.write_u16(self.layout.available + 2, state.available_index);
+        Some(head)
+    }
+
+    /// Tell the device there are new chains, unless it said it doesn't need telling
+    pub fn notify(&self) {
+        fence(Ordering::SeqCst);
+        if self.read_u16(self.layout.used) & USED_NO_NOTIFY == 0 {
+            self.notifier.notify();
+        }
+    }
+
+    /// Look at new used entries, and wake the tasks waiting for them. Called from the interrupt handler, and by
+    /// waiting tasks
+    pub fn process(&self) {
+        let mut used = [0u64; MAX_QUEUE_SIZE as usize / 64];
+        {
+            let mut state = self.state.lock();
+            loop {
+                let device_index = self.read_u16(self.layout.used + 2);
+                if device_index == state.used_index {
+                    break;
+                }
+                // The entry must be read after the index that says it is there
+                fence(Ordering::Acquire);
+                let element = self.layout.used + 4 + 8 * (state.used_index % self.size) as usize;
+                let head = self.read_u32(element) as usize;
+                let length = self.read_u32(element + 4);
+                if head < self.size as usize {
+                    state.completed[head] = Some(length);
+                    used[head / 64] |= 1 << (head % 64);
+                }
+                state.used_index = state.used_index.wrapping_add(1);
+            }
+        }
+        for head in 0..self.size as usize {
+            if used[head / 64] & (1 << (head % 64)) != 0 {
+                self.wakers[head].wake();
+            }
+        }
+    }
+
+    /// Wait for the chain at `head` to be used, then free it. Gives how many bytes the device wrote
+    pub fn wait(&self, head: u16) -> Used<'_> {
+        Used { queue: self, head }
+    }
+
+    /// If the chain at `head` has been used, free it and say how many bytes the device wrote. For code that
+    /// can't wait - like an interrupt handler, or a write that spins until it is done
+    pub fn try_take(&self, head: u16) -> Option<u32> {
+        self.process();
+        let length = {
+            let mut state = self.state.lock();
+            let length = state.completed[head as usize].take()?;
+            self.free_chain(&mut state, head);
+            length
+        };
+        // Only try the lock, so this can't deadlock in an interrupt handler. If it is held, its holder is adding
+        // a waiter, which tries for descriptors again afterwards - and sees the ones we just freed
+        if let Some(mut waiters) = self.descriptor_waiters.try_lock() {
+            for waker in core::mem::take(&mut *waiters) {
+                waker.wake();
+            }
+        }
+        Some(length)
+    }
+
+    /// Put `buffers` in a chain as soon as there are enough free descriptors, and notify the device. Returns
+    /// the head, to [wait](#method.wait) for
+    pub async fn add_notify(&self, buffers: &[Buffer]) -> Result<u16, VirtioError> {
+        if buffers.len() > self.size as usize {
+            return Err(VirtioError::ChainTooLong);
+        }
+        let head = FreeDescriptors { queue: self, buffers }.await;
+        self.notify();
+        Ok(head)
+    }
+
+    /// Send a request and wait for the device to finish it. Returns how many bytes the device wrote
+    pub async fn submit(&self, buffers: &[Buffer]) -> Result<u32, VirtioError> {
+        let head = self.add_notify(buffers).await?;
+        Ok(self.wait(head).await)
+    }
+
+    /// Give the chain at `head` back to the free list
+    fn free_chain(&self, state: &mut QueueState, head: u16) {
+        let mut descriptor = head;
+        loop {
+            state.free.push(descriptor);
+            let entry = descriptor as usize * DESCRIPTOR_SIZE;
+            if self.read_u16(entry + 12) & DESCRIPTOR_NEXT == 0 {
+                break;
+            }
+            descriptor = self.read_u16(entry + 14);
+        }
+    }
+}
+
+/// Waits for enough free descriptors, then adds the chain
+struct FreeDescriptors<'a> {
+    queue: &'a Virtqueue,
+    buffers: &'a [Buffer],
+}
+
+impl<'a> Future for FreeDescriptors<'a> {
+    type Output = u16;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<u16> {
+        if let Some(head) = self.queue.add(self.buffers) {
+            return Poll::Ready(head);
+        }
+        self.queue.descriptor_waiters.lock().push(cx.waker().clone());
+        // Descriptors may have been freed between our try and registering
+        match self.queue.add(self.buffers) {
+            Some(head) => Poll::Ready(head),
+            None => {
+                if !self.queue.interrupts {
+                    self.queue.process();
+                    cx.waker().wake_by_ref(); // chains only get freed if their tasks are polled
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// # Used
+///
+/// Waits for a chain to be used. Gives how many bytes the device wrote
+pub struct Used<'a> {
+    queue: &'a Virtqueue,
+    head: u16,
+}
+
+impl<'a> Future for Used<'a> {
+    type Output = u32;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<u32> {
+        let queue = self.queue;
+        queue.wakers[self.head as usize].register(cx.waker());
+        // The chain may have been used before we registered, or the queue may not interrupt us
+        match queue.try_take(self.head) {
+            Some(length) => Poll::Ready(length),
+            None => {
+                if !queue.interrupts {
+                    cx.waker().wake_by_ref(); // nothing will wake us, so ask to be polled again
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/* Testing */
+
+#[test_case]
+fn virtqueue_layout() {
+    // The legacy layout, as in the virtio spec's example - a 256 entry queue is 4096 + 518, rounded up, + 2054
+    let queue = layout(256);
+    assert_eq!(queue.available, 4096);
+    assert_eq!(queue.used, 8192);
+    assert_eq!(queue.size, 8192 + 6 + 8 * 256);
+    let small = layout(16);
+    assert_eq!(small.available, 256);
+    assert_eq!(small.used, 4096);
+}
@@ -0,0 +1,112 @@
+//! # Virtio entropy
+//!
+//! Driver for virtio random number generators (QEMU's `virtio-rng-pci`). It has one queue: we give it a buffer,
+//! and it fills as much as it likes with random bytes from the host. Each device seeds the kernel's
+//! [entropy pool](../../../entropy/index.html) when it is found, and is registered as a source for reseeding.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use crate::entropy::{self, EntropyFuture, EntropySource};
+use crate::memory::dma::DmaBuffer;
+use crate::serial_println;
+use crate::task::block_on;
+use super::{Buffer, VirtioDevice, Virtqueue, DEVICE_ENTROPY};
+use super::super::{Device, DeviceId, DeviceMatch, Driver, ProbeError};
+
+const PAGE_SIZE: usize = 4096;
+/// How many bytes we seed the pool with when the device is found
+const SEED_BYTES: usize = 64;
+/// How many entries we ask for in the queue
+const QUEUE_SIZE: u16 = 8;
+
+/// # VirtioRng
+///
+/// A virtio random number generator, as an entropy source. Dropping it resets the device
+pub struct VirtioRng {
+    name: String,
+    queue: Arc<Virtqueue>,
+    /// Kept so the device is reset when the source goes away
+    _device: VirtioDevice,
+}
+
+impl EntropySource for VirtioRng {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read<'a>(&'a self, buffer: &'a mut [u8]) -> EntropyFuture<'a> {
+        Box::pin(async move {
+            let bounce = match DmaBuffer::new(buffer.len().min(PAGE_SIZE)) {
+                Some(bounce) => bounce,
+                None => return 0,
+            };
+            let mut filled = 0;
+            while filled < buffer.len() {
+                let wanted = (buffer.len() - filled).min(bounce.size());
+                let written = match self.queue.submit(&[Buffer::writable(&bounce, wanted)]).await {
+                    Ok(written) => (written as usize).min(wanted),
+                    Err(_) => break,
+                };
+                if written == 0 {
+                    break; // nothing more for now
+                }
+                buffer[filled..filled + written].copy_from_slice(&bounce.as_slice()[..written]);
+                filled += written;
+            }
+            filled
+        })
+    }
+}
+
+/// # VirtioRngDriver
+///
+/// Binds to virtio random number generators, seeds the entropy pool from each, and registers them as sources
+pub struct VirtioRngDriver {
+    /// Each device, and its source's name
+    sources: Mutex<Vec<(DeviceId, String)>>,
+    next_source: AtomicUsize,
+}
+
+/// The virtio-rng driver. [driver::init](../../fn.init.html) registers it
+pub static VIRTIO_RNG_DRIVER: VirtioRngDriver = VirtioRngDriver { sources: Mutex::new(Vec::new()), next_source: AtomicUsize::new(0) };
+
+impl Driver for VirtioRngDriver {
+    fn name(&self) -> &'static str {
+        "virtio-rng"
+    }
+
+    fn match_table(&self) -> &'static [DeviceMatch] {
+        &[DeviceMatch::Virtio(DEVICE_ENTROPY)]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let mut virtio = VirtioDevice::new(device)?;
+        virtio.negotiate(0)?;
+        let queue = virtio.setup_queue(0, QUEUE_SIZE)?;
+        virtio.driver_ok()?;
+
+        let name = format!("virtio-rng{}", self.next_source.fetch_add(1, Ordering::Relaxed));
+        let source = VirtioRng { name: name.clone(), queue, _device: virtio };
+        let mut seed = [0u8; SEED_BYTES];
+        let read = block_on(source.read(&mut seed));
+        entropy::add(&seed[..read], read * source.bits_per_byte());
+        serial_println!("[LOG] {}: seeded the entropy pool with {} bytes", name, read);
+
+        entropy::register_source(Arc::new(source));
+        self.sources.lock().push((device.id, name));
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        let mut sources = self.sources.lock();
+        if let Some(index) = sources.iter().position(|(id, _)| *id == device.id) {
+            let (_, name) = sources.remove(index);
+            entropy::unregister_source(&name);
+        }
+    }
+}
@@ -0,0 +1,377 @@
+//! # Virtio PCI transports
+//!
+//! How we reach a virtio device's registers through its PCI function. There are two ways:
+//!
+//! * Legacy (virtio 0.9) - everything is in I/O BAR 0: features, queue setup, status, and then the device
+//!   specific config. Only 32 feature bits, and queues are the size the device says
+//! * Modern (virtio 1.0) - vendor specific capabilities point into memory BARs: a common config structure,
+//!   the notify area, the ISR status, and the device config. 64 feature bits, and we pick queue sizes
+//!
+//! Transitional devices (QEMU's default) have both, and we use the modern one.
+
+use core::ptr;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::instructions::port::Port;
+use crate::memory;
+use crate::driver::pci::{capability, Bar, PciDevice};
+use super::VirtioError;
+
+/* Legacy registers (offsets from I/O BAR 0) */
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+/// The queue's physical page number
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_DEVICE_STATUS: u16 = 0x12;
+const LEGACY_ISR_STATUS: u16 = 0x13;
+/// These two only exist while MSI-X is enabled, and push the device config along by 4
+const LEGACY_CONFIG_VECTOR: u16 = 0x14;
+const LEGACY_QUEUE_VECTOR: u16 = 0x16;
+const LEGACY_CONFIG: u16 = 0x14;
+const LEGACY_CONFIG_MSIX: u16 = 0x18;
+
+/* Modern capability types */
+const CAP_COMMON_CONFIG: u8 = 1;
+const CAP_NOTIFY_CONFIG: u8 = 2;
+const CAP_ISR_CONFIG: u8 = 3;
+const CAP_DEVICE_CONFIG: u8 = 4;
+
+/* Modern capability fields (offsets from the capability) */
+const CAP_TYPE: u16 = 3;
+const CAP_BAR: u16 = 4;
+const CAP_OFFSET: u16 = 8;
+const CAP_LENGTH: u16 = 12;
+const CAP_NOTIFY_MULTIPLIER: u16 = 16;
+
+/* Common config fields */
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0C;
+const COMMON_CONFIG_VECTOR: u64 = 0x10;
+const COMMON_NUM_QUEUES: u64 = 0x12;
+const COMMON_DEVICE_STATUS: u64 = 0x14;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_VECTOR: u64 = 0x1A;
+const COMMON_QUEUE_ENABLE: u64 = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 0x1E;
+const COMMON_QUEUE_DESC: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+/// The MSI-X vector meaning "don't interrupt"
+pub const NO_VECTOR: u16 = 0xFFFF;
+
+/// Legacy queues are given to the device as a page number
+const LEGACY_QUEUE_ALIGN: u64 = 4096;
+
+/// # Notifier
+///
+/// How to tell the device a queue has new buffers
+#[derive(Debug, Clone, Copy)]
+pub enum Notifier {
+    /// Write the queue index to this I/O port
+    Port { port: u16, queue: u16 },
+    /// Write the queue index to this address
+    Mmio { address: VirtAddr, queue: u16 },
+}
+
+impl Notifier {
+    pub fn notify(&self) {
+        match *self {
+            Notifier::Port { port, queue } => unsafe { Port::new(port).write(queue) },
+            Notifier::Mmio { address, queue } => unsafe { ptr::write_volatile(address.as_mut_ptr::<u16>(), queue) },
+        }
+    }
+}
+
+/// The modern transport's structures, mapped
+#[derive(Debug, Clone, Copy)]
+pub struct ModernTransport {
+    common: VirtAddr,
+    notify: VirtAddr,
+    notify_multiplier: u32,
+    isr: VirtAddr,
+    device: VirtAddr,
+}
+
+/// # Transport
+///
+/// A virtio device's registers, through whichever transport it has
+#[derive(Debug, Clone, Copy)]
+pub enum Transport {
+    /// `msix` says whether MSI-X is enabled, which moves the device config
+    Legacy { io_base: u16, msix: bool },
+    Modern(ModernTransport),
+}
+
+impl Transport {
+    /// Find the transport of `function` - modern if it has the capabilities, otherwise legacy
+    pub fn new(function: &PciDevice) -> Result<Self, VirtioError> {
+        if let Some(modern) = Self::modern(function)? {
+            function.enable_memory_space();
+            return Ok(Transport::Modern(modern));
+        }
+        match function.bar(0) {
+            Some(Bar::Io { port, .. }) => {
+                function.enable_io_space();
+                Ok(Transport::Legacy { io_base: port as u16, msix: false })
+            }
+            _ => Err(VirtioError::BadTransport("no modern capabilities or legacy I/O BAR")),
+        }
+    }
+
+    /// Look for the modern capabilities. `None` if there aren't any
+    fn modern(function: &PciDevice) -> Result<Option<ModernTransport>, VirtioError> {
+        let (mut common, mut notify, mut isr, mut device) = (None, None, None, None);
+        let mut notify_multiplier = 0;
+        for capability in function.capabilities().filter(|capability| capability.id == capability::VENDOR_SPECIFIC) {
+            let kind = function.read_u8(capability.offset + CAP_TYPE);
+            let bar = function.read_u8(capability.offset + CAP_BAR);
+            let offset = function.read_u32(capability.offset + CAP_OFFSET) as u64;
+            let length = function.read_u32(capability.offset + CAP_LENGTH) as u64;
+            // Only the first capability of each type counts - later ones are alternatives
+            let slot = match kind {
+                CAP_COMMON_CONFIG => &mut common,
+                CAP_NOTIFY_CONFIG => {
+                    if notify.is_none() {
+                        notify_multiplier = function.read_u32(capability.offset + CAP_NOTIFY_MULTIPLIER);
+                    }
+                    &mut notify
+                }
+                CAP_ISR_CONFIG => &mut isr,
+                CAP_DEVICE_CONFIG => &mut device,
+                _ => continue,
+            };
+            if slot.is_none() {
+                *slot = Some((bar, offset, length));
+            }
+        }
+
+        // The BAR is usually 64 bit and prefetchable, and may be above the physical memory mapping - and these
+        // are registers, so they are mapped uncached
+        let map = |found: Option<(u8, u64, u64)>| -> Result<VirtAddr, VirtioError> {
+            let (bar, offset, length) = found.ok_or(VirtioError::BadTransport("missing virtio capability"))?;
+            match function.bar(bar as usize) {
+                Some(Bar::Memory { address, size, .. }) if offset + length <= size => {
+                    memory::map_device(PhysAddr::new(address + offset), length)
+                        .map_err(|_| VirtioError::BadTransport("couldn't map a virtio capability"))
+                }
+                Some(Bar::Memory { .. }) => Err(VirtioError::BadTransport("virtio capability past the end of its BAR")),
+                _ => Err(VirtioError::BadTransport("virtio capability in a BAR that isn't memory")),
+            }
+        };
+        if common.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(ModernTransport {
+            common: map(common)?,
+            notify: map(notify)?,
+            notify_multiplier,
+            isr: map(isr)?,
+            // Devices without config (like the entropy device) don't need this one
+            device: map(device).unwrap_or_else(|_| VirtAddr::zero()),
+        }))
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        matches!(self, Transport::Legacy { .. })
+    }
+
+    /// Say whether MSI-X is enabled. The legacy transport's registers move when it is
+    pub fn set_msix(&mut self, enabled: bool) {
+        if let Transport::Legacy { msix, .. } = self {
+            *msix = enabled;
+        }
+    }
+
+    pub fn status(&self) -> u8 {
+        match self {
+            Transport::Legacy { io_base, .. } => unsafe { Port::new(io_base + LEGACY_DEVICE_STATUS).read() },
+            Transport::Modern(modern) => modern.read_u8(COMMON_DEVICE_STATUS),
+        }
+    }
+
+    pub fn set_status(&self, status: u8) {
+        match self {
+            Transport::Legacy { io_base, .. } => unsafe { Port::new(io_base + LEGACY_DEVICE_STATUS).write(status) },
+            Transport::Modern(modern) => modern.write_u8(COMMON_DEVICE_STATUS, status),
+        }
+    }
+
+    /// Reset the device. Writing 0 to the status starts the reset - it is done when the status reads 0
+    pub fn reset(&self) {
+        self.set_status(0);
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// The ISR status. Reading it acknowledges a legacy (INTx) interrupt
+    pub fn isr_status(&self) -> u8 {
+        match self {
+            Transport::Legacy { io_base, .. } => unsafe { Port::new(io_base + LEGACY_ISR_STATUS).read() },
+            Transport::Modern(modern) => unsafe { ptr::read_volatile(modern.isr.as_ptr::<u8>()) },
+        }
+    }
+
+    pub fn device_features(&self) -> u64 {
+        match self {
+            Transport::Legacy { io_base, .. } => unsafe { Port::<u32>::new(io_base + LEGACY_DEVICE_FEATURES).read() as u64 },
+            Transport::Modern(modern) => {
+                modern.write_u32(COMMON_DEVICE_FEATURE_SELECT, 0);
+                let low = modern.read_u32(COMMON_DEVICE_FEATURE) as u64;
+                modern.write_u32(COMMON_DEVICE_FEATURE_SELECT, 1);
+                low | (modern.read_u32(COMMON_DEVICE_FEATURE) as u64) << 32
+            }
+        }
+    }
+
+    pub fn set_driver_features(&self, features: u64) {
+        match self {
+            Transport::Legacy { io_base, .. } => unsafe { Port::new(io_base + LEGACY_DRIVER_FEATURES).write(features as u32) },
+            Transport::Modern(modern) => {
+                modern.write_u32(COMMON_DRIVER_FEATURE_SELECT, 0);
+                modern.write_u32(COMMON_DRIVER_FEATURE, features as u32);
+                modern.write_u32(COMMON_DRIVER_FEATURE_SELECT, 1);
+                modern.write_u32(COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+            }
+        }
+    }
+
+    /// How many queues the device has. The legacy transport doesn't say, so we count until one has no size
+    pub fn queue_count(&self) -> u16 {
+        match self {
+            Transport::Legacy { .. } => (0..u16::MAX).find(|&queue| self.max_queue_size(queue) == 0).unwrap_or(0),
+            Transport::Modern(modern) => modern.read_u16(COMMON_NUM_QUEUES),
+        }
+    }
+
+    /// The biggest queue `queue` can be - 0 if it doesn't exist. A legacy queue has to be exactly this big
+    pub fn max_queue_size(&self, queue: u16) -> u16 {
+        match self {
+            Transport::Legacy { io_base, .. } => unsafe {
+                Port::new(io_base + LEGACY_QUEUE_SELECT).write(queue);
+                Port::new(io_base + LEGACY_QUEUE_SIZE).read()
+            },
+            Transport::Modern(modern) => {
+                modern.write_u16(COMMON_QUEUE_SELECT, queue);
+                modern.read_u16(COMMON_QUEUE_SIZE)
+            }
+        }
+    }
+
+    /// Give `queue` its rings (`size` entries, laid out from `descriptors` as
+    /// [queue::layout](../queue/fn.layout.html) says) and MSI-X vector, and enable it
+    pub fn setup_queue(
+        &self,
+        queue: u16,
+        size: u16,
+        descriptors: PhysAddr,
+        available: PhysAddr,
+        used: PhysAddr,
+        vector: u16,
+    ) -> Result<Notifier, VirtioError> {
+        match *self {
+            Transport::Legacy { io_base, msix } => unsafe {
+                if descriptors.as_u64() % LEGACY_QUEUE_ALIGN != 0 || self.max_queue_size(queue) != size {
+                    return Err(VirtioError::QueueUnavailable);
+                }
+                Port::new(io_base + LEGACY_QUEUE_SELECT).write(queue);
+                if msix {
+                    Port::new(io_base + LEGACY_QUEUE_VECTOR).write(vector);
+                    if vector != NO_VECTOR && Port::<u16>::new(io_base + LEGACY_QUEUE_VECTOR).read() != vector {
+                        return Err(VirtioError::QueueUnavailable);
+                    }
+                }
+                Port::new(io_base + LEGACY_QUEUE_ADDRESS).write((descriptors.as_u64() / LEGACY_QUEUE_ALIGN) as u32);
+                Ok(Notifier::Port { port: io_base + LEGACY_QUEUE_NOTIFY, queue })
+            },
+            Transport::Modern(modern) => {
+                modern.write_u16(COMMON_QUEUE_SELECT, queue);
+                let max = modern.read_u16(COMMON_QUEUE_SIZE);
+                if max == 0 || size > max {
+                    return Err(VirtioError::QueueUnavailable);
+                }
+                modern.write_u16(COMMON_QUEUE_SIZE, size);
+                modern.write_u64(COMMON_QUEUE_DESC, descriptors.as_u64());
+                modern.write_u64(COMMON_QUEUE_DRIVER, available.as_u64());
+                modern.write_u64(COMMON_QUEUE_DEVICE, used.as_u64());
+                modern.write_u16(COMMON_QUEUE_VECTOR, vector);
+                if modern.read_u16(COMMON_QUEUE_VECTOR) != vector {
+                    return Err(VirtioError::QueueUnavailable); // the device couldn't take the vector
+                }
+                let notify_offset = modern.read_u16(COMMON_QUEUE_NOTIFY_OFF) as u64;
+                modern.write_u16(COMMON_QUEUE_ENABLE, 1);
+                let address = modern.notify + notify_offset * modern.notify_multiplier as u64;
+                Ok(Notifier::Mmio { address, queue })
+            }
+        }
+    }
+
+    /// Which MSI-X vector configuration changes are sent to. `NO_VECTOR` for none
+    pub fn set_config_vector(&self, vector: u16) {
+        match self {
+            Transport::Legacy { io_base, msix: true } => unsafe { Port::new(io_base + LEGACY_CONFIG_VECTOR).write(vector) },
+            Transport::Legacy { .. } => {}
+            Transport::Modern(modern) => modern.write_u16(COMMON_CONFIG_VECTOR, vector),
+        }
+    }
+
+    /// A byte of device config. Wider fields are read a byte at a time - the legacy transport packs them
+    /// unaligned, and the modern one allows it
+    pub fn read_config_u8(&self, offset: u16) -> u8 {
+        match self {
+            Transport::Legacy { io_base, msix } => {
+                let config = if *msix { LEGACY_CONFIG_MSIX } else { LEGACY_CONFIG };
+                unsafe { Port::new(io_base + config + offset).read() }
+            }
+            Transport::Modern(modern) => unsafe { ptr::read_volatile((modern.device + offset as u64).as_ptr::<u8>()) },
+        }
+    }
+
+    pub fn write_config_u8(&self, offset: u16, value: u8) {
+        match self {
+            Transport::Legacy { io_base, msix } => {
+                let config = if *msix { LEGACY_CONFIG_MSIX } else { LEGACY_CONFIG };
+                unsafe { Port::new(io_base + config + offset).write(value) }
+            }
+            Transport::Modern(modern) => unsafe { ptr::write_volatile((modern.device + offset as u64).as_mut_ptr::<u8>(), value) },
+        }
+    }
+}
+
+impl ModernTransport {
+    fn read_u8(&self, offset: u64) -> u8 {
+        unsafe { ptr::read_volatile((self.common + offset).as_ptr::<u8>()) }
+    }
+
+    fn write_u8(&self, offset: u64, value: u8) {
+        unsafe { ptr::write_volatile((self.common + offset).as_mut_ptr::<u8>(), value) }
+    }
+
+    fn read_u16(&self, offset: u64) -> u16 {
+        unsafe { ptr::read_volatile((self.common + offset).as_ptr::<u16>()) }
+    }
+
+    fn write_u16(&self, offset: u64, value: u16) {
+        unsafe { ptr::write_volatile((self.common + offset).as_mut_ptr::<u16>(), value) }
+    }
+
+    fn read_u32(&self, offset: u64) -> u32 {
+        unsafe { ptr::read_volatile((self.common + offset).as_ptr::<u32>()) }
+    }
+
+    fn write_u32(&self, offset: u64, value: u32) {
+        unsafe { ptr::write_volatile((self.common + offset).as_mut_ptr::<u32>(), value) }
+    }
+
+    /// 64 bit fields are written a half at a time, low half first
+    fn write_u64(&self, offset: u64, value: u64) {
+        self.write_u32(offset, value as u32);
+        self.write_u32(offset + 4, (value >> 32) as u32);
+    }
+}
@@ -0,0 +1,237 @@
+//! # Entropy
+//!
+//! The kernel's pool of randomness. Hardware random number generators (like a virtio-rng device) register as
+//! [sources](trait.EntropySource.html), and anything unpredictable can be mixed in with [add](fn.add.html).
+//! Random bytes come out of [fill](fn.fill.html).
+//!
+//! The pool is a ChaCha20 key. Input is folded into the key, then the key is replaced by a ChaCha20 block
+//! made with it, so every input changes everything that comes out afterwards. Output is ChaCha20 keystream,
+//! and the key is replaced after every request (fast key erasure), so output already given out can't be
+//! worked out from the pool later.
+//!
+//! Sources say how much entropy they give. Until 128 bits have been credited the pool isn't
+//! [seeded](fn.is_seeded.html) - output is still unpredictable to anyone who can't see the machine, but
+//! shouldn't be used for keys.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use spin::Mutex;
+
+/// How much entropy the pool can hold - the size of its key
+const POOL_BITS: usize = 256;
+/// How much has to be credited before the pool counts as seeded
+const SEEDED_BITS: usize = 128;
+/// How many bytes `reseed` reads from each source
+const RESEED_BYTES: usize = 32;
+
+/// The second nonce word, so mixing and output never make the same block
+const NONCE_MIX: u32 = 0;
+const NONCE_OUTPUT: u32 = 1;
+
+/// "expand 32-byte k"
+const CHACHA_CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646E, 0x7962_2D32, 0x6B20_6574];
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// One ChaCha20 block (RFC 7539), as words
+fn chacha20_block(key: &[u32; 8], counter: u32, nonce: [u32; 3]) -> [u32; 16] {
+    let mut initial = [0u32; 16];
+    initial[..4].copy_from_slice(&CHACHA_CONSTANTS);
+    initial[4..12].copy_from_slice(key);
+    initial[12] = counter;
+    initial[13..].copy_from_slice(&nonce);
+
+    let mut state = initial;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (word, initial) in state.iter_mut().zip(initial.iter()) {
+        *word = word.wrapping_add(*initial);
+    }
+    state
+}
+
+struct Pool {
+    key: [u32; 8],
+    /// How many blocks have been made with the pool. Never repeats, so neither do blocks
+    counter: u64,
+    /// How much entropy has been credited, up to `POOL_BITS`
+    credited: usize,
+}
+
+impl Pool {
+    /// The next block, for `purpose` (mixing or output)
+    fn block(&mut self, purpose: u32) -> [u32; 16] {
+        let block = chacha20_block(&self.key, self.counter as u32, [(self.counter >> 32) as u32, purpose, 0]);
+        self.counter += 1;
+        block
+    }
+
+    /// Replace the key with a block made from it
+    fn stir(&mut self, purpose: u32) {
+        let block = self.block(purpose);
+        self.key.copy_from_slice(&block[..8]);
+    }
+
+    fn mix(&mut self, data: &[u8]) {
+        for chunk in data.chunks(32) {
+            for (index, word) in chunk.chunks(4).enumerate() {
+                let mut bytes = [0; 4];
+                bytes[..word.len()].copy_from_slice(word);
+                self.key[index] ^= u32::from_le_bytes(bytes);
+            }
+            self.stir(NONCE_MIX);
+        }
+    }
+}
+
+static POOL: Mutex<Pool> = Mutex::new(Pool { key: [0; 8], counter: 0, credited: 0 });
+
+/// The time stamp counter - not much entropy, but some, and free
+fn timestamp() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// # add
+///
+/// Mix `data` into the pool, crediting it with `bits` of entropy (0 if you aren't sure it has any)
+pub fn add(data: &[u8], bits: usize) {
+    let mut pool = POOL.lock();
+    pool.mix(&timestamp().to_le_bytes());
+    pool.mix(data);
+    pool.credited = (pool.credited + bits).min(POOL_BITS);
+}
+
+/// # fill
+///
+/// Fill `buffer` with random bytes
+pub fn fill(buffer: &mut [u8]) {
+    let mut pool = POOL.lock();
+    pool.mix(&timestamp().to_le_bytes());
+    for chunk in buffer.chunks_mut(64) {
+        let block = pool.block(NONCE_OUTPUT);
+        for (bytes, word) in chunk.chunks_mut(4).zip(block.iter()) {
+            bytes.copy_from_slice(&word.to_le_bytes()[..bytes.len()]);
+        }
+    }
+    pool.stir(NONCE_OUTPUT); // so nothing just given out can be made again
+}
+
+/// A random `u64`
+pub fn u64() -> u64 {
+    let mut bytes = [0; 8];
+    fill(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+/// How many bits of entropy have been credited
+pub fn credited_bits() -> usize {
+    POOL.lock().credited
+}
+
+/// Whether enough entropy has been credited for the output to be used for keys
+pub fn is_seeded() -> bool {
+    credited_bits() >= SEEDED_BITS
+}
+
+/// What `EntropySource::read` returns - a boxed future, so the trait can be used as `dyn EntropySource`
+pub type EntropyFuture<'a> = Pin<Box<dyn Future<Output = usize> + Send + 'a>>;
+
+/// # EntropySource
+///
+/// Something that makes random bytes, like a hardware random number generator
+pub trait EntropySource: Send + Sync {
+    /// The name it is registered under, like `virtio-rng0`
+    fn name(&self) -> &str;
+
+    /// How many bits of entropy each byte it gives has. 8 for a true random number generator
+    fn bits_per_byte(&self) -> usize {
+        8
+    }
+
+    /// Fill as much of `buffer` as it can, returning how many bytes that was
+    fn read<'a>(&'a self, buffer: &'a mut [u8]) -> EntropyFuture<'a>;
+}
+
+/// Every registered source
+static SOURCES: Mutex<Vec<Arc<dyn EntropySource>>> = Mutex::new(Vec::new());
+
+/// Add an entropy source, for [reseed](fn.reseed.html) to read from. Its name must be unique
+pub fn register_source(source: Arc<dyn EntropySource>) {
+    SOURCES.lock().push(source);
+}
+
+/// Remove the source called `name` - its driver is going away
+pub fn unregister_source(name: &str) {
+    SOURCES.lock().retain(|source| source.name() != name);
+}
+
+/// Every registered source
+pub fn sources() -> Vec<Arc<dyn EntropySource>> {
+    SOURCES.lock().clone()
+}
+
+/// # reseed
+///
+/// Read from every source into the pool. Returns how many bits were credited
+pub async fn reseed() -> usize {
+    let mut credited = 0;
+    for source in sources() {
+        let mut buffer = vec![0; RESEED_BYTES];
+        let read = source.read(&mut buffer).await.min(buffer.len());
+        let bits = read * source.bits_per_byte().min(8);
+        add(&buffer[..read], bits);
+        credited += bits;
+    }
+    credited
+}
+
+/* Testing */
+
+#[test_case]
+fn chacha20_test_vector() {
+    // RFC 7539, section 2.3.2
+    let mut key = [0u32; 8];
+    for (index, word) in key.iter_mut().enumerate() {
+        let byte = 4 * index as u32;
+        *word = u32::from_le_bytes([byte as u8, byte as u8 + 1, byte as u8 + 2, byte as u8 + 3]);
+    }
+    let block = chacha20_block(&key, 1, [0x0900_0000, 0x4A00_0000, 0]);
+    assert_eq!(&block[..4], &[0xE4E7_F110, 0x1559_3BD1, 0x1FDD_0F50, 0xC471_20A3]);
+    assert_eq!(block[15], 0x4E3C_50A2);
+}
+
+#[test_case]
+fn entropy_pool() {
+    let before = credited_bits();
+    add(b"some entropy", 8);
+    assert_eq!(credited_bits(), (before + 8).min(POOL_BITS));
+
+    let mut first = [0u8; 100];
+    let mut second = [0u8; 100];
+    fill(&mut first);
+    fill(&mut second);
+    assert_ne!(&first[..], &second[..]);
+    assert!(first.iter().any(|&byte| byte != 0));
+    assert_ne!(u64(), u64());
+}
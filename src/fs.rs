@@ -0,0 +1,384 @@
+//! # Filesystems
+//!
+//! The virtual filesystem (VFS) - one tree of files that every filesystem is mounted into. A filesystem
+//! implements [Filesystem](trait.Filesystem.html), and [Inode](trait.Inode.html) for each file, directory and
+//! symlink in it; the VFS does the rest, so RAM filesystems, disk filesystems and pseudo-filesystems all look
+//! the same to the kernel.
+//!
+//! - Inodes are the files themselves. A filesystem only has to find a name in a directory (`lookup`), read
+//!   and write at an offset, list, create and remove - it never sees a path.
+//! - Dentries ([Dentry](dentry/struct.Dentry.html)) are names in the tree, pointing at an inode. They are kept
+//!   once looked up, so walking the same path again doesn't go to the filesystem.
+//! - Mounts put a filesystem's root over a directory. [mount_root](fn.mount_root.html) mounts the first one at
+//!   `/`, and [mount](fn.mount.html) mounts more over directories in it.
+//! - Path resolution follows `..` (up through mount points), symlinks (up to 40 of them, so loops end) and
+//!   mounts.
+//! - Open files ([File](file/struct.File.html)) have an offset that `read` and `write` move, like Unix file
+//!   descriptors.
+//!
+//! Everything that might wait for a disk is async. There are no processes yet, so relative paths start at `/`.
+
+pub mod path; // Splitting and checking paths
+pub mod dentry; // Names in the tree, mounts, and path resolution
+pub mod file; // Open files
+pub mod ramfs; // Filesystem in memory - the root until a disk is mounted
+pub mod initrd; // The archive of files bundled into the kernel, unpacked into the root at boot
+pub mod fat; // FAT12/16/32 on a block device
+
+pub use dentry::{mount, mount_root, mounts, unmount, Dentry, Mount};
+pub use file::{File, OpenOptions, SeekFrom};
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use crate::block::BlockError;
+use crate::serial_println;
+use crate::task::block_on;
+use dentry::{root, walk, walk_parent};
+
+/// # FsError
+///
+/// Why a filesystem operation failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    /// Nothing has that name
+    NotFound,
+    /// Something already has that name
+    AlreadyExists,
+    /// A directory was needed - a path went through a file
+    NotDirectory,
+    /// A directory can't be read, written or unlinked like a file
+    IsDirectory,
+    /// Removing a directory that still has entries
+    NotEmpty,
+    /// A bad name, offset or argument - like a name with `/` in it, or moving a directory into itself
+    InvalidArgument,
+    /// Too many symlinks were followed - probably a loop
+    TooManyLinks,
+    /// The filesystem is read only, or the file wasn't opened for writing
+    ReadOnly,
+    /// The file wasn't opened for reading
+    NotReadable,
+    /// There's no room left on the filesystem
+    NoSpace,
+    /// Something is mounted there, or the filesystem is in use
+    Busy,
+    /// Linking or renaming between two filesystems
+    CrossDevice,
+    /// The filesystem can't do that
+    NotSupported,
+    /// The filesystem's structures on disk don't make sense
+    Corrupt(&'static str),
+    /// The block device under the filesystem failed
+    Io(BlockError),
+}
+
+impl From<BlockError> for FsError {
+    fn from(error: BlockError) -> Self {
+        FsError::Io(error)
+    }
+}
+
+/// What `Filesystem` and `Inode` methods return - a boxed future, so the traits can be used as `dyn`
+pub type FsFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, FsError>> + Send + 'a>>;
+
+/// A future that fails straight away with `error` - for the `Inode` methods a filesystem doesn't implement
+pub fn fail<'a, T: Send + 'a>(error: FsError) -> FsFuture<'a, T> {
+    Box::pin(async move { Err(error) })
+}
+
+/// # FileType
+///
+/// What an inode is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+}
+
+/// # Stat
+///
+/// What `stat` says about a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stat {
+    /// The inode number - unique within its filesystem
+    pub inode: u64,
+    pub kind: FileType,
+    /// In bytes. A symlink's size is the length of its target
+    pub size: u64,
+    /// How many directory entries point at it
+    pub links: u32,
+    /// The bytes of storage it takes - less than `size` if it has holes
+    pub allocated: u64,
+}
+
+/// # DirEntry
+///
+/// A name in a directory. Directories don't list `.` and `..`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u64,
+    pub kind: FileType,
+}
+
+/// # Filesystem
+///
+/// A mountable filesystem - an instance of one, like a particular FAT partition
+pub trait Filesystem: Send + Sync {
+    /// The kind of filesystem, like `ramfs` or `fat`
+    fn name(&self) -> &str;
+
+    /// The root directory
+    fn root(&self) -> Arc<dyn Inode>;
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Write everything cached to the disk
+    fn sync(&self) -> FsFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// # Inode
+///
+/// A file, directory or symlink. The VFS checks the inode's type before calling a method, so a filesystem only
+/// implements the methods that make sense for its inodes - the rest fail with `NotSupported`. Names given to
+/// directory methods have been checked with [is_valid_name](path/fn.is_valid_name.html)
+pub trait Inode: Send + Sync {
+    /// The inode number - unique within its filesystem
+    fn id(&self) -> u64;
+
+    fn kind(&self) -> FileType;
+
+    fn stat(&self) -> FsFuture<'_, Stat>;
+
+    /// Read from `offset` into `buffer`, returning how much was read - less than asked at the end of the file,
+    /// and 0 past it
+    fn read_at<'a>(&'a self, _offset: u64, _buffer: &'a mut [u8]) -> FsFuture<'a, usize> {
+        fail(FsError::NotSupported)
+    }
+
+    /// Write `buffer` at `offset`, growing the file if it goes past the end. Returns how much was written
+    fn write_at<'a>(&'a self, _offset: u64, _buffer: &'a [u8]) -> FsFuture<'a, usize> {
+        fail(FsError::NotSupported)
+    }
+
+    /// Cut the file to `size` bytes, or grow it with zeros
+    fn truncate(&self, _size: u64) -> FsFuture<'_, ()> {
+        fail(FsError::NotSupported)
+    }
+
+    /// The inode called `name` in this directory
+    fn lookup<'a>(&'a self, _name: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        fail(FsError::NotSupported)
+    }
+
+    /// Everything in this directory
+    fn read_dir(&self) -> FsFuture<'_, Vec<DirEntry>> {
+        fail(FsError::NotSupported)
+    }
+
+    /// Make an empty file or directory called `name` in this directory
+    fn create<'a>(&'a self, _name: &'a str, _kind: FileType) -> FsFuture<'a, Arc<dyn Inode>> {
+        fail(FsError::NotSupported)
+    }
+
+    /// Make a symlink called `name` to `target` in this directory
+    fn symlink<'a>(&'a self, _name: &'a str, _target: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        fail(FsError::NotSupported)
+    }
+
+    /// Where this symlink points
+    fn read_link(&self) -> FsFuture<'_, String> {
+        fail(FsError::NotSupported)
+    }
+
+    /// Add `inode`, from the same filesystem, to this directory as `name` - a hard link
+    fn link<'a>(&'a self, _name: &'a str, _inode: &'a Arc<dyn Inode>) -> FsFuture<'a, ()> {
+        fail(FsError::NotSupported)
+    }
+
+    /// Remove `name` from this directory. Directories must be empty
+    fn unlink<'a>(&'a self, _name: &'a str) -> FsFuture<'a, ()> {
+        fail(FsError::NotSupported)
+    }
+
+    /// Move `name` to `new_name` in the directory `to` (this one, or another in the same filesystem). Whatever
+    /// was called `new_name` is replaced - a directory only by a directory, and only if it is empty
+    fn rename<'a>(&'a self, _name: &'a str, _to: &'a Arc<dyn Inode>, _new_name: &'a str) -> FsFuture<'a, ()> {
+        fail(FsError::NotSupported)
+    }
+}
+
+/// The directories made in the root ramfs
+const ROOT_DIRECTORIES: [&str; 3] = ["/dev", "/mnt", "/tmp"];
+
+/// # init
+///
+/// Mount a ramfs at `/`, with the usual directories, and unpack the [initrd](initrd/index.html) into it, so
+/// there are files before (or without) any disk. Does nothing if something is already mounted there
+pub fn init() {
+    if mount_root(Arc::new(ramfs::RamFs::new())).is_err() {
+        return;
+    }
+    for directory in ROOT_DIRECTORIES.iter() {
+        block_on(mkdir(directory)).expect("Couldn't make the root directories");
+    }
+    match block_on(initrd::unpack(initrd::IMAGE, "/")) {
+        Ok(count) => serial_println!("[LOG] Unpacked {} files from the initrd ({} bytes)", count, initrd::IMAGE.len()),
+        Err(error) => serial_println!("[WARN] Couldn't unpack the initrd: {:?}", error),
+    }
+}
+
+/* The namespace - everything takes paths from `/` */
+
+/// # open
+///
+/// Open the file at `path`, following symlinks
+pub async fn open(path: &str, options: OpenOptions) -> Result<File, FsError> {
+    let root = root()?;
+    let dentry = match walk(&root, &root, path, true).await {
+        Ok(_) if options.is_create_new() => return Err(FsError::AlreadyExists),
+        Ok(dentry) => dentry,
+        Err(FsError::NotFound) if options.is_create() => {
+            let (parent, name) = walk_parent(&root, &root, path).await?;
+            parent.create(&name, FileType::Regular).await?
+        }
+        Err(error) => return Err(error),
+    };
+    File::open(dentry, options).await
+}
+
+/// # lookup
+///
+/// The dentry at `path`. Symlinks are followed, except the last one if `follow` is false
+pub async fn lookup(path: &str, follow: bool) -> Result<Arc<Dentry>, FsError> {
+    let root = root()?;
+    walk(&root, &root, path, follow).await
+}
+
+/// # stat
+///
+/// What the file at `path` is, following symlinks
+pub async fn stat(path: &str) -> Result<Stat, FsError> {
+    lookup(path, true).await?.inode().stat().await
+}
+
+/// # lstat
+///
+/// Like [stat](fn.stat.html), but about a symlink itself rather than what it points at
+pub async fn lstat(path: &str) -> Result<Stat, FsError> {
+    lookup(path, false).await?.inode().stat().await
+}
+
+/// # read_dir
+///
+/// Everything in the directory at `path`
+pub async fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    lookup(path, true).await?.read_dir().await
+}
+
+/// # read_link
+///
+/// Where the symlink at `path` points
+pub async fn read_link(path: &str) -> Result<String, FsError> {
+    let dentry = lookup(path, false).await?;
+    if dentry.kind() != FileType::Symlink {
+        return Err(FsError::InvalidArgument);
+    }
+    dentry.inode().read_link().await
+}
+
+/// # mkdir
+///
+/// Make a directory at `path`
+pub async fn mkdir(path: &str) -> Result<(), FsError> {
+    let root = root()?;
+    let (parent, name) = walk_parent(&root, &root, path).await?;
+    parent.create(&name, FileType::Directory).await.map(|_| ())
+}
+
+/// # symlink
+///
+/// Make a symlink at `path`, pointing at `target`. The target doesn't have to exist
+pub async fn symlink(target: &str, path: &str) -> Result<(), FsError> {
+    if target.is_empty() {
+        return Err(FsError::InvalidArgument);
+    }
+    let root = root()?;
+    let (parent, name) = walk_parent(&root, &root, path).await?;
+    parent.symlink(&name, target).await.map(|_| ())
+}
+
+/// # link
+///
+/// Make `path` another name for the file at `existing` - a hard link. Both must be on the same filesystem,
+/// and directories can't be linked
+pub async fn link(existing: &str, path: &str) -> Result<(), FsError> {
+    let root = root()?;
+    let target = walk(&root, &root, existing, false).await?;
+    let (parent, name) = walk_parent(&root, &root, path).await?;
+    parent.link(&name, &target).await.map(|_| ())
+}
+
+/// # unlink
+///
+/// Remove the file or symlink at `path`. The file itself goes when nothing else links to it
+pub async fn unlink(path: &str) -> Result<(), FsError> {
+    let root = root()?;
+    let (parent, name) = walk_parent(&root, &root, path).await?;
+    parent.remove(&name, false).await
+}
+
+/// # rmdir
+///
+/// Remove the empty directory at `path`
+pub async fn rmdir(path: &str) -> Result<(), FsError> {
+    let root = root()?;
+    let (parent, name) = walk_parent(&root, &root, path).await?;
+    parent.remove(&name, true).await
+}
+
+/// # rename
+///
+/// Move the file or directory at `from` to `to`, replacing what is there. Both must be on the same filesystem
+pub async fn rename(from: &str, to: &str) -> Result<(), FsError> {
+    let root = root()?;
+    let (from_parent, from_name) = walk_parent(&root, &root, from).await?;
+    let (to_parent, to_name) = walk_parent(&root, &root, to).await?;
+    from_parent.rename(&from_name, &to_parent, &to_name).await
+}
+
+/// # read
+///
+/// The whole of the file at `path`
+pub async fn read(path: &str) -> Result<Vec<u8>, FsError> {
+    open(path, OpenOptions::new().read(true)).await?.read_to_end().await
+}
+
+/// # write
+///
+/// Make the file at `path` hold `data`, creating it if needed
+pub async fn write(path: &str, data: &[u8]) -> Result<(), FsError> {
+    let file = open(path, OpenOptions::new().write(true).create(true).truncate(true)).await?;
+    file.write_all(data).await
+}
+
+/// # sync
+///
+/// Write everything every mounted filesystem has cached to its disk
+pub async fn sync() -> Result<(), FsError> {
+    for mount in dentry::mounts() {
+        mount.filesystem().sync().await?;
+    }
+    Ok(())
+}
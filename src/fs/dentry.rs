@@ -0,0 +1,529 @@
+//! # Dentries and mounts
+//!
+//! A [Dentry](struct.Dentry.html) is a name in the tree: a directory entry that has been looked up, with the
+//! inode it points at and the dentry of the directory it is in. Each dentry keeps the children that have been
+//! looked up through it, so walking a path a second time doesn't ask the filesystem again. The cache is only
+//! pruned when names are removed or renamed, and when a filesystem is unmounted.
+//!
+//! A [Mount](struct.Mount.html) is a filesystem put over a directory - the mount point. The mount point's
+//! dentry points at the root dentry of the filesystem on it, and walking into the mount point goes there
+//! instead. A mount's root has no parent: `..` from it goes to the mount point's parent.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::serial_println;
+use super::path::{components, is_absolute, is_valid_name, split_last};
+use super::{DirEntry, FileType, Filesystem, FsError, Inode};
+
+/// The most symlinks followed in one path, so a loop ends
+pub const MAX_SYMLINKS: usize = 40;
+
+/// # Mount
+///
+/// A mounted filesystem
+pub struct Mount {
+    filesystem: Arc<dyn Filesystem>,
+    /// The dentry it is mounted over. `None` for the root filesystem
+    mountpoint: Option<Arc<Dentry>>,
+}
+
+impl Mount {
+    pub fn filesystem(&self) -> &Arc<dyn Filesystem> {
+        &self.filesystem
+    }
+
+    /// Where it is mounted
+    pub fn path(&self) -> String {
+        match &self.mountpoint {
+            Some(mountpoint) => mountpoint.path(),
+            None => String::from("/"),
+        }
+    }
+}
+
+/// # Dentry
+///
+/// A name in the tree, and the inode it points at
+pub struct Dentry {
+    name: String,
+    inode: Arc<dyn Inode>,
+    /// The directory it is in. `None` for the root of a mount
+    parent: Option<Arc<Dentry>>,
+    /// The mount it is in
+    mount: Arc<Mount>,
+    /// The children that have been looked up
+    children: Mutex<BTreeMap<String, Arc<Dentry>>>,
+    /// The root of the filesystem mounted over this one, if there is one
+    mounted: Mutex<Option<Arc<Dentry>>>,
+}
+
+impl Dentry {
+    /// The root dentry of `filesystem`, mounted over `mountpoint`
+    fn new_root(filesystem: Arc<dyn Filesystem>, mountpoint: Option<Arc<Dentry>>) -> Arc<Dentry> {
+        let inode = filesystem.root();
+        Arc::new(Dentry {
+            name: String::from("/"),
+            inode,
+            parent: None,
+            mount: Arc::new(Mount { filesystem, mountpoint }),
+            children: Mutex::new(BTreeMap::new()),
+            mounted: Mutex::new(None),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    pub fn kind(&self) -> FileType {
+        self.inode.kind()
+    }
+
+    pub fn mount(&self) -> &Arc<Mount> {
+        &self.mount
+    }
+
+    /// Whether this is the root of a mounted filesystem
+    pub fn is_mount_root(&self) -> bool {
+        self.parent.is_none()
+    }
+
+    /// The full path to it, through mount points
+    pub fn path(&self) -> String {
+        let mut names = Vec::new();
+        let mut dentry = self;
+        loop {
+            match (&dentry.parent, &dentry.mount.mountpoint) {
+                (Some(parent), _) => {
+                    names.push(dentry.name.as_str());
+                    dentry = parent;
+                }
+                (None, Some(mountpoint)) => dentry = mountpoint,
+                (None, None) => break,
+            }
+        }
+        if names.is_empty() {
+            return String::from("/");
+        }
+        let mut path = String::new();
+        for name in names.iter().rev() {
+            path.push('/');
+            path.push_str(name);
+        }
+        path
+    }
+
+    /// The filesystem mounted here, or this dentry if there isn't one
+    fn cross_mounts(self: &Arc<Self>) -> Arc<Dentry> {
+        let mut dentry = self.clone();
+        loop {
+            let mounted = dentry.mounted.lock().clone();
+            match mounted {
+                Some(root) => dentry = root,
+                None => return dentry,
+            }
+        }
+    }
+
+    /// The dentry for `name` in this directory - from the cache, or looked up. Doesn't go into a filesystem
+    /// mounted on it
+    pub async fn child(self: &Arc<Self>, name: &str) -> Result<Arc<Dentry>, FsError> {
+        if self.kind() != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+        if let Some(child) = self.children.lock().get(name) {
+            return Ok(child.clone());
+        }
+        let inode = self.inode.lookup(name).await?;
+        Ok(self.add_child(name, inode))
+    }
+
+    /// Cache `inode` as `name`. If another task got there first, theirs is kept
+    fn add_child(self: &Arc<Self>, name: &str, inode: Arc<dyn Inode>) -> Arc<Dentry> {
+        self.children
+            .lock()
+            .entry(String::from(name))
+            .or_insert_with(|| {
+                Arc::new(Dentry {
+                    name: String::from(name),
+                    inode,
+                    parent: Some(self.clone()),
+                    mount: self.mount.clone(),
+                    children: Mutex::new(BTreeMap::new()),
+                    mounted: Mutex::new(None),
+                })
+            })
+            .clone()
+    }
+
+    /// Drop `name` from the cache
+    fn forget(&self, name: &str) {
+        self.children.lock().remove(name);
+    }
+
+    /// Whether `name` is cached and has something mounted on it
+    fn is_mountpoint(&self, name: &str) -> bool {
+        self.children.lock().get(name).map_or(false, |child| child.mounted.lock().is_some())
+    }
+
+    /// Check that this is a directory that can be changed
+    fn check_writable_directory(&self) -> Result<(), FsError> {
+        if self.kind() != FileType::Directory {
+            Err(FsError::NotDirectory)
+        } else if self.mount.filesystem.is_read_only() {
+            Err(FsError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Everything in this directory
+    pub async fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        if self.kind() != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+        self.inode.read_dir().await
+    }
+
+    /// Make an empty file or directory called `name` in this directory
+    pub async fn create(self: &Arc<Self>, name: &str, kind: FileType) -> Result<Arc<Dentry>, FsError> {
+        self.check_writable_directory()?;
+        if !is_valid_name(name) {
+            return Err(FsError::InvalidArgument);
+        }
+        let inode = self.inode.create(name, kind).await?;
+        Ok(self.add_child(name, inode))
+    }
+
+    /// Make a symlink called `name` to `target` in this directory
+    pub async fn symlink(self: &Arc<Self>, name: &str, target: &str) -> Result<Arc<Dentry>, FsError> {
+        self.check_writable_directory()?;
+        if !is_valid_name(name) {
+            return Err(FsError::InvalidArgument);
+        }
+        let inode = self.inode.symlink(name, target).await?;
+        Ok(self.add_child(name, inode))
+    }
+
+    /// Add `target` to this directory as `name`
+    pub async fn link(self: &Arc<Self>, name: &str, target: &Arc<Dentry>) -> Result<Arc<Dentry>, FsError> {
+        self.check_writable_directory()?;
+        if !is_valid_name(name) {
+            return Err(FsError::InvalidArgument);
+        }
+        if !Arc::ptr_eq(&self.mount, &target.mount) {
+            return Err(FsError::CrossDevice);
+        }
+        if target.kind() == FileType::Directory {
+            return Err(FsError::IsDirectory);
+        }
+        self.inode.link(name, &target.inode).await?;
+        Ok(self.add_child(name, target.inode.clone()))
+    }
+
+    /// Remove `name` from this directory - a directory if `directory`, anything else if not
+    pub async fn remove(self: &Arc<Self>, name: &str, directory: bool) -> Result<(), FsError> {
+        self.check_writable_directory()?;
+        if !is_valid_name(name) {
+            return Err(FsError::InvalidArgument);
+        }
+        let child = self.child(name).await?;
+        if child.mounted.lock().is_some() {
+            return Err(FsError::Busy);
+        }
+        match (child.kind() == FileType::Directory, directory) {
+            (true, false) => return Err(FsError::IsDirectory),
+            (false, true) => return Err(FsError::NotDirectory),
+            _ => {}
+        }
+        self.inode.unlink(name).await?;
+        self.forget(name);
+        Ok(())
+    }
+
+    /// Move `name` to `new_name` in the directory `to`
+    pub async fn rename(self: &Arc<Self>, name: &str, to: &Arc<Dentry>, new_name: &str) -> Result<(), FsError> {
+        self.check_writable_directory()?;
+        to.check_writable_directory()?;
+        if !is_valid_name(name) || !is_valid_name(new_name) {
+            return Err(FsError::InvalidArgument);
+        }
+        if !Arc::ptr_eq(&self.mount, &to.mount) {
+            return Err(FsError::CrossDevice);
+        }
+        let source = self.child(name).await?;
+        if Arc::ptr_eq(self, to) && name == new_name {
+            return Ok(());
+        }
+        if source.mounted.lock().is_some() || to.is_mountpoint(new_name) {
+            return Err(FsError::Busy);
+        }
+        // A directory can't go inside itself
+        if source.kind() == FileType::Directory {
+            let mut ancestor = Some(to.clone());
+            while let Some(dentry) = ancestor {
+                if Arc::ptr_eq(&dentry, &source) {
+                    return Err(FsError::InvalidArgument);
+                }
+                ancestor = dentry.parent.clone();
+            }
+        }
+        self.inode.rename(name, &to.inode, new_name).await?;
+        self.forget(name);
+        to.forget(new_name);
+        Ok(())
+    }
+}
+
+/// Where `..` from `dentry` goes - its parent, through mount points, staying put at `root`
+fn parent_of(root: &Arc<Dentry>, dentry: &Arc<Dentry>) -> Arc<Dentry> {
+    let mut dentry = dentry.clone();
+    loop {
+        if Arc::ptr_eq(&dentry, root) {
+            return dentry;
+        }
+        let up = match (&dentry.parent, &dentry.mount.mountpoint) {
+            (Some(parent), _) => return parent.clone(),
+            (None, Some(mountpoint)) => mountpoint.clone(),
+            (None, None) => return dentry,
+        };
+        dentry = up;
+    }
+}
+
+/// # walk
+///
+/// Resolve `path` to a dentry. Absolute paths start at `root`, relative ones at `start`. Symlinks on the way
+/// are followed, and the last one too if `follow`
+pub async fn walk(root: &Arc<Dentry>, start: &Arc<Dentry>, path: &str, follow: bool) -> Result<Arc<Dentry>, FsError> {
+    if path.is_empty() {
+        return Err(FsError::NotFound);
+    }
+    let mut current = if is_absolute(path) { root.cross_mounts() } else { start.cross_mounts() };
+    // The names still to walk - a symlink's target goes on the front
+    let mut pending: VecDeque<String> = components(path).map(String::from).collect();
+    let mut links = 0;
+    while let Some(name) = pending.pop_front() {
+        if current.kind() != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+        if name == ".." {
+            current = parent_of(root, &current);
+            continue;
+        }
+        let child = current.child(&name).await?.cross_mounts();
+        if child.kind() == FileType::Symlink && (follow || !pending.is_empty()) {
+            links += 1;
+            if links > MAX_SYMLINKS {
+                return Err(FsError::TooManyLinks);
+            }
+            let target = child.inode.read_link().await?;
+            if is_absolute(&target) {
+                current = root.cross_mounts();
+            }
+            for name in components(&target).rev() {
+                pending.push_front(String::from(name));
+            }
+            continue;
+        }
+        current = child;
+    }
+    Ok(current)
+}
+
+/// # walk_parent
+///
+/// Resolve the directory `path` is in, for making or removing it - returns the directory and the last name
+pub async fn walk_parent(root: &Arc<Dentry>, start: &Arc<Dentry>, path: &str) -> Result<(Arc<Dentry>, String), FsError> {
+    let (directory, name) = split_last(path);
+    let name = match name {
+        Some(name) if is_valid_name(name) => name,
+        _ => return Err(FsError::InvalidArgument),
+    };
+    let parent = walk(root, start, directory, true).await?;
+    if parent.kind() != FileType::Directory {
+        return Err(FsError::NotDirectory);
+    }
+    Ok((parent, String::from(name)))
+}
+
+/* The mount table */
+
+/// The root of the tree
+static ROOT: Mutex<Option<Arc<Dentry>>> = Mutex::new(None);
+/// The root dentry of every mounted filesystem, the root filesystem first
+static MOUNTS: Mutex<Vec<Arc<Dentry>>> = Mutex::new(Vec::new());
+
+/// The root of the tree - `NotFound` if nothing is mounted yet
+pub fn root() -> Result<Arc<Dentry>, FsError> {
+    ROOT.lock().clone().ok_or(FsError::NotFound)
+}
+
+/// Put `filesystem` over `mountpoint`, returning the filesystem's root dentry
+fn mount_on(mountpoint: &Arc<Dentry>, filesystem: Arc<dyn Filesystem>) -> Result<Arc<Dentry>, FsError> {
+    if mountpoint.kind() != FileType::Directory {
+        return Err(FsError::NotDirectory);
+    }
+    let mut mounted = mountpoint.mounted.lock();
+    // One filesystem per directory, and not straight over another filesystem's root
+    if mounted.is_some() || mountpoint.is_mount_root() {
+        return Err(FsError::Busy);
+    }
+    let root = Dentry::new_root(filesystem, Some(mountpoint.clone()));
+    *mounted = Some(root.clone());
+    Ok(root)
+}
+
+/// # mount_root
+///
+/// Make `filesystem` the root of the tree. There can only be one
+pub fn mount_root(filesystem: Arc<dyn Filesystem>) -> Result<(), FsError> {
+    let mut root = ROOT.lock();
+    if root.is_some() {
+        return Err(FsError::Busy);
+    }
+    serial_println!("[LOG] Mounted {} at /", filesystem.name());
+    let dentry = Dentry::new_root(filesystem, None);
+    MOUNTS.lock().push(dentry.clone());
+    *root = Some(dentry);
+    Ok(())
+}
+
+/// # mount
+///
+/// Mount `filesystem` over the directory at `path`
+pub async fn mount(path: &str, filesystem: Arc<dyn Filesystem>) -> Result<(), FsError> {
+    let root = root()?;
+    let mountpoint = walk(&root, &root, path, true).await?;
+    let dentry = mount_on(&mountpoint, filesystem)?;
+    serial_println!("[LOG] Mounted {} at {}", dentry.mount.filesystem.name(), mountpoint.path());
+    MOUNTS.lock().push(dentry);
+    Ok(())
+}
+
+/// # unmount
+///
+/// Unmount the filesystem mounted at `path`, syncing it first. Fails with `Busy` for the root, and while
+/// other filesystems are mounted inside it. Files still open on it keep working
+pub async fn unmount(path: &str) -> Result<(), FsError> {
+    let root = root()?;
+    let dentry = walk(&root, &root, path, true).await?;
+    let mountpoint = match (dentry.is_mount_root(), &dentry.mount.mountpoint) {
+        (false, _) => return Err(FsError::InvalidArgument),
+        (true, None) => return Err(FsError::Busy),
+        (true, Some(mountpoint)) => mountpoint.clone(),
+    };
+    let nested = MOUNTS.lock().iter().any(|other| match &other.mount.mountpoint {
+        Some(under) => Arc::ptr_eq(&under.mount, &dentry.mount),
+        None => false,
+    });
+    if nested {
+        return Err(FsError::Busy);
+    }
+    dentry.mount.filesystem.sync().await?;
+    *mountpoint.mounted.lock() = None;
+    MOUNTS.lock().retain(|other| !Arc::ptr_eq(other, &dentry));
+    // Children point back at their parents - dropping the cache lets the dentries go
+    dentry.children.lock().clear();
+    serial_println!("[LOG] Unmounted {}", mountpoint.path());
+    Ok(())
+}
+
+/// # mounts
+///
+/// Every mounted filesystem, the root filesystem first
+pub fn mounts() -> Vec<Arc<Mount>> {
+    MOUNTS.lock().iter().map(|dentry| dentry.mount.clone()).collect()
+}
+
+/* Testing */
+
+#[test_case]
+fn dentry_walk() {
+    use alloc::boxed::Box;
+    use alloc::vec;
+    use crate::task::block_on;
+    use super::{FsFuture, Stat};
+
+    /// A read only tree of directories and symlinks
+    struct Node {
+        id: u64,
+        link: Option<&'static str>,
+        children: Vec<(&'static str, Arc<Node>)>,
+    }
+    impl Inode for Node {
+        fn id(&self) -> u64 {
+            self.id
+        }
+        fn kind(&self) -> FileType {
+            if self.link.is_some() { FileType::Symlink } else { FileType::Directory }
+        }
+        fn stat(&self) -> FsFuture<'_, Stat> {
+            Box::pin(async move { Ok(Stat { inode: self.id, kind: self.kind(), size: 0, links: 1, allocated: 0 }) })
+        }
+        fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+            Box::pin(async move {
+                let child = self.children.iter().find(|(child, _)| *child == name).ok_or(FsError::NotFound)?;
+                Ok(child.1.clone() as Arc<dyn Inode>)
+            })
+        }
+        fn read_link(&self) -> FsFuture<'_, String> {
+            Box::pin(async move { self.link.map(String::from).ok_or(FsError::NotSupported) })
+        }
+    }
+    struct Tree(Arc<Node>);
+    impl Filesystem for Tree {
+        fn name(&self) -> &str {
+            "tree"
+        }
+        fn root(&self) -> Arc<dyn Inode> {
+            self.0.clone()
+        }
+    }
+    let directory = |id, children| Arc::new(Node { id, link: None, children });
+    let symlink = |id, target| Arc::new(Node { id, link: Some(target), children: Vec::new() });
+
+    // / { a { b, up -> .., loop -> loop2, loop2 -> loop }, abs -> /a/b, mnt }, with { c, back -> ../a } on /mnt
+    let a = directory(2, vec![
+        ("b", directory(3, Vec::new())),
+        ("up", symlink(4, "..")),
+        ("loop", symlink(5, "loop2")),
+        ("loop2", symlink(6, "loop")),
+    ]);
+    let top = directory(1, vec![("a", a), ("abs", symlink(7, "/a/b")), ("mnt", directory(8, Vec::new()))]);
+    let other = directory(1, vec![("c", directory(2, Vec::new())), ("back", symlink(3, "../a"))]);
+    let root = Dentry::new_root(Arc::new(Tree(top)), None);
+    let mountpoint = block_on(walk(&root, &root, "/mnt", true)).unwrap();
+    let mounted = mount_on(&mountpoint, Arc::new(Tree(other))).unwrap();
+    assert_eq!(mount_on(&mountpoint, Arc::new(Tree(directory(1, Vec::new())))).err(), Some(FsError::Busy));
+
+    let resolve = |path: &str, follow: bool| block_on(walk(&root, &root, path, follow));
+    let path = |path: &str| resolve(path, true).map(|dentry| dentry.path());
+    assert_eq!(path("/a/b"), Ok(String::from("/a/b")));
+    assert!(Arc::ptr_eq(&resolve("/a//./b/../..", true).unwrap(), &root));
+    assert!(Arc::ptr_eq(&resolve("/..", true).unwrap(), &root));
+    assert_eq!(path("/abs/.."), Ok(String::from("/a")));
+    assert_eq!(path("/a/up/a/b"), Ok(String::from("/a/b")));
+    assert_eq!(resolve("/abs", false).map(|dentry| dentry.kind()), Ok(FileType::Symlink));
+
+    // Into the mount, and back out of it
+    assert!(Arc::ptr_eq(&resolve("/mnt", true).unwrap(), &mounted));
+    assert_eq!(path("/mnt/c"), Ok(String::from("/mnt/c")));
+    assert!(Arc::ptr_eq(&resolve("/mnt/c/../..", true).unwrap(), &root));
+    assert_eq!(path("/mnt/back/b"), Ok(String::from("/a/b")));
+
+    assert_eq!(path("/a/loop").err(), Some(FsError::TooManyLinks));
+    assert_eq!(path("/a/b/x").err(), Some(FsError::NotFound));
+
+    let (parent, name) = block_on(walk_parent(&root, &root, "/mnt/c/new")).unwrap();
+    assert_eq!((parent.path().as_str(), name.as_str()), ("/mnt/c", "new"));
+    assert_eq!(block_on(walk_parent(&root, &root, "/")).err(), Some(FsError::InvalidArgument));
+    assert_eq!(block_on(walk_parent(&root, &root, "/a/..")).err(), Some(FsError::InvalidArgument));
+}
@@ -0,0 +1,713 @@
+//! # FAT
+//!
+//! The FAT12, FAT16 and FAT32 filesystems, on a block device - what USB sticks, SD cards, floppies and EFI
+//! system partitions use, and what `mkfs.fat` makes. Files, directories, long names and writes all work, and
+//! [check](struct.FatFs.html#method.check) looks the whole volume over like `fsck.fat`.
+//!
+//! - The [boot sector](bpb/index.html) says where everything is, and which FAT it is.
+//! - The [FAT](table/index.html) chains each file's clusters together, and says which are free.
+//! - [Directories](dir/index.html) are lists of 8.3 entries, with long names in entries of their own.
+//!
+//! Everything goes through a [BufferCache](../../block/cache/struct.BufferCache.html) on a
+//! [RequestQueue](../../block/queue/struct.RequestQueue.html), so nothing is on the disk for sure until
+//! `sync`, which also writes the FAT32 FSInfo sector (the free cluster count and where to look for the next).
+//!
+//! FAT has no inode numbers - a file is its directory entry - so the inode number here is where the entry is.
+//! It changes when the file is renamed. FAT has no symlinks or hard links either, and no holes: growing a file
+//! writes the zeros. A file unlinked while it is open can't be read or written any more, as its clusters go
+//! straight away. Operations on a volume are done one at a time.
+
+pub mod bpb; // The boot sector and FSInfo
+pub mod table; // The FAT - cluster chains and allocation
+pub mod dir; // Directory entries and long names
+pub mod check; // Checking and repairing a volume
+
+pub use bpb::FatType;
+pub use check::CheckReport;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::block::cache::BufferCache;
+use crate::block::queue::RequestQueue;
+use crate::block::BlockDevice;
+use crate::sync::AsyncMutex;
+use bpb::{FsInfo, Layout, FSINFO_UNKNOWN};
+use dir::{Entry, ATTR_ARCHIVE, ATTR_DIRECTORY, ENTRY_SIZE};
+use super::{DirEntry, FileType, Filesystem, FsError, FsFuture, Inode, Stat};
+
+/// How many sectors each volume keeps cached
+const CACHE_SECTORS: usize = 64;
+
+/// The root directory's inode number. Everything else is numbered from where its entry is
+const ROOT_ID: u64 = 1;
+
+/// The inode number of the entry in `slot` of `directory`
+fn node_id(directory: u32, slot: u32) -> u64 {
+    ((directory as u64) << 32 | slot as u64) + 2
+}
+
+/// The free cluster count and next free hint - what FSInfo keeps
+struct Allocation {
+    /// `None` until counted, if FSInfo didn't say
+    free: Option<u32>,
+    next_free: u32,
+    /// Changed since FSInfo was written
+    dirty: bool,
+}
+
+/// A mounted FAT volume
+struct Volume {
+    layout: Layout,
+    disk: BufferCache,
+    read_only: bool,
+    /// Held for every operation - they read and write the FAT and directories in several steps
+    lock: AsyncMutex<()>,
+    allocation: Mutex<Allocation>,
+    /// Every node that is around, by inode number - so a file is one node however it is found
+    nodes: Mutex<BTreeMap<u64, Weak<Node>>>,
+}
+
+impl Volume {
+    /// Read `buffer.len()` bytes from `position`, through the cache
+    async fn read_bytes(&self, position: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        let sector_size = self.layout.bytes_per_sector as usize;
+        let mut done = 0;
+        while done < buffer.len() {
+            let at = position + done as u64;
+            let sector = at / sector_size as u64;
+            let within = (at % sector_size as u64) as usize;
+            let remaining = buffer.len() - done;
+            if within == 0 && remaining >= sector_size {
+                // Whole sectors go straight into the buffer
+                let whole = remaining / sector_size * sector_size;
+                self.disk.read(sector, &mut buffer[done..done + whole]).await?;
+                done += whole;
+            } else {
+                let part = (sector_size - within).min(remaining);
+                let mut scratch = vec![0; sector_size];
+                self.disk.read(sector, &mut scratch).await?;
+                buffer[done..done + part].copy_from_slice(&scratch[within..within + part]);
+                done += part;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `data` at `position`, through the cache
+    async fn write_bytes(&self, position: u64, data: &[u8]) -> Result<(), FsError> {
+        let sector_size = self.layout.bytes_per_sector as usize;
+        let mut done = 0;
+        while done < data.len() {
+            let at = position + done as u64;
+            let sector = at / sector_size as u64;
+            let within = (at % sector_size as u64) as usize;
+            let remaining = data.len() - done;
+            if within == 0 && remaining >= sector_size {
+                let whole = remaining / sector_size * sector_size;
+                self.disk.write(sector, &data[done..done + whole]).await?;
+                done += whole;
+            } else {
+                // Part of a sector - read it, change the part, write it back
+                let part = (sector_size - within).min(remaining);
+                let mut scratch = vec![0; sector_size];
+                self.disk.read(sector, &mut scratch).await?;
+                scratch[within..within + part].copy_from_slice(&data[done..done + part]);
+                self.disk.write(sector, &scratch).await?;
+                done += part;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill `cluster` with zeros
+    async fn zero_cluster(&self, cluster: u32) -> Result<(), FsError> {
+        let zeros = vec![0; self.layout.cluster_size() as usize];
+        self.write_bytes(self.layout.cluster_position(cluster), &zeros).await
+    }
+
+    /// What a directory's `..` says for `directory` - the root is always 0, even on FAT32
+    fn parent_reference(&self, directory: u32) -> u32 {
+        if directory == self.layout.root_cluster { 0 } else { directory }
+    }
+
+    /// The node with inode number `id`, if it is still around
+    fn find(&self, id: u64) -> Option<Arc<Node>> {
+        // Upgrade with the table unlocked - if it is dropped straight after, `Node::drop` takes the lock
+        let weak = self.nodes.lock().get(&id).cloned();
+        weak.and_then(|weak| weak.upgrade())
+    }
+
+    /// The node for `entry`, in `directory`
+    fn node(self: &Arc<Self>, directory: u32, entry: &Entry) -> Arc<Node> {
+        let id = node_id(directory, entry.slot);
+        if let Some(node) = self.find(id) {
+            return node;
+        }
+        let kind = if entry.is_directory() { FileType::Directory } else { FileType::Regular };
+        let size = if entry.is_directory() { 0 } else { entry.size() };
+        let location = Some(Location { directory, slot: entry.slot });
+        let state = NodeState { id, location, first_cluster: entry.first_cluster(), size, removed: false };
+        let node = Arc::new(Node { volume: self.clone(), kind, state: Mutex::new(state) });
+        self.nodes.lock().insert(id, Arc::downgrade(&node));
+        node
+    }
+
+    /// The entry with inode number `id` was removed - its node, if any, can't be used any more
+    fn detach(&self, id: u64) {
+        if let Some(node) = self.find(id) {
+            let mut state = node.state.lock();
+            state.removed = true;
+            state.location = None;
+        }
+        self.nodes.lock().remove(&id);
+    }
+
+    /// The entry with inode number `id` moved to `slot` of `directory`
+    fn moved(&self, id: u64, directory: u32, slot: u32) {
+        if let Some(node) = self.find(id) {
+            let new_id = node_id(directory, slot);
+            {
+                let mut state = node.state.lock();
+                state.id = new_id;
+                state.location = Some(Location { directory, slot });
+            }
+            let mut nodes = self.nodes.lock();
+            nodes.remove(&id);
+            nodes.insert(new_id, Arc::downgrade(&node));
+        }
+    }
+
+    /// How many clusters are free, counting them if that isn't known
+    async fn free_clusters(&self) -> Result<u32, FsError> {
+        if let Some(free) = self.allocation.lock().free {
+            return Ok(free);
+        }
+        let free = self.count_free().await?;
+        self.allocation.lock().free = Some(free);
+        Ok(free)
+    }
+
+    /// Write FSInfo if it changed, then everything cached
+    async fn sync(&self) -> Result<(), FsError> {
+        if self.read_only {
+            return Ok(());
+        }
+        let (free, next_free, dirty) = {
+            let allocation = self.allocation.lock();
+            (allocation.free, allocation.next_free, allocation.dirty)
+        };
+        if let (Some(sector), true) = (self.layout.fsinfo_sector, dirty) {
+            let size = self.layout.bytes_per_sector as usize;
+            let mut buffer = vec![0; size];
+            let position = sector as u64 * size as u64;
+            self.read_bytes(position, &mut buffer).await?;
+            FsInfo { free_clusters: free.unwrap_or(FSINFO_UNKNOWN), next_free }.write(&mut buffer);
+            self.write_bytes(position, &buffer).await?;
+            self.allocation.lock().dirty = false;
+        }
+        self.disk.flush().await?;
+        Ok(())
+    }
+}
+
+/// Where a node's short entry is
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    directory: u32,
+    slot: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct NodeState {
+    id: u64,
+    /// None for the root, and for removed entries
+    location: Option<Location>,
+    first_cluster: u32,
+    size: u32,
+    removed: bool,
+}
+
+/// A FAT file or directory
+struct Node {
+    volume: Arc<Volume>,
+    kind: FileType,
+    state: Mutex<NodeState>,
+}
+
+impl Node {
+    /// A copy of the state, as long as the entry is still there
+    fn current(&self) -> Result<NodeState, FsError> {
+        let state = *self.state.lock();
+        if state.removed { Err(FsError::NotFound) } else { Ok(state) }
+    }
+
+    /// The directory number of this directory, for the directory functions
+    fn directory(&self) -> Result<u32, FsError> {
+        if self.kind != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+        let state = self.current()?;
+        // Only the FAT12/16 root has no clusters
+        if state.first_cluster == 0 && state.location.is_some() {
+            return Err(FsError::Corrupt("directory without clusters"));
+        }
+        Ok(state.first_cluster)
+    }
+
+    /// Keep `state`, and write its first cluster and size into its entry
+    async fn save(&self, state: NodeState) -> Result<(), FsError> {
+        *self.state.lock() = state;
+        if let Some(location) = state.location {
+            let size = if self.kind == FileType::Directory { 0 } else { state.size };
+            self.volume
+                .update_entry(location.directory, location.slot, |entry| {
+                    dir::set_first_cluster(entry, state.first_cluster);
+                    dir::set_file_size(entry, size);
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Write `length` bytes at `offset` - of `data`, or zeros. Clusters are added as needed; if the volume fills
+    /// up, what fitted is kept
+    async fn write_range(
+        &self,
+        state: &mut NodeState,
+        clusters: &mut Vec<u32>,
+        offset: u64,
+        data: Option<&[u8]>,
+        length: usize,
+    ) -> Result<usize, FsError> {
+        let volume = &self.volume;
+        let cluster_size = volume.layout.cluster_size() as u64;
+        let zeros = if data.is_none() { vec![0; (cluster_size as usize).min(length)] } else { Vec::new() };
+        let mut done = 0;
+        while done < length {
+            let position = offset + done as u64;
+            let index = (position / cluster_size) as usize;
+            let within = position % cluster_size;
+            let part = ((cluster_size - within) as usize).min(length - done);
+            while clusters.len() <= index {
+                match volume.allocate(clusters.last().cloned()).await {
+                    Ok(cluster) => clusters.push(cluster),
+                    Err(FsError::NoSpace) if done > 0 => return Ok(done),
+                    Err(error) => return Err(error),
+                }
+                if state.first_cluster == 0 {
+                    state.first_cluster = clusters[0];
+                }
+            }
+            let source = match data {
+                Some(data) => &data[done..done + part],
+                None => &zeros[..part],
+            };
+            volume.write_bytes(volume.layout.cluster_position(clusters[index]) + within, source).await?;
+            done += part;
+            state.size = state.size.max((position + part as u64) as u32);
+        }
+        Ok(done)
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        // Renamed or removed nodes may have been replaced in the table - only take out this one
+        let id = self.state.get_mut().id;
+        let mut nodes = self.volume.nodes.lock();
+        if nodes.get(&id).map_or(false, |weak| core::ptr::eq(weak.as_ptr(), self)) {
+            nodes.remove(&id);
+        }
+    }
+}
+
+/// The biggest a FAT file can be
+const MAX_FILE_SIZE: u64 = u32::MAX as u64;
+
+impl Inode for Node {
+    fn id(&self) -> u64 {
+        self.state.lock().id
+    }
+
+    fn kind(&self) -> FileType {
+        self.kind
+    }
+
+    fn stat(&self) -> FsFuture<'_, Stat> {
+        Box::pin(async move {
+            let _guard = self.volume.lock.lock().await;
+            let state = self.current()?;
+            let cluster_size = self.volume.layout.cluster_size() as u64;
+            let (size, allocated) = if self.kind == FileType::Directory {
+                let size = match state.first_cluster {
+                    0 => self.volume.layout.root_entries as u64 * ENTRY_SIZE as u64,
+                    first => self.volume.chain(first).await?.len() as u64 * cluster_size,
+                };
+                (size, size)
+            } else {
+                let size = state.size as u64;
+                (size, (size + cluster_size - 1) / cluster_size * cluster_size)
+            };
+            Ok(Stat { inode: state.id, kind: self.kind, size, links: 1, allocated })
+        })
+    }
+
+    fn read_at<'a>(&'a self, offset: u64, buffer: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            if self.kind != FileType::Regular {
+                return Err(FsError::IsDirectory);
+            }
+            let _guard = self.volume.lock.lock().await;
+            let state = self.current()?;
+            if offset >= state.size as u64 {
+                return Ok(0);
+            }
+            let length = ((state.size as u64 - offset) as usize).min(buffer.len());
+            let clusters = self.volume.chain(state.first_cluster).await?;
+            let cluster_size = self.volume.layout.cluster_size() as u64;
+            let mut done = 0;
+            while done < length {
+                let position = offset + done as u64;
+                let cluster = *clusters.get((position / cluster_size) as usize).ok_or(FsError::Corrupt("file is shorter than its size"))?;
+                let within = position % cluster_size;
+                let part = ((cluster_size - within) as usize).min(length - done);
+                self.volume.read_bytes(self.volume.layout.cluster_position(cluster) + within, &mut buffer[done..done + part]).await?;
+                done += part;
+            }
+            Ok(length)
+        })
+    }
+
+    fn write_at<'a>(&'a self, offset: u64, buffer: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            if self.kind != FileType::Regular {
+                return Err(FsError::IsDirectory);
+            }
+            if self.volume.read_only {
+                return Err(FsError::ReadOnly);
+            }
+            let end = offset.checked_add(buffer.len() as u64).ok_or(FsError::InvalidArgument)?;
+            if end > MAX_FILE_SIZE {
+                return Err(FsError::InvalidArgument);
+            }
+            let _guard = self.volume.lock.lock().await;
+            let mut state = self.current()?;
+            let mut clusters = self.volume.chain(state.first_cluster).await?;
+            // Past the end - the gap is written as zeros first
+            let size = state.size as u64;
+            let mut result = Ok(0);
+            if offset > size {
+                let gap = (offset - size) as usize;
+                result = match self.write_range(&mut state, &mut clusters, size, None, gap).await {
+                    Ok(done) if done < gap => Err(FsError::NoSpace),
+                    other => other,
+                };
+            }
+            if result.is_ok() {
+                result = self.write_range(&mut state, &mut clusters, offset, Some(buffer), buffer.len()).await;
+            }
+            self.save(state).await?;
+            result
+        })
+    }
+
+    fn truncate(&self, size: u64) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            if self.kind != FileType::Regular {
+                return Err(FsError::IsDirectory);
+            }
+            if self.volume.read_only {
+                return Err(FsError::ReadOnly);
+            }
+            if size > MAX_FILE_SIZE {
+                return Err(FsError::InvalidArgument);
+            }
+            let _guard = self.volume.lock.lock().await;
+            let mut state = self.current()?;
+            let old = state.size as u64;
+            let mut result = Ok(());
+            if size < old {
+                let cluster_size = self.volume.layout.cluster_size() as u64;
+                let keep = ((size + cluster_size - 1) / cluster_size) as u32;
+                state.first_cluster = self.volume.truncate_chain(state.first_cluster, keep).await?;
+                state.size = size as u32;
+            } else if size > old {
+                let mut clusters = self.volume.chain(state.first_cluster).await?;
+                result = match self.write_range(&mut state, &mut clusters, old, None, (size - old) as usize).await {
+                    Ok(done) if done as u64 == size - old => Ok(()),
+                    Ok(_) => Err(FsError::NoSpace),
+                    Err(error) => Err(error),
+                };
+            }
+            self.save(state).await?;
+            result
+        })
+    }
+
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            let _guard = self.volume.lock.lock().await;
+            let directory = self.directory()?;
+            let loaded = self.volume.load_directory(directory).await?;
+            let entry = loaded.find(name).ok_or(FsError::NotFound)?;
+            Ok(self.volume.node(directory, entry) as Arc<dyn Inode>)
+        })
+    }
+
+    fn read_dir(&self) -> FsFuture<'_, Vec<DirEntry>> {
+        Box::pin(async move {
+            let _guard = self.volume.lock.lock().await;
+            let directory = self.directory()?;
+            let loaded = self.volume.load_directory(directory).await?;
+            Ok(loaded
+                .entries
+                .into_iter()
+                .filter(|entry| !entry.is_dot())
+                .map(|entry| DirEntry {
+                    inode: node_id(directory, entry.slot),
+                    kind: if entry.is_directory() { FileType::Directory } else { FileType::Regular },
+                    name: entry.name,
+                })
+                .collect())
+        })
+    }
+
+    fn create<'a>(&'a self, name: &'a str, kind: FileType) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            if self.volume.read_only {
+                return Err(FsError::ReadOnly);
+            }
+            let _guard = self.volume.lock.lock().await;
+            let directory = self.directory()?;
+            let volume = &self.volume;
+            let blank = b"           ";
+            let short = match kind {
+                FileType::Regular => dir::new_entry(blank, ATTR_ARCHIVE, 0),
+                FileType::Directory => {
+                    // A new directory has `.` and `..`, in a cluster of its own
+                    let cluster = volume.allocate(None).await?;
+                    volume.zero_cluster(cluster).await?;
+                    let mut dots = [0u8; 2 * ENTRY_SIZE];
+                    dots[..ENTRY_SIZE].copy_from_slice(&dir::new_entry(b".          ", ATTR_DIRECTORY, cluster));
+                    dots[ENTRY_SIZE..]
+                        .copy_from_slice(&dir::new_entry(b"..         ", ATTR_DIRECTORY, volume.parent_reference(directory)));
+                    volume.write_bytes(volume.layout.cluster_position(cluster), &dots).await?;
+                    dir::new_entry(blank, ATTR_DIRECTORY, cluster)
+                }
+                _ => return Err(FsError::NotSupported),
+            };
+            let (first_slot, slot) = match volume.add_entry(directory, name, short).await {
+                Ok(slots) => slots,
+                Err(error) => {
+                    if kind == FileType::Directory {
+                        volume.free_chain(dir::first_cluster(&short)).await?;
+                    }
+                    return Err(error);
+                }
+            };
+            let entry = Entry { name: String::from(name), short, first_slot, slot };
+            Ok(volume.node(directory, &entry) as Arc<dyn Inode>)
+        })
+    }
+
+    fn unlink<'a>(&'a self, name: &'a str) -> FsFuture<'a, ()> {
+        Box::pin(async move {
+            if self.volume.read_only {
+                return Err(FsError::ReadOnly);
+            }
+            let _guard = self.volume.lock.lock().await;
+            let directory = self.directory()?;
+            let volume = &self.volume;
+            let loaded = volume.load_directory(directory).await?;
+            let entry = loaded.find(name).ok_or(FsError::NotFound)?;
+            if entry.is_directory() && !volume.load_directory(entry.first_cluster()).await?.is_empty() {
+                return Err(FsError::NotEmpty);
+            }
+            volume.remove_entry(directory, entry.first_slot, entry.slot).await?;
+            volume.free_chain(entry.first_cluster()).await?;
+            volume.detach(node_id(directory, entry.slot));
+            Ok(())
+        })
+    }
+
+    fn rename<'a>(&'a self, name: &'a str, to: &'a Arc<dyn Inode>, new_name: &'a str) -> FsFuture<'a, ()> {
+        Box::pin(async move {
+            if self.volume.read_only {
+                return Err(FsError::ReadOnly);
+            }
+            let _guard = self.volume.lock.lock().await;
+            let volume = &self.volume;
+            let target = volume.find(to.id()).ok_or(FsError::CrossDevice)?;
+            if !core::ptr::eq(Arc::as_ptr(&target) as *const u8, Arc::as_ptr(to) as *const u8) {
+                return Err(FsError::CrossDevice);
+            }
+            let from = self.directory()?;
+            let into = target.directory()?;
+            let source = volume.load_directory(from).await?;
+            let entry = source.find(name).ok_or(FsError::NotFound)?.clone();
+
+            // Whatever has the new name goes - unless it is the same entry, and only the case is changing
+            let mut same = false;
+            if let Some(existing) = volume.load_directory(into).await?.find(new_name) {
+                if from == into && existing.slot == entry.slot {
+                    if existing.name == new_name {
+                        return Ok(());
+                    }
+                    same = true;
+                } else {
+                    match (entry.is_directory(), existing.is_directory()) {
+                        (true, false) => return Err(FsError::NotDirectory),
+                        (false, true) => return Err(FsError::IsDirectory),
+                        (true, true) if !volume.load_directory(existing.first_cluster()).await?.is_empty() => {
+                            return Err(FsError::NotEmpty)
+                        }
+                        _ => {}
+                    }
+                    volume.remove_entry(into, existing.first_slot, existing.slot).await?;
+                    volume.free_chain(existing.first_cluster()).await?;
+                    volume.detach(node_id(into, existing.slot));
+                }
+            }
+
+            // Changing case needs the old entry out of the way first. Otherwise the new entry is added first, so
+            // a failure leaves the old name
+            if same {
+                volume.remove_entry(from, entry.first_slot, entry.slot).await?;
+            }
+            let (_, slot) = match volume.add_entry(into, new_name, entry.short).await {
+                Ok(slots) => slots,
+                Err(error) => {
+                    if same {
+                        let start = entry.first_slot as usize * ENTRY_SIZE;
+                        let end = (entry.slot as usize + 1) * ENTRY_SIZE;
+                        for (index, slot) in source.slots[start..end].chunks(ENTRY_SIZE).enumerate() {
+                            volume.write_slot(from, entry.first_slot + index as u32, slot).await?;
+                        }
+                    }
+                    return Err(error);
+                }
+            };
+            if !same {
+                volume.remove_entry(from, entry.first_slot, entry.slot).await?;
+            }
+            // A directory that changed parent has to say so in its `..`
+            if entry.is_directory() && from != into {
+                let parent = volume.parent_reference(into);
+                volume.update_entry(entry.first_cluster(), 1, |dots| dir::set_first_cluster(dots, parent)).await?;
+            }
+            volume.moved(node_id(from, entry.slot), into, slot);
+            Ok(())
+        })
+    }
+}
+
+/// # FatFs
+///
+/// A FAT12, FAT16 or FAT32 filesystem on a block device
+pub struct FatFs {
+    volume: Arc<Volume>,
+    root: Arc<Node>,
+}
+
+impl FatFs {
+    /// Open the FAT filesystem on `device`. Its sector size has to be the filesystem's. If the device is read
+    /// only, so is the filesystem
+    pub async fn open(device: Arc<dyn BlockDevice>) -> Result<FatFs, FsError> {
+        let mut sector = vec![0; device.sector_size()];
+        device.read(0, &mut sector).await?;
+        let layout = Layout::parse(&sector)?;
+        if layout.bytes_per_sector as usize != device.sector_size() {
+            return Err(FsError::NotSupported);
+        }
+        if layout.total_sectors as u64 > device.sector_count() {
+            return Err(FsError::Corrupt("filesystem is bigger than its disk"));
+        }
+
+        let read_only = device.is_read_only();
+        let disk = BufferCache::new(Arc::new(RequestQueue::new(device)), CACHE_SECTORS);
+        let mut allocation = Allocation { free: None, next_free: 2, dirty: false };
+        if let Some(fsinfo) = layout.fsinfo_sector {
+            disk.read(fsinfo as u64, &mut sector).await?;
+            if let Some(info) = FsInfo::parse(&sector) {
+                // Only trusted if it makes sense - it is a hint, and `check` fixes it
+                if info.free_clusters <= layout.cluster_count {
+                    allocation.free = Some(info.free_clusters);
+                }
+                if layout.is_valid_cluster(info.next_free) {
+                    allocation.next_free = info.next_free;
+                }
+            }
+        }
+
+        let root_state = NodeState { id: ROOT_ID, location: None, first_cluster: layout.root_cluster, size: 0, removed: false };
+        let volume = Arc::new(Volume {
+            layout,
+            disk,
+            read_only,
+            lock: AsyncMutex::new(()),
+            allocation: Mutex::new(allocation),
+            nodes: Mutex::new(BTreeMap::new()),
+        });
+        let root = Arc::new(Node { volume: volume.clone(), kind: FileType::Directory, state: Mutex::new(root_state) });
+        volume.nodes.lock().insert(ROOT_ID, Arc::downgrade(&root));
+        Ok(FatFs { volume, root })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.volume.layout.fat_type
+    }
+
+    /// The volume label from the boot sector, if it has one
+    pub fn label(&self) -> &str {
+        &self.volume.layout.label
+    }
+
+    pub fn cluster_size(&self) -> u32 {
+        self.volume.layout.cluster_size()
+    }
+
+    pub fn cluster_count(&self) -> u32 {
+        self.volume.layout.cluster_count
+    }
+
+    /// How many clusters are free
+    pub async fn free_clusters(&self) -> Result<u32, FsError> {
+        let _guard = self.volume.lock.lock().await;
+        self.volume.free_clusters().await
+    }
+
+    /// Look the whole volume over, like `fsck.fat`. With `repair`, what can be fixed safely is
+    pub async fn check(&self, repair: bool) -> Result<CheckReport, FsError> {
+        if repair && self.volume.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let _guard = self.volume.lock.lock().await;
+        self.volume.check(repair).await
+    }
+}
+
+impl Filesystem for FatFs {
+    fn name(&self) -> &str {
+        "fat"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.volume.read_only
+    }
+
+    fn sync(&self) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            let _guard = self.volume.lock.lock().await;
+            self.volume.sync().await
+        })
+    }
+}
@@ -0,0 +1,296 @@
+//! # Boot sector
+//!
+//! The first sector of a FAT volume holds the BIOS parameter block (BPB), which says where everything is:
+//! the reserved sectors, the FATs, the root directory (a fixed area on FAT12/16, a cluster chain on FAT32)
+//! and the clusters. Which FAT it is depends only on how many clusters there are. FAT32 volumes also have an
+//! FSInfo sector, which remembers how many clusters are free and where to look for the next one.
+
+use alloc::string::String;
+use super::super::FsError;
+
+/// The lowest cluster number - 0 and 1 are reserved
+pub const FIRST_CLUSTER: u32 = 2;
+
+const FSINFO_LEAD: u32 = 0x4161_5252;
+const FSINFO_STRUCT: u32 = 0x6141_7272;
+const FSINFO_TRAIL: u32 = 0xAA55_0000;
+/// FSInfo's "don't know", for the free count and next free cluster
+pub const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+/// # FatType
+///
+/// How big FAT entries are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// Any entry at or above this ends a chain
+    pub fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xFF8,
+            FatType::Fat16 => 0xFFF8,
+            FatType::Fat32 => 0x0FFF_FFF8,
+        }
+    }
+
+    /// The entry for a bad cluster
+    pub fn bad_cluster(self) -> u32 {
+        self.end_of_chain() - 1
+    }
+
+    /// What is written to end a chain
+    pub fn end_marker(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+}
+
+fn u16_at(sector: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([sector[offset], sector[offset + 1]])
+}
+
+fn u32_at(sector: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([sector[offset], sector[offset + 1], sector[offset + 2], sector[offset + 3]])
+}
+
+/// # Layout
+///
+/// Where everything on a volume is, from its BPB. Positions are in sectors from the start of the volume
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    pub fat_type: FatType,
+    pub bytes_per_sector: u32,
+    pub sectors_per_cluster: u32,
+    pub reserved_sectors: u32,
+    pub fat_count: u32,
+    /// The size of each FAT
+    pub fat_size: u32,
+    /// The size of the fixed root directory, in entries - 0 on FAT32
+    pub root_entries: u32,
+    pub root_dir_sector: u32,
+    pub root_dir_sectors: u32,
+    pub first_data_sector: u32,
+    pub total_sectors: u32,
+    pub cluster_count: u32,
+    /// The root directory's first cluster on FAT32, 0 otherwise
+    pub root_cluster: u32,
+    pub fsinfo_sector: Option<u32>,
+    /// The only FAT in use, if FAT32 mirroring is off. Otherwise every FAT is kept the same
+    pub active_fat: Option<u32>,
+    pub volume_id: u32,
+    pub label: String,
+}
+
+impl Layout {
+    /// Read the BPB in the boot sector
+    pub fn parse(sector: &[u8]) -> Result<Layout, FsError> {
+        if sector.len() < 512 || sector[510] != 0x55 || sector[511] != 0xAA {
+            return Err(FsError::Corrupt("no boot sector signature"));
+        }
+        if sector[0] != 0xEB && sector[0] != 0xE9 {
+            return Err(FsError::Corrupt("no jump at the start of the boot sector"));
+        }
+        let bytes_per_sector = u16_at(sector, 11) as u32;
+        let sectors_per_cluster = sector[13] as u32;
+        let reserved_sectors = u16_at(sector, 14) as u32;
+        let fat_count = sector[16] as u32;
+        let root_entries = u16_at(sector, 17) as u32;
+        let total_sectors = match u16_at(sector, 19) {
+            0 => u32_at(sector, 32),
+            total => total as u32,
+        };
+        let fat_size = match u16_at(sector, 22) {
+            0 => u32_at(sector, 36),
+            size => size as u32,
+        };
+        if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096) {
+            return Err(FsError::Corrupt("bad bytes per sector"));
+        }
+        if !sectors_per_cluster.is_power_of_two() || sectors_per_cluster > 128 {
+            return Err(FsError::Corrupt("bad sectors per cluster"));
+        }
+        if reserved_sectors == 0 || fat_count == 0 || fat_size == 0 {
+            return Err(FsError::Corrupt("no reserved sectors or FATs"));
+        }
+
+        let root_dir_sector = reserved_sectors + fat_count * fat_size;
+        let root_dir_sectors = (root_entries * 32 + bytes_per_sector - 1) / bytes_per_sector;
+        let first_data_sector = root_dir_sector + root_dir_sectors;
+        if first_data_sector >= total_sectors {
+            return Err(FsError::Corrupt("no room for data after the FATs"));
+        }
+        let cluster_count = (total_sectors - first_data_sector) / sectors_per_cluster;
+        let fat_type = match cluster_count {
+            0..=4084 => FatType::Fat12,
+            4085..=65524 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        // Each FAT has to have an entry for every cluster
+        let entries = (fat_size as u64 * bytes_per_sector as u64 * 8) / match fat_type {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        };
+        if entries < cluster_count as u64 + 2 {
+            return Err(FsError::Corrupt("FAT is too small for the clusters"));
+        }
+
+        let (root_cluster, fsinfo_sector, active_fat, id_offset) = if fat_type == FatType::Fat32 {
+            if root_entries != 0 || u16_at(sector, 42) != 0 {
+                return Err(FsError::Corrupt("bad FAT32 BPB"));
+            }
+            let root_cluster = u32_at(sector, 44);
+            if root_cluster < FIRST_CLUSTER || root_cluster > cluster_count + 1 {
+                return Err(FsError::Corrupt("root directory cluster out of range"));
+            }
+            let fsinfo = u16_at(sector, 48) as u32;
+            let flags = u16_at(sector, 40);
+            let active_fat = if flags & 0x80 != 0 { Some((flags & 0xF) as u32) } else { None };
+            if active_fat.map_or(false, |fat| fat >= fat_count) {
+                return Err(FsError::Corrupt("active FAT out of range"));
+            }
+            let fsinfo_sector = if fsinfo != 0 && fsinfo != 0xFFFF && fsinfo < reserved_sectors { Some(fsinfo) } else { None };
+            (root_cluster, fsinfo_sector, active_fat, 64)
+        } else {
+            if root_entries == 0 {
+                return Err(FsError::Corrupt("no root directory"));
+            }
+            (0, None, None, 36)
+        };
+        // The extended boot signature says whether the volume ID and label are there
+        let (volume_id, label) = if sector[id_offset + 2] == 0x29 {
+            let label = &sector[id_offset + 7..id_offset + 18];
+            (u32_at(sector, id_offset + 3), label.iter().map(|&byte| byte as char).collect::<String>().trim_end().into())
+        } else {
+            (0, String::new())
+        };
+
+        Ok(Layout {
+            fat_type,
+            bytes_per_sector,
+            sectors_per_cluster,
+            reserved_sectors,
+            fat_count,
+            fat_size,
+            root_entries,
+            root_dir_sector,
+            root_dir_sectors,
+            first_data_sector,
+            total_sectors,
+            cluster_count,
+            root_cluster,
+            fsinfo_sector,
+            active_fat,
+            volume_id,
+            label,
+        })
+    }
+
+    pub fn cluster_size(&self) -> u32 {
+        self.bytes_per_sector * self.sectors_per_cluster
+    }
+
+    /// The highest cluster number
+    pub fn last_cluster(&self) -> u32 {
+        self.cluster_count + 1
+    }
+
+    pub fn is_valid_cluster(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..=self.last_cluster()).contains(&cluster)
+    }
+
+    /// The byte position of `cluster`
+    pub fn cluster_position(&self, cluster: u32) -> u64 {
+        let sector = self.first_data_sector as u64 + (cluster - FIRST_CLUSTER) as u64 * self.sectors_per_cluster as u64;
+        sector * self.bytes_per_sector as u64
+    }
+
+    /// The byte position of FAT number `fat`
+    pub fn fat_position(&self, fat: u32) -> u64 {
+        (self.reserved_sectors + fat * self.fat_size) as u64 * self.bytes_per_sector as u64
+    }
+
+    /// The byte position of `cluster`'s entry in a FAT, from the start of the FAT
+    pub fn entry_offset(&self, cluster: u32) -> u64 {
+        match self.fat_type {
+            FatType::Fat12 => cluster as u64 + cluster as u64 / 2,
+            FatType::Fat16 => cluster as u64 * 2,
+            FatType::Fat32 => cluster as u64 * 4,
+        }
+    }
+}
+
+/// # FsInfo
+///
+/// What a FAT32 FSInfo sector remembers. Either can be `FSINFO_UNKNOWN`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsInfo {
+    pub free_clusters: u32,
+    pub next_free: u32,
+}
+
+impl FsInfo {
+    /// Read an FSInfo sector - `None` if its signatures are wrong
+    pub fn parse(sector: &[u8]) -> Option<FsInfo> {
+        if u32_at(sector, 0) != FSINFO_LEAD || u32_at(sector, 484) != FSINFO_STRUCT || u32_at(sector, 508) != FSINFO_TRAIL {
+            return None;
+        }
+        Some(FsInfo { free_clusters: u32_at(sector, 488), next_free: u32_at(sector, 492) })
+    }
+
+    /// Write this into an FSInfo sector, signatures and all
+    pub fn write(&self, sector: &mut [u8]) {
+        sector[0..4].copy_from_slice(&FSINFO_LEAD.to_le_bytes());
+        sector[484..488].copy_from_slice(&FSINFO_STRUCT.to_le_bytes());
+        sector[488..492].copy_from_slice(&self.free_clusters.to_le_bytes());
+        sector[492..496].copy_from_slice(&self.next_free.to_le_bytes());
+        sector[508..512].copy_from_slice(&FSINFO_TRAIL.to_le_bytes());
+    }
+}
+
+/* Testing */
+
+#[test_case]
+fn fat_boot_sector() {
+    use alloc::vec;
+
+    // A 1.44 MB floppy, as mkfs.fat makes it
+    let mut sector = vec![0u8; 512];
+    sector[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    sector[11..13].copy_from_slice(&512u16.to_le_bytes());
+    sector[13] = 1;
+    sector[14..16].copy_from_slice(&1u16.to_le_bytes());
+    sector[16] = 2;
+    sector[17..19].copy_from_slice(&224u16.to_le_bytes());
+    sector[19..21].copy_from_slice(&2880u16.to_le_bytes());
+    sector[22..24].copy_from_slice(&9u16.to_le_bytes());
+    sector[38] = 0x29;
+    sector[39..43].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+    sector[43..54].copy_from_slice(b"FLOPPY     ");
+    sector[510] = 0x55;
+    sector[511] = 0xAA;
+
+    let layout = Layout::parse(&sector).unwrap();
+    assert_eq!(layout.fat_type, FatType::Fat12);
+    assert_eq!((layout.root_dir_sector, layout.root_dir_sectors, layout.first_data_sector), (19, 14, 33));
+    assert_eq!(layout.cluster_count, 2847);
+    assert_eq!((layout.volume_id, layout.label.as_str()), (0x1234_5678, "FLOPPY"));
+    assert_eq!(layout.cluster_position(2), 33 * 512);
+    assert_eq!(layout.entry_offset(3), 4);
+
+    // FATs too small for the clusters
+    sector[22..24].copy_from_slice(&1u16.to_le_bytes());
+    assert!(Layout::parse(&sector).is_err());
+
+    let mut fsinfo = vec![0u8; 512];
+    assert_eq!(FsInfo::parse(&fsinfo), None);
+    FsInfo { free_clusters: 100, next_free: 7 }.write(&mut fsinfo);
+    assert_eq!(FsInfo::parse(&fsinfo), Some(FsInfo { free_clusters: 100, next_free: 7 }));
+}
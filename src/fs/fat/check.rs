@@ -0,0 +1,249 @@
+//! # Checking
+//!
+//! Looks a FAT volume over the way `fsck.fat` does: every directory is walked from the root, and every
+//! chain followed, so each cluster in use is claimed by exactly one file or directory. Then the FAT is gone
+//! through for what is left over.
+//!
+//! Problems found:
+//!
+//! - chains that go out of range, run into a free or bad cluster, or share a cluster with another chain
+//!   (cross-linked);
+//! - files whose chain is longer or shorter than their size says;
+//! - directories with a wrong `.` or `..`, or long name entries that don't belong to a file;
+//! - lost clusters - marked used in the FAT, but not in any chain;
+//! - a free cluster count (from FSInfo) that doesn't match the FAT;
+//! - FATs that aren't the same, when they are mirrored.
+//!
+//! Repairing only does what can't lose data: lost clusters are freed, stray long name entries are deleted, the
+//! free count is set, and the FATs are made the same as the first. Everything else is just reported.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use super::bpb::FIRST_CLUSTER;
+use super::super::path::join;
+use super::super::FsError;
+use super::Volume;
+
+/// # CheckReport
+///
+/// What [check](../struct.FatFs.html#method.check) found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    pub files: u32,
+    /// Including the root
+    pub directories: u32,
+    /// Clusters in the chain of a file or directory
+    pub used_clusters: u32,
+    pub free_clusters: u32,
+    pub bad_clusters: u32,
+    /// Clusters the FAT says are used, that aren't in any chain
+    pub lost_clusters: u32,
+    /// What is wrong, one problem each
+    pub problems: Vec<String>,
+    /// Whether anything was changed to fix a problem
+    pub repaired: bool,
+}
+
+impl CheckReport {
+    /// Whether nothing is wrong
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Which clusters are in a chain already
+struct Claimed(Vec<u8>);
+
+impl Claimed {
+    /// Mark `cluster`, returning whether it was marked already
+    fn claim(&mut self, cluster: u32) -> bool {
+        let (byte, bit) = (cluster as usize / 8, 1 << (cluster % 8));
+        let claimed = self.0[byte] & bit != 0;
+        self.0[byte] |= bit;
+        claimed
+    }
+
+    fn is_claimed(&self, cluster: u32) -> bool {
+        self.0[cluster as usize / 8] & (1 << (cluster % 8)) != 0
+    }
+}
+
+impl Volume {
+    /// Follow the chain for `path` from `first`, claiming its clusters. Returns how long it is, or `None` (with
+    /// the problem reported) if it is broken
+    async fn claim_chain(
+        &self,
+        claimed: &mut Claimed,
+        first: u32,
+        path: &str,
+        report: &mut CheckReport,
+    ) -> Result<Option<u32>, FsError> {
+        let fat_type = self.layout.fat_type;
+        let mut cluster = first;
+        let mut length = 0;
+        loop {
+            if !self.layout.is_valid_cluster(cluster) {
+                report.problems.push(format!("{}: chain goes out of range at cluster {}", path, cluster));
+                return Ok(None);
+            }
+            if claimed.claim(cluster) {
+                report.problems.push(format!("{}: cross-linked at cluster {}", path, cluster));
+                return Ok(None);
+            }
+            length += 1;
+            report.used_clusters += 1;
+            let next = self.fat_entry(cluster).await?;
+            if next >= fat_type.end_of_chain() {
+                return Ok(Some(length));
+            }
+            if next < FIRST_CLUSTER || next == fat_type.bad_cluster() {
+                report.problems.push(format!("{}: chain runs into a free or bad cluster after {}", path, cluster));
+                return Ok(None);
+            }
+            cluster = next;
+        }
+    }
+
+    /// Check the whole volume, and fix what can be fixed safely if `repair` is set
+    pub(super) async fn check(&self, repair: bool) -> Result<CheckReport, FsError> {
+        let layout = &self.layout;
+        let fat_type = layout.fat_type;
+        let cluster_size = layout.cluster_size() as u64;
+        let mut report = CheckReport::default();
+        let mut claimed = Claimed(vec![0; (layout.last_cluster() as usize + 8) / 8]);
+
+        // The root directory, then everything under it - (directory, its parent, its path)
+        let root = layout.root_cluster;
+        if root != 0 && self.claim_chain(&mut claimed, root, "/", &mut report).await?.is_none() {
+            return Ok(report);
+        }
+        let mut pending = vec![(root, root, String::from("/"))];
+        while let Some((directory, parent, path)) = pending.pop() {
+            let loaded = match self.load_directory(directory).await {
+                Ok(loaded) => loaded,
+                Err(FsError::Corrupt(why)) => {
+                    report.problems.push(format!("{}: {}", path, why));
+                    continue;
+                }
+                Err(error) => return Err(error),
+            };
+            report.directories += 1;
+            if !loaded.orphans.is_empty() {
+                report.problems.push(format!("{}: {} long name entries without a file", path, loaded.orphans.len()));
+                if repair {
+                    for &slot in loaded.orphans.iter() {
+                        self.remove_entry(directory, slot, slot).await?;
+                    }
+                    report.repaired = true;
+                }
+            }
+            if directory != root {
+                let dots = (loaded.entries.first(), loaded.entries.get(1));
+                // `..` should say 0 for the root, but some tools put the FAT32 root's cluster there
+                let parents = [self.parent_reference(parent), parent];
+                match dots {
+                    (Some(dot), Some(dotdot)) if &dot.short[..2] == b". " && &dotdot.short[..3] == b".. " => {
+                        if dot.first_cluster() != directory || !parents.contains(&dotdot.first_cluster()) {
+                            report.problems.push(format!("{}: . or .. points at the wrong directory", path));
+                        }
+                    }
+                    _ => report.problems.push(format!("{}: no . and .. entries", path)),
+                }
+            }
+
+            for entry in loaded.entries.iter().filter(|entry| !entry.is_dot()) {
+                let child = join(&path, &entry.name);
+                let first = entry.first_cluster();
+                let length = match first {
+                    0 => Some(0),
+                    first => self.claim_chain(&mut claimed, first, &child, &mut report).await?,
+                };
+                if entry.is_directory() {
+                    match (first, length) {
+                        (0, _) => report.problems.push(format!("{}: directory without clusters", child)),
+                        (_, Some(_)) => pending.push((first, directory, child)),
+                        (_, None) => {}
+                    }
+                    continue;
+                }
+                report.files += 1;
+                let expected = (entry.size() as u64 + cluster_size - 1) / cluster_size;
+                match length {
+                    Some(length) if length as u64 > expected => {
+                        report.problems.push(format!("{}: {} clusters is too long for {} bytes", child, length, entry.size()))
+                    }
+                    Some(length) if (length as u64) < expected => {
+                        report.problems.push(format!("{}: {} clusters is too short for {} bytes", child, length, entry.size()))
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // What is left in the FAT
+        let mut counted_free = 0;
+        for cluster in FIRST_CLUSTER..=layout.last_cluster() {
+            let value = self.fat_entry(cluster).await?;
+            if value == 0 {
+                counted_free += 1;
+                report.free_clusters += 1;
+            } else if value == fat_type.bad_cluster() {
+                report.bad_clusters += 1;
+            } else if !claimed.is_claimed(cluster) {
+                report.lost_clusters += 1;
+                if repair {
+                    self.set_fat_entry(cluster, 0).await?;
+                    report.free_clusters += 1;
+                }
+            }
+        }
+        if report.lost_clusters > 0 {
+            report.problems.push(format!("{} lost clusters", report.lost_clusters));
+            report.repaired |= repair;
+        }
+
+        {
+            let mut allocation = self.allocation.lock();
+            if let Some(free) = allocation.free.filter(|&free| free != counted_free) {
+                report.problems.push(format!("free cluster count is {}, but {} are free", free, counted_free));
+                report.repaired |= repair;
+            }
+            // Set it if it is being repaired (freeing lost clusters changes it too), or wasn't known
+            if (repair || allocation.free.is_none()) && allocation.free != Some(report.free_clusters) {
+                allocation.free = Some(report.free_clusters);
+                allocation.dirty = true;
+            }
+        }
+
+        // Mirrored FATs should all be the same as the first
+        if layout.active_fat.is_none() {
+            let sector_size = layout.bytes_per_sector as usize;
+            let (mut first, mut other) = (vec![0; sector_size], vec![0; sector_size]);
+            for fat in 1..layout.fat_count {
+                let mut differs = false;
+                for sector in 0..layout.fat_size as u64 {
+                    let offset = sector * sector_size as u64;
+                    self.read_bytes(layout.fat_position(0) + offset, &mut first).await?;
+                    self.read_bytes(layout.fat_position(fat) + offset, &mut other).await?;
+                    if first != other {
+                        differs = true;
+                        if repair {
+                            self.write_bytes(layout.fat_position(fat) + offset, &first).await?;
+                        }
+                    }
+                }
+                if differs {
+                    report.problems.push(format!("FAT {} is not the same as FAT 0", fat));
+                    report.repaired |= repair;
+                }
+            }
+        }
+
+        if report.repaired {
+            self.sync().await?;
+        }
+        Ok(report)
+    }
+}
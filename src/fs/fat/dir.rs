@@ -0,0 +1,568 @@
+//! # Directories
+//!
+//! A FAT directory is a list of 32 byte entries. Every file has a short entry - an 8.3 name in capitals, its
+//! attributes, first cluster and size. A name that doesn't fit 8.3 is kept in long name (VFAT) entries
+//! before the short entry: 13 UTF-16 characters each, last part first, each with a checksum of the short name
+//! so a tool that doesn't know about long names (and moves the short entry) can be caught.
+//!
+//! A short name is still needed for those files. It is made from the long name (capitals, no spaces, cut to
+//! 8.3), with a `~1` style tail if anything was lost or the name is taken. Names that fit 8.3 but are all
+//! lower case get no long name: two flags in the short entry say to show its name or extension in lower case,
+//! as Windows does.
+//!
+//! A deleted entry starts with 0xE5, and an entry starting with 0 ends the directory. The fixed root directory
+//! of FAT12/16 is directory "0" here; everything else is the cluster chain it starts at.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use super::super::FsError;
+use super::Volume;
+
+pub const ENTRY_SIZE: usize = 32;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+/// What marks a long name entry - no real entry has all four
+pub const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+/// The first byte of a deleted entry
+pub const DELETED: u8 = 0xE5;
+/// Set on the last long name entry of a name (the first one in the directory)
+const LAST_LONG_ENTRY: u8 = 0x40;
+/// Characters in each long name entry
+const LONG_NAME_CHARACTERS: usize = 13;
+/// Where the characters of a long name entry are
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_CHARACTERS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// The most UTF-16 characters a long name can have
+const MAX_LONG_NAME: usize = 255;
+/// A directory can't have more entries than this
+const MAX_ENTRIES: usize = 65536;
+
+/// Short entry flags (in the byte Windows NT reserved) - show the name or extension in lower case
+const LOWER_CASE_BASE: u8 = 0x08;
+const LOWER_CASE_EXTENSION: u8 = 0x10;
+
+/// The date in new entries - there is no clock yet, so the earliest FAT date, 1980-01-01
+const DEFAULT_DATE: u16 = (1 << 5) | 1;
+
+/// The first cluster in a short entry
+pub fn first_cluster(entry: &[u8]) -> u32 {
+    (u16::from_le_bytes([entry[20], entry[21]]) as u32) << 16 | u16::from_le_bytes([entry[26], entry[27]]) as u32
+}
+
+pub fn set_first_cluster(entry: &mut [u8], cluster: u32) {
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+/// The file size in a short entry
+pub fn file_size(entry: &[u8]) -> u32 {
+    u32::from_le_bytes([entry[28], entry[29], entry[30], entry[31]])
+}
+
+pub fn set_file_size(entry: &mut [u8], size: u32) {
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+}
+
+/// A short entry with `attributes`, starting at `cluster`, named `name` (padded 8.3, like `b"..         "`)
+pub fn new_entry(name: &[u8; 11], attributes: u8, cluster: u32) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0u8; ENTRY_SIZE];
+    entry[..11].copy_from_slice(name);
+    entry[11] = attributes;
+    for &offset in [16, 18, 24].iter() {
+        entry[offset..offset + 2].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+    }
+    set_first_cluster(&mut entry, cluster);
+    entry
+}
+
+/// # Entry
+///
+/// A name in a directory, and the slots it takes - its long name entries, then its short entry at `slot`
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    pub short: [u8; ENTRY_SIZE],
+    pub first_slot: u32,
+    pub slot: u32,
+}
+
+impl Entry {
+    pub fn attributes(&self) -> u8 {
+        self.short[11]
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.attributes() & ATTR_DIRECTORY != 0
+    }
+
+    pub fn first_cluster(&self) -> u32 {
+        first_cluster(&self.short)
+    }
+
+    pub fn size(&self) -> u32 {
+        file_size(&self.short)
+    }
+
+    /// Whether it is `.` or `..`
+    pub fn is_dot(&self) -> bool {
+        self.short[0] == b'.'
+    }
+}
+
+/// # Directory
+///
+/// A whole directory, read into memory
+pub struct Directory {
+    /// Its clusters - none for the fixed root directory
+    pub clusters: Vec<u32>,
+    /// Every slot, used or not
+    pub slots: Vec<u8>,
+    pub entries: Vec<Entry>,
+    /// The slots of long name entries that don't belong to any short entry
+    pub orphans: Vec<u32>,
+}
+
+impl Directory {
+    /// The entry called `name` - names are compared ignoring case, as Windows does
+    pub fn find(&self, name: &str) -> Option<&Entry> {
+        self.entries.iter().filter(|entry| !entry.is_dot()).find(|entry| same_name(&entry.name, name))
+    }
+
+    /// Whether there's anything but `.` and `..`
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| entry.is_dot())
+    }
+
+    fn slot_count(&self) -> usize {
+        self.slots.len() / ENTRY_SIZE
+    }
+
+    /// Whether a short name is taken
+    fn has_short_name(&self, name: &[u8; 11]) -> bool {
+        self.entries.iter().any(|entry| &entry.short[..11] == name)
+    }
+}
+
+/// Whether two names are the same, ignoring case
+pub fn same_name(a: &str, b: &str) -> bool {
+    a.chars().flat_map(char::to_uppercase).eq(b.chars().flat_map(char::to_uppercase))
+}
+
+/// Whether `name` can be a long name - Windows doesn't allow control characters, any of `"*/:<>?\|`, or a
+/// space or `.` at the end
+pub fn is_valid_long_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.encode_utf16().count() <= MAX_LONG_NAME
+        && !name.ends_with(|c| c == '.' || c == ' ')
+        && name.chars().all(|c| c >= ' ' && !"\"*/:<>?\\|\u{7F}".contains(c))
+}
+
+/// Whether `c` can be in a short name, as it is
+fn is_short_character(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || "$%'-_@~`!(){}^#&".contains(c)
+}
+
+/// The checksum of a short name, kept in its long name entries
+pub fn checksum(name: &[u8]) -> u8 {
+    name[..11].iter().fold(0u8, |sum, &byte| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(byte))
+}
+
+/// The name to show for a short entry
+fn short_name(entry: &[u8]) -> String {
+    let part = |bytes: &[u8], lower: bool| -> String {
+        let text = bytes.iter().map(|&byte| byte as char).collect::<String>();
+        let text = text.trim_end();
+        if lower { text.to_ascii_lowercase() } else { String::from(text) }
+    };
+    let mut base = [0u8; 8];
+    base.copy_from_slice(&entry[..8]);
+    // 0xE5 is a real first character, kept as 0x05
+    if base[0] == 0x05 {
+        base[0] = DELETED;
+    }
+    let mut name = part(&base, entry[12] & LOWER_CASE_BASE != 0);
+    let extension = part(&entry[8..11], entry[12] & LOWER_CASE_EXTENSION != 0);
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(&extension);
+    }
+    name
+}
+
+/// `name` as a short name, and the lower case flags, if it fits 8.3 exactly - no long name is needed
+fn short_form(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, extension) = match name.find('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 || extension.contains('.') || !name.is_ascii() {
+        return None;
+    }
+    // Each part is all capitals or all lower case
+    let case = |part: &str, flag: u8| -> Option<u8> {
+        if part.chars().all(|c| is_short_character(c.to_ascii_uppercase()) && !c.is_ascii_lowercase()) {
+            Some(0)
+        } else if part.chars().all(|c| is_short_character(c.to_ascii_uppercase()) && !c.is_ascii_uppercase()) {
+            Some(flag)
+        } else {
+            None
+        }
+    };
+    let flags = case(base, LOWER_CASE_BASE)? | case(extension, LOWER_CASE_EXTENSION)?;
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+    short[8..8 + extension.len()].copy_from_slice(extension.to_ascii_uppercase().as_bytes());
+    Some((short, flags))
+}
+
+/// The short name a long name starts from - capitals, without spaces or leading dots, other characters as
+/// `_`, cut to 8.3 - and whether anything was lost doing that
+fn basis_name(name: &str) -> ([u8; 11], bool) {
+    let trimmed = name.trim_start_matches('.');
+    let mut lossy = trimmed.len() != name.len();
+    let mut convert = |part: &str, length: usize| -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for c in part.chars() {
+            if c == ' ' || c == '.' {
+                lossy = true;
+                continue;
+            }
+            let c = c.to_ascii_uppercase();
+            let byte = if c.is_ascii() && is_short_character(c) {
+                c as u8
+            } else {
+                lossy = true;
+                b'_'
+            };
+            if bytes.len() == length {
+                lossy = true;
+                break;
+            }
+            bytes.push(byte);
+        }
+        bytes
+    };
+    let (base, extension) = match trimmed.rfind('.') {
+        Some(dot) => (convert(&trimmed[..dot], 8), convert(&trimmed[dot + 1..], 3)),
+        None => (convert(trimmed, 8), Vec::new()),
+    };
+    let mut short = [b' '; 11];
+    if base.is_empty() {
+        short[0] = b'_';
+        lossy = true;
+    }
+    short[..base.len()].copy_from_slice(&base);
+    short[8..8 + extension.len()].copy_from_slice(&extension);
+    (short, lossy)
+}
+
+/// `basis` with the tail `~number`, cutting its base to make room
+fn with_tail(basis: &[u8; 11], number: u32) -> [u8; 11] {
+    let tail = alloc::format!("~{}", number);
+    let base = basis[..8].iter().position(|&byte| byte == b' ').unwrap_or(8).min(8 - tail.len());
+    let mut short = *basis;
+    short[base..base + tail.len()].copy_from_slice(tail.as_bytes());
+    short[base + tail.len()..8].iter_mut().for_each(|byte| *byte = b' ');
+    short
+}
+
+/// The long name entries for `name`, in the order they go in the directory
+fn long_entries(name: &str, checksum: u8) -> Vec<[u8; ENTRY_SIZE]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    // Ended by a 0 if there's room, then padded with 0xFFFF
+    if units.len() % LONG_NAME_CHARACTERS != 0 {
+        units.push(0);
+    }
+    while units.len() % LONG_NAME_CHARACTERS != 0 {
+        units.push(0xFFFF);
+    }
+    let count = units.len() / LONG_NAME_CHARACTERS;
+    (0..count)
+        .rev()
+        .map(|index| {
+            let mut entry = [0u8; ENTRY_SIZE];
+            entry[0] = (index + 1) as u8 | if index + 1 == count { LAST_LONG_ENTRY } else { 0 };
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+            let part = &units[index * LONG_NAME_CHARACTERS..(index + 1) * LONG_NAME_CHARACTERS];
+            for (unit, &offset) in part.iter().zip(LONG_NAME_OFFSETS.iter()) {
+                entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            entry
+        })
+        .collect()
+}
+
+/// A long name being put together, from its last part back
+struct LongName {
+    checksum: u8,
+    /// The ordinal of the next entry - 0 when the name is complete
+    next: u8,
+    first_slot: u32,
+    /// The slot after its last entry so far
+    end_slot: u32,
+    units: Vec<u16>,
+}
+
+impl LongName {
+    fn slots(&self) -> core::ops::Range<u32> {
+        self.first_slot..self.end_slot
+    }
+}
+
+/// The names in a directory's slots, and the slots of long name entries that don't belong to one
+pub fn parse(slots: &[u8]) -> (Vec<Entry>, Vec<u32>) {
+    let mut entries = Vec::new();
+    let mut orphans = Vec::new();
+    let mut long: Option<LongName> = None;
+    for (index, slot) in slots.chunks_exact(ENTRY_SIZE).enumerate() {
+        let index = index as u32;
+        match slot[0] {
+            0 => break,
+            DELETED => {
+                orphans.extend(long.take().map_or(0..0, |long| long.slots()));
+                continue;
+            }
+            _ => {}
+        }
+        if slot[11] & 0x3F == ATTR_LONG_NAME {
+            let ordinal = slot[0] & 0x1F;
+            if slot[0] & LAST_LONG_ENTRY != 0 {
+                orphans.extend(long.take().map_or(0..0, |long| long.slots()));
+                if ordinal != 0 {
+                    let units = vec![0; ordinal as usize * LONG_NAME_CHARACTERS];
+                    long = Some(LongName { checksum: slot[13], next: ordinal, first_slot: index, end_slot: index, units });
+                }
+            }
+            match &mut long {
+                Some(name) if name.next == ordinal && ordinal != 0 && name.checksum == slot[13] => {
+                    let start = (ordinal as usize - 1) * LONG_NAME_CHARACTERS;
+                    for (position, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+                        name.units[start + position] = u16::from_le_bytes([slot[offset], slot[offset + 1]]);
+                    }
+                    name.next -= 1;
+                    name.end_slot = index + 1;
+                }
+                _ => {
+                    orphans.extend(long.take().map_or(0..0, |long| long.slots()));
+                    orphans.push(index);
+                }
+            }
+            continue;
+        }
+        if slot[11] & ATTR_VOLUME_ID != 0 {
+            // The volume label
+            orphans.extend(long.take().map_or(0..0, |long| long.slots()));
+            continue;
+        }
+
+        let mut short = [0u8; ENTRY_SIZE];
+        short.copy_from_slice(slot);
+        let (name, first_slot) = match long.take() {
+            Some(long) if long.next == 0 && long.checksum == checksum(slot) => {
+                let end = long.units.iter().position(|&unit| unit == 0).unwrap_or(long.units.len());
+                let name: String =
+                    core::char::decode_utf16(long.units[..end].iter().cloned()).map(|c| c.unwrap_or('\u{FFFD}')).collect();
+                (name, long.first_slot)
+            }
+            other => {
+                orphans.extend(other.map_or(0..0, |long| long.slots()));
+                (short_name(slot), index)
+            }
+        };
+        entries.push(Entry { name, short, first_slot, slot: index });
+    }
+    orphans.extend(long.map_or(0..0, |long| long.slots()));
+    (entries, orphans)
+}
+
+impl Volume {
+    /// Read the whole of directory `directory`
+    pub(super) async fn load_directory(&self, directory: u32) -> Result<Directory, FsError> {
+        let (clusters, slots) = if directory == 0 {
+            let mut slots = vec![0; self.layout.root_entries as usize * ENTRY_SIZE];
+            self.read_bytes(self.root_position(), &mut slots).await?;
+            (Vec::new(), slots)
+        } else {
+            let clusters = self.chain(directory).await?;
+            let size = self.layout.cluster_size() as usize;
+            let mut slots = vec![0; clusters.len() * size];
+            for (index, &cluster) in clusters.iter().enumerate() {
+                self.read_bytes(self.layout.cluster_position(cluster), &mut slots[index * size..(index + 1) * size]).await?;
+            }
+            (clusters, slots)
+        };
+        let (entries, orphans) = parse(&slots);
+        Ok(Directory { clusters, slots, entries, orphans })
+    }
+
+    /// Where the fixed root directory is
+    fn root_position(&self) -> u64 {
+        self.layout.root_dir_sector as u64 * self.layout.bytes_per_sector as u64
+    }
+
+    /// Where `slot` of a directory with `clusters` is
+    fn slot_position(&self, clusters: &[u32], slot: u32) -> u64 {
+        let offset = slot as u64 * ENTRY_SIZE as u64;
+        if clusters.is_empty() {
+            return self.root_position() + offset;
+        }
+        let size = self.layout.cluster_size() as u64;
+        self.layout.cluster_position(clusters[(offset / size) as usize]) + offset % size
+    }
+
+    /// Write `entry` into `slot` of `directory`
+    pub(super) async fn write_slot(&self, directory: u32, slot: u32, entry: &[u8]) -> Result<(), FsError> {
+        let clusters = if directory == 0 { Vec::new() } else { self.chain(directory).await? };
+        let slots = match directory {
+            0 => self.layout.root_entries as usize,
+            _ => clusters.len() * self.layout.cluster_size() as usize / ENTRY_SIZE,
+        };
+        if slot as usize >= slots {
+            return Err(FsError::Corrupt("directory entry out of range"));
+        }
+        self.write_bytes(self.slot_position(&clusters, slot), &entry[..ENTRY_SIZE]).await
+    }
+
+    /// Change the short entry in `slot` of `directory` with `change`
+    pub(super) async fn update_entry<F: FnOnce(&mut [u8])>(&self, directory: u32, slot: u32, change: F) -> Result<(), FsError> {
+        let clusters = if directory == 0 { Vec::new() } else { self.chain(directory).await? };
+        let position = self.slot_position(&clusters, slot);
+        let mut entry = [0u8; ENTRY_SIZE];
+        self.read_bytes(position, &mut entry).await?;
+        change(&mut entry);
+        self.write_bytes(position, &entry).await
+    }
+
+    /// Add `name` to `directory`, with the short entry `short` (its name is filled in). Returns the slots it
+    /// took - the first of its long name, and its short entry
+    pub(super) async fn add_entry(&self, directory: u32, name: &str, mut short: [u8; ENTRY_SIZE]) -> Result<(u32, u32), FsError> {
+        if !is_valid_long_name(name) {
+            return Err(FsError::InvalidArgument);
+        }
+        let mut loaded = self.load_directory(directory).await?;
+        if loaded.find(name).is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let (short_name, flags, long) = match short_form(name) {
+            Some((short_name, flags)) if !loaded.has_short_name(&short_name) => (short_name, flags, false),
+            _ => {
+                let (basis, lossy) = basis_name(name);
+                let short_name = if !lossy && !loaded.has_short_name(&basis) {
+                    basis
+                } else {
+                    (1..1_000_000)
+                        .map(|number| with_tail(&basis, number))
+                        .find(|short_name| !loaded.has_short_name(short_name))
+                        .ok_or(FsError::NoSpace)?
+                };
+                (short_name, 0, true)
+            }
+        };
+        short[..11].copy_from_slice(&short_name);
+        short[12] = flags;
+        let mut slots = if long { long_entries(name, checksum(&short_name)) } else { Vec::new() };
+        slots.push(short);
+
+        // The first run of free slots that is long enough. Everything from the end marker on is free, and the
+        // directory grows if that isn't enough
+        let count = loaded.slot_count();
+        let (mut start, mut run) = (count, 0);
+        for index in 0..count {
+            let first = loaded.slots[index * ENTRY_SIZE];
+            if first != 0 && first != DELETED {
+                run = 0;
+                continue;
+            }
+            if run == 0 {
+                start = index;
+            }
+            run += 1;
+            if first == 0 || run == slots.len() {
+                break;
+            }
+        }
+        if run == 0 {
+            start = count;
+        }
+        let end = start + slots.len();
+
+        // Grow the directory to fit, with zeroed clusters
+        if end > loaded.slot_count() {
+            if directory == 0 || end > MAX_ENTRIES {
+                return Err(FsError::NoSpace);
+            }
+            let per_cluster = self.layout.cluster_size() as usize / ENTRY_SIZE;
+            while loaded.clusters.len() * per_cluster < end {
+                let cluster = self.allocate(loaded.clusters.last().cloned()).await?;
+                self.zero_cluster(cluster).await?;
+                loaded.clusters.push(cluster);
+            }
+        }
+        for (index, slot) in slots.iter().enumerate() {
+            self.write_bytes(self.slot_position(&loaded.clusters, (start + index) as u32), slot).await?;
+        }
+        Ok((start as u32, (end - 1) as u32))
+    }
+
+    /// Mark the slots from `first_slot` to `slot` of `directory` deleted
+    pub(super) async fn remove_entry(&self, directory: u32, first_slot: u32, slot: u32) -> Result<(), FsError> {
+        let clusters = if directory == 0 { Vec::new() } else { self.chain(directory).await? };
+        for index in first_slot..=slot {
+            self.write_bytes(self.slot_position(&clusters, index), &[DELETED]).await?;
+        }
+        Ok(())
+    }
+}
+
+/* Testing */
+
+#[test_case]
+fn fat_names() {
+    assert_eq!(short_form("README.TXT"), Some((*b"README  TXT", 0)));
+    assert_eq!(short_form("hello.txt"), Some((*b"HELLO   TXT", LOWER_CASE_BASE | LOWER_CASE_EXTENSION)));
+    assert_eq!(short_form("Makefile"), None);
+    assert_eq!(short_form("toolongname.c"), None);
+    assert_eq!(short_form("a.b.c"), None);
+
+    assert_eq!(basis_name("Makefile"), (*b"MAKEFILE   ", false));
+    assert_eq!(basis_name("A long file name.text"), (*b"ALONGFILTEX", true));
+    assert_eq!(basis_name(".bashrc"), (*b"BASHRC     ", true));
+    assert_eq!(with_tail(b"ALONGFILTEX", 1), *b"ALONGF~1TEX");
+    assert_eq!(with_tail(b"AB      C  ", 12), *b"AB~12   C  ");
+
+    assert!(is_valid_long_name("A long file name.txt"));
+    assert!(!is_valid_long_name("what?"));
+    assert!(!is_valid_long_name("trailing."));
+    assert!(same_name("Hello.TXT", "hello.txt"));
+
+    // A long name, and its short entry, read back
+    let mut slots = Vec::new();
+    let short_name = with_tail(&basis_name("A long file name.text").0, 1);
+    for entry in long_entries("A long file name.text", checksum(&short_name)) {
+        slots.extend_from_slice(&entry);
+    }
+    assert_eq!(slots.len(), 2 * ENTRY_SIZE);
+    slots.extend_from_slice(&new_entry(&short_name, ATTR_ARCHIVE, 5));
+    let mut lower = new_entry(b"HELLO   TXT", ATTR_ARCHIVE, 0);
+    lower[12] = LOWER_CASE_BASE;
+    slots.extend_from_slice(&lower);
+    // A long name entry whose short entry was deleted
+    slots.extend_from_slice(&long_entries("gone", 0)[0]);
+    slots.extend_from_slice(&[DELETED; ENTRY_SIZE]);
+    slots.extend_from_slice(&[0; ENTRY_SIZE]);
+
+    let (entries, orphans) = parse(&slots);
+    assert_eq!(entries.len(), 2);
+    assert_eq!((entries[0].name.as_str(), entries[0].first_slot, entries[0].slot), ("A long file name.text", 0, 2));
+    assert_eq!(entries[0].first_cluster(), 5);
+    assert_eq!((entries[1].name.as_str(), entries[1].first_slot), ("hello.TXT", 3));
+    assert_eq!(orphans, [4]);
+}
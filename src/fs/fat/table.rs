@@ -0,0 +1,202 @@
+//! # The FAT
+//!
+//! The file allocation table has an entry for every cluster: 0 if it is free, the next cluster of its chain,
+//! or a marker for the end of a chain (or a bad cluster). Files and directories are chains, starting at the
+//! cluster in their directory entry.
+//!
+//! FAT12 entries are a byte and a half, so two share three bytes and an entry can cross a sector. There are
+//! usually two FATs, kept the same - unless FAT32 mirroring is off, when only the active one is used.
+
+use alloc::vec::Vec;
+use super::bpb::{FatType, FIRST_CLUSTER};
+use super::super::FsError;
+use super::Volume;
+
+impl Volume {
+    /// The FATs that are written - every one, or just the active one
+    fn written_fats(&self) -> core::ops::Range<u32> {
+        match self.layout.active_fat {
+            Some(fat) => fat..fat + 1,
+            None => 0..self.layout.fat_count,
+        }
+    }
+
+    /// The FAT that is read
+    fn read_fat(&self) -> u32 {
+        self.layout.active_fat.unwrap_or(0)
+    }
+
+    /// `cluster`'s entry in FAT number `fat`
+    pub(super) async fn fat_entry_in(&self, fat: u32, cluster: u32) -> Result<u32, FsError> {
+        let position = self.layout.fat_position(fat) + self.layout.entry_offset(cluster);
+        let mut bytes = [0u8; 4];
+        Ok(match self.layout.fat_type {
+            FatType::Fat12 => {
+                self.read_bytes(position, &mut bytes[..2]).await?;
+                unpack_fat12(cluster, u16::from_le_bytes([bytes[0], bytes[1]]))
+            }
+            FatType::Fat16 => {
+                self.read_bytes(position, &mut bytes[..2]).await?;
+                u16::from_le_bytes([bytes[0], bytes[1]]) as u32
+            }
+            FatType::Fat32 => {
+                self.read_bytes(position, &mut bytes).await?;
+                u32::from_le_bytes(bytes) & 0x0FFF_FFFF
+            }
+        })
+    }
+
+    /// `cluster`'s entry in the FAT
+    pub(super) async fn fat_entry(&self, cluster: u32) -> Result<u32, FsError> {
+        self.fat_entry_in(self.read_fat(), cluster).await
+    }
+
+    /// Set `cluster`'s entry in every FAT that is written
+    pub(super) async fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        for fat in self.written_fats() {
+            let position = self.layout.fat_position(fat) + self.layout.entry_offset(cluster);
+            let mut bytes = [0u8; 4];
+            match self.layout.fat_type {
+                FatType::Fat12 => {
+                    self.read_bytes(position, &mut bytes[..2]).await?;
+                    let packed = pack_fat12(cluster, u16::from_le_bytes([bytes[0], bytes[1]]), value);
+                    self.write_bytes(position, &packed.to_le_bytes()).await?;
+                }
+                FatType::Fat16 => self.write_bytes(position, &(value as u16).to_le_bytes()).await?,
+                FatType::Fat32 => {
+                    // The top four bits are reserved, and kept
+                    self.read_bytes(position, &mut bytes).await?;
+                    let value = (u32::from_le_bytes(bytes) & 0xF000_0000) | (value & 0x0FFF_FFFF);
+                    self.write_bytes(position, &value.to_le_bytes()).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The clusters of the chain starting at `first`. An empty chain (`first` is 0) has none
+    pub(super) async fn chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut clusters = Vec::new();
+        let mut cluster = first;
+        if cluster == 0 {
+            return Ok(clusters);
+        }
+        let end = self.layout.fat_type.end_of_chain();
+        loop {
+            if !self.layout.is_valid_cluster(cluster) {
+                return Err(FsError::Corrupt("cluster chain goes out of range"));
+            }
+            if clusters.len() > self.layout.cluster_count as usize {
+                return Err(FsError::Corrupt("cluster chain loops"));
+            }
+            clusters.push(cluster);
+            let next = self.fat_entry(cluster).await?;
+            if next >= end {
+                return Ok(clusters);
+            }
+            if next < FIRST_CLUSTER || next == self.layout.fat_type.bad_cluster() {
+                return Err(FsError::Corrupt("cluster chain runs into a free or bad cluster"));
+            }
+            cluster = next;
+        }
+    }
+
+    /// Take a free cluster, ending a chain, and add it after `previous` if there is one. The search starts at
+    /// the next free hint, and wraps round
+    pub(super) async fn allocate(&self, previous: Option<u32>) -> Result<u32, FsError> {
+        let (free, hint) = {
+            let allocation = self.allocation.lock();
+            (allocation.free, allocation.next_free)
+        };
+        if free == Some(0) {
+            return Err(FsError::NoSpace);
+        }
+        let count = self.layout.cluster_count;
+        let start = if self.layout.is_valid_cluster(hint) { hint } else { FIRST_CLUSTER };
+        for index in 0..count {
+            let cluster = FIRST_CLUSTER + (start - FIRST_CLUSTER + index) % count;
+            if self.fat_entry(cluster).await? != 0 {
+                continue;
+            }
+            self.set_fat_entry(cluster, self.layout.fat_type.end_marker()).await?;
+            if let Some(previous) = previous {
+                self.set_fat_entry(previous, cluster).await?;
+            }
+            let mut allocation = self.allocation.lock();
+            allocation.free = allocation.free.map(|free| free.saturating_sub(1));
+            allocation.next_free = cluster + 1;
+            allocation.dirty = true;
+            return Ok(cluster);
+        }
+        self.allocation.lock().free = Some(0);
+        Err(FsError::NoSpace)
+    }
+
+    /// Free every cluster of the chain starting at `first`
+    pub(super) async fn free_chain(&self, first: u32) -> Result<(), FsError> {
+        let clusters = self.chain(first).await?;
+        for &cluster in clusters.iter() {
+            self.set_fat_entry(cluster, 0).await?;
+        }
+        let mut allocation = self.allocation.lock();
+        allocation.free = allocation.free.map(|free| free + clusters.len() as u32);
+        allocation.dirty = true;
+        Ok(())
+    }
+
+    /// Cut the chain starting at `first` to its first `keep` clusters, freeing the rest. Returns the chain's
+    /// new first cluster - 0 if none are kept
+    pub(super) async fn truncate_chain(&self, first: u32, keep: u32) -> Result<u32, FsError> {
+        if keep == 0 {
+            self.free_chain(first).await?;
+            return Ok(0);
+        }
+        let clusters = self.chain(first).await?;
+        if let (Some(&last), Some(&next)) = (clusters.get(keep as usize - 1), clusters.get(keep as usize)) {
+            self.set_fat_entry(last, self.layout.fat_type.end_marker()).await?;
+            self.free_chain(next).await?;
+        }
+        Ok(first)
+    }
+
+    /// Count the free clusters, from the FAT
+    pub(super) async fn count_free(&self) -> Result<u32, FsError> {
+        let mut free = 0;
+        for cluster in FIRST_CLUSTER..=self.layout.last_cluster() {
+            if self.fat_entry(cluster).await? == 0 {
+                free += 1;
+            }
+        }
+        Ok(free)
+    }
+}
+
+/// The entry for `cluster` in the two bytes at its FAT12 offset - odd clusters have the top 12 bits
+fn unpack_fat12(cluster: u32, bytes: u16) -> u32 {
+    if cluster & 1 == 1 { (bytes >> 4) as u32 } else { (bytes & 0x0FFF) as u32 }
+}
+
+/// The two bytes at `cluster`'s FAT12 offset with its entry set to `value`, keeping the neighbour's nibble
+fn pack_fat12(cluster: u32, bytes: u16, value: u32) -> u16 {
+    let value = (value & 0x0FFF) as u16;
+    if cluster & 1 == 1 { (bytes & 0x000F) | (value << 4) } else { (bytes & 0xF000) | value }
+}
+
+/* Testing */
+
+#[test_case]
+fn fat12_packing() {
+    // Clusters 2 and 3 share the bytes 3, 4 and 5 - 0x345 and 0x678
+    let bytes = [0x45u8, 0x83, 0x67];
+    let low = u16::from_le_bytes([bytes[0], bytes[1]]);
+    let high = u16::from_le_bytes([bytes[1], bytes[2]]);
+    assert_eq!(unpack_fat12(2, low), 0x345);
+    assert_eq!(unpack_fat12(3, high), 0x678);
+
+    // Setting one leaves the other alone
+    let low = pack_fat12(2, low, 0xFFF);
+    assert_eq!(low.to_le_bytes(), [0xFF, 0x8F]);
+    let high = pack_fat12(3, u16::from_le_bytes([0x8F, bytes[2]]), 0xABC);
+    assert_eq!(high.to_le_bytes(), [0xCF, 0xAB]);
+    assert_eq!(unpack_fat12(2, u16::from_le_bytes([0xFF, 0xCF])), 0xFFF);
+}
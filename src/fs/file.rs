@@ -0,0 +1,241 @@
+//! # Open files
+//!
+//! A [File](struct.File.html) is an opened dentry, with an offset that `read` and `write` start at and move
+//! on. The offset is locked for the whole of a `read` or `write`, so two tasks sharing a file don't read the
+//! same bytes twice. `read_at` and `write_at` take their own offset and leave the file's alone.
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::sync::AsyncMutex;
+use super::{DirEntry, Dentry, FileType, FsError, Stat};
+
+/// How much `read_to_end` asks for at a time
+const READ_CHUNK: usize = 4096;
+
+/// # OpenOptions
+///
+/// How to open a file - for reading, writing, or both, and what to do if it is or isn't there. Built up like
+/// `OpenOptions::new().write(true).create(true)`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+}
+
+impl OpenOptions {
+    /// Nothing allowed - turn on what is needed
+    pub fn new() -> Self {
+        OpenOptions::default()
+    }
+
+    pub fn read(mut self, read: bool) -> Self {
+        self.read = read;
+        self
+    }
+
+    pub fn write(mut self, write: bool) -> Self {
+        self.write = write;
+        self
+    }
+
+    /// Every write goes to the end of the file. Implies `write`
+    pub fn append(mut self, append: bool) -> Self {
+        self.append = append;
+        self
+    }
+
+    /// Empty the file when it is opened. Needs `write`
+    pub fn truncate(mut self, truncate: bool) -> Self {
+        self.truncate = truncate;
+        self
+    }
+
+    /// Make the file if it isn't there
+    pub fn create(mut self, create: bool) -> Self {
+        self.create = create;
+        self
+    }
+
+    /// Make the file, failing if it is already there
+    pub fn create_new(mut self, create_new: bool) -> Self {
+        self.create_new = create_new;
+        self
+    }
+
+    pub fn is_create(&self) -> bool {
+        self.create || self.create_new
+    }
+
+    pub fn is_create_new(&self) -> bool {
+        self.create_new
+    }
+
+    fn is_writable(&self) -> bool {
+        self.write || self.append
+    }
+}
+
+/// # SeekFrom
+///
+/// Where `seek` counts from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+/// # File
+///
+/// An open file or directory
+pub struct File {
+    dentry: Arc<Dentry>,
+    options: OpenOptions,
+    offset: AsyncMutex<u64>,
+}
+
+impl File {
+    /// Open `dentry`. Directories can only be opened for reading, to list them
+    pub async fn open(dentry: Arc<Dentry>, options: OpenOptions) -> Result<File, FsError> {
+        let writable = options.is_writable();
+        match dentry.kind() {
+            FileType::Directory if writable || options.truncate => return Err(FsError::IsDirectory),
+            FileType::Symlink => return Err(FsError::InvalidArgument),
+            _ => {}
+        }
+        if writable && dentry.mount().filesystem().is_read_only() {
+            return Err(FsError::ReadOnly);
+        }
+        if options.truncate {
+            if !writable {
+                return Err(FsError::InvalidArgument);
+            }
+            dentry.inode().truncate(0).await?;
+        }
+        Ok(File { dentry, options, offset: AsyncMutex::new(0) })
+    }
+
+    pub fn dentry(&self) -> &Arc<Dentry> {
+        &self.dentry
+    }
+
+    pub fn options(&self) -> OpenOptions {
+        self.options
+    }
+
+    fn check_readable(&self) -> Result<(), FsError> {
+        if !self.options.read {
+            Err(FsError::NotReadable)
+        } else if self.dentry.kind() == FileType::Directory {
+            Err(FsError::IsDirectory)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_writable(&self) -> Result<(), FsError> {
+        if !self.options.is_writable() {
+            Err(FsError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Read into `buffer` from the offset, moving it on. Returns how much was read - 0 at the end of the file
+    pub async fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        self.check_readable()?;
+        let mut offset = self.offset.lock().await;
+        let read = self.dentry.inode().read_at(*offset, buffer).await?;
+        *offset += read as u64;
+        Ok(read)
+    }
+
+    /// Write `buffer` at the offset (or the end, if appending), moving it on. Returns how much was written
+    pub async fn write(&self, buffer: &[u8]) -> Result<usize, FsError> {
+        self.check_writable()?;
+        let mut offset = self.offset.lock().await;
+        if self.options.append {
+            *offset = self.dentry.inode().stat().await?.size;
+        }
+        let written = self.dentry.inode().write_at(*offset, buffer).await?;
+        *offset += written as u64;
+        Ok(written)
+    }
+
+    /// Read into `buffer` from `offset`, without moving the file's offset
+    pub async fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        self.check_readable()?;
+        self.dentry.inode().read_at(offset, buffer).await
+    }
+
+    /// Write `buffer` at `offset`, without moving the file's offset
+    pub async fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        self.check_writable()?;
+        self.dentry.inode().write_at(offset, buffer).await
+    }
+
+    /// Move the offset, returning where it is now. It can go past the end - a write there leaves a hole
+    pub async fn seek(&self, from: SeekFrom) -> Result<u64, FsError> {
+        let mut offset = self.offset.lock().await;
+        let (base, delta) = match from {
+            SeekFrom::Start(position) => (position, 0),
+            SeekFrom::End(delta) => (self.dentry.inode().stat().await?.size, delta),
+            SeekFrom::Current(delta) => (*offset, delta),
+        };
+        let position = if delta < 0 { base.checked_sub(delta.unsigned_abs()) } else { base.checked_add(delta as u64) };
+        *offset = position.ok_or(FsError::InvalidArgument)?;
+        Ok(*offset)
+    }
+
+    /// Cut the file to `size` bytes, or grow it with zeros. The offset doesn't move
+    pub async fn set_len(&self, size: u64) -> Result<(), FsError> {
+        self.check_writable()?;
+        if self.dentry.kind() == FileType::Directory {
+            return Err(FsError::IsDirectory);
+        }
+        self.dentry.inode().truncate(size).await
+    }
+
+    pub async fn stat(&self) -> Result<Stat, FsError> {
+        self.dentry.inode().stat().await
+    }
+
+    /// Everything in the directory
+    pub async fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        self.dentry.read_dir().await
+    }
+
+    /// Read from the offset to the end of the file
+    pub async fn read_to_end(&self) -> Result<Vec<u8>, FsError> {
+        let mut data = Vec::new();
+        let mut chunk = vec![0; READ_CHUNK];
+        loop {
+            let read = self.read(&mut chunk).await?;
+            if read == 0 {
+                return Ok(data);
+            }
+            data.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    /// Write all of `buffer` at the offset
+    pub async fn write_all(&self, mut buffer: &[u8]) -> Result<(), FsError> {
+        while !buffer.is_empty() {
+            match self.write(buffer).await? {
+                0 => return Err(FsError::NoSpace),
+                written => buffer = &buffer[written..],
+            }
+        }
+        Ok(())
+    }
+
+    /// Write what the filesystem has cached to its disk
+    pub async fn sync(&self) -> Result<(), FsError> {
+        self.dentry.mount().filesystem().sync().await
+    }
+}
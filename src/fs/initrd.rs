@@ -0,0 +1,353 @@
+//! # initrd
+//!
+//! The initial ramdisk - an archive of files bundled into the kernel, unpacked into the root ramfs at boot, so
+//! programs, config files and test fixtures are there before any disk is. The bootloader only hands us a
+//! memory map, so the archive is built into the kernel image itself: `build.rs` packs the `initrd/` directory
+//! into a USTAR archive (or uses the archive `DBOS_INITRD` points at), and it is included here.
+//!
+//! Both tar (USTAR, with GNU or pax long names) and newc cpio archives (what Linux initramfs images are) can be read.
+//! Directories, files, symlinks and hard links are unpacked; devices, FIFOs and permissions are skipped.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use super::path::{components, join, split_last};
+use super::{link, mkdir, symlink, write, FsError};
+
+/// The archive bundled into the kernel
+pub static IMAGE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initrd.img"));
+
+const TAR_BLOCK: usize = 512;
+const CPIO_HEADER: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+/// # Format
+///
+/// The kinds of archive that can be unpacked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Ustar,
+    /// cpio, in the "new" portable format - with or without checksums
+    Newc,
+}
+
+/// # EntryKind
+///
+/// What an archive entry makes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryKind<'a> {
+    File(&'a [u8]),
+    Directory,
+    Symlink(String),
+    /// Another name for the file at this path, earlier in the archive
+    HardLink(String),
+}
+
+/// # Entry
+///
+/// A file in an archive. Paths are relative to where the archive is unpacked, without `.` or `..`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry<'a> {
+    pub path: String,
+    pub kind: EntryKind<'a>,
+}
+
+/// What kind of archive `archive` is, if it is one
+pub fn format(archive: &[u8]) -> Option<Format> {
+    if archive.starts_with(b"070701") || archive.starts_with(b"070702") {
+        Some(Format::Newc)
+    } else if archive.len() >= TAR_BLOCK && &archive[257..262] == b"ustar" {
+        Some(Format::Ustar)
+    } else {
+        None
+    }
+}
+
+/// `path` made relative and tidied - `None` for the root itself, and for paths that climb out with `..`
+fn normalize(path: &str) -> Option<String> {
+    let mut normalized = String::new();
+    for name in components(path) {
+        if name == ".." {
+            return None;
+        }
+        if !normalized.is_empty() {
+            normalized.push('/');
+        }
+        normalized.push_str(name);
+    }
+    if normalized.is_empty() { None } else { Some(normalized) }
+}
+
+/// A NUL terminated (or field filling) string
+fn string(field: &[u8]) -> Result<&str, FsError> {
+    let length = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..length]).map_err(|_| FsError::Corrupt("archive name isn't UTF-8"))
+}
+
+/// A tar number - octal, padded with spaces or NULs
+fn octal(field: &[u8]) -> Result<u64, FsError> {
+    let digits = string(field)?.trim_matches(' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| FsError::Corrupt("bad number in tar header"))
+}
+
+/// A cpio number - 8 hex digits
+fn hex(field: &[u8]) -> Result<u64, FsError> {
+    let digits = core::str::from_utf8(field).map_err(|_| FsError::Corrupt("bad number in cpio header"))?;
+    u64::from_str_radix(digits, 16).map_err(|_| FsError::Corrupt("bad number in cpio header"))
+}
+
+fn round_up(value: usize, to: usize) -> usize {
+    (value + to - 1) / to * to
+}
+
+/// The data `size` bytes from `start`, if the archive is that long
+fn data(archive: &[u8], start: usize, size: u64) -> Result<&[u8], FsError> {
+    let end = (size as usize).checked_add(start).filter(|&end| size <= usize::MAX as u64 && end <= archive.len());
+    end.map(|end| &archive[start..end]).ok_or(FsError::Corrupt("archive entry runs past the end"))
+}
+
+/// The `path` and `linkpath` records of a pax extended header - `length key=value\n`, over and over
+fn pax_records(mut records: &[u8]) -> Result<(Option<String>, Option<String>), FsError> {
+    let (mut path, mut link) = (None, None);
+    while !records.is_empty() {
+        let bad = FsError::Corrupt("bad pax extended header");
+        let space = records.iter().position(|&byte| byte == b' ').ok_or(bad)?;
+        let length: usize = string(&records[..space])?.parse().map_err(|_| bad)?;
+        if length <= space + 1 || length > records.len() {
+            return Err(bad);
+        }
+        let record = string(&records[space + 1..length - 1])?;
+        match record.split_once('=') {
+            Some(("path", value)) => path = Some(String::from(value)),
+            Some(("linkpath", value)) => link = Some(String::from(value)),
+            _ => {}
+        }
+        records = &records[length..];
+    }
+    Ok((path, link))
+}
+
+fn parse_ustar(archive: &[u8]) -> Result<Vec<Entry>, FsError> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    // A long name and link target (from a GNU long name entry, or a pax header), for the entry after it
+    let mut long_name: Option<String> = None;
+    let mut long_link: Option<String> = None;
+    while offset + TAR_BLOCK <= archive.len() {
+        let header = &archive[offset..offset + TAR_BLOCK];
+        // The archive ends with empty blocks
+        if header.iter().all(|&byte| byte == 0) {
+            break;
+        }
+        // Worked out with the checksum field itself as spaces
+        let checksum: u64 =
+            header.iter().enumerate().map(|(index, &byte)| (if (148..156).contains(&index) { b' ' } else { byte }) as u64).sum();
+        if octal(&header[148..156])? != checksum {
+            return Err(FsError::Corrupt("bad tar header checksum"));
+        }
+        let size = octal(&header[124..136])?;
+        let contents = data(archive, offset + TAR_BLOCK, size)?;
+        offset += TAR_BLOCK + round_up(contents.len(), TAR_BLOCK);
+
+        let name = match long_name.take() {
+            Some(name) => name,
+            None => {
+                let prefix = string(&header[345..500])?;
+                let name = string(&header[0..100])?;
+                if &header[257..262] == b"ustar" && !prefix.is_empty() { join(prefix, name) } else { String::from(name) }
+            }
+        };
+        let link = match long_link.take() {
+            Some(link) => link,
+            None => String::from(string(&header[157..257])?),
+        };
+        let kind = match header[156] {
+            b'0' | b'7' | 0 => EntryKind::File(contents),
+            b'1' => match normalize(&link) {
+                Some(target) => EntryKind::HardLink(target),
+                None => continue,
+            },
+            b'2' => EntryKind::Symlink(link),
+            b'5' => EntryKind::Directory,
+            b'L' => {
+                long_name = Some(String::from(string(contents)?));
+                continue;
+            }
+            b'K' => {
+                long_link = Some(String::from(string(contents)?));
+                continue;
+            }
+            b'x' => {
+                let (path, link) = pax_records(contents)?;
+                long_name = path.or(long_name);
+                long_link = link.or(long_link);
+                continue;
+            }
+            // Devices, FIFOs, and pax global headers
+            _ => continue,
+        };
+        if let Some(path) = normalize(&name) {
+            entries.push(Entry { path, kind });
+        }
+    }
+    Ok(entries)
+}
+
+fn parse_newc(archive: &[u8]) -> Result<Vec<Entry>, FsError> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    // The first path of each file with more than one link, by device and inode
+    let mut linked: BTreeMap<(u64, u64, u64), String> = BTreeMap::new();
+    loop {
+        let header = data(archive, offset, CPIO_HEADER as u64)?;
+        if !header.starts_with(b"070701") && !header.starts_with(b"070702") {
+            return Err(FsError::Corrupt("bad cpio header magic"));
+        }
+        let field = |index: usize| hex(&header[6 + index * 8..14 + index * 8]);
+        let (inode, mode, links, size) = (field(0)?, field(1)?, field(4)?, field(6)?);
+        let (device_major, device_minor, name_size) = (field(7)?, field(8)?, field(11)?);
+        if name_size == 0 {
+            return Err(FsError::Corrupt("cpio entry has no name"));
+        }
+        let name = string(data(archive, offset + CPIO_HEADER, name_size)?)?;
+        let start = round_up(offset + CPIO_HEADER + name_size as usize, 4);
+        let contents = data(archive, start, size)?;
+        offset = round_up(start + contents.len(), 4);
+
+        if name == CPIO_TRAILER {
+            return Ok(entries);
+        }
+        let path = match normalize(name) {
+            Some(path) => path,
+            None => continue,
+        };
+        match mode & 0o170000 {
+            0o040000 => entries.push(Entry { path, kind: EntryKind::Directory }),
+            0o120000 => {
+                let target = core::str::from_utf8(contents).map_err(|_| FsError::Corrupt("symlink target isn't UTF-8"))?;
+                entries.push(Entry { path, kind: EntryKind::Symlink(String::from(target)) });
+            }
+            0o100000 => {
+                // Hard links share an inode number. The data comes with one of them - usually the last
+                let first = if links > 1 { linked.get(&(device_major, device_minor, inode)).cloned() } else { None };
+                match first {
+                    Some(first) => {
+                        entries.push(Entry { path: path.clone(), kind: EntryKind::HardLink(first) });
+                        if !contents.is_empty() {
+                            entries.push(Entry { path, kind: EntryKind::File(contents) });
+                        }
+                    }
+                    None => {
+                        if links > 1 {
+                            linked.insert((device_major, device_minor, inode), path.clone());
+                        }
+                        entries.push(Entry { path, kind: EntryKind::File(contents) });
+                    }
+                }
+            }
+            // Devices, FIFOs and sockets
+            _ => {}
+        }
+    }
+}
+
+/// # parse
+///
+/// The entries in `archive`, in order. An empty archive (or one that is just zeros) has none
+pub fn parse(archive: &[u8]) -> Result<Vec<Entry>, FsError> {
+    match format(archive) {
+        Some(Format::Ustar) => parse_ustar(archive),
+        Some(Format::Newc) => parse_newc(archive),
+        None if archive.iter().all(|&byte| byte == 0) => Ok(Vec::new()),
+        None => Err(FsError::Corrupt("not a tar or cpio archive")),
+    }
+}
+
+/// Make the directories `path` is in, if they aren't there
+async fn make_parents(path: &str) -> Result<(), FsError> {
+    let (directory, _) = split_last(path);
+    let mut parent = String::new();
+    for name in components(directory) {
+        parent.push('/');
+        parent.push_str(name);
+        match mkdir(&parent).await {
+            Ok(()) | Err(FsError::AlreadyExists) => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+/// # unpack
+///
+/// Make the files in `archive` under the directory `into` (an absolute path), returning how many entries there
+/// were. Directories that are already there are kept, and files already there are replaced
+pub async fn unpack(archive: &[u8], into: &str) -> Result<usize, FsError> {
+    let entries = parse(archive)?;
+    for entry in entries.iter() {
+        let path = join(into, &entry.path);
+        make_parents(&path).await?;
+        match &entry.kind {
+            EntryKind::Directory => match mkdir(&path).await {
+                Ok(()) | Err(FsError::AlreadyExists) => {}
+                Err(error) => return Err(error),
+            },
+            EntryKind::File(contents) => write(&path, contents).await?,
+            EntryKind::Symlink(target) => symlink(target, &path).await?,
+            EntryKind::HardLink(target) => link(&join(into, target), &path).await?,
+        }
+    }
+    Ok(entries.len())
+}
+
+/* Testing */
+
+#[test_case]
+fn initrd_parse() {
+    use alloc::vec;
+
+    /// A newc entry
+    fn cpio(archive: &mut Vec<u8>, name: &str, inode: u64, mode: u64, links: u64, contents: &[u8]) {
+        let fields = [inode, mode, 0, 0, links, 0, contents.len() as u64, 0, 0, 0, 0, name.len() as u64 + 1, 0];
+        archive.extend_from_slice(b"070701");
+        for field in fields.iter() {
+            archive.extend_from_slice(alloc::format!("{:08X}", field).as_bytes());
+        }
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(round_up(archive.len(), 4), 0);
+        archive.extend_from_slice(contents);
+        archive.resize(round_up(archive.len(), 4), 0);
+    }
+
+    let mut archive = Vec::new();
+    cpio(&mut archive, ".", 1, 0o040755, 2, b"");
+    cpio(&mut archive, "bin", 2, 0o040755, 2, b"");
+    cpio(&mut archive, "bin/a", 3, 0o100755, 2, b"");
+    cpio(&mut archive, "./bin/b", 3, 0o100755, 2, b"shared");
+    cpio(&mut archive, "bin/sh", 4, 0o120777, 1, b"a");
+    cpio(&mut archive, "dev/null", 5, 0o020666, 1, b"");
+    cpio(&mut archive, "../escape", 6, 0o100644, 1, b"no");
+    cpio(&mut archive, CPIO_TRAILER, 0, 0, 1, b"");
+    assert_eq!(format(&archive), Some(Format::Newc));
+    assert_eq!(
+        parse(&archive).unwrap(),
+        vec![
+            Entry { path: String::from("bin"), kind: EntryKind::Directory },
+            Entry { path: String::from("bin/a"), kind: EntryKind::File(b"") },
+            Entry { path: String::from("bin/b"), kind: EntryKind::HardLink(String::from("bin/a")) },
+            Entry { path: String::from("bin/b"), kind: EntryKind::File(b"shared") },
+            Entry { path: String::from("bin/sh"), kind: EntryKind::Symlink(String::from("a")) },
+        ]
+    );
+    // Cut short - no trailer
+    assert!(matches!(parse(&archive[..archive.len() - 120]), Err(FsError::Corrupt(_))));
+
+    assert_eq!(parse(&[0; 1024]), Ok(Vec::new()));
+    assert!(parse(b"hello").is_err());
+    assert_eq!(normalize("./a//b/"), Some(String::from("a/b")));
+    assert_eq!(normalize("/"), None);
+}
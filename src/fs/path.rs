@@ -0,0 +1,79 @@
+//! # Paths
+//!
+//! Paths are `/` separated names, like `/etc/hosts`. Empty names (`a//b`) and `.` are skipped; `..` is left
+//! for the VFS to follow, since what it means depends on mounts and symlinks.
+
+use alloc::string::String;
+
+/// The longest name a directory entry can have, in bytes
+pub const MAX_NAME_LENGTH: usize = 255;
+
+/// Whether `path` starts at the root rather than the current directory
+pub fn is_absolute(path: &str) -> bool {
+    path.starts_with('/')
+}
+
+/// The names in `path`, in order, without empty names and `.`
+pub fn components(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split('/').filter(|name| !name.is_empty() && *name != ".")
+}
+
+/// Split `path` into its directory and last name - `/a/b/c` into `/a/b` and `c`. The name is `None` if there
+/// isn't one (`/`), or it is `..`, which can't be created or removed
+pub fn split_last(path: &str) -> (&str, Option<&str>) {
+    let trimmed = path.trim_end_matches('/');
+    let (directory, name) = match trimmed.rfind('/') {
+        Some(0) => ("/", &trimmed[1..]),
+        Some(index) => (&trimmed[..index], &trimmed[index + 1..]),
+        None if is_absolute(path) => ("/", ""),
+        None => (".", trimmed),
+    };
+    match name {
+        "" | "." | ".." => (path, None),
+        name => (directory, Some(name)),
+    }
+}
+
+/// Whether `name` can be a directory entry - not empty, `.` or `..`, no `/` or NUL, and not too long
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name.len() <= MAX_NAME_LENGTH
+        && !name.bytes().any(|byte| byte == b'/' || byte == 0)
+}
+
+/// `name` added to the end of `directory` - or just `name`, if it is absolute
+pub fn join(directory: &str, name: &str) -> String {
+    if is_absolute(name) {
+        return String::from(name);
+    }
+    let mut path = String::from(directory.trim_end_matches('/'));
+    path.push('/');
+    path.push_str(name);
+    path
+}
+
+/* Testing */
+
+#[test_case]
+fn path_components() {
+    use alloc::vec::Vec;
+
+    let names: Vec<&str> = components("/usr//lib/./../bin/").collect();
+    assert_eq!(names, ["usr", "lib", "..", "bin"]);
+    assert_eq!(components("/").count(), 0);
+    assert!(is_absolute("/etc") && !is_absolute("etc"));
+
+    assert_eq!(split_last("/etc/hosts"), ("/etc", Some("hosts")));
+    assert_eq!(split_last("/etc/"), ("/", Some("etc")));
+    assert_eq!(split_last("hosts"), (".", Some("hosts")));
+    assert_eq!(split_last("/"), ("/", None));
+    assert_eq!(split_last("/etc/.."), ("/etc/..", None));
+
+    assert!(is_valid_name("hosts"));
+    assert!(!is_valid_name("") && !is_valid_name("..") && !is_valid_name("a/b"));
+    assert!(!is_valid_name(&"x".repeat(256)));
+    assert_eq!(join("/etc/", "hosts"), "/etc/hosts");
+    assert_eq!(join("/etc", "/bin"), "/bin");
+}
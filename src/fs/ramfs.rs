@@ -0,0 +1,478 @@
+//! # ramfs
+//!
+//! A filesystem that only lives in memory - what `/` is before (or without) any disk. Files are kept in
+//! blocks on the kernel heap, only for the parts that have been written: a hole (written past, or grown by
+//! `truncate`) reads as zeros and takes nothing. Blocks are small, because the heap is.
+//!
+//! Directories, files, symlinks and hard links all work as on a disk filesystem. A ramfs can be given a limit,
+//! so filling it fails with `NoSpace` rather than running the heap dry.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use super::{DirEntry, FileType, Filesystem, FsError, FsFuture, Inode, Stat};
+
+/// The size of the blocks file data is kept in
+pub const BLOCK_SIZE: usize = 1024;
+
+/// What every inode of a ramfs shares
+struct Shared {
+    next_id: AtomicU64,
+    /// Every inode, by number - for hard links and renames, which are given the inode rather than the node
+    nodes: Mutex<BTreeMap<u64, Weak<Node>>>,
+    /// Bytes of file data kept
+    used: AtomicUsize,
+    /// The most `used` can be
+    limit: usize,
+}
+
+impl Shared {
+    /// The node with inode number `id`, if it is still around
+    fn find(&self, id: u64) -> Option<Arc<Node>> {
+        // Upgrade with the table unlocked - if it is dropped straight after, `Node::drop` takes the lock
+        let weak = self.nodes.lock().get(&id).cloned();
+        weak.and_then(|weak| weak.upgrade())
+    }
+
+    /// Take `bytes` of the limit
+    fn reserve(&self, bytes: usize) -> Result<(), FsError> {
+        let mut used = self.used.load(Ordering::Relaxed);
+        loop {
+            let total = used.checked_add(bytes).filter(|&total| total <= self.limit).ok_or(FsError::NoSpace)?;
+            match self.used.compare_exchange_weak(used, total, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return Ok(()),
+                Err(now) => used = now,
+            }
+        }
+    }
+
+    fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// A file's contents - its size, and the blocks that have been written, by index
+#[derive(Default)]
+struct FileData {
+    size: u64,
+    blocks: BTreeMap<u64, Box<[u8]>>,
+}
+
+enum Data {
+    File(FileData),
+    Directory(BTreeMap<String, Arc<Node>>),
+    Symlink(String),
+}
+
+/// A ramfs inode
+struct Node {
+    id: u64,
+    kind: FileType,
+    shared: Arc<Shared>,
+    /// How many directory entries point at it, for files and symlinks. Directories count their own
+    links: AtomicU32,
+    data: Mutex<Data>,
+}
+
+impl Node {
+    fn new(shared: &Arc<Shared>, data: Data) -> Arc<Node> {
+        let kind = match data {
+            Data::File(_) => FileType::Regular,
+            Data::Directory(_) => FileType::Directory,
+            Data::Symlink(_) => FileType::Symlink,
+        };
+        let id = shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let node = Arc::new(Node { id, kind, shared: shared.clone(), links: AtomicU32::new(0), data: Mutex::new(data) });
+        shared.nodes.lock().insert(id, Arc::downgrade(&node));
+        node
+    }
+
+    /// Add a new node made from `data` to this directory as `name`
+    fn add(&self, name: &str, data: Data) -> Result<Arc<Node>, FsError> {
+        let mut directory = self.data.lock();
+        let entries = match &mut *directory {
+            Data::Directory(entries) => entries,
+            _ => return Err(FsError::NotDirectory),
+        };
+        if entries.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        let node = Node::new(&self.shared, data);
+        node.links.store(1, Ordering::Relaxed);
+        entries.insert(String::from(name), node.clone());
+        Ok(node)
+    }
+
+    /// The bytes of file data it keeps
+    fn allocated(data: &FileData) -> usize {
+        data.blocks.len() * BLOCK_SIZE
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.shared.nodes.lock().remove(&self.id);
+        if let Data::File(file) = self.data.get_mut() {
+            self.shared.release(Node::allocated(file));
+        }
+    }
+}
+
+/// Whether `node` is an empty directory, for removing or replacing it
+fn is_empty_directory(node: &Node) -> bool {
+    match &*node.data.lock() {
+        Data::Directory(entries) => entries.is_empty(),
+        _ => false,
+    }
+}
+
+impl Inode for Node {
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn kind(&self) -> FileType {
+        self.kind
+    }
+
+    fn stat(&self) -> FsFuture<'_, Stat> {
+        Box::pin(async move {
+            let data = self.data.lock();
+            let (size, links, allocated) = match &*data {
+                Data::File(file) => (file.size, self.links.load(Ordering::Relaxed), Node::allocated(file) as u64),
+                // Its entry in its parent, its own `.`, and each subdirectory's `..`
+                Data::Directory(entries) => {
+                    let subdirectories = entries.values().filter(|node| node.kind == FileType::Directory).count();
+                    (entries.len() as u64, 2 + subdirectories as u32, 0)
+                }
+                Data::Symlink(target) => (target.len() as u64, self.links.load(Ordering::Relaxed), 0),
+            };
+            Ok(Stat { inode: self.id, kind: self.kind, size, links, allocated })
+        })
+    }
+
+    fn read_at<'a>(&'a self, offset: u64, buffer: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            let data = self.data.lock();
+            let file = match &*data {
+                Data::File(file) => file,
+                _ => return Err(FsError::NotSupported),
+            };
+            if offset >= file.size {
+                return Ok(0);
+            }
+            let length = ((file.size - offset) as usize).min(buffer.len());
+            let mut done = 0;
+            while done < length {
+                let position = offset + done as u64;
+                let index = position / BLOCK_SIZE as u64;
+                let within = (position % BLOCK_SIZE as u64) as usize;
+                let part = (BLOCK_SIZE - within).min(length - done);
+                let target = &mut buffer[done..done + part];
+                match file.blocks.get(&index) {
+                    Some(block) => target.copy_from_slice(&block[within..within + part]),
+                    None => target.iter_mut().for_each(|byte| *byte = 0),
+                }
+                done += part;
+            }
+            Ok(length)
+        })
+    }
+
+    fn write_at<'a>(&'a self, offset: u64, buffer: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            let end = offset.checked_add(buffer.len() as u64).ok_or(FsError::InvalidArgument)?;
+            let mut data = self.data.lock();
+            let file = match &mut *data {
+                Data::File(file) => file,
+                _ => return Err(FsError::NotSupported),
+            };
+            let mut done = 0;
+            while done < buffer.len() {
+                let position = offset + done as u64;
+                let index = position / BLOCK_SIZE as u64;
+                let within = (position % BLOCK_SIZE as u64) as usize;
+                let part = (BLOCK_SIZE - within).min(buffer.len() - done);
+                let source = &buffer[done..done + part];
+                match file.blocks.get_mut(&index) {
+                    Some(block) => block[within..within + part].copy_from_slice(source),
+                    // Zeros written into a hole leave it a hole
+                    None if source.iter().all(|&byte| byte == 0) => {}
+                    None => {
+                        if let Err(error) = self.shared.reserve(BLOCK_SIZE) {
+                            // Keep what fitted
+                            file.size = file.size.max(position);
+                            return if done > 0 { Ok(done) } else { Err(error) };
+                        }
+                        let mut block = vec![0; BLOCK_SIZE].into_boxed_slice();
+                        block[within..within + part].copy_from_slice(source);
+                        file.blocks.insert(index, block);
+                    }
+                }
+                done += part;
+            }
+            file.size = file.size.max(end);
+            Ok(done)
+        })
+    }
+
+    fn truncate(&self, size: u64) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            let mut data = self.data.lock();
+            let file = match &mut *data {
+                Data::File(file) => file,
+                _ => return Err(FsError::NotSupported),
+            };
+            if size < file.size {
+                // Drop the blocks past the end, and clear the end of the last one, so growing again reads zeros
+                let keep = (size + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64;
+                let dropped = file.blocks.split_off(&keep);
+                self.shared.release(dropped.len() * BLOCK_SIZE);
+                let within = (size % BLOCK_SIZE as u64) as usize;
+                if within != 0 {
+                    if let Some(block) = file.blocks.get_mut(&(size / BLOCK_SIZE as u64)) {
+                        block[within..].iter_mut().for_each(|byte| *byte = 0);
+                    }
+                }
+            }
+            file.size = size;
+            Ok(())
+        })
+    }
+
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            match &*self.data.lock() {
+                Data::Directory(entries) => match entries.get(name) {
+                    Some(node) => Ok(node.clone() as Arc<dyn Inode>),
+                    None => Err(FsError::NotFound),
+                },
+                _ => Err(FsError::NotDirectory),
+            }
+        })
+    }
+
+    fn read_dir(&self) -> FsFuture<'_, Vec<DirEntry>> {
+        Box::pin(async move {
+            match &*self.data.lock() {
+                Data::Directory(entries) => Ok(entries
+                    .iter()
+                    .map(|(name, node)| DirEntry { name: name.clone(), inode: node.id, kind: node.kind })
+                    .collect()),
+                _ => Err(FsError::NotDirectory),
+            }
+        })
+    }
+
+    fn create<'a>(&'a self, name: &'a str, kind: FileType) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            let data = match kind {
+                FileType::Regular => Data::File(FileData::default()),
+                FileType::Directory => Data::Directory(BTreeMap::new()),
+                _ => return Err(FsError::NotSupported),
+            };
+            self.add(name, data).map(|node| node as Arc<dyn Inode>)
+        })
+    }
+
+    fn symlink<'a>(&'a self, name: &'a str, target: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move { self.add(name, Data::Symlink(String::from(target))).map(|node| node as Arc<dyn Inode>) })
+    }
+
+    fn read_link(&self) -> FsFuture<'_, String> {
+        Box::pin(async move {
+            match &*self.data.lock() {
+                Data::Symlink(target) => Ok(target.clone()),
+                _ => Err(FsError::InvalidArgument),
+            }
+        })
+    }
+
+    fn link<'a>(&'a self, name: &'a str, inode: &'a Arc<dyn Inode>) -> FsFuture<'a, ()> {
+        Box::pin(async move {
+            let node = self.shared.find(inode.id()).ok_or(FsError::CrossDevice)?;
+            if node.kind == FileType::Directory {
+                return Err(FsError::IsDirectory);
+            }
+            let mut directory = self.data.lock();
+            let entries = match &mut *directory {
+                Data::Directory(entries) => entries,
+                _ => return Err(FsError::NotDirectory),
+            };
+            if entries.contains_key(name) {
+                return Err(FsError::AlreadyExists);
+            }
+            node.links.fetch_add(1, Ordering::Relaxed);
+            entries.insert(String::from(name), node);
+            Ok(())
+        })
+    }
+
+    fn unlink<'a>(&'a self, name: &'a str) -> FsFuture<'a, ()> {
+        Box::pin(async move {
+            let removed = {
+                let mut directory = self.data.lock();
+                let entries = match &mut *directory {
+                    Data::Directory(entries) => entries,
+                    _ => return Err(FsError::NotDirectory),
+                };
+                let node = entries.get(name).ok_or(FsError::NotFound)?;
+                if node.kind == FileType::Directory && !is_empty_directory(node) {
+                    return Err(FsError::NotEmpty);
+                }
+                entries.remove(name).unwrap()
+            };
+            removed.links.fetch_sub(1, Ordering::Relaxed);
+            // Dropped here, with no locks held - it goes if nothing else links to it or has it open
+            Ok(())
+        })
+    }
+
+    fn rename<'a>(&'a self, name: &'a str, to: &'a Arc<dyn Inode>, new_name: &'a str) -> FsFuture<'a, ()> {
+        Box::pin(async move {
+            let target = self.shared.find(to.id()).ok_or(FsError::CrossDevice)?;
+            let replaced;
+            {
+                // Lock both directories, lowest inode number first so two renames can't deadlock
+                let same = target.id == self.id;
+                let (mut first, mut second) = match (same, self.id < target.id) {
+                    (true, _) => (self.data.lock(), None),
+                    (false, true) => {
+                        let first = self.data.lock();
+                        (first, Some(target.data.lock()))
+                    }
+                    (false, false) => {
+                        let second = target.data.lock();
+                        (self.data.lock(), Some(second))
+                    }
+                };
+                let from = match &mut *first {
+                    Data::Directory(entries) => entries,
+                    _ => return Err(FsError::NotDirectory),
+                };
+                let source = from.get(name).ok_or(FsError::NotFound)?.clone();
+                let into = match second.as_deref_mut() {
+                    Some(Data::Directory(entries)) => Some(entries),
+                    Some(_) => return Err(FsError::NotDirectory),
+                    None => None,
+                };
+                let existing = match &into {
+                    Some(into) => into.get(new_name).cloned(),
+                    None => from.get(new_name).cloned(),
+                };
+                if let Some(existing) = &existing {
+                    if Arc::ptr_eq(existing, &source) {
+                        return Ok(()); // Two names for the same file - nothing to do
+                    }
+                    match (source.kind == FileType::Directory, existing.kind == FileType::Directory) {
+                        (true, false) => return Err(FsError::NotDirectory),
+                        (false, true) => return Err(FsError::IsDirectory),
+                        (true, true) if !is_empty_directory(existing) => return Err(FsError::NotEmpty),
+                        _ => {}
+                    }
+                }
+                from.remove(name);
+                replaced = match into {
+                    Some(into) => into.insert(String::from(new_name), source),
+                    None => from.insert(String::from(new_name), source),
+                };
+            }
+            if let Some(replaced) = replaced {
+                replaced.links.fetch_sub(1, Ordering::Relaxed);
+            }
+            Ok(())
+        })
+    }
+}
+
+/// # RamFs
+///
+/// A filesystem in memory
+pub struct RamFs {
+    shared: Arc<Shared>,
+    root: Arc<Node>,
+}
+
+impl RamFs {
+    /// An empty ramfs, which can grow until the heap runs out
+    pub fn new() -> Self {
+        RamFs::with_limit(usize::MAX)
+    }
+
+    /// An empty ramfs that holds at most `limit` bytes of file data
+    pub fn with_limit(limit: usize) -> Self {
+        let shared =
+            Arc::new(Shared { next_id: AtomicU64::new(1), nodes: Mutex::new(BTreeMap::new()), used: AtomicUsize::new(0), limit });
+        let root = Node::new(&shared, Data::Directory(BTreeMap::new()));
+        RamFs { shared, root }
+    }
+
+    /// The bytes of file data it holds
+    pub fn used(&self) -> usize {
+        self.shared.used.load(Ordering::Relaxed)
+    }
+
+    /// How many inodes it has
+    pub fn inode_count(&self) -> usize {
+        self.shared.nodes.lock().len()
+    }
+}
+
+impl Default for RamFs {
+    fn default() -> Self {
+        RamFs::new()
+    }
+}
+
+impl Filesystem for RamFs {
+    fn name(&self) -> &str {
+        "ramfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+/* Testing */
+
+#[test_case]
+fn ramfs_holes() {
+    use crate::task::block_on;
+
+    let fs = RamFs::with_limit(4 * BLOCK_SIZE);
+    let root = fs.root();
+    let file = block_on(root.create("file", FileType::Regular)).unwrap();
+
+    // A write far into the file leaves a hole before it
+    let offset = 100 * BLOCK_SIZE as u64 + 10;
+    assert_eq!(block_on(file.write_at(offset, b"hello")), Ok(5));
+    let stat = block_on(file.stat()).unwrap();
+    assert_eq!((stat.size, stat.allocated), (offset + 5, BLOCK_SIZE as u64));
+    let mut buffer = [0xFFu8; 16];
+    assert_eq!(block_on(file.read_at(offset - 6, &mut buffer)), Ok(11));
+    assert_eq!(&buffer[..11], b"\0\0\0\0\0\0hello");
+    assert_eq!(block_on(file.read_at(offset + 5, &mut buffer)), Ok(0));
+
+    // Shrinking clears what was cut off, so growing again reads zeros
+    block_on(file.truncate(offset + 2)).unwrap();
+    block_on(file.truncate(offset + 5)).unwrap();
+    assert_eq!(block_on(file.read_at(offset, &mut buffer)), Ok(5));
+    assert_eq!(&buffer[..5], b"he\0\0\0");
+    block_on(file.truncate(0)).unwrap();
+    assert_eq!(fs.used(), 0);
+
+    // Past the limit - the part that fits is written
+    let data = vec![1u8; 6 * BLOCK_SIZE];
+    assert_eq!(block_on(file.write_at(0, &data)), Ok(4 * BLOCK_SIZE));
+    assert_eq!(block_on(file.write_at(4 * BLOCK_SIZE as u64, &data)), Err(FsError::NoSpace));
+    drop(file);
+    block_on(root.unlink("file")).unwrap();
+    assert_eq!(fs.used(), 0);
+    assert_eq!(fs.inode_count(), 1);
+}
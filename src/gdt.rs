@@ -3,6 +3,8 @@ use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::SegmentSelector;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor};
 use lazy_static::lazy_static;
+use alloc::boxed::Box;
+use crate::smp::MAX_CPUS;
 use crate::{serial_println};
 
 
@@ -10,7 +12,25 @@ use crate::{serial_println};
 /// 
 /// This stack will be used on a double fault
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// The stack NMIs (non-maskable interrupts) run on. An NMI can arrive at any point - even halfway through
+/// switching stacks - so it gets its own known good stack too
+pub const NMI_IST_INDEX: u16 = 1;
 
+/// How many IST stacks each core has
+const IST_STACK_COUNT: usize = 2;
+/// Size of each IST stack
+const IST_STACK_SIZE: usize = 4096 * 5;
+
+/// The IST stacks of every core. Each core needs its own, as two cores double faulting onto the same
+/// stack would corrupt each other. They're statics so the BSP has them before the heap exists
+static mut IST_STACKS: [[[u8; IST_STACK_SIZE]; IST_STACK_COUNT]; MAX_CPUS] =
+    [[[0; IST_STACK_SIZE]; IST_STACK_COUNT]; MAX_CPUS];
+
+/// The top of IST stack `index` for core `cpu` (stacks grow down, so we hand out the end)
+fn ist_stack_top(cpu: usize, index: u16) -> VirtAddr {
+    let stack_start = VirtAddr::from_ptr(unsafe { &IST_STACKS[cpu][index as usize] });
+    stack_start + IST_STACK_SIZE
+}
 
 /// Create a TSS, which stores our stack tables.
 /// This can be used for privilage tables, like for user-only apps. 
@@ -19,20 +39,17 @@ pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 /// run into a double fault caused by, for example a stack overflow. NOTE: as we 
 /// lack a guard page, we should not do any stack intensive tasks on the double fault stack in case we corrupt the memory below the stack.
 /// 
-/// TODO: memory allocation
+/// Every core has its own TSS, pointing at its own IST stacks.
+fn new_tss(cpu: usize) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = ist_stack_top(cpu, DOUBLE_FAULT_IST_INDEX);
+    tss.interrupt_stack_table[NMI_IST_INDEX as usize] = ist_stack_top(cpu, NMI_IST_INDEX);
+    tss
+}
+
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
+    /// The bootstrap processor's TSS (CPU 0). The APs allocate theirs in [init_ap](fn.init_ap.html)
+    static ref TSS: TaskStateSegment = new_tss(0);
 }
 
 
@@ -78,3 +95,27 @@ pub fn init() {
         serial_println!("[LOG] Set GDT tss selector");
     }
 }
+
+/// # init_ap
+/// 
+/// Give an application processor its own GDT and TSS, and load them. Every core needs its own TSS, as the
+/// TSS holds the interrupt stacks.
+/// 
+/// The tables are leaked, as they must live as long as the core does (forever).
+pub fn init_ap(cpu: usize) {
+    use x86_64::instructions::segmentation::set_cs;
+    use x86_64::instructions::tables::load_tss;
+
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(new_tss(cpu)));
+
+    let gdt: &'static mut GlobalDescriptorTable = Box::leak(Box::new(GlobalDescriptorTable::new()));
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    let gdt: &'static GlobalDescriptorTable = gdt;
+
+    gdt.load();
+    unsafe {
+        set_cs(code_selector);
+        load_tss(tss_selector);
+    }
+}
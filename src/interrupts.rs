@@ -1,14 +1,17 @@
 // Import our IDT, which defines how exceptions and interrupts are handled
 // The x86_64 crate controls how the CPU stores the stack and CPU states when 
 // an exception or interrupt is triggered. We also import the stack frame struct
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame};
 use x86_64::structures::idt::PageFaultErrorCode;
 use crate::hlt_loop;
 use crate::{println, print, del_col, serial_println};
 use crate::gdt; // Get the double_fault stack index
 use lazy_static::lazy_static;
 use pic8259_simple::ChainedPics;
-use spin;
+use crate::sync::IrqSpinlock;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::percpu;
+use crate::acpi::{self, Polarity, TriggerMode};
 
 
 
@@ -21,10 +24,40 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 /// asynchrynously to the CPU. It can take input from various sources, like mouse, keyboard, Real time clock, 
 /// ACPI, a total of 15 interrupts. Interrupts are better than polling as it allows the CPU to react much quicker.
 /// 
-/// Here, we lock it in a mutex as its mutable state cannot change, especially since it runs async (And especially
-/// if we add multiprocessing support).
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+/// Here, we lock it as its mutable state cannot change, especially since it runs async (And especially
+/// with multiprocessing support). Interrupt handlers lock it to send the end of interrupt, so it is an
+/// [IrqSpinlock](../sync/irq_spinlock/struct.IrqSpinlock.html).
+pub static PICS: IrqSpinlock<ChainedPics> =
+    IrqSpinlock::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+/// How often the PIC timer interrupts. We never reprogram PIT channel 0, so it runs at the default
+/// 1193182 / 65536 Hz
+pub const TIMER_HZ: u64 = 18;
+
+/// How many timer interrupts there have been since interrupts were enabled
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// # ticks
+///
+/// The timer interrupts so far, `TIMER_HZ` a second. For timeouts that can't spin (like in an async task);
+/// only the bootstrap processor gets the timer interrupt, so this is the same on every core
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+percpu! {
+    /// How many hardware interrupts (timer, keyboard, IPIs) each core has handled. Per core, so
+    /// counting doesn't need a lock or bounce a cache line between cores
+    pub static INTERRUPT_COUNT: AtomicU64 = AtomicU64::new(0);
+
+    /// How many NMIs each core has had. The NMI handler can't take any lock (the NMI may have landed while
+    /// this core held it), so it only counts - [report_nmis](fn.report_nmis.html) prints them later
+    pub static NMI_COUNT: AtomicU64 = AtomicU64::new(0);
+    /// How many of `NMI_COUNT` have been printed
+    static NMIS_REPORTED: AtomicU64 = AtomicU64::new(0);
+    /// Where the last NMI on each core interrupted
+    static NMI_ADDRESS: AtomicU64 = AtomicU64::new(0);
+}
 
 /// # InterruptIndex
 /// 
@@ -33,11 +66,19 @@ pub static PICS: spin::Mutex<ChainedPics> =
 /// 
 /// So, in order to get around this, we offset it by 32. This InterruptIndex struct will 
 /// store our interrupt values, to save us time remembering it all.
+/// 
+/// The local APIC vectors live at the top of the IDT, well out of the way of the PIC. Everything in between
+/// is handed out at runtime, by [allocate_vector](fn.allocate_vector.html).
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    AcpiSci = PIC_1_OFFSET + 9, // The ACPI System Control Interrupt (power button and friends). IRQ 9 on PCs
+    PrimaryAta = PIC_1_OFFSET + 14, // The legacy IDE channels, IRQs 14 and 15
+    SecondaryAta,
+    ApicWakeup = 0xF0, // IPI sent to wake a halted core when it has new work
+    ApicSpurious = 0xFF, // The local APIC sends this if an interrupt disappears before it is delivered
 }
 
 impl InterruptIndex {
@@ -50,6 +91,109 @@ impl InterruptIndex {
     }
 }
 
+/// The vectors [allocate_vector](fn.allocate_vector.html) hands out - everything between the PIC and the
+/// local APIC's own vectors. MSI and MSI-X interrupts are delivered straight to a local APIC, so there is one
+/// of these per device interrupt (or per queue)
+pub const DYNAMIC_VECTOR_START: u8 = 0x30;
+pub const DYNAMIC_VECTOR_END: u8 = 0xEF; // inclusive
+const DYNAMIC_VECTOR_COUNT: usize = (DYNAMIC_VECTOR_END - DYNAMIC_VECTOR_START) as usize + 1;
+
+/// # VectorHandler
+///
+/// Called (in interrupt context) when an allocated vector fires, with the `context` it was allocated with -
+/// usually an index into the driver's own table of devices or queues. It runs with interrupts off, so it
+/// must not block or allocate. The end of interrupt is sent for it.
+pub type VectorHandler = fn(context: usize);
+
+/// The handler and context for each dynamic vector. `None` means the vector is free. Interrupt handlers copy
+/// their entry out, so the lock is only held for a moment and never while a handler runs
+static VECTOR_HANDLERS: IrqSpinlock<[Option<(VectorHandler, usize)>; DYNAMIC_VECTOR_COUNT]> =
+    IrqSpinlock::new([None; DYNAMIC_VECTOR_COUNT]);
+
+/// # allocate_vector
+///
+/// Take a free IDT vector, and call `handler` with `context` whenever it fires. `None` if every vector is taken.
+/// Point an MSI or MSI-X message at the vector (see [msi](../driver/pci/msi/index.html)) to use it.
+pub fn allocate_vector(handler: VectorHandler, context: usize) -> Option<u8> {
+    let mut handlers = VECTOR_HANDLERS.lock();
+    let index = handlers.iter().position(|entry| entry.is_none())?;
+    handlers[index] = Some((handler, context));
+    Some(DYNAMIC_VECTOR_START + index as u8)
+}
+
+/// Give back a vector from [allocate_vector](fn.allocate_vector.html). Make sure nothing can still send it
+/// (mask or disable the MSI first) - if it does fire, it is acknowledged and ignored
+pub fn free_vector(vector: u8) {
+    if let Some(entry) = dynamic_index(vector) {
+        VECTOR_HANDLERS.lock()[entry] = None;
+    }
+}
+
+/// How many dynamic vectors are free
+pub fn free_vector_count() -> usize {
+    VECTOR_HANDLERS.lock().iter().filter(|entry| entry.is_none()).count()
+}
+
+/// Where `vector` is in `VECTOR_HANDLERS`, if it is a dynamic vector
+fn dynamic_index(vector: u8) -> Option<usize> {
+    if (DYNAMIC_VECTOR_START..=DYNAMIC_VECTOR_END).contains(&vector) {
+        Some((vector - DYNAMIC_VECTOR_START) as usize)
+    } else {
+        None
+    }
+}
+
+/// # unmask_pic_irq
+///
+/// Let ISA IRQ `irq` through the PIC. Remapping the PICs keeps the firmware's masks, so an IRQ the firmware
+/// didn't use may still be masked
+pub fn unmask_pic_irq(irq: u8) {
+    use x86_64::instructions::port::Port;
+
+    let _pics = PICS.lock(); // nobody else touches the PICs while we read-modify-write the mask
+    unsafe {
+        if irq >= 8 {
+            let mut slave = Port::<u8>::new(0xA1);
+            let mask = slave.read();
+            slave.write(mask & !(1 << (irq - 8)));
+        }
+        // Slave IRQs arrive through the master's IRQ 2
+        let line = if irq >= 8 { 2 } else { irq };
+        let mut master = Port::<u8>::new(0x21);
+        let mask = master.read();
+        master.write(mask & !(1 << line));
+    }
+}
+
+/// # IsaIrqRoute
+///
+/// Where an ISA IRQ really arrives once interrupts go through an I/O APIC rather than the PIC. ISA IRQs are
+/// edge triggered, active high, and wired to the global system interrupt (GSI) with the same number - unless
+/// the ACPI MADT has an interrupt source override saying otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsaIrqRoute {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// Look up where ISA IRQ `irq` is routed, applying any ACPI interrupt source override
+pub fn isa_irq_route(irq: u8) -> IsaIrqRoute {
+    let mut route = IsaIrqRoute { gsi: irq as u32, polarity: Polarity::ActiveHigh, trigger: TriggerMode::Edge };
+
+    if let Some(irq_override) = acpi::isa_irq_override(irq) {
+        route.gsi = irq_override.gsi;
+        if irq_override.polarity != Polarity::BusDefault {
+            route.polarity = irq_override.polarity;
+        }
+        if irq_override.trigger != TriggerMode::BusDefault {
+            route.trigger = irq_override.trigger;
+        }
+    }
+
+    route
+}
+
 lazy_static! {
     /// # IDT
     /// 
@@ -66,12 +210,35 @@ lazy_static! {
 
         idt.page_fault.set_handler_fn(page_fault_handler); // Set the handler
 
+        unsafe {
+            idt.non_maskable_interrupt.set_handler_fn(nmi_handler).set_stack_index(gdt::NMI_IST_INDEX); // NMIs can arrive anywhere, so they get a known good stack
+        }
+
         idt[InterruptIndex::Timer.as_usize()]
             .set_handler_fn(timer_interrupt_handler); // Add our timer interrupt
 
         idt[InterruptIndex::Keyboard.as_usize()]
             .set_handler_fn(keyboard_interrupt_handler); // Add our keyboard interrupt
 
+        idt[InterruptIndex::AcpiSci.as_usize()]
+            .set_handler_fn(acpi_sci_handler); // ACPI events, like the power button
+
+        idt[InterruptIndex::PrimaryAta.as_usize()]
+            .set_handler_fn(primary_ata_handler); // IDE disk commands finishing
+
+        idt[InterruptIndex::SecondaryAta.as_usize()]
+            .set_handler_fn(secondary_ata_handler);
+
+        idt[InterruptIndex::ApicWakeup.as_usize()]
+            .set_handler_fn(apic_wakeup_handler); // Wake up IPI from another core
+
+        idt[InterruptIndex::ApicSpurious.as_usize()]
+            .set_handler_fn(apic_spurious_handler);
+
+        // Every dynamic vector gets its own stub, which looks up whoever allocated it
+        for (index, &handler) in DYNAMIC_HANDLERS.iter().flatten().enumerate() {
+            idt[DYNAMIC_VECTOR_START as usize + index].set_handler_fn(handler);
+        }
 
         idt
     };
@@ -141,6 +308,31 @@ extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut InterruptStackFra
 }
 
 
+// Non-maskable interrupt handler. These come from hardware errors (or another core, on purpose),
+// and can't be disabled. They can land in the middle of anything, even while this core holds the serial
+// port lock - so we only count it, and report_nmis prints it later
+extern "x86-interrupt" fn nmi_handler(stack_frame: &mut InterruptStackFrame)
+{
+    NMI_ADDRESS.get().store(stack_frame.instruction_pointer.as_u64(), Ordering::Relaxed);
+    NMI_COUNT.get().fetch_add(1, Ordering::Release);
+}
+
+/// Print the NMIs the calling core has had since it last called this. Only call it from normal (not interrupt)
+/// context - the executors do, before they go idle
+pub fn report_nmis() {
+    let count = NMI_COUNT.get().load(Ordering::Acquire);
+    let reported = NMIS_REPORTED.get().swap(count, Ordering::Relaxed);
+    if count != reported {
+        serial_println!(
+            "NMI on CPU {} ({} since the last report, the last one at {:#x})",
+            percpu::current_cpu_id(),
+            count - reported,
+            NMI_ADDRESS.get().load(Ordering::Relaxed)
+        );
+    }
+}
+
+
 /* Interrupts */
 
 // Timer interrupt handler. Runs every tick or so.
@@ -148,6 +340,9 @@ extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut InterruptStackFra
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame)
 {
     //print!(".");
+    INTERRUPT_COUNT.get().fetch_add(1, Ordering::Relaxed);
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    crate::driver::ata::handle_timer(); // so commands whose interrupt never comes can time out
     // Take our mutex, lock it
     // Then tell the PIC that the interrupt has been handled
     // So it can continue serving interrupts
@@ -161,14 +356,9 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptSt
 // This gets called on key press and key release
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame)
 {
-    use crate::driver::DRIVER_HANDLER;
-
-    {
-        // Lock our mutex to our driver handler (So we can use it mutably), then get the keyboard
-        // driver and scan a keypress.
-        DRIVER_HANDLER.lock().keyboard_driver.read_scancode();
-    } // drop our lock
-    
+    INTERRUPT_COUNT.get().fetch_add(1, Ordering::Relaxed);
+    // Read the keypress, and pass it on to the keyboard driver if it is bound
+    crate::driver::keyboard::read_scancode();
 
     // Let the PIC know that we've finished with the interrupt
     unsafe {
@@ -179,6 +369,94 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut Interrup
 
 
 
+// ACPI System Control Interrupt handler
+// The firmware raises this for ACPI events - for us, just the power button
+extern "x86-interrupt" fn acpi_sci_handler(_stack_frame: &mut InterruptStackFrame)
+{
+    INTERRUPT_COUNT.get().fetch_add(1, Ordering::Relaxed);
+    crate::power::handle_sci(); // just acknowledges the event - a task does the shutting down
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::AcpiSci.as_u8());
+    }
+}
+
+// ATA (IDE) handlers
+// A drive interrupts when it has finished a command, or has the next sector ready
+extern "x86-interrupt" fn primary_ata_handler(_stack_frame: &mut InterruptStackFrame)
+{
+    INTERRUPT_COUNT.get().fetch_add(1, Ordering::Relaxed);
+    crate::driver::ata::handle_interrupt(0);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::PrimaryAta.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn secondary_ata_handler(_stack_frame: &mut InterruptStackFrame)
+{
+    INTERRUPT_COUNT.get().fetch_add(1, Ordering::Relaxed);
+    crate::driver::ata::handle_interrupt(1);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::SecondaryAta.as_u8());
+    }
+}
+
+// Wake up IPI handler
+// Another core sends us this when it queues work for us while we're halted. Simply
+// getting the interrupt takes us out of `hlt`, so there's nothing to do but acknowledge it
+extern "x86-interrupt" fn apic_wakeup_handler(_stack_frame: &mut InterruptStackFrame)
+{
+    INTERRUPT_COUNT.get().fetch_add(1, Ordering::Relaxed);
+    crate::apic::end_of_interrupt();
+}
+
+// Spurious interrupt handler
+// These must NOT be acknowledged with an end of interrupt, so we just ignore them
+extern "x86-interrupt" fn apic_spurious_handler(_stack_frame: &mut InterruptStackFrame)
+{
+}
+
+// Dynamic vector handlers
+// The CPU doesn't tell a handler which vector it came in on, so each vector needs its own function. This
+// makes a row of 16 of them, for vectors `row * 16` to `row * 16 + 15`
+macro_rules! dynamic_handler_row {
+    ($row:literal) => {
+        dynamic_handler_row!(@ $row; 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15)
+    };
+    (@ $row:literal; $($column:literal)*) => {
+        [$({
+            extern "x86-interrupt" fn handler(_stack_frame: &mut InterruptStackFrame) {
+                dynamic_interrupt($row * 16 + $column);
+            }
+            handler as HandlerFunc
+        }),*]
+    };
+}
+
+/// One stub per dynamic vector, `DYNAMIC_VECTOR_START` (0x30) to `DYNAMIC_VECTOR_END` (0xEF)
+static DYNAMIC_HANDLERS: [[HandlerFunc; 16]; DYNAMIC_VECTOR_COUNT / 16] = [
+    dynamic_handler_row!(0x3), dynamic_handler_row!(0x4), dynamic_handler_row!(0x5), dynamic_handler_row!(0x6),
+    dynamic_handler_row!(0x7), dynamic_handler_row!(0x8), dynamic_handler_row!(0x9), dynamic_handler_row!(0xA),
+    dynamic_handler_row!(0xB), dynamic_handler_row!(0xC), dynamic_handler_row!(0xD), dynamic_handler_row!(0xE),
+];
+
+// Run the handler for an allocated vector. MSIs are delivered by the local APIC, so that's who gets the end of
+// interrupt
+fn dynamic_interrupt(vector: u8)
+{
+    INTERRUPT_COUNT.get().fetch_add(1, Ordering::Relaxed);
+    let entry = dynamic_index(vector).and_then(|index| VECTOR_HANDLERS.lock()[index]);
+    if let Some((handler, context)) = entry {
+        handler(context);
+    }
+    crate::apic::end_of_interrupt();
+}
+
 
 /* Testing */
 
@@ -189,3 +467,21 @@ fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
 }
 
+// Vectors are handed out in order, and can be reused once freed
+#[test_case]
+fn test_allocate_vector() {
+    fn handler(_context: usize) {}
+
+    let free = free_vector_count();
+    let first = allocate_vector(handler, 1).expect("no free vectors");
+    let second = allocate_vector(handler, 2).expect("no free vectors");
+    assert!(first >= DYNAMIC_VECTOR_START && second <= DYNAMIC_VECTOR_END);
+    assert!(second > first);
+    assert_eq!(free_vector_count(), free - 2);
+
+    free_vector(first);
+    assert_eq!(allocate_vector(handler, 3), Some(first));
+    free_vector(first);
+    free_vector(second);
+    assert_eq!(free_vector_count(), free);
+}
@@ -7,6 +7,8 @@
 #![feature(const_in_array_repeat_expressions)] // None type doesn't support COPY, so we use this
 #![feature(vec_into_raw_parts)] // Lets us split up alloc types to check debug info
 #![feature(wake_trait)] // Lets us use the Wake trait, a safe alternative to RawWaker
+#![feature(global_asm)] // Lets us include the AP startup trampoline, which is written in assembly
+#![feature(asm)] // Inline assembly, for things the x86_64 crate doesn't wrap (like gs: relative loads)
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
@@ -22,6 +24,16 @@ pub mod allocator; // Dynamic allocation functions, for heap support
 pub mod cpu_specs; // Outputs CPU specs and details CPU support
 pub mod driver; // All kernel level drivers (Not user)
 pub mod task; // Cooperative Multitasking - basically async
+pub mod apic; // Local APIC, for inter-processor interrupts
+pub mod smp; // Multi-core support - starts the other CPU cores
+pub mod acpi; // ACPI tables (CPUs, interrupt routing, PCI Express config space, power management)
+pub mod percpu; // Per-CPU variables, found through the GS base
+pub mod power; // Shutdown and reboot, through ACPI
+pub mod sync; // Locks - interrupt safe and fair spinlocks, with optional lock debugging
+pub mod block; // Block devices (disks) - the interface storage drivers implement
+pub mod net; // Network devices (NICs) - the interface network drivers implement
+pub mod entropy; // Kernel entropy pool - random numbers, fed by hardware random number generators
+pub mod fs; // Virtual filesystem - files, directories and mounts, the interface filesystems implement
 
 use core::panic::PanicInfo;
 
@@ -34,6 +46,7 @@ use bootloader::{entry_point, BootInfo};
 /// and much, much more.
 pub fn init() {
     serial_println!("[INIT] Booting kernel...");
+    percpu::init(0); // The BSP is always CPU 0. Set up its per-CPU area (GS base) before anything asks which core it is on
     interrupts::init_idt(); // Load the IDT to the CPU.
     gdt::init(); // init the GDT (Load the TSS and setup the GDT)
     unsafe { interrupts::PICS.lock().initialize() }; // Enable interrupts from the PIC
@@ -81,6 +94,8 @@ where
         serial_print!("{}...\t", core::any::type_name::<T>());
         self();
         serial_println!("[ok]");
+        // Results also go to the test channel, if there is a virtio console with one
+        driver::virtio::console::write_channel(driver::virtio::console::Channel::Test, &format!("{} [ok]\n", core::any::type_name::<T>()));
     }
 }
 
@@ -105,6 +120,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    driver::virtio::console::write_channel(driver::virtio::console::Channel::Test, &format!("[failed] {}\n", info));
     exit_qemu(QemuExitCode::Failed);
     loop {}
 }
@@ -121,9 +137,14 @@ entry_point!(test_kernel_main);
 
 /// Entry point for `cargo test`
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
     // like before
     init();
+    // Some unit tests allocate (the driver registry, channels...), so they need a heap - and the DMA pool
+    let phys_mem_offset = x86_64::VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+    memory::dma::init();
     test_main();
     hlt_loop();
 }
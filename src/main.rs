@@ -21,9 +21,9 @@ use core::ops::Add;
 
 /// Use our library to get the various macros we want
 use dbos::{println, clear_screen, serial_println};
-use dbos::{memory, allocator, cpu_specs}; // Modules that control memory, the allocator and output CPU info
-use dbos::task::{Task, executor::Executor}; // Use our better Executor to run our async tasks
-use dbos::driver::{DRIVER_HANDLER, keyboard}; // Get access to our keyboard module so we can add the print_keypresses async function to our task queue
+use dbos::{memory, allocator, cpu_specs, acpi, apic, power, smp, block, net, fs}; // Modules that control memory, the allocator, output CPU info, the ACPI tables, the local APIC, power, other cores, disks, network cards and files
+use dbos::task::smp_executor::SMP_EXECUTOR; // Use our multi-core executor to run our async tasks
+use dbos::driver::{self, keyboard, pci, registry}; // The driver model, and our keyboard module so we can add the print_keypresses async function to our task queue
 
 use tinypci::PciFullClass;
 
@@ -56,12 +56,19 @@ fn main(boot_info: &'static BootInfo) {
     //let memory_capacity = memory::get_physical_memory_capacity(&boot_info.memory_map);
     //println!("Free physical memory: {:?}/{:?} MiB\n", memory_capacity.0, memory_capacity.1);
 
-    {
-        let driver_handler = &DRIVER_HANDLER.lock();
+    if let Some(scanner) = pci::scanner() {
+        pci::print_devices(&scanner.functions);
+        registry::print_devices();
+        for disk in block::devices() {
+            println!("Disk {}: {} MiB", disk.name(), disk.sector_count() * disk.sector_size() as u64 >> 20);
+        }
+        for card in net::devices() {
+            println!("Network {}: {}", card.name(), card.mac_address());
+        }
         let storage_types = vec!(PciFullClass::MassStorage_SATA, PciFullClass::MassStorage_IDE, PciFullClass::MassStorage_NVM, PciFullClass::MassStorage_Other, PciFullClass::MassStorage_IpiBus, PciFullClass::MassStorage_Floppy, PciFullClass::MassStorage_ATA);
         
         for pci_type in storage_types{
-            let devices = driver_handler.pci_scanner.scan_for_type(pci_type);
+            let devices = scanner.scan_for_type(pci_type);
             if devices.len() > 0{
                 serial_println!("{:?} devices found: {:?}", pci_type, devices);
                 println!("{:?} devices found: {:?}", pci_type, devices);
@@ -73,10 +80,10 @@ fn main(boot_info: &'static BootInfo) {
 
     }
 
-    let mut executor = Executor::new(); // Create a new Executor
-    executor.spawn(Task::new(example_task())); // Add a new task to the simple executor
-    executor.spawn(Task::new(keyboard::print_keypresses())); // Add our "print_keypresses" task to our executor
-    executor.run(); // Run all tasks
+    SMP_EXECUTOR.spawn(example_task()); // Add a new task to the executor
+    SMP_EXECUTOR.spawn(keyboard::print_keypresses()); // Add our "print_keypresses" task to our executor
+    SMP_EXECUTOR.spawn(power::handle_power_button()); // Shut down when the power button is pressed
+    SMP_EXECUTOR.run(); // Run all tasks on this core
 
 
 
@@ -103,15 +110,29 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     // Get the offset from bootinfo
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    // Create the mapper using the offset
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    // Create a frame allocator using our memory map from bootinfo
-    let mut frame_allocator = unsafe {
-        memory::BootInfoFrameAllocator::init(&boot_info.memory_map)
-    };
+    // Create the kernel's mapper using the offset, and a frame allocator using our memory map from bootinfo
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
     // Initialize our allocator heap using the mapper and allocator
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
+    allocator::init_heap()
     .expect("heap initialization failed");
+    // Set aside physically contiguous memory for drivers that do DMA (disk and network controllers)
+    memory::dma::init();
+
+    // Find the ACPI tables (CPU list, interrupt overrides, PCI Express config space...). They're read through
+    // the physical memory mapping, and parsed onto the heap, so this has to wait for the allocator
+    acpi::init();
+    // Map PCI Express config space (from the ACPI MCFG), so drivers can reach extended capabilities
+    pci::ecam::init();
+    // Enable the power button, so it shuts us down cleanly
+    power::init();
+    // The local APIC registers are mapped into the kernel page table, so this has to wait for memory::init
+    apic::init();
+    // Start the other CPU cores. They wait in the SMP executor for work
+    smp::init();
+    // Find the devices on every bus and bind drivers to them. Drivers may use MSI, so the local APIC has to be up
+    driver::init();
+    // Mount a ramfs at `/` and unpack the initrd into it, so there are files before any disk is mounted
+    fs::init();
 
 
     // We can now commence the main program
@@ -123,7 +144,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     // map an unused page
     let page = Page::containing_address(VirtAddr::new(0xdeadbeaf000));
-    memory::create_example_mapping(page, &mut mapper, &mut frame_allocator);
+    memory::with_mapper(|mapper, frame_allocator| memory::create_example_mapping(page, mapper, frame_allocator));
     // write the string `New!` to the screen through the new mapping
     let page_ptr: *mut u64 = page.start_address().as_mut_ptr();
     //unsafe { page_ptr.offset(200).write_volatile(0x_f021_f077_f065_f04e)};
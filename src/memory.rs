@@ -4,18 +4,67 @@ use x86_64::{
     structures::paging::{PageTable, Page, PhysFrame, Mapper, Size4KiB, FrameAllocator}
 };
 use x86_64::structures::paging::OffsetPageTable;
+use x86_64::structures::paging::mapper::MapToError;
+use conquer_once::spin::OnceCell;
+/// Memory Map struct that contains mapping info from the BIOS
+use bootloader::bootinfo::MemoryMap;
+use spin::Mutex;
 use crate::serial_println;
 
-/// Initialize a new OffsetPageTable.
+pub mod dma; // Physically contiguous memory for devices that do DMA
+
+/// The virtual address the bootloader mapped all of physical memory to. Set by [init](fn.init.html), and used
+/// to reach physical memory (like memory-mapped device registers) without creating new mappings.
+static PHYSICAL_MEMORY_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+
+/// Where [map_device](fn.map_device.html) maps device memory, one mapping after another
+pub const DEVICE_MEMORY_START: u64 = 0x_7777_0000_0000;
+/// The next free address after `DEVICE_MEMORY_START`
+static NEXT_DEVICE_MEMORY: Mutex<u64> = Mutex::new(DEVICE_MEMORY_START);
+
+/// The kernel's page table, and the allocator that frames for new mappings (and their page tables) come from
+struct Paging {
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
+}
+
+/// Set by [init](fn.init.html). There is only ever one `OffsetPageTable`, so nothing else can hold a `&mut`
+/// to the level 4 table at the same time - everything that maps memory goes through [with_mapper](fn.with_mapper.html)
+static PAGING: OnceCell<Mutex<Paging>> = OnceCell::uninit();
+
+/// Initialize the kernel page table and the frame allocator.
 ///
 /// This function is unsafe because the caller must guarantee that the
 /// complete physical memory is mapped to virtual memory at the passed
-/// `physical_memory_offset`. Also, this function must be only called once
-/// to avoid aliasing `&mut` references (which is undefined behavior).
-pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+/// `physical_memory_offset`, and that all frames marked as `USABLE` in
+/// `memory_map` are really unused. It panics if called more than once.
+pub unsafe fn init(physical_memory_offset: VirtAddr, memory_map: &'static MemoryMap) {
+    PHYSICAL_MEMORY_OFFSET.try_init_once(|| physical_memory_offset)
+        .expect("memory::init should only be called once");
     let level_4_table = active_level_4_table(physical_memory_offset);
+    let mapper = OffsetPageTable::new(level_4_table, physical_memory_offset);
+    let frame_allocator = BootInfoFrameAllocator::init(memory_map);
+    let _ = PAGING.try_init_once(|| Mutex::new(Paging { mapper, frame_allocator }));
     serial_println!("Initialized page table from level 4 offset");
-    OffsetPageTable::new(level_4_table, physical_memory_offset)
+}
+
+/// Run `f` with the kernel page table and frame allocator, locked so only one CPU changes mappings at a time.
+///
+/// Panics if [init](fn.init.html) hasn't been called yet.
+pub fn with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R) -> R {
+    let mut paging = PAGING.try_get().expect("memory::init hasn't been called").lock();
+    let paging = &mut *paging;
+    f(&mut paging.mapper, &mut paging.frame_allocator)
+}
+
+/// Translate a physical address to the virtual address it is mapped at, using the bootloader's
+/// physical memory mapping.
+///
+/// Panics if [init](fn.init.html) hasn't been called yet.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET.try_get()
+        .expect("physical memory offset not initialized");
+    *offset + addr.as_u64()
 }
 
 /// Returns a mutable reference to the active level 4 table.
@@ -23,7 +72,7 @@ pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static>
 /// This function is unsafe because the caller must guarantee that the
 /// complete physical memory is mapped to virtual memory at the passed
 /// `physical_memory_offset`. Also, this function must be only called once
-/// to avoid aliasing `&mut` references (which is undefined behavior).
+/// to avoid aliasing `&mut` references (which is undefined behavior) - [init](fn.init.html) does that.
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
     -> &'static mut PageTable
 {
@@ -56,6 +105,45 @@ pub fn create_example_mapping(
     map_to_result.expect("map_to failed").flush();
 }
 
+/// Map `size` bytes of device memory (MMIO) at `phys` to `virt`. The pages are uncached, as device registers
+/// must be read and written exactly when we say - the bootloader's mapping of physical memory is cached.
+///
+/// `phys` and `virt` should be page aligned. Frames are only allocated for page tables.
+pub fn map_mmio(
+    phys: PhysAddr,
+    virt: VirtAddr,
+    size: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_CACHE | Flags::WRITE_THROUGH | Flags::NO_EXECUTE;
+    for offset in (0..size).step_by(4096) {
+        let page = Page::<Size4KiB>::containing_address(virt + offset);
+        let frame = PhysFrame::<Size4KiB>::containing_address(phys + offset);
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+    Ok(())
+}
+
+/// # map_device
+///
+/// Map `size` bytes of device memory at `phys` (a memory BAR, or part of one) with [map_mmio](fn.map_mmio.html),
+/// and return where. For drivers, which probe long after boot - the mapping goes into the kernel page table
+/// through [with_mapper](fn.with_mapper.html). Mappings are never taken down.
+pub fn map_device(phys: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let offset = phys.as_u64() % 4096;
+    let length = (offset + size.max(1) + 4095) / 4096 * 4096;
+
+    // Holding the lock means only one mapping is made at a time
+    let mut next = NEXT_DEVICE_MEMORY.lock();
+    let virt = VirtAddr::new(*next);
+    with_mapper(|mapper, frame_allocator| map_mmio(phys - offset, virt, length, mapper, frame_allocator))?;
+    *next += length;
+    Ok(virt + offset)
+}
+
 /// A FrameAllocator that always returns `None`.
 /// Just kinda chilling here
 pub struct EmptyFrameAllocator;
@@ -66,9 +154,6 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
-/// Memory Map struct that contains mapping info from the BIOS
-use bootloader::bootinfo::MemoryMap;
-
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
 /// We use this to allocate frames (Locations in physical memory) so we can allocate
 /// a frame to a page (the data to store in the virtual memory) to the Page table.
@@ -0,0 +1,200 @@
+//! # DMA memory
+//!
+//! Devices that do DMA (bus mastering) read and write physical memory themselves, so what we give them must
+//! be physically contiguous - the heap is only contiguous virtually. [init](fn.init.html) takes a run of
+//! contiguous frames from the frame allocator, and [DmaBuffer](struct.DmaBuffer.html)s are handed out from it
+//! a page at a time. We reach them through the physical memory mapping (x86 DMA is cache coherent, so the
+//! cached mapping is fine).
+
+use core::slice;
+use spin::Mutex;
+use x86_64::structures::paging::FrameAllocator;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::serial_println;
+
+const PAGE_SIZE: usize = 4096;
+/// How many pages the pool has (4 MiB). One bit of `Pool::used` each
+const POOL_PAGES: usize = 1024;
+/// How many frames we look through for a contiguous run before giving up
+const MAX_FRAMES_TRIED: usize = 16 * POOL_PAGES;
+
+struct Pool {
+    /// Physical address of the first page
+    start: u64,
+    /// Whether `init` found the memory
+    ready: bool,
+    /// Bit `n` is set while page `n` is handed out
+    used: [u64; POOL_PAGES / 64],
+}
+
+impl Pool {
+    fn is_used(&self, page: usize) -> bool {
+        self.used[page / 64] & (1 << (page % 64)) != 0
+    }
+
+    fn set_used(&mut self, page: usize, used: bool) {
+        if used {
+            self.used[page / 64] |= 1 << (page % 64);
+        } else {
+            self.used[page / 64] &= !(1 << (page % 64));
+        }
+    }
+
+    /// The first run of `pages` free pages (first fit)
+    fn find_free(&self, pages: usize) -> Option<usize> {
+        let mut run = 0;
+        for page in 0..POOL_PAGES {
+            run = if self.is_used(page) { 0 } else { run + 1 };
+            if run == pages {
+                return Some(page + 1 - pages);
+            }
+        }
+        None
+    }
+}
+
+static POOL: Mutex<Pool> = Mutex::new(Pool { start: 0, ready: false, used: [0; POOL_PAGES / 64] });
+
+/// # init
+///
+/// Take `POOL_PAGES` physically contiguous frames for DMA buffers. Call after `memory::init`, before any driver
+/// that does DMA probes. The frame allocator hands frames out in address order, so we keep taking them until
+/// we have a long enough run - the frames skipped over are lost, but that only happens at the holes in the
+/// memory map.
+pub fn init() {
+    let (start, run) = super::with_mapper(|_, frame_allocator| {
+        let mut start = None;
+        let mut run = 0;
+        for _ in 0..MAX_FRAMES_TRIED {
+            let frame = match frame_allocator.allocate_frame() {
+                Some(frame) => frame.start_address(),
+                None => break,
+            };
+            match start {
+                Some(start_address) if start_address + (run * PAGE_SIZE) as u64 == frame => run += 1,
+                _ => {
+                    start = Some(frame);
+                    run = 1;
+                }
+            }
+            if run == POOL_PAGES {
+                break;
+            }
+        }
+        (start, run)
+    });
+
+    match start {
+        Some(start) if run == POOL_PAGES => {
+            let mut pool = POOL.lock();
+            pool.start = start.as_u64();
+            pool.ready = true;
+            serial_println!("[LOG] DMA pool: {} KiB at {:#x}", POOL_PAGES * PAGE_SIZE / 1024, start.as_u64());
+        }
+        _ => serial_println!("[LOG] DMA pool: couldn't find {} contiguous frames", POOL_PAGES),
+    }
+}
+
+/// # DmaBuffer
+///
+/// Physically contiguous, page aligned, zeroed memory a device can DMA to and from. Given back to the pool
+/// when dropped - so it must outlive any transfer that uses it.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    phys: PhysAddr,
+    pages: usize,
+}
+
+impl DmaBuffer {
+    /// At least `size` bytes (rounded up to whole pages). `None` if the pool is full, or was never set up
+    pub fn new(size: usize) -> Option<Self> {
+        let pages = ((size + PAGE_SIZE - 1) / PAGE_SIZE).max(1);
+        let phys = {
+            let mut pool = POOL.lock();
+            if !pool.ready {
+                return None;
+            }
+            let first = pool.find_free(pages)?;
+            for page in first..first + pages {
+                pool.set_used(page, true);
+            }
+            PhysAddr::new(pool.start + (first * PAGE_SIZE) as u64)
+        };
+        let mut buffer = DmaBuffer { phys, pages };
+        buffer.as_mut_slice().iter_mut().for_each(|byte| *byte = 0);
+        Some(buffer)
+    }
+
+    /// The address to give the device
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// The address to use ourselves
+    pub fn virt_addr(&self) -> VirtAddr {
+        super::phys_to_virt(self.phys)
+    }
+
+    /// The size in bytes (whole pages)
+    pub fn size(&self) -> usize {
+        self.pages * PAGE_SIZE
+    }
+
+    /// A pointer to the byte at `offset`, as a `T`. For structures the device reads and writes, which should be
+    /// accessed with volatile reads and writes
+    pub fn ptr<T>(&self, offset: usize) -> *mut T {
+        assert!(offset + core::mem::size_of::<T>() <= self.size(), "DMA buffer access out of bounds");
+        (self.virt_addr() + offset).as_mut_ptr()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.virt_addr().as_ptr(), self.size()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.virt_addr().as_mut_ptr(), self.size()) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        let mut pool = POOL.lock();
+        let first = (self.phys.as_u64() - pool.start) as usize / PAGE_SIZE;
+        for page in first..first + self.pages {
+            pool.set_used(page, false);
+        }
+    }
+}
+
+// Nothing else points into the buffer - the device only gets the physical address while a transfer is running
+unsafe impl Send for DmaBuffer {}
+unsafe impl Sync for DmaBuffer {}
+
+/// How many pages of the pool are free
+pub fn free_pages() -> usize {
+    let pool = POOL.lock();
+    if !pool.ready {
+        return 0;
+    }
+    (0..POOL_PAGES).filter(|&page| !pool.is_used(page)).count()
+}
+
+/* Testing */
+
+#[test_case]
+fn dma_buffers() {
+    let free = free_pages();
+    assert!(free > 0, "DMA pool not set up");
+    {
+        let mut first = DmaBuffer::new(100).unwrap();
+        let second = DmaBuffer::new(PAGE_SIZE + 1).unwrap();
+        assert_eq!(first.size(), PAGE_SIZE);
+        assert_eq!(second.size(), 2 * PAGE_SIZE);
+        assert_eq!(first.phys_addr().as_u64() % PAGE_SIZE as u64, 0);
+        assert!(second.as_slice().iter().all(|&byte| byte == 0));
+        first.as_mut_slice()[0] = 0xAB;
+        assert_eq!(unsafe { *first.ptr::<u8>(0) }, 0xAB);
+        assert_eq!(free_pages(), free - 3);
+    }
+    assert_eq!(free_pages(), free);
+}
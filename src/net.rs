@@ -0,0 +1,199 @@
+//! # Network devices
+//!
+//! Network cards, as ethernet frame movers. NIC drivers implement [NetworkDevice](trait.NetworkDevice.html)
+//! for each card they find and [register](fn.register.html) it under a name from
+//! [next_name](fn.next_name.html) (`eth0`, `eth1`...), so a network stack can use any card without knowing
+//! what is behind it.
+//!
+//! Frames are whole ethernet frames - destination and source MAC, ethertype, payload - without the CRC.
+//! Sending and receiving are async: `receive` sleeps until a frame arrives.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use crate::serial_println;
+
+/// The ethernet header - two MAC addresses and the ethertype
+pub const ETHERNET_HEADER_SIZE: usize = 14;
+/// The usual MTU, for cards that don't say
+pub const DEFAULT_MTU: usize = 1500;
+
+/// # MacAddress
+///
+/// A card's hardware address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: MacAddress = MacAddress([0xFF; 6]);
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5])
+    }
+}
+
+/// # NetError
+///
+/// Why a frame couldn't be sent or received
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// There's no cable, or nothing at the other end of it
+    LinkDown,
+    /// The frame is bigger than the MTU allows, or smaller than an ethernet header
+    BadFrameSize,
+    /// The card reported an error, or stopped responding
+    DeviceError(&'static str),
+}
+
+/// # PartialChecksum
+///
+/// An internet checksum left for someone else to finish: it covers the frame from `start` to the end, and goes
+/// at `start + offset`. Whatever is there already (like a TCP or UDP pseudo header sum) is included
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartialChecksum {
+    pub start: usize,
+    pub offset: usize,
+}
+
+/// # Received
+///
+/// A frame that arrived, and whether the card checked its TCP or UDP checksum for us
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Received {
+    pub frame: Vec<u8>,
+    pub checksum_valid: bool,
+}
+
+/// What `NetworkDevice` methods return - a boxed future, so the trait can be used as `dyn NetworkDevice`
+pub type NetFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, NetError>> + Send + 'a>>;
+
+/// # NetworkDevice
+///
+/// A network card
+pub trait NetworkDevice: Send + Sync {
+    /// The name it is registered under, like `eth0`
+    fn name(&self) -> &str;
+
+    fn mac_address(&self) -> MacAddress;
+
+    /// The biggest payload a frame can carry
+    fn mtu(&self) -> usize {
+        DEFAULT_MTU
+    }
+
+    /// Whether the card is connected to anything
+    fn link_up(&self) -> bool;
+
+    /// Whether `send` finishes checksums itself. If not, it finishes them in software
+    fn checksum_offload(&self) -> bool {
+        false
+    }
+
+    /// Send `frame`, finishing `checksum` first if there is one
+    fn send<'a>(&'a self, frame: &'a [u8], checksum: Option<PartialChecksum>) -> NetFuture<'a, ()>;
+
+    /// Wait for the next frame
+    fn receive(&self) -> NetFuture<'_, Received>;
+}
+
+/// # check_frame
+///
+/// Check that `frame` can be sent by `device`. Drivers call this before touching the hardware
+pub fn check_frame(device: &dyn NetworkDevice, frame: &[u8]) -> Result<(), NetError> {
+    if frame.len() < ETHERNET_HEADER_SIZE || frame.len() > ETHERNET_HEADER_SIZE + device.mtu() {
+        return Err(NetError::BadFrameSize);
+    }
+    Ok(())
+}
+
+/// # internet_checksum
+///
+/// The ones' complement of the ones' complement sum of `data`'s 16 bit big endian words (RFC 1071). An odd
+/// last byte is padded with zero
+pub fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for word in data.chunks(2) {
+        let high = (word[0] as u32) << 8;
+        sum += high | word.get(1).copied().unwrap_or(0) as u32;
+    }
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Finish `checksum` in `frame`, in software - for cards that can't
+pub fn finish_checksum(frame: &mut [u8], checksum: PartialChecksum) {
+    let field = checksum.start + checksum.offset;
+    if field + 2 > frame.len() {
+        return;
+    }
+    let value = internet_checksum(&frame[checksum.start..]);
+    frame[field..field + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+/// Every registered network device
+static DEVICES: Mutex<Vec<Arc<dyn NetworkDevice>>> = Mutex::new(Vec::new());
+static NEXT_NAME: AtomicUsize = AtomicUsize::new(0);
+
+/// A name for a new network device - `eth0`, `eth1`...
+pub fn next_name() -> String {
+    format!("eth{}", NEXT_NAME.fetch_add(1, Ordering::Relaxed))
+}
+
+/// # register
+///
+/// Make a network device available. Its name must be unique
+pub fn register(device: Arc<dyn NetworkDevice>) {
+    serial_println!(
+        "[LOG] Network device {}: {}, MTU {}, link {}",
+        device.name(),
+        device.mac_address(),
+        device.mtu(),
+        if device.link_up() { "up" } else { "down" }
+    );
+    DEVICES.lock().push(device);
+}
+
+/// Remove the network device called `name` - its driver is going away
+pub fn unregister(name: &str) {
+    DEVICES.lock().retain(|device| device.name() != name);
+}
+
+/// The network device called `name`
+pub fn get(name: &str) -> Option<Arc<dyn NetworkDevice>> {
+    DEVICES.lock().iter().find(|device| device.name() == name).cloned()
+}
+
+/// Every registered network device
+pub fn devices() -> Vec<Arc<dyn NetworkDevice>> {
+    DEVICES.lock().clone()
+}
+
+/* Testing */
+
+#[test_case]
+fn internet_checksums() {
+    // The example from RFC 1071
+    assert_eq!(internet_checksum(&[0x00, 0x01, 0xF2, 0x03, 0xF4, 0xF5, 0xF6, 0xF7]), !0xDDF2);
+    // An odd length is padded
+    assert_eq!(internet_checksum(&[0x01]), !0x0100);
+
+    // Finishing a checksum makes the covered part sum to zero
+    let mut frame = [0u8; 20];
+    frame[10..].copy_from_slice(&[0x45, 0x00, 0x00, 0x1C, 0x12, 0x34, 0x00, 0x00, 0x40, 0x11]);
+    finish_checksum(&mut frame, PartialChecksum { start: 10, offset: 6 });
+    assert_ne!(&frame[16..18], &[0, 0]);
+    assert_eq!(internet_checksum(&frame[10..]), 0);
+    assert_eq!(format!("{}", MacAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56])), "52:54:00:12:34:56");
+}
@@ -0,0 +1,155 @@
+//! # Per-CPU data
+//!
+//! Some state should exist once per core rather than once per kernel - run queues, statistics, interrupt
+//! nesting counters and so on. Keeping them per core means no locks (each core only touches its own copy)
+//! and no cache lines bouncing between cores.
+//!
+//! Every core gets a small [CpuArea](struct.CpuArea.html), and the GS base MSR of each core points at its own
+//! area. So `gs:[8]` always holds the index of the core we are running on, and finding it is a single `mov`.
+//!
+//! Declare per-CPU variables with the [percpu](../macro.percpu.html) macro:
+//!
+//! ```
+//! percpu! {
+//!     /// Timer ticks seen by each core
+//!     pub static TICKS: AtomicU64 = AtomicU64::new(0);
+//! }
+//!
+//! TICKS.get().fetch_add(1, Ordering::Relaxed); // only touches this core's counter
+//! ```
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use x86_64::registers::model_specific::Msr;
+use crate::smp::MAX_CPUS;
+
+/// The IA32_GS_BASE MSR - the base address used for `gs:` memory accesses
+const IA32_GS_BASE_MSR: u32 = 0xC000_0101;
+
+/// # CpuArea
+///
+/// The block each core's GS base points at. The layout is fixed (`repr(C)`), as we read the fields with
+/// `gs:`-relative assembly
+#[repr(C)]
+pub struct CpuArea {
+    /// Address of this area, at `gs:[0]`
+    self_ptr: AtomicU64,
+    /// The index of the core this area belongs to, at `gs:[8]`
+    cpu_id: AtomicUsize,
+}
+
+/// Initial value for `AREAS`. A named const lets us use it in an array repeat expression
+const AREA_INIT: CpuArea = CpuArea {
+    self_ptr: AtomicU64::new(0),
+    cpu_id: AtomicUsize::new(0),
+};
+
+/// One area per core. These are statics (not heap allocated) so the BSP can set its area up before the heap exists
+static AREAS: [CpuArea; MAX_CPUS] = [AREA_INIT; MAX_CPUS];
+
+/// Set once the BSP has called [init](fn.init.html). Until then GS base is 0, so we must not read through it
+static BSP_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// # init
+///
+/// Point the calling core's GS base at the area for `cpu`. Every core must call this before anything asks
+/// which core it is on - the BSP does it in `dbos::init`, and each AP first thing in `smp::ap_entry`.
+pub fn init(cpu: usize) {
+    assert!(cpu < MAX_CPUS, "CPU index {} is larger than MAX_CPUS", cpu);
+
+    let area = &AREAS[cpu];
+    let addr = area as *const CpuArea as u64;
+    area.self_ptr.store(addr, Ordering::Relaxed);
+    area.cpu_id.store(cpu, Ordering::Relaxed);
+
+    unsafe { Msr::new(IA32_GS_BASE_MSR).write(addr) };
+
+    if cpu == 0 {
+        BSP_INITIALIZED.store(true, Ordering::Release);
+    }
+}
+
+/// The index (`0..MAX_CPUS`) of the core we are running on. A single `gs:`-relative load
+pub fn current_cpu_id() -> usize {
+    if !BSP_INITIALIZED.load(Ordering::Acquire) {
+        return 0; // GS isn't set up yet, and only the BSP is running
+    }
+
+    let cpu: usize;
+    unsafe {
+        asm!("mov {}, gs:[8]", out(reg) cpu, options(nostack, preserves_flags, readonly));
+    }
+    cpu
+}
+
+/// # PerCpu
+///
+/// One `T` per core. Declare these with the [percpu](../macro.percpu.html) macro rather than directly.
+///
+/// Each core should only change its own copy. Use [get](#method.get) for types that are already safe to share
+/// (like atomics), or [with_mut](#method.with_mut) for plain data.
+pub struct PerCpu<T> {
+    slots: [UnsafeCell<T>; MAX_CPUS],
+}
+
+// Each core only gets mutable access to its own slot, with interrupts disabled (see `with_mut`)
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    /// Create a new per-CPU variable from one initial value per core. Used by the `percpu` macro
+    pub const fn new(slots: [UnsafeCell<T>; MAX_CPUS]) -> Self {
+        PerCpu { slots }
+    }
+
+    /// Run `f` with mutable access to this core's copy. Interrupts are disabled while `f` runs, so an
+    /// interrupt handler on this core can't observe a half-finished update. Don't call `with_mut` on the same
+    /// variable from inside `f`.
+    pub fn with_mut<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        use x86_64::instructions::interrupts;
+
+        interrupts::without_interrupts(|| {
+            let slot = self.slots[current_cpu_id()].get();
+            f(unsafe { &mut *slot })
+        })
+    }
+
+    /// A raw pointer to `cpu`'s copy. Used when setting up per-core hardware structures (like the TSS)
+    /// that need a `'static` address. The caller must make sure nothing else is using that copy.
+    pub fn as_mut_ptr_for(&self, cpu: usize) -> *mut T {
+        self.slots[cpu].get()
+    }
+}
+
+impl<T: Sync> PerCpu<T> {
+    /// This core's copy
+    pub fn get(&self) -> &T {
+        self.get_for(current_cpu_id())
+    }
+
+    /// Another core's copy, for reading statistics and the like
+    pub fn get_for(&self, cpu: usize) -> &T {
+        unsafe { &*self.slots[cpu].get() }
+    }
+}
+
+/// Declare one or more per-CPU variables. Each one becomes a [PerCpu](percpu/struct.PerCpu.html) with every
+/// core's copy starting at the given (constant) value.
+///
+/// ```
+/// percpu! {
+///     /// How deeply nested in interrupt handlers each core is
+///     pub static INTERRUPT_NESTING: AtomicUsize = AtomicUsize::new(0);
+/// }
+/// ```
+#[macro_export]
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::percpu::PerCpu<$ty> = {
+                const INIT: core::cell::UnsafeCell<$ty> = core::cell::UnsafeCell::new($init);
+                $crate::percpu::PerCpu::new([INIT; $crate::smp::MAX_CPUS])
+            };
+        )*
+    };
+}
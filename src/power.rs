@@ -0,0 +1,397 @@
+//! # Power
+//!
+//! Turning the machine off and restarting it.
+//!
+//! * [shutdown](fn.shutdown.html) puts the machine into the ACPI S5 ("soft off") sleep state, by writing
+//!   the S5 sleep type (the DSDT's `\_S5` package) to the FADT's PM1 control registers
+//! * [reboot](fn.reboot.html) writes to the FADT's reset register. If that doesn't work it pulses the reset
+//!   line through the 8042 keyboard controller, and if *that* doesn't work it triple faults the CPU
+//!
+//! [init](fn.init.html) also enables the power button, so pressing it (or `system_powerdown` in the QEMU
+//! monitor) shuts down cleanly rather than just cutting the power. The SCI handler only passes the press on -
+//! [handle_power_button](fn.handle_power_button.html) is the task that shuts down.
+
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+use crate::acpi::{self, fadt, Fadt, GenericAddress};
+use crate::driver::pci::config::{self, PciAddress};
+use crate::driver::registry;
+use crate::interrupts;
+use crate::task::channel::mpsc;
+use crate::{memory, serial_println, smp};
+
+/// `SCI_EN` in PM1 control - set once the machine is in ACPI mode (rather than legacy/SMM mode)
+const PM1_CNT_SCI_EN: u16 = 1 << 0;
+/// `SLP_TYP` in PM1 control - the sleep state to enter
+const PM1_CNT_SLP_TYP_SHIFT: u16 = 10;
+const PM1_CNT_SLP_TYP_MASK: u16 = 0b111 << PM1_CNT_SLP_TYP_SHIFT;
+/// `SLP_EN` in PM1 control - writing this enters the sleep state in `SLP_TYP`
+const PM1_CNT_SLP_EN: u16 = 1 << 13;
+/// `PWRBTN_STS`/`PWRBTN_EN` in PM1 status/enable - the power button was pressed
+const PM1_PWRBTN: u16 = 1 << 8;
+
+/// The ISA IRQ we have an IDT entry for (`InterruptIndex::AcpiSci`). The SCI is IRQ 9 on every PC we know of
+pub const SCI_IRQ: u8 = 9;
+
+/// Set once `init` has enabled the power button, so the SCI handler knows the event registers are ours
+static SCI_ENABLED: AtomicBool = AtomicBool::new(false);
+/// The S5 sleep types, found by `init`, so shutting down doesn't have to evaluate AML
+static S5_SLEEP_TYPES: OnceCell<(u8, u8)> = OnceCell::uninit();
+/// How the SCI handler tells `handle_power_button` the button was pressed. Set by that task when it starts
+static POWER_BUTTON_SENDER: OnceCell<mpsc::IrqSender<()>> = OnceCell::uninit();
+
+/// # init
+///
+/// Switch the machine into ACPI mode and enable the power button event. Call this after `acpi::init`.
+pub fn init() {
+    let fadt = match acpi::fadt() {
+        Some(fadt) => fadt,
+        None => {
+            serial_println!("[LOG] No FADT, the power button won't work");
+            return;
+        }
+    };
+
+    if s5_sleep_types().is_none() {
+        serial_println!("[LOG] No \\_S5 package in the DSDT, shutting down won't power off");
+    }
+
+    if !enable_acpi_mode(fadt) {
+        serial_println!("[LOG] The firmware didn't switch to ACPI mode, the power button won't work");
+        return;
+    }
+
+    if fadt.sci_interrupt != SCI_IRQ as u16 {
+        serial_println!("[LOG] The SCI is on IRQ {}, but we only handle IRQ {}", fadt.sci_interrupt, SCI_IRQ);
+        return;
+    }
+
+    // Clear anything already pending (status bits are write 1 to clear), then enable the power button
+    for &block in [fadt.pm1a_event_block, fadt.pm1b_event_block].iter().filter(|&&block| block != 0) {
+        let (mut status, mut enable) = pm1_event_ports(fadt, block);
+        unsafe {
+            let pending = status.read();
+            status.write(pending);
+            let enabled = enable.read();
+            enable.write(enabled | PM1_PWRBTN);
+        }
+    }
+
+    SCI_ENABLED.store(true, Ordering::Release);
+    interrupts::unmask_pic_irq(SCI_IRQ);
+    serial_println!("[LOG] ACPI power button enabled");
+}
+
+/// # handle_sci
+///
+/// Called by the SCI interrupt handler. Acknowledges every PM1 event, and if the power button was pressed,
+/// wakes [handle_power_button](fn.handle_power_button.html). Suspending the devices takes their locks, so
+/// we can't shut down from here
+pub fn handle_sci() {
+    if !SCI_ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+    let fadt = match acpi::fadt() {
+        Some(fadt) => fadt,
+        None => return,
+    };
+
+    let mut power_button = false;
+    for &block in [fadt.pm1a_event_block, fadt.pm1b_event_block].iter().filter(|&&block| block != 0) {
+        let (mut status, _) = pm1_event_ports(fadt, block);
+        unsafe {
+            let pending = status.read();
+            status.write(pending); // acknowledge, or the (level triggered) SCI fires again straight away
+            power_button |= pending & PM1_PWRBTN != 0;
+        }
+    }
+
+    if power_button {
+        // Before the task has started, or with a press already queued, there's nothing more to do
+        if let Ok(sender) = POWER_BUTTON_SENDER.try_get() {
+            let _ = sender.try_send(());
+        }
+    }
+}
+
+/// # handle_power_button
+///
+/// The task that shuts down when the power button is pressed. Spawn it once, on any executor
+pub async fn handle_power_button() {
+    let (sender, mut receiver) = mpsc::channel(1);
+    POWER_BUTTON_SENDER.try_init_once(|| sender.irq_sender())
+        .expect("handle_power_button should only be spawned once");
+
+    if receiver.recv().await.is_some() {
+        serial_println!("[LOG] Power button pressed");
+        shutdown();
+    }
+}
+
+/// # shutdown
+///
+/// Suspend every device, then power the machine off through ACPI. If that isn't possible, halt forever with
+/// interrupts disabled. Drivers take locks to suspend, so don't call this from an interrupt handler.
+pub fn shutdown() -> ! {
+    // Stop the devices first, so disks have flushed their caches and nothing is doing DMA when the power goes
+    registry::suspend_all();
+
+    x86_64::instructions::interrupts::disable();
+    serial_println!("[LOG] Shutting down");
+
+    if let Some(fadt) = acpi::fadt() {
+        match s5_sleep_types() {
+            Some((slp_typa, slp_typb)) => {
+                enable_acpi_mode(fadt);
+                enter_sleep_state(fadt.pm1a_control_block, slp_typa);
+                if fadt.pm1b_control_block != 0 {
+                    enter_sleep_state(fadt.pm1b_control_block, slp_typb);
+                }
+                // Powering off isn't instant
+                for _ in 0..100 {
+                    smp::delay_us(10_000);
+                }
+                serial_println!("[LOG] ACPI shutdown failed");
+            }
+            None => serial_println!("[LOG] No \\_S5 package in the DSDT, can't shut down through ACPI"),
+        }
+    }
+
+    serial_println!("[LOG] It is now safe to turn off your computer");
+    crate::hlt_loop();
+}
+
+/// # reboot
+///
+/// Restart the machine - through the FADT reset register, then the 8042 keyboard controller, and if all
+/// else fails, by triple faulting.
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+    serial_println!("[LOG] Rebooting");
+
+    if let Some((register, value)) = acpi::fadt().and_then(|fadt| fadt.reset_register) {
+        write_reset_register(&register, value);
+        smp::delay_us(50_000);
+        serial_println!("[LOG] ACPI reset register didn't reset, trying the 8042");
+    }
+
+    if acpi::fadt().map_or(true, |fadt| fadt.has_8042()) {
+        pulse_8042_reset();
+        smp::delay_us(50_000);
+        serial_println!("[LOG] 8042 reset didn't reset, triple faulting");
+    }
+
+    triple_fault();
+}
+
+/// The S5 sleep type values (for PM1a and PM1b control), from the `\_S5` package. Evaluated once and
+/// then cached
+fn s5_sleep_types() -> Option<(u8, u8)> {
+    if let Ok(&sleep_types) = S5_SLEEP_TYPES.try_get() {
+        return Some(sleep_types);
+    }
+
+    let sleep_types = evaluate_s5().or_else(scan_dsdt_for_s5)?;
+    let _ = S5_SLEEP_TYPES.try_init_once(|| sleep_types);
+    Some(sleep_types)
+}
+
+/// Evaluate `\_S5` in the AML namespace. Its first two elements are the values for PM1a and PM1b
+fn evaluate_s5() -> Option<(u8, u8)> {
+    let s5 = acpi::aml::evaluate("\\_S5", Vec::new()).ok()?;
+    let elements = s5.as_package().ok()?;
+    let slp_typa = elements.first()?.as_integer().ok()?;
+    let slp_typb = elements.get(1).map_or(Ok(0), |element| element.as_integer()).ok()?;
+    Some((slp_typa as u8, slp_typb as u8))
+}
+
+/// Find `\_S5` by scanning the DSDT's bytecode, in case the interpreter couldn't load it. It's almost
+/// always a plain package like `Name (_S5, Package () { 0x05, 0x05, 0, 0 })`
+fn scan_dsdt_for_s5() -> Option<(u8, u8)> {
+    find_s5_package(acpi::dsdt()?.aml())
+}
+
+/// Find `Name (_S5, Package () { ... })` in `aml`, and decode the first two elements
+fn find_s5_package(aml: &[u8]) -> Option<(u8, u8)> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+
+    let name = aml.windows(4).position(|window| window == b"_S5_")?;
+
+    // It must be a named object - `NameOp _S5_`, or `NameOp \_S5_`
+    let is_name = (name >= 1 && aml[name - 1] == NAME_OP)
+        || (name >= 2 && aml[name - 2] == NAME_OP && aml[name - 1] == b'\\');
+    if !is_name || *aml.get(name + 4)? != PACKAGE_OP {
+        return None;
+    }
+
+    // Skip the package length (the top two bits of its first byte say how many more bytes it has),
+    // and the element count
+    let mut offset = name + 5;
+    offset += 1 + (*aml.get(offset)? >> 6) as usize;
+    offset += 1;
+
+    let slp_typa = aml_byte(aml, &mut offset)?;
+    let slp_typb = aml_byte(aml, &mut offset)?;
+    Some((slp_typa, slp_typb))
+}
+
+/// Read a small AML integer - `ZeroOp`, `OneOp`, or `BytePrefix` and a byte
+fn aml_byte(aml: &[u8], offset: &mut usize) -> Option<u8> {
+    const ZERO_OP: u8 = 0x00;
+    const ONE_OP: u8 = 0x01;
+    const BYTE_PREFIX: u8 = 0x0A;
+
+    match *aml.get(*offset)? {
+        ZERO_OP => {
+            *offset += 1;
+            Some(0)
+        }
+        ONE_OP => {
+            *offset += 1;
+            Some(1)
+        }
+        BYTE_PREFIX => {
+            let value = *aml.get(*offset + 1)?;
+            *offset += 2;
+            Some(value)
+        }
+        _ => None,
+    }
+}
+
+/// Ask the firmware to switch to ACPI mode, if it isn't already. Returns whether we're in ACPI mode
+fn enable_acpi_mode(fadt: &Fadt) -> bool {
+    let mut control: Port<u16> = Port::new(fadt.pm1a_control_block as u16);
+    if unsafe { control.read() } & PM1_CNT_SCI_EN != 0 {
+        return true;
+    }
+    if fadt.smi_command_port == 0 || fadt.acpi_enable == 0 {
+        return false; // no way to switch, hardware reduced or ACPI only machine
+    }
+
+    unsafe { Port::<u8>::new(fadt.smi_command_port as u16).write(fadt.acpi_enable) };
+    // The spec allows the firmware up to 3 seconds
+    for _ in 0..300 {
+        if unsafe { control.read() } & PM1_CNT_SCI_EN != 0 {
+            return true;
+        }
+        smp::delay_us(10_000);
+    }
+    false
+}
+
+/// The PM1 status and enable registers of an event block. Each is half of the block
+fn pm1_event_ports(fadt: &Fadt, block: u32) -> (Port<u16>, Port<u16>) {
+    let (status, enable) = pm1_event_registers(block, fadt.pm1_event_length);
+    (Port::new(status), Port::new(enable))
+}
+
+/// The port numbers of the PM1 status and enable registers, in an event block `length` bytes long
+fn pm1_event_registers(block: u32, length: u8) -> (u16, u16) {
+    let half = (length / 2) as u32;
+    (block as u16, (block + half) as u16)
+}
+
+/// Write `SLP_TYP` and `SLP_EN` to a PM1 control register
+fn enter_sleep_state(control_block: u32, sleep_type: u8) {
+    let mut control: Port<u16> = Port::new(control_block as u16);
+    unsafe {
+        let value = sleep_control_value(control.read(), sleep_type);
+        control.write(value);
+    }
+}
+
+/// The PM1 control value that enters `sleep_type`, keeping the other bits of `control` as they are
+fn sleep_control_value(control: u16, sleep_type: u8) -> u16 {
+    let sleep_type = ((sleep_type as u16) << PM1_CNT_SLP_TYP_SHIFT) & PM1_CNT_SLP_TYP_MASK;
+    (control & !PM1_CNT_SLP_TYP_MASK) | sleep_type | PM1_CNT_SLP_EN
+}
+
+/// Write `value` to the FADT reset register, wherever it lives
+fn write_reset_register(register: &GenericAddress, value: u8) {
+    let address = register.address;
+    match register.address_space {
+        fadt::ADDRESS_SPACE_IO => unsafe { Port::<u8>::new(address as u16).write(value) },
+        fadt::ADDRESS_SPACE_MEMORY => unsafe {
+            let pointer = memory::phys_to_virt(PhysAddr::new(address)).as_mut_ptr::<u8>();
+            core::ptr::write_volatile(pointer, value);
+        },
+        fadt::ADDRESS_SPACE_PCI_CONFIG => {
+            // Bus 0, with the device, function and offset packed into the address
+            let device = (address >> 32) as u8;
+            let function = (address >> 16) as u8;
+            config::write_u8(PciAddress::new(0, 0, device, function), address as u16, value);
+        }
+        space => serial_println!("[LOG] Reset register is in address space {}, which we don't support", space),
+    }
+}
+
+/// Ask the 8042 keyboard controller to pulse the CPU reset line
+fn pulse_8042_reset() {
+    let mut status: Port<u8> = Port::new(0x64);
+    let mut command: Port<u8> = Port::new(0x64);
+    unsafe {
+        // Wait for its input buffer to be empty, so it'll accept a command
+        for _ in 0..100_000 {
+            if status.read() & 0b10 == 0 {
+                break;
+            }
+        }
+        command.write(0xFE);
+    }
+}
+
+/// Load an empty IDT and raise an exception. The CPU can't find a handler, can't find a double fault
+/// handler either, and resets
+fn triple_fault() -> ! {
+    use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
+
+    let empty = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe {
+        lidt(&empty);
+    }
+    x86_64::instructions::interrupts::int3();
+    crate::hlt_loop();
+}
+
+/* Testing */
+
+// SLP_TYP replaces bits 10-12, SLP_EN is set, and everything else (like SCI_EN) is left alone
+#[test_case]
+fn sleep_control_values() {
+    assert_eq!(sleep_control_value(PM1_CNT_SCI_EN, 5), PM1_CNT_SCI_EN | 5 << 10 | PM1_CNT_SLP_EN);
+    assert_eq!(sleep_control_value(PM1_CNT_SCI_EN | 0b111 << 10, 0), PM1_CNT_SCI_EN | PM1_CNT_SLP_EN);
+    assert_eq!(sleep_control_value(0, 7), 7 << 10 | PM1_CNT_SLP_EN);
+    // Only three bits of sleep type fit
+    assert_eq!(sleep_control_value(0, 0xFF), 7 << 10 | PM1_CNT_SLP_EN);
+}
+
+// The status register is the first half of the event block, the enable register the second
+#[test_case]
+fn pm1_event_register_ports() {
+    assert_eq!(pm1_event_registers(0x600, 4), (0x600, 0x602));
+    assert_eq!(pm1_event_registers(0xB000, 8), (0xB000, 0xB004));
+}
+
+// \_S5 packages as iasl writes them - with ZeroOp/OneOp or BytePrefix elements, and with or without the root
+#[test_case]
+fn s5_packages() {
+    // Name (_S5, Package (0x04) { 0x05, 0x05, Zero, Zero })
+    let bytes = [0x08, b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04, 0x0A, 0x05, 0x0A, 0x05, 0x00, 0x00];
+    assert_eq!(find_s5_package(&bytes), Some((5, 5)));
+    // Name (\_S5, Package (0x02) { Zero, One })
+    let rooted = [0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x04, 0x02, 0x00, 0x01];
+    assert_eq!(find_s5_package(&rooted), Some((0, 1)));
+
+    // Not a named object, or not a package
+    let method = [0x14, 0x06, b'_', b'S', b'5', b'_', 0x00];
+    assert_eq!(find_s5_package(&method), None);
+    let integer = [0x08, b'_', b'S', b'5', b'_', 0x0A, 0x05];
+    assert_eq!(find_s5_package(&integer), None);
+    // Cut short
+    assert_eq!(find_s5_package(&bytes[..10]), None);
+}
@@ -1,28 +1,61 @@
 use uart_16550::SerialPort;
-use spin::Mutex;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::lazy_static;
+use crate::sync::IrqSpinlock;
 
 
 
 lazy_static! {
     /// Similar to what we did with the vga buffer, except we define a serial port through the uart 16550 crate.
     /// UART - the chips implementing the serial interface. 16550 is compatible with *most* systems
-    /// We wrap it in a lazy_static and mutex so we can access the SERIAL1 variable wherever, safely. 
+    /// We wrap it in a lazy_static and lock so we can access the SERIAL1 variable wherever, safely. Interrupt
+    /// handlers print too, so the lock is an [IrqSpinlock](../sync/irq_spinlock/struct.IrqSpinlock.html).
     /// 
     /// Write to the serial port using the macros - [serial_print](../macro.serial_print.html) & [serial_println](../macro.serial_println.html)
-    pub static ref SERIAL1: Mutex<SerialPort> = {
+    pub static ref SERIAL1: IrqSpinlock<SerialPort> = {
         let mut serial_port = unsafe { SerialPort::new(0x3F8) }; // Standard first port for serial
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqSpinlock::new(serial_port)
     };
 }
 
 
+/// Where printed text is copied to, as well as the serial port (like a virtio console's log port). The address
+/// of a `fn(&str)`, or 0 for none - interrupt handlers print too, so reading it mustn't take a lock
+static MIRROR: AtomicUsize = AtomicUsize::new(0);
+
+/// Copy everything printed to serial to `mirror` as well, or stop copying with `None`. The mirror is called
+/// wherever printing happens, so it mustn't wait for anything
+pub fn set_mirror(mirror: Option<fn(&str)>) {
+    MIRROR.store(mirror.map_or(0, |mirror| mirror as usize), Ordering::Release);
+}
+
+fn mirror() -> Option<fn(&str)> {
+    match MIRROR.load(Ordering::Acquire) {
+        0 => None,
+        // Only `set_mirror` stores here, and only `fn(&str)`s
+        address => Some(unsafe { core::mem::transmute::<usize, fn(&str)>(address) }),
+    }
+}
+
+/// Hands formatted text to a mirror
+struct MirrorWriter(fn(&str));
+
+impl core::fmt::Write for MirrorWriter {
+    fn write_str(&mut self, text: &str) -> core::fmt::Result {
+        (self.0)(text);
+        Ok(())
+    }
+}
+
 // Similar to the print macros in vga_buffer, except this works through serial.
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
     SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
+    if let Some(mirror) = mirror() {
+        let _ = MirrorWriter(mirror).write_fmt(args);
+    }
 }
 
 // function to read from serial port
@@ -0,0 +1,349 @@
+//! # SMP
+//!
+//! Symmetric multiprocessing - starting the other CPU cores (application processors, or APs), and
+//! helpers for code that needs to know which core it is running on.
+//!
+//! The bootstrap processor (BSP, the core the bootloader ran on) finds the other cores in the ACPI MADT,
+//! copies a small real mode trampoline (`smp/trampoline.s`) into low memory, and wakes each AP with the
+//! INIT-SIPI-SIPI sequence. Each AP climbs from real mode into long mode on the BSP's page table, gets its
+//! own stack, per-CPU area, GDT and TSS, loads the IDT and then parks itself in the SMP executor, ready to run tasks.
+//!
+//! APs are started one at a time, as they share the trampoline's parameters. An AP that doesn't come online in
+//! time is stopped with another INIT and taken back out of the CPU table.
+
+use core::sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use core::ptr;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use crate::{acpi, apic, gdt, interrupts, memory, percpu, serial_println};
+use crate::task::smp_executor::SMP_EXECUTOR;
+
+/// The most CPU cores we support. Per-core structures (like the executor run queues) are sized by this
+pub const MAX_CPUS: usize = 16;
+
+/// Physical address we copy the trampoline to. APs start in real mode, so it must be below 1MiB and page aligned
+const TRAMPOLINE_BASE: u64 = 0x8000;
+
+/// Where we map the AP kernel stacks. Each AP we try to start gets a new `STACK_SLOT_SIZE` slot laid out as
+/// `[guard page][kernel stack]` - the unmapped guard page turns a stack overflow into a page fault
+/// instead of silent corruption. Slots are never reused, so an AP that starts late can't share its stack with
+/// the next one. (The interrupt stacks live in gdt.rs)
+const AP_STACKS_START: u64 = 0x_5555_0000_0000;
+const PAGE_SIZE: u64 = 4096;
+const KERNEL_STACK_SIZE: u64 = 16 * PAGE_SIZE; // 64 KiB
+const STACK_SLOT_SIZE: u64 = PAGE_SIZE + KERNEL_STACK_SIZE;
+
+/// How long we give an AP to reach `ap_entry` before giving up on it
+const AP_START_TIMEOUT_MS: u64 = 100;
+
+// The trampoline lives in its own assembly file, as it has to be written for real, protected and long mode.
+global_asm!(include_str!("smp/trampoline.s"));
+
+// Labels exported from the trampoline. We only ever take their addresses
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_cpu: u8;
+    static ap_trampoline_ack: u8;
+}
+
+/* CPU states */
+/// Being started by the BSP
+const CPU_STARTING: u8 = 0;
+/// Up and running
+const CPU_ONLINE: u8 = 1;
+/// The BSP gave up on it. If it gets as far as `ap_entry` anyway, it halts there
+const CPU_FAILED: u8 = 2;
+
+/// # CpuInfo
+///
+/// What we know about each core. The index into `CPUS` is the CPU index used everywhere else in the kernel
+struct CpuInfo {
+    apic_id: AtomicU32,
+    /// One of the `CPU_*` states. Moved to `CPU_ONLINE` by the core itself once it is up and running
+    state: AtomicU8,
+}
+
+/// Initial value for `CPUS`. A named const lets us use it in an array repeat expression
+const CPU_INFO_INIT: CpuInfo = CpuInfo {
+    apic_id: AtomicU32::new(0),
+    state: AtomicU8::new(CPU_STARTING),
+};
+
+/// Every core we know about. Entries `0..CPU_COUNT` are valid, and entry 0 is always the BSP
+static CPUS: [CpuInfo; MAX_CPUS] = [CPU_INFO_INIT; MAX_CPUS];
+/// Number of valid entries in `CPUS`
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// The index (`0..MAX_CPUS`) of the CPU we're running on. Read from the per-CPU area (see percpu.rs)
+pub fn current_cpu() -> usize {
+    percpu::current_cpu_id()
+}
+
+/// The local APIC id of the CPU with index `cpu`, so we can send it IPIs
+pub fn apic_id(cpu: usize) -> u32 {
+    if cpu < CPU_COUNT.load(Ordering::Acquire) {
+        CPUS[cpu].apic_id.load(Ordering::Relaxed)
+    } else {
+        cpu as u32
+    }
+}
+
+/// The number of cores that are up and running (including the BSP)
+pub fn cpu_count() -> usize {
+    let count = CPU_COUNT.load(Ordering::Acquire);
+    (0..count).filter(|&cpu| is_online(cpu)).count()
+}
+
+/// Check whether the CPU with index `cpu` has finished starting up
+pub fn is_online(cpu: usize) -> bool {
+    cpu < CPU_COUNT.load(Ordering::Acquire) && CPUS[cpu].state.load(Ordering::Acquire) == CPU_ONLINE
+}
+
+/// Add a core to the CPU table, returning its index
+fn register_cpu(apic_id: u32) -> usize {
+    let cpu = CPU_COUNT.load(Ordering::Relaxed);
+    CPUS[cpu].apic_id.store(apic_id, Ordering::Relaxed);
+    CPUS[cpu].state.store(CPU_STARTING, Ordering::Relaxed);
+    CPU_COUNT.store(cpu + 1, Ordering::Release);
+    cpu
+}
+
+/// Take a core that failed to start back out of the CPU table. It must be the last one registered, and stopped
+fn unregister_cpu(cpu: usize) {
+    assert_eq!(cpu + 1, CPU_COUNT.load(Ordering::Relaxed), "only the last CPU registered can be unregistered");
+    CPU_COUNT.store(cpu, Ordering::Release);
+}
+
+/// # init
+///
+/// Start every application processor listed in the ACPI MADT. Call this on the BSP, after the heap and the
+/// local APIC are initialized, and after `acpi::init`. The kernel page table is used to identity map the trampoline and
+/// to map each AP's stacks - but isn't held while an AP starts, as it may need to map things itself.
+pub fn init() {
+    // The BSP is always CPU 0
+    let bsp_apic_id = apic::id();
+    let bsp = register_cpu(bsp_apic_id);
+    CPUS[bsp].state.store(CPU_ONLINE, Ordering::Release);
+
+    let processors = acpi::processors();
+    if processors.is_empty() {
+        serial_println!("[LOG] No ACPI MADT found, running on the bootstrap processor only");
+        return;
+    }
+
+    memory::with_mapper(|mapper, frame_allocator| install_trampoline(mapper, frame_allocator));
+
+    // We drive the local APIC in xAPIC mode, which can only address APIC ids up to 255
+    let aps = processors.iter().filter(|p| p.enabled && p.apic_id != bsp_apic_id && p.apic_id <= 0xFF);
+    for (slot, processor) in aps.enumerate() {
+        if CPU_COUNT.load(Ordering::Relaxed) >= MAX_CPUS {
+            serial_println!("[LOG] More than {} CPUs, ignoring the rest", MAX_CPUS);
+            break;
+        }
+
+        if let Err(err) = memory::with_mapper(|mapper, frame_allocator| map_stack(slot, mapper, frame_allocator)) {
+            serial_println!("[LOG] Failed to map the stack for APIC {}: {:?}", processor.apic_id, err);
+            break;
+        }
+        let cpu = register_cpu(processor.apic_id);
+        match start_ap(cpu, processor.apic_id, kernel_stack_top(slot)) {
+            Ok(()) => {}
+            Err(StartError::Late) => {
+                serial_println!("[LOG] CPU {} (APIC {}) didn't start", cpu, processor.apic_id);
+                unregister_cpu(cpu);
+            }
+            Err(StartError::NoResponse) => {
+                // It never picked up its parameters, so it might still do - and take the next AP's. Leave the
+                // trampoline to it, and run with the CPUs we have
+                serial_println!("[LOG] CPU {} (APIC {}) never answered, not starting any more CPUs", cpu, processor.apic_id);
+                unregister_cpu(cpu);
+                break;
+            }
+        }
+    }
+
+    serial_println!("[LOG] {} CPUs online", cpu_count());
+}
+
+/// Copy the trampoline to `TRAMPOLINE_BASE` and identity map it. Paging is switched on while the AP is
+/// running from this page, so it has to be mapped at the same virtual address as its physical one.
+fn install_trampoline(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) {
+    let (start, end) = unsafe { (&ap_trampoline_start as *const u8, &ap_trampoline_end as *const u8) };
+    let size = end as usize - start as usize;
+    assert!(size as u64 <= PAGE_SIZE, "AP trampoline doesn't fit in a page");
+
+    let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(TRAMPOLINE_BASE));
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(TRAMPOLINE_BASE));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => flush.flush(),
+        Err(MapToError::PageAlreadyMapped(_)) => {} // the bootloader already identity mapped low memory
+        Err(err) => panic!("failed to identity map the AP trampoline: {:?}", err),
+    }
+
+    // Copy through the physical memory mapping
+    let destination = memory::phys_to_virt(PhysAddr::new(TRAMPOLINE_BASE)).as_mut_ptr::<u8>();
+    unsafe { ptr::copy_nonoverlapping(start, destination, size) };
+}
+
+/// Where one of the trampoline's parameters is. `symbol` is the trampoline label for it
+fn trampoline_parameter(symbol: &u8) -> *mut u64 {
+    let offset = symbol as *const u8 as u64 - unsafe { &ap_trampoline_start as *const u8 as u64 };
+    memory::phys_to_virt(PhysAddr::new(TRAMPOLINE_BASE + offset)).as_mut_ptr::<u64>()
+}
+
+/// Write one of the trampoline's parameters
+fn write_trampoline_parameter(symbol: &u8, value: u64) {
+    unsafe { ptr::write_volatile(trampoline_parameter(symbol), value) };
+}
+
+/// Read one of the trampoline's parameters
+fn read_trampoline_parameter(symbol: &u8) -> u64 {
+    unsafe { ptr::read_volatile(trampoline_parameter(symbol)) }
+}
+
+/// The start of stack slot `slot` (the first guard page)
+fn stack_slot(slot: usize) -> u64 {
+    AP_STACKS_START + slot as u64 * STACK_SLOT_SIZE
+}
+
+/// The top of the kernel stack in `slot` (stacks grow down)
+fn kernel_stack_top(slot: usize) -> VirtAddr {
+    VirtAddr::new(stack_slot(slot) + STACK_SLOT_SIZE)
+}
+
+/// Map the kernel stack in `slot`, leaving the guard page unmapped
+fn map_stack(
+    slot: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let stack_start = kernel_stack_top(slot) - KERNEL_STACK_SIZE;
+    let first = Page::<Size4KiB>::containing_address(stack_start);
+    let last = Page::<Size4KiB>::containing_address(kernel_stack_top(slot) - 1u64);
+
+    for page in Page::range_inclusive(first, last) {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+
+    Ok(())
+}
+
+/// Why an AP didn't come online
+enum StartError {
+    /// It picked up its parameters, but didn't make it in time
+    Late,
+    /// It never picked up its parameters
+    NoResponse,
+}
+
+/// Start the AP with index `cpu` on the stack ending at `stack_top`, and wait for it to come online. If it
+/// doesn't, it is sent an INIT to stop it wherever it got to.
+fn start_ap(cpu: usize, apic_id: u32, stack_top: VirtAddr) -> Result<(), StartError> {
+    use x86_64::registers::control::Cr3;
+
+    // The AP loads CR3 while still in 32 bit mode, so the page table has to be in the first 4GiB
+    let (level_4_table, _) = Cr3::read();
+    let cr3 = level_4_table.start_address().as_u64();
+    assert!(cr3 < 0x1_0000_0000, "page table is above 4GiB, APs can't load it");
+
+    unsafe {
+        write_trampoline_parameter(&ap_trampoline_cr3, cr3);
+        write_trampoline_parameter(&ap_trampoline_stack, stack_top.as_u64());
+        write_trampoline_parameter(&ap_trampoline_entry, ap_entry as usize as u64);
+        write_trampoline_parameter(&ap_trampoline_cpu, cpu as u64);
+        write_trampoline_parameter(&ap_trampoline_ack, 0);
+    }
+
+    // INIT-SIPI-SIPI, with the delays the Intel manual asks for. The second SIPI is only needed
+    // if the first one was missed
+    apic::send_init(apic_id);
+    delay_us(10_000);
+    for _ in 0..2 {
+        apic::send_startup(apic_id, (TRAMPOLINE_BASE >> 12) as u8);
+        delay_us(200);
+        if is_online(cpu) {
+            break;
+        }
+    }
+
+    for _ in 0..AP_START_TIMEOUT_MS {
+        if is_online(cpu) {
+            return Ok(());
+        }
+        delay_us(1000);
+    }
+
+    // Give up on it - unless it came online just now
+    if CPUS[cpu].state.compare_exchange(CPU_STARTING, CPU_FAILED, Ordering::AcqRel, Ordering::Acquire).is_err() {
+        return Ok(());
+    }
+    // INIT puts it back into wait-for-SIPI, wherever it got to
+    apic::send_init(apic_id);
+    delay_us(10_000);
+
+    if unsafe { read_trampoline_parameter(&ap_trampoline_ack) } != 0 {
+        Err(StartError::Late)
+    } else {
+        Err(StartError::NoResponse)
+    }
+}
+
+/// Where the trampoline jumps to, on the AP's own stack. Sets up the per-core tables, then parks
+/// the core in the SMP executor
+extern "C" fn ap_entry(cpu: usize) -> ! {
+    percpu::init(cpu); // Must come first - everything after may ask which core it is on
+    gdt::init_ap(cpu); // Our own GDT, TSS and interrupt stacks
+    interrupts::init_idt(); // The IDT is shared, but each core has to load it
+    apic::init(); // Enable our local APIC, so we can receive wake up IPIs
+
+    // The BSP is waiting on this. If it has already given up on us, our index may go to another core - stop here
+    if CPUS[cpu].state.compare_exchange(CPU_STARTING, CPU_ONLINE, Ordering::AcqRel, Ordering::Relaxed).is_err() {
+        loop {
+            x86_64::instructions::hlt(); // interrupts are still off, so this is for good
+        }
+    }
+    x86_64::instructions::interrupts::enable();
+
+    SMP_EXECUTOR.run() // Idle until there's work for us
+}
+
+/// Busy wait for `micros` microseconds using channel 2 of the PIT (the PC speaker channel), which runs at a
+/// known frequency. Good for at most ~54ms per call.
+pub(crate) fn delay_us(micros: u64) {
+    const PIT_FREQUENCY: u64 = 1_193_182;
+
+    let mut gate: Port<u8> = Port::new(0x61); // bit 0 = channel 2 gate, bit 1 = speaker, bit 5 = channel 2 output
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_2: Port<u8> = Port::new(0x42);
+    let ticks = (PIT_FREQUENCY * micros / 1_000_000).max(1).min(0xFFFF);
+
+    unsafe {
+        let value = gate.read() & !0b11; // gate off, speaker off
+        gate.write(value);
+        command.write(0b1011_0000); // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+        channel_2.write(ticks as u8);
+        channel_2.write((ticks >> 8) as u8);
+        gate.write(value | 1); // raising the gate starts the count
+
+        while gate.read() & (1 << 5) == 0 {
+            core::hint::spin_loop(); // output goes high once the count hits zero
+        }
+
+        gate.write(value);
+    }
+}
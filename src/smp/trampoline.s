@@ -0,0 +1,135 @@
+# Application processor trampoline
+#
+# Started APs begin executing here in 16 bit real mode, after `smp::init` has copied this code
+# to TRAMPOLINE_BASE (0x8000) in low memory. We step through protected mode into long mode, using
+# the bootstrap processor's page table, then call into Rust (`smp::ap_entry`).
+#
+# This code runs from the copy, not from where the linker put it, so every address is computed
+# as TRAMPOLINE_BASE + (label - ap_trampoline_start) (see the TRAMPOLINE_* symbols at the bottom).
+#
+# `smp::init` fills in the four quads at the end (page table, stack, entry point and cpu index)
+# before starting each AP, and clears the fifth. The AP sets it once it has read the other four, which
+# tells the BSP it can change them for the next AP.
+
+.intel_syntax noprefix
+.pushsection .text.ap_trampoline, "ax"
+
+.set TRAMPOLINE_BASE, 0x8000
+
+.global ap_trampoline_start
+.global ap_trampoline_end
+.global ap_trampoline_cr3
+.global ap_trampoline_stack
+.global ap_trampoline_entry
+.global ap_trampoline_cpu
+.global ap_trampoline_ack
+
+.code16
+ap_trampoline_start:
+    cli
+    cld
+    xor ax, ax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    # Load our temporary GDT and switch on protected mode
+    lgdt [TRAMPOLINE_GDT_PTR]
+    mov eax, cr0
+    or eax, 1                                   # CR0.PE
+    mov cr0, eax
+
+    # ljmp 0x08:protected_mode - hand assembled so we get a 32 bit offset from 16 bit code
+    .byte 0x66, 0xEA
+    .long TRAMPOLINE_BASE + (ap_trampoline_protected_mode - ap_trampoline_start)
+    .word 0x08
+
+.code32
+ap_trampoline_protected_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    # Enable PAE, which long mode needs
+    mov eax, cr4
+    or eax, 1 << 5                              # CR4.PAE
+    mov cr4, eax
+
+    # Use the same page table as the bootstrap processor (it must be below 4GiB)
+    mov eax, [TRAMPOLINE_CR3]
+    mov cr3, eax
+
+    # Enable long mode, and no-execute pages (the kernel's page table uses the NX bit)
+    mov ecx, 0xC0000080                         # IA32_EFER
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)                # EFER.LME | EFER.NXE
+    wrmsr
+
+    # Enable paging and write protection. This page is identity mapped, so we keep running
+    mov eax, cr0
+    or eax, (1 << 31) | (1 << 16)               # CR0.PG | CR0.WP
+    mov cr0, eax
+
+    # ljmp 0x18:long_mode
+    .byte 0xEA
+    .long TRAMPOLINE_BASE + (ap_trampoline_long_mode - ap_trampoline_start)
+    .word 0x18
+
+.code64
+ap_trampoline_long_mode:
+    xor ax, ax                                  # data segments are ignored in long mode
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov fs, ax
+    mov gs, ax
+
+    mov rsp, [TRAMPOLINE_STACK]
+    mov rdi, [TRAMPOLINE_CPU]
+    mov rax, [TRAMPOLINE_ENTRY]
+    mov qword ptr [TRAMPOLINE_ACK], 1           # stores aren't reordered before earlier loads
+    call rax                                    # ap_entry(cpu) never returns
+
+ap_trampoline_halt:
+    hlt
+    jmp ap_trampoline_halt
+
+# Null, 32 bit code, 32 bit data and 64 bit code segments
+.align 8
+ap_trampoline_gdt:
+    .quad 0
+    .quad 0x00CF9A000000FFFF
+    .quad 0x00CF92000000FFFF
+    .quad 0x00AF9A000000FFFF
+ap_trampoline_gdt_end:
+
+ap_trampoline_gdt_ptr:
+    .word ap_trampoline_gdt_end - ap_trampoline_gdt - 1
+    .long TRAMPOLINE_BASE + (ap_trampoline_gdt - ap_trampoline_start)
+
+# Filled in by smp::init
+.align 8
+ap_trampoline_cr3:
+    .quad 0
+ap_trampoline_stack:
+    .quad 0
+ap_trampoline_entry:
+    .quad 0
+ap_trampoline_cpu:
+    .quad 0
+ap_trampoline_ack:
+    .quad 0
+
+ap_trampoline_end:
+
+# Where the labels we read from end up once the code is copied to TRAMPOLINE_BASE
+.set TRAMPOLINE_GDT_PTR, TRAMPOLINE_BASE + (ap_trampoline_gdt_ptr - ap_trampoline_start)
+.set TRAMPOLINE_CR3, TRAMPOLINE_BASE + (ap_trampoline_cr3 - ap_trampoline_start)
+.set TRAMPOLINE_STACK, TRAMPOLINE_BASE + (ap_trampoline_stack - ap_trampoline_start)
+.set TRAMPOLINE_ENTRY, TRAMPOLINE_BASE + (ap_trampoline_entry - ap_trampoline_start)
+.set TRAMPOLINE_CPU, TRAMPOLINE_BASE + (ap_trampoline_cpu - ap_trampoline_start)
+.set TRAMPOLINE_ACK, TRAMPOLINE_BASE + (ap_trampoline_ack - ap_trampoline_start)
+
+.popsection
+.att_syntax prefix
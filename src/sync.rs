@@ -0,0 +1,8 @@
+pub mod irq_spinlock; // Spinlock that disables interrupts while held - for data shared with interrupt handlers
+pub mod ticket_lock; // Fair (first come, first served) spinlock
+pub mod async_mutex; // Lock that async tasks can hold across an await
+pub mod lockdep; // Lock debugging (owner tracking, recursion and lock order checks), enabled by the `lock-debug` feature
+
+pub use irq_spinlock::{IrqSpinlock, IrqSpinlockGuard};
+pub use ticket_lock::{TicketLock, TicketLockGuard};
+pub use async_mutex::{AsyncMutex, AsyncMutexGuard};
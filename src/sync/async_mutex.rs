@@ -0,0 +1,119 @@
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+/// # AsyncMutex
+///
+/// A lock for async code that holds it across an `.await` - like a driver that owns a controller for the
+/// whole of a command. Waiting tasks are parked (their waker is stored) rather than spinning, so the core can
+/// run something else, including the task that holds the lock.
+///
+/// Not for interrupt handlers - they can't wait.
+pub struct AsyncMutex<T> {
+    locked: AtomicBool,
+    /// Tasks waiting for the lock. They are all woken when it is released, and race for it
+    waiters: Mutex<Vec<Waker>>,
+    data: UnsafeCell<T>,
+}
+
+// Only one task at a time can get at the data, through the guard
+unsafe impl<T: Send> Sync for AsyncMutex<T> {}
+unsafe impl<T: Send> Send for AsyncMutex<T> {}
+
+impl<T> AsyncMutex<T> {
+    /// Create a new, unlocked, lock. `const`, so it can be used in statics
+    pub const fn new(data: T) -> Self {
+        AsyncMutex { locked: AtomicBool::new(false), waiters: Mutex::new(Vec::new()), data: UnsafeCell::new(data) }
+    }
+
+    /// Wait for the lock
+    pub fn lock(&self) -> AsyncMutexLock<T> {
+        AsyncMutexLock { mutex: self }
+    }
+
+    /// Take the lock if it is free
+    pub fn try_lock(&self) -> Option<AsyncMutexGuard<T>> {
+        if self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+            Some(AsyncMutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    /// Whether someone holds the lock right now. Only a hint, it can change straight after
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
+
+/// The future returned by [AsyncMutex::lock](struct.AsyncMutex.html#method.lock)
+pub struct AsyncMutexLock<'a, T> {
+    mutex: &'a AsyncMutex<T>,
+}
+
+impl<'a, T> Future for AsyncMutexLock<'a, T> {
+    type Output = AsyncMutexGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<AsyncMutexGuard<'a, T>> {
+        if let Some(guard) = self.mutex.try_lock() {
+            return Poll::Ready(guard);
+        }
+        self.mutex.waiters.lock().push(cx.waker().clone());
+        // The holder may have let go between our try and registering - if so, nobody would wake us
+        match self.mutex.try_lock() {
+            Some(guard) => Poll::Ready(guard),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// # AsyncMutexGuard
+///
+/// Gives access to the data while the lock is held. Dropping it unlocks, and wakes the waiting tasks
+pub struct AsyncMutexGuard<'a, T> {
+    mutex: &'a AsyncMutex<T>,
+}
+
+impl<'a, T> Deref for AsyncMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for AsyncMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> Drop for AsyncMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        let waiters = core::mem::take(&mut *self.mutex.waiters.lock());
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+}
+
+/* Testing */
+
+#[test_case]
+fn async_mutex_try_lock() {
+    let lock = AsyncMutex::new(0);
+    {
+        let mut guard = lock.try_lock().expect("lock should be free");
+        *guard += 1;
+        assert!(lock.is_locked());
+        assert!(lock.try_lock().is_none());
+    }
+    assert!(!lock.is_locked());
+    assert_eq!(*crate::task::block_on(lock.lock()), 1);
+}
@@ -0,0 +1,167 @@
+use core::any::type_name;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+use super::lockdep::LockDebug;
+
+/// # IrqSpinlock
+///
+/// A spinlock that disables interrupts on the current core for as long as it is held, and puts them back
+/// the way they were when the guard is dropped.
+///
+/// Use this for anything an interrupt handler locks. With a plain `spin::Mutex`, an interrupt that arrives
+/// while the core holds the lock spins forever waiting for code that can't run until the handler returns.
+/// Wrapping every `lock()` in `without_interrupts` by hand works too, until someone forgets.
+///
+/// Keep critical sections short - interrupts are off the whole time.
+pub struct IrqSpinlock<T> {
+    locked: AtomicBool,
+    debug: LockDebug,
+    data: UnsafeCell<T>,
+}
+
+// Only one core at a time can get at the data, through the guard
+unsafe impl<T: Send> Sync for IrqSpinlock<T> {}
+unsafe impl<T: Send> Send for IrqSpinlock<T> {}
+
+impl<T> IrqSpinlock<T> {
+    /// Create a new, unlocked, lock. `const`, so it can be used in statics
+    pub const fn new(data: T) -> Self {
+        IrqSpinlock {
+            locked: AtomicBool::new(false),
+            debug: LockDebug::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Disable interrupts, then spin until we get the lock
+    #[track_caller]
+    pub fn lock(&self) -> IrqSpinlockGuard<T> {
+        let site = Location::caller();
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        self.debug.before_acquire(site, type_name::<Self>());
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            // Spin on a plain load, so we aren't fighting the holder for the cache line
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+        self.debug.acquired(site, type_name::<Self>());
+
+        IrqSpinlockGuard { lock: self, interrupts_were_enabled, _not_send: PhantomData }
+    }
+
+    /// Take the lock if it is free. Interrupts are left alone if it isn't
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqSpinlockGuard<T>> {
+        let site = Location::caller();
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        if self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+            self.debug.acquired(site, type_name::<Self>());
+            Some(IrqSpinlockGuard { lock: self, interrupts_were_enabled, _not_send: PhantomData })
+        } else {
+            if interrupts_were_enabled {
+                interrupts::enable();
+            }
+            None
+        }
+    }
+
+    /// Whether someone holds the lock right now. Only a hint, it can change straight after
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// The core holding the lock and where it was taken. Always `None` unless built with the `lock-debug` feature
+    pub fn holder(&self) -> Option<(usize, &'static Location<'static>)> {
+        self.debug.holder()
+    }
+
+    /// We have the only reference, so no locking needed
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+/// # IrqSpinlockGuard
+///
+/// Gives access to the data while the lock is held. Dropping it unlocks, and re-enables interrupts if
+/// they were enabled before locking.
+///
+/// Not `Send` - interrupts have to be restored on the core that disabled them.
+pub struct IrqSpinlockGuard<'a, T> {
+    lock: &'a IrqSpinlock<T>,
+    interrupts_were_enabled: bool,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<'a, T> Deref for IrqSpinlockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for IrqSpinlockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for IrqSpinlockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.debug.released();
+        self.lock.locked.store(false, Ordering::Release);
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+        self.lock.debug.after_release();
+    }
+}
+
+/* Testing */
+
+#[test_case]
+fn interrupts_disabled_while_held() {
+    let lock = IrqSpinlock::new(0);
+    assert!(interrupts::are_enabled());
+    {
+        let mut guard = lock.lock();
+        *guard += 1;
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(*lock.lock(), 1);
+}
+
+#[test_case]
+fn nested_locks_restore_interrupts() {
+    let outer = IrqSpinlock::new(());
+    let inner = IrqSpinlock::new(());
+
+    let outer_guard = outer.lock();
+    {
+        let _inner_guard = inner.lock();
+    }
+    // The inner guard saw interrupts already disabled, so it must not turn them back on
+    assert!(!interrupts::are_enabled());
+    drop(outer_guard);
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+fn try_lock_fails_while_held() {
+    let lock = IrqSpinlock::new(());
+    let guard = lock.lock();
+    assert!(lock.try_lock().is_none());
+    drop(guard);
+    assert!(lock.try_lock().is_some());
+}
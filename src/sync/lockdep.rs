@@ -0,0 +1,404 @@
+//! # Lock debugging
+//!
+//! Build with `--features lock-debug` to have every [IrqSpinlock](../irq_spinlock/struct.IrqSpinlock.html) and
+//! [TicketLock](../ticket_lock/struct.TicketLock.html) keep track of:
+//!
+//! * who holds it - the CPU and the source location the lock was taken at
+//! * recursive locking - taking a lock the same CPU already holds would spin forever, so we panic instead,
+//!   saying where the lock was first taken
+//! * lock order inversions - like Linux's lockdep, we remember "B was taken while A was held". If somewhere
+//!   else then takes A while holding B, two CPUs could deadlock on each other, even if it hasn't happened yet.
+//!   We print a warning (once per pair of locks) over serial, once the CPU has let go of every lock
+//!
+//! The order is tracked per lock class, not per lock - every lock of the same type (say, every virtqueue's
+//! `IrqSpinlock<Virtqueue>`) is one class, so locks created at runtime don't each use up a class.
+//!
+//! Without the feature, `LockDebug` is zero sized and every check compiles away.
+
+#[cfg(feature = "lock-debug")]
+pub use enabled::*;
+#[cfg(not(feature = "lock-debug"))]
+pub use disabled::*;
+
+#[cfg(feature = "lock-debug")]
+mod enabled {
+    use core::panic::Location;
+    use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+    use core::{slice, str};
+    use crate::percpu::current_cpu_id;
+    use crate::{percpu, serial_println};
+
+    /// How many lock classes (lock types) we track the order of. Each one is a bit in a `u64`, so the graph
+    /// needs no allocation (locks are taken inside the allocator's callers and interrupt handlers, so we can't
+    /// allocate here)
+    const MAX_CLASSES: usize = 64;
+    /// How many locks one CPU can hold at once before we stop tracking the rest
+    const MAX_HELD: usize = 16;
+    /// How many inversions one CPU keeps to print, while it still holds locks
+    const MAX_REPORTS: usize = 4;
+
+    /// `owner` value for an unlocked lock
+    const NO_OWNER: usize = usize::MAX;
+    /// `class` value for a lock that hasn't been taken yet
+    const CLASS_UNASSIGNED: usize = 0;
+    /// `class` value for a lock that came after we ran out of classes - it isn't order checked
+    const CLASS_UNTRACKED: usize = usize::MAX;
+
+    /// Bit `b` of `DEPENDS_ON[a]` is set once lock `b` has been taken while lock `a` was held
+    static DEPENDS_ON: [AtomicU64; MAX_CLASSES] = [ZERO; MAX_CLASSES];
+    /// Bit `b` of `REPORTED[a]` is set once we've warned about `a` and `b`, so we only warn once
+    static REPORTED: [AtomicU64; MAX_CLASSES] = [ZERO; MAX_CLASSES];
+    /// Initial value for the bitsets above. A named const lets us use it in an array repeat expression
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+
+    /// The key (the lock's type name) each class was handed out for, as a pointer and length. A class is only
+    /// matched against once its `CLASS_READY` flag is set
+    static CLASS_KEY_POINTERS: [AtomicUsize; MAX_CLASSES] = [USIZE_ZERO; MAX_CLASSES];
+    static CLASS_KEY_LENGTHS: [AtomicUsize; MAX_CLASSES] = [USIZE_ZERO; MAX_CLASSES];
+    static CLASS_READY: [AtomicBool; MAX_CLASSES] = [NOT_READY; MAX_CLASSES];
+    /// Initial values for the arrays above
+    const USIZE_ZERO: AtomicUsize = AtomicUsize::new(0);
+    const NOT_READY: AtomicBool = AtomicBool::new(false);
+
+    /// The next class to hand out
+    static NEXT_CLASS: AtomicUsize = AtomicUsize::new(0);
+    /// Set when a lock type doesn't get a class because we ran out, until we've warned about it
+    static OUT_OF_CLASSES: AtomicBool = AtomicBool::new(false);
+    /// Whether we've warned that we ran out of classes
+    static OUT_OF_CLASSES_REPORTED: AtomicBool = AtomicBool::new(false);
+    /// How many inversions have been found, mostly for the tests
+    static INVERSIONS: AtomicUsize = AtomicUsize::new(0);
+
+    /// An inversion waiting to be printed - where the lock was taken, and where the lock held at the time was
+    #[derive(Clone, Copy)]
+    struct Inversion {
+        site: &'static Location<'static>,
+        held_site: &'static Location<'static>,
+    }
+
+    /// The locks a CPU currently holds, in the order it took them, and the inversions it found while holding them
+    struct HeldLocks {
+        classes: [usize; MAX_HELD],
+        sites: [Option<&'static Location<'static>>; MAX_HELD],
+        depth: usize,
+        reports: [Option<Inversion>; MAX_REPORTS],
+        /// Inversions found after `reports` filled up
+        reports_missed: usize,
+    }
+
+    percpu! {
+        static HELD: HeldLocks = HeldLocks {
+            classes: [0; MAX_HELD],
+            sites: [None; MAX_HELD],
+            depth: 0,
+            reports: [None; MAX_REPORTS],
+            reports_missed: 0,
+        };
+    }
+
+    /// # LockDebug
+    ///
+    /// The debugging state each lock carries around
+    pub struct LockDebug {
+        /// The CPU holding the lock, or `NO_OWNER`
+        owner: AtomicUsize,
+        /// The `&'static Location` the lock was taken at (as an address), or 0
+        site: AtomicUsize,
+        /// Index into the order graph (plus one) of the lock's class, looked up the first time the lock is taken
+        class: AtomicUsize,
+    }
+
+    impl LockDebug {
+        pub const fn new() -> Self {
+            LockDebug {
+                owner: AtomicUsize::new(NO_OWNER),
+                site: AtomicUsize::new(0),
+                class: AtomicUsize::new(CLASS_UNASSIGNED),
+            }
+        }
+
+        /// The CPU holding the lock and where it took it, if it is held
+        pub fn holder(&self) -> Option<(usize, &'static Location<'static>)> {
+            let owner = self.owner.load(Ordering::Relaxed);
+            let site = self.site.load(Ordering::Relaxed) as *const Location<'static>;
+            if owner == NO_OWNER || site.is_null() {
+                return None;
+            }
+            Some((owner, unsafe { &*site }))
+        }
+
+        /// Called before spinning on the lock. Panics on recursive locking, and warns about order inversions.
+        /// `key` names the lock's class - the lock's type name
+        pub fn before_acquire(&self, site: &'static Location<'static>, key: &'static str) {
+            let cpu = current_cpu_id();
+            if self.owner.load(Ordering::Relaxed) == cpu {
+                match self.holder() {
+                    Some((_, held_at)) => panic!(
+                        "recursive locking on CPU {}: lock taken at {} is already held (taken at {})",
+                        cpu, site, held_at
+                    ),
+                    None => panic!("recursive locking on CPU {}: lock taken at {} is already held", cpu, site),
+                }
+            }
+
+            if let Some(class) = self.class(key) {
+                check_order(class, site);
+            }
+        }
+
+        /// Called once the lock is ours
+        pub fn acquired(&self, site: &'static Location<'static>, key: &'static str) {
+            self.owner.store(current_cpu_id(), Ordering::Relaxed);
+            self.site.store(site as *const Location<'static> as usize, Ordering::Relaxed);
+
+            if let Some(class) = self.class(key) {
+                HELD.with_mut(|held| {
+                    if held.depth < MAX_HELD {
+                        held.classes[held.depth] = class;
+                        held.sites[held.depth] = Some(site);
+                        held.depth += 1;
+                    }
+                });
+            }
+        }
+
+        /// Called just before the lock is released
+        pub fn released(&self) {
+            self.owner.store(NO_OWNER, Ordering::Relaxed);
+            self.site.store(0, Ordering::Relaxed);
+
+            // The class was looked up when the lock was taken
+            if let Some(class) = decode_class(self.class.load(Ordering::Relaxed)) {
+                HELD.with_mut(|held| {
+                    // Locks don't have to be released in the order they were taken, so search from the top
+                    if let Some(index) = (0..held.depth).rev().find(|&i| held.classes[i] == class) {
+                        for i in index..held.depth - 1 {
+                            held.classes[i] = held.classes[i + 1];
+                            held.sites[i] = held.sites[i + 1];
+                        }
+                        held.depth -= 1;
+                    }
+                });
+            }
+        }
+
+        /// Called once the lock has been released (and interrupts put back). Prints the inversions found while
+        /// this CPU held locks, once it holds none - printing takes the serial port lock, so doing it while
+        /// still holding locks could deadlock on one of them
+        pub fn after_release(&self) {
+            let (reports, missed) = HELD.with_mut(|held| {
+                if held.depth > 0 {
+                    return ([None; MAX_REPORTS], 0);
+                }
+                let taken = (held.reports, held.reports_missed);
+                held.reports = [None; MAX_REPORTS];
+                held.reports_missed = 0;
+                taken
+            });
+
+            for inversion in reports.iter().flatten() {
+                serial_println!(
+                    "[WARN] possible lock order inversion on CPU {}: lock taken at {} while holding the \
+                     lock taken at {}, but elsewhere they have been taken the other way round",
+                    current_cpu_id(),
+                    inversion.site,
+                    inversion.held_site
+                );
+            }
+            if missed > 0 {
+                serial_println!("[WARN] {} more lock order inversions on CPU {}", missed, current_cpu_id());
+            }
+            if OUT_OF_CLASSES.load(Ordering::Relaxed) && !OUT_OF_CLASSES_REPORTED.swap(true, Ordering::Relaxed) {
+                serial_println!("[WARN] more than {} lock types, the rest aren't order checked", MAX_CLASSES);
+            }
+        }
+
+        /// This lock's class in the order graph, looking it up from `key` the first time. `None` if its type
+        /// came after we ran out of classes
+        fn class(&self, key: &'static str) -> Option<usize> {
+            let class = match self.class.load(Ordering::Relaxed) {
+                CLASS_UNASSIGNED => {
+                    let class = class_for(key);
+                    self.class.store(class, Ordering::Relaxed);
+                    class
+                }
+                class => class,
+            };
+            decode_class(class)
+        }
+    }
+
+    /// Turn a `class` field value into an index into the order graph
+    fn decode_class(class: usize) -> Option<usize> {
+        match class {
+            CLASS_UNASSIGNED | CLASS_UNTRACKED => None,
+            class => Some(class - 1),
+        }
+    }
+
+    /// The class (plus one) for locks with `key`, handing out a new one if there isn't one yet. Two CPUs taking
+    /// a new kind of lock for the first time at once may both hand one out - that only costs a class
+    fn class_for(key: &'static str) -> usize {
+        let count = NEXT_CLASS.load(Ordering::Acquire).min(MAX_CLASSES);
+        for class in 0..count {
+            if CLASS_READY[class].load(Ordering::Acquire) && class_key(class) == key {
+                return class + 1;
+            }
+        }
+
+        let class = NEXT_CLASS.fetch_add(1, Ordering::AcqRel);
+        if class >= MAX_CLASSES {
+            OUT_OF_CLASSES.store(true, Ordering::Relaxed);
+            return CLASS_UNTRACKED;
+        }
+        CLASS_KEY_POINTERS[class].store(key.as_ptr() as usize, Ordering::Relaxed);
+        CLASS_KEY_LENGTHS[class].store(key.len(), Ordering::Relaxed);
+        CLASS_READY[class].store(true, Ordering::Release);
+        class + 1
+    }
+
+    /// The key `class` was handed out for. Only call once its `CLASS_READY` flag is set
+    fn class_key(class: usize) -> &'static str {
+        let pointer = CLASS_KEY_POINTERS[class].load(Ordering::Relaxed) as *const u8;
+        let length = CLASS_KEY_LENGTHS[class].load(Ordering::Relaxed);
+        unsafe { str::from_utf8_unchecked(slice::from_raw_parts(pointer, length)) }
+    }
+
+    /// We're about to take `class` - record that it comes after every lock we hold, and note an inversion to
+    /// warn about if some path already says one of those comes after it
+    fn check_order(class: usize, site: &'static Location<'static>) {
+        HELD.with_mut(|held| {
+            for i in 0..held.depth {
+                let before = held.classes[i];
+                if before == class {
+                    continue;
+                }
+
+                if reachable(class, before) {
+                    let pair_bit = 1 << before;
+                    if REPORTED[class].fetch_or(pair_bit, Ordering::Relaxed) & pair_bit == 0 {
+                        INVERSIONS.fetch_add(1, Ordering::Relaxed);
+                        // Printed by `after_release`, as we're in the middle of taking a lock
+                        let inversion = Inversion { site, held_site: held.sites[i].unwrap() };
+                        match held.reports.iter_mut().find(|report| report.is_none()) {
+                            Some(report) => *report = Some(inversion),
+                            None => held.reports_missed += 1,
+                        }
+                    }
+                } else {
+                    DEPENDS_ON[before].fetch_or(1 << class, Ordering::Relaxed);
+                }
+            }
+        });
+    }
+
+    /// Whether the graph has a path from `from` to `to` (`to` has been taken, maybe indirectly, while
+    /// holding `from`). A breadth first search, with the frontier as a bitset
+    fn reachable(from: usize, to: usize) -> bool {
+        let mut seen = 0u64;
+        let mut frontier = 1u64 << from;
+
+        while frontier != 0 {
+            seen |= frontier;
+            let mut next = 0u64;
+            for class in 0..MAX_CLASSES {
+                if frontier & (1 << class) != 0 {
+                    next |= DEPENDS_ON[class].load(Ordering::Relaxed);
+                }
+            }
+            frontier = next & !seen;
+        }
+
+        seen & (1 << to) != 0
+    }
+
+    /// How many lock order inversions have been found so far
+    pub fn inversion_count() -> usize {
+        INVERSIONS.load(Ordering::Relaxed)
+    }
+
+    /* Testing */
+
+    #[test_case]
+    fn records_holder() {
+        use crate::sync::IrqSpinlock;
+
+        let lock = IrqSpinlock::new(());
+        let guard = lock.lock();
+        let (cpu, site) = lock.holder().expect("lock has no holder");
+        assert_eq!(cpu, current_cpu_id());
+        assert_eq!(site.file(), file!());
+        drop(guard);
+        assert!(lock.holder().is_none());
+    }
+
+    #[test_case]
+    fn reports_lock_order_inversion() {
+        use crate::sync::{IrqSpinlock, TicketLock};
+
+        // Types of their own, so no other test has put their classes in some order already
+        struct First;
+        struct Second;
+        let a = IrqSpinlock::new(First);
+        let b = TicketLock::new(Second);
+        let before = inversion_count();
+        {
+            let _a = a.lock();
+            let _b = b.lock();
+        }
+        assert_eq!(inversion_count(), before);
+        {
+            let _b = b.lock();
+            let _a = a.lock(); // a then b above, b then a here
+        }
+        assert_eq!(inversion_count(), before + 1);
+    }
+
+    // Every lock of a type shares one class, so making lots of locks doesn't use the classes up
+    #[test_case]
+    fn classes_are_per_type() {
+        use crate::sync::IrqSpinlock;
+
+        struct Counted;
+        let used = NEXT_CLASS.load(Ordering::Relaxed);
+        for _ in 0..2 * MAX_CLASSES {
+            let lock = IrqSpinlock::new(Counted);
+            drop(lock.lock());
+        }
+        assert!(NEXT_CLASS.load(Ordering::Relaxed) <= used + 1);
+    }
+}
+
+#[cfg(not(feature = "lock-debug"))]
+mod disabled {
+    use core::panic::Location;
+
+    /// # LockDebug
+    ///
+    /// Lock debugging is turned off, so this does nothing
+    pub struct LockDebug;
+
+    impl LockDebug {
+        #[inline(always)]
+        pub const fn new() -> Self {
+            LockDebug
+        }
+
+        #[inline(always)]
+        pub fn holder(&self) -> Option<(usize, &'static Location<'static>)> {
+            None
+        }
+
+        #[inline(always)]
+        pub fn before_acquire(&self, _site: &'static Location<'static>, _key: &'static str) {}
+
+        #[inline(always)]
+        pub fn acquired(&self, _site: &'static Location<'static>, _key: &'static str) {}
+
+        #[inline(always)]
+        pub fn released(&self) {}
+
+        #[inline(always)]
+        pub fn after_release(&self) {}
+    }
+}
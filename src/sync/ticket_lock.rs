@@ -0,0 +1,139 @@
+use core::any::type_name;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::lockdep::LockDebug;
+
+/// # TicketLock
+///
+/// A fair spinlock. Like the queue at a deli counter - each core takes a ticket, and the lock is handed
+/// out in ticket order. With a plain spinlock, a core that keeps re-locking can starve the others,
+/// because whoever happens to win the race gets it. Here, nobody waits for more than everyone ahead of them.
+///
+/// This doesn't touch interrupts, so don't use it for data an interrupt handler locks - use
+/// [IrqSpinlock](../irq_spinlock/struct.IrqSpinlock.html) for that.
+pub struct TicketLock<T> {
+    /// The ticket the next core to arrive gets
+    next_ticket: AtomicUsize,
+    /// The ticket that currently holds the lock
+    now_serving: AtomicUsize,
+    debug: LockDebug,
+    data: UnsafeCell<T>,
+}
+
+// Only one core at a time can get at the data, through the guard
+unsafe impl<T: Send> Sync for TicketLock<T> {}
+unsafe impl<T: Send> Send for TicketLock<T> {}
+
+impl<T> TicketLock<T> {
+    /// Create a new, unlocked, lock. `const`, so it can be used in statics
+    pub const fn new(data: T) -> Self {
+        TicketLock {
+            next_ticket: AtomicUsize::new(0),
+            now_serving: AtomicUsize::new(0),
+            debug: LockDebug::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Take a ticket, then wait for our turn
+    #[track_caller]
+    pub fn lock(&self) -> TicketLockGuard<T> {
+        let site = Location::caller();
+        self.debug.before_acquire(site, type_name::<Self>());
+
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            core::hint::spin_loop();
+        }
+        self.debug.acquired(site, type_name::<Self>());
+
+        TicketLockGuard { lock: self, _not_send: PhantomData }
+    }
+
+    /// Take the lock if nobody holds it or is waiting for it
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<TicketLockGuard<T>> {
+        let ticket = self.now_serving.load(Ordering::Acquire);
+        // Only take a ticket if it would be served straight away
+        if self.next_ticket.compare_exchange(ticket, ticket + 1, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+            self.debug.acquired(Location::caller(), type_name::<Self>());
+            Some(TicketLockGuard { lock: self, _not_send: PhantomData })
+        } else {
+            None
+        }
+    }
+
+    /// Whether someone holds (or is waiting for) the lock right now. Only a hint, it can change straight after
+    pub fn is_locked(&self) -> bool {
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+
+    /// The core holding the lock and where it was taken. Always `None` unless built with the `lock-debug` feature
+    pub fn holder(&self) -> Option<(usize, &'static Location<'static>)> {
+        self.debug.holder()
+    }
+
+    /// We have the only reference, so no locking needed
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+/// # TicketLockGuard
+///
+/// Gives access to the data while the lock is held. Dropping it hands the lock to the next ticket.
+pub struct TicketLockGuard<'a, T> {
+    lock: &'a TicketLock<T>,
+    // The lock debugging keeps a per-core list of held locks, so unlock on the core that locked
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<'a, T> Deref for TicketLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for TicketLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for TicketLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.debug.released();
+        // Only the holder changes `now_serving`, so a plain add is fine
+        self.lock.now_serving.fetch_add(1, Ordering::Release);
+        self.lock.debug.after_release();
+    }
+}
+
+/* Testing */
+
+#[test_case]
+fn tickets_are_served_in_order() {
+    let lock = TicketLock::new(0);
+    for i in 0..10 {
+        let mut guard = lock.lock();
+        assert_eq!(*guard, i);
+        *guard += 1;
+    }
+    assert!(!lock.is_locked());
+    assert_eq!(*lock.lock(), 10);
+}
+
+#[test_case]
+fn ticket_try_lock_fails_while_held() {
+    let lock = TicketLock::new(());
+    let guard = lock.lock();
+    assert!(lock.is_locked());
+    assert!(lock.try_lock().is_none());
+    drop(guard);
+    assert!(lock.try_lock().is_some());
+}
@@ -0,0 +1,272 @@
+//! Broadcast channel - every value sent is seen by every receiver.
+//!
+//! Values live in a fixed size ring buffer. Each receiver keeps its own read position, so a slow
+//! receiver that falls more than `capacity` values behind misses the oldest values and gets told
+//! how many it missed through `RecvError::Lagged`. Senders never wait.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use super::SendError;
+
+/// Error returned when receiving from a broadcast channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// Every sender is gone and we've seen every value
+    Closed,
+    /// We fell behind and the oldest values were overwritten. Contains how many we missed.
+    /// The next receive picks up from the oldest value still in the buffer
+    Lagged(u64),
+}
+
+/// The ring buffer and bookkeeping, guarded by the channel lock
+struct State<T> {
+    /// One slot per value. Slot `seq % capacity` holds the value with sequence number `seq`
+    buffer: Vec<Option<T>>,
+    /// Sequence number of the next value to be sent
+    tail: u64,
+    senders: usize,
+    receivers: usize,
+    /// Receivers waiting for a new value, tagged with the receiver id so re-polling replaces the old waker.
+    /// We drain this with `drain(..)`, which keeps the allocation around - so sending never frees memory
+    wakers: Vec<(u64, Waker)>,
+    /// Id handed to the next receiver
+    next_receiver_id: u64,
+}
+
+/// Shared between all senders and receivers
+struct Shared<T> {
+    state: Mutex<State<T>>,
+}
+
+impl<T> Shared<T> {
+    /// Lock the state with interrupts disabled. Senders may run in interrupt handlers, so if we held this
+    /// lock with interrupts on, an interrupt on this CPU could spin on it forever.
+    fn with_state<R>(&self, f: impl FnOnce(&mut State<T>) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut self.state.lock()))
+    }
+}
+
+/// Create a broadcast channel that buffers up to `capacity` values
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "broadcast channel capacity must be non-zero");
+
+    let mut buffer = Vec::with_capacity(capacity);
+    buffer.resize_with(capacity, || None);
+
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            buffer,
+            tail: 0,
+            senders: 1,
+            receivers: 1,
+            wakers: Vec::new(),
+            next_receiver_id: 1,
+        }),
+    });
+
+    (Sender { shared: shared.clone() }, Receiver { shared, next: 0, id: 0 })
+}
+
+/// # Sender
+///
+/// Sending half of a broadcast channel. Can be cloned, and can create new receivers with `subscribe`.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Clone> Sender<T> {
+    /// Send a value to every receiver, returning how many receivers there are. Fails (giving the value back)
+    /// if there are no receivers.
+    ///
+    /// Never blocks or allocates. Once the ring is full, sending drops the oldest value, so it is only safe
+    /// to call from an interrupt handler when dropping a `T` doesn't free memory (plain data, like scancodes).
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let (result, oldest) = self.shared.with_state(|state| {
+            if state.receivers == 0 {
+                return (Err(SendError(value)), None);
+            }
+
+            let capacity = state.buffer.len() as u64;
+            let slot = (state.tail % capacity) as usize;
+            let oldest = state.buffer[slot].replace(value); // the oldest value, once the ring is full
+            state.tail += 1;
+
+            for (_, waker) in state.wakers.drain(..) {
+                waker.wake();
+            }
+
+            (Ok(state.receivers), oldest)
+        });
+
+        // Drop the overwritten value outside the lock, so whatever its drop does can't run with the channel held
+        drop(oldest);
+        result
+    }
+
+    /// Create a new receiver. It only sees values sent after it subscribed.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let (next, id) = self.shared.with_state(|state| {
+            state.receivers += 1;
+            state.next_receiver_id += 1;
+            (state.tail, state.next_receiver_id - 1)
+        });
+
+        Receiver { shared: self.shared.clone(), next, id }
+    }
+
+    /// The number of live receivers
+    pub fn receiver_count(&self) -> usize {
+        self.shared.with_state(|state| state.receivers)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.with_state(|state| state.senders += 1);
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.with_state(|state| {
+            state.senders -= 1;
+            if state.senders == 0 {
+                // wake everyone so they can see the channel is closed
+                for (_, waker) in state.wakers.drain(..) {
+                    waker.wake();
+                }
+            }
+        });
+    }
+}
+
+/// # Receiver
+///
+/// Receiving half of a broadcast channel. Each receiver gets its own clone of every value.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    /// Sequence number of the next value we want
+    next: u64,
+    /// Used to replace our waker instead of piling up new ones every time we're polled
+    id: u64,
+}
+
+impl<T: Clone> Receiver<T> {
+    /// Receive a value without waiting. `Ok(None)` means there is nothing new yet.
+    pub fn try_recv(&mut self) -> Result<Option<T>, RecvError> {
+        let next = &mut self.next;
+        self.shared.with_state(|state| Self::take(state, next))
+    }
+
+    /// Wait for the next value
+    pub fn recv(&mut self) -> RecvFuture<'_, T> {
+        RecvFuture { receiver: self }
+    }
+
+    /// Read the value at `next` from the ring, if there is one
+    fn take(state: &mut State<T>, next: &mut u64) -> Result<Option<T>, RecvError> {
+        let capacity = state.buffer.len() as u64;
+
+        // the oldest value still in the ring
+        let oldest = state.tail.saturating_sub(capacity);
+        if *next < oldest {
+            let missed = oldest - *next;
+            *next = oldest;
+            return Err(RecvError::Lagged(missed));
+        }
+
+        if *next < state.tail {
+            let value = state.buffer[(*next % capacity) as usize].clone();
+            *next += 1;
+            return Ok(value);
+        }
+
+        if state.senders == 0 {
+            return Err(RecvError::Closed);
+        }
+
+        Ok(None)
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    /// The clone starts reading from the same position as this receiver
+    fn clone(&self) -> Self {
+        let id = self.shared.with_state(|state| {
+            state.receivers += 1;
+            state.next_receiver_id += 1;
+            state.next_receiver_id - 1
+        });
+
+        Receiver { shared: self.shared.clone(), next: self.next, id }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let id = self.id;
+        self.shared.with_state(|state| {
+            state.receivers -= 1;
+            state.wakers.retain(|(waker_id, _)| *waker_id != id);
+        });
+    }
+}
+
+/// Future returned by [Receiver::recv](struct.Receiver.html#method.recv)
+pub struct RecvFuture<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<'a, T: Clone> Future for RecvFuture<'a, T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let receiver = &mut *self.get_mut().receiver;
+        let id = receiver.id;
+        let next = &mut receiver.next;
+
+        // Check and register under the same lock, so a send can't sneak in between
+        receiver.shared.with_state(|state| match Receiver::take(state, next) {
+            Ok(Some(value)) => Poll::Ready(Ok(value)),
+            Err(err) => Poll::Ready(Err(err)),
+            Ok(None) => {
+                match state.wakers.iter_mut().find(|(waker_id, _)| *waker_id == id) {
+                    Some((_, waker)) => *waker = cx.waker().clone(),
+                    None => state.wakers.push((id, cx.waker().clone())),
+                }
+                Poll::Pending
+            }
+        })
+    }
+}
+
+/* Testing */
+
+// Every receiver should see every value
+#[test_case]
+fn test_broadcast_fan_out() {
+    let (sender, mut first) = channel(4);
+    let mut second = sender.subscribe();
+    assert_eq!(sender.send(5), Ok(2));
+    assert_eq!(first.try_recv(), Ok(Some(5)));
+    assert_eq!(second.try_recv(), Ok(Some(5)));
+    assert_eq!(first.try_recv(), Ok(None));
+}
+
+// A receiver that falls behind should be told how much it missed, then carry on from the oldest value
+#[test_case]
+fn test_broadcast_lagged() {
+    let (sender, mut receiver) = channel(2);
+    for i in 0..5 {
+        sender.send(i).unwrap();
+    }
+    assert_eq!(receiver.try_recv(), Err(RecvError::Lagged(3)));
+    assert_eq!(receiver.try_recv(), Ok(Some(3)));
+    assert_eq!(receiver.try_recv(), Ok(Some(4)));
+}
@@ -0,0 +1,53 @@
+//! Async channels, so drivers and tasks can talk to each other without each one inventing its own
+//! static queue + waker pair (like the keyboard driver used to).
+//!
+//! There are three flavours:
+//!
+//! * [oneshot](oneshot/index.html) - send exactly one value, then the channel is done
+//! * [mpsc](mpsc/index.html) - many senders, one receiver. Comes in a bounded (with backpressure) and an unbounded version
+//! * [broadcast](broadcast/index.html) - many senders, many receivers. Every receiver gets a clone of every value
+//!
+//! # Interrupt safety
+//!
+//! Interrupt handlers must not block or allocate (the allocator is behind a spinlock, so if we interrupt
+//! code that is holding it, we deadlock). The senders that are safe to call from an interrupt handler say so
+//! in their docs - they are `oneshot::Sender::send`, `mpsc::Sender::try_send`, `mpsc::IrqSender::try_send`
+//! and `broadcast::Sender::send` (as long as dropping the value doesn't free memory - see its docs). The unbounded
+//! mpsc sender allocates, so it is **not** interrupt safe.
+
+pub mod oneshot; // Single value channels, useful for "tell me when this request finished"
+pub mod mpsc; // Multi-producer, single-consumer queues
+pub mod broadcast; // Multi-producer, multi-consumer - every receiver sees every value
+
+/// Error returned by `try_send` when the value could not be sent. We give the value back so it isn't lost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is at capacity, try again later
+    Full(T),
+    /// The receiving half is gone, nobody will ever read this value
+    Closed(T),
+}
+
+impl<T> TrySendError<T> {
+    /// Get back the value that failed to send
+    pub fn into_inner(self) -> T {
+        match self {
+            TrySendError::Full(value) => value,
+            TrySendError::Closed(value) => value,
+        }
+    }
+}
+
+/// Error returned when sending on a channel whose receiver(s) have all been dropped.
+/// We give the value back so it isn't lost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+/// Error returned by `try_recv`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// There is nothing to receive right now, but there might be later
+    Empty,
+    /// Every sender has been dropped and the channel is drained, so nothing will ever arrive
+    Closed,
+}
@@ -0,0 +1,452 @@
+//! Multi-producer, single-consumer channels.
+//!
+//! `channel(capacity)` creates a bounded channel. The queue is allocated up front, so `try_send` never
+//! allocates, and `send(..).await` waits for room when the queue is full (backpressure).
+//!
+//! `unbounded()` creates a channel that never fills up, but every send may allocate - so it must not be
+//! used from interrupt handlers.
+//!
+//! Both flavours share the same [Receiver](struct.Receiver.html), which can be awaited with `recv` or used as a `Stream`.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::{ArrayQueue, SegQueue};
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use super::{SendError, TryRecvError, TrySendError};
+
+/// The queue backing a channel
+enum Queue<T> {
+    /// Fixed size, allocated once when the channel is created
+    Bounded(ArrayQueue<T>),
+    /// Grows as needed (allocates)
+    Unbounded(SegQueue<T>),
+}
+
+impl<T> Queue<T> {
+    /// Push a value, handing it back if the queue is full
+    fn push(&self, value: T) -> Result<(), T> {
+        match self {
+            Queue::Bounded(queue) => queue.push(value).map_err(|err| err.0),
+            Queue::Unbounded(queue) => {
+                queue.push(value);
+                Ok(())
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<T> {
+        match self {
+            Queue::Bounded(queue) => queue.pop().ok(),
+            Queue::Unbounded(queue) => queue.pop().ok(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Queue::Bounded(queue) => queue.len(),
+            Queue::Unbounded(queue) => queue.len(),
+        }
+    }
+}
+
+/// State shared between all the senders and the receiver
+struct Chan<T> {
+    queue: Queue<T>,
+    /// Woken when a value is pushed, or the last sender goes away
+    rx_waker: AtomicWaker,
+    /// Senders waiting for room in a full bounded queue, one slot per `send` future (by its waiter id). Only
+    /// touched from task context (never from interrupts), as registering a waker allocates
+    tx_wakers: Mutex<VecDeque<(usize, Waker)>>,
+    /// The next waiter id to hand out
+    next_waiter: AtomicUsize,
+    /// Number of live senders (of any kind). When it hits zero the channel is closed
+    senders: AtomicUsize,
+    /// Set when the receiver is dropped
+    rx_closed: AtomicBool,
+}
+
+impl<T> Chan<T> {
+    fn new(queue: Queue<T>) -> Arc<Self> {
+        Arc::new(Chan {
+            queue,
+            rx_waker: AtomicWaker::new(),
+            tx_wakers: Mutex::new(VecDeque::new()),
+            next_waiter: AtomicUsize::new(0),
+            senders: AtomicUsize::new(1),
+            rx_closed: AtomicBool::new(false),
+        })
+    }
+
+    /// Push without waiting. Never allocates for bounded queues.
+    fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        if self.rx_closed.load(Ordering::Acquire) {
+            return Err(TrySendError::Closed(value));
+        }
+
+        match self.queue.push(value) {
+            Ok(()) => {
+                self.rx_waker.wake(); // wake the receiver AFTER the push, so it is guaranteed to see the value
+                Ok(())
+            }
+            Err(value) => Err(TrySendError::Full(value)),
+        }
+    }
+
+    /// Register `waker` for the `send` future with id `waiter`, giving it an id if it hasn't got one. A future
+    /// that is already waiting keeps its place, with its waker updated
+    fn register_sender(&self, waiter: &mut Option<usize>, waker: &Waker) {
+        let mut wakers = self.tx_wakers.lock();
+        if let Some(id) = *waiter {
+            if let Some((_, registered)) = wakers.iter_mut().find(|(other, _)| *other == id) {
+                if !registered.will_wake(waker) {
+                    *registered = waker.clone();
+                }
+                return;
+            }
+        }
+        let id = *waiter.get_or_insert_with(|| self.next_waiter.fetch_add(1, Ordering::Relaxed));
+        wakers.push_back((id, waker.clone()));
+    }
+
+    /// Take the `send` future with id `waiter` off the queue, returning whether it was still waiting (rather
+    /// than woken already)
+    fn unregister_sender(&self, waiter: usize) -> bool {
+        let mut wakers = self.tx_wakers.lock();
+        match wakers.iter().position(|(id, _)| *id == waiter) {
+            Some(index) => {
+                wakers.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wake the sender that has waited longest, as one slot was freed
+    fn wake_sender(&self) {
+        let waker = self.tx_wakers.lock().pop_front();
+        if let Some((_, waker)) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Wake every sender that is waiting, so they see the channel is closed
+    fn wake_all_senders(&self) {
+        let mut wakers = self.tx_wakers.lock();
+        while let Some((_, waker)) = wakers.pop_front() {
+            waker.wake();
+        }
+    }
+
+    fn add_sender(&self) {
+        self.senders.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn drop_sender(&self) {
+        if self.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.rx_waker.wake(); // last sender gone, let the receiver see the channel is closed
+        }
+    }
+}
+
+/// Create a bounded channel that holds at most `capacity` values
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let chan = Chan::new(Queue::Bounded(ArrayQueue::new(capacity)));
+    (Sender { chan: chan.clone() }, Receiver { chan })
+}
+
+/// Create an unbounded channel. Sending on it allocates, so don't use it from interrupt handlers.
+pub fn unbounded<T>() -> (UnboundedSender<T>, Receiver<T>) {
+    let chan = Chan::new(Queue::Unbounded(SegQueue::new()));
+    (UnboundedSender { chan: chan.clone() }, Receiver { chan })
+}
+
+/// # Sender
+///
+/// Sending half of a bounded channel. Can be cloned to create more producers.
+pub struct Sender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Try to send a value without waiting. Fails if the channel is full or the receiver is gone.
+    ///
+    /// Never blocks or allocates, so it is safe to call from an interrupt handler.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.chan.try_send(value)
+    }
+
+    /// Send a value, waiting for room if the channel is full. Resolves to an error (with the value)
+    /// if the receiver is dropped.
+    pub fn send(&self, value: T) -> SendFuture<'_, T> {
+        SendFuture { chan: &self.chan, value: Some(value), waiter: None }
+    }
+
+    /// Create an [IrqSender](struct.IrqSender.html) for this channel, to be handed to an interrupt handler
+    pub fn irq_sender(&self) -> IrqSender<T> {
+        self.chan.add_sender();
+        IrqSender { chan: self.chan.clone() }
+    }
+
+    /// Check whether the receiver has been dropped
+    pub fn is_closed(&self) -> bool {
+        self.chan.rx_closed.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.chan.add_sender();
+        Sender { chan: self.chan.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+/// Future returned by [Sender::send](struct.Sender.html#method.send)
+pub struct SendFuture<'a, T> {
+    chan: &'a Chan<T>,
+    value: Option<T>,
+    /// Our id in the channel's waiting senders, once we have had to wait
+    waiter: Option<usize>,
+}
+
+// We never hand out a pinned reference to `value`, so moving the future is fine
+impl<'a, T> Unpin for SendFuture<'a, T> {}
+
+impl<'a, T> Future for SendFuture<'a, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let value = this.value.take().expect("SendFuture polled after completion");
+
+        // fast path - there is room
+        let value = match this.chan.try_send(value) {
+            Ok(()) => return Poll::Ready(Ok(())),
+            Err(TrySendError::Closed(value)) => return Poll::Ready(Err(SendError(value))),
+            Err(TrySendError::Full(value)) => value,
+        };
+
+        // queue is full. Register our waker, then try once more in case the receiver made room in between
+        this.chan.register_sender(&mut this.waiter, cx.waker());
+        match this.chan.try_send(value) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(TrySendError::Closed(value)) => Poll::Ready(Err(SendError(value))),
+            Err(TrySendError::Full(value)) => {
+                this.value = Some(value); // hold on to it until we're woken
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<'a, T> Drop for SendFuture<'a, T> {
+    fn drop(&mut self) {
+        if let Some(waiter) = self.waiter {
+            // If we were woken for a free slot but never sent, hand the wakeup on to the next sender
+            if !self.chan.unregister_sender(waiter) && self.value.is_some() {
+                self.chan.wake_sender();
+            }
+        }
+    }
+}
+
+/// # IrqSender
+///
+/// A sender meant to be stored in a static and used by an interrupt handler. It can only `try_send`,
+/// which never blocks and never allocates. It counts as a sender, so the channel stays open while it exists.
+pub struct IrqSender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> IrqSender<T> {
+    /// Try to send a value without waiting. Safe to call from an interrupt handler.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.chan.try_send(value)
+    }
+}
+
+impl<T> Drop for IrqSender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+/// # UnboundedSender
+///
+/// Sending half of an unbounded channel. Sending never waits, but may allocate - **not** interrupt safe.
+pub struct UnboundedSender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> UnboundedSender<T> {
+    /// Send a value. Only fails if the receiver is gone.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.chan.try_send(value).map_err(|err| SendError(err.into_inner()))
+    }
+
+    /// Check whether the receiver has been dropped
+    pub fn is_closed(&self) -> bool {
+        self.chan.rx_closed.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for UnboundedSender<T> {
+    fn clone(&self) -> Self {
+        self.chan.add_sender();
+        UnboundedSender { chan: self.chan.clone() }
+    }
+}
+
+impl<T> Drop for UnboundedSender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+/// # Receiver
+///
+/// Receiving half of an mpsc channel (bounded or unbounded). Yields `None` once every sender is gone
+/// and the queue is drained.
+pub struct Receiver<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Receive a value without waiting
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if let Some(value) = self.chan.queue.pop() {
+            self.chan.wake_sender(); // we made room for one
+            return Ok(value);
+        }
+
+        if self.chan.senders.load(Ordering::Acquire) == 0 {
+            // The last sender may have pushed right before it dropped, so look one final time
+            return self.chan.queue.pop().ok_or(TryRecvError::Closed);
+        }
+
+        Err(TryRecvError::Empty)
+    }
+
+    /// Wait for the next value. Resolves to `None` when the channel is closed and empty.
+    pub fn recv(&mut self) -> RecvFuture<'_, T> {
+        RecvFuture { receiver: self }
+    }
+
+    /// How many values are waiting in the queue
+    pub fn len(&self) -> usize {
+        self.chan.queue.len()
+    }
+
+    /// Check the queue is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Poll for the next value - shared by `recv` and the `Stream` impl
+    fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        // fast path - optimistically check there is already a value
+        match self.try_recv() {
+            Ok(value) => return Poll::Ready(Some(value)),
+            Err(TryRecvError::Closed) => return Poll::Ready(None),
+            Err(TryRecvError::Empty) => {}
+        }
+
+        // register a waker, then check again - a sender may have pushed in between
+        self.chan.rx_waker.register(cx.waker());
+        match self.try_recv() {
+            Ok(value) => {
+                self.chan.rx_waker.take(); // we no longer need to be woken
+                Poll::Ready(Some(value))
+            }
+            Err(TryRecvError::Closed) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.chan.rx_closed.store(true, Ordering::Release);
+        self.chan.wake_all_senders(); // let any waiting senders see the channel is closed
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+/// Future returned by [Receiver::recv](struct.Receiver.html#method.recv)
+pub struct RecvFuture<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<'a, T> Future for RecvFuture<'a, T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().receiver.poll_recv(cx)
+    }
+}
+
+/* Testing */
+
+// A bounded channel should hand values back in order, and report full when at capacity
+#[test_case]
+fn test_bounded_try_send() {
+    let (sender, mut receiver) = channel(2);
+    assert_eq!(sender.try_send(1), Ok(()));
+    assert_eq!(sender.try_send(2), Ok(()));
+    assert_eq!(sender.try_send(3), Err(TrySendError::Full(3)));
+    assert_eq!(receiver.try_recv(), Ok(1));
+    assert_eq!(receiver.try_recv(), Ok(2));
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+}
+
+// Dropping every sender closes the channel once it is drained
+#[test_case]
+fn test_close_on_sender_drop() {
+    let (sender, mut receiver) = channel(4);
+    let irq_sender = sender.irq_sender();
+    sender.try_send(7).unwrap();
+    drop(sender);
+    assert_eq!(receiver.try_recv(), Ok(7));
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty)); // the irq sender is still alive
+    drop(irq_sender);
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Closed));
+}
+
+// A pending send keeps a single place in the queue however often it is polled, and is woken for a free slot
+#[test_case]
+fn test_send_waits_once() {
+    use futures_util::task::noop_waker_ref;
+
+    let (sender, mut receiver) = channel(1);
+    sender.try_send(1).unwrap();
+    let mut cx = Context::from_waker(noop_waker_ref());
+    let mut send = sender.send(2);
+    for _ in 0..3 {
+        assert_eq!(Pin::new(&mut send).poll(&mut cx), Poll::Pending);
+    }
+    assert_eq!(sender.chan.tx_wakers.lock().len(), 1);
+
+    assert_eq!(receiver.try_recv(), Ok(1));
+    assert!(sender.chan.tx_wakers.lock().is_empty());
+    assert_eq!(Pin::new(&mut send).poll(&mut cx), Poll::Ready(Ok(())));
+    drop(send);
+    assert_eq!(receiver.try_recv(), Ok(2));
+}
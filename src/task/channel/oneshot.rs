@@ -0,0 +1,190 @@
+//! One-shot channel - send a single value from one place to another.
+//!
+//! Handy for request/response style driver code: a task submits a request, keeps the `Receiver`,
+//! and the interrupt handler completes the request by calling `send` on the `Sender`.
+
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+use super::TryRecvError;
+
+/// Nothing has been sent yet
+const EMPTY: u8 = 0;
+/// The sender is currently writing the value
+const WRITING: u8 = 1;
+/// The value is sitting in the channel, waiting to be taken
+const FULL: u8 = 2;
+/// The receiver has taken the value
+const TAKEN: u8 = 3;
+/// One of the halves was dropped before a value was sent
+const CLOSED: u8 = 4;
+
+/// Error returned when the `Sender` was dropped without sending a value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Canceled;
+
+/// The shared state between the sender and the receiver.
+///
+/// `state` guards access to `value` - only the side that moves `state` into `WRITING` (the sender)
+/// or out of `FULL` (the receiver) may touch the cell. That way we never need a lock, which is what makes
+/// `send` safe to call from an interrupt handler.
+struct Inner<T> {
+    state: AtomicU8,
+    value: UnsafeCell<Option<T>>,
+    waker: AtomicWaker,
+}
+
+// The state machine above makes sure only one side ever touches `value` at a time
+unsafe impl<T: Send> Send for Inner<T> {}
+unsafe impl<T: Send> Sync for Inner<T> {}
+
+/// Create a new oneshot channel, returning the sending and receiving halves
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(Inner {
+        state: AtomicU8::new(EMPTY),
+        value: UnsafeCell::new(None),
+        waker: AtomicWaker::new(),
+    });
+
+    (Sender { inner: inner.clone() }, Receiver { inner })
+}
+
+/// # Sender
+///
+/// The sending half of a oneshot channel. Sending consumes the sender, so it can only ever happen once.
+pub struct Sender<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Send `value` to the receiver. If the receiver has been dropped, we give the value back.
+    ///
+    /// Never blocks or allocates, so it is safe to call from an interrupt handler.
+    pub fn send(self, value: T) -> Result<(), T> {
+        // Claim the cell. This fails if the receiver is already gone
+        if self.inner.state.compare_exchange(EMPTY, WRITING, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            return Err(value);
+        }
+
+        unsafe { *self.inner.value.get() = Some(value) };
+        self.inner.state.store(FULL, Ordering::Release); // publish the value before we wake, so the receiver can see it
+        self.inner.waker.wake();
+        Ok(())
+    }
+
+    /// Check whether the receiver has been dropped (so sending would be pointless)
+    pub fn is_canceled(&self) -> bool {
+        self.inner.state.load(Ordering::Acquire) == CLOSED
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // If we never sent anything, let the receiver know nothing is coming. After a successful
+        // send the state is `FULL`, so this does nothing
+        if self.inner.state.compare_exchange(EMPTY, CLOSED, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+            self.inner.waker.wake();
+        }
+    }
+}
+
+/// # Receiver
+///
+/// The receiving half of a oneshot channel. `await` it to get the value, or `Canceled` if the sender
+/// was dropped without sending.
+pub struct Receiver<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Check for the value without waiting
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        match self.inner.state.load(Ordering::Acquire) {
+            FULL => Ok(self.take()),
+            CLOSED => Err(TryRecvError::Closed),
+            TAKEN => panic!("oneshot value already received"),
+            _ => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Take the value out of the cell. Only call when the state is `FULL`
+    fn take(&mut self) -> T {
+        self.inner.state.store(TAKEN, Ordering::Relaxed); // we're the only one allowed to leave `FULL`
+        unsafe { (*self.inner.value.get()).take() }.expect("oneshot state was FULL with no value")
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, Canceled>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        // fast path - the value is already here
+        match this.try_recv() {
+            Ok(value) => return Poll::Ready(Ok(value)),
+            Err(TryRecvError::Closed) => return Poll::Ready(Err(Canceled)),
+            Err(TryRecvError::Empty) => {}
+        }
+
+        // register, then check again so we don't miss a send that happened in between
+        this.inner.waker.register(cx.waker());
+        match this.try_recv() {
+            Ok(value) => Poll::Ready(Ok(value)),
+            Err(TryRecvError::Closed) => Poll::Ready(Err(Canceled)),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        // Tell the sender we're gone. If a value was already sent it gets dropped along with `Inner`
+        let _ = self.inner.state.compare_exchange(EMPTY, CLOSED, Ordering::AcqRel, Ordering::Relaxed);
+    }
+}
+
+/* Testing */
+
+// The value sent should be the value received, whether we wait for it or not
+#[test_case]
+fn test_oneshot_send_recv() {
+    use futures_util::task::noop_waker_ref;
+
+    let (sender, mut receiver) = channel();
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+    assert_eq!(sender.send(5), Ok(()));
+    assert_eq!(receiver.try_recv(), Ok(5));
+
+    let (sender, mut receiver) = channel();
+    let mut cx = Context::from_waker(noop_waker_ref());
+    assert_eq!(Pin::new(&mut receiver).poll(&mut cx), Poll::Pending);
+    sender.send(6).unwrap();
+    assert_eq!(Pin::new(&mut receiver).poll(&mut cx), Poll::Ready(Ok(6)));
+}
+
+// Dropping the sender without sending should cancel the receiver
+#[test_case]
+fn test_oneshot_sender_dropped() {
+    use futures_util::task::noop_waker_ref;
+
+    let (sender, mut receiver) = channel::<u32>();
+    drop(sender);
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Closed));
+    let mut cx = Context::from_waker(noop_waker_ref());
+    assert_eq!(Pin::new(&mut receiver).poll(&mut cx), Poll::Ready(Err(Canceled)));
+}
+
+// Once the receiver is gone, the sender should see it and get its value back
+#[test_case]
+fn test_oneshot_receiver_dropped() {
+    let (sender, receiver) = channel();
+    assert!(!sender.is_canceled());
+    drop(receiver);
+    assert!(sender.is_canceled());
+    assert_eq!(sender.send(7), Err(7));
+}
@@ -86,6 +86,8 @@ impl Executor {
     fn sleep_if_idle(&self) {
         use x86_64::instructions::interrupts::{self, enable_interrupts_and_hlt};
 
+        crate::interrupts::report_nmis(); // the NMI handler can't print, so we do it here
+
         interrupts::disable(); // We should disable interrupts before checking the task queue, as between checking the task queue and sleeping,
                                // another interrupt could fire
         if self.task_queue.is_empty() {
@@ -1,5 +1,7 @@
 pub mod simple_executor; // very basic, barebones executor (Executors manage the current tasks running)
 pub mod executor; // Much better executor
+pub mod smp_executor; // Multi-core, work stealing executor
+pub mod channel; // Async channels (oneshot, mpsc, broadcast) for talking between tasks and interrupt handlers
 
 use core::{future::Future, pin::Pin}; // Get the pin and futures we need to use async - pin works by making sure the position of the future
                                       // on the heap doesn't move, but instead stays (Which is important when multitasking!). It 'pins' it :D
@@ -45,4 +47,46 @@ impl Task {
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context) // the poll method requires a mutable future, so we borrow the pin as a mutable ref
     }
-}
\ No newline at end of file
+}
+
+/// # block_on
+///
+/// Run `future` to completion on this core, halting until it is woken in between polls. For code that
+/// isn't async itself but needs something that is - like a driver reading a partition table while it
+/// probes, or a test. Interrupts must be enabled, or a future waiting for one never finishes. A wake up from
+/// another core is noticed at the next interrupt here (the timer, at worst).
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    use alloc::sync::Arc;
+    use alloc::task::Wake;
+    use core::sync::atomic::AtomicBool;
+    use core::task::Waker;
+    use x86_64::instructions::interrupts;
+
+    struct Woken(AtomicBool);
+
+    impl Wake for Woken {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::Release);
+        }
+    }
+
+    let woken = Arc::new(Woken(AtomicBool::new(true)));
+    let waker = Waker::from(woken.clone());
+    let mut context = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+
+    loop {
+        if woken.0.swap(false, Ordering::AcqRel) {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+        }
+        // Check again with interrupts off, so a wake up can't slip in between the check and the `hlt`
+        interrupts::disable();
+        if woken.0.load(Ordering::Acquire) {
+            interrupts::enable();
+        } else {
+            interrupts::enable_and_hlt();
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::interrupts::InterruptIndex;
+use crate::smp::{self, MAX_CPUS};
+use crate::{apic, serial_println};
+
+/// How many tasks each core's run queue can hold
+const QUEUE_CAPACITY: usize = 256;
+/// How many times `run_task` tries to take a task another core is still polling, backing off a little longer
+/// each time, before it puts the task back on a run queue
+const LOCK_ATTEMPTS: u32 = 8;
+
+/// Initial value for the per-core flags. A named const lets us use it in an array repeat expression
+const FLAG_INIT: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// # SMP_EXECUTOR
+    ///
+    /// The global multi-core executor. Every core that wants to run tasks calls `SMP_EXECUTOR.run()`,
+    /// and tasks can be spawned onto it from any core.
+    pub static ref SMP_EXECUTOR: SmpExecutor = SmpExecutor::new();
+}
+
+/// # SmpExecutor
+///
+/// An executor that runs tasks on every core that calls [run](struct.SmpExecutor.html#method.run).
+///
+/// Each core has its own run queue. A woken task goes back onto the queue of the core it last ran on
+/// (its cache is probably still warm there). When a core runs out of work, it steals half of another
+/// core's queue. When a core runs out of work *and* there's nothing to steal, it halts - and whoever
+/// queues work for it later sends it a wake up IPI.
+///
+/// Tasks can be polled by any core, so their futures must be `Send`.
+pub struct SmpExecutor {
+    /// One run queue per core
+    queues: Vec<ArrayQueue<Arc<SmpTask>>>,
+    /// Set while a core is halted in `sleep_if_idle`, so wakers know to send it an IPI
+    idle: [AtomicBool; MAX_CPUS],
+    /// Set once a core has entered `run`. We don't queue woken tasks for cores that aren't running
+    online: [AtomicBool; MAX_CPUS],
+    /// Number of tasks that haven't finished yet
+    live_tasks: AtomicUsize,
+}
+
+impl SmpExecutor {
+    /// Create a new executor. You probably want the global [SMP_EXECUTOR](struct.SMP_EXECUTOR.html) instead
+    pub fn new() -> Self {
+        serial_println!("Initialized SMP task executor");
+
+        let mut queues = Vec::with_capacity(MAX_CPUS);
+        for _ in 0..MAX_CPUS {
+            queues.push(ArrayQueue::new(QUEUE_CAPACITY));
+        }
+
+        SmpExecutor {
+            queues,
+            idle: [FLAG_INIT; MAX_CPUS],
+            online: [FLAG_INIT; MAX_CPUS],
+            live_tasks: AtomicUsize::new(0),
+        }
+    }
+
+    /// Spawn a new task onto the calling core's run queue. Other cores will steal it if we're busy.
+    pub fn spawn(&'static self, future: impl Future<Output = ()> + Send + 'static) {
+        let task = Arc::new(SmpTask {
+            future: Mutex::new(Some(Box::pin(future))),
+            queued: AtomicBool::new(true),
+            cpu: AtomicUsize::new(smp::current_cpu()),
+            executor: self,
+        });
+
+        self.live_tasks.fetch_add(1, Ordering::Relaxed);
+        self.schedule(task);
+    }
+
+    /// The number of spawned tasks that haven't finished yet
+    pub fn task_count(&self) -> usize {
+        self.live_tasks.load(Ordering::Relaxed)
+    }
+
+    /// Run tasks on the calling core, forever. Call this once on every core that should take part.
+    pub fn run(&'static self) -> ! {
+        let cpu = smp::current_cpu();
+        self.online[cpu].store(true, Ordering::SeqCst);
+        serial_println!("[LOG] CPU {} joined the SMP executor", cpu);
+
+        loop {
+            while let Some(task) = self.next_task(cpu) {
+                self.run_task(cpu, task);
+            }
+            self.sleep_if_idle(cpu); // sleep if idle :P
+        }
+    }
+}
+
+impl SmpExecutor {
+    /// Put a task on the run queue of the core it last ran on, and make sure someone will pick it up.
+    ///
+    /// Doesn't allocate, so it is safe to call from interrupt handlers (wakers end up here).
+    fn schedule(&self, task: Arc<SmpTask>) {
+        let current = smp::current_cpu();
+        let mut home = task.cpu.load(Ordering::Relaxed);
+        if !self.online[home].load(Ordering::Relaxed) {
+            // The task was spawned on a core that isn't running the executor (nobody would ever pop it
+            // there), so send it to one that is
+            if let Some(cpu) = (0..MAX_CPUS).find(|&cpu| self.online[cpu].load(Ordering::Relaxed)) {
+                home = cpu;
+            }
+        }
+
+        // Try the home core first, then everyone else, in case the home queue is full
+        let mut task = Some(task);
+        let mut target = home;
+        for offset in 0..MAX_CPUS {
+            let cpu = (home + offset) % MAX_CPUS;
+            if cpu != home && !self.online[cpu].load(Ordering::Relaxed) {
+                continue;
+            }
+            match self.queues[cpu].push(task.take().unwrap()) {
+                Ok(()) => {
+                    target = cpu;
+                    break;
+                }
+                Err(err) => task = Some(err.0),
+            }
+        }
+        if task.is_some() {
+            panic!("every SMP executor run queue is full");
+        }
+
+        // Pairs with the fence in `sleep_if_idle` - either the core sees our task, or we see it is idle
+        fence(Ordering::SeqCst);
+
+        if target != current && self.idle[target].load(Ordering::SeqCst) {
+            self.kick(target);
+        } else if target != current {
+            // The target core is busy. Wake any idle core, so it can steal the task instead of waiting
+            if let Some(idle_cpu) = (0..MAX_CPUS).find(|&cpu| cpu != current && self.idle[cpu].load(Ordering::SeqCst)) {
+                self.kick(idle_cpu);
+            }
+        }
+    }
+
+    /// Send a wake up IPI to a halted core
+    fn kick(&self, cpu: usize) {
+        if apic::is_initialized() {
+            apic::send_ipi(smp::apic_id(cpu), InterruptIndex::ApicWakeup as u8);
+        }
+    }
+
+    /// Get the next task for `cpu` - from its own queue, or stolen from another core
+    fn next_task(&self, cpu: usize) -> Option<Arc<SmpTask>> {
+        if let Ok(task) = self.queues[cpu].pop() {
+            return Some(task);
+        }
+        self.steal(cpu)
+    }
+
+    /// Steal half of the first non-empty queue we find. We run the first stolen task, and move the
+    /// rest onto our own queue.
+    fn steal(&self, cpu: usize) -> Option<Arc<SmpTask>> {
+        for offset in 1..MAX_CPUS {
+            // We don't skip cores that aren't running the executor - tasks spawned before any core joined
+            // end up on their queues, and stealing is the only way they get run
+            let victim = (cpu + offset) % MAX_CPUS;
+            let queue = &self.queues[victim];
+            let first = match queue.pop() {
+                Ok(task) => task,
+                Err(_) => continue,
+            };
+
+            let to_steal = queue.len() / 2;
+            for _ in 0..to_steal {
+                match queue.pop() {
+                    Ok(task) => {
+                        if let Err(err) = self.queues[cpu].push(task) {
+                            let _ = queue.push(err.0); // our queue is full, give it back
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+
+            return Some(first);
+        }
+
+        None
+    }
+
+    /// Poll a task once on `cpu`
+    fn run_task(&self, cpu: usize, task: Arc<SmpTask>) {
+        // Another core could still be polling this task (it was woken during its poll and we stole it).
+        // Polls are short, so give it a moment to finish. If it still hasn't, put the task back - it'll be
+        // picked up again once the other core is done with it
+        let mut future_slot = None;
+        for attempt in 0..LOCK_ATTEMPTS {
+            future_slot = task.future.try_lock();
+            if future_slot.is_some() {
+                break;
+            }
+            for _ in 0..16 << attempt {
+                core::hint::spin_loop();
+            }
+        }
+        let mut future_slot = match future_slot {
+            Some(slot) => slot,
+            None => {
+                self.schedule(task.clone());
+                return;
+            }
+        };
+
+        // Clear the flag *before* polling, so a wake during the poll queues the task again
+        task.queued.store(false, Ordering::SeqCst);
+        task.cpu.store(cpu, Ordering::Relaxed);
+
+        let future = match future_slot.as_mut() {
+            Some(future) => future,
+            None => return, // task already finished, this was a stale wake up
+        };
+
+        let waker = Waker::from(task.clone());
+        let mut context = Context::from_waker(&waker);
+        if let Poll::Ready(()) = future.as_mut().poll(&mut context) {
+            *future_slot = None; // drop the future now, rather than when the last waker goes away
+            self.live_tasks.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// If there's no work anywhere, we should hlt to avoid wasting precious CPU time.
+    fn sleep_if_idle(&self, cpu: usize) {
+        use x86_64::instructions::interrupts::{self, enable_interrupts_and_hlt};
+
+        crate::interrupts::report_nmis(); // the NMI handler can't print, so we do it here
+
+        interrupts::disable(); // We should disable interrupts before checking the queues, as between checking and sleeping,
+                               // another interrupt could fire
+        self.idle[cpu].store(true, Ordering::SeqCst);
+        fence(Ordering::SeqCst); // Pairs with the fence in `schedule`
+
+        let has_work = (0..MAX_CPUS).any(|other| !self.queues[other].is_empty());
+        if has_work {
+            interrupts::enable(); // we have tasks to run, just re-enable interrupts and don't halt
+        } else {
+            enable_interrupts_and_hlt(); // We re-enable interrupts and halt, until a wake up IPI (or any interrupt) arrives
+        }
+
+        self.idle[cpu].store(false, Ordering::SeqCst);
+    }
+}
+
+/// # SmpTask
+///
+/// A spawned task. It is shared (through an `Arc`) between the run queues and its wakers.
+struct SmpTask {
+    /// `None` once the task has finished
+    future: Mutex<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+    /// Set while the task is sitting in a run queue, so waking it twice doesn't queue it twice
+    queued: AtomicBool,
+    /// The core this task last ran on
+    cpu: AtomicUsize,
+    executor: &'static SmpExecutor,
+}
+
+impl SmpTask {
+    /// Queue the task again, unless it is already queued
+    fn wake_task(self: &Arc<Self>) {
+        if !self.queued.swap(true, Ordering::SeqCst) {
+            self.executor.schedule(self.clone());
+        }
+    }
+}
+
+// This allows an `Arc<SmpTask>` to be used directly as a Waker - the task is its own waker
+impl Wake for SmpTask {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
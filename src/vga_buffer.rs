@@ -1,6 +1,6 @@
 use volatile::Volatile; // Helps prevent the optimizer optimizing our buffer
 use lazy_static::lazy_static; // Allows us to create static structs
-use spin::Mutex; // no_std mutex
+use crate::sync::IrqSpinlock; // Lock that keeps interrupts off while held, so an interrupt handler printing can't deadlock us
 use core::fmt; // Lets us format strings easily
 
 
@@ -13,10 +13,11 @@ lazy_static! {
     /// 
     /// a raw pointer to the VGA buffer.
     /// 
-    /// Can be modified when you take a [Mutex](../../spin/struct.Mutex.html) and lock it.
+    /// Can be modified when you take an [IrqSpinlock](../sync/irq_spinlock/struct.IrqSpinlock.html) and lock it. Interrupt handlers
+    /// print too, so the lock keeps interrupts disabled while it is held.
     /// 
     /// Not doing this results in unsafe code, as you potentially cause a [race condition](https://doc.rust-lang.org/nomicon/races.html)
-    pub static ref WRITER_GLOBAL: Mutex<Writer> = Mutex::new(Writer {
+    pub static ref WRITER_GLOBAL: IrqSpinlock<Writer> = IrqSpinlock::new(Writer {
         column_position: 0,
         color_code: ColorCode::new(Color::White, Color::Blue),
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
@@ -99,11 +100,11 @@ struct Buffer {
 /// 
 /// ```
 /// use lazy_static::lazy_static; // Allows us to create static structs
-/// use spin::Mutex; // no_std mutex
+/// use dbos::sync::IrqSpinlock; // no_std lock that also keeps interrupts off while held
 /// 
-/// // Create a global static writer (In a mutex so that we don't run into race conditions)
+/// // Create a global static writer (In a lock so that we don't run into race conditions)
 /// lazy_static! {
-///    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+///    pub static ref WRITER: IrqSpinlock<Writer> = IrqSpinlock::new(Writer {
 ///     column_position: 0,
 ///     color_code: ColorCode::new(Color::White, Color::Blue),
 ///     buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
@@ -317,44 +318,26 @@ macro_rules! del_col {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(|| {     // Make sure that no interrupt can run while the writer is locked
-        WRITER_GLOBAL.lock().write_fmt(args).unwrap();
-    });
+    WRITER_GLOBAL.lock().write_fmt(args).unwrap(); // The lock disables interrupts while it is held
 }
 
 /// Create a _read function that reads the last row, and returns a string value
 pub fn readln() -> [u8; BUFFER_WIDTH] {
-    use core::fmt::Write;
-    use x86_64::instructions::interrupts;
-    let mut row_bytes: [u8; BUFFER_WIDTH] = [0; BUFFER_WIDTH];
-    interrupts::without_interrupts(|| {     // Make sure that no interrupt can run while the writer is locked
-        row_bytes = WRITER_GLOBAL.lock().read().unwrap();
-    });
-
-    row_bytes
+    WRITER_GLOBAL.lock().read().unwrap()
 }
 
 /// Create a _clear_screen function that clears the screen contents.
 /// Useful for wiping screen contents (IE, if you want to reset or something)
 #[doc(hidden)]
 pub fn _clear_screen() {
-    use x86_64::instructions::interrupts;
-
-        interrupts::without_interrupts(|| {     // Make sure that no interrupt can run while the writer is locked
-        WRITER_GLOBAL.lock().clear_screen();
-    });
+    WRITER_GLOBAL.lock().clear_screen();
 }
 
 /// Deletes the last character in the column - useful for backspace
 #[doc(hidden)]
 pub fn _backspace() {
-    use x86_64::instructions::interrupts;
-
-        interrupts::without_interrupts(|| {     // Make sure that no interrupt can run while the writer is locked
-        WRITER_GLOBAL.lock().backspace();
-    });
+    WRITER_GLOBAL.lock().backspace();
 }
 
 /* Tests */
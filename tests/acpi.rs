@@ -0,0 +1,83 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(dbos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*
+    Integration test to check that we find and parse the ACPI tables QEMU gives us
+    (the default `pc` machine, run with `-smp 4`)
+*/
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use dbos::{acpi, allocator};
+    use dbos::memory;
+    use x86_64::VirtAddr;
+
+    dbos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+    acpi::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    dbos::test_panic_handler(info)
+}
+
+use dbos::acpi;
+
+// One MADT entry per core, all usable
+#[test_case]
+fn madt_lists_processors() {
+    let processors = acpi::processors();
+    assert_eq!(processors.len(), 4);
+    assert!(processors.iter().all(|p| p.enabled));
+}
+
+// QEMU wires the PIT (IRQ 0) to GSI 2, and has a single I/O APIC
+#[test_case]
+fn madt_interrupt_overrides() {
+    let timer = acpi::isa_irq_override(0).expect("no override for IRQ 0");
+    assert_eq!(timer.gsi, 2);
+    assert_eq!(dbos::interrupts::isa_irq_route(0).gsi, 2);
+    assert_eq!(dbos::interrupts::isa_irq_route(1).gsi, 1); // no override, identity mapped
+
+    let madt = acpi::tables().unwrap().madt.as_ref().unwrap();
+    assert_eq!(madt.io_apics.len(), 1);
+}
+
+#[test_case]
+fn fadt_and_dsdt() {
+    let fadt = acpi::fadt().expect("no FADT");
+    assert_eq!(fadt.sci_interrupt, 9);
+    assert_ne!(fadt.pm1a_control_block, 0);
+
+    let dsdt = acpi::dsdt().expect("no DSDT");
+    assert_eq!(&dsdt.header.signature, b"DSDT");
+    assert!(!dsdt.aml().is_empty());
+}
+
+#[test_case]
+fn hpet_present() {
+    let hpet = acpi::hpet().expect("no HPET");
+    assert_eq!(hpet.base_address.as_u64(), 0xFED0_0000);
+    assert!(hpet.comparator_count >= 3);
+}
+
+// The `pc` machine is PCI, not PCI Express, so there's no MCFG
+#[test_case]
+fn no_ecam_on_pc() {
+    assert!(acpi::ecam_regions().is_empty());
+}
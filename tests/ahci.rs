@@ -0,0 +1,50 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(dbos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*
+    Integration test for the AHCI driver - the test arguments in Cargo.toml add an ich9-ahci controller with a
+    64 MiB disk on port 0, which reads as zeroes and throws writes away. It shows up as `sata0`
+*/
+
+extern crate alloc;
+
+mod common;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    common::boot(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    dbos::test_panic_handler(info)
+}
+
+use dbos::block;
+use dbos::driver::{registry, Bus};
+
+// The controller is bound, and its disk is registered with the size QEMU gave it
+#[test_case]
+fn disk_registered() {
+    let controller = registry::devices_on(Bus::Pci).into_iter().find(|device| registry::driver_of(device.id) == Some("ahci"));
+    assert!(controller.is_some(), "AHCI controller not bound");
+    let disk = block::get("sata0").expect("no sata0");
+    assert_eq!(disk.sector_size(), 512);
+    assert_eq!(disk.sector_count(), 64 * 1024 * 1024 / 512);
+}
+
+// Reads, writes and flushes complete (through the interrupt), including reads split over several commands
+// and many in flight at once, in separate command slots (and NCQ tags, if the drive has NCQ)
+#[test_case]
+fn null_disk() {
+    common::null_disk(&*block::get("sata0").unwrap(), 8);
+}
@@ -0,0 +1,94 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(dbos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*
+    Integration test to check that the AML interpreter loads QEMU's DSDT (the default `pc` machine)
+    and can evaluate the objects we care about
+*/
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use dbos::{acpi, allocator};
+    use dbos::memory;
+    use x86_64::VirtAddr;
+
+    dbos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+    acpi::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    dbos::test_panic_handler(info)
+}
+
+use alloc::format;
+use alloc::vec::Vec;
+use dbos::acpi::aml::{self, AmlValue};
+
+// `\_S5` is a package - the PM1a and PM1b sleep types for soft off come first
+#[test_case]
+fn s5_package() {
+    let s5 = aml::evaluate("\\_S5", Vec::new()).expect("couldn't evaluate \\_S5");
+    let elements = s5.as_package().expect("\\_S5 isn't a package");
+    assert!(elements.len() >= 2);
+    assert!(elements[0].as_integer().is_ok());
+}
+
+// The PCI host bridge is `\_SB.PCI0`, a PNP0A03 (plain PCI) on i440fx
+#[test_case]
+fn pci_host_bridge() {
+    let hid = aml::evaluate("\\_SB.PCI0._HID", Vec::new());
+    assert_eq!(hid, Ok(AmlValue::Integer(0x030AD041)));
+}
+
+// `_PRT` is a method that builds the routing table in a loop. Every entry is { address, pin, link, index }
+#[test_case]
+fn pci_interrupt_routing() {
+    let prt = aml::evaluate("\\_SB.PCI0._PRT", Vec::new()).expect("couldn't evaluate _PRT");
+    let entries = prt.as_package().expect("_PRT isn't a package");
+    assert!(!entries.is_empty());
+    for entry in entries {
+        let entry = entry.as_package().expect("_PRT entry isn't a package");
+        assert_eq!(entry.len(), 4);
+        assert_eq!(entry[0].as_integer().map(|address| address & 0xFFFF), Ok(0xFFFF));
+        match &entry[2] {
+            AmlValue::Reference(link) => assert!(link.starts_with("\\_SB_.LNK")),
+            other => panic!("_PRT link isn't a reference: {:?}", other),
+        }
+    }
+}
+
+// The HPET's `_STA` reads its registers through a SystemMemory operation region
+#[test_case]
+fn hpet_status() {
+    let hpet = aml::with_interpreter(|interpreter| interpreter.devices())
+        .expect("no AML namespace")
+        .into_iter()
+        .find(|device| device.ends_with(".HPET"))
+        .expect("no HPET device");
+    let sta = aml::evaluate(&format!("{}._STA", hpet), Vec::new());
+    assert_eq!(sta, Ok(AmlValue::Integer(0x0F)));
+}
+
+// The host bridge and the ISA bridge behind it are both devices
+#[test_case]
+fn devices_enumerated() {
+    let devices = aml::with_interpreter(|interpreter| interpreter.devices()).expect("no AML namespace");
+    assert!(devices.iter().any(|device| device == "\\_SB_.PCI0"));
+    assert!(devices.iter().any(|device| device.starts_with("\\_SB_.PCI0.ISA_")));
+}
@@ -0,0 +1,89 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(dbos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*
+    Integration test for the ATA driver - QEMU attaches the boot disk as the primary master of the PIIX3 IDE
+    controller, so it shows up as `ata0`. The test arguments in Cargo.toml add a scratch disk as the primary
+    slave (`ata1`), which is what gets written to
+*/
+
+extern crate alloc;
+
+mod common;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    common::boot(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    dbos::test_panic_handler(info)
+}
+
+use alloc::vec;
+use dbos::block::{self, BlockError};
+use dbos::task::block_on;
+
+// The boot disk is found and registered
+#[test_case]
+fn boot_disk_registered() {
+    let disk = block::get("ata0").expect("no ata0");
+    assert_eq!(disk.sector_size(), 512);
+    assert!(disk.sector_count() > 0);
+}
+
+// Sector 0 is the boot sector, which ends in the 0x55AA signature
+#[test_case]
+fn read_boot_sector() {
+    let disk = block::get("ata0").unwrap();
+    let mut sector = vec![0u8; 512];
+    block_on(disk.read(0, &mut sector)).expect("read failed");
+    assert_eq!(&sector[510..], &[0x55, 0xAA]);
+}
+
+// Multi-sector reads agree with single sector ones
+#[test_case]
+fn read_many() {
+    let disk = block::get("ata0").unwrap();
+    let mut both = vec![0u8; 1024];
+    let mut second = vec![0u8; 512];
+    block_on(disk.read(0, &mut both)).unwrap();
+    block_on(disk.read(1, &mut second)).unwrap();
+    assert_eq!(&both[512..], &second[..]);
+}
+
+// Write the scratch disk's last sector and read it back. Never the boot disk - a failed assert would leave
+// it corrupted
+#[test_case]
+fn write_round_trip() {
+    let disk = block::get("ata1").expect("no scratch disk");
+    assert_eq!(disk.sector_count(), 32 * 1024 * 1024 / 512);
+    let last = disk.sector_count() - 1;
+
+    let pattern: alloc::vec::Vec<u8> = (0..1024).map(|i| (i * 7) as u8).collect();
+    block_on(disk.write(last - 1, &pattern)).expect("write failed");
+    block_on(disk.flush()).expect("flush failed");
+    let mut read_back = vec![0u8; 1024];
+    block_on(disk.read(last - 1, &mut read_back)).unwrap();
+    assert_eq!(read_back, pattern);
+}
+
+// Requests past the end of the disk, or that aren't whole sectors, fail without touching the drive
+#[test_case]
+fn bad_requests() {
+    let disk = block::get("ata0").unwrap();
+    let mut sector = vec![0u8; 512];
+    assert_eq!(block_on(disk.read(disk.sector_count(), &mut sector)), Err(BlockError::OutOfRange));
+    let mut partial = vec![0u8; 100];
+    assert_eq!(block_on(disk.read(0, &mut partial)), Err(BlockError::UnalignedBuffer));
+}
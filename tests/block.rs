@@ -0,0 +1,208 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(dbos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*
+    Integration test for the block layer - partition tables, request queues and the buffer cache, all on RAM
+    disks, so no drivers are needed
+*/
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use dbos::allocator;
+    use dbos::memory;
+    use x86_64::VirtAddr;
+
+    dbos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    dbos::test_panic_handler(info)
+}
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use dbos::block::cache::BufferCache;
+use dbos::block::partition::{self, PartitionKind};
+use dbos::block::queue::RequestQueue;
+use dbos::block::ramdisk::{self, RamDisk};
+use dbos::block::{self, BlockDevice};
+use dbos::task::block_on;
+
+/// A 16 MiB RAM disk
+fn disk() -> Arc<RamDisk> {
+    Arc::new(RamDisk::new(ramdisk::next_name(), 512, 32768))
+}
+
+/// An MBR (or extended boot record) with `entries` - type, start and sector count
+fn boot_record(entries: &[(u8, u32, u32)]) -> Vec<u8> {
+    let mut sector = vec![0u8; 512];
+    for (index, &(kind, start, count)) in entries.iter().enumerate() {
+        let entry = 446 + index * 16;
+        sector[entry + 4] = kind;
+        sector[entry + 8..entry + 12].copy_from_slice(&start.to_le_bytes());
+        sector[entry + 12..entry + 16].copy_from_slice(&count.to_le_bytes());
+    }
+    sector[510] = 0x55;
+    sector[511] = 0xAA;
+    sector
+}
+
+/// Write a GPT with one partition per entry of `partitions` - name, first and last sector
+fn write_gpt(disk: &RamDisk, partitions: &[(&str, u64, u64)]) {
+    let linux_data = [0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4];
+    let mut entries = vec![0u8; 128 * 128];
+    for (index, &(name, first, last)) in partitions.iter().enumerate() {
+        let entry = &mut entries[index * 128..(index + 1) * 128];
+        entry[..16].copy_from_slice(&linux_data);
+        entry[16] = index as u8 + 1; // unique GUID
+        entry[32..40].copy_from_slice(&first.to_le_bytes());
+        entry[40..48].copy_from_slice(&last.to_le_bytes());
+        for (unit, c) in name.encode_utf16().enumerate() {
+            entry[56 + unit * 2..58 + unit * 2].copy_from_slice(&c.to_le_bytes());
+        }
+    }
+    let mut header = vec![0u8; 512];
+    header[..8].copy_from_slice(b"EFI PART");
+    header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+    header[12..16].copy_from_slice(&92u32.to_le_bytes());
+    header[24..32].copy_from_slice(&1u64.to_le_bytes());
+    header[32..40].copy_from_slice(&(disk.sector_count() - 1).to_le_bytes());
+    header[40..48].copy_from_slice(&34u64.to_le_bytes());
+    header[48..56].copy_from_slice(&(disk.sector_count() - 34).to_le_bytes());
+    header[72..80].copy_from_slice(&2u64.to_le_bytes());
+    header[80..84].copy_from_slice(&128u32.to_le_bytes());
+    header[84..88].copy_from_slice(&128u32.to_le_bytes());
+    header[88..92].copy_from_slice(&partition::crc32(&entries).to_le_bytes());
+    let crc = partition::crc32(&header[..92]);
+    header[16..20].copy_from_slice(&crc.to_le_bytes());
+
+    let protective = boot_record(&[(0xEE, 1, disk.sector_count() as u32 - 1)]);
+    block_on(disk.write(0, &protective)).unwrap();
+    block_on(disk.write(1, &header)).unwrap();
+    block_on(disk.write(2, &entries)).unwrap();
+}
+
+// Primary and logical MBR partitions are registered as block devices, and are windows onto their disk
+#[test_case]
+fn mbr_partitions() {
+    let disk = disk();
+    // Two primary partitions, then an extended one holding two logical partitions
+    block_on(disk.write(0, &boot_record(&[(0x0C, 2048, 4096), (0x83, 8192, 2048), (0x0F, 16384, 8192)]))).unwrap();
+    block_on(disk.write(16384, &boot_record(&[(0x83, 63, 1000), (0x05, 2048, 4096)]))).unwrap();
+    block_on(disk.write(16384 + 2048, &boot_record(&[(0x07, 63, 2000)]))).unwrap();
+
+    let name = String::from(disk.name());
+    block::register(disk.clone());
+    let partition = |number: u32| block::get(&partition::partition_name(&name, number));
+    assert_eq!(partition(1).map(|p| p.sector_count()), Some(4096));
+    assert_eq!(partition(2).map(|p| p.sector_count()), Some(2048));
+    assert!(partition(3).is_none()); // the extended partition itself isn't one
+    assert_eq!(partition(5).map(|p| p.sector_count()), Some(1000));
+    assert_eq!(partition(6).map(|p| p.sector_count()), Some(2000));
+    assert_eq!(partition(5).unwrap().parent(), Some(name.as_str()));
+
+    // Sector 0 of a partition is its first sector on the disk
+    let data = vec![0x5Au8; 512];
+    block_on(partition(6).unwrap().write(0, &data)).unwrap();
+    let mut buffer = vec![0u8; 512];
+    block_on(disk.read(16384 + 2048 + 63, &mut buffer)).unwrap();
+    assert_eq!(buffer, data);
+    assert!(block_on(partition(2).unwrap().read(2048, &mut buffer)).is_err());
+
+    block::unregister(&name);
+    assert!(block::get(&name).is_none());
+    assert!(partition(1).is_none());
+}
+
+// GPT partitions come with their names, and a table with a bad CRC is ignored
+#[test_case]
+fn gpt_partitions() {
+    let disk = disk();
+    write_gpt(&disk, &[("boot", 2048, 4095), ("root", 4096, 32000)]);
+    let table = block_on(partition::read_table(&*disk)).unwrap();
+    assert_eq!(table.len(), 2);
+    assert_eq!((table[0].number, table[0].start, table[0].count), (1, 2048, 2048));
+    assert_eq!((table[1].start, table[1].count), (4096, 32000 - 4096 + 1));
+    match &table[1].kind {
+        PartitionKind::Gpt { name, .. } => assert_eq!(name, "root"),
+        kind => panic!("expected a GPT partition, got {:?}", kind),
+    }
+
+    // Break an entry - the entry CRC no longer matches
+    let mut entries = vec![0u8; 512];
+    block_on(disk.read(2, &mut entries)).unwrap();
+    entries[40] ^= 1;
+    block_on(disk.write(2, &entries)).unwrap();
+    assert!(block_on(partition::read_table(&*disk)).unwrap().is_empty());
+}
+
+// Reads of neighbouring sectors made at the same time go to the disk as one
+#[test_case]
+fn queue_merges() {
+    let disk = disk();
+    let data: Vec<u8> = (0..16 * 512).map(|index| (index / 512) as u8).collect();
+    block_on(disk.write(100, &data)).unwrap();
+    let queue = RequestQueue::new(disk.clone());
+
+    let mut buffers = vec![vec![0u8; 512]; 16];
+    let reads = buffers.iter_mut().enumerate().map(|(index, buffer)| queue.read(100 + index as u64, buffer));
+    for result in block_on(futures_util::future::join_all(reads)) {
+        result.unwrap();
+    }
+    for (index, buffer) in buffers.iter().enumerate() {
+        assert!(buffer.iter().all(|&byte| byte == index as u8));
+    }
+    let stats = queue.stats();
+    assert_eq!(stats.submitted, 16);
+    assert_eq!(stats.dispatched, 1);
+
+    // A write and a read of the same sector stay in order
+    let ones = vec![1u8; 512];
+    let mut buffer = vec![0u8; 512];
+    let (written, read) = block_on(futures_util::future::join(queue.write(100, &ones), queue.read(100, &mut buffer)));
+    written.unwrap();
+    read.unwrap();
+    assert_eq!(buffer, ones);
+    block_on(queue.flush()).unwrap();
+}
+
+// A cache on a queue on a disk - writes stay in the cache until a flush
+#[test_case]
+fn cache_on_queue() {
+    let disk = disk();
+    let cache = BufferCache::new(Arc::new(RequestQueue::new(disk.clone())), 32);
+    let data = vec![7u8; 8 * 512];
+    block_on(cache.write(10, &data)).unwrap();
+    assert_eq!(cache.dirty_count(), 8);
+    assert_eq!(disk.memory_used(), 0);
+
+    block_on(cache.flush()).unwrap();
+    assert_eq!(cache.dirty_count(), 0);
+    let mut buffer = vec![0u8; 8 * 512];
+    block_on(disk.read(10, &mut buffer)).unwrap();
+    assert_eq!(buffer, data);
+
+    // Reading it again is all hits
+    let before = cache.stats();
+    block_on(cache.read(10, &mut buffer)).unwrap();
+    assert_eq!(cache.stats().hits - before.hits, 8);
+    assert_eq!(cache.stats().misses, before.misses);
+}
@@ -0,0 +1,103 @@
+/*
+    Shared by the integration tests - booting with the drivers bound, the checks every disk that reads as zeroes
+    and throws writes away (QEMU's null-co) should pass, and the checks for disks backed by tests/disks/scratch.img
+*/
+
+// Each test binary compiles its own copy, and not every one uses everything
+#![allow(dead_code)]
+
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::BootInfo;
+use dbos::block::{BlockDevice, BlockError};
+use dbos::task::block_on;
+
+/// Set up memory, the heap and DMA pool, ACPI, PCI config space and the local APIC, then bind the drivers
+pub fn boot(boot_info: &'static BootInfo) {
+    use dbos::{acpi, allocator, apic};
+    use dbos::driver::{self, pci};
+    use dbos::memory;
+    use x86_64::VirtAddr;
+
+    dbos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+    memory::dma::init();
+    acpi::init();
+    pci::ecam::init();
+    apic::init();
+    driver::init();
+}
+
+/// Reads (one sector, and ones big enough to be split up), `in_flight` reads at once, writes and flushes all
+/// complete on `disk`, which reads as zeroes. Requests past its end fail without reaching the device
+pub fn null_disk(disk: &dyn BlockDevice, in_flight: usize) {
+    let sector_size = disk.sector_size();
+    for &length in &[sector_size, 64 * 1024, 300 * 1024] {
+        let mut buffer = vec![0xFFu8; length];
+        block_on(disk.read(3, &mut buffer)).expect("read failed");
+        assert!(buffer.iter().all(|&byte| byte == 0));
+    }
+
+    let mut buffers: Vec<Vec<u8>> = (0..in_flight).map(|_| vec![0xFFu8; sector_size]).collect();
+    let reads = buffers.iter_mut().enumerate().map(|(index, buffer)| disk.read(index as u64 * 8, buffer));
+    for result in block_on(futures_util::future::join_all(reads)) {
+        assert_eq!(result, Ok(()));
+    }
+    assert!(buffers.iter().all(|buffer| buffer.iter().all(|&byte| byte == 0)));
+
+    let buffer = vec![0x5Au8; 8 * sector_size];
+    block_on(disk.write(10, &buffer)).expect("write failed");
+    block_on(disk.flush()).expect("flush failed");
+
+    let mut buffer = vec![0u8; 2 * sector_size];
+    assert_eq!(block_on(disk.read(disk.sector_count(), &mut buffer)), Err(BlockError::OutOfRange));
+    assert_eq!(block_on(disk.read(disk.sector_count() - 1, &mut buffer)), Err(BlockError::OutOfRange));
+}
+
+/// The size of tests/disks/scratch.img. Each of its 512 byte sectors is the sector's number, as a little endian
+/// u32, over and over
+pub const SCRATCH_IMAGE_SIZE: u64 = 1024 * 1024;
+
+/// What sector `sector` of tests/disks/scratch.img holds
+fn scratch_sector(sector: u64) -> Vec<u8> {
+    (sector as u32).to_le_bytes().iter().copied().cycle().take(512).collect()
+}
+
+/// `disk` is tests/disks/scratch.img, attached as a snapshot: it reads what the image holds, and what is written
+/// to it reads back - in one sector, and in a transfer big enough to need a scatter/gather (or PRP) list - without
+/// touching the sectors around it
+pub fn scratch_disk(disk: &dyn BlockDevice) {
+    assert_eq!(disk.sector_size(), 512);
+    assert_eq!(disk.sector_count() * 512, SCRATCH_IMAGE_SIZE);
+
+    let mut buffer = vec![0u8; 16 * 1024];
+    block_on(disk.read(0, &mut buffer)).expect("read failed");
+    for (sector, data) in buffer.chunks(512).enumerate() {
+        assert_eq!(data, &scratch_sector(sector as u64)[..], "sector {} isn't what the image holds", sector);
+    }
+
+    // 96 KiB - 24 pages
+    let first = 301;
+    let pattern: Vec<u8> = (0..96 * 1024).map(|index| (index * 13 % 256) as u8 ^ 0xA5).collect();
+    block_on(disk.write(first, &pattern)).expect("write failed");
+    block_on(disk.flush()).expect("flush failed");
+    let mut read_back = vec![0u8; pattern.len()];
+    block_on(disk.read(first, &mut read_back)).expect("read failed");
+    assert!(read_back == pattern, "what was written didn't read back");
+
+    let last = disk.sector_count() - 1;
+    let sector = vec![0x3Cu8; 512];
+    block_on(disk.write(last, &sector)).expect("write failed");
+    let mut read_back = vec![0u8; 512];
+    block_on(disk.read(last, &mut read_back)).expect("read failed");
+    assert_eq!(read_back, sector);
+
+    // The neighbours are untouched
+    let after = first + pattern.len() as u64 / 512;
+    for &neighbour in &[first - 1, after, last - 1] {
+        block_on(disk.read(neighbour, &mut read_back)).expect("read failed");
+        assert_eq!(read_back, scratch_sector(neighbour), "sector {} changed", neighbour);
+    }
+}
@@ -0,0 +1,97 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(dbos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*
+    Integration test for the driver model - devices on QEMU's `pc` machine being added to the registry, and
+    drivers binding to them
+*/
+
+extern crate alloc;
+
+mod common;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    common::boot(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    dbos::test_panic_handler(info)
+}
+
+use alloc::string::ToString;
+use dbos::driver::{pci, registry, Bus, Device, DeviceKind, DeviceMatch, Driver, ProbeError};
+
+// Every PCI function the scan found is in the registry
+#[test_case]
+fn pci_functions_registered() {
+    let functions = pci::enumerate();
+    let devices = registry::devices_on(Bus::Pci);
+    assert_eq!(devices.len(), functions.len());
+    assert!(devices.iter().any(|device| device.name == "0000:00:01.1")); // the PIIX3 IDE controller
+}
+
+// The keyboard driver binds to the PS/2 controller
+#[test_case]
+fn keyboard_bound() {
+    let bound = registry::bound_devices();
+    assert!(bound.iter().any(|(device, driver)| device.name == "i8042" && *driver == "ps2-keyboard"));
+}
+
+// ACPI devices come with their hardware IDs, decoded from EISA IDs
+#[test_case]
+fn acpi_devices_registered() {
+    let devices = registry::devices_on(Bus::Acpi);
+    assert!(devices.iter().any(|device| match &device.kind {
+        DeviceKind::Acpi { hid } => hid == "PNP0A03", // the PCI host bridge
+        _ => false,
+    }));
+}
+
+struct ClassDriver;
+
+impl Driver for ClassDriver {
+    fn name(&self) -> &'static str {
+        "test-vga"
+    }
+
+    fn match_table(&self) -> &'static [DeviceMatch] {
+        &[DeviceMatch::PciClassCode { class: 0x03, subclass: 0x00 }]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        match device.pci_function() {
+            Some(function) if function.vendor_id == 0x1234 => Ok(()),
+            _ => Err(ProbeError::Unsupported),
+        }
+    }
+}
+
+static CLASS_DRIVER: ClassDriver = ClassDriver;
+
+// A driver registered late binds to devices that are already there, and can be unbound again
+#[test_case]
+fn late_driver_binds() {
+    registry::register_driver(&CLASS_DRIVER);
+    let (vga, _) = registry::bound_devices()
+        .into_iter()
+        .find(|(_, driver)| *driver == "test-vga")
+        .expect("test driver didn't bind");
+    assert_eq!(vga.pci_function().map(|function| function.device_id), Some(0x1111));
+
+    registry::unbind(vga.id);
+    assert_eq!(registry::driver_of(vga.id), None);
+    assert!(registry::bind(vga.id));
+    assert_eq!(registry::driver_of(vga.id), Some("test-vga"));
+    assert_eq!(vga.to_string(), alloc::format!("{} pci:0000:00:02.0", vga.id));
+}
@@ -0,0 +1,160 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(dbos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*
+    Integration test for the e1000 driver - the test arguments in Cargo.toml add an e1000 (82540EM, polled)
+    and an e1000e (82574L, with MSI), each on its own QEMU user mode network. The gateway there (10.0.2.2)
+    answers ARP and pings, so we can check frames go both ways without a real network
+*/
+
+extern crate alloc;
+
+mod common;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    common::boot(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    dbos::test_panic_handler(info)
+}
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use dbos::net::{self, MacAddress, NetworkDevice, Received};
+use dbos::task::block_on;
+
+/// The MAC addresses QEMU was given for the e1000 and the e1000e
+const MACS: [MacAddress; 2] = [MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x60]), MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x61])];
+const OUR_IP: [u8; 4] = [10, 0, 2, 15];
+const GATEWAY_IP: [u8; 4] = [10, 0, 2, 2];
+const ETHERTYPE_IPV4: [u8; 2] = [0x08, 0x00];
+const ETHERTYPE_ARP: [u8; 2] = [0x08, 0x06];
+
+/// Both cards, found by their MAC addresses, once their links are up
+fn cards() -> Vec<Arc<dyn NetworkDevice>> {
+    let cards: Vec<Arc<dyn NetworkDevice>> = MACS
+        .iter()
+        .map(|mac| net::devices().into_iter().find(|card| card.mac_address() == *mac).expect("e1000 card not registered"))
+        .collect();
+    // Autonegotiation can take a moment after reset
+    for card in cards.iter() {
+        for _ in 0..1_000_000 {
+            if card.link_up() {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+    cards
+}
+
+/// Receive frames until one matches `wanted`
+fn receive_until(card: &dyn NetworkDevice, wanted: impl Fn(&Received) -> bool) -> Received {
+    for _ in 0..32 {
+        let received = block_on(card.receive()).expect("receive failed");
+        if wanted(&received) {
+            return received;
+        }
+    }
+    panic!("expected frame never arrived");
+}
+
+/// Ask who has `GATEWAY_IP`, and return its MAC address
+fn resolve_gateway(card: &dyn NetworkDevice) -> MacAddress {
+    let mac = card.mac_address();
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&MacAddress::BROADCAST.0);
+    frame.extend_from_slice(&mac.0);
+    frame.extend_from_slice(&ETHERTYPE_ARP);
+    // Ethernet and IPv4 addresses, a request
+    frame.extend_from_slice(&[0, 1, 0x08, 0x00, 6, 4, 0, 1]);
+    frame.extend_from_slice(&mac.0);
+    frame.extend_from_slice(&OUR_IP);
+    frame.extend_from_slice(&[0; 6]);
+    frame.extend_from_slice(&GATEWAY_IP);
+    block_on(card.send(&frame, None)).expect("send failed");
+
+    let reply = receive_until(card, |received| {
+        let frame = &received.frame;
+        frame.len() >= 42 && frame[12..14] == ETHERTYPE_ARP && frame[20..22] == [0, 2] && frame[28..32] == GATEWAY_IP
+    });
+    let mut gateway = [0; 6];
+    gateway.copy_from_slice(&reply.frame[22..28]);
+    MacAddress(gateway)
+}
+
+// Both cards are registered with the MAC addresses from their EEPROMs, and their links come up
+#[test_case]
+fn cards_registered() {
+    for card in cards() {
+        assert!(card.name().starts_with("eth"));
+        assert!(card.link_up());
+        assert_eq!(card.mtu(), net::DEFAULT_MTU);
+        assert!(!card.checksum_offload());
+    }
+}
+
+// Frames of the wrong size are refused
+#[test_case]
+fn bad_frames() {
+    for card in cards() {
+        assert_eq!(block_on(card.send(&[0; 10], None)), Err(net::NetError::BadFrameSize));
+        let huge = vec![0; net::ETHERNET_HEADER_SIZE + card.mtu() + 1];
+        assert_eq!(block_on(card.send(&huge, None)), Err(net::NetError::BadFrameSize));
+    }
+}
+
+// The gateway answers ARP, then pings - more of them than the rings have descriptors, so both wrap around
+#[test_case]
+fn ping_gateway() {
+    for card in cards() {
+        let gateway = resolve_gateway(&*card);
+        assert_ne!(gateway, MacAddress::default());
+
+        for sequence in 0..40u16 {
+            let payload = [sequence as u8; 32];
+            let mut frame = Vec::new();
+            frame.extend_from_slice(&gateway.0);
+            frame.extend_from_slice(&card.mac_address().0);
+            frame.extend_from_slice(&ETHERTYPE_IPV4);
+            let total_length = (20 + 8 + payload.len()) as u16;
+            let mut ip = [0x45, 0, 0, 0, 0x12, 0x34, 0, 0, 64, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+            ip[2..4].copy_from_slice(&total_length.to_be_bytes());
+            ip[12..16].copy_from_slice(&OUR_IP);
+            ip[16..20].copy_from_slice(&GATEWAY_IP);
+            let checksum = net::internet_checksum(&ip);
+            ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+            frame.extend_from_slice(&ip);
+            // Echo request, identifier 0xBEEF. The driver finishes the checksum in software
+            frame.extend_from_slice(&[8, 0, 0, 0, 0xBE, 0xEF]);
+            frame.extend_from_slice(&sequence.to_be_bytes());
+            frame.extend_from_slice(&payload);
+            block_on(card.send(&frame, Some(net::PartialChecksum { start: 34, offset: 2 }))).expect("send failed");
+
+            let reply = receive_until(&*card, |received| {
+                let frame = &received.frame;
+                frame.len() >= 42
+                    && frame[12..14] == ETHERTYPE_IPV4
+                    && frame[23] == 1
+                    && frame[34] == 0
+                    && frame[38..40] == [0xBE, 0xEF]
+                    && frame[40..42] == sequence.to_be_bytes()
+            });
+            assert_eq!(&reply.frame[42..42 + payload.len()], &payload);
+            assert_eq!(net::internet_checksum(&reply.frame[34..42 + payload.len()]), 0);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Makes the FAT12, FAT16 and FAT32 disk images the FAT test runs on, with `mkfs.fat`, and copies the files in
+//! `tests/fat/` onto them with mtools. They are made in `OUT_DIR`, then written out again without their empty
+//! chunks (see `sparse`) for `lib.rs` to include - so only the FAT test has them, and it loads them onto RAM
+//! disks. Without `mkfs.fat` (dosfstools) or mtools the build fails, rather than the test checking nothing.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// The files copied onto each image
+const FIXTURES: &str = "../fat";
+/// Each image - its FAT size, its size in KiB, and any more `mkfs.fat` arguments
+const IMAGES: [(u32, u64, &[&str]); 3] = [(12, 1440, &[]), (16, 16 * 1024, &[]), (32, 40 * 1024, &["-s", "1"])];
+/// The size of the generated file copied onto each image, `big.bin`, which spans many clusters
+const BIG_FILE: usize = 100 * 1024;
+
+/// The size of the chunks the sparse images leave out when they're all zeros. `lib.rs` has the same
+const CHUNK_SIZE: usize = 4096;
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", FIXTURES);
+    let directory = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+    // `big.bin` counts up through every byte value except the last few, so a cluster out of place shows
+    let big = directory.join("big.bin");
+    fs::write(&big, (0..BIG_FILE).map(|index| (index % 251) as u8).collect::<Vec<u8>>()).expect("Couldn't write big.bin");
+    let mut fixtures: Vec<PathBuf> = fs::read_dir(FIXTURES)
+        .expect("Couldn't read the FAT fixtures")
+        .map(|entry| entry.expect("Couldn't read the FAT fixtures").path())
+        .collect();
+    fixtures.sort();
+    fixtures.push(big);
+
+    for (bits, size, arguments) in IMAGES.iter() {
+        let image = directory.join(format!("fat{}.img", bits));
+        let _ = fs::remove_file(&image);
+        run(Command::new("mkfs.fat")
+            .args(&["-C", "-F", &bits.to_string(), "-n", &format!("FAT{}", bits)])
+            .args(arguments.iter())
+            .arg(&image)
+            .arg(size.to_string()));
+        for fixture in fixtures.iter() {
+            // Don't let mtools complain about the geometry of an image without a partition table
+            run(Command::new("mcopy").env("MTOOLS_SKIP_CHECK", "1").args(&["-s", "-i"]).arg(&image).arg(fixture).arg("::/"));
+        }
+        let contents = fs::read(&image).expect("Couldn't read back a FAT image");
+        fs::write(directory.join(format!("fat{}.sparse", bits)), sparse(&contents)).expect("Couldn't write a FAT image");
+    }
+}
+
+/// `image` without its empty chunks: its size (a little endian u64), then each `CHUNK_SIZE` chunk that isn't all
+/// zeros, after its offset (a little endian u64). The FAT32 image is 40 MiB, but only a few hundred KiB of it
+/// isn't empty
+fn sparse(image: &[u8]) -> Vec<u8> {
+    assert_eq!(image.len() % CHUNK_SIZE, 0, "FAT images should be a whole number of chunks");
+    let mut sparse = (image.len() as u64).to_le_bytes().to_vec();
+    for (index, chunk) in image.chunks(CHUNK_SIZE).enumerate() {
+        if chunk.iter().any(|&byte| byte != 0) {
+            sparse.extend_from_slice(&((index * CHUNK_SIZE) as u64).to_le_bytes());
+            sparse.extend_from_slice(chunk);
+        }
+    }
+    sparse
+}
+
+/// Run `command`, failing the build if it doesn't work
+fn run(command: &mut Command) {
+    match command.status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => panic!("{:?} failed ({})", command, status),
+        Err(error) => panic!("Couldn't run {:?} ({}) - the FAT test needs dosfstools and mtools", command, error),
+    }
+}
@@ -0,0 +1,33 @@
+//! The FAT12, FAT16 and FAT32 images the FAT test runs on. `build.rs` makes them, and leaves out the parts that
+//! are all zeros - so they're small enough to include in the test, which loads them onto RAM disks
+
+#![no_std]
+
+use core::convert::TryInto;
+
+/// How big the chunks of an image are. `build.rs` has the same
+pub const CHUNK_SIZE: usize = 4096;
+
+/// # Image
+///
+/// A disk image, with the `CHUNK_SIZE` chunks that aren't all zeros
+pub struct Image(&'static [u8]);
+
+impl Image {
+    /// The size of the whole image, in bytes
+    pub fn size(&self) -> u64 {
+        u64::from_le_bytes(self.0[..8].try_into().unwrap())
+    }
+
+    /// The chunks that aren't all zeros, and where they are in the image
+    pub fn chunks(&self) -> impl Iterator<Item = (u64, &'static [u8])> {
+        self.0[8..].chunks(8 + CHUNK_SIZE).map(|chunk| {
+            let (offset, data) = chunk.split_at(8);
+            (u64::from_le_bytes(offset.try_into().unwrap()), data)
+        })
+    }
+}
+
+pub static FAT12: Image = Image(include_bytes!(concat!(env!("OUT_DIR"), "/fat12.sparse")));
+pub static FAT16: Image = Image(include_bytes!(concat!(env!("OUT_DIR"), "/fat16.sparse")));
+pub static FAT32: Image = Image(include_bytes!(concat!(env!("OUT_DIR"), "/fat32.sparse")));
@@ -0,0 +1,176 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(dbos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*
+    Integration test for the FAT driver - tests/fat-images makes a FAT12, FAT16 and FAT32 image with mkfs.fat
+    and copies the files in tests/fat onto them. They're included in the test, and each is loaded onto a RAM disk
+    for its test case
+*/
+
+extern crate alloc;
+
+mod common;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    common::boot(boot_info);
+    dbos::fs::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    dbos::test_panic_handler(info)
+}
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use dbos::block::{ramdisk::{self, RamDisk}, BlockDevice};
+use dbos::fs::{self, fat::{FatFs, FatType}, FileType, FsError, OpenOptions};
+use dbos::task::block_on;
+
+/// The size of big.bin, which tests/fat-images makes
+const BIG_FILE: usize = 100 * 1024;
+
+/// A RAM disk holding `image`
+fn load(image: &fat_images::Image) -> Arc<dyn BlockDevice> {
+    let disk = RamDisk::new(ramdisk::next_name(), 512, image.size() / 512);
+    for (offset, chunk) in image.chunks() {
+        block_on(disk.write(offset / 512, chunk)).expect("couldn't load a FAT image");
+    }
+    Arc::new(disk)
+}
+
+/// Open the FAT volume on `disk`
+async fn open(disk: &Arc<dyn BlockDevice>) -> FatFs {
+    FatFs::open(disk.clone()).await.unwrap_or_else(|error| panic!("{} isn't a FAT volume: {:?}", disk.name(), error))
+}
+
+/// The names in the directory at `path`, sorted
+async fn names(path: &str) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(path).await.unwrap().into_iter().map(|entry| entry.name).collect();
+    names.sort();
+    names
+}
+
+/// Check what tests/fat-images put on the volume mounted at `root`
+async fn read_fixtures(root: &str) {
+    assert_eq!(names(root).await, ["A long file name.txt", "big.bin", "docs", "hello.txt"]);
+    assert_eq!(fs::read(&format!("{}/hello.txt", root)).await.unwrap(), b"Hello from mkfs.fat!\n");
+    assert_eq!(fs::read(&format!("{}/A LONG FILE NAME.TXT", root)).await.unwrap(), b"Long names work\n");
+    assert_eq!(fs::read(&format!("{}/docs/nested/deep.txt", root)).await.unwrap(), b"deep\n");
+    assert_eq!(fs::stat(&format!("{}/docs", root)).await.unwrap().kind, FileType::Directory);
+
+    // Bit by bit, so it doesn't all have to be on the heap at once
+    let big = fs::open(&format!("{}/big.bin", root), OpenOptions::new().read(true)).await.unwrap();
+    assert_eq!(big.stat().await.unwrap().size, BIG_FILE as u64);
+    let mut buffer = vec![0u8; 4096];
+    let mut offset = 0;
+    while offset < BIG_FILE {
+        let count = big.read(&mut buffer).await.unwrap();
+        assert!(count > 0, "big.bin ended early at {}", offset);
+        for (index, &byte) in buffer[..count].iter().enumerate() {
+            assert_eq!(byte, ((offset + index) % 251) as u8);
+        }
+        offset += count;
+    }
+}
+
+/// Write, make and remove directories, and rename on the volume mounted at `root`
+async fn change(root: &str) {
+    let path = |name: &str| format!("{}/{}", root, name);
+
+    // A file over a few clusters, a long name, and a directory that needs more than one cluster of entries
+    let data: Vec<u8> = (0..20 * 1024).map(|index| (index % 7) as u8).collect();
+    fs::write(&path("written.dat"), &data).await.unwrap();
+    fs::mkdir(&path("A directory with a long name")).await.unwrap();
+    for index in 0..40 {
+        fs::write(&path(&format!("A directory with a long name/entry number {}", index)), b"x").await.unwrap();
+    }
+    fs::rename(&path("hello.txt"), &path("docs/Moved Hello.txt")).await.unwrap();
+    fs::rename(&path("docs/nested"), &path("nested")).await.unwrap();
+
+    assert_eq!(fs::mkdir(&path("DOCS")).await, Err(FsError::AlreadyExists));
+    assert_eq!(fs::rmdir(&path("docs")).await, Err(FsError::NotEmpty));
+    fs::unlink(&path("docs/readme.md")).await.unwrap();
+    fs::unlink(&path("docs/Moved Hello.txt")).await.unwrap();
+    fs::rmdir(&path("docs")).await.unwrap();
+}
+
+/// Check what [change] did, on the volume mounted at `root`
+async fn read_changes(root: &str) {
+    let path = |name: &str| format!("{}/{}", root, name);
+    assert_eq!(
+        names(root).await,
+        ["A directory with a long name", "A long file name.txt", "big.bin", "nested", "written.dat"]
+    );
+    let data = fs::read(&path("written.dat")).await.unwrap();
+    assert_eq!(data.len(), 20 * 1024);
+    assert!(data.iter().enumerate().all(|(index, &byte)| byte == (index % 7) as u8));
+    assert_eq!(names(&path("A directory with a long name")).await.len(), 40);
+    assert_eq!(fs::read(&path("nested/deep.txt")).await.unwrap(), b"deep\n");
+    assert_eq!(fs::stat(&path("docs")).await, Err(FsError::NotFound));
+}
+
+/// Everything, on `image`
+fn exercise(image: &fat_images::Image, fat_type: FatType, label: &str) {
+    let disk = load(image);
+    block_on(async {
+        let volume = open(&disk).await;
+        assert_eq!(volume.fat_type(), fat_type);
+        assert_eq!(volume.label(), label);
+        let report = volume.check(false).await.unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+
+        let root = format!("/mnt/{}", label.to_lowercase());
+        fs::mkdir(&root).await.unwrap();
+        let volume = Arc::new(volume);
+        fs::mount(&root, volume.clone()).await.unwrap();
+        read_fixtures(&root).await;
+        let free = volume.free_clusters().await.unwrap();
+        change(&root).await;
+        assert!(volume.free_clusters().await.unwrap() < free);
+        fs::sync().await.unwrap();
+        fs::unmount(&root).await.unwrap();
+
+        let report = volume.check(false).await.unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert_eq!(report.free_clusters, volume.free_clusters().await.unwrap());
+        drop(volume);
+
+        // Everything is still there when it is opened again
+        let volume = open(&disk).await;
+        fs::mount(&root, Arc::new(volume)).await.unwrap();
+        read_changes(&root).await;
+        fs::unmount(&root).await.unwrap();
+    });
+}
+
+// FAT12, on a floppy-sized image
+#[test_case]
+fn fat12() {
+    exercise(&fat_images::FAT12, FatType::Fat12, "FAT12");
+}
+
+// FAT16
+#[test_case]
+fn fat16() {
+    exercise(&fat_images::FAT16, FatType::Fat16, "FAT16");
+}
+
+// FAT32, with the root directory in a cluster chain and an FSInfo sector
+#[test_case]
+fn fat32() {
+    exercise(&fat_images::FAT32, FatType::Fat32, "FAT32");
+}
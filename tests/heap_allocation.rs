@@ -17,17 +17,13 @@ entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
     use dbos::allocator;
-    use dbos::memory::{self, BootInfoFrameAllocator};
+    use dbos::memory;
     use x86_64::VirtAddr;
 
     dbos::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
-    };
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
-        .expect("heap initialization failed");
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
 
     test_main();
     loop {}
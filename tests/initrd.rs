@@ -0,0 +1,125 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(dbos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*
+    Integration test for the initrd - the bundled archive is unpacked at boot, and tar and cpio archives unpack
+*/
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use dbos::allocator;
+    use dbos::memory;
+    use x86_64::VirtAddr;
+
+    dbos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+    dbos::fs::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    dbos::test_panic_handler(info)
+}
+
+use alloc::format;
+use alloc::vec::Vec;
+use dbos::fs::{self, initrd, FileType, FsError};
+use dbos::task::block_on;
+
+/// A newc cpio entry
+fn cpio(archive: &mut Vec<u8>, name: &str, inode: u32, mode: u32, links: u32, contents: &[u8]) {
+    let fields = [inode, mode, 0, 0, links, 0, contents.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+    archive.extend_from_slice(b"070701");
+    for field in fields.iter() {
+        archive.extend_from_slice(format!("{:08X}", field).as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize((archive.len() + 3) & !3, 0);
+    archive.extend_from_slice(contents);
+    archive.resize((archive.len() + 3) & !3, 0);
+}
+
+/// A USTAR entry
+fn tar(archive: &mut Vec<u8>, name: &str, kind: u8, link: &str, contents: &[u8]) {
+    let mut header = [0u8; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[124..135].copy_from_slice(format!("{:011o}", contents.len()).as_bytes());
+    header[156] = kind;
+    header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(contents);
+    archive.resize((archive.len() + 511) & !511, 0);
+}
+
+// The archive bundled into the kernel was unpacked into the root at boot
+#[test_case]
+fn bundled() {
+    assert!(initrd::format(initrd::IMAGE).is_some());
+    assert_eq!(block_on(fs::read("/etc/hostname")).unwrap(), b"dbos\n");
+    assert_eq!(block_on(fs::stat("/etc")).unwrap().kind, FileType::Directory);
+    // The root directories are still there
+    assert_eq!(block_on(fs::stat("/tmp")).unwrap().kind, FileType::Directory);
+}
+
+// A cpio archive, with a hard link whose data comes with its second name
+#[test_case]
+fn unpack_cpio() {
+    let mut archive = Vec::new();
+    cpio(&mut archive, "bin", 2, 0o040755, 2, b"");
+    cpio(&mut archive, "bin/true", 3, 0o100755, 2, b"");
+    cpio(&mut archive, "bin/false", 3, 0o100755, 2, b"#!\n");
+    cpio(&mut archive, "bin/sh", 4, 0o120777, 1, b"true");
+    cpio(&mut archive, "TRAILER!!!", 0, 0, 1, b"");
+    block_on(async {
+        fs::mkdir("/tmp/cpio").await.unwrap();
+        assert_eq!(initrd::unpack(&archive, "/tmp/cpio").await, Ok(5));
+        assert_eq!(fs::read("/tmp/cpio/bin/true").await.unwrap(), b"#!\n");
+        assert_eq!(fs::read("/tmp/cpio/bin/sh").await.unwrap(), b"#!\n");
+        let stat = fs::stat("/tmp/cpio/bin/false").await.unwrap();
+        assert_eq!(stat.links, 2);
+        assert_eq!(stat.inode, fs::stat("/tmp/cpio/bin/true").await.unwrap().inode);
+    });
+}
+
+// A tar archive, without entries for its directories, unpacked over files that are already there
+#[test_case]
+fn unpack_tar() {
+    let mut archive = Vec::new();
+    tar(&mut archive, "./usr/share/doc/readme", b'0', "", b"read me");
+    tar(&mut archive, "usr/share/doc/copy", b'1', "usr/share/doc/readme", b"");
+    tar(&mut archive, "usr/lib/", b'5', "", b"");
+    tar(&mut archive, "usr/dev", b'3', "", b""); // a character device - skipped
+    archive.resize(archive.len() + 1024, 0);
+    block_on(async {
+        fs::mkdir("/tmp/tar").await.unwrap();
+        fs::mkdir("/tmp/tar/usr").await.unwrap();
+        assert_eq!(initrd::unpack(&archive, "/tmp/tar").await, Ok(3));
+        assert_eq!(fs::read("/tmp/tar/usr/share/doc/copy").await.unwrap(), b"read me");
+        assert!(fs::read_dir("/tmp/tar/usr/lib").await.unwrap().is_empty());
+        assert_eq!(fs::stat("/tmp/tar/usr/dev").await.err(), Some(FsError::NotFound));
+
+        // A broken header checksum stops it before anything is made
+        archive[0] = b'X';
+        assert!(matches!(initrd::unpack(&archive, "/tmp/tar").await, Err(FsError::Corrupt(_))));
+        assert_eq!(fs::stat("/tmp/tar/X").await.err(), Some(FsError::NotFound));
+    });
+}
@@ -0,0 +1,56 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(dbos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*
+    Integration test for the NVMe driver - the test arguments in Cargo.toml add an NVMe controller with one
+    64 MiB namespace, which reads as zeroes and throws writes away. It shows up as `nvme0n1`. A second controller
+    has tests/disks/scratch.img (as a snapshot, so the file isn't changed), which shows up as `nvme1n1`
+*/
+
+extern crate alloc;
+
+mod common;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    common::boot(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    dbos::test_panic_handler(info)
+}
+
+use dbos::block;
+use dbos::driver::{registry, Bus};
+
+// The controller is bound, and its namespace is registered with the size QEMU gave it
+#[test_case]
+fn namespace_registered() {
+    let controller = registry::devices_on(Bus::Pci).into_iter().find(|device| registry::driver_of(device.id) == Some("nvme"));
+    assert!(controller.is_some(), "NVMe controller not bound");
+    let disk = block::get("nvme0n1").expect("no nvme0n1");
+    assert_eq!(disk.sector_count() * disk.sector_size() as u64, 64 * 1024 * 1024);
+}
+
+// Reads, writes and flushes complete, including reads that need a PRP list or several commands, and many
+// commands in flight at once
+#[test_case]
+fn null_disk() {
+    common::null_disk(&*block::get("nvme0n1").unwrap(), 16);
+}
+
+// The namespace backed by a file reads what is in it, and what is written (with a PRP list, or without) comes back
+#[test_case]
+fn file_backed_round_trip() {
+    common::scratch_disk(&*block::get("nvme1n1").expect("no nvme1n1"));
+}
@@ -0,0 +1,161 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(dbos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*
+    Integration test for PCI config space access, BAR sizing and capability lists, against the devices
+    QEMU's default `pc` machine has: the i440FX host bridge, the PIIX3 ISA bridge and IDE controller,
+    the standard VGA card and an e1000 network card. It has no MCFG, so this also checks the fallback to
+    legacy config access. None of them can do MSI, so interrupt vectors are tested with an IPI to ourselves,
+    which arrives through the local APIC just like a message would
+*/
+
+extern crate alloc;
+
+mod common;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    common::boot(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    dbos::test_panic_handler(info)
+}
+
+use dbos::driver::pci::{self, capability, config, Bar, PciAddress, PciDevice};
+
+// The i440FX host bridge is always 00:00.0
+#[test_case]
+fn host_bridge() {
+    let host = PciDevice::new(PciAddress::new(0, 0, 0, 0)).expect("no host bridge");
+    assert_eq!((host.vendor_id, host.device_id), (0x8086, 0x1237));
+    assert_eq!((host.class, host.subclass), (0x06, 0x00));
+}
+
+// Nothing answers at an empty slot
+#[test_case]
+fn empty_slot() {
+    assert!(PciDevice::new(PciAddress::new(0, 0, 31, 0)).is_none());
+    assert_eq!(config::read_u16(PciAddress::new(0, 0, 31, 0), config::VENDOR_ID), 0xFFFF);
+}
+
+// Enumeration finds the PIIX3, which is a multifunction device (ISA bridge and IDE controller)
+#[test_case]
+fn enumerate_finds_functions() {
+    let devices = pci::enumerate();
+    assert!(devices.iter().any(|d| d.address == PciAddress::new(0, 0, 1, 0) && d.class == 0x06 && d.subclass == 0x01));
+    assert!(devices.iter().any(|d| d.address == PciAddress::new(0, 0, 1, 1) && d.class == 0x01 && d.subclass == 0x01));
+}
+
+// The VGA framebuffer is a 16 MiB prefetchable memory BAR, and sizing it doesn't lose the address
+#[test_case]
+fn vga_framebuffer_bar() {
+    let vga = pci::enumerate().into_iter().find(|d| d.vendor_id == 0x1234 && d.device_id == 0x1111).expect("no VGA card");
+    let before = vga.read_u32(config::BAR0);
+    match vga.bar(0) {
+        Some(Bar::Memory { size, prefetchable, .. }) => {
+            assert_eq!(size, 16 << 20);
+            assert!(prefetchable);
+        }
+        other => panic!("unexpected BAR0: {:?}", other),
+    }
+    assert_eq!(vga.read_u32(config::BAR0), before);
+}
+
+// The e1000 has a 128 KiB memory BAR and 64 I/O ports
+#[test_case]
+fn e1000_bars() {
+    let nic = pci::enumerate().into_iter().find(|d| d.vendor_id == 0x8086 && d.device_id == 0x100E).expect("no e1000");
+    let bars = nic.bars();
+    assert!(bars.iter().any(|(_, bar)| !bar.is_io() && bar.size() == 128 << 10));
+    assert!(bars.iter().any(|(_, bar)| bar.is_io() && bar.size() == 64));
+}
+
+// Capability lists stay inside config space, after the header, and `find_capability` agrees with them
+#[test_case]
+fn capability_lists() {
+    for device in pci::enumerate() {
+        for found in device.capabilities() {
+            assert!(found.offset >= 0x40 && found.offset < config::CONFIG_SPACE_SIZE);
+            assert_eq!(device.find_capability(found.id).map(|first| first.id), Some(found.id));
+        }
+    }
+    assert!(PciDevice::new(PciAddress::new(0, 0, 0, 0)).unwrap().find_capability(capability::MSIX).is_none());
+}
+
+// Turning bus mastering on sticks, and only changes that bit
+#[test_case]
+fn enable_bus_mastering() {
+    let nic = pci::enumerate().into_iter().find(|d| d.vendor_id == 0x8086 && d.device_id == 0x100E).expect("no e1000");
+    let command = nic.command();
+    nic.enable_bus_mastering();
+    assert_eq!(nic.command(), command | config::COMMAND_BUS_MASTER);
+}
+
+// Without an MCFG there is no ECAM, so only the first 256 bytes of config space can be reached
+#[test_case]
+fn legacy_fallback_without_mcfg() {
+    assert!(!pci::ecam::is_available());
+    let host = PciAddress::new(0, 0, 0, 0);
+    assert_eq!(config::read_u16(host, config::VENDOR_ID), 0x8086); // still works through the I/O ports
+    assert_eq!(config::read_u32(host, config::CONFIG_SPACE_SIZE), !0);
+    for device in pci::enumerate() {
+        assert_eq!(device.extended_capabilities().count(), 0);
+    }
+}
+
+/// The `pc` machine's own devices (vendor and device IDs) - whatever else the test arguments in Cargo.toml add
+const PC_DEVICES: &[(u16, u16)] = &[
+    (0x8086, 0x1237), // i440FX host bridge
+    (0x8086, 0x7000), // PIIX3 ISA bridge
+    (0x8086, 0x7010), // PIIX3 IDE
+    (0x8086, 0x7113), // PIIX4 ACPI
+    (0x1234, 0x1111), // VGA
+];
+
+// None of the `pc` machine's own devices have MSI or MSI-X
+#[test_case]
+fn no_msi_on_pc() {
+    use dbos::driver::pci::MsiError;
+    let devices = pci::enumerate();
+    for &(vendor, device_id) in PC_DEVICES {
+        let device = devices.iter().find(|device| (device.vendor_id, device.device_id) == (vendor, device_id));
+        let device = device.unwrap_or_else(|| panic!("no {:04x}:{:04x}", vendor, device_id));
+        assert_eq!(device.msi().err(), Some(MsiError::NotSupported));
+        assert_eq!(device.msix().err(), Some(MsiError::NotSupported));
+    }
+}
+
+static FIRED: AtomicUsize = AtomicUsize::new(0);
+
+fn record_vector(context: usize) {
+    FIRED.store(context, Ordering::SeqCst);
+}
+
+// An allocated vector reaches its handler, with its context
+#[test_case]
+fn allocated_vector_dispatch() {
+    use dbos::{apic, interrupts};
+
+    let vector = interrupts::allocate_vector(record_vector, 42).expect("no free vectors");
+    apic::send_ipi(apic::id(), vector);
+    for _ in 0..1000 {
+        if FIRED.load(Ordering::SeqCst) == 42 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    assert_eq!(FIRED.load(Ordering::SeqCst), 42);
+    interrupts::free_vector(vector);
+}
@@ -0,0 +1,160 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(dbos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*
+    Integration test for QEMU's `q35` machine - its firmware's ACPI tables, with the Q35 host bridge's interrupt
+    routing, and PCI config space through ECAM (q35 has an MCFG, unlike `pc`). It isn't part of `cargo test`: run
+    it with `cargo test-q35`, which adds `-machine q35` to the test arguments in Cargo.toml
+*/
+
+extern crate alloc;
+
+mod common;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    common::boot(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    dbos::test_panic_handler(info)
+}
+
+use alloc::vec;
+use alloc::vec::Vec;
+use dbos::acpi::aml::{self, AmlValue};
+use dbos::driver::pci::{self, config, PciAddress};
+use x86_64::instructions::port::Port;
+
+// `\_S5` is a package, with the PM1a and PM1b sleep types for soft off first
+#[test_case]
+fn s5_package() {
+    let s5 = aml::evaluate("\\_S5", Vec::new()).expect("couldn't evaluate \\_S5");
+    let elements = s5.as_package().expect("\\_S5 isn't a package");
+    assert!(elements.len() >= 2);
+    assert!(elements[0].as_integer().is_ok());
+    assert!(elements[1].as_integer().is_ok());
+}
+
+// The host bridge is a PCI Express one (PNP0A08), compatible with plain PCI (PNP0A03)
+#[test_case]
+fn pci_express_host_bridge() {
+    assert_eq!(aml::evaluate("\\_SB.PCI0._HID", Vec::new()), Ok(AmlValue::Integer(0x080AD041)));
+    assert_eq!(aml::evaluate("\\_SB.PCI0._CID", Vec::new()), Ok(AmlValue::Integer(0x030AD041)));
+}
+
+/// The link device of every `_PRT` entry, checking each entry is { address, pin, link, index }
+fn prt_links() -> Vec<AmlValue> {
+    let prt = aml::evaluate("\\_SB.PCI0._PRT", Vec::new()).expect("couldn't evaluate _PRT");
+    let entries = prt.as_package().expect("_PRT isn't a package");
+    assert!(!entries.is_empty());
+    entries
+        .iter()
+        .map(|entry| {
+            let entry = entry.as_package().expect("_PRT entry isn't a package");
+            assert_eq!(entry.len(), 4);
+            assert_eq!(entry[0].as_integer().map(|address| address & 0xFFFF), Ok(0xFFFF));
+            entry[2].clone()
+        })
+        .collect()
+}
+
+/// Whether `link` is a reference to a device whose name starts with `prefix`
+fn links_to(link: &AmlValue, prefix: &str) -> bool {
+    match link {
+        AmlValue::Reference(path) => path.starts_with(prefix),
+        _ => false,
+    }
+}
+
+// `_PRT` picks its table by interrupt mode - PCI interrupt links (LNKx) in PIC mode, and GSI devices (GSIx)
+// once `_PIC` says we use the I/O APIC
+#[test_case]
+fn pci_interrupt_routing() {
+    let pic_links = prt_links();
+    assert!(pic_links.iter().all(|link| links_to(link, "\\_SB_.LNK")), "{:?}", pic_links);
+
+    aml::evaluate("\\_PIC", vec![AmlValue::Integer(1)]).expect("couldn't evaluate _PIC");
+    let apic_links = prt_links();
+    aml::evaluate("\\_PIC", vec![AmlValue::Integer(0)]).expect("couldn't evaluate _PIC");
+    assert!(apic_links.iter().all(|link| links_to(link, "\\_SB_.GSI")), "{:?}", apic_links);
+}
+
+// The firmware's MCFG covers bus 0, so config space goes through ECAM
+#[test_case]
+fn ecam_mapped() {
+    assert!(pci::ecam::is_available());
+    let region = pci::ecam::region(0, 0).expect("no ECAM region for bus 0");
+    assert_eq!(region.start_bus, 0);
+    let host = PciAddress::new(0, 0, 0, 0);
+    assert_eq!(config::read_u16(host, config::VENDOR_ID), 0x8086);
+    assert_eq!(config::read_u32(host, 0), legacy_read_u32(host, 0));
+}
+
+/// Read a config space dword through the I/O ports, even where ECAM covers it
+fn legacy_read_u32(address: PciAddress, offset: u16) -> u32 {
+    let select = 1 << 31
+        | (address.bus as u32) << 16
+        | (address.device as u32) << 11
+        | (address.function as u32) << 8
+        | (offset & 0xFC) as u32;
+    // Nothing else is touching config space while the test runs, but an interrupt handler could
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        Port::<u32>::new(0xCF8).write(select);
+        Port::<u32>::new(0xCFC).read()
+    })
+}
+
+/// Every function on every bus, with its vendor and device ID, found by trying each address through the I/O
+/// ports
+fn legacy_functions() -> Vec<(PciAddress, u32)> {
+    let mut functions = Vec::new();
+    for bus in 0..=255 {
+        for device in 0..32 {
+            let first = PciAddress::new(0, bus, device, 0);
+            if legacy_read_u32(first, 0) & 0xFFFF == 0xFFFF {
+                continue;
+            }
+            let multifunction = legacy_read_u32(first, 0x0C) >> 16 & config::HEADER_TYPE_MULTIFUNCTION as u32 != 0;
+            for function in 0..if multifunction { 8 } else { 1 } {
+                let address = PciAddress::new(0, bus, device, function);
+                let id = legacy_read_u32(address, 0);
+                if id & 0xFFFF != 0xFFFF {
+                    functions.push((address, id));
+                }
+            }
+        }
+    }
+    functions
+}
+
+// Enumerating through ECAM finds the same functions as the I/O ports do, including the Q35 chipset's own
+#[test_case]
+fn ecam_enumeration_matches_legacy() {
+    let mut found: Vec<(PciAddress, u32)> = pci::enumerate()
+        .iter()
+        .map(|device| (device.address, (device.device_id as u32) << 16 | device.vendor_id as u32))
+        .collect();
+    found.sort();
+    assert_eq!(found, legacy_functions());
+
+    let chipset = [
+        (PciAddress::new(0, 0, 0, 0), 0x29C0_8086),  // Q35 host bridge (MCH)
+        (PciAddress::new(0, 0, 0x1F, 0), 0x2918_8086), // ICH9 LPC (ISA) bridge
+        (PciAddress::new(0, 0, 0x1F, 2), 0x2922_8086), // ICH9 AHCI
+        (PciAddress::new(0, 0, 0x1F, 3), 0x2930_8086), // ICH9 SMBus
+    ];
+    for function in chipset.iter() {
+        assert!(found.contains(function), "{} not found", function.0);
+    }
+}
@@ -0,0 +1,210 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(dbos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*
+    Integration test for the VFS, on the root ramfs - files, directories, symlinks, hard links and mounts
+*/
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use dbos::allocator;
+    use dbos::memory;
+    use x86_64::VirtAddr;
+
+    dbos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+    dbos::fs::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    dbos::test_panic_handler(info)
+}
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use dbos::fs::{self, ramfs::RamFs, FileType, FsError, OpenOptions, SeekFrom};
+use dbos::task::block_on;
+
+/// The names in the directory at `path`, in order
+fn names(path: &str) -> Vec<String> {
+    block_on(fs::read_dir(path)).unwrap().into_iter().map(|entry| entry.name).collect()
+}
+
+// The root ramfs is mounted, with the usual directories
+#[test_case]
+fn root_mounted() {
+    assert_eq!(names("/"), ["dev", "mnt", "tmp"]);
+    let mounts = fs::mounts();
+    assert_eq!(mounts[0].path(), "/");
+    assert_eq!(mounts[0].filesystem().name(), "ramfs");
+    assert_eq!(fs::mount_root(Arc::new(RamFs::new())), Err(FsError::Busy));
+}
+
+// Files are read and written through open files, whose offsets move
+#[test_case]
+fn files() {
+    block_on(async {
+        fs::write("/tmp/hello", b"hello world").await.unwrap();
+        assert_eq!(fs::read("/tmp/hello").await.unwrap(), b"hello world");
+
+        let file = fs::open("/tmp/hello", OpenOptions::new().read(true).write(true)).await.unwrap();
+        let mut buffer = [0u8; 5];
+        assert_eq!(file.read(&mut buffer).await, Ok(5));
+        assert_eq!(file.seek(SeekFrom::Current(1)).await, Ok(6));
+        assert_eq!(file.write(b"there").await, Ok(5));
+        assert_eq!(file.seek(SeekFrom::End(-11)).await, Ok(0));
+        assert_eq!(file.read_to_end().await.unwrap(), b"hello there");
+        assert_eq!(file.seek(SeekFrom::Current(-12)).await, Err(FsError::InvalidArgument));
+
+        // Appends go to the end, wherever the offset is
+        let log = fs::open("/tmp/hello", OpenOptions::new().append(true)).await.unwrap();
+        log.write_all(b"!").await.unwrap();
+        assert_eq!(fs::read("/tmp/hello").await.unwrap(), b"hello there!");
+        let mut byte = [0u8; 1];
+        assert_eq!(log.read(&mut byte).await, Err(FsError::NotReadable));
+
+        // What open does when the file is or isn't there
+        assert_eq!(fs::open("/tmp/missing", OpenOptions::new().read(true)).await.err(), Some(FsError::NotFound));
+        assert_eq!(fs::open("/tmp/hello", OpenOptions::new().write(true).create_new(true)).await.err(), Some(FsError::AlreadyExists));
+        assert_eq!(fs::open("/tmp", OpenOptions::new().write(true)).await.err(), Some(FsError::IsDirectory));
+        fs::open("/tmp/hello", OpenOptions::new().write(true).truncate(true)).await.unwrap();
+        assert_eq!(fs::stat("/tmp/hello").await.unwrap().size, 0);
+        fs::unlink("/tmp/hello").await.unwrap();
+        assert_eq!(fs::stat("/tmp/hello").await.err(), Some(FsError::NotFound));
+    });
+}
+
+// Writing past the end leaves a hole that reads as zeros and takes no memory
+#[test_case]
+fn sparse_files() {
+    block_on(async {
+        let file = fs::open("/tmp/sparse", OpenOptions::new().read(true).write(true).create(true)).await.unwrap();
+        file.seek(SeekFrom::Start(1 << 20)).await.unwrap();
+        file.write_all(b"end").await.unwrap();
+        let stat = file.stat().await.unwrap();
+        assert_eq!(stat.size, (1 << 20) + 3);
+        assert!(stat.allocated <= 2 * fs::ramfs::BLOCK_SIZE as u64);
+        let mut buffer = vec![0xFFu8; 4096];
+        assert_eq!(file.read_at(4096, &mut buffer).await, Ok(4096));
+        assert!(buffer.iter().all(|&byte| byte == 0));
+
+        file.set_len(10).await.unwrap();
+        assert_eq!(file.stat().await.unwrap().allocated, 0);
+        assert_eq!(file.read_at(0, &mut buffer).await, Ok(10));
+        fs::unlink("/tmp/sparse").await.unwrap();
+    });
+}
+
+// Directories are made, listed and removed, and names can be moved
+#[test_case]
+fn directories() {
+    block_on(async {
+        fs::mkdir("/tmp/a").await.unwrap();
+        fs::mkdir("/tmp/a/b").await.unwrap();
+        fs::write("/tmp/a/b/file", b"data").await.unwrap();
+        assert_eq!(fs::mkdir("/tmp/a").await, Err(FsError::AlreadyExists));
+        assert_eq!(fs::mkdir("/tmp/missing/b").await, Err(FsError::NotFound));
+        assert_eq!(fs::mkdir("/tmp/a/b/file/c").await, Err(FsError::NotDirectory));
+        assert_eq!(names("/tmp/a/b"), ["file"]);
+        assert_eq!(fs::stat("/tmp/a").await.unwrap().links, 3);
+
+        assert_eq!(fs::rmdir("/tmp/a/b").await, Err(FsError::NotEmpty));
+        assert_eq!(fs::unlink("/tmp/a/b").await, Err(FsError::IsDirectory));
+        assert_eq!(fs::rmdir("/tmp/a/b/file").await, Err(FsError::NotDirectory));
+
+        // Renames - within a directory, between them, over a file, and not into itself
+        fs::rename("/tmp/a/b/file", "/tmp/a/b/moved").await.unwrap();
+        fs::rename("/tmp/a/b/moved", "/tmp/a/moved").await.unwrap();
+        fs::write("/tmp/a/other", b"other").await.unwrap();
+        fs::rename("/tmp/a/other", "/tmp/a/moved").await.unwrap();
+        assert_eq!(fs::read("/tmp/a/moved").await.unwrap(), b"other");
+        assert_eq!(fs::rename("/tmp/a", "/tmp/a/b/a").await, Err(FsError::InvalidArgument));
+        assert_eq!(fs::rename("/tmp/a/moved", "/tmp/a/b").await, Err(FsError::IsDirectory));
+        fs::rename("/tmp/a/b", "/tmp/b").await.unwrap();
+        assert_eq!(names("/tmp/a"), ["moved"]);
+
+        fs::unlink("/tmp/a/moved").await.unwrap();
+        fs::rmdir("/tmp/a").await.unwrap();
+        fs::rmdir("/tmp/b").await.unwrap();
+        assert_eq!(fs::stat("/tmp/a").await.err(), Some(FsError::NotFound));
+    });
+}
+
+// Symlinks are followed (relative, absolute, through `..`), and hard links share a file
+#[test_case]
+fn links() {
+    block_on(async {
+        fs::mkdir("/tmp/links").await.unwrap();
+        fs::write("/tmp/links/target", b"linked").await.unwrap();
+        fs::symlink("target", "/tmp/links/relative").await.unwrap();
+        fs::symlink("/tmp/links/target", "/tmp/links/absolute").await.unwrap();
+        fs::symlink("../links/./target", "/tmp/links/dots").await.unwrap();
+        fs::symlink("/tmp/links", "/tmp/links/directory").await.unwrap();
+        fs::symlink("loop", "/tmp/links/loop").await.unwrap();
+        for link in &["relative", "absolute", "dots", "directory/directory/target"] {
+            let path = alloc::format!("/tmp/links/{}", link);
+            assert_eq!(fs::read(&path).await.unwrap(), b"linked");
+        }
+        assert_eq!(fs::read_link("/tmp/links/dots").await.unwrap(), "../links/./target");
+        assert_eq!(fs::lstat("/tmp/links/relative").await.unwrap().kind, FileType::Symlink);
+        assert_eq!(fs::stat("/tmp/links/relative").await.unwrap().kind, FileType::Regular);
+        assert_eq!(fs::read("/tmp/links/loop").await.err(), Some(FsError::TooManyLinks));
+        assert_eq!(fs::lookup("/tmp/links/directory/..", true).await.unwrap().path(), "/tmp");
+
+        // A hard link is the same inode - it outlives the name it was made from
+        fs::link("/tmp/links/target", "/tmp/links/hard").await.unwrap();
+        let stat = fs::stat("/tmp/links/hard").await.unwrap();
+        assert_eq!(stat.links, 2);
+        assert_eq!(stat.inode, fs::stat("/tmp/links/target").await.unwrap().inode);
+        assert_eq!(fs::link("/tmp/links", "/tmp/links2").await, Err(FsError::IsDirectory));
+        fs::unlink("/tmp/links/target").await.unwrap();
+        assert_eq!(fs::read("/tmp/links/hard").await.unwrap(), b"linked");
+        assert_eq!(fs::stat("/tmp/links/hard").await.unwrap().links, 1);
+        assert_eq!(fs::read("/tmp/links/relative").await.err(), Some(FsError::NotFound));
+    });
+}
+
+// Another ramfs mounted on a directory hides it, and paths cross into and out of it
+#[test_case]
+fn mounts() {
+    block_on(async {
+        fs::mkdir("/mnt/disk").await.unwrap();
+        fs::write("/mnt/disk/hidden", b"under").await.unwrap();
+        fs::mount("/mnt/disk", Arc::new(RamFs::new())).await.unwrap();
+        assert!(names("/mnt/disk").is_empty());
+        fs::write("/mnt/disk/file", b"on top").await.unwrap();
+        assert_eq!(fs::lookup("/mnt/disk/file", true).await.unwrap().path(), "/mnt/disk/file");
+        assert_eq!(fs::read("/mnt/disk/../disk/file").await.unwrap(), b"on top");
+        assert_eq!(fs::mounts().len(), 2);
+
+        assert_eq!(fs::link("/mnt/disk/file", "/tmp/file").await, Err(FsError::CrossDevice));
+        assert_eq!(fs::rename("/mnt/disk/file", "/tmp/file").await, Err(FsError::CrossDevice));
+        assert_eq!(fs::rmdir("/mnt/disk").await, Err(FsError::Busy));
+        assert_eq!(fs::mount("/mnt/disk", Arc::new(RamFs::new())).await, Err(FsError::Busy));
+        assert_eq!(fs::unmount("/").await, Err(FsError::Busy));
+
+        // Files opened on it keep working after it is unmounted
+        let file = fs::open("/mnt/disk/file", OpenOptions::new().read(true)).await.unwrap();
+        fs::unmount("/mnt/disk").await.unwrap();
+        assert_eq!(fs::read("/mnt/disk/hidden").await.unwrap(), b"under");
+        assert_eq!(file.read_to_end().await.unwrap(), b"on top");
+        assert_eq!(fs::mounts().len(), 1);
+    });
+}
@@ -0,0 +1,78 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(dbos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*
+    Integration test to check that the application processors start up (QEMU is run with `-smp 4`),
+    and that they pick up work from the SMP executor
+*/
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use dbos::{acpi, allocator, apic, smp};
+    use dbos::memory;
+    use x86_64::VirtAddr;
+
+    dbos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+    acpi::init();
+    apic::init();
+    smp::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    dbos::test_panic_handler(info)
+}
+
+use dbos::smp;
+
+// Every core QEMU gave us should be online
+#[test_case]
+fn all_cpus_online() {
+    assert_eq!(smp::cpu_count(), 4);
+}
+
+// We're on the bootstrap processor, which is always CPU 0
+#[test_case]
+fn bsp_is_cpu_zero() {
+    assert_eq!(smp::current_cpu(), 0);
+}
+
+use dbos::task::smp_executor::SMP_EXECUTOR;
+
+static TASK_RAN: AtomicBool = AtomicBool::new(false);
+static TASK_CPU: AtomicUsize = AtomicUsize::new(0);
+
+// The BSP isn't running the executor, so a spawned task has to be picked up by a (halted) AP
+#[test_case]
+fn ap_runs_spawned_task() {
+    SMP_EXECUTOR.spawn(async {
+        TASK_CPU.store(smp::current_cpu(), Ordering::SeqCst);
+        TASK_RAN.store(true, Ordering::SeqCst);
+    });
+
+    for _ in 0..100_000_000u64 {
+        if TASK_RAN.load(Ordering::SeqCst) {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+
+    assert!(TASK_RAN.load(Ordering::SeqCst), "no AP ran the task");
+    assert_ne!(TASK_CPU.load(Ordering::SeqCst), 0);
+}
@@ -0,0 +1,101 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(dbos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*
+    Integration test for the virtio core - the test arguments in Cargo.toml add two entropy devices, one with
+    only the legacy transport and one with only the modern one. Entropy devices are the simplest there are: one
+    queue, which the device fills with random bytes
+*/
+
+extern crate alloc;
+
+mod common;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    common::boot(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    dbos::test_panic_handler(info)
+}
+
+use alloc::vec::Vec;
+use dbos::driver::virtio::{self, Buffer, VirtioDevice};
+use dbos::driver::{registry, Bus, Device, DeviceKind};
+use dbos::memory::dma::DmaBuffer;
+use dbos::task::block_on;
+
+/// The entropy devices, taken from whatever driver has them
+fn entropy_devices() -> Vec<Device> {
+    let devices: Vec<Device> = registry::devices_on(Bus::Virtio)
+        .into_iter()
+        .filter(|device| matches!(device.kind, DeviceKind::Virtio { device_type: virtio::DEVICE_ENTROPY, .. }))
+        .collect();
+    for device in devices.iter() {
+        registry::unbind(device.id);
+    }
+    devices
+}
+
+// The virtio-pci driver binds to every virtio function, and adds a virtio device for each
+#[test_case]
+fn devices_added() {
+    let functions: Vec<Device> = registry::devices_on(Bus::Pci)
+        .into_iter()
+        .filter(|device| device.pci_function().map(|function| function.vendor_id) == Some(virtio::VENDOR))
+        .collect();
+    assert!(functions.iter().all(|device| registry::driver_of(device.id) == Some("virtio-pci")));
+    assert_eq!(registry::devices_on(Bus::Virtio).len(), functions.len());
+    assert_eq!(entropy_devices().len(), 2);
+}
+
+// Each transport can be set up, and a request goes through a queue and comes back
+#[test_case]
+fn entropy_request() {
+    let mut legacy = 0;
+    for device in entropy_devices() {
+        let mut virtio = VirtioDevice::new(&device).expect("couldn't set up device");
+        if virtio.is_legacy() {
+            legacy += 1;
+        }
+        virtio.negotiate(0).expect("feature negotiation failed");
+        let queue = virtio.setup_queue(0, 64).expect("couldn't set up queue");
+        virtio.driver_ok().expect("device failed");
+
+        let buffer = DmaBuffer::new(64).unwrap();
+        let written = block_on(queue.submit(&[Buffer::writable(&buffer, 64)])).expect("request failed");
+        assert!(written > 0 && written <= 64);
+        assert!(buffer.as_slice()[..written as usize].iter().any(|&byte| byte != 0));
+        assert_eq!(queue.free_descriptors(), queue.size() as usize);
+    }
+    assert_eq!(legacy, 1);
+}
+
+// More requests than the queue has descriptors wait for earlier ones to finish
+#[test_case]
+fn queue_full() {
+    let device = entropy_devices().into_iter().next().unwrap();
+    let mut virtio = VirtioDevice::new(&device).unwrap();
+    virtio.negotiate(0).unwrap();
+    let queue = virtio.setup_queue(0, 8).unwrap();
+    virtio.driver_ok().unwrap();
+
+    let count = queue.size() as usize * 2;
+    let buffers: Vec<DmaBuffer> = (0..count).map(|_| DmaBuffer::new(16).unwrap()).collect();
+    let chains: Vec<[Buffer; 1]> = buffers.iter().map(|buffer| [Buffer::writable(buffer, 16)]).collect();
+    let requests = chains.iter().map(|chain| queue.submit(chain));
+    for result in block_on(futures_util::future::join_all(requests)) {
+        assert!(result.unwrap() > 0);
+    }
+}
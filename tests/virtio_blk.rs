@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(dbos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*
+    Integration test for the virtio-blk driver - the test arguments in Cargo.toml add two virtio disks which
+    read as zeroes and throw writes away: a 64 MiB one, and a 32 MiB read only one. A third has
+    tests/disks/scratch.img (as a snapshot, so the file isn't changed)
+*/
+
+extern crate alloc;
+
+mod common;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    common::boot(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    dbos::test_panic_handler(info)
+}
+
+use alloc::sync::Arc;
+use alloc::vec;
+use dbos::block::{self, BlockDevice, BlockError};
+use dbos::task::block_on;
+
+const WRITABLE_SIZE: u64 = 64 * 1024 * 1024;
+const READ_ONLY_SIZE: u64 = 32 * 1024 * 1024;
+
+/// The virtio disk of `size` bytes
+fn disk(size: u64) -> Arc<dyn BlockDevice> {
+    block::devices()
+        .into_iter()
+        .find(|disk| disk.name().starts_with("vblk") && disk.sector_count() * disk.sector_size() as u64 == size)
+        .expect("virtio disk not registered")
+}
+
+// All three disks are registered, with the sizes QEMU gave them, and only the one QEMU made read only is
+#[test_case]
+fn disks_registered() {
+    assert!(!disk(WRITABLE_SIZE).is_read_only());
+    assert!(disk(READ_ONLY_SIZE).is_read_only());
+    assert!(!disk(common::SCRATCH_IMAGE_SIZE).is_read_only());
+}
+
+// Reads, writes and flushes complete on the writable disk, including reads split into several requests and
+// many requests in flight at once
+#[test_case]
+fn null_disk() {
+    common::null_disk(&*disk(WRITABLE_SIZE), 64);
+}
+
+// The disk backed by a file reads what is in it, and what is written comes back
+#[test_case]
+fn file_backed_round_trip() {
+    common::scratch_disk(&*disk(common::SCRATCH_IMAGE_SIZE));
+}
+
+// The read only disk can be read, but not written
+#[test_case]
+fn read_only() {
+    let disk = disk(READ_ONLY_SIZE);
+    let mut buffer = vec![0xFFu8; disk.sector_size()];
+    block_on(disk.read(0, &mut buffer)).expect("read failed");
+    assert_eq!(block_on(disk.write(0, &buffer)), Err(BlockError::ReadOnly));
+}
@@ -0,0 +1,83 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(dbos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*
+    Integration test for the virtio-console and virtio-rng drivers - the test arguments in Cargo.toml add a
+    virtio-serial card with the kernel's three named ports (their host ends go nowhere), and two random number
+    generators, which should have seeded the entropy pool by the time the drivers are up
+*/
+
+extern crate alloc;
+
+mod common;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    common::boot(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    dbos::test_panic_handler(info)
+}
+
+use dbos::driver::virtio::console::{self, Channel, ConsoleError};
+use dbos::driver::virtio;
+use dbos::driver::{registry, Bus, DeviceKind};
+use dbos::entropy;
+use dbos::task::block_on;
+
+// The device announces the named ports, and each is ready
+#[test_case]
+fn ports_named() {
+    for channel in [Channel::Log, Channel::Shell, Channel::Test].iter() {
+        let port = console::channel(*channel).expect("channel port missing");
+        assert!(port.is_ready());
+        assert_eq!(port.name().as_deref(), Some(channel.port_name()));
+    }
+    assert!(console::port("dbos.nothing").is_none());
+}
+
+// Writes go through, waiting or not, and big ones are split up
+#[test_case]
+fn port_writes() {
+    let port = console::channel(Channel::Shell).unwrap();
+    block_on(port.write(b"hello from the kernel\n")).expect("write failed");
+    let big = [b'x'; 10000];
+    block_on(port.write(&big)).expect("big write failed");
+    port.write_blocking(b"and without waiting\n").expect("blocking write failed");
+    assert_ne!(port.write_blocking(&big), Err(ConsoleError::NotReady));
+}
+
+// Printing is copied to the log port without getting in the way
+#[test_case]
+fn log_mirrored() {
+    for line in 0..100 {
+        dbos::serial_println!("log line {}", line);
+    }
+    console::write_channel(Channel::Test, "log lines printed\n");
+}
+
+// Each random number generator seeded the pool, and is a source for reseeding
+#[test_case]
+fn entropy_seeded() {
+    let generators = registry::devices_on(Bus::Virtio)
+        .into_iter()
+        .filter(|device| matches!(device.kind, DeviceKind::Virtio { device_type: virtio::DEVICE_ENTROPY, .. }))
+        .filter(|device| registry::driver_of(device.id) == Some("virtio-rng"))
+        .count();
+    assert_eq!(generators, 2);
+    assert_eq!(entropy::sources().len(), 2);
+    assert!(entropy::is_seeded());
+    assert!(block_on(entropy::reseed()) > 0);
+    assert_ne!(entropy::u64(), entropy::u64());
+}
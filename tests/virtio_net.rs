@@ -0,0 +1,134 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(dbos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*
+    Integration test for the virtio-net driver - the test arguments in Cargo.toml add a virtio network card on
+    QEMU's user mode network. The gateway there (10.0.2.2) answers ARP and pings, so we can check frames go
+    both ways without a real network
+*/
+
+extern crate alloc;
+
+mod common;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    common::boot(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    dbos::test_panic_handler(info)
+}
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use dbos::net::{self, MacAddress, NetworkDevice, PartialChecksum, Received};
+use dbos::task::block_on;
+
+const MAC: MacAddress = MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+const OUR_IP: [u8; 4] = [10, 0, 2, 15];
+const GATEWAY_IP: [u8; 4] = [10, 0, 2, 2];
+const ETHERTYPE_IPV4: [u8; 2] = [0x08, 0x00];
+const ETHERTYPE_ARP: [u8; 2] = [0x08, 0x06];
+
+/// The virtio card, found by the MAC address QEMU was given
+fn card() -> Arc<dyn NetworkDevice> {
+    net::devices().into_iter().find(|card| card.mac_address() == MAC).expect("virtio card not registered")
+}
+
+/// Receive frames until one matches `wanted`
+fn receive_until(card: &dyn NetworkDevice, wanted: impl Fn(&Received) -> bool) -> Received {
+    for _ in 0..32 {
+        let received = block_on(card.receive()).expect("receive failed");
+        if wanted(&received) {
+            return received;
+        }
+    }
+    panic!("expected frame never arrived");
+}
+
+/// Ask who has `GATEWAY_IP`, and return its MAC address
+fn resolve_gateway(card: &dyn NetworkDevice) -> MacAddress {
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&MacAddress::BROADCAST.0);
+    frame.extend_from_slice(&MAC.0);
+    frame.extend_from_slice(&ETHERTYPE_ARP);
+    // Ethernet and IPv4 addresses, a request
+    frame.extend_from_slice(&[0, 1, 0x08, 0x00, 6, 4, 0, 1]);
+    frame.extend_from_slice(&MAC.0);
+    frame.extend_from_slice(&OUR_IP);
+    frame.extend_from_slice(&[0; 6]);
+    frame.extend_from_slice(&GATEWAY_IP);
+    block_on(card.send(&frame, None)).expect("send failed");
+
+    let reply = receive_until(card, |received| {
+        let frame = &received.frame;
+        frame.len() >= 42 && frame[12..14] == ETHERTYPE_ARP && frame[20..22] == [0, 2] && frame[28..32] == GATEWAY_IP
+    });
+    let mut mac = [0; 6];
+    mac.copy_from_slice(&reply.frame[22..28]);
+    MacAddress(mac)
+}
+
+// The card is registered with the MAC address QEMU gave it, and its link is up
+#[test_case]
+fn card_registered() {
+    let card = card();
+    assert!(card.name().starts_with("eth"));
+    assert!(card.link_up());
+    assert_eq!(card.mtu(), net::DEFAULT_MTU);
+}
+
+// Frames of the wrong size are refused
+#[test_case]
+fn bad_frames() {
+    let card = card();
+    assert_eq!(block_on(card.send(&[0; 10], None)), Err(net::NetError::BadFrameSize));
+    let huge = vec![0; net::ETHERNET_HEADER_SIZE + card.mtu() + 1];
+    assert_eq!(block_on(card.send(&huge, None)), Err(net::NetError::BadFrameSize));
+}
+
+// The gateway answers ARP, then a ping whose ICMP checksum was left to the card (or finished in software)
+#[test_case]
+fn ping_gateway() {
+    let card = card();
+    let gateway = resolve_gateway(&*card);
+    assert_ne!(gateway, MacAddress::default());
+
+    let payload = [0xA5u8; 32];
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&gateway.0);
+    frame.extend_from_slice(&MAC.0);
+    frame.extend_from_slice(&ETHERTYPE_IPV4);
+    let total_length = (20 + 8 + payload.len()) as u16;
+    let mut ip = [0x45, 0, 0, 0, 0x12, 0x34, 0, 0, 64, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    ip[2..4].copy_from_slice(&total_length.to_be_bytes());
+    ip[12..16].copy_from_slice(&OUR_IP);
+    ip[16..20].copy_from_slice(&GATEWAY_IP);
+    let checksum = net::internet_checksum(&ip);
+    ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+    frame.extend_from_slice(&ip);
+    // Echo request, identifier 0xBEEF, sequence 1. The checksum starts as zero
+    frame.extend_from_slice(&[8, 0, 0, 0, 0xBE, 0xEF, 0, 1]);
+    frame.extend_from_slice(&payload);
+    block_on(card.send(&frame, Some(PartialChecksum { start: 34, offset: 2 }))).expect("send failed");
+
+    let reply = receive_until(&*card, |received| {
+        let frame = &received.frame;
+        frame.len() >= 42 && frame[12..14] == ETHERTYPE_IPV4 && frame[23] == 1 && frame[34] == 0 && frame[38..40] == [0xBE, 0xEF]
+    });
+    assert_eq!(&reply.frame[26..30], &GATEWAY_IP);
+    assert_eq!(&reply.frame[42..42 + payload.len()], &payload);
+    assert_eq!(net::internet_checksum(&reply.frame[34..42 + payload.len()]), 0);
+}